@@ -0,0 +1,82 @@
+use std::io;
+use std::io::Write;
+use std::sync::Arc;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand_core::RngCore;
+use rencfs::crypto;
+use rencfs::crypto::parallel::CryptoPool;
+use rencfs::crypto::write::CryptoWrite;
+use rencfs::crypto::Cipher;
+use rencfs::stream_util;
+use shush_rs::SecretVec;
+
+const LEN: usize = 1024 * 1024 * 1024;
+const CHUNK_LEN: usize = 4 * 1024 * 1024;
+const THREADS: [usize; 4] = [1, 2, 4, 8];
+
+fn create_key(cipher: Cipher) -> SecretVec<u8> {
+    let mut key: Vec<u8> = vec![0; cipher.key_len()];
+    rand::thread_rng().fill_bytes(&mut key);
+    SecretVec::new(Box::new(key))
+}
+
+fn bench_write_1gb(c: &mut Criterion) {
+    let cipher = Cipher::ChaCha20Poly1305;
+    let key = create_key(cipher);
+    let mut chunk = vec![0; CHUNK_LEN];
+    rand::thread_rng().fill_bytes(&mut chunk);
+
+    let mut group = c.benchmark_group("bench_write_1gb_chacha");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(LEN as u64));
+    for threads in THREADS {
+        let pool = Arc::new(CryptoPool::new(threads));
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |b, _| {
+            b.iter(|| {
+                let file = tempfile::tempfile().unwrap();
+                let mut writer = crypto::create_parallel_write(file, cipher, &key, pool.clone());
+                for _ in 0..LEN / CHUNK_LEN {
+                    writer.write_all(&chunk).unwrap();
+                }
+                black_box(writer.finish().unwrap());
+            });
+        });
+    }
+    group.finish();
+}
+
+fn bench_read_1gb(c: &mut Criterion) {
+    let cipher = Cipher::ChaCha20Poly1305;
+    let key = create_key(cipher);
+    let file = tempfile::tempfile().unwrap();
+    let mut writer = crypto::create_write(file, cipher, &key);
+    let mut chunk = vec![0; CHUNK_LEN];
+    rand::thread_rng().fill_bytes(&mut chunk);
+    for _ in 0..LEN / CHUNK_LEN {
+        writer.write_all(&chunk).unwrap();
+    }
+    let file = writer.finish().unwrap();
+
+    let mut group = c.benchmark_group("bench_read_1gb_chacha");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(LEN as u64));
+    for threads in THREADS {
+        let pool = Arc::new(CryptoPool::new(threads));
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |b, _| {
+            b.iter(|| {
+                let mut file = file.try_clone().unwrap();
+                io::Seek::rewind(&mut file).unwrap();
+                let mut reader = crypto::create_parallel_read(file, cipher, &key, pool.clone());
+                let mut buf = vec![0; CHUNK_LEN];
+                while stream_util::read(&mut reader, &mut buf).unwrap() > 0 {
+                    black_box(&buf);
+                }
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_write_1gb, bench_read_1gb);
+criterion_main!(benches);
@@ -0,0 +1,79 @@
+use std::str::FromStr;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::Rng;
+use rencfs::crypto::Cipher;
+use rencfs::encryptedfs::{CreateFileAttr, EncryptedFs, FileType, FsOptions, ROOT_INODE};
+use rencfs::password::StaticPasswordProvider;
+use shush_rs::SecretString;
+
+const WRITE_LEN: usize = 4 * 1024;
+const FILE_SIZES: [u64; 4] = [
+    1024 * 1024,
+    16 * 1024 * 1024,
+    64 * 1024 * 1024,
+    256 * 1024 * 1024,
+];
+
+const fn create_attr() -> CreateFileAttr {
+    CreateFileAttr {
+        kind: FileType::RegularFile,
+        perm: 0o644,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: 0,
+        compress: None,
+    }
+}
+
+/// Random 4K writes in files of increasing size. The contents are encrypted in blocks, so a write only re-encrypts
+/// the blocks it touches and the time of a write should be the same for all sizes.
+fn bench_random_write(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let fs = rt
+        .block_on(EncryptedFs::new(
+            data_dir.path().to_path_buf(),
+            Box::new(StaticPasswordProvider(
+                SecretString::from_str("password").unwrap(),
+            )),
+            Cipher::ChaCha20Poly1305,
+            false,
+            FsOptions::default(),
+        ))
+        .unwrap();
+    let data = [42_u8; WRITE_LEN];
+
+    let mut group = c.benchmark_group("bench_random_write_4k_chacha");
+    group.throughput(Throughput::Bytes(WRITE_LEN as u64));
+    for file_size in FILE_SIZES {
+        let name = SecretString::from_str(&format!("file-{file_size}")).unwrap();
+        let (fh, attr) = rt
+            .block_on(fs.create(ROOT_INODE, &name, create_attr(), false, true))
+            .unwrap();
+        // extend to the full size by writing at the end
+        rt.block_on(async {
+            fs.write(attr.ino, file_size - 1, &[0], fh).await.unwrap();
+            fs.flush(fh).await.unwrap();
+        });
+
+        let mut rnd = rand::thread_rng();
+        group.bench_with_input(
+            BenchmarkId::from_parameter(file_size),
+            &file_size,
+            |b, file_size| {
+                b.iter(|| {
+                    let offset = rnd.gen_range(0..file_size - WRITE_LEN as u64);
+                    let len = rt.block_on(fs.write(attr.ino, offset, &data, fh)).unwrap();
+                    black_box(len);
+                });
+            },
+        );
+        rt.block_on(fs.release(fh)).unwrap();
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_random_write);
+criterion_main!(benches);
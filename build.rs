@@ -0,0 +1,5 @@
+fn main() {
+    // WinFsp is found and loaded at runtime, the binaries only need to delay load its DLL
+    #[cfg(all(target_os = "windows", feature = "winfsp"))]
+    winfsp::build::winfsp_link_delayload();
+}
@@ -6,8 +6,7 @@ use anyhow::Result;
 use shush_rs::SecretString;
 
 use rencfs::crypto::Cipher;
-use rencfs::encryptedfs::write_all_string_to_fs;
-use rencfs::encryptedfs::{CreateFileAttr, EncryptedFs, FileType, PasswordProvider};
+use rencfs::encryptedfs::{CreateFileAttr, EncryptedFs, FileType, FsOptions, PasswordProvider};
 
 const ROOT_INODE: u64 = 1;
 
@@ -32,6 +31,7 @@ async fn main() -> Result<()> {
         Box::new(PasswordProviderImpl {}),
         cipher,
         false,
+        FsOptions::default(),
     )
     .await?;
 
@@ -40,7 +40,7 @@ async fn main() -> Result<()> {
         .create(ROOT_INODE, &file1, file_attr(), false, true)
         .await?;
     let data = "Hello, world!";
-    write_all_string_to_fs(&fs, attr.ino, 0, data, fh).await?;
+    fs.write_all(attr.ino, 0, data.as_bytes(), fh).await?;
     fs.flush(fh).await?;
     fs.release(fh).await?;
     let fh = fs.open(attr.ino, true, false).await?;
@@ -62,5 +62,6 @@ const fn file_attr() -> CreateFileAttr {
         gid: 0,
         rdev: 0,
         flags: 0,
+        compress: None,
     }
 }
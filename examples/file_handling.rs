@@ -3,9 +3,7 @@ use core::str::FromStr;
 use anyhow::Result;
 use rencfs::{
     crypto::Cipher,
-    encryptedfs::{
-        write_all_string_to_fs, CreateFileAttr, EncryptedFs, FileType, PasswordProvider,
-    },
+    encryptedfs::{CreateFileAttr, EncryptedFs, FileType, FsOptions, PasswordProvider},
 };
 use shush_rs::SecretString;
 use std::{
@@ -36,6 +34,7 @@ async fn main() -> Result<()> {
         Box::new(PasswordProviderImpl),
         cipher,
         false,
+        FsOptions::default(),
     )
     .await?;
 
@@ -45,7 +44,8 @@ async fn main() -> Result<()> {
         .await?;
 
     let data = "Hello, world!";
-    write_all_string_to_fs(&fs, attr.ino, 0, data, file_handle).await?;
+    fs.write_all(attr.ino, 0, data.as_bytes(), file_handle)
+        .await?;
 
     fs.flush(file_handle).await?;
     fs.release(file_handle).await?;
@@ -57,9 +57,9 @@ async fn main() -> Result<()> {
 
     assert_eq!(data, String::from_utf8(buffer)?);
 
-    assert!(fs.exists_by_name(ROOT_INODE, &file_name)?);
+    assert!(fs.exists_by_name(ROOT_INODE, &file_name).await?);
     fs.remove_file(ROOT_INODE, &file_name).await?;
-    assert!(!fs.exists_by_name(ROOT_INODE, &file_name)?);
+    assert!(!fs.exists_by_name(ROOT_INODE, &file_name).await?);
 
     clean_up_directory(&data_dir)?;
 
@@ -69,11 +69,12 @@ async fn main() -> Result<()> {
 const fn file_attributes() -> CreateFileAttr {
     CreateFileAttr {
         kind: FileType::RegularFile,
-        perm: 0o644, // Permissions
-        uid: 0,      // User ID
-        gid: 0,      // Group ID
-        rdev: 0,     // Device ID
-        flags: 0,    // File flags
+        perm: 0o644,    // Permissions
+        uid: 0,         // User ID
+        gid: 0,         // Group ID
+        rdev: 0,        // Device ID
+        flags: 0,       // File flags
+        compress: None, // Compression, as set in FsOptions
     }
 }
 
@@ -9,6 +9,9 @@ set CARGO_BUILD_JOBS=14
 
 if %errorlevel% neq 0 exit /b %errorlevel%
 
+cargo build --all-targets --no-default-features
+if %errorlevel% neq 0 exit /b %errorlevel%
+
 cargo build --all-targets --all-features
 if %errorlevel% neq 0 exit /b %errorlevel%
 
@@ -10,21 +10,28 @@ export CARGO_BUILD_JOBS=14
 
 cargo fmt --all
 
+cargo build --all-targets --no-default-features
 cargo build --all-targets --all-features
 cargo build --release --all-targets --all-features
 cargo clippy --release --all-targets --fix --allow-dirty --allow-staged
 cargo fmt --all -- --check
 cargo check --all
-cargo clippy --all-targets --release -- \
-    -A clippy::similar_names \
-    -A clippy::too_many_arguments \
-    -A clippy::significant_drop_tightening \
-    -A clippy::redundant_closure \
-    -A clippy::missing_errors_doc \
-    -A clippy::type_complexity
+for features in --no-default-features --all-features; do
+  cargo clippy --all-targets --release $features -- \
+      -A clippy::similar_names \
+      -A clippy::too_many_arguments \
+      -A clippy::significant_drop_tightening \
+      -A clippy::redundant_closure \
+      -A clippy::missing_errors_doc \
+      -A clippy::type_complexity
+done
 cargo test --release --all --all-features
 cargo bench --workspace --all-targets --all-features -j 14
 cargo doc --workspace --all-features --no-deps
+cargo update -Z direct-minimal-versions
+cargo check --all-targets --no-default-features
+cargo check --all-targets --all-features
+cargo update
 
 # cargo publish --dry-run --allow-dirty
 
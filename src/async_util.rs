@@ -1,4 +1,8 @@
 use std::future::Future;
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
 use tokio::runtime::Handle;
 use tokio::task;
 
@@ -8,3 +12,50 @@ where
 {
     task::block_in_place(move || Handle::current().block_on(f))
 }
+
+/// Run `f` to completion from sync code, also when there is no runtime or it's a current thread one, where
+/// [`call_async`] can't block.
+///
+/// It runs on a new thread with its own runtime, so `f` must not wait for tasks of the caller's runtime.
+pub fn call_async_blocking<F>(f: F) -> F::Output
+where
+    F: Future + Send,
+    F::Output: Send,
+{
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .unwrap()
+                    .block_on(f)
+            })
+            .join()
+            .unwrap()
+    })
+}
+
+/// Run `f` to completion on the current thread, parking it while `f` is pending.
+///
+/// The runtime is not driven, so `f` must not wait on its tasks, the blocking threads are fine. It's meant for futures which are ready right away,
+/// like the ones of a [`Storage`](crate::storage::Storage) used from sync code.
+pub fn block_on<F: Future>(f: F) -> F::Output {
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut f = pin!(f);
+    loop {
+        if let Poll::Ready(output) = f.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
@@ -3,9 +3,10 @@ use std::io;
 use std::io::{Read, Seek, Write};
 use std::num::ParseIntError;
 use std::path::{Path, PathBuf};
-use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use argon2::Argon2;
+use argon2::{Algorithm, Argon2, Params, Version};
 use base64::alphabet::STANDARD;
 use base64::engine::general_purpose::NO_PAD;
 use base64::engine::GeneralPurpose;
@@ -14,20 +15,28 @@ use hex::FromHexError;
 use num_format::{Locale, ToFormattedString};
 use rand_chacha::rand_core::{CryptoRng, RngCore, SeedableRng};
 use rand_chacha::ChaCha20Rng;
-use ring::aead::{AES_256_GCM, CHACHA20_POLY1305};
+use ring::aead::{AES_256_GCM, CHACHA20_POLY1305, NONCE_LEN};
 use serde::{Deserialize, Serialize};
-use shush_rs::{ExposeSecret, SecretString, SecretVec};
+use shush_rs::{ExposeSecret, SecretBox, SecretString, SecretVec};
+use strum::IntoEnumIterator;
 use strum_macros::{Display, EnumIter, EnumString};
 use thiserror::Error;
 use tracing::{debug, error, instrument};
 use write::CryptoInnerWriter;
 
+use crate::crypto::block_key::{BlockKey, XNONCE_LEN};
+use crate::crypto::holes::SharedHoles;
+use crate::crypto::parallel::CryptoPool;
 use crate::crypto::read::{CryptoRead, CryptoReadSeek, RingCryptoRead};
-use crate::crypto::write::{CryptoWrite, CryptoWriteSeek, RingCryptoWrite};
+use crate::crypto::write::{CryptoWrite, CryptoWriteSeek, RingCryptoWrite, BLOCK_SIZE};
 use crate::encryptedfs::FsResult;
 use crate::{fs_util, stream_util};
 
+pub(crate) mod base32;
+mod block_key;
 pub mod buf_mut;
+pub mod holes;
+pub mod parallel;
 pub mod read;
 pub mod write;
 
@@ -36,9 +45,12 @@ pub static BASE64: GeneralPurpose = GeneralPurpose::new(&STANDARD, NO_PAD);
 #[derive(
     Debug, Clone, Copy, EnumIter, EnumString, Display, Serialize, Deserialize, PartialEq, Eq,
 )]
+// the index of the variant is serialized, like in the key slots, add the new ones at the end
 pub enum Cipher {
     ChaCha20Poly1305,
     Aes256Gcm,
+    /// With a 192-bit nonce, so random nonces don't repeat even after encrypting a lot of blocks with the same key.
+    XChaCha20Poly1305,
 }
 
 impl Cipher {
@@ -47,7 +59,7 @@ impl Cipher {
     #[allow(clippy::use_self)]
     pub fn key_len(&self) -> usize {
         match self {
-            Cipher::ChaCha20Poly1305 => CHACHA20_POLY1305.key_len(),
+            Cipher::ChaCha20Poly1305 | Cipher::XChaCha20Poly1305 => CHACHA20_POLY1305.key_len(),
             Cipher::Aes256Gcm => AES_256_GCM.key_len(),
         }
     }
@@ -57,10 +69,126 @@ impl Cipher {
     #[allow(clippy::use_self)]
     pub const fn max_plaintext_len(&self) -> usize {
         match self {
-            Cipher::ChaCha20Poly1305 => (2_usize.pow(32) - 1) * 64,
+            Cipher::ChaCha20Poly1305 | Cipher::XChaCha20Poly1305 => (2_usize.pow(32) - 1) * 64,
             Cipher::Aes256Gcm => (2_usize.pow(39) - 256) / 8,
         }
     }
+
+    /// Estimate of how many plaintext bytes fit in `ciphertext_len` bytes, each block carries its own nonce and tag.
+    #[must_use]
+    #[allow(clippy::use_self)]
+    pub fn plaintext_len(&self, ciphertext_len: u64) -> u64 {
+        let ciphertext_block_size = self.ciphertext_block_size();
+        let overhead = ciphertext_block_size - BLOCK_SIZE as u64;
+        ciphertext_len / ciphertext_block_size * BLOCK_SIZE as u64
+            + (ciphertext_len % ciphertext_block_size).saturating_sub(overhead)
+    }
+
+    /// Size (in bytes) of an encrypted block on disk, including its nonce and tag.
+    #[must_use]
+    #[allow(clippy::use_self)]
+    pub fn ciphertext_block_size(&self) -> u64 {
+        let tag_len = match self {
+            Cipher::ChaCha20Poly1305 | Cipher::XChaCha20Poly1305 => CHACHA20_POLY1305.tag_len(),
+            Cipher::Aes256Gcm => AES_256_GCM.tag_len(),
+        };
+        (self.nonce_len() + BLOCK_SIZE + tag_len) as u64
+    }
+
+    /// In bytes, each block has its own random nonce.
+    #[must_use]
+    #[allow(clippy::use_self)]
+    pub const fn nonce_len(&self) -> usize {
+        match self {
+            Cipher::ChaCha20Poly1305 | Cipher::Aes256Gcm => NONCE_LEN,
+            Cipher::XChaCha20Poly1305 => XNONCE_LEN,
+        }
+    }
+
+    /// The faster cipher on this CPU, [`Cipher::Aes256Gcm`] if it has AES instructions, see
+    /// [`has_aes_acceleration`], else [`Cipher::ChaCha20Poly1305`].
+    #[must_use]
+    pub fn recommended() -> Self {
+        if has_aes_acceleration() {
+            Self::Aes256Gcm
+        } else {
+            Self::ChaCha20Poly1305
+        }
+    }
+
+    /// Measure how fast each cipher encrypts blocks on this CPU, for about `duration` each.
+    ///
+    /// A random key is used and nothing is kept, it's only to show the choice to the user.
+    #[must_use]
+    #[allow(clippy::missing_panics_doc)]
+    pub fn benchmark(duration: Duration) -> Vec<CipherThroughput> {
+        Self::iter()
+            .map(|cipher| {
+                let mut key = vec![0; cipher.key_len()];
+                create_rng().fill_bytes(&mut key);
+                let key = BlockKey::new(cipher, &SecretVec::from(key));
+                let mut block = vec![0; BLOCK_SIZE];
+                let mut bytes = 0_u64;
+                let start = Instant::now();
+                let mut counter = 0_u64;
+                while start.elapsed() < duration {
+                    let mut nonce = vec![0; cipher.nonce_len()];
+                    nonce[..8].copy_from_slice(&counter.to_le_bytes());
+                    counter += 1;
+                    key.seal_in_place_separate_tag(&nonce, &[], &mut block)
+                        .unwrap();
+                    bytes += block.len() as u64;
+                }
+                CipherThroughput {
+                    cipher,
+                    bytes_per_sec: bytes as f64 / start.elapsed().as_secs_f64(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// How fast a [`Cipher`] encrypts, from [`Cipher::benchmark`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CipherThroughput {
+    pub cipher: Cipher,
+    /// Plaintext bytes encrypted per second.
+    pub bytes_per_sec: f64,
+}
+
+/// The cipher to open a data dir with, any [`Cipher`] converts into a [`CipherChoice::Fixed`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CipherChoice {
+    /// [`Cipher::recommended`] for a new data dir, the one it was created with for an existing one.
+    #[default]
+    Auto,
+    Fixed(Cipher),
+}
+
+impl From<Cipher> for CipherChoice {
+    fn from(cipher: Cipher) -> Self {
+        Self::Fixed(cipher)
+    }
+}
+
+/// If the CPU has the AES instructions, and the carry-less multiplication GCM needs, so AES-GCM is faster than
+/// ChaCha20-Poly1305.
+#[must_use]
+pub fn has_aes_acceleration() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        std::arch::is_x86_feature_detected!("aes")
+            && std::arch::is_x86_feature_detected!("pclmulqdq")
+    }
+    // includes the polynomial multiplication
+    #[cfg(target_arch = "aarch64")]
+    {
+        std::arch::is_aarch64_feature_detected!("aes")
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        false
+    }
 }
 
 #[derive(Debug, Error)]
@@ -115,7 +243,17 @@ pub fn create_write<W: CryptoInnerWriter + Send + Sync + 'static>(
     cipher: Cipher,
     key: &SecretVec<u8>,
 ) -> impl CryptoWrite<W> {
-    create_ring_write(writer, cipher, key)
+    create_ring_write(writer, cipher, key, &[])
+}
+
+/// Creates an encrypted writer which authenticates `aad` with the content, read it with [`create_read_with_aad`]
+pub fn create_write_with_aad<W: CryptoInnerWriter + Send + Sync + 'static>(
+    writer: W,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    aad: &[u8],
+) -> impl CryptoWrite<W> {
+    create_ring_write(writer, cipher, key, aad)
 }
 
 /// Creates an encrypted writer with seek
@@ -124,55 +262,75 @@ pub fn create_write_seek<W: CryptoInnerWriter + Seek + Read + Send + Sync + 'sta
     cipher: Cipher,
     key: &SecretVec<u8>,
 ) -> impl CryptoWriteSeek<W> {
-    create_ring_write_seek(writer, cipher, key)
+    create_ring_write_seek(writer, cipher, key, &[])
+}
+
+/// Like [`create_write`], but the blocks of zeros are written as holes and kept in `holes`, read it with
+/// [`create_sparse_read`] and the same holes, see [`RingCryptoWrite::sparse`]
+pub fn create_sparse_write<W: CryptoInnerWriter + Send + Sync + 'static>(
+    writer: W,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    holes: SharedHoles,
+) -> impl CryptoWrite<W> {
+    create_ring_write(writer, cipher, key, &[]).sparse(holes)
+}
+
+/// Like [`create_write_seek`], but seeking past the end and the blocks of zeros leave holes, kept in `holes`, read
+/// it with [`create_sparse_read_seek`] and the same holes, see [`RingCryptoWrite::sparse`]
+pub fn create_sparse_write_seek<W: CryptoInnerWriter + Seek + Read + Send + Sync + 'static>(
+    writer: W,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    holes: SharedHoles,
+) -> impl CryptoWriteSeek<W> {
+    create_ring_write_seek(writer, cipher, key, &[]).sparse(holes)
+}
+
+/// Like [`create_write`], but large writes are encrypted on the threads of `pool`, see [`CryptoPool`]
+pub fn create_parallel_write<W: CryptoInnerWriter + Send + Sync + 'static>(
+    writer: W,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    pool: Arc<CryptoPool>,
+) -> impl CryptoWrite<W> {
+    create_ring_write(writer, cipher, key, &[]).parallel(pool)
 }
 
-fn create_ring_write<W: CryptoInnerWriter + Send + Sync>(
+pub(crate) fn create_ring_write<W: CryptoInnerWriter + Send + Sync>(
     writer: W,
     cipher: Cipher,
     key: &SecretVec<u8>,
+    aad: &[u8],
 ) -> RingCryptoWrite<W> {
-    let algorithm = match cipher {
-        Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
-        Cipher::Aes256Gcm => &AES_256_GCM,
-    };
-    RingCryptoWrite::new(writer, false, algorithm, key)
+    RingCryptoWrite::with_key(writer, false, BlockKey::new(cipher, key), aad)
 }
 
-fn create_ring_write_seek<W: CryptoInnerWriter + Seek + Read + Send + Sync>(
+pub(crate) fn create_ring_write_seek<W: CryptoInnerWriter + Seek + Read + Send + Sync>(
     writer: W,
     cipher: Cipher,
     key: &SecretVec<u8>,
+    aad: &[u8],
 ) -> RingCryptoWrite<W> {
-    let algorithm = match cipher {
-        Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
-        Cipher::Aes256Gcm => &AES_256_GCM,
-    };
-    RingCryptoWrite::new(writer, true, algorithm, key)
+    RingCryptoWrite::with_key(writer, true, BlockKey::new(cipher, key), aad)
 }
 
-fn create_ring_read<R: Read + Send + Sync>(
+pub(crate) fn create_ring_read<R: Read + Send + Sync>(
     reader: R,
     cipher: Cipher,
     key: &SecretVec<u8>,
+    aad: &[u8],
 ) -> RingCryptoRead<R> {
-    let algorithm = match cipher {
-        Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
-        Cipher::Aes256Gcm => &AES_256_GCM,
-    };
-    RingCryptoRead::new(reader, algorithm, key)
+    RingCryptoRead::with_key(reader, BlockKey::new(cipher, key), aad)
 }
 
-fn create_ring_read_seek<R: Read + Seek + Send + Sync>(
+pub(crate) fn create_ring_read_seek<R: Read + Seek + Send + Sync>(
     reader: R,
     cipher: Cipher,
     key: &SecretVec<u8>,
+    aad: &[u8],
 ) -> RingCryptoRead<R> {
-    let algorithm = match cipher {
-        Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
-        Cipher::Aes256Gcm => &AES_256_GCM,
-    };
-    RingCryptoRead::new_seek(reader, algorithm, key)
+    RingCryptoRead::with_key(reader, BlockKey::new(cipher, key), aad)
 }
 
 /// Creates an encrypted reader
@@ -181,7 +339,17 @@ pub fn create_read<R: Read + Send + Sync>(
     cipher: Cipher,
     key: &SecretVec<u8>,
 ) -> impl CryptoRead<R> {
-    create_ring_read(reader, cipher, key)
+    create_ring_read(reader, cipher, key, &[])
+}
+
+/// Creates an encrypted reader for content written with [`create_write_with_aad`] with the same `aad`
+pub fn create_read_with_aad<R: Read + Send + Sync>(
+    reader: R,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    aad: &[u8],
+) -> impl CryptoRead<R> {
+    create_ring_read(reader, cipher, key, aad)
 }
 
 /// Creates an encrypted reader with seek
@@ -190,23 +358,60 @@ pub fn create_read_seek<R: Read + Seek + Send + Sync>(
     cipher: Cipher,
     key: &SecretVec<u8>,
 ) -> impl CryptoReadSeek<R> {
-    create_ring_read_seek(reader, cipher, key)
+    create_ring_read_seek(reader, cipher, key, &[])
+}
+
+/// Creates an encrypted reader for content written with [`create_sparse_write`], the blocks in `holes` read as
+/// zeros, see [`RingCryptoRead::sparse`]
+pub fn create_sparse_read<R: Read + Send + Sync>(
+    reader: R,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    holes: SharedHoles,
+) -> impl CryptoRead<R> {
+    create_ring_read(reader, cipher, key, &[]).sparse(holes)
+}
+
+/// Like [`create_read`], but large reads are decrypted on the threads of `pool`, see [`CryptoPool`]
+pub fn create_parallel_read<R: Read + Send + Sync>(
+    reader: R,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    pool: Arc<CryptoPool>,
+) -> impl CryptoRead<R> {
+    create_ring_read(reader, cipher, key, &[]).parallel(pool)
+}
+
+/// Creates an encrypted reader with seek for content written with [`create_sparse_write_seek`], with its `holes`
+pub fn create_sparse_read_seek<R: Read + Seek + Send + Sync>(
+    reader: R,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    holes: SharedHoles,
+) -> impl CryptoReadSeek<R> {
+    create_ring_read_seek(reader, cipher, key, &[]).sparse(holes)
 }
 
 #[allow(clippy::missing_errors_doc)]
 pub fn encrypt(s: &SecretString, cipher: Cipher, key: &SecretVec<u8>) -> Result<String> {
+    Ok(BASE64.encode(encrypt_to_vec(s, cipher, key)?))
+}
+
+#[allow(clippy::missing_panics_doc)]
+#[allow(clippy::missing_errors_doc)]
+pub fn decrypt(s: &str, cipher: Cipher, key: &SecretVec<u8>) -> Result<SecretString> {
+    decrypt_from_vec(BASE64.decode(s)?, cipher, key)
+}
+
+fn encrypt_to_vec(s: &SecretString, cipher: Cipher, key: &SecretVec<u8>) -> Result<Vec<u8>> {
     let mut cursor = io::Cursor::new(vec![]);
     let mut writer = create_write(cursor, cipher, key);
     writer.write_all(s.expose_secret().as_bytes())?;
     cursor = writer.finish()?;
-    let v = cursor.into_inner();
-    Ok(BASE64.encode(v))
+    Ok(cursor.into_inner())
 }
 
-#[allow(clippy::missing_panics_doc)]
-#[allow(clippy::missing_errors_doc)]
-pub fn decrypt(s: &str, cipher: Cipher, key: &SecretVec<u8>) -> Result<SecretString> {
-    let vec = BASE64.decode(s)?;
+fn decrypt_from_vec(vec: Vec<u8>, cipher: Cipher, key: &SecretVec<u8>) -> Result<SecretString> {
     let cursor = io::Cursor::new(vec);
 
     let mut reader = create_read(cursor, cipher, key);
@@ -215,58 +420,181 @@ pub fn decrypt(s: &str, cipher: Cipher, key: &SecretVec<u8>) -> Result<SecretStr
     Ok(SecretString::new(Box::new(decrypted)))
 }
 
+/// Decrypt a name encrypted by [`encrypt_file_name`], without the padding it may have.
 #[allow(clippy::missing_errors_doc)]
 pub fn decrypt_file_name(name: &str, cipher: Cipher, key: &SecretVec<u8>) -> Result<SecretString> {
-    let name = String::from(name).replace('|', "/");
-    decrypt(&name, cipher, key)
+    let mut name = decrypt_padded_file_name(name, cipher, key)?;
+    let len = name.expose_secret().trim_end_matches(NAME_PADDING).len();
+    name.expose_secret_mut().truncate(len);
+    Ok(name)
+}
+
+/// Like [`decrypt_file_name`], but it keeps the padding, to encrypt the name again the same way.
+#[allow(clippy::missing_errors_doc)]
+pub fn decrypt_padded_file_name(
+    name: &str,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> Result<SecretString> {
+    let encrypted = base32::decode(name).ok_or(Error::Generic("invalid encrypted file name"))?;
+    decrypt_from_vec(encrypted, cipher, key)
+}
+
+/// Encode like [`encrypt_file_name`] a name encrypted by the data dirs up to version `9`, which were base64 with `|`
+/// instead of `/`, without decrypting it.
+#[allow(clippy::missing_errors_doc)]
+pub fn reencode_legacy_file_name(name: &str) -> Result<String> {
+    match name {
+        "$." => Ok(SELF_ENTRY_NAME.to_owned()),
+        "$.." => Ok(PARENT_ENTRY_NAME.to_owned()),
+        _ => Ok(base32::encode(&BASE64.decode(name.replace('|', "/"))?)),
+    }
 }
 
 #[instrument(skip(password, salt))]
 #[allow(clippy::missing_errors_doc)]
 pub fn derive_key(password: &SecretString, cipher: Cipher, salt: &[u8]) -> Result<SecretVec<u8>> {
+    derive_key_with_params(password, cipher, salt, Params::default())
+}
+
+/// Like [`derive_key`] with the Argon2 `params` instead of the default ones.
+#[instrument(skip(password, salt))]
+#[allow(clippy::missing_errors_doc)]
+pub fn derive_key_with_params(
+    password: &SecretString,
+    cipher: Cipher,
+    salt: &[u8],
+    params: Params,
+) -> Result<SecretVec<u8>> {
     let mut dk = vec![];
     let key_len = cipher.key_len();
     dk.resize(key_len, 0);
-    Argon2::default()
+    Argon2::new(Algorithm::default(), Version::default(), params)
         .hash_password_into(password.expose_secret().as_bytes(), salt, &mut dk)
         .map_err(|err| Error::GenericString(err.to_string()))?;
     Ok(SecretVec::new(Box::new(dk)))
 }
 
+/// Encrypt `name` and encode it in lowercase base32, so it's a valid name on any filesystem, even a case-insensitive
+/// one. `.` and `..` are not encrypted, they are [`SELF_ENTRY_NAME`] and [`PARENT_ENTRY_NAME`].
 #[allow(clippy::missing_errors_doc)]
 pub fn encrypt_file_name(
     name: &SecretString,
     cipher: Cipher,
     key: &SecretVec<u8>,
+) -> FsResult<String> {
+    encrypt_file_name_with_padding(name, 0, cipher, key)
+}
+
+/// Like [`encrypt_file_name`], but `name` is padded to the next multiple of `padding` bytes before it's encrypted,
+/// so the encrypted names don't give away the exact length of the names. Zero or one doesn't pad it.
+///
+/// The padding is [`NAME_PADDING`], which a name can't have, [`decrypt_file_name`] removes it. The encrypted name is
+/// longer than [`MAX_NAME_COMPONENT_LEN`] if `name` is longer than [`max_file_name_len`].
+#[allow(clippy::missing_errors_doc)]
+pub fn encrypt_file_name_with_padding(
+    name: &SecretString,
+    padding: usize,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
 ) -> FsResult<String> {
     let secret_string = name.expose_secret();
+    if let Some(special) = special_entry_name(&secret_string) {
+        return Ok(special.to_owned());
+    }
+    let padded_len = padded_name_len(secret_string.len(), padding);
+    let mut padded = String::with_capacity(padded_len);
+    padded.push_str(&secret_string);
+    padded.extend(std::iter::repeat(NAME_PADDING).take(padded_len - secret_string.len()));
+    let secret = SecretString::new(Box::new(padded));
+    Ok(base32::encode(&encrypt_to_vec(&secret, cipher, key)?))
+}
 
-    match secret_string.as_str() {
-        "$." | "$.." => Ok(secret_string.clone()),
-        "." | ".." => Ok(format!("${secret_string}")),
-        _ => {
-            let secret = SecretString::from_str(&secret_string)
-                .map_err(|err| Error::GenericString(err.to_string()))?;
-            let mut encrypted = encrypt(&secret, cipher, key)?;
-            encrypted = encrypted.replace('/', "|");
+/// Name in the storage of the entry of a directory for itself, it's not encrypted.
+///
+/// The names of the entries in the storage don't end with a dot, Windows drops it.
+pub const SELF_ENTRY_NAME: &str = "$self";
 
-            Ok(encrypted)
-        }
+/// Name in the storage of the entry of a directory for its parent, see [`SELF_ENTRY_NAME`].
+pub const PARENT_ENTRY_NAME: &str = "$parent";
+
+/// [`SELF_ENTRY_NAME`] for `.` and [`PARENT_ENTRY_NAME`] for `..`, the entries are named `$.` and `$..` too.
+fn special_entry_name(name: &str) -> Option<&'static str> {
+    match name {
+        "." | "$." => Some(SELF_ENTRY_NAME),
+        ".." | "$.." => Some(PARENT_ENTRY_NAME),
+        _ => None,
     }
 }
 
-#[allow(clippy::missing_errors_doc)]
+/// Max length in bytes of a name in the storage, which is the limit of most filesystems for a path component.
+pub const MAX_NAME_COMPONENT_LEN: usize = 255;
+
+/// What the names are padded with by [`encrypt_file_name_with_padding`].
+pub const NAME_PADDING: char = '\0';
+
+/// Max length in bytes of a name whose encrypted name by [`encrypt_file_name_with_padding`] with `padding` and
+/// `cipher` fits in [`MAX_NAME_COMPONENT_LEN`].
+///
+/// The encrypted name has the nonce and the tag of its block and is base32 encoded without padding, so it's 8/5 of
+/// that, which must fit in [`MAX_NAME_COMPONENT_LEN`]. That's 131 bytes with the ciphers having a 96-bit nonce and
+/// 119 with [`Cipher::XChaCha20Poly1305`], without padding.
 #[must_use]
-pub fn hash_file_name(name: &SecretString) -> String {
-    if *name.expose_secret() == "$." || *name.expose_secret() == "$.." {
-        name.expose_secret().clone()
-    } else if *name.expose_secret() == "." || *name.expose_secret() == ".." {
-        format!("${}", name.expose_secret())
+pub fn max_file_name_len(cipher: Cipher, padding: usize) -> usize {
+    let tag_len = cipher.ciphertext_block_size() as usize - cipher.nonce_len() - BLOCK_SIZE;
+    let max_encrypted_len = MAX_NAME_COMPONENT_LEN * 5 / 8;
+    let max_len = max_encrypted_len - cipher.nonce_len() - tag_len;
+    if padding > 1 {
+        max_len / padding * padding
     } else {
-        hex::encode(hash_secret_string(name))
+        max_len
     }
 }
 
+fn padded_name_len(len: usize, padding: usize) -> usize {
+    if padding > 1 {
+        len.div_ceil(padding) * padding
+    } else {
+        len
+    }
+}
+
+/// Length of the salt each directory has for [`hash_file_name`].
+pub const NAME_SALT_LEN: usize = 32;
+
+/// Context to derive the key of [`hash_file_name`] from the master key.
+const NAME_HASH_KEY_CONTEXT: &str = "rencfs 2024-08-01 file name hash";
+
+/// Hash of `name` used as the file name of its entry in the directory with `salt`.
+///
+/// It's keyed with a key derived from the master `key` and mixed with the `salt` of the directory, so without the key
+/// the hashes can't confirm a guessed name, and the same name has different hashes in different directories.
+#[must_use]
+pub fn hash_file_name(name: &SecretString, salt: &[u8], key: &SecretVec<u8>) -> String {
+    if let Some(special) = special_entry_name(&name.expose_secret()) {
+        special.to_owned()
+    } else {
+        let mut name_key = SecretBox::new(Box::new([0_u8; 32]));
+        blake3::derive_key(
+            NAME_HASH_KEY_CONTEXT,
+            &key.expose_secret(),
+            &mut name_key.expose_secret_mut()[..],
+        );
+        let mut hasher = blake3::Hasher::new_keyed(&name_key.expose_secret());
+        hasher.update(salt);
+        hasher.update(name.expose_secret().as_bytes());
+        hex::encode(hasher.finalize().as_bytes())
+    }
+}
+
+/// Random salt for a new directory, see [`hash_file_name`].
+#[must_use]
+pub fn create_name_salt() -> [u8; NAME_SALT_LEN] {
+    let mut salt = [0; NAME_SALT_LEN];
+    create_rng().fill_bytes(&mut salt);
+    salt
+}
+
 #[must_use]
 pub fn hash(data: &[u8]) -> [u8; 32] {
     let mut hasher = blake3::Hasher::new();
@@ -352,29 +680,32 @@ pub fn serialize_encrypt_into<W, T>(
     value: &T,
     cipher: Cipher,
     key: &SecretVec<u8>,
+    aad: &[u8],
 ) -> Result<W>
 where
     W: CryptoInnerWriter + Send + Sync + 'static,
     T: serde::Serialize + ?Sized,
 {
-    let mut writer = create_write(writer, cipher, key);
+    let mut writer = create_write_with_aad(writer, cipher, key, aad);
     bincode::serialize_into(&mut writer, value)?;
     let writer = writer.finish()?;
     Ok(writer)
 }
 
+/// Serialize and encrypt `value` into `file` atomically, `aad` is authenticated with it, see [`create_write_with_aad`].
 pub fn atomic_serialize_encrypt_into<T>(
     file: &Path,
     value: &T,
     cipher: Cipher,
     key: &SecretVec<u8>,
+    aad: &[u8],
 ) -> Result<()>
 where
     T: serde::Serialize + ?Sized,
 {
     let parent = file.parent().ok_or(Error::Generic("file has no parent"))?;
     let mut file = fs_util::open_atomic_write(file)?;
-    file = serialize_encrypt_into(file, value, cipher, key)?;
+    file = serialize_encrypt_into(file, value, cipher, key, aad)?;
     file.commit()?;
     File::open(parent)?.sync_all()?;
     Ok(())
@@ -386,6 +717,8 @@ mod tests {
 
     use rand_core::RngCore;
     use shush_rs::{ExposeSecret, SecretString, SecretVec};
+    use std::collections::HashSet;
+    use std::str::FromStr;
     use std::{
         fs::File,
         io::{self, Write},
@@ -428,11 +761,27 @@ mod tests {
         SecretVec::new(Box::new(key))
     }
 
+    #[test]
+    fn test_recommended_cipher() {
+        // AES-GCM only with hardware support
+        assert_eq!(
+            has_aes_acceleration(),
+            Cipher::recommended() == Cipher::Aes256Gcm
+        );
+
+        let results = Cipher::benchmark(Duration::from_millis(20));
+        assert_eq!(
+            Cipher::iter().collect::<Vec<_>>(),
+            results.iter().map(|r| r.cipher).collect::<Vec<_>>()
+        );
+        assert!(results.iter().all(|r| r.bytes_per_sec > 0.0));
+    }
+
     #[test]
     fn test_simple_encrypt_and_decrypt() {
         let secret = SecretString::from_str("Test secret").unwrap();
 
-        for &cipher in &[Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
+        for cipher in Cipher::iter() {
             let key = secret_key(cipher);
 
             let encrypted = encrypt(&secret, cipher, &key).unwrap();
@@ -445,7 +794,7 @@ mod tests {
     fn test_encrypt_and_decrypt_file_name() {
         let secret_name = SecretString::from_str("testfile.txt").unwrap();
 
-        for &cipher in &[Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
+        for cipher in Cipher::iter() {
             let key = secret_key(cipher);
             let encrypted = encrypt_file_name(&secret_name, cipher, &key).unwrap();
             let decrypted = decrypt_file_name(&encrypted, cipher, &key).unwrap();
@@ -454,7 +803,7 @@ mod tests {
 
         let secret_name = SecretString::from_str("testfile\\With/slash.txt").unwrap();
 
-        for &cipher in &[Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
+        for cipher in Cipher::iter() {
             let key = secret_key(cipher);
             let encrypted = encrypt_file_name(&secret_name, cipher, &key).unwrap();
             let decrypted = decrypt_file_name(&encrypted, cipher, &key).unwrap();
@@ -462,6 +811,65 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_file_names_are_portable() {
+        for cipher in Cipher::iter() {
+            let key = secret_key(cipher);
+            let mut folded = HashSet::new();
+            for name in [
+                "CON",
+                "nul.txt",
+                "a:b*c?d\"<e>|f",
+                "ends with dot.",
+                "ends with space ",
+                "Case",
+                "case",
+            ] {
+                let name = SecretString::from_str(name).unwrap();
+                let encrypted = encrypt_file_name(&name, cipher, &key).unwrap();
+                assert!(encrypted
+                    .bytes()
+                    .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit()));
+                assert!(folded.insert(encrypted.to_ascii_lowercase()));
+                // read back from a filesystem which changed its case
+                let decrypted =
+                    decrypt_file_name(&encrypted.to_ascii_uppercase(), cipher, &key).unwrap();
+                assert_eq!(decrypted.expose_secret(), name.expose_secret());
+            }
+        }
+    }
+
+    #[test]
+    fn test_reencode_legacy_file_name() {
+        for cipher in Cipher::iter() {
+            let key = secret_key(cipher);
+            // some have a `/` in base64
+            for i in 0..32 {
+                let name = SecretString::new(Box::new(format!("file-{i}")));
+                let legacy = encrypt(&name, cipher, &key).unwrap().replace('/', "|");
+                let encoded = reencode_legacy_file_name(&legacy).unwrap();
+                let decrypted = decrypt_file_name(&encoded, cipher, &key).unwrap();
+                assert_eq!(decrypted.expose_secret(), name.expose_secret());
+            }
+        }
+        assert_eq!(SELF_ENTRY_NAME, reencode_legacy_file_name("$.").unwrap());
+        assert_eq!(PARENT_ENTRY_NAME, reencode_legacy_file_name("$..").unwrap());
+    }
+
+    #[test]
+    fn test_base32() {
+        for len in 0..20 {
+            let bytes: Vec<u8> = (0..len).map(|i| (i * 37 + 11) as u8).collect();
+            let encoded = base32::encode(&bytes);
+            assert_eq!(base32::encoded_len(len), encoded.len());
+            assert_eq!(Some(bytes.clone()), base32::decode(&encoded));
+            assert_eq!(Some(bytes), base32::decode(&encoded.to_ascii_uppercase()));
+        }
+        assert_eq!(None, base32::decode("a"));
+        assert_eq!(None, base32::decode("ab1"));
+        assert_eq!(None, base32::decode("ab=="));
+    }
+
     #[test]
     fn test_encrypt_and_decrypt_file_name_invalid_cipher() {
         let key = secret_key(Cipher::ChaCha20Poly1305);
@@ -472,12 +880,54 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_encrypt_and_decrypt_padded_file_name() {
+        for cipher in Cipher::iter() {
+            let key = secret_key(cipher);
+            let short = SecretString::from_str("a.txt").unwrap();
+            let longer = SecretString::from_str("a longer name.txt").unwrap();
+            let encrypted_short = encrypt_file_name_with_padding(&short, 32, cipher, &key).unwrap();
+            let encrypted_longer =
+                encrypt_file_name_with_padding(&longer, 32, cipher, &key).unwrap();
+            assert_eq!(encrypted_short.len(), encrypted_longer.len());
+            let decrypted = decrypt_file_name(&encrypted_short, cipher, &key).unwrap();
+            assert_eq!(decrypted.expose_secret(), short.expose_secret());
+            let padded = decrypt_padded_file_name(&encrypted_short, cipher, &key).unwrap();
+            assert_eq!(padded.expose_secret().len(), 32);
+
+            // the longest names fit in a path component, one more byte doesn't
+            for padding in [0, 16, 32] {
+                let max_len = max_file_name_len(cipher, padding);
+                let name = SecretString::new(Box::new("a".repeat(max_len)));
+                let encrypted =
+                    encrypt_file_name_with_padding(&name, padding, cipher, &key).unwrap();
+                assert!(encrypted.len() <= MAX_NAME_COMPONENT_LEN);
+                let name = SecretString::new(Box::new("a".repeat(max_len + 1)));
+                let encrypted =
+                    encrypt_file_name_with_padding(&name, padding, cipher, &key).unwrap();
+                assert!(encrypted.len() > MAX_NAME_COMPONENT_LEN);
+            }
+        }
+    }
+
+    #[test]
+    fn test_plaintext_len() {
+        for cipher in Cipher::iter() {
+            let key = secret_key(cipher);
+            for len in [0, 1, BLOCK_SIZE - 1, BLOCK_SIZE, BLOCK_SIZE * 3 + 42] {
+                let (_temp_dir, path) = create_encrypted_file(&"a".repeat(len), cipher, &key);
+                let ciphertext_len = std::fs::metadata(path).unwrap().len();
+                assert_eq!(len as u64, cipher.plaintext_len(ciphertext_len));
+            }
+        }
+    }
+
     #[test]
     fn test_derive_key() {
         let password = SecretString::from_str("password").unwrap();
         let salt = b"salt_of_pass";
 
-        for &cipher in &[Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
+        for cipher in Cipher::iter() {
             let derived_key = derive_key(&password, cipher, salt).unwrap();
             assert_eq!(derived_key.expose_secret().len(), cipher.key_len());
         }
@@ -521,7 +971,7 @@ mod tests {
 
     #[test]
     fn test_encrypt_decrypt() {
-        for &cipher in &[Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
+        for cipher in Cipher::iter() {
             let key = secret_key(cipher);
 
             let data = SecretString::from_str("A").unwrap();
@@ -549,35 +999,36 @@ mod tests {
 
     #[test]
     fn test_hash_file_name_special_cases() {
-        let expected = "$.".to_owned();
-        let name = SecretString::new(Box::new(expected.clone()));
-        let result = hash_file_name(&name);
-        assert_eq!(result, expected);
-
-        let expected = "$..".to_owned();
-        let name = SecretString::new(Box::new(expected.clone()));
-        let result = hash_file_name(&name);
-        assert_eq!(result, expected);
-
-        let input = ".".to_owned();
-        let expected = "$.".to_owned();
-        let name = SecretString::new(Box::new(input));
-        let result = hash_file_name(&name);
-        assert_eq!(result, expected);
-
-        let input = "..".to_owned();
-        let expected = "$..".to_owned();
-        let name = SecretString::new(Box::new(input));
-        let result = hash_file_name(&name);
-        assert_eq!(result, expected);
+        let key = SecretVec::new(Box::new(vec![0; 32]));
+        let salt = create_name_salt();
+        for (input, expected) in [
+            ("$.", SELF_ENTRY_NAME),
+            ("$..", PARENT_ENTRY_NAME),
+            (".", SELF_ENTRY_NAME),
+            ("..", PARENT_ENTRY_NAME),
+        ] {
+            let name = SecretString::new(Box::new(input.to_owned()));
+            assert_eq!(hash_file_name(&name, &salt, &key), expected);
+            assert_eq!(
+                encrypt_file_name(&name, Cipher::ChaCha20Poly1305, &key).unwrap(),
+                expected
+            );
+        }
     }
 
     #[test]
     fn test_hash_file_name_regular_case() {
+        let key = SecretVec::new(Box::new(vec![0; 32]));
+        let salt = create_name_salt();
         let name = SecretString::new(Box::new("filename.txt".to_owned()));
-        let result = hash_file_name(&name);
-        let expected_hash = hex::encode(hash_secret_string(&name));
-        assert_eq!(result, expected_hash);
+        let result = hash_file_name(&name, &salt, &key);
+        assert_eq!(result, hash_file_name(&name, &salt, &key));
+        // not the plain hash of the name
+        assert_ne!(result, hex::encode(hash_secret_string(&name)));
+        // depends on the salt and the key
+        assert_ne!(result, hash_file_name(&name, &create_name_salt(), &key));
+        let other_key = SecretVec::new(Box::new(vec![1; 32]));
+        assert_ne!(result, hash_file_name(&name, &salt, &other_key));
     }
 
     #[test]
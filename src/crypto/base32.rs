@@ -0,0 +1,52 @@
+//! Base32 of the encrypted names, see [`encode`].
+//!
+//! It's RFC 4648 base32 in lowercase without padding. It has only letters and digits, so the names are valid on any
+//! filesystem, and no two of them differ only by case, so they stay apart on the case-insensitive ones.
+
+/// RFC 4648 base32 in lowercase.
+const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Length of the base32 of `len` bytes.
+pub(crate) const fn encoded_len(len: usize) -> usize {
+    (len * 8).div_ceil(5)
+}
+
+pub(crate) fn encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(encoded_len(bytes.len()));
+    let mut buffer = 0_u16;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | u16::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(char::from(ALPHABET[usize::from((buffer >> bits) & 0x1f)]));
+        }
+    }
+    if bits > 0 {
+        encoded.push(char::from(
+            ALPHABET[usize::from((buffer << (5 - bits)) & 0x1f)],
+        ));
+    }
+    encoded
+}
+
+/// Decode what [`encode`] made, in any case, `None` if it's not base32.
+#[allow(clippy::cast_possible_truncation)]
+pub(crate) fn decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(encoded.len() * 5 / 8);
+    let mut buffer = 0_u16;
+    let mut bits = 0;
+    for c in encoded.bytes() {
+        let index = ALPHABET.iter().position(|a| *a == c.to_ascii_lowercase())?;
+        buffer = (buffer << 5) | index as u16;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+        buffer &= (1 << bits) - 1;
+    }
+    // what is left are the zero bits completing the last letter
+    (bits < 5 && buffer == 0).then_some(bytes)
+}
@@ -0,0 +1,113 @@
+//! The key encrypting and authenticating the blocks, for each [`Cipher`], see [`BlockKey`].
+
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{Tag, XChaCha20Poly1305, XNonce};
+use ring::aead::{
+    Aad, Algorithm, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, CHACHA20_POLY1305, NONCE_LEN,
+};
+use ring::error::Unspecified;
+use shush_rs::{ExposeSecret, SecretVec};
+
+use crate::crypto::Cipher;
+
+/// Length of the nonce of [`Cipher::XChaCha20Poly1305`].
+pub(crate) const XNONCE_LEN: usize = 24;
+const XCHACHA_TAG_LEN: usize = 16;
+
+/// Seals and opens the blocks with the nonce given for each of them.
+///
+/// ring has the ciphers with a 96-bit nonce, XChaCha20-Poly1305 with its 192-bit nonce is from RustCrypto.
+pub(crate) enum BlockKey {
+    Ring(LessSafeKey),
+    XChaCha20Poly1305(Box<XChaCha20Poly1305>),
+}
+
+impl BlockKey {
+    pub(crate) fn new(cipher: Cipher, key: &SecretVec<u8>) -> Self {
+        match cipher {
+            Cipher::ChaCha20Poly1305 => Self::ring(&CHACHA20_POLY1305, key),
+            Cipher::Aes256Gcm => Self::ring(&AES_256_GCM, key),
+            Cipher::XChaCha20Poly1305 => Self::XChaCha20Poly1305(Box::new(
+                XChaCha20Poly1305::new_from_slice(&key.expose_secret()).expect("key length"),
+            )),
+        }
+    }
+
+    pub(crate) fn ring(algorithm: &'static Algorithm, key: &SecretVec<u8>) -> Self {
+        let unbound_key = UnboundKey::new(algorithm, &key.expose_secret()).expect("unbound key");
+        Self::Ring(LessSafeKey::new(unbound_key))
+    }
+
+    pub(crate) const fn nonce_len(&self) -> usize {
+        match self {
+            Self::Ring(_) => NONCE_LEN,
+            Self::XChaCha20Poly1305(_) => XNONCE_LEN,
+        }
+    }
+
+    pub(crate) fn tag_len(&self) -> usize {
+        match self {
+            Self::Ring(key) => key.algorithm().tag_len(),
+            Self::XChaCha20Poly1305(_) => XCHACHA_TAG_LEN,
+        }
+    }
+
+    /// Encrypt `data` in place and return its tag.
+    pub(crate) fn seal_in_place_separate_tag(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+        data: &mut [u8],
+    ) -> Result<Vec<u8>, Unspecified> {
+        match self {
+            Self::Ring(key) => {
+                let tag = key.seal_in_place_separate_tag(
+                    Nonce::try_assume_unique_for_key(nonce)?,
+                    Aad::from(aad),
+                    data,
+                )?;
+                Ok(tag.as_ref().to_vec())
+            }
+            Self::XChaCha20Poly1305(key) => {
+                if nonce.len() != XNONCE_LEN {
+                    return Err(Unspecified);
+                }
+                let tag = key
+                    .encrypt_in_place_detached(XNonce::from_slice(nonce), aad, data)
+                    .map_err(|_| Unspecified)?;
+                Ok(tag.to_vec())
+            }
+        }
+    }
+
+    /// Decrypt `data`, which ends with the tag, in place and return the plaintext at its start.
+    pub(crate) fn open_in_place<'a>(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+        data: &'a mut [u8],
+    ) -> Result<&'a mut [u8], Unspecified> {
+        match self {
+            Self::Ring(key) => key.open_in_place(
+                Nonce::try_assume_unique_for_key(nonce)?,
+                Aad::from(aad),
+                data,
+            ),
+            Self::XChaCha20Poly1305(key) => {
+                if nonce.len() != XNONCE_LEN {
+                    return Err(Unspecified);
+                }
+                let len = data.len().checked_sub(self.tag_len()).ok_or(Unspecified)?;
+                let (plaintext, tag) = data.split_at_mut(len);
+                key.decrypt_in_place_detached(
+                    XNonce::from_slice(nonce),
+                    aad,
+                    plaintext,
+                    Tag::from_slice(tag),
+                )
+                .map_err(|_| Unspecified)?;
+                Ok(plaintext)
+            }
+        }
+    }
+}
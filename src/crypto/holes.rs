@@ -0,0 +1,171 @@
+//! The blocks of a stream which are holes, see [`Holes`].
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+/// [`Holes`] shared by the readers and writers of the same stream, so the ones a writer leaves are read as holes.
+pub type SharedHoles = Arc<Mutex<Holes>>;
+
+/// The indexes of the blocks of a stream which are holes, kept apart from the stream in authenticated metadata.
+///
+/// A hole is stored as zeros instead of the encrypted block, see [`RingCryptoWrite::sparse`]. The ciphertext alone
+/// can't tell a hole from a block zeroed by someone who can change it, so a block of zeros is read as a hole only if
+/// it's here, else it fails to decrypt like any other changed block.
+///
+/// [`RingCryptoWrite::sparse`]: crate::crypto::write::RingCryptoWrite::sparse
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Holes {
+    /// Start of each range of holes to its end, exclusive. They don't overlap or touch.
+    ranges: BTreeMap<u64, u64>,
+    /// If they changed since [`Holes::take_changed`].
+    #[serde(skip)]
+    changed: bool,
+}
+
+impl Holes {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// If the block with `index` is a hole.
+    #[must_use]
+    pub fn contains(&self, index: u64) -> bool {
+        self.ranges
+            .range(..=index)
+            .next_back()
+            .is_some_and(|(_, end)| index < *end)
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// The ranges of holes, in order.
+    pub fn iter(&self) -> impl Iterator<Item = Range<u64>> + '_ {
+        self.ranges.iter().map(|(start, end)| *start..*end)
+    }
+
+    /// Mark the blocks in `blocks` as holes.
+    pub fn insert(&mut self, blocks: Range<u64>) {
+        if blocks.is_empty() {
+            return;
+        }
+        let (mut start, mut end) = (blocks.start, blocks.end);
+        // merge with the ranges overlapping or touching it
+        if let Some((prev_start, prev_end)) = self.ranges.range(..=start).next_back() {
+            if *prev_end >= start {
+                start = *prev_start;
+                end = end.max(*prev_end);
+            }
+        }
+        let merged: Vec<(u64, u64)> = self
+            .ranges
+            .range(start..=end)
+            .map(|(start, end)| (*start, *end))
+            .collect();
+        for (next_start, next_end) in merged {
+            self.ranges.remove(&next_start);
+            end = end.max(next_end);
+        }
+        self.ranges.insert(start, end);
+        self.changed = true;
+    }
+
+    /// Mark the blocks in `blocks` as not holes.
+    pub fn remove(&mut self, blocks: Range<u64>) {
+        if blocks.is_empty() {
+            return;
+        }
+        let overlapping: Vec<(u64, u64)> = self
+            .ranges
+            .range(..blocks.end)
+            .rev()
+            .take_while(|(_, end)| **end > blocks.start)
+            .map(|(start, end)| (*start, *end))
+            .collect();
+        for (start, end) in overlapping {
+            self.ranges.remove(&start);
+            if start < blocks.start {
+                self.ranges.insert(start, blocks.start);
+            }
+            if end > blocks.end {
+                self.ranges.insert(blocks.end, end);
+            }
+            self.changed = true;
+        }
+    }
+
+    /// Forget the holes from the block with `index` on, like when the stream is truncated there.
+    pub fn truncate(&mut self, index: u64) {
+        self.remove(index..u64::MAX);
+    }
+
+    /// Replace them with `holes`, like when the stream is replaced by another one.
+    pub fn replace(&mut self, holes: Self) {
+        self.ranges = holes.ranges;
+        self.changed = true;
+    }
+
+    /// Forget all holes.
+    pub fn clear(&mut self) {
+        if !self.ranges.is_empty() {
+            self.ranges.clear();
+            self.changed = true;
+        }
+    }
+
+    /// If they changed since the last call, which resets it.
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::take(&mut self.changed)
+    }
+
+    /// If they changed since the last [`Holes::take_changed`].
+    #[must_use]
+    pub const fn changed(&self) -> bool {
+        self.changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Holes;
+
+    #[test]
+    fn insert_merges() {
+        let mut holes = Holes::new();
+        holes.insert(2..4);
+        holes.insert(6..8);
+        holes.insert(4..6);
+        assert_eq!(holes.iter().collect::<Vec<_>>(), vec![2..8]);
+        holes.insert(0..3);
+        holes.insert(10..12);
+        assert_eq!(holes.iter().collect::<Vec<_>>(), vec![0..8, 10..12]);
+        assert!(holes.contains(0));
+        assert!(holes.contains(7));
+        assert!(!holes.contains(8));
+        assert!(holes.contains(11));
+        assert!(!holes.contains(12));
+    }
+
+    #[test]
+    fn remove_splits() {
+        let mut holes = Holes::new();
+        holes.insert(0..10);
+        holes.insert(20..30);
+        holes.remove(5..6);
+        assert_eq!(holes.iter().collect::<Vec<_>>(), vec![0..5, 6..10, 20..30]);
+        holes.remove(8..25);
+        assert_eq!(holes.iter().collect::<Vec<_>>(), vec![0..5, 6..8, 25..30]);
+        holes.truncate(7);
+        assert_eq!(holes.iter().collect::<Vec<_>>(), vec![0..5, 6..7]);
+        assert!(holes.take_changed());
+        assert!(!holes.take_changed());
+        holes.remove(10..20);
+        assert!(!holes.changed());
+    }
+}
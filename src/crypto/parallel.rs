@@ -0,0 +1,138 @@
+//! Encrypting and decrypting the blocks of large reads and writes on more threads, see [`CryptoPool`].
+
+use std::io;
+use std::sync::OnceLock;
+
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use tracing::error;
+
+use crate::crypto::block_key::BlockKey;
+use crate::crypto::holes::Holes;
+
+/// Reads and writes of fewer whole blocks than this are made on the calling thread.
+pub const MIN_PARALLEL_BLOCKS: usize = 4;
+
+/// Threads encrypting and decrypting the blocks of large reads and writes, started when first used.
+///
+/// Each block has its own nonce and its index is authenticated with it, so the blocks of a read or write of at least
+/// [`MIN_PARALLEL_BLOCKS`] are given to the threads and put back in order. Smaller ones are made on the calling
+/// thread, like all of them with a single thread.
+pub struct CryptoPool {
+    threads: usize,
+    pool: OnceLock<Result<ThreadPool, String>>,
+}
+
+impl CryptoPool {
+    #[must_use]
+    pub const fn new(threads: usize) -> Self {
+        Self {
+            threads,
+            pool: OnceLock::new(),
+        }
+    }
+
+    #[must_use]
+    pub const fn threads(&self) -> usize {
+        self.threads
+    }
+
+    fn pool(&self) -> io::Result<&ThreadPool> {
+        self.pool
+            .get_or_init(|| {
+                ThreadPoolBuilder::new()
+                    .num_threads(self.threads)
+                    .thread_name(|i| format!("rencfs-crypto-{i}"))
+                    .build()
+                    .map_err(|err| err.to_string())
+            })
+            .as_ref()
+            .map_err(|err| io::Error::other(format!("cannot start the crypto threads: {err}")))
+    }
+
+    /// Encrypt the `plaintext` blocks of `block_size`, the first one having `first_index`, each with its nonce from
+    /// `nonces`. Returns the encrypted blocks one after the other, each with its nonce and tag.
+    pub(crate) fn seal_blocks(
+        &self,
+        key: &BlockKey,
+        aad: &[u8],
+        first_index: u64,
+        nonces: &[u8],
+        plaintext: &[u8],
+        block_size: usize,
+    ) -> io::Result<Vec<u8>> {
+        let ciphertext_block_size = key.nonce_len() + block_size + key.tag_len();
+        let mut ciphertext = vec![0; plaintext.len() / block_size * ciphertext_block_size];
+        self.pool()?.install(|| {
+            ciphertext
+                .par_chunks_mut(ciphertext_block_size)
+                .zip(plaintext.par_chunks(block_size))
+                .zip(nonces.par_chunks(key.nonce_len()))
+                .enumerate()
+                .try_for_each(|(i, ((out, block), nonce))| -> io::Result<()> {
+                    let (nonce_out, rest) = out.split_at_mut(key.nonce_len());
+                    let (data, tag_out) = rest.split_at_mut(block_size);
+                    nonce_out.copy_from_slice(nonce);
+                    data.copy_from_slice(block);
+                    let aad = [aad, &(first_index + i as u64).to_le_bytes()[..]].concat();
+                    let tag = key
+                        .seal_in_place_separate_tag(nonce, &aad, data)
+                        .map_err(sealing_error)?;
+                    tag_out.copy_from_slice(&tag);
+                    Ok(())
+                })
+        })?;
+        Ok(ciphertext)
+    }
+
+    /// Decrypt the whole encrypted blocks in `ciphertext`, the first one having `first_index`, into `plaintext`
+    /// which has room for them. The blocks are decrypted in place, so `ciphertext` has plaintext after this.
+    ///
+    /// The blocks in `holes` which are all zeros are holes of zeros, see [`RingCryptoRead::sparse`].
+    ///
+    /// [`RingCryptoRead::sparse`]: crate::crypto::read::RingCryptoRead::sparse
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn open_blocks(
+        &self,
+        key: &BlockKey,
+        aad: &[u8],
+        first_index: u64,
+        ciphertext: &mut [u8],
+        plaintext: &mut [u8],
+        block_size: usize,
+        holes: Option<&Holes>,
+    ) -> io::Result<()> {
+        let ciphertext_block_size = key.nonce_len() + block_size + key.tag_len();
+        self.pool()?.install(|| {
+            ciphertext
+                .par_chunks_mut(ciphertext_block_size)
+                .zip(plaintext.par_chunks_mut(block_size))
+                .enumerate()
+                .try_for_each(|(i, (block, out))| -> io::Result<()> {
+                    if holes.is_some_and(|holes| holes.contains(first_index + i as u64))
+                        && block.iter().all(|b| *b == 0)
+                    {
+                        out.fill(0);
+                        return Ok(());
+                    }
+                    let (nonce, data) = block.split_at_mut(key.nonce_len());
+                    let aad = [aad, &(first_index + i as u64).to_le_bytes()[..]].concat();
+                    let data = key
+                        .open_in_place(nonce, &aad, data)
+                        .map_err(opening_error)?;
+                    out.copy_from_slice(data);
+                    Ok(())
+                })
+        })
+    }
+}
+
+fn sealing_error(err: ring::error::Unspecified) -> io::Error {
+    error!("error sealing in place: {}", err);
+    io::Error::other(format!("error sealing in place: {err}"))
+}
+
+fn opening_error(err: ring::error::Unspecified) -> io::Error {
+    error!("error opening within: {}", err);
+    io::Error::new(io::ErrorKind::InvalidData, "error opening within")
+}
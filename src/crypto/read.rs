@@ -1,15 +1,16 @@
 use std::io;
 use std::io::{Read, Seek, SeekFrom};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
-use ring::aead::{
-    Aad, Algorithm, BoundKey, Nonce, NonceSequence, OpeningKey, UnboundKey, NONCE_LEN,
-};
-use ring::error;
-use shush_rs::{ExposeSecret, SecretVec};
+use ring::aead::Algorithm;
+use shush_rs::zeroize::Zeroizing;
+use shush_rs::SecretVec;
 use tracing::{error, instrument, warn};
 
+use crate::crypto::block_key::BlockKey;
 use crate::crypto::buf_mut::BufMut;
+use crate::crypto::holes::SharedHoles;
+use crate::crypto::parallel::{CryptoPool, MIN_PARALLEL_BLOCKS};
 use crate::crypto::write::BLOCK_SIZE;
 use crate::stream_util;
 
@@ -23,9 +24,12 @@ pub trait CryptoRead<R: Read + Send + Sync>: Read + Send + Sync {
 }
 
 /// ring
+///
+/// `$holes` is an `Option<&SharedHoles>`, a block which is all zeros and is in it is a hole and its plaintext is
+/// zeros, see [`RingCryptoRead::sparse`].
 #[macro_export]
 macro_rules! decrypt_block {
-    ($block_index:expr, $buf:expr, $input:expr, $last_nonce:expr, $opening_key:expr) => {{
+    ($block_index:expr, $buf:expr, $input:expr, $key:expr, $aad:expr, $holes:expr) => {{
         let len = {
             $buf.clear();
             let buffer = $buf.as_mut_remaining();
@@ -44,28 +48,39 @@ macro_rules! decrypt_block {
                 }
                 pos
             };
-            if len != 0 {
-                let data = &mut buffer[..len];
-                let aad = Aad::from(($block_index).to_le_bytes());
-                // extract nonce
-                $last_nonce
-                    .lock()
-                    .unwrap()
-                    .replace(data[..NONCE_LEN].to_vec());
-                let data = &mut data[NONCE_LEN..];
-                let plaintext = $opening_key.open_within(aad, data, 0..).map_err(|err| {
+            if len != 0
+                && $holes.is_some_and(|holes| {
+                    buffer[..len].iter().all(|b| *b == 0)
+                        && holes.lock().unwrap().contains($block_index)
+                })
+            {
+                // a hole, the zeros after the nonce are its plaintext
+                len = len
+                    .checked_sub($key.nonce_len() + $key.tag_len())
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "hole too short"))?;
+            } else if len != 0 {
+                if len < $key.nonce_len() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "block too short",
+                    ));
+                }
+                let aad = [&$aad[..], &($block_index).to_le_bytes()[..]].concat();
+                let (nonce, data) = buffer[..len].split_at_mut($key.nonce_len());
+                let plaintext = $key.open_in_place(nonce, &aad, data).map_err(|err| {
                     error!("error opening within: {}", err);
-                    io::Error::new(io::ErrorKind::Other, "error opening within")
+                    io::Error::new(io::ErrorKind::InvalidData, "error opening within")
                 })?;
                 len = plaintext.len();
             }
             len
         };
         if len != 0 {
-            $buf.seek_available(SeekFrom::Start(NONCE_LEN as u64 + len as u64))
+            $buf.seek_available(SeekFrom::Start($key.nonce_len() as u64 + len as u64))
                 .unwrap();
             // skip nonce
-            $buf.seek_read(SeekFrom::Start(NONCE_LEN as u64)).unwrap();
+            $buf.seek_read(SeekFrom::Start($key.nonce_len() as u64))
+                .unwrap();
             $block_index += 1;
         }
     }};
@@ -76,32 +91,112 @@ pub(crate) use decrypt_block;
 #[allow(clippy::module_name_repetitions)]
 pub struct RingCryptoRead<R: Read> {
     input: Option<R>,
-    opening_key: OpeningKey<ExistingNonceSequence>,
+    key: BlockKey,
     buf: BufMut,
-    last_nonce: Arc<Mutex<Option<Vec<u8>>>>,
+    nonce_len: usize,
     ciphertext_block_size: usize,
     plaintext_block_size: usize,
     block_index: u64,
+    aad: Vec<u8>,
+    holes: Option<SharedHoles>,
+    parallel: Option<Arc<CryptoPool>>,
 }
 
 impl<R: Read> RingCryptoRead<R> {
+    /// `aad` is authenticated with each block, it must be the same as the one used when writing.
     #[allow(clippy::missing_panics_doc)]
-    pub fn new(reader: R, algorithm: &'static Algorithm, key: &SecretVec<u8>) -> Self {
-        let ciphertext_block_size = NONCE_LEN + BLOCK_SIZE + algorithm.tag_len();
+    pub fn new(reader: R, algorithm: &'static Algorithm, key: &SecretVec<u8>, aad: &[u8]) -> Self {
+        Self::with_key(reader, BlockKey::ring(algorithm, key), aad)
+    }
+
+    pub(crate) fn with_key(reader: R, key: BlockKey, aad: &[u8]) -> Self {
+        let nonce_len = key.nonce_len();
+        let ciphertext_block_size = nonce_len + BLOCK_SIZE + key.tag_len();
         let buf = BufMut::new(vec![0; ciphertext_block_size]);
-        let last_nonce = Arc::new(Mutex::new(None));
-        let unbound_key = UnboundKey::new(algorithm, &key.expose_secret()).unwrap();
-        let nonce_sequence = ExistingNonceSequence::new(last_nonce.clone());
-        let opening_key = OpeningKey::new(unbound_key, nonce_sequence);
         Self {
             input: Some(reader),
-            opening_key,
+            key,
             buf,
-            last_nonce,
+            nonce_len,
             ciphertext_block_size,
             plaintext_block_size: BLOCK_SIZE,
             block_index: 0,
+            aad: aad.to_vec(),
+            holes: None,
+            parallel: None,
+        }
+    }
+
+    /// Read the blocks in `holes` which are all zeros as holes of zeros, like [`RingCryptoWrite::sparse`] writes
+    /// them.
+    ///
+    /// A block of zeros which is not in `holes` is decrypted like the others, so it fails. The `holes` must come from
+    /// authenticated metadata, they are what tells a hole from a block zeroed by someone who can change the
+    /// ciphertext.
+    ///
+    /// [`RingCryptoWrite::sparse`]: crate::crypto::write::RingCryptoWrite::sparse
+    #[must_use]
+    pub fn sparse(mut self, holes: SharedHoles) -> Self {
+        self.holes = Some(holes);
+        self
+    }
+
+    /// Decrypt the blocks of large reads on the threads of `pool`. With a single thread it does nothing.
+    #[must_use]
+    pub fn parallel(mut self, pool: Arc<CryptoPool>) -> Self {
+        if pool.threads() > 1 {
+            self.parallel = Some(pool);
+        }
+        self
+    }
+
+    /// Read and decrypt the next blocks on the threads of the pool, if `buf` has room for at least
+    /// [`MIN_PARALLEL_BLOCKS`] of them and the current block was all read.
+    ///
+    /// The last block read is decrypted like in [`Read::read`], so we are left in it as if we read them one by one.
+    /// Returns `None` if it's left to be read one block at a time.
+    fn read_parallel(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        let Some(pool) = self.parallel.as_ref() else {
+            return Ok(None);
+        };
+        let blocks = buf.len() / self.plaintext_block_size;
+        if blocks < MIN_PARALLEL_BLOCKS || self.buf.available_read() != 0 {
+            return Ok(None);
+        }
+        // has the plaintext of the blocks after they are decrypted
+        let mut ciphertext = Zeroizing::new(vec![0; blocks * self.ciphertext_block_size]);
+        let len = stream_util::read(self.input.as_mut().unwrap(), &mut ciphertext[..])?;
+        if len == 0 {
+            return Ok(Some(0));
         }
+        let whole_blocks = (len - 1) / self.ciphertext_block_size;
+        let (whole, last) =
+            ciphertext[..len].split_at_mut(whole_blocks * self.ciphertext_block_size);
+        let plaintext_len = whole_blocks * self.plaintext_block_size;
+        {
+            let holes = self.holes.as_ref().map(|holes| holes.lock().unwrap());
+            pool.open_blocks(
+                &self.key,
+                &self.aad,
+                self.block_index,
+                whole,
+                &mut buf[..plaintext_len],
+                self.plaintext_block_size,
+                holes.as_deref(),
+            )?;
+        }
+        self.block_index += whole_blocks as u64;
+        let mut last: &[u8] = last;
+        decrypt_block!(
+            self.block_index,
+            self.buf,
+            last,
+            &self.key,
+            &self.aad,
+            self.holes.as_ref()
+        );
+        let last_len = self.buf.read(&mut buf[plaintext_len..])?;
+        Ok(Some(plaintext_len + last_len))
     }
 }
 
@@ -113,35 +208,23 @@ impl<R: Read> Read for RingCryptoRead<R> {
         if len != 0 {
             return Ok(len);
         }
+        if let Some(len) = self.read_parallel(buf)? {
+            return Ok(len);
+        }
         // we read all the data from the buffer, so we need to read a new block and decrypt it
         decrypt_block!(
             self.block_index,
             self.buf,
             self.input.as_mut().unwrap(),
-            self.last_nonce,
-            self.opening_key
+            &self.key,
+            &self.aad,
+            self.holes.as_ref()
         );
         let len = self.buf.read(buf)?;
         Ok(len)
     }
 }
 
-pub(crate) struct ExistingNonceSequence {
-    last_nonce: Arc<Mutex<Option<Vec<u8>>>>,
-}
-
-impl ExistingNonceSequence {
-    pub const fn new(last_nonce: Arc<Mutex<Option<Vec<u8>>>>) -> Self {
-        Self { last_nonce }
-    }
-}
-
-impl NonceSequence for ExistingNonceSequence {
-    fn advance(&mut self) -> Result<Nonce, error::Unspecified> {
-        Nonce::try_assume_unique_for_key(self.last_nonce.lock().unwrap().as_mut().unwrap())
-    }
-}
-
 impl<R: Read + Send + Sync> CryptoRead<R> for RingCryptoRead<R> {
     fn into_inner(&mut self) -> R {
         self.input.take().unwrap()
@@ -155,13 +238,18 @@ pub trait CryptoReadSeek<R: Read + Seek + Send + Sync>:
 }
 
 impl<R: Read + Seek> RingCryptoRead<R> {
-    pub fn new_seek(reader: R, algorithm: &'static Algorithm, key: &SecretVec<u8>) -> Self {
-        Self::new(reader, algorithm, key)
+    pub fn new_seek(
+        reader: R,
+        algorithm: &'static Algorithm,
+        key: &SecretVec<u8>,
+        aad: &[u8],
+    ) -> Self {
+        Self::new(reader, algorithm, key, aad)
     }
 
     const fn pos(&self) -> u64 {
         self.block_index.saturating_sub(1) * self.plaintext_block_size as u64
-            + self.buf.pos_read().saturating_sub(NONCE_LEN) as u64
+            + self.buf.pos_read().saturating_sub(self.nonce_len) as u64
     }
 
     fn get_plaintext_len(&mut self) -> io::Result<u64> {
@@ -210,7 +298,7 @@ impl<R: Read + Seek> Seek for RingCryptoRead<R> {
             {
                 // seek inside current block
                 self.buf.seek_read(SeekFrom::Start(
-                    NONCE_LEN as u64 + new_pos % self.plaintext_block_size as u64,
+                    self.nonce_len as u64 + new_pos % self.plaintext_block_size as u64,
                 ))?;
             } else {
                 // we need to read a new block and seek inside that block
@@ -233,8 +321,9 @@ impl<R: Read + Seek> Seek for RingCryptoRead<R> {
                     self.block_index,
                     self.buf,
                     self.input.as_mut().unwrap(),
-                    self.last_nonce,
-                    self.opening_key
+                    &self.key,
+                    &self.aad,
+                    self.holes.as_ref()
                 );
             }
             // seek inside new block
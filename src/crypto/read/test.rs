@@ -43,7 +43,7 @@ fn test_read_empty() {
     let mut buf = [0u8; 10];
     let cipher = &CHACHA20_POLY1305;
     let key = create_secret_key(CHACHA20_POLY1305.key_len());
-    let mut crypto_reader = RingCryptoRead::new(reader, cipher, &key);
+    let mut crypto_reader = RingCryptoRead::new(reader, cipher, &key, &[]);
     let result = &crypto_reader.read(&mut buf).unwrap();
     let expected: usize = 0;
     assert_eq!(*result, expected);
@@ -61,7 +61,8 @@ fn test_read_single_block() {
     let data = binding.as_bytes();
     let key = create_secret_key(CHACHA20_POLY1305.key_len());
     let encrypted_data = create_encrypted_data(data, &key);
-    let mut reader = RingCryptoRead::new(Cursor::new(encrypted_data), &CHACHA20_POLY1305, &key);
+    let mut reader =
+        RingCryptoRead::new(Cursor::new(encrypted_data), &CHACHA20_POLY1305, &key, &[]);
     let mut buf = vec![0u8; BLOCK_SIZE];
     assert_eq!(reader.read(&mut buf).unwrap(), BLOCK_SIZE);
 }
@@ -82,7 +83,8 @@ fn test_read_multiple_blocks() {
     let data = binding.as_bytes();
     let key = create_secret_key(CHACHA20_POLY1305.key_len());
     let encrypted_data = create_encrypted_data(data, &key);
-    let mut reader = RingCryptoRead::new(Cursor::new(encrypted_data), &CHACHA20_POLY1305, &key);
+    let mut reader =
+        RingCryptoRead::new(Cursor::new(encrypted_data), &CHACHA20_POLY1305, &key, &[]);
     let mut buf = vec![0u8; block_size];
     for _ in 0..num_blocks {
         assert_eq!(reader.read(&mut buf).unwrap(), BLOCK_SIZE);
@@ -102,7 +104,8 @@ fn test_partial_read() {
     let data = binding.as_bytes();
     let key = create_secret_key(CHACHA20_POLY1305.key_len());
     let encrypted_data = create_encrypted_data(data, &key);
-    let mut reader = RingCryptoRead::new(Cursor::new(encrypted_data), &CHACHA20_POLY1305, &key);
+    let mut reader =
+        RingCryptoRead::new(Cursor::new(encrypted_data), &CHACHA20_POLY1305, &key, &[]);
     let mut buf = vec![0u8; BLOCK_SIZE / 2];
     assert_eq!(reader.read(&mut buf).unwrap(), BLOCK_SIZE / 2);
 }
@@ -110,13 +113,13 @@ fn test_partial_read() {
 #[test]
 #[traced_test]
 fn test_read_one_byte_less_than_block() {
-    use crate::crypto::read::{RingCryptoRead, BLOCK_SIZE, NONCE_LEN};
-    use ring::aead::CHACHA20_POLY1305;
+    use crate::crypto::read::{RingCryptoRead, BLOCK_SIZE};
+    use ring::aead::{CHACHA20_POLY1305, NONCE_LEN};
     use std::io::Cursor;
     use std::io::Read;
     let data = vec![0u8; NONCE_LEN + BLOCK_SIZE + CHACHA20_POLY1305.tag_len() - 1];
     let key = create_secret_key(CHACHA20_POLY1305.key_len());
-    let mut reader = RingCryptoRead::new(Cursor::new(data), &CHACHA20_POLY1305, &key);
+    let mut reader = RingCryptoRead::new(Cursor::new(data), &CHACHA20_POLY1305, &key, &[]);
     let mut buf = vec![0u8; BLOCK_SIZE];
     assert!(reader.read(&mut buf).is_err());
 }
@@ -137,7 +140,8 @@ fn test_alternating_small_and_large_reads() {
     let data = binding.as_bytes();
     let key = create_secret_key(CHACHA20_POLY1305.key_len());
     let encrypted_data = create_encrypted_data(data, &key);
-    let mut reader = RingCryptoRead::new(Cursor::new(encrypted_data), &CHACHA20_POLY1305, &key);
+    let mut reader =
+        RingCryptoRead::new(Cursor::new(encrypted_data), &CHACHA20_POLY1305, &key, &[]);
     let mut small_buf = vec![0u8; 10];
     let mut large_buf = vec![0u8; 40];
     assert_eq!(reader.read(&mut small_buf).unwrap(), 10);
@@ -152,13 +156,13 @@ fn test_alternating_small_and_large_reads() {
 #[test]
 #[traced_test]
 fn test_read_one_byte_more_than_block() {
-    use crate::crypto::read::{RingCryptoRead, BLOCK_SIZE, NONCE_LEN};
-    use ring::aead::CHACHA20_POLY1305;
+    use crate::crypto::read::{RingCryptoRead, BLOCK_SIZE};
+    use ring::aead::{CHACHA20_POLY1305, NONCE_LEN};
     use std::io::Cursor;
     use std::io::Read;
     let data = vec![0u8; NONCE_LEN + BLOCK_SIZE + CHACHA20_POLY1305.tag_len() + 1];
     let key = create_secret_key(CHACHA20_POLY1305.key_len());
-    let mut reader = RingCryptoRead::new(Cursor::new(data), &CHACHA20_POLY1305, &key);
+    let mut reader = RingCryptoRead::new(Cursor::new(data), &CHACHA20_POLY1305, &key, &[]);
     let mut buf = vec![0u8; BLOCK_SIZE];
     assert!(reader.read(&mut buf).is_err());
 }
@@ -183,13 +187,13 @@ fn test_ring_crypto_read_seek_chacha() {
     let key = SecretVec::new(Box::new(vec![0; algorithm.key_len()]));
 
     // write the data
-    let mut writer = RingCryptoWrite::new(cursor, false, algorithm, &key);
+    let mut writer = RingCryptoWrite::new(cursor, false, algorithm, &key, &[]);
     writer.write_all(data.as_bytes()).unwrap();
     cursor = writer.finish().unwrap();
 
     // Create a RingCryptoReaderSeek
     cursor.seek(SeekFrom::Start(0)).unwrap();
-    let mut reader = RingCryptoRead::new(&mut cursor, algorithm, &key);
+    let mut reader = RingCryptoRead::new(&mut cursor, algorithm, &key, &[]);
 
     // Seek to the middle of the data
     reader.seek(SeekFrom::Start(7)).unwrap();
@@ -231,13 +235,13 @@ fn test_ring_crypto_read_seek_aes() {
     let key = SecretVec::new(Box::new(vec![0; algorithm.key_len()]));
 
     // write the data
-    let mut writer = RingCryptoWrite::new(cursor, true, algorithm, &key);
+    let mut writer = RingCryptoWrite::new(cursor, true, algorithm, &key, &[]);
     writer.write_all(data.as_bytes()).unwrap();
     cursor = writer.finish().unwrap();
 
     // Create a RingCryptoReaderSeek
     cursor.seek(SeekFrom::Start(0)).unwrap();
-    let mut reader = RingCryptoRead::new_seek(cursor, algorithm, &key);
+    let mut reader = RingCryptoRead::new_seek(cursor, algorithm, &key, &[]);
 
     // Seek to the middle of the data
     reader.seek(SeekFrom::Start(7)).unwrap();
@@ -282,13 +286,13 @@ fn test_ring_crypto_read_seek_blocks_chacha() {
     let key = SecretVec::new(Box::new(vec![0; algorithm.key_len()]));
 
     // write the data
-    let mut writer = RingCryptoWrite::new(cursor, false, algorithm, &key);
+    let mut writer = RingCryptoWrite::new(cursor, false, algorithm, &key, &[]);
     writer.write_all(&data).unwrap();
     cursor = writer.finish().unwrap();
 
     // Create a RingCryptoReaderSeek
     cursor.seek(SeekFrom::Start(0)).unwrap();
-    let mut reader = RingCryptoRead::new_seek(&mut cursor, algorithm, &key);
+    let mut reader = RingCryptoRead::new_seek(&mut cursor, algorithm, &key, &[]);
 
     // Seek in the second block
     reader.seek(SeekFrom::Start(BLOCK_SIZE as u64)).unwrap();
@@ -333,13 +337,13 @@ fn test_ring_crypto_read_seek_blocks_aes() {
     let key = SecretVec::new(Box::new(vec![0; algorithm.key_len()]));
 
     // write the data
-    let mut writer = RingCryptoWrite::new(cursor, true, algorithm, &key);
+    let mut writer = RingCryptoWrite::new(cursor, true, algorithm, &key, &[]);
     writer.write_all(&data).unwrap();
     cursor = writer.finish().unwrap();
 
     // Create a RingCryptoReaderSeek
     cursor.seek(SeekFrom::Start(0)).unwrap();
-    let mut reader = RingCryptoRead::new_seek(cursor, algorithm, &key);
+    let mut reader = RingCryptoRead::new_seek(cursor, algorithm, &key, &[]);
 
     // Seek in the second block
     reader.seek(SeekFrom::Start(BLOCK_SIZE as u64)).unwrap();
@@ -384,13 +388,13 @@ fn test_ring_crypto_read_seek_blocks_boundary_chacha() {
     let key = SecretVec::new(Box::new(vec![0; algorithm.key_len()]));
 
     // write the data
-    let mut writer = RingCryptoWrite::new(cursor, false, algorithm, &key);
+    let mut writer = RingCryptoWrite::new(cursor, false, algorithm, &key, &[]);
     writer.write_all(&data).unwrap();
     cursor = writer.finish().unwrap();
 
     // Create a RingCryptoReaderSeek
     cursor.seek(SeekFrom::Start(0)).unwrap();
-    let mut reader = RingCryptoRead::new_seek(&mut cursor, algorithm, &key);
+    let mut reader = RingCryptoRead::new_seek(&mut cursor, algorithm, &key, &[]);
 
     reader.read_exact(&mut [0; 1]).unwrap();
     // Seek to the second block boundary
@@ -432,13 +436,13 @@ fn test_ring_crypto_read_seek_blocks_boundary_aes() {
     let key = SecretVec::new(Box::new(vec![0; algorithm.key_len()]));
 
     // write the data
-    let mut writer = RingCryptoWrite::new(cursor, true, algorithm, &key);
+    let mut writer = RingCryptoWrite::new(cursor, true, algorithm, &key, &[]);
     writer.write_all(&data).unwrap();
     cursor = writer.finish().unwrap();
 
     // Create a RingCryptoReaderSeek
     cursor.seek(SeekFrom::Start(0)).unwrap();
-    let mut reader = RingCryptoRead::new_seek(cursor, algorithm, &key);
+    let mut reader = RingCryptoRead::new_seek(cursor, algorithm, &key, &[]);
 
     reader.read_exact(&mut [0; 1]).unwrap();
     // Seek to the second block boundary
@@ -480,13 +484,13 @@ fn test_ring_crypto_read_seek_skip_blocks_chacha() {
     let key = SecretVec::new(Box::new(vec![0; algorithm.key_len()]));
 
     // write the data
-    let mut writer = RingCryptoWrite::new(cursor, false, algorithm, &key);
+    let mut writer = RingCryptoWrite::new(cursor, false, algorithm, &key, &[]);
     writer.write_all(&data).unwrap();
     cursor = writer.finish().unwrap();
 
     // Create a RingCryptoReaderSeek
     cursor.seek(SeekFrom::Start(0)).unwrap();
-    let mut reader = RingCryptoRead::new_seek(cursor, algorithm, &key);
+    let mut reader = RingCryptoRead::new_seek(cursor, algorithm, &key, &[]);
 
     reader.seek(SeekFrom::Start(2 * BLOCK_SIZE as u64)).unwrap();
     let mut buffer = vec![0; BLOCK_SIZE];
@@ -516,13 +520,13 @@ fn test_ring_crypto_read_seek_skip_blocks_aes() {
     let key = SecretVec::new(Box::new(vec![0; algorithm.key_len()]));
 
     // write the data
-    let mut writer = RingCryptoWrite::new(cursor, false, algorithm, &key);
+    let mut writer = RingCryptoWrite::new(cursor, false, algorithm, &key, &[]);
     writer.write_all(&data).unwrap();
     cursor = writer.finish().unwrap();
 
     // Create a RingCryptoReaderSeek
     cursor.seek(SeekFrom::Start(0)).unwrap();
-    let mut reader = RingCryptoRead::new_seek(cursor, algorithm, &key);
+    let mut reader = RingCryptoRead::new_seek(cursor, algorithm, &key, &[]);
 
     reader.seek(SeekFrom::Start(2 * BLOCK_SIZE as u64)).unwrap();
     let mut buffer = vec![0; BLOCK_SIZE];
@@ -552,13 +556,13 @@ fn test_ring_crypto_read_seek_in_second_block() {
     let key = SecretVec::new(Box::new(vec![0; algorithm.key_len()]));
 
     // write the data
-    let mut writer = RingCryptoWrite::new(cursor, false, algorithm, &key);
+    let mut writer = RingCryptoWrite::new(cursor, false, algorithm, &key, &[]);
     writer.write_all(&data).unwrap();
     cursor = writer.finish().unwrap();
 
     // Create a RingCryptoReaderSeek
     cursor.seek(SeekFrom::Start(0)).unwrap();
-    let mut reader = RingCryptoRead::new_seek(cursor, algorithm, &key);
+    let mut reader = RingCryptoRead::new_seek(cursor, algorithm, &key, &[]);
 
     assert_eq!(
         reader.seek(SeekFrom::Start(BLOCK_SIZE as u64)).unwrap(),
@@ -571,8 +575,12 @@ fn test_ring_crypto_read_seek_in_second_block() {
 fn finish_seek() {
     use super::RingCryptoRead;
     let reader = io::Cursor::new(vec![0; 10]);
-    let mut reader =
-        RingCryptoRead::new_seek(reader, &AES_256_GCM, &SecretVec::new(Box::new(vec![0; 32])));
+    let mut reader = RingCryptoRead::new_seek(
+        reader,
+        &AES_256_GCM,
+        &SecretVec::new(Box::new(vec![0; 32])),
+        &[],
+    );
     let mut reader = reader.into_inner();
     let _ = reader.seek(io::SeekFrom::Start(0));
 }
@@ -638,3 +646,129 @@ fn reader_with_seeks() {
     reader.seek(SeekFrom::Start(42)).unwrap();
     assert_eq!(reader.stream_position().unwrap(), 42);
 }
+
+#[test]
+#[traced_test]
+fn test_sparse_holes() {
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    use ring::aead::{CHACHA20_POLY1305, NONCE_LEN};
+
+    use super::RingCryptoRead;
+    use crate::crypto::holes::SharedHoles;
+    use crate::crypto::read::BLOCK_SIZE;
+    use crate::crypto::write::{CryptoWrite, RingCryptoWrite};
+
+    let key = create_secret_key(CHACHA20_POLY1305.key_len());
+    let offset = 10 * BLOCK_SIZE + 5;
+    let holes = SharedHoles::default();
+    let mut writer = RingCryptoWrite::new(Cursor::new(vec![]), true, &CHACHA20_POLY1305, &key, &[])
+        .sparse(holes.clone());
+    writer.seek(SeekFrom::Start(offset as u64)).unwrap();
+    writer.write_all(b"data").unwrap();
+    let mut data = writer.finish().unwrap().into_inner();
+    // the blocks before are holes
+    let ciphertext_block_size = NONCE_LEN + BLOCK_SIZE + CHACHA20_POLY1305.tag_len();
+    assert_eq!(11 * ciphertext_block_size - BLOCK_SIZE + 9, data.len());
+    assert!(data[..10 * ciphertext_block_size].iter().all(|b| *b == 0));
+    assert_eq!(
+        vec![0..10],
+        holes.lock().unwrap().iter().collect::<Vec<_>>()
+    );
+
+    let mut buf = vec![];
+    RingCryptoRead::new(Cursor::new(&data), &CHACHA20_POLY1305, &key, &[])
+        .sparse(holes.clone())
+        .read_to_end(&mut buf)
+        .unwrap();
+    assert_eq!(offset + 4, buf.len());
+    assert!(buf[..offset].iter().all(|b| *b == 0));
+    assert_eq!(b"data", &buf[offset..]);
+
+    // they are not valid blocks for the other readers
+    assert!(
+        RingCryptoRead::new(Cursor::new(&data), &CHACHA20_POLY1305, &key, &[])
+            .read_to_end(&mut vec![])
+            .is_err()
+    );
+
+    // a block of data zeroed is not a hole, it fails like any other change
+    data[10 * ciphertext_block_size..].fill(0);
+    assert!(
+        RingCryptoRead::new(Cursor::new(&data), &CHACHA20_POLY1305, &key, &[])
+            .sparse(holes.clone())
+            .read_to_end(&mut vec![])
+            .is_err()
+    );
+
+    // writing data over a hole removes it
+    let mut writer = RingCryptoWrite::new(
+        Cursor::new(vec![0; 10 * ciphertext_block_size]),
+        true,
+        &CHACHA20_POLY1305,
+        &key,
+        &[],
+    )
+    .sparse(holes.clone());
+    writer.seek(SeekFrom::Start(3 * BLOCK_SIZE as u64)).unwrap();
+    writer.write_all(b"data").unwrap();
+    writer.finish().unwrap();
+    assert_eq!(
+        vec![0..3, 4..10],
+        holes.lock().unwrap().iter().collect::<Vec<_>>()
+    );
+}
+
+#[test]
+#[traced_test]
+fn test_parallel() {
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+    use std::sync::Arc;
+
+    use rand::RngCore;
+    use ring::aead::{CHACHA20_POLY1305, NONCE_LEN};
+
+    use super::RingCryptoRead;
+    use crate::crypto::parallel::CryptoPool;
+    use crate::crypto::read::BLOCK_SIZE;
+    use crate::crypto::write::{CryptoWrite, RingCryptoWrite};
+
+    let key = create_secret_key(CHACHA20_POLY1305.key_len());
+    let pool = Arc::new(CryptoPool::new(4));
+    let mut data = vec![0; 10 * BLOCK_SIZE + 42];
+    rand::thread_rng().fill_bytes(&mut data);
+
+    // written in parallel, read on this thread
+    let mut writer = RingCryptoWrite::new(Cursor::new(vec![]), true, &CHACHA20_POLY1305, &key, &[])
+        .parallel(pool.clone());
+    writer.write_all(&data).unwrap();
+    // overwrite inside the blocks written in parallel
+    writer.seek(SeekFrom::Start(5)).unwrap();
+    writer.write_all(b"changed").unwrap();
+    data[5..12].copy_from_slice(b"changed");
+    let encrypted = writer.finish().unwrap().into_inner();
+    let mut buf = vec![];
+    RingCryptoRead::new(Cursor::new(&encrypted), &CHACHA20_POLY1305, &key, &[])
+        .read_to_end(&mut buf)
+        .unwrap();
+    assert_eq!(data, buf);
+
+    // written on this thread, read in parallel
+    let encrypted = create_encrypted_data(&data, &key);
+    let mut reader = RingCryptoRead::new(Cursor::new(&encrypted), &CHACHA20_POLY1305, &key, &[])
+        .parallel(pool.clone());
+    let mut buf = vec![0; 3];
+    reader.read_exact(&mut buf).unwrap();
+    // continues after what was read
+    let mut rest = vec![0; data.len()];
+    let len = crate::stream_util::read(&mut reader, &mut rest).unwrap();
+    buf.extend_from_slice(&rest[..len]);
+    assert_eq!(data, buf);
+
+    // a changed block fails like on a single thread
+    let mut encrypted = encrypted;
+    encrypted[3 * (NONCE_LEN + BLOCK_SIZE + CHACHA20_POLY1305.tag_len()) + 20] ^= 1;
+    let mut reader =
+        RingCryptoRead::new(Cursor::new(&encrypted), &CHACHA20_POLY1305, &key, &[]).parallel(pool);
+    assert!(crate::stream_util::read(&mut reader, &mut vec![0; data.len()]).is_err());
+}
@@ -1,19 +1,19 @@
 use std::any::Any;
 use std::io;
 use std::io::{Read, Seek, SeekFrom, Write};
-use std::sync::{Arc, Mutex};
+use std::ops::Range;
+use std::sync::Arc;
 
 use bytes::Buf;
 use rand_chacha::rand_core::RngCore;
-use ring::aead::{
-    Aad, Algorithm, BoundKey, Nonce, NonceSequence, OpeningKey, SealingKey, UnboundKey, NONCE_LEN,
-};
-use ring::error::Unspecified;
-use shush_rs::{ExposeSecret, SecretVec};
+use ring::aead::Algorithm;
+use shush_rs::SecretVec;
 use tracing::error;
 
+use crate::crypto::block_key::BlockKey;
 use crate::crypto::buf_mut::BufMut;
-use crate::crypto::read::ExistingNonceSequence;
+use crate::crypto::holes::SharedHoles;
+use crate::crypto::parallel::{CryptoPool, MIN_PARALLEL_BLOCKS};
 use crate::{crypto, decrypt_block, stream_util};
 
 mod bench;
@@ -62,72 +62,124 @@ pub trait CryptoWrite<W: CryptoInnerWriter + Send + Sync>: Write + Send + Sync {
 }
 
 /// Write with Seek
-pub trait CryptoWriteSeek<W: CryptoInnerWriter + Send + Sync>: CryptoWrite<W> + Seek {}
+pub trait CryptoWriteSeek<W: CryptoInnerWriter + Send + Sync>: CryptoWrite<W> + Seek {
+    /// Write the block it's in if it changed and forget it, so the next write reads it again from the stream, like
+    /// when another writer of the same stream may have changed it meanwhile. The position goes back to the start,
+    /// seek before writing again.
+    ///
+    /// Returns if a block was written.
+    #[allow(clippy::missing_errors_doc)]
+    fn release_block(&mut self) -> io::Result<bool>;
+}
 
 /// ring
 #[allow(clippy::module_name_repetitions)]
 pub struct RingCryptoWrite<W: CryptoInnerWriter + Send + Sync> {
     writer: Option<W>,
     seek: bool,
-    sealing_key: SealingKey<RandomNonceSequenceWrapper>,
+    key: BlockKey,
     buf: BufMut,
-    nonce_sequence: Arc<Mutex<RandomNonceSequence>>,
+    rng: Box<dyn RngCore + Send + Sync>,
+    // of the last block encrypted
+    nonce: Vec<u8>,
     ciphertext_block_size: usize,
     plaintext_block_size: usize,
     block_index: u64,
-    opening_key: Option<OpeningKey<ExistingNonceSequence>>,
-    last_nonce: Option<Arc<Mutex<Option<Vec<u8>>>>>,
     decrypt_buf: Option<BufMut>,
+    aad: Vec<u8>,
+    /// Where the holes are kept, if sparse.
+    holes: Option<SharedHoles>,
+    /// End of the holes skipped past the end of the stream, the stream is extended up to it by
+    /// [`RingCryptoWrite::end_holes`].
+    hole_end: u64,
+    parallel: Option<Arc<CryptoPool>>,
 }
 
 impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::needless_pass_by_value)]
+    /// `aad` is authenticated with each block, the reader needs the same one.
     pub fn new(
-        mut writer: W,
+        writer: W,
         seek: bool,
         algorithm: &'static Algorithm,
         key: &SecretVec<u8>,
+        aad: &[u8],
     ) -> Self {
-        let unbound_key = UnboundKey::new(algorithm, &key.expose_secret()).expect("unbound key");
-        let nonce_sequence = Arc::new(Mutex::new(RandomNonceSequence::default()));
-        let wrapping_nonce_sequence = RandomNonceSequenceWrapper::new(nonce_sequence.clone());
-        let sealing_key = SealingKey::new(unbound_key, wrapping_nonce_sequence);
-        let buf = BufMut::new(vec![0; BLOCK_SIZE]);
-
-        let (last_nonce, opening_key, decrypt_buf) = if writer.as_write_seek_read().is_some() {
-            let last_nonce = Arc::new(Mutex::new(None));
-            let unbound_key = UnboundKey::new(algorithm, &key.expose_secret()).unwrap();
-            let nonce_sequence2 = ExistingNonceSequence::new(last_nonce.clone());
-            let opening_key = OpeningKey::new(unbound_key, nonce_sequence2);
-            let ciphertext_block_size = NONCE_LEN + BLOCK_SIZE + algorithm.tag_len();
-            let decrypt_buf = BufMut::new(vec![0; ciphertext_block_size]);
+        Self::with_key(writer, seek, BlockKey::ring(algorithm, key), aad)
+    }
 
-            (Some(last_nonce), Some(opening_key), Some(decrypt_buf))
-        } else {
-            (None, None, None)
-        };
+    pub(crate) fn with_key(mut writer: W, seek: bool, key: BlockKey, aad: &[u8]) -> Self {
+        let ciphertext_block_size = key.nonce_len() + BLOCK_SIZE + key.tag_len();
+        let buf = BufMut::new(vec![0; BLOCK_SIZE]);
+        let decrypt_buf = writer
+            .as_write_seek_read()
+            .map(|_| BufMut::new(vec![0; ciphertext_block_size]));
         Self {
             writer: Some(writer),
             seek,
-            sealing_key,
+            nonce: vec![0; key.nonce_len()],
+            key,
             buf,
-            nonce_sequence,
-            ciphertext_block_size: NONCE_LEN + BLOCK_SIZE + algorithm.tag_len(),
+            rng: Box::new(crypto::create_rng()),
+            ciphertext_block_size,
             plaintext_block_size: BLOCK_SIZE,
             block_index: 0,
-            opening_key,
-            last_nonce,
             decrypt_buf,
+            aad: aad.to_vec(),
+            holes: None,
+            hole_end: 0,
+            parallel: None,
+        }
+    }
+
+    /// Encrypt the blocks of large writes on the threads of `pool`. With a single thread it does nothing.
+    #[must_use]
+    pub fn parallel(mut self, pool: Arc<CryptoPool>) -> Self {
+        if pool.threads() > 1 {
+            self.parallel = Some(pool);
+        }
+        self
+    }
+
+    /// Write the blocks which are all zeros as holes and keep their indexes in `holes`, see
+    /// [`RingCryptoRead::sparse`].
+    ///
+    /// A hole is stored as zeros instead of the encrypted block, the ones past the end of the stream are skipped, so
+    /// the storage doesn't keep them if it supports sparse files. Seeking past the end leaves holes up to the new
+    /// position instead of writing zeros. The blocks written with data are removed from `holes`. Read it with a
+    /// sparse reader with the same holes, they must be kept in authenticated metadata.
+    ///
+    /// [`RingCryptoRead::sparse`]: crate::crypto::read::RingCryptoRead::sparse
+    #[must_use]
+    pub fn sparse(mut self, holes: SharedHoles) -> Self {
+        self.holes = Some(holes);
+        self
+    }
+
+    /// Mark the blocks in `blocks` as holes or not, if sparse.
+    fn set_holes(&self, blocks: Range<u64>, hole: bool) {
+        if let Some(holes) = self.holes.as_ref() {
+            let mut holes = holes.lock().unwrap();
+            if hole {
+                holes.insert(blocks);
+            } else {
+                holes.remove(blocks);
+            }
         }
     }
 
     fn encrypt_and_write(&mut self) -> io::Result<()> {
+        if self.holes.is_some() && self.buf.as_ref().iter().all(|b| *b == 0) {
+            return self.write_hole();
+        }
+        self.set_holes(self.block_index..self.block_index + 1, false);
         let data = self.buf.as_mut();
-        let aad = Aad::from(self.block_index.to_le_bytes());
+        let aad = [&self.aad[..], &self.block_index.to_le_bytes()].concat();
+        self.rng.fill_bytes(&mut self.nonce);
         let tag = self
-            .sealing_key
-            .seal_in_place_separate_tag(aad, data)
+            .key
+            .seal_in_place_separate_tag(&self.nonce, &aad, data)
             .map_err(|err| {
                 error!("error sealing in place: {}", err);
                 io::Error::new(
@@ -135,21 +187,159 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
                     format!("error sealing in place: {err}"),
                 )
             })?;
-        let nonce_sequence = self.nonce_sequence.lock().unwrap();
-        let nonce = &nonce_sequence.last_nonce;
         let writer = self
             .writer
             .as_mut()
             .ok_or(io::Error::new(io::ErrorKind::NotConnected, "no writer"))?;
-        writer.write_all(nonce)?;
+        writer.write_all(&self.nonce)?;
         writer.write_all(data)?;
         self.buf.clear();
-        writer.write_all(tag.as_ref())?;
+        writer.write_all(&tag)?;
         writer.flush()?;
         self.block_index += 1;
         Ok(())
     }
 
+    /// Encrypt and write the whole blocks at the start of `buf` on the threads of the pool, if there are at least
+    /// [`MIN_PARALLEL_BLOCKS`] of them and we are at the start of a block.
+    ///
+    /// Returns the number of bytes written, `0` if it's left to be written one block at a time.
+    fn write_parallel(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(pool) = self.parallel.as_ref() else {
+            return Ok(0);
+        };
+        let blocks = buf.len() / self.plaintext_block_size;
+        if blocks < MIN_PARALLEL_BLOCKS || self.buf.is_dirty() || self.buf.pos_write() != 0 {
+            return Ok(0);
+        }
+        let plaintext = &buf[..blocks * self.plaintext_block_size];
+        if self.holes.is_some()
+            && plaintext
+                .chunks(self.plaintext_block_size)
+                .any(|block| block.iter().all(|b| *b == 0))
+        {
+            // the holes are written one by one
+            return Ok(0);
+        }
+        let mut nonces = vec![0; blocks * self.key.nonce_len()];
+        self.rng.fill_bytes(&mut nonces);
+        let ciphertext = pool.seal_blocks(
+            &self.key,
+            &self.aad,
+            self.block_index,
+            &nonces,
+            plaintext,
+            self.plaintext_block_size,
+        )?;
+        let writer = self
+            .writer
+            .as_mut()
+            .ok_or(io::Error::new(io::ErrorKind::NotConnected, "no writer"))?;
+        if self.seek {
+            // the loaded block, if any, is overwritten
+            let writer = writer.as_write_seek_read().ok_or(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "downcast failed",
+            ))?;
+            writer.seek(SeekFrom::Start(
+                self.block_index * self.ciphertext_block_size as u64,
+            ))?;
+        }
+        writer.write_all(&ciphertext)?;
+        writer.flush()?;
+        self.buf.clear();
+        self.set_holes(self.block_index..self.block_index + blocks as u64, false);
+        self.block_index += blocks as u64;
+        if self.seek {
+            // load the next block, so a smaller write in it keeps the rest of it
+            let writer = self
+                .writer
+                .as_mut()
+                .and_then(CryptoInnerWriter::as_write_seek_read)
+                .ok_or(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "downcast failed",
+                ))?;
+            if writer.stream_len()? > self.block_index * self.ciphertext_block_size as u64 {
+                self.decrypt_block()?;
+            }
+        }
+        Ok(plaintext.len())
+    }
+
+    /// Write the current block, which is all zeros, as a hole.
+    fn write_hole(&mut self) -> io::Result<()> {
+        let len =
+            (self.ciphertext_block_size - self.plaintext_block_size + self.buf.available()) as u64;
+        let writer = self
+            .writer
+            .as_mut()
+            .ok_or(io::Error::new(io::ErrorKind::NotConnected, "no writer"))?;
+        if writer.as_write_seek_read().is_none() {
+            io::copy(&mut io::repeat(0).take(len), writer)?;
+        } else {
+            let writer = writer.as_write_seek_read().unwrap();
+            let start = writer.stream_position()?;
+            if start >= writer.stream_len()? {
+                // past the end, nothing to overwrite
+                writer.seek(SeekFrom::Start(start + len))?;
+                self.hole_end = self.hole_end.max(start + len);
+            } else {
+                io::copy(&mut io::repeat(0).take(len), writer)?;
+            }
+        }
+        self.buf.clear();
+        self.set_holes(self.block_index..self.block_index + 1, true);
+        self.block_index += 1;
+        Ok(())
+    }
+
+    /// Leave holes from the end of the stream until the block with `block_index`, completing the last block with
+    /// zeros first.
+    fn skip_to_block(&mut self, block_index: u64) -> io::Result<()> {
+        if self.buf.available() > 0 {
+            let len = self.plaintext_block_size - self.buf.available();
+            self.buf
+                .seek_write(SeekFrom::Start(self.buf.available() as u64))?;
+            stream_util::fill_zeros(self, len as u64)?;
+            self.encrypt_and_write()?;
+        }
+        let end = block_index * self.ciphertext_block_size as u64;
+        let writer = self
+            .writer
+            .as_mut()
+            .ok_or(io::Error::new(io::ErrorKind::NotConnected, "no writer"))?
+            .as_write_seek_read()
+            .ok_or(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "downcast failed",
+            ))?;
+        writer.seek(SeekFrom::Start(end))?;
+        self.hole_end = self.hole_end.max(end);
+        self.buf.clear();
+        self.set_holes(self.block_index..block_index, true);
+        self.block_index = block_index;
+        Ok(())
+    }
+
+    /// Extend the stream over the holes skipped past its end, writing only their last byte.
+    fn end_holes(&mut self) -> io::Result<()> {
+        let Some(writer) = self
+            .writer
+            .as_mut()
+            .and_then(CryptoInnerWriter::as_write_seek_read)
+        else {
+            return Ok(());
+        };
+        if self.hole_end > writer.stream_len()? {
+            let pos = writer.stream_position()?;
+            writer.seek(SeekFrom::Start(self.hole_end - 1))?;
+            writer.write_all(&[0])?;
+            writer.seek(SeekFrom::Start(pos))?;
+        }
+        Ok(())
+    }
+
     const fn pos(&self) -> u64 {
         self.block_index * self.plaintext_block_size as u64 + self.buf.pos_write() as u64
     }
@@ -169,8 +359,9 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
             self.block_index,
             self.decrypt_buf.as_mut().unwrap(),
             writer,
-            self.last_nonce.as_ref().unwrap(),
-            self.opening_key.as_mut().unwrap()
+            &self.key,
+            &self.aad,
+            self.holes.as_ref()
         );
         if old_block_index == self.block_index {
             // no decryption happened
@@ -217,6 +408,10 @@ impl<W: CryptoInnerWriter + Send + Sync> Write for RingCryptoWrite<W> {
                 "write called on already finished writer",
             ));
         }
+        let len = self.write_parallel(buf)?;
+        if len > 0 {
+            return Ok(len);
+        }
         if self.pos() == 0 && self.buf.available() == 0 {
             if self.seek {
                 // first write since we opened the writer, try to load the first block
@@ -277,6 +472,7 @@ impl<W: CryptoInnerWriter + Send + Sync> CryptoWrite<W> for RingCryptoWrite<W> {
             // encrypt and write last block, use as many bytes as we have
             self.encrypt_and_write()?;
         }
+        self.end_holes()?;
         let boxed = self
             .writer
             .take()
@@ -288,44 +484,6 @@ impl<W: CryptoInnerWriter + Send + Sync> CryptoWrite<W> for RingCryptoWrite<W> {
     }
 }
 
-struct RandomNonceSequence {
-    rng: Mutex<Box<dyn RngCore + Send + Sync>>,
-    last_nonce: Vec<u8>,
-}
-
-impl Default for RandomNonceSequence {
-    fn default() -> Self {
-        Self {
-            rng: Mutex::new(Box::new(crypto::create_rng())),
-            last_nonce: vec![0; NONCE_LEN],
-        }
-    }
-}
-
-impl NonceSequence for RandomNonceSequence {
-    // called once for each seal operation
-    fn advance(&mut self) -> Result<Nonce, Unspecified> {
-        self.rng.lock().unwrap().fill_bytes(&mut self.last_nonce);
-        Nonce::try_assume_unique_for_key(&self.last_nonce)
-    }
-}
-
-struct RandomNonceSequenceWrapper {
-    inner: Arc<Mutex<RandomNonceSequence>>,
-}
-
-impl RandomNonceSequenceWrapper {
-    pub const fn new(inner: Arc<Mutex<RandomNonceSequence>>) -> Self {
-        Self { inner }
-    }
-}
-
-impl NonceSequence for RandomNonceSequenceWrapper {
-    fn advance(&mut self) -> Result<Nonce, Unspecified> {
-        self.inner.lock().unwrap().advance()
-    }
-}
-
 impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
     fn get_plaintext_len(&mut self) -> io::Result<u64> {
         let writer = self
@@ -337,7 +495,7 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
                 io::ErrorKind::NotConnected,
                 "downcast failed",
             ))?;
-        let ciphertext_len = writer.stream_len()?;
+        let ciphertext_len = writer.stream_len()?.max(self.hole_end);
         if ciphertext_len == 0 && self.buf.available() == 0 {
             return Ok(0);
         }
@@ -434,6 +592,9 @@ impl<W: CryptoInnerWriter + Send + Sync> Seek for RingCryptoWrite<W> {
             // try to decrypt target block
             self.block_index = target_block_index;
             self.decrypt_block()?;
+            if self.holes.is_some() && self.block_index < new_block_index {
+                self.skip_to_block(new_block_index)?;
+            }
             if self.block_index == new_block_index {
                 // seek inside new block as much as we can
                 let desired_offset = new_pos % self.plaintext_block_size as u64;
@@ -446,7 +607,7 @@ impl<W: CryptoInnerWriter + Send + Sync> Seek for RingCryptoWrite<W> {
                     .seek_write(SeekFrom::Start(self.buf.available() as u64))?;
             }
         }
-        // if we couldn't seek until new pos, write zeros until new position
+        // if we couldn't seek until new pos, write zeros until new position, only in the new block if sparse
         if self.pos() < new_pos {
             let len = new_pos - self.pos();
             stream_util::fill_zeros(self, len)?;
@@ -455,4 +616,27 @@ impl<W: CryptoInnerWriter + Send + Sync> Seek for RingCryptoWrite<W> {
     }
 }
 
-impl<W: CryptoInnerWriter + Send + Sync> CryptoWriteSeek<W> for RingCryptoWrite<W> {}
+impl<W: CryptoInnerWriter + Send + Sync> CryptoWriteSeek<W> for RingCryptoWrite<W> {
+    fn release_block(&mut self) -> io::Result<bool> {
+        let written = self.buf.is_dirty();
+        if written {
+            self.encrypt_and_write()?;
+        }
+        self.end_holes()?;
+        // start again like a new writer, which reads the block it writes in first
+        let writer = self
+            .writer
+            .as_mut()
+            .ok_or(io::Error::new(io::ErrorKind::NotConnected, "no writer"))?
+            .as_write_seek_read()
+            .ok_or(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "downcast failed",
+            ))?;
+        writer.seek(SeekFrom::Start(0))?;
+        self.block_index = 0;
+        self.buf.clear();
+        self.hole_end = 0;
+        Ok(written)
+    }
+}
@@ -1,13 +1,12 @@
 use std::io::{self, Seek, SeekFrom};
 
-use ring::aead::{Aad, Algorithm, BoundKey, OpeningKey, UnboundKey, NONCE_LEN};
+use ring::aead::{Aad, Algorithm, LessSafeKey, Nonce, UnboundKey, NONCE_LEN};
 use shush_rs::{ExposeSecret, SecretVec};
-use std::sync::{Arc, Mutex};
 #[allow(unused_imports)]
 use tracing_test::traced_test;
 
 use crate::crypto;
-use crate::crypto::read::{CryptoRead, ExistingNonceSequence};
+use crate::crypto::read::CryptoRead;
 use crate::crypto::Cipher;
 
 #[allow(dead_code)]
@@ -33,14 +32,14 @@ fn verify_encryption(
 
     let key_bytes = &key.expose_secret();
     let unbound_key = UnboundKey::new(algorithm, key_bytes).unwrap();
-    let nonce_sequence = ExistingNonceSequence::new(Arc::new(Mutex::new(Some(nonce.to_vec()))));
-    let mut opening_key = OpeningKey::new(unbound_key, nonce_sequence);
+    let opening_key = LessSafeKey::new(unbound_key);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).unwrap();
 
     let mut decrypted = encrypted[NONCE_LEN..].to_vec();
 
     let block_index: u64 = 0;
     let aad = Aad::from(block_index.to_le_bytes());
-    matches!(opening_key.open_in_place(aad, &mut decrypted), Ok(decrypted_data) if decrypted_data == plaintext)
+    matches!(opening_key.open_in_place(nonce, aad, &mut decrypted), Ok(decrypted_data) if decrypted_data == plaintext)
 }
 
 #[test]
@@ -95,7 +94,7 @@ fn test_flush() {
     let cipher = &CHACHA20_POLY1305;
     let key = create_secret_key(cipher.key_len());
 
-    let mut crypto_writer = RingCryptoWrite::new(writer, false, cipher, &key);
+    let mut crypto_writer = RingCryptoWrite::new(writer, false, cipher, &key, &[]);
 
     let data = b"Hello, world!";
 
@@ -117,7 +116,7 @@ fn test_write_after_finish() {
     let cipher = &CHACHA20_POLY1305;
     let key = create_secret_key(cipher.key_len());
 
-    let mut crypto_writer = RingCryptoWrite::new(writer, false, cipher, &key);
+    let mut crypto_writer = RingCryptoWrite::new(writer, false, cipher, &key, &[]);
 
     let data = b"Hello, world!";
 
@@ -134,7 +133,7 @@ fn test_encrypt_and_write_nonce_uniqueness() {
     use std::io::{Cursor, Write};
     let writer = Cursor::new(Vec::new());
     let key = create_secret_key(CHACHA20_POLY1305.key_len());
-    let mut crypto_writer = RingCryptoWrite::new(writer, false, &CHACHA20_POLY1305, &key);
+    let mut crypto_writer = RingCryptoWrite::new(writer, false, &CHACHA20_POLY1305, &key, &[]);
 
     crypto_writer.write_all(&[0u8; BLOCK_SIZE]).unwrap();
     crypto_writer.write_all(&[0u8; BLOCK_SIZE]).unwrap();
@@ -153,7 +152,7 @@ fn test_pos_initial() {
     use std::io::Cursor;
     let writer = Cursor::new(Vec::new());
     let key = create_secret_key(CHACHA20_POLY1305.key_len());
-    let crypto_writer = RingCryptoWrite::new(writer, false, &CHACHA20_POLY1305, &key);
+    let crypto_writer = RingCryptoWrite::new(writer, false, &CHACHA20_POLY1305, &key, &[]);
 
     assert_eq!(crypto_writer.pos(), 0);
 }
@@ -166,7 +165,7 @@ fn test_pos_after_write() {
     use std::io::{Cursor, Write};
     let writer = Cursor::new(Vec::new());
     let key = create_secret_key(CHACHA20_POLY1305.key_len());
-    let mut crypto_writer = RingCryptoWrite::new(writer, false, &CHACHA20_POLY1305, &key);
+    let mut crypto_writer = RingCryptoWrite::new(writer, false, &CHACHA20_POLY1305, &key, &[]);
 
     crypto_writer.write_all(b"Hello, World!").unwrap();
     assert_eq!(crypto_writer.pos(), 13);
@@ -180,7 +179,7 @@ fn test_pos_after_multiple_writes() {
     use std::io::{Cursor, Write};
     let writer = Cursor::new(Vec::new());
     let key = create_secret_key(CHACHA20_POLY1305.key_len());
-    let mut crypto_writer = RingCryptoWrite::new(writer, false, &CHACHA20_POLY1305, &key);
+    let mut crypto_writer = RingCryptoWrite::new(writer, false, &CHACHA20_POLY1305, &key, &[]);
 
     crypto_writer.write_all(b"Hello").unwrap();
     crypto_writer.write_all(b", ").unwrap();
@@ -196,7 +195,7 @@ fn test_pos_after_seek() {
     use std::io::{Cursor, Write};
     let writer = Cursor::new(Vec::new());
     let key = create_secret_key(CHACHA20_POLY1305.key_len());
-    let mut crypto_writer = RingCryptoWrite::new(writer, true, &CHACHA20_POLY1305, &key);
+    let mut crypto_writer = RingCryptoWrite::new(writer, true, &CHACHA20_POLY1305, &key, &[]);
 
     crypto_writer.write_all(b"Hello, World!").unwrap();
     crypto_writer.seek(SeekFrom::Start(7)).unwrap();
@@ -211,7 +210,7 @@ fn test_pos_after_seek_beyond_end() {
     use std::io::{Cursor, Write};
     let writer = Cursor::new(Vec::new());
     let key = create_secret_key(CHACHA20_POLY1305.key_len());
-    let mut crypto_writer = RingCryptoWrite::new(writer, true, &CHACHA20_POLY1305, &key);
+    let mut crypto_writer = RingCryptoWrite::new(writer, true, &CHACHA20_POLY1305, &key, &[]);
 
     crypto_writer.write_all(b"Hello, World!").unwrap();
     crypto_writer.seek(SeekFrom::End(10)).unwrap();
@@ -226,7 +225,7 @@ fn test_pos_after_write_full_block() {
     use std::io::{Cursor, Write};
     let writer = Cursor::new(Vec::new());
     let key = create_secret_key(CHACHA20_POLY1305.key_len());
-    let mut crypto_writer = RingCryptoWrite::new(writer, false, &CHACHA20_POLY1305, &key);
+    let mut crypto_writer = RingCryptoWrite::new(writer, false, &CHACHA20_POLY1305, &key, &[]);
 
     let full_block = vec![0u8; crypto_writer.plaintext_block_size];
     crypto_writer.write_all(&full_block).unwrap();
@@ -244,7 +243,7 @@ fn test_pos_after_write_multiple_blocks() {
     use std::io::{Cursor, Write};
     let writer = Cursor::new(Vec::new());
     let key = create_secret_key(CHACHA20_POLY1305.key_len());
-    let mut crypto_writer = RingCryptoWrite::new(writer, false, &CHACHA20_POLY1305, &key);
+    let mut crypto_writer = RingCryptoWrite::new(writer, false, &CHACHA20_POLY1305, &key, &[]);
 
     let data = vec![0u8; crypto_writer.plaintext_block_size * 3 + 100];
     crypto_writer.write_all(&data).unwrap();
@@ -262,7 +261,7 @@ fn test_pos_after_seek_and_write() {
     use std::io::{Cursor, Write};
     let writer = Cursor::new(Vec::new());
     let key = create_secret_key(CHACHA20_POLY1305.key_len());
-    let mut crypto_writer = RingCryptoWrite::new(writer, true, &CHACHA20_POLY1305, &key);
+    let mut crypto_writer = RingCryptoWrite::new(writer, true, &CHACHA20_POLY1305, &key, &[]);
 
     crypto_writer.write_all(b"Hello, World!").unwrap();
     crypto_writer.seek(SeekFrom::Start(7)).unwrap();
@@ -278,7 +277,7 @@ fn test_pos_after_flush() {
     use std::io::{Cursor, Write};
     let writer = Cursor::new(Vec::new());
     let key = create_secret_key(CHACHA20_POLY1305.key_len());
-    let mut crypto_writer = RingCryptoWrite::new(writer, false, &CHACHA20_POLY1305, &key);
+    let mut crypto_writer = RingCryptoWrite::new(writer, false, &CHACHA20_POLY1305, &key, &[]);
 
     crypto_writer.write_all(b"Hello, World!").unwrap();
     crypto_writer.flush().unwrap();
@@ -293,7 +292,7 @@ fn test_pos_consistency_with_seek() {
     use std::io::{Cursor, Seek, Write};
     let writer = Cursor::new(Vec::new());
     let key = create_secret_key(CHACHA20_POLY1305.key_len());
-    let mut crypto_writer = RingCryptoWrite::new(writer, true, &CHACHA20_POLY1305, &key);
+    let mut crypto_writer = RingCryptoWrite::new(writer, true, &CHACHA20_POLY1305, &key, &[]);
 
     crypto_writer.write_all(b"Hello, World!").unwrap();
     let pos1 = crypto_writer.pos();
@@ -1381,3 +1380,53 @@ fn writer_with_seeks() {
     writer.seek(SeekFrom::Start(42)).unwrap();
     assert_eq!(writer.stream_position().unwrap(), 42);
 }
+
+#[test]
+#[traced_test]
+fn writers_release_block() {
+    use std::fs::{File, OpenOptions};
+    use std::io::{Read, Write};
+
+    use crate::crypto::write::{CryptoWrite, CryptoWriteSeek, BLOCK_SIZE};
+
+    let cipher = Cipher::ChaCha20Poly1305;
+    let key = create_secret_key(cipher.key_len());
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let open = || -> File {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(file.path())
+            .unwrap()
+    };
+    let mut writer1 = crypto::create_write_seek(open(), cipher, &key);
+    let mut writer2 = crypto::create_write_seek(open(), cipher, &key);
+
+    // each one in the block the other wrote to, releasing it before the other writes
+    writer1.write_all(&[b'a'; BLOCK_SIZE + 10]).unwrap();
+    assert!(writer1.release_block().unwrap());
+    writer2.seek(SeekFrom::Start(5)).unwrap();
+    writer2.write_all(b"bb").unwrap();
+    writer2
+        .seek(SeekFrom::Start(BLOCK_SIZE as u64 + 2))
+        .unwrap();
+    writer2.write_all(b"bb").unwrap();
+    assert!(writer2.release_block().unwrap());
+    writer1
+        .seek(SeekFrom::Start(BLOCK_SIZE as u64 + 4))
+        .unwrap();
+    writer1.write_all(b"cc").unwrap();
+    writer1.finish().unwrap();
+    // nothing changed since it was released
+    assert!(!writer2.release_block().unwrap());
+    writer2.finish().unwrap();
+
+    let mut expected = vec![b'a'; BLOCK_SIZE + 10];
+    expected[5..7].copy_from_slice(b"bb");
+    expected[BLOCK_SIZE + 2..BLOCK_SIZE + 4].copy_from_slice(b"bb");
+    expected[BLOCK_SIZE + 4..BLOCK_SIZE + 6].copy_from_slice(b"cc");
+    let mut reader = crypto::create_read(open(), cipher, &key);
+    let mut plaintext = vec![];
+    reader.read_to_end(&mut plaintext).unwrap();
+    assert_eq!(expected, plaintext);
+}
@@ -1,62 +1,173 @@
 use argon2::password_hash::rand_core::RngCore;
 use async_trait::async_trait;
-use futures_util::TryStreamExt;
+use futures_util::StreamExt;
 use lru::LruCache;
 use num_format::{Locale, ToFormattedString};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use shush_rs::{ExposeSecret, SecretBox, SecretString, SecretVec};
 use std::backtrace::Backtrace;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
-use std::fs::{DirEntry, File, OpenOptions, ReadDir};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::num::{NonZeroUsize, ParseIntError};
-use std::path::{Path, PathBuf};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::future::Future;
+use std::io;
+use std::io::{Cursor, IoSlice, Read, Seek, SeekFrom, Write};
+use std::num::{NonZeroU64, NonZeroUsize, ParseIntError};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, LazyLock, Weak};
-use std::time::{Duration, SystemTime};
-use std::{fs, io};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
-use tokio::runtime::Runtime;
+use tokio::runtime::Handle;
 use tokio::sync::{Mutex, RwLock};
 use tokio::task::{JoinError, JoinSet};
-use tokio_stream::wrappers::ReadDirStream;
 use tracing::{debug, error, info, instrument, warn, Level};
 
 use crate::arc_hashmap::ArcHashMap;
+use crate::crypto::holes::{Holes, SharedHoles};
+use crate::crypto::parallel::CryptoPool;
 use crate::crypto::read::{CryptoRead, CryptoReadSeek};
-use crate::crypto::write::{CryptoInnerWriter, CryptoWrite, CryptoWriteSeek};
-use crate::crypto::Cipher;
+use crate::crypto::write::{CryptoInnerWriter, CryptoWrite, CryptoWriteSeek, BLOCK_SIZE};
+use crate::crypto::{Cipher, CipherChoice};
 use crate::expire_value::{ExpireValue, ValueProvider};
-use crate::{crypto, fs_util, stream_util};
+use crate::segmented_file::SegmentedFile;
+use crate::sharded_map::ShardedMap;
+use crate::storage::{EntryKind, LocalStorage, Storage, StorageEntry};
+use crate::{async_util, crypto, stream_util};
 use bon::bon;
+use journal::{JournalOp, ReplacedEntry};
+use key_slots::{KeySlot, KeySlots};
+use op_control::ControlledRead;
 
+mod access;
+mod archive;
+mod async_io;
 mod bench;
+mod block_cache;
+mod changes;
+mod check;
+mod cipher_change;
+mod compression;
+#[cfg(any(test, feature = "fuzz"))]
+mod consistency;
+mod dir_keys;
+mod events;
+mod import;
+mod journal;
+mod key_slots;
+mod locks;
+mod metrics;
+mod op_control;
+mod path;
+mod quota;
+mod recovery_key;
+#[cfg(feature = "snapshots")]
+mod snapshot;
 #[cfg(test)]
 mod test;
+mod throttle;
+mod unicode;
+mod watch;
+
+pub use access::{ContextFs, RequestContext, TimeOrNow, ACCESS_EXEC, ACCESS_READ, ACCESS_WRITE};
+pub use async_io::{EncryptedFileReader, EncryptedFileWriter};
+pub use changes::ChangeRecord;
+pub use check::{CheckProblem, CheckProblemKind, CheckReport, LOST_FOUND_DIR};
+#[cfg(any(test, feature = "fuzz"))]
+pub use consistency::{check_consistency, check_consistency_blocking, ConsistencyOp};
+pub use events::FsEvent;
+pub use import::{ImportFilter, ImportOptions, ImportOverwrite, ImportProgress, ImportProgressFn};
+pub use locks::{Flock, LockInfo, LockType};
+pub use metrics::{CacheStats, MetricsSnapshot, OpStats, LATENCY_BUCKETS};
+pub use op_control::{OpControl, OpProgressFn};
+#[cfg(any(feature = "sftp", feature = "webdav"))]
+pub(crate) use path::split_parent;
+pub use quota::DirQuota;
+pub use throttle::{ThrottleLimits, ThrottleStatus};
+pub use unicode::NormalizedName;
+#[cfg(feature = "watch")]
+pub use watch::ExternalChangeWatcher;
 
 pub(crate) const INODES_DIR: &str = "inodes";
 pub(crate) const CONTENTS_DIR: &str = "contents";
 pub(crate) const SECURITY_DIR: &str = "security";
 pub(crate) const KEY_ENC_FILENAME: &str = "key.enc";
 pub(crate) const KEY_SALT_FILENAME: &str = "key.salt";
+pub(crate) const VERSION_FILENAME: &str = "version";
+/// The [`Cipher`] of the data dir, so it's opened with [`CipherChoice::Auto`] without knowing it.
+pub(crate) const CIPHER_FILENAME: &str = "cipher";
+/// Locked by each [`EncryptedFs`] using the data dir, see [`lock_instance`].
+pub(crate) const INSTANCE_LOCK_FILENAME: &str = "instance.lock";
 
 pub(crate) const LS_DIR: &str = "ls";
+/// Start of the names in [`LS_DIR`] which are a hash of the encrypted name, see [`ls_entry_name`]. It's not in the
+/// alphabet of the encrypted names.
+pub(crate) const LONG_NAME_PREFIX: char = '#';
 pub(crate) const HASH_DIR: &str = "hash";
+/// Salt of the hashes in [`HASH_DIR`], kept in the contents dir of each directory, see [`crypto::hash_file_name`].
+pub(crate) const NAME_SALT_FILENAME: &str = "salt";
+/// Number of entries in [`LS_DIR`], kept in the contents dir of each directory so [`EncryptedFs::len`] doesn't need
+/// to list them. Directories made before it existed get it on the first [`EncryptedFs::len`].
+pub(crate) const CHILD_COUNT_FILENAME: &str = "count";
+/// Next inode number of [`InodeAlloc::Sequential`], kept in [`SECURITY_DIR`]. All the ones below it may be used.
+pub(crate) const INODE_COUNTER_FILENAME: &str = "next_ino";
+/// How many inode numbers [`InodeAlloc::Sequential`] reserves in [`INODE_COUNTER_FILENAME`] at once.
+const INODE_COUNTER_BATCH: u64 = 1024;
+/// Size of the random bytes written at once by [`EncryptedFs::shred`].
+const SHRED_BUF_LEN: u64 = 256 * 1024;
+/// Directory in [`SECURITY_DIR`] with the values of [`EncryptedFs::serialize_encrypted`].
+pub(crate) const APP_DATA_DIR: &str = "app";
+/// Directory next to [`INODES_DIR`] with a data dir for each snapshot, only with the `snapshots` feature, see
+/// [`EncryptedFs::create_snapshot`].
+pub(crate) const SNAPSHOTS_DIR: &str = "snapshots";
 
 pub(crate) const ROOT_INODE: u64 = 1;
 
-fn spawn_runtime() -> Runtime {
-    let runtime = tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .build()
-        .unwrap();
-    runtime
-}
-
-static DIR_ENTRIES_RT: LazyLock<Runtime> = LazyLock::new(spawn_runtime);
-static NOD_RT: LazyLock<Runtime> = LazyLock::new(spawn_runtime);
+/// Version of the on-disk layout, kept in [`VERSION_FILENAME`] inside [`SECURITY_DIR`].
+/// - `0`: flat `inodes` and `contents` directories, data dirs created before the version file existed
+/// - `1`: `inodes` and `contents` are sharded, see [`shard_path`]
+/// - `2`: names in [`HASH_DIR`] are keyed hashes salted per directory, see [`crypto::hash_file_name`]
+/// - `3`: inodes and directory entries are bound to their location, see [`inode_aad`] and [`dir_entry_aad`]
+/// - `4`: inodes have [`FileAttr::compressed`]
+/// - `5`: inodes have [`FileAttr::generation`]
+/// - `6`: the contents can have holes, see [`crypto::create_sparse_write_seek`]
+/// - `7`: inodes have [`FileAttr::key_id`]
+/// - `8`: names can be padded, see [`FsOptions::name_padding`]
+/// - `9`: entries in [`LS_DIR`] can be named by a hash of their encrypted name, see [`ls_entry_name`]
+/// - `10`: the encrypted names are base32 and the entries of `.` and `..` don't end with a dot, see
+///   [`crypto::encrypt_file_name`]
+/// - `11`: inodes have [`FileAttr::ino_generation`]
+/// - `12`: the holes of the contents are kept in the inodes after the attributes, see [`Holes`]
+/// - `13`: the changed inodes are logged, see [`EncryptedFs::changes_since`]
+/// - `14`: inodes have [`FileAttr::quota`] and the total size in [`quota::USED_BYTES_FILENAME`] is encrypted
+///
+/// The contents were in blocks from the start, each with its own nonce and tag and its index as AAD, see
+/// [`crypto::create_write_seek`]. So writing in the middle of a file only encrypts the blocks it changes, and
+/// doing it without going through the blocks before needed no new version or migration.
+pub(crate) const FORMAT_VERSION: u32 = 14;
+
+/// How long the count of inodes used by [`EncryptedFs::statfs`] is cached.
+const INODES_COUNT_TTL: Duration = Duration::from_secs(5);
+
+/// Names of the entries of `.` and `..` up to version `9`, see [`crypto::SELF_ENTRY_NAME`].
+const LEGACY_SELF_ENTRY_NAME: &str = "$.";
+const LEGACY_PARENT_ENTRY_NAME: &str = "$..";
+
+/// Max length in bytes of a name, like the max length of a path on Linux.
+const MAX_NAME_LEN: usize = 4096;
+
+/// The permission bits of [`FileAttr::perm`] which can be set with [`EncryptedFs::set_permissions`].
+const PERM_MASK: u16 = 0o7777;
+
+/// Unit of [`FileAttr::blocks`].
+const STAT_BLOCK_SIZE: u64 = 512;
+
+/// Max bytes kept in memory at once by [`EncryptedFs::copy_file_range`].
+pub const COPY_FILE_RANGE_CHUNK_SIZE: usize = 1024 * 1024;
 
 /// File attributes.
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
@@ -65,7 +176,8 @@ pub struct FileAttr {
     pub ino: u64,
     /// Size in bytes
     pub size: u64,
-    /// Size in blocks
+    /// Size in 512-byte units the contents take in the storage, like `st_blocks`, so `du` works. The holes are not
+    /// counted, it's updated when the inode is written
     pub blocks: u64,
     /// Time of last access
     pub atime: SystemTime,
@@ -87,29 +199,84 @@ pub struct FileAttr {
     pub gid: u32,
     /// Rdev
     pub rdev: u32,
-    /// Block size
+    /// Preferred size of the IO, the plaintext size of an encrypted block
     pub blksize: u32,
     /// Flags (macOS only, see chflags(2))
     pub flags: u32,
+    /// The contents are compressed before being encrypted, see [`FsOptions::compression_level`].
+    pub compressed: bool,
+    /// Generation of the last time it was written, see [`EncryptedFs::changes_since`].
+    pub generation: u64,
+    /// Directory key the contents and the names of the entries are encrypted with, `None` for the master key, see
+    /// [`EncryptedFs::set_directory_key`].
+    pub key_id: Option<u32>,
+    /// Generation of the inode number, the [`FileAttr::generation`] it was created at, so a file created later with
+    /// the same number has another one, see [`FileId`]. `0` for the inodes created before it was kept.
+    pub ino_generation: u64,
+    /// Max sum of the sizes of the files under the directory, `None` if it has no quota, see
+    /// [`EncryptedFs::set_dir_quota`].
+    pub quota: Option<u64>,
+}
+
+impl FileAttr {
+    /// The [`FileId`] of this file.
+    #[must_use]
+    pub const fn file_id(&self) -> FileId {
+        FileId {
+            ino: self.ino,
+            generation: self.ino_generation,
+        }
+    }
+
+    /// Set [`FileAttr::blocks`] from the size and [`FileAttr::blksize`] for the inodes written before they were kept,
+    /// which have them at `0`.
+    #[allow(clippy::cast_possible_truncation)]
+    fn fill_blocks(&mut self) {
+        if self.blksize == 0 {
+            self.blocks = self.size.div_ceil(STAT_BLOCK_SIZE);
+            self.blksize = BLOCK_SIZE as u32;
+        }
+    }
+}
+
+/// A file by its inode number and its [`FileAttr::ino_generation`], so it doesn't find another file which was given
+/// the same number after it was removed, see [`EncryptedFs::open_id`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct FileId {
+    pub ino: u64,
+    pub generation: u64,
 }
 
 /// File types.
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub enum FileType {
-    // /// Named pipe (S_IFIFO)
-    // NamedPipe,
-    // /// Character device (S_IFCHR)
-    // CharDevice,
-    // /// Block device (S_IFBLK)
-    // BlockDevice,
     /// Directory (`S_IFDIR`)
     Directory,
     /// Regular file (`S_IFREG`)
     RegularFile,
+    // the variants are serialized by their index, the new ones go at the end so the data dirs can still be read
+    /// Named pipe (`S_IFIFO`)
+    NamedPipe,
+    /// Character device (`S_IFCHR`), its number is [`FileAttr::rdev`]
+    CharDevice,
+    /// Block device (`S_IFBLK`), its number is [`FileAttr::rdev`]
+    BlockDevice,
+    /// Unix domain socket (`S_IFSOCK`)
+    Socket,
     // /// Symbolic link (S_IFLNK)
     // Symlink,
-    // /// Unix domain socket (S_IFSOCK)
-    // Socket,
+}
+
+impl FileType {
+    /// If it's a FIFO, a device or a socket. They are only their attributes, they can't be opened, read or written
+    /// here, the kernel gives them to the pipe, the device or the socket.
+    #[must_use]
+    pub const fn is_special(self) -> bool {
+        matches!(
+            self,
+            Self::NamedPipe | Self::CharDevice | Self::BlockDevice | Self::Socket
+        )
+    }
 }
 
 #[derive(Debug, Clone, Copy, Default)]
@@ -134,6 +301,9 @@ pub struct SetFileAttr {
     pub rdev: Option<u32>,
     /// Flags (macOS only, see chflags(2))
     pub flags: Option<u32>,
+    /// The atime and mtime set are the current time, like `UTIME_NOW` in `utimensat(2)`. In
+    /// [`ContextFs::set_attr`] write permission is enough for them, explicit times need the owner.
+    pub times_now: bool,
 }
 
 impl SetFileAttr {
@@ -193,7 +363,17 @@ impl SetFileAttr {
 
     #[must_use]
     pub const fn with_flags(mut self, flags: u32) -> Self {
-        self.rdev = Some(flags);
+        self.flags = Some(flags);
+        self
+    }
+
+    /// Set the atime and the mtime to the current time, see [`SetFileAttr::times_now`].
+    #[must_use]
+    pub fn with_times_now(mut self) -> Self {
+        let now = SystemTime::now();
+        self.atime = Some(now);
+        self.mtime = Some(now);
+        self.times_now = true;
         self
     }
 }
@@ -212,6 +392,38 @@ pub struct CreateFileAttr {
     pub rdev: u32,
     /// Flags (macOS only, see chflags(2))
     pub flags: u32,
+    /// Compress the contents of a regular file, `None` to do it if [`FsOptions::compression_level`] is set.
+    pub compress: Option<bool>,
+}
+
+/// How to open a file in [`EncryptedFs::open_with_flags`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpenFlags {
+    pub read: bool,
+    pub write: bool,
+    /// Truncate the file to zero length, like `O_TRUNC`. Needs `write`.
+    pub truncate: bool,
+}
+
+/// How to create a node in [`EncryptedFs::create_with_flags`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CreateFlags {
+    pub read: bool,
+    pub write: bool,
+    /// Fail with [`FsError::AlreadyExists`] if the name exists, like `O_EXCL`. Otherwise the existing file is opened.
+    pub exclusive: bool,
+    /// Truncate the existing file to zero length when opening it, like `O_TRUNC`. Needs `write`.
+    pub truncate: bool,
+}
+
+impl From<CreateFlags> for OpenFlags {
+    fn from(value: CreateFlags) -> Self {
+        Self {
+            read: value.read,
+            write: value.write,
+            truncate: value.truncate,
+        }
+    }
 }
 
 impl From<CreateFileAttr> for FileAttr {
@@ -235,8 +447,14 @@ impl From<CreateFileAttr> for FileAttr {
             uid: value.uid,
             gid: value.gid,
             rdev: value.rdev,
-            blksize: 0,
+            #[allow(clippy::cast_possible_truncation)]
+            blksize: BLOCK_SIZE as u32,
             flags: value.flags,
+            compressed: value.kind == FileType::RegularFile && value.compress == Some(true),
+            generation: 0,
+            key_id: None,
+            ino_generation: 0,
+            quota: None,
         }
     }
 }
@@ -255,24 +473,22 @@ pub enum FsError {
         source: bincode::Error,
         backtrace: Backtrace,
     },
-    #[error("item not found: {0}")]
-    NotFound(&'static str),
+    #[error("item not found: {what}")]
+    NotFound { what: String },
     #[error("inode not found")]
     InodeNotFound,
-    #[error("invalid input")]
-    InvalidInput(&'static str),
+    #[error("invalid input: {reason}")]
+    InvalidInput { reason: String },
     #[error("invalid node type")]
     InvalidInodeType,
     #[error("invalid file handle")]
     InvalidFileHandle,
     #[error("already exists")]
     AlreadyExists,
-    #[error("already open for write")]
-    AlreadyOpenForWrite,
     #[error("not empty")]
     NotEmpty,
-    #[error("other: {0}")]
-    Other(&'static str),
+    #[error("other: {message}")]
+    Other { message: String },
     #[error("invalid password")]
     InvalidPassword,
     #[error("invalid structure of data directory")]
@@ -301,10 +517,111 @@ pub enum FsError {
         source: JoinError,
         backtrace: Backtrace,
     },
-    #[error("max filesize exceeded, max allowed {0}")]
-    MaxFilesizeExceeded(usize),
     #[error("Read only mode is active.")]
     ReadOnly,
+    #[error("unsupported data dir version {0}")]
+    UnsupportedVersion(u32),
+    #[error("offset is past the end of file")]
+    OffsetPastEof,
+    #[error("integrity violation, data was moved or altered")]
+    IntegrityViolation,
+    #[error("data dir is already in use{}", pid.map(|pid| format!(" by process {pid}")).unwrap_or_default())]
+    AlreadyMounted { pid: Option<u32> },
+    #[error("changing the cipher was interrupted, run it again to complete it")]
+    CipherChangeInterrupted,
+    #[error("locked, no password was provided")]
+    Locked,
+    #[error("corrupted {what}{}", ino.map(|ino| format!(" of inode {ino}")).unwrap_or_default())]
+    Corrupted { what: String, ino: Option<u64> },
+    #[error("internal error: {0}")]
+    Internal(String),
+    #[error("quota exceeded")]
+    QuotaExceeded,
+    #[error("permission denied")]
+    PermissionDenied,
+    #[error("operation not permitted")]
+    NotPermitted,
+    #[error("file name too long")]
+    NameTooLong,
+    #[error("cannot seek to offset {requested}, stopped at {reached}")]
+    SeekFailed { requested: u64, reached: u64 },
+    #[error("file too large")]
+    MaxFilesizeExceeded,
+    #[error("operation cancelled")]
+    Cancelled,
+    #[error("a conflicting lock is held")]
+    LockConflict,
+    #[error("waiting for the lock would deadlock")]
+    Deadlock,
+    #[error("stale file id, the file was removed")]
+    StaleFileId,
+}
+
+impl FsError {
+    pub fn not_found(what: impl Into<String>) -> Self {
+        Self::NotFound { what: what.into() }
+    }
+
+    pub fn invalid_input(reason: impl Into<String>) -> Self {
+        Self::InvalidInput {
+            reason: reason.into(),
+        }
+    }
+
+    pub fn other(message: impl Into<String>) -> Self {
+        Self::Other {
+            message: message.into(),
+        }
+    }
+
+    /// The `errno` value closest to this error, for the mount layers.
+    pub fn to_errno(&self) -> i32 {
+        match self {
+            Self::Io { source, .. } => source.raw_os_error().unwrap_or(libc::EIO),
+            Self::NotFound { .. } | Self::InodeNotFound => libc::ENOENT,
+            Self::InvalidInput { .. } | Self::InvalidInodeType => libc::EINVAL,
+            Self::InvalidFileHandle => libc::EBADF,
+            Self::AlreadyExists => libc::EEXIST,
+            Self::NotEmpty => libc::ENOTEMPTY,
+            Self::InvalidPassword | Self::Locked => libc::EACCES,
+            Self::ReadOnly => libc::EROFS,
+            Self::OffsetPastEof => libc::ENXIO,
+            Self::AlreadyMounted { .. } => libc::EBUSY,
+            Self::QuotaExceeded => libc::ENOSPC,
+            Self::PermissionDenied => libc::EACCES,
+            Self::NotPermitted => libc::EPERM,
+            Self::NameTooLong => libc::ENAMETOOLONG,
+            Self::MaxFilesizeExceeded => libc::EFBIG,
+            Self::Cancelled => libc::ECANCELED,
+            Self::LockConflict => libc::EAGAIN,
+            Self::Deadlock => libc::EDEADLK,
+            Self::StaleFileId => libc::ESTALE,
+            Self::SerializeError { .. }
+            | Self::Other { .. }
+            | Self::InvalidDataDirStructure
+            | Self::Crypto { .. }
+            | Self::Keyring { .. }
+            | Self::ParseIntError { .. }
+            | Self::JoinError { .. }
+            | Self::UnsupportedVersion(_)
+            | Self::IntegrityViolation
+            | Self::CipherChangeInterrupted
+            | Self::Corrupted { .. }
+            | Self::SeekFailed { .. }
+            | Self::Internal(_) => libc::EIO,
+        }
+    }
+
+    /// Turn an error reading the data of `what` into [`FsError::Corrupted`], the others are kept.
+    fn into_corrupted(self, what: &str, ino: Option<u64>) -> Self {
+        match self {
+            Self::SerializeError { .. } => Self::Corrupted {
+                what: what.to_owned(),
+                ino,
+            },
+            err => err,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -394,6 +711,17 @@ impl From<FileAttr> for TimesFileAttr {
     }
 }
 
+impl From<TimesAndSizeFileAttr> for TimesFileAttr {
+    fn from(value: TimesAndSizeFileAttr) -> Self {
+        Self {
+            atime: value.atime,
+            mtime: value.mtime,
+            ctime: value.ctime,
+            crtime: value.crtime,
+        }
+    }
+}
+
 impl From<TimesFileAttr> for SetFileAttr {
     fn from(value: TimesFileAttr) -> Self {
         Self::default()
@@ -449,6 +777,28 @@ impl Iterator for DirectoryEntryIterator {
     }
 }
 
+/// An entry of [`EncryptedFs::read_dir_tolerant`].
+#[derive(Debug)]
+pub enum DirectoryEntryResult {
+    Entry(DirectoryEntry),
+    /// The entry can't be read, like when its name doesn't decrypt or the `ls` file is truncated.
+    Corrupted {
+        /// Name of the `ls` file of the entry, to find it in the storage.
+        encrypted_name: String,
+        error: FsError,
+    },
+}
+
+pub struct DirectoryEntryResultIterator(VecDeque<DirectoryEntryResult>);
+
+impl Iterator for DirectoryEntryResultIterator {
+    type Item = DirectoryEntryResult;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.pop_front()
+    }
+}
+
 pub struct DirectoryEntryPlusIterator(VecDeque<FsResult<DirectoryEntryPlus>>);
 
 impl Iterator for DirectoryEntryPlusIterator {
@@ -463,7 +813,9 @@ impl Iterator for DirectoryEntryPlusIterator {
 struct ReadHandleContext {
     ino: u64,
     attr: TimesFileAttr,
-    reader: Option<Box<dyn CryptoReadSeek<File>>>,
+    // the access time was updated, it's written on release
+    accessed: bool,
+    reader: Option<Box<dyn CryptoReadSeek<SegmentedFile>>>,
 }
 
 enum ReadHandleContextOperation {
@@ -482,24 +834,45 @@ enum WriteHandleContextOperation {
     Create { ino: u64 },
 }
 
-impl WriteHandleContextOperation {
-    const fn get_ino(&self) -> u64 {
-        match *self {
-            Self::Create { ino, .. } => ino,
-        }
-    }
-}
-
 struct WriteHandleContext {
     ino: u64,
     attr: TimesAndSizeFileAttr,
-    writer: Option<Box<dyn CryptoWriteSeek<File>>>,
+    // `attr` changed by writes since it was last written to the inode by `EncryptedFs::flush` or `EncryptedFs::fsync`
+    attr_dirty: bool,
+    writer: Option<Box<dyn CryptoWriteSeek<SegmentedFile>>>,
+    // from the first to the last byte written, sent with `FsEvent::Written` on release
+    written: Option<Range<u64>>,
+    // contiguous small writes not given to the writer yet, starting at `buffer_offset`, see
+    // `FsOptions::write_buffer_size`
+    buffer: Vec<u8>,
+    buffer_offset: u64,
+}
+
+impl WriteHandleContext {
+    /// Give what is in the buffer to the writer. It stays in the writer's last block until that is written.
+    ///
+    /// The writer is missing only when suspended, and it's suspended only after this is called.
+    fn write_buffer(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let writer = self
+            .writer
+            .as_mut()
+            .ok_or_else(|| io::Error::other("writer is missing"))?;
+        if writer.seek(SeekFrom::Start(self.buffer_offset))? != self.buffer_offset {
+            return Err(io::Error::other("cannot seek to the buffered writes"));
+        }
+        writer.write_all(&self.buffer)?;
+        self.buffer.clear();
+        Ok(())
+    }
 }
 
 struct KeyProvider {
-    key_path: PathBuf,
-    salt_path: PathBuf,
-    password_provider: Box<dyn PasswordProvider>,
+    storage: Arc<dyn Storage>,
+    // shared with the views of the snapshots
+    password_provider: Arc<dyn AsyncPasswordProvider>,
     cipher: Cipher,
 }
 
@@ -509,8 +882,9 @@ impl ValueProvider<SecretVec<u8>, FsError> for KeyProvider {
         let password = self
             .password_provider
             .get_password()
-            .ok_or(FsError::InvalidPassword)?;
-        read_or_create_key(&self.key_path, &self.salt_path, &password, self.cipher)
+            .await
+            .ok_or(FsError::Locked)?;
+        read_or_create_key(&*self.storage, &key_path(), &password, self.cipher).await
     }
 }
 
@@ -518,42 +892,375 @@ pub trait PasswordProvider: Send + Sync + 'static {
     fn get_password(&self) -> Option<SecretString>;
 }
 
-struct DirEntryNameCacheProvider {}
+impl<T: PasswordProvider + ?Sized> PasswordProvider for Box<T> {
+    fn get_password(&self) -> Option<SecretString> {
+        (**self).get_password()
+    }
+}
+
+/// Like [`PasswordProvider`] for providers which need to wait for the password, like from a secrets service or
+/// a prompt.
+///
+/// All [`PasswordProvider`]s are also [`AsyncPasswordProvider`]s.
+#[async_trait]
+pub trait AsyncPasswordProvider: Send + Sync + 'static {
+    async fn get_password(&self) -> Option<SecretString>;
+}
+
+#[async_trait]
+impl<T: PasswordProvider + ?Sized> AsyncPasswordProvider for T {
+    async fn get_password(&self) -> Option<SecretString> {
+        PasswordProvider::get_password(self)
+    }
+}
+
+struct DirEntryNameCacheProvider {
+    capacity: NonZeroUsize,
+}
 #[async_trait]
 impl ValueProvider<Mutex<LruCache<String, SecretString>>, FsError> for DirEntryNameCacheProvider {
     async fn provide(&self) -> Result<Mutex<LruCache<String, SecretString>>, FsError> {
-        Ok(Mutex::new(LruCache::new(NonZeroUsize::new(2000).unwrap())))
+        Ok(Mutex::new(LruCache::new(self.capacity)))
     }
 }
 
-struct DirEntryMetaCacheProvider {}
+struct DirEntryMetaCacheProvider {
+    capacity: NonZeroUsize,
+}
 #[async_trait]
 impl ValueProvider<Mutex<DirEntryMetaCache>, FsError> for DirEntryMetaCacheProvider {
     async fn provide(&self) -> Result<Mutex<DirEntryMetaCache>, FsError> {
-        Ok(Mutex::new(LruCache::new(NonZeroUsize::new(2000).unwrap())))
+        Ok(Mutex::new(LruCache::new(self.capacity)))
     }
 }
 
-struct AttrCacheProvider {}
+struct AttrCacheProvider {
+    capacity: NonZeroUsize,
+}
 #[async_trait]
 impl ValueProvider<RwLock<LruCache<u64, FileAttr>>, FsError> for AttrCacheProvider {
     async fn provide(&self) -> Result<RwLock<LruCache<u64, FileAttr>>, FsError> {
-        Ok(RwLock::new(LruCache::new(NonZeroUsize::new(2000).unwrap())))
+        Ok(RwLock::new(LruCache::new(self.capacity)))
+    }
+}
+
+const DEFAULT_CACHE_CAPACITY: NonZeroUsize = match NonZeroUsize::new(2000) {
+    Some(v) => v,
+    None => unreachable!(),
+};
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+const DEFAULT_DIR_TIMES_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// With [`AtimePolicy::Relatime`] the access time is updated at least this often.
+const RELATIME_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+const DEFAULT_DIR_ENTRIES_CONCURRENCY: NonZeroUsize = match NonZeroUsize::new(64) {
+    Some(v) => v,
+    None => unreachable!(),
+};
+const DEFAULT_WRITE_BUFFER_SIZE: usize = 256 * 1024;
+
+/// One for each core, see [`FsOptions::crypto_threads`].
+fn default_crypto_threads() -> usize {
+    thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
+
+/// When the metadata and the contents are synced to the storage, see [`FsOptions::durability`].
+///
+/// Syncing every change is slow, creating 100 files takes 0.965 sec with it and 0.130 sec without.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DurabilityPolicy {
+    /// Sync every change as it's made, a created file is on disk when [`EncryptedFs::create`] returns.
+    #[default]
+    Always,
+    /// Sync the changes on [`EncryptedFs::release`] and [`EncryptedFs::fsync`], a crash before may lose them.
+    OnRelease,
+    /// Never sync, only [`EncryptedFs::fsync`] does. A crash may lose the changes not yet written back by the OS.
+    Never,
+}
+
+/// The order the entries of a directory are listed in, see [`FsOptions::dir_iteration_order`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DirIterationOrder {
+    /// The order the storage lists them in, which differs between filesystems and changes as entries are added.
+    #[default]
+    Unordered,
+    /// `.` and `..` first, then by name compared by code points. The entries which can't be read are last, by their
+    /// encrypted name.
+    ByNameAscending,
+}
+
+/// When reading updates the access time of the files and directories, see [`FsOptions::atime`].
+///
+/// Writing it is a metadata write, which tools watching the data dir see as a change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AtimePolicy {
+    /// Never update it, reading doesn't write anything.
+    Never,
+    /// Update it only if it's not after the modification or the change time, or it's older than a day, like the
+    /// `relatime` mount option.
+    #[default]
+    Relatime,
+    /// Update it on every read.
+    Always,
+}
+
+impl AtimePolicy {
+    /// If reading something with these times `now` updates its access time.
+    fn should_update(
+        self,
+        atime: SystemTime,
+        mtime: SystemTime,
+        ctime: SystemTime,
+        now: SystemTime,
+    ) -> bool {
+        match self {
+            Self::Never => false,
+            Self::Relatime => {
+                atime <= mtime
+                    || atime <= ctime
+                    || now.duration_since(atime).unwrap_or_default() >= RELATIME_MAX_AGE
+            }
+            Self::Always => true,
+        }
+    }
+}
+
+/// How the numbers of new inodes are picked, see [`FsOptions::inode_alloc`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InodeAlloc {
+    /// Random 64-bit numbers.
+    #[default]
+    Random,
+    /// Increasing numbers, starting above the largest one in the data dir. They give shorter names in the storage,
+    /// and the files created together are kept next to each other.
+    ///
+    /// The counter is kept in the data dir and reserved in batches, a crash skips the rest of the batch.
+    Sequential,
+}
+
+/// Tuning options for [`EncryptedFs`].
+///
+/// Use [`FsOptions::default()`] to get the defaults or [`FsOptions::builder()`] to override some of them.
+#[derive(Debug, Clone)]
+pub struct FsOptions {
+    /// Max number of entries kept in the attributes cache.
+    pub attr_cache_capacity: NonZeroUsize,
+    /// Max number of entries kept in the cache of decrypted directory entry names.
+    pub dir_entries_name_cache_capacity: NonZeroUsize,
+    /// Max number of entries kept in the cache of directory entry metadata.
+    pub dir_entries_meta_cache_capacity: NonZeroUsize,
+    /// How long the derived key is kept in memory before being derived again from the password.
+    pub key_ttl: Duration,
+    /// How long the caches are kept before being dropped and recreated.
+    pub cache_ttl: Duration,
+    /// When the changes are synced to the storage.
+    pub durability: DurabilityPolicy,
+    /// How long the time updates of a directory from adding and removing entries are kept in memory before being
+    /// written, so many changes in a directory write its inode once. Zero writes them right away.
+    pub dir_times_flush_interval: Duration,
+    /// Runtime the background tasks are spawned on, by default the one [`EncryptedFs`] is created on. Set it to keep
+    /// them apart from the caller's tasks.
+    pub runtime_handle: Option<Handle>,
+    /// Max number of directory entries read at once when listing a directory, so a large one doesn't spawn a task
+    /// for each of them.
+    pub dir_entries_concurrency: NonZeroUsize,
+    /// How the numbers of new inodes are picked. Data dirs can be opened with another one than they were created
+    /// with.
+    pub inode_alloc: InodeAlloc,
+    /// Overwrite with random bytes what is removed from the storage, the contents and inode of deleted files, their
+    /// directory entries and what is cut by [`EncryptedFs::set_len`], so the ciphertext doesn't stay there in case
+    /// the key is disclosed later. See also [`EncryptedFs::delete_file_secure`].
+    ///
+    /// It's best-effort, on SSDs and copy-on-write or journaling filesystems the previous blocks can still be kept
+    /// by the device or the filesystem. Contents still in a snapshot are overwritten when the last snapshot having
+    /// them is deleted.
+    pub secure_delete: bool,
+    /// Max sum of the sizes of all files, growing them over it fails with [`FsError::QuotaExceeded`] and
+    /// [`EncryptedFs::statfs`] reports it as the total size. The sizes are the plaintext ones, what the data dir takes
+    /// is a bit more. See also [`EncryptedFs::set_dir_quota`].
+    pub max_size_bytes: Option<u64>,
+    /// Compress the contents of new files with zstd at this level before encrypting them, unless
+    /// [`CreateFileAttr::compress`] says otherwise. Files are read the way they were created, whatever this is.
+    ///
+    /// Compressed files can only be written at the end and reading them backward decompresses again from the start,
+    /// so it suits files written once and read sequentially, like logs and archives.
+    pub compression_level: Option<i32>,
+    /// When reading updates the access time.
+    pub atime: AtimePolicy,
+    /// Max bytes of contiguous writes smaller than this kept by each write handle before they are encrypted
+    /// together, so many small writes don't seal a block each. Zero writes them right away.
+    ///
+    /// The buffer is written when a write is not contiguous with it, when it's full, on [`EncryptedFs::flush`],
+    /// [`EncryptedFs::fsync`] and [`EncryptedFs::release`], and before reading the file. Nothing is buffered while
+    /// the file has more write handles. Errors writing it are returned by the operation that writes it.
+    pub write_buffer_size: usize,
+    /// Max bytes of decrypted blocks kept in memory, shared by all the read handles, so reading the same blocks
+    /// again doesn't decrypt them again. Zero doesn't keep them.
+    ///
+    /// The blocks of a file are dropped when it's changed. They are zeroized when dropped, but they are plaintext
+    /// kept in memory meanwhile. Compressed files are not kept.
+    pub block_cache_bytes: usize,
+    /// Threads encrypting and decrypting the blocks of large reads and writes in parallel, like the copies made by
+    /// [`EncryptedFs::copy_file_range`], [`EncryptedFs::set_len`], importing and exporting. By default as many as
+    /// the cores. With one they are made on the calling thread, like the small ones always are, see [`CryptoPool`].
+    pub crypto_threads: usize,
+    /// Pad the names to the next multiple of this many bytes before encrypting them, so the names in the data dir
+    /// don't give away the length of the real ones, like 16 or 32. Zero doesn't pad them. Data dirs can be opened
+    /// with another one than they were created with, each name is read the way it was written.
+    ///
+    /// A name whose padded encrypted name is too long for a path component of the storage is kept under a hash of
+    /// it, see [`crypto::max_file_name_len`].
+    pub name_padding: usize,
+    /// Normalize the names of a new data dir to NFC before they are hashed and encrypted, so a name spelled in NFD,
+    /// like by macOS, finds the same entry. It's kept in the data dir, which is opened the way it was created
+    /// whatever this is, see [`EncryptedFs::merge_unicode_duplicates`] to turn it on for an existing one.
+    pub normalize_unicode: bool,
+    /// The order [`EncryptedFs::read_dir`] and the others listing a directory return the entries in. A stable one
+    /// keeps the offsets of a listing valid for the next one, see [`DirIterationOrder`].
+    ///
+    /// Sorting decrypts all the names of a directory before returning the first, the name cache keeps them for the
+    /// next listings.
+    pub dir_iteration_order: DirIterationOrder,
+    /// Limits on the bytes read and written per second and on the reads and writes running at once, so copying a
+    /// lot of data doesn't take the whole storage from the others. None by default, they can be changed later with
+    /// [`EncryptedFs::set_throttle`].
+    pub throttle: ThrottleLimits,
+    /// Encrypted blocks per segment of the contents instead of the most that fit, see [`EncryptedFs::segment_size`].
+    /// It's part of the format, so a data dir must always be opened with the same value. Only the tests change it,
+    /// with small segments so they cross their boundaries.
+    pub(crate) segment_blocks: Option<NonZeroU64>,
+}
+
+#[bon]
+impl FsOptions {
+    #[builder]
+    pub fn new(
+        #[builder(default = DEFAULT_CACHE_CAPACITY)] attr_cache_capacity: NonZeroUsize,
+        #[builder(default = DEFAULT_CACHE_CAPACITY)] dir_entries_name_cache_capacity: NonZeroUsize,
+        #[builder(default = DEFAULT_CACHE_CAPACITY)] dir_entries_meta_cache_capacity: NonZeroUsize,
+        #[builder(default = DEFAULT_CACHE_TTL)] key_ttl: Duration,
+        #[builder(default = DEFAULT_CACHE_TTL)] cache_ttl: Duration,
+        #[builder(default)] durability: DurabilityPolicy,
+        #[builder(default = DEFAULT_DIR_TIMES_FLUSH_INTERVAL)] dir_times_flush_interval: Duration,
+        runtime_handle: Option<Handle>,
+        #[builder(default = DEFAULT_DIR_ENTRIES_CONCURRENCY)] dir_entries_concurrency: NonZeroUsize,
+        #[builder(default)] inode_alloc: InodeAlloc,
+        #[builder(default)] secure_delete: bool,
+        max_size_bytes: Option<u64>,
+        compression_level: Option<i32>,
+        #[builder(default)] atime: AtimePolicy,
+        #[builder(default = DEFAULT_WRITE_BUFFER_SIZE)] write_buffer_size: usize,
+        #[builder(default)] block_cache_bytes: usize,
+        #[builder(default = default_crypto_threads())] crypto_threads: usize,
+        #[builder(default)] name_padding: usize,
+        #[builder(default = true)] normalize_unicode: bool,
+        #[builder(default)] dir_iteration_order: DirIterationOrder,
+        #[builder(default)] throttle: ThrottleLimits,
+    ) -> Self {
+        Self {
+            attr_cache_capacity,
+            dir_entries_name_cache_capacity,
+            dir_entries_meta_cache_capacity,
+            key_ttl,
+            cache_ttl,
+            durability,
+            dir_times_flush_interval,
+            runtime_handle,
+            dir_entries_concurrency,
+            inode_alloc,
+            secure_delete,
+            max_size_bytes,
+            compression_level,
+            atime,
+            write_buffer_size,
+            block_cache_bytes,
+            crypto_threads,
+            name_padding,
+            normalize_unicode,
+            dir_iteration_order,
+            throttle,
+            segment_blocks: None,
+        }
+    }
+
+    /// Set [`FsOptions::segment_blocks`].
+    #[cfg(test)]
+    pub(crate) const fn with_segment_blocks(mut self, blocks: NonZeroU64) -> Self {
+        self.segment_blocks = Some(blocks);
+        self
+    }
+}
+
+impl Default for FsOptions {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+struct InodesCountProvider {
+    storage: Arc<dyn Storage>,
+}
+#[async_trait]
+impl ValueProvider<u64, FsError> for InodesCountProvider {
+    async fn provide(&self) -> Result<u64, FsError> {
+        let mut count = 0;
+        // walk the shards
+        let mut dirs = vec![PathBuf::from(INODES_DIR)];
+        while let Some(dir) = dirs.pop() {
+            for entry in self.storage.list(&dir).await? {
+                if entry.kind == EntryKind::Dir {
+                    dirs.push(dir.join(entry.name));
+                } else {
+                    count += 1;
+                }
+            }
+        }
+        Ok(count)
     }
 }
 
 type DirEntryMetaCache = LruCache<String, (u64, FileType)>;
 
+/// What to look for in [`EncryptedFs::lseek`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekWhence {
+    /// Next region with data, like `SEEK_DATA`.
+    Data,
+    /// Next hole, like `SEEK_HOLE`. The end of the file is considered a hole.
+    Hole,
+}
+
+/// Statistics of the filesystem, see [`EncryptedFs::statfs`].
+///
+/// Sizes are estimates of the plaintext bytes, the encryption overhead of the filesystem holding the data dir is deducted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsStats {
+    pub total_bytes: u64,
+    pub free_bytes: u64,
+    /// Free bytes for unprivileged users.
+    pub available_bytes: u64,
+    pub used_inodes: u64,
+    pub free_inodes: u64,
+    pub block_size: u32,
+    /// See [`EncryptedFs::max_name_len`].
+    pub max_name_len: u32,
+    /// See [`EncryptedFs::current_generation`].
+    pub generation: u64,
+}
+
 /// Encrypted FS that stores encrypted files in a dedicated directory with a specific structure based on `inode`.
+///
+/// The directory is kept in a [`Storage`], a directory on disk with [`EncryptedFs::new`] or any other one with
+/// [`EncryptedFs::with_storage`].
 pub struct EncryptedFs {
-    pub(crate) data_dir: PathBuf,
-    write_handles: RwLock<HashMap<u64, Mutex<WriteHandleContext>>>,
-    read_handles: RwLock<HashMap<u64, Mutex<ReadHandleContext>>>,
+    pub(crate) storage: Arc<dyn Storage>,
+    // sharded so ops on different handles and files don't wait for each other
+    write_handles: ShardedMap<u64, Mutex<WriteHandleContext>>,
+    read_handles: ShardedMap<u64, Mutex<ReadHandleContext>>,
     current_handle: AtomicU64,
     cipher: Cipher,
-    // (ino, fh)
-    opened_files_for_read: RwLock<HashMap<u64, HashSet<u64>>>,
-    opened_files_for_write: RwLock<HashMap<u64, u64>>,
+    // (ino, fh), a handle is added here before it's added to the handles and removed from here after
+    opened_files_for_read: ShardedMap<u64, HashSet<u64>>,
+    opened_files_for_write: ShardedMap<u64, HashSet<u64>>,
     // used for rw ops of actual serialization
     // use std::sync::RwLock instead of tokio::sync::RwLock because we need to use it also in sync code in `DirectoryEntryIterator` and `DirectoryEntryPlusIterator`
     serialize_inode_locks: Arc<ArcHashMap<u64, RwLock<bool>>>,
@@ -564,46 +1271,174 @@ pub struct EncryptedFs {
     serialize_dir_entries_hash_locks: Arc<ArcHashMap<String, RwLock<bool>>>,
     read_write_locks: ArcHashMap<u64, RwLock<bool>>,
     key: ExpireValue<SecretVec<u8>, FsError, KeyProvider>,
+    // the unlocked directory keys by id, see `EncryptedFs::set_directory_key`
+    dir_keys: RwLock<HashMap<u32, Arc<SecretVec<u8>>>>,
     self_weak: std::sync::Mutex<Option<Weak<Self>>>,
     attr_cache: ExpireValue<RwLock<LruCache<u64, FileAttr>>, FsError, AttrCacheProvider>,
     dir_entries_name_cache:
         ExpireValue<Mutex<LruCache<String, SecretString>>, FsError, DirEntryNameCacheProvider>,
     dir_entries_meta_cache:
         ExpireValue<Mutex<DirEntryMetaCache>, FsError, DirEntryMetaCacheProvider>,
+    inodes_count: ExpireValue<u64, FsError, InodesCountProvider>,
     sizes_write: Mutex<HashMap<u64, AtomicU64>>,
     sizes_read: Mutex<HashMap<u64, AtomicU64>>,
     requested_read: Mutex<HashMap<u64, AtomicU64>>,
     read_only: bool,
+    durability: DurabilityPolicy,
+    // running `import_dir` calls, which relax `DurabilityPolicy::Always`
+    imports: AtomicUsize,
+    // keys not synced yet with `DurabilityPolicy::OnRelease`
+    pending_syncs: Mutex<HashSet<PathBuf>>,
+    dir_times_flush_interval: Duration,
+    // time updates of directories not written yet, with when the first one was made
+    pending_dir_times: Mutex<HashMap<u64, (Instant, SystemTime)>>,
+    // inodes given by `generate_next_inode` whose inode file is not written yet
+    reserved_inodes: std::sync::Mutex<HashSet<u64>>,
+    inode_alloc: InodeAlloc,
+    // what is left of the batch reserved in `INODE_COUNTER_FILENAME`, with `InodeAlloc::Sequential`
+    inode_counter: Mutex<Range<u64>>,
+    // the generation counter and the removed inodes, see `EncryptedFs::changes_since`
+    generations: Mutex<changes::Generations>,
+    // there are snapshots, so the contents may be hard linked in them and are copied before they are changed
+    shared_contents: AtomicBool,
+    secure_delete: bool,
+    // the size of the files and the limits on it, see `FsOptions::max_size_bytes`
+    quota: Mutex<quota::QuotaState>,
+    compression_level: Option<i32>,
+    atime: AtimePolicy,
+    write_buffer_size: usize,
+    name_padding: usize,
+    // the names are normalized to NFC, see `FsOptions::normalize_unicode`
+    normalize_unicode: AtomicBool,
+    dir_iteration_order: DirIterationOrder,
+    // see `FsOptions::segment_blocks`
+    segment_blocks: Option<NonZeroU64>,
+    block_cache: block_cache::BlockCache,
+    throttle: throttle::Throttle,
+    crypto_pool: Arc<CryptoPool>,
+    events: events::EventSender,
+    metrics: metrics::Metrics,
+    // the advisory locks of the files, see `EncryptedFs::lock_range`
+    locks: locks::FileLocks,
+    // the files of the handles opened with `EncryptedFs::open_id`, checked on each read and write
+    handle_ids: std::sync::Mutex<HashMap<u64, FileId>>,
+    // the holes of the contents shared by their readers and writers, kept in the inodes, see
+    // `EncryptedFs::contents_holes`
+    contents_holes: std::sync::Mutex<HashMap<u64, SharedHoles>>,
+    runtime: Handle,
+    dir_entries_concurrency: NonZeroUsize,
+    // sequence number of the next record in the journal
+    journal_seq: AtomicU64,
+    external_change_listeners: std::sync::Mutex<Vec<watch::ExternalChangeListener>>,
+    // lock on the data dir, held while we are alive, only for a local storage
+    _instance_lock: Option<File>,
 }
 
 impl EncryptedFs {
+    /// Open the data dir, or create it if it doesn't exist.
+    ///
+    /// `cipher` is a [`Cipher`] or [`CipherChoice::Auto`], which uses the cipher the data dir was created with and
+    /// [`Cipher::recommended`] for a new one. The cipher is kept in the data dir, opening it with another one returns
+    /// [`FsError::InvalidInput`].
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
     pub async fn new(
         data_dir: PathBuf,
-        password_provider: Box<dyn PasswordProvider>,
-        cipher: Cipher,
+        password_provider: Box<dyn AsyncPasswordProvider>,
+        cipher: impl Into<CipherChoice>,
         read_only: bool,
+        options: FsOptions,
     ) -> FsResult<Arc<Self>> {
-        let key_provider = KeyProvider {
-            key_path: data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME),
-            salt_path: data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME),
+        Self::with_storage(
+            Arc::new(LocalStorage::new(data_dir)),
             password_provider,
             cipher,
+            read_only,
+            options,
+        )
+        .await
+    }
+
+    /// Like [`EncryptedFs::new`] but the data dir is kept in `storage`.
+    ///
+    /// Only a [`Storage::local_path`] is locked against being used by other instances, see
+    /// [`FsError::AlreadyMounted`].
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn with_storage(
+        storage: Arc<dyn Storage>,
+        password_provider: Box<dyn AsyncPasswordProvider>,
+        cipher: impl Into<CipherChoice>,
+        read_only: bool,
+        options: FsOptions,
+    ) -> FsResult<Arc<Self>> {
+        let stored_cipher = read_cipher(&*storage).await?;
+        let cipher = match (cipher.into(), stored_cipher) {
+            (CipherChoice::Fixed(cipher), Some(stored)) if cipher != stored => {
+                return Err(FsError::invalid_input(format!(
+                    "data dir is encrypted with {stored}, not {cipher}"
+                )));
+            }
+            (CipherChoice::Fixed(cipher), _) | (CipherChoice::Auto, Some(cipher)) => cipher,
+            (CipherChoice::Auto, None) => {
+                if storage.kind(&key_path()).await?.is_some() {
+                    // made before the cipher was kept
+                    return Err(FsError::invalid_input(
+                        "the cipher of the data dir is not known, open it once with it",
+                    ));
+                }
+                Cipher::recommended()
+            }
+        };
+        let key_provider = KeyProvider {
+            storage: storage.clone(),
+            password_provider: Arc::from(password_provider),
+            cipher,
         };
-        let key = ExpireValue::new(key_provider, Duration::from_secs(10 * 60));
+        let fs = Self::with_key_provider(storage, key_provider, read_only, options).await?;
+        if stored_cipher.is_none() && !read_only {
+            // the password was checked, so it's the right one
+            write_cipher(&*fs.storage, cipher).await?;
+        }
+        Ok(fs)
+    }
 
-        ensure_structure_created(&data_dir.clone()).await?;
-        key.get().await?; // this will check the password
+    /// Like [`EncryptedFs::with_storage`] but the key is read by `key_provider`, from another storage for the views
+    /// of the snapshots.
+    async fn with_key_provider(
+        storage: Arc<dyn Storage>,
+        key_provider: KeyProvider,
+        read_only: bool,
+        options: FsOptions,
+    ) -> FsResult<Arc<Self>> {
+        let cipher = key_provider.cipher;
+        let key = ExpireValue::new(key_provider, options.key_ttl);
+
+        ensure_structure_created(&*storage, options.normalize_unicode).await?;
+        let normalize_unicode = unicode::read_normalize_unicode(&*storage).await?;
+        let instance_lock = storage
+            .local_path()
+            .map(|data_dir| lock_instance(data_dir, !read_only))
+            .transpose()?;
+        cipher_change::check_not_interrupted(&*storage).await?;
+        // this will check the password
+        run_migrations(&*storage, cipher, &*key.get().await?).await?;
+
+        let inodes_count = ExpireValue::new(
+            InodesCountProvider {
+                storage: storage.clone(),
+            },
+            INODES_COUNT_TTL,
+        );
 
         let fs = Self {
-            data_dir,
-            write_handles: RwLock::new(HashMap::new()),
-            read_handles: RwLock::new(HashMap::new()),
+            storage,
+            write_handles: ShardedMap::default(),
+            read_handles: ShardedMap::default(),
             current_handle: AtomicU64::new(1),
             cipher,
-            opened_files_for_read: RwLock::new(HashMap::new()),
-            opened_files_for_write: RwLock::new(HashMap::new()),
+            opened_files_for_read: ShardedMap::default(),
+            opened_files_for_write: ShardedMap::default(),
             serialize_inode_locks: Arc::new(ArcHashMap::default()),
             serialize_update_inode_locks: ArcHashMap::default(),
             serialize_dir_entries_ls_locks: Arc::new(ArcHashMap::default()),
@@ -611,22 +1446,62 @@ impl EncryptedFs {
             key,
             self_weak: std::sync::Mutex::new(None),
             read_write_locks: ArcHashMap::default(),
-            // todo: take duration from param
-            attr_cache: ExpireValue::new(AttrCacheProvider {}, Duration::from_secs(10 * 60)),
-            // todo: take duration from param
+            attr_cache: ExpireValue::new(
+                AttrCacheProvider {
+                    capacity: options.attr_cache_capacity,
+                },
+                options.cache_ttl,
+            ),
             dir_entries_name_cache: ExpireValue::new(
-                DirEntryNameCacheProvider {},
-                Duration::from_secs(10 * 60),
+                DirEntryNameCacheProvider {
+                    capacity: options.dir_entries_name_cache_capacity,
+                },
+                options.cache_ttl,
             ),
-            // todo: take duration from param
             dir_entries_meta_cache: ExpireValue::new(
-                DirEntryMetaCacheProvider {},
-                Duration::from_secs(10 * 60),
+                DirEntryMetaCacheProvider {
+                    capacity: options.dir_entries_meta_cache_capacity,
+                },
+                options.cache_ttl,
             ),
+            inodes_count,
             sizes_write: Mutex::default(),
             sizes_read: Mutex::default(),
             requested_read: Mutex::default(),
             read_only,
+            durability: options.durability,
+            imports: AtomicUsize::new(0),
+            pending_syncs: Mutex::new(HashSet::new()),
+            dir_times_flush_interval: options.dir_times_flush_interval,
+            pending_dir_times: Mutex::new(HashMap::new()),
+            reserved_inodes: std::sync::Mutex::new(HashSet::new()),
+            inode_alloc: options.inode_alloc,
+            inode_counter: Mutex::new(0..0),
+            generations: Mutex::new(changes::Generations::new()),
+            shared_contents: AtomicBool::new(false),
+            secure_delete: options.secure_delete,
+            quota: Mutex::new(quota::QuotaState::new(options.max_size_bytes)),
+            compression_level: options.compression_level,
+            atime: options.atime,
+            write_buffer_size: options.write_buffer_size,
+            name_padding: options.name_padding,
+            normalize_unicode: AtomicBool::new(normalize_unicode),
+            dir_iteration_order: options.dir_iteration_order,
+            segment_blocks: options.segment_blocks,
+            block_cache: block_cache::BlockCache::new(options.block_cache_bytes),
+            throttle: throttle::Throttle::new(options.throttle),
+            crypto_pool: Arc::new(CryptoPool::new(options.crypto_threads)),
+            dir_keys: RwLock::new(HashMap::new()),
+            events: events::EventSender::new(),
+            metrics: metrics::Metrics::new(),
+            locks: locks::FileLocks::new(),
+            handle_ids: std::sync::Mutex::new(HashMap::new()),
+            contents_holes: std::sync::Mutex::new(HashMap::new()),
+            runtime: options.runtime_handle.unwrap_or_else(Handle::current),
+            dir_entries_concurrency: options.dir_entries_concurrency,
+            journal_seq: AtomicU64::new(0),
+            external_change_listeners: std::sync::Mutex::new(vec![]),
+            _instance_lock: instance_lock,
         };
 
         let arc = Arc::new(fs);
@@ -635,21 +1510,62 @@ impl EncryptedFs {
             .expect("cannot obtain lock")
             .replace(Arc::downgrade(&arc));
 
+        arc.load_generations().await?;
+        #[cfg(feature = "snapshots")]
+        arc.load_snapshots().await?;
         arc.ensure_root_exists().await?;
+        if read_only {
+            if journal::has_pending(&*arc.storage).await? {
+                warn!(
+                    "operations interrupted by a crash are completed only when opened for writing"
+                );
+            }
+        } else {
+            arc.replay_journal().await?;
+            if arc.inode_alloc == InodeAlloc::Sequential {
+                arc.load_inode_counter().await?;
+            }
+        }
+        arc.load_quota().await?;
 
         Ok(arc)
     }
 
-    pub fn exists(&self, ino: u64) -> bool {
-        self.ino_file(ino).is_file()
+    /// A new filesystem kept in memory with [`InMemoryStorage`], it's lost when dropped.
+    ///
+    /// Everything is still encrypted like on disk, but nothing is synced, so it's fast for tests. Needs the
+    /// `test-util` feature.
+    #[cfg(any(test, feature = "test-util"))]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn new_in_memory(
+        password_provider: Box<dyn AsyncPasswordProvider>,
+        cipher: impl Into<CipherChoice>,
+    ) -> FsResult<Arc<Self>> {
+        Self::with_storage(
+            Arc::new(crate::storage::InMemoryStorage::new()),
+            password_provider,
+            cipher,
+            false,
+            FsOptions::default(),
+        )
+        .await
+    }
+
+    pub async fn exists(&self, ino: u64) -> bool {
+        self.kind_at(&self.ino_file(ino)).await == Some(EntryKind::File)
+    }
+
+    pub async fn is_dir(&self, ino: u64) -> bool {
+        self.kind_at(&self.contents_path(ino)).await == Some(EntryKind::Dir)
     }
 
-    pub fn is_dir(&self, ino: u64) -> bool {
-        self.contents_path(ino).is_dir()
+    pub async fn is_file(&self, ino: u64) -> bool {
+        self.kind_at(&self.contents_path(ino)).await == Some(EntryKind::File)
     }
 
-    pub fn is_file(&self, ino: u64) -> bool {
-        self.contents_path(ino).is_file()
+    /// What is at `key` in the storage, an error is taken as nothing like [`Path::exists`] does.
+    async fn kind_at(&self, key: &Path) -> Option<EntryKind> {
+        self.storage.kind(key).await.ok().flatten()
     }
 
     #[allow(dead_code)]
@@ -660,14 +1576,27 @@ impl EncryptedFs {
     fn validate_filename(&self, secret_filename: &SecretBox<String>) -> FsResult<()> {
         let filename = secret_filename.expose_secret().to_string();
         if filename.contains('/') {
-            Err(FsError::InvalidInput("'/' not allowed in the filename"))
+            Err(FsError::invalid_input("'/' not allowed in the filename"))
         } else if filename.contains('\\') {
-            Err(FsError::InvalidInput("'\\' not allowed in the filename"))
+            Err(FsError::invalid_input("'\\' not allowed in the filename"))
+        } else if filename.contains(crypto::NAME_PADDING) {
+            Err(FsError::invalid_input("NUL not allowed in the filename"))
+        } else if filename.len() > self.max_name_len() {
+            Err(FsError::NameTooLong)
         } else {
             Ok(())
         }
     }
 
+    /// Max length in bytes of a name, longer ones fail with [`FsError::NameTooLong`].
+    ///
+    /// It's more than a path component of the storage can have, the names longer than [`crypto::max_file_name_len`]
+    /// are kept under a hash of their encrypted name.
+    #[must_use]
+    pub const fn max_name_len(&self) -> usize {
+        MAX_NAME_LEN
+    }
+
     /// Create a new node in the filesystem
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
@@ -680,59 +1609,70 @@ impl EncryptedFs {
         read: bool,
         write: bool,
     ) -> FsResult<(u64, FileAttr)> {
+        let _timer = self.metrics.create.start();
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
         if *name.expose_secret() == "." || *name.expose_secret() == ".." {
-            return Err(FsError::InvalidInput("name cannot be '.' or '..'"));
+            return Err(FsError::invalid_input("name cannot be '.' or '..'"));
         }
-        if !self.exists(parent) {
+        if !self.exists(parent).await {
             return Err(FsError::InodeNotFound);
         }
-        if self.exists_by_name(parent, name)? {
-            return Err(FsError::AlreadyExists);
-        }
         self.validate_filename(name)?;
 
-        // spawn on a dedicated runtime to not interfere with other higher priority tasks
-        let self_clone = self
-            .self_weak
-            .lock()
-            .unwrap()
-            .as_ref()
-            .unwrap()
-            .upgrade()
-            .unwrap();
+        // spawn so it completes even if the caller is dropped
+        let self_clone = self.self_arc()?;
         let name_clone = name.clone();
-        NOD_RT
+        let event_name = self.normalized_name(name).into_owned();
+        self.runtime
             .spawn(async move {
+                if self_clone.exists_by_name(parent, &name_clone).await? {
+                    return Err(FsError::AlreadyExists);
+                }
+                let compress = create_attr
+                    .compress
+                    .unwrap_or(self_clone.compression_level.is_some());
                 let mut attr: FileAttr = create_attr.into();
-                attr.ino = self_clone.generate_next_inode();
+                attr.compressed = attr.kind == FileType::RegularFile && compress;
+                attr.key_id = self_clone
+                    .get_inode_from_cache_or_storage(parent)
+                    .await?
+                    .key_id;
+                attr.ino_generation = self_clone.next_generation().await?;
 
                 let fs = self_clone;
                 let mut join_set = JoinSet::new();
 
-                // write inode
-                let self_clone = fs.clone();
-                self_clone.write_inode_to_storage(&attr).await?;
+                // write inode, after that it's seen as taken without the reservation
+                {
+                    let reservation = fs.generate_next_inode().await?;
+                    attr.ino = reservation.ino;
+                    fs.write_inode_to_storage(&attr).await?;
+                }
 
                 match attr.kind {
-                    FileType::RegularFile => {
+                    // the special files have an empty contents file too, so they are handled like the files
+                    FileType::RegularFile
+                    | FileType::NamedPipe
+                    | FileType::CharDevice
+                    | FileType::BlockDevice
+                    | FileType::Socket => {
                         let self_clone = fs.clone();
                         join_set.spawn(async move {
                             // create in contents directory
-                            let file = File::create(self_clone.contents_path(attr.ino))?;
-                            // sync_all file and parent
-                            // these operations are a bit slow, but are necessary to make sure the file is correctly created
-                            // i.e. creating 100 files takes 0.965 sec with sync_all and 0.130 sec without
-                            file.sync_all()?;
-                            File::open(
-                                self_clone
-                                    .contents_path(attr.ino)
-                                    .parent()
-                                    .expect("oops, we don't have a parent"),
-                            )?
-                            .sync_all()?;
+                            let path = self_clone.contents_path(attr.ino);
+                            self_clone.ensure_shard_synced_with_policy(&path).await?;
+                            self_clone.storage.create(&path).await?;
+                            // sync file and parent
+                            // these operations are a bit slow, but are necessary to make sure the file is correctly created,
+                            // unless the `DurabilityPolicy` says otherwise
+                            self_clone.sync_with_policy(&path).await?;
+                            self_clone
+                                .sync_with_policy(
+                                    path.parent().expect("oops, we don't have a parent"),
+                                )
+                                .await?;
                             Ok::<(), FsError>(())
                         });
                     }
@@ -742,12 +1682,29 @@ impl EncryptedFs {
                         join_set.spawn(async move {
                             // create in contents directory
                             let contents_dir = self_clone.contents_path(attr.ino);
-                            fs::create_dir(contents_dir.clone())?;
+                            let storage = &*self_clone.storage;
+                            self_clone
+                                .ensure_shard_synced_with_policy(&contents_dir)
+                                .await?;
+                            storage.create_dir(&contents_dir).await?;
                             // used to keep encrypted file names used by [`read_dir`] and [`read_dir_plus`]
-                            fs::create_dir(contents_dir.join(LS_DIR))?;
+                            storage.create_dir(&contents_dir.join(LS_DIR)).await?;
                             // used to keep hashes of encrypted file names used by [`exists_by_name`] and [`find_by_name`]
                             // this optimizes the search process as we don't need to decrypt all file names and search
-                            fs::create_dir(contents_dir.join(HASH_DIR))?;
+                            storage.create_dir(&contents_dir.join(HASH_DIR)).await?;
+                            self_clone
+                                .write_with_policy(
+                                    &contents_dir.join(NAME_SALT_FILENAME),
+                                    &crypto::create_name_salt(),
+                                )
+                                .await?;
+                            self_clone
+                                .write_bound(
+                                    &contents_dir.join(CHILD_COUNT_FILENAME),
+                                    &0_u64,
+                                    &child_count_aad(attr_clone.ino),
+                                )
+                                .await?;
 
                             // add "." and ".." entries
                             self_clone
@@ -794,16 +1751,7 @@ impl EncryptedFs {
 
                 let self_clone = fs.clone();
                 join_set.spawn(async move {
-                    let now = SystemTime::now();
-                    self_clone
-                        .set_attr(
-                            parent,
-                            SetFileAttr::default()
-                                .with_mtime(now)
-                                .with_ctime(now)
-                                .with_atime(now),
-                        )
-                        .await?;
+                    self_clone.touch_dir(parent).await?;
                     Ok::<(), FsError>(())
                 });
 
@@ -811,6 +1759,14 @@ impl EncryptedFs {
                 while let Some(res) = join_set.join_next().await {
                     res??;
                 }
+                if attr.kind == FileType::RegularFile {
+                    fs.quota_add_entry(parent, attr.ino, 0).await?;
+                }
+                fs.events.send(FsEvent::Created {
+                    parent,
+                    ino: attr.ino,
+                    name: event_name,
+                });
 
                 let self_clone = fs.clone();
                 let handle = if attr.kind == FileType::RegularFile {
@@ -837,340 +1793,727 @@ impl EncryptedFs {
         parent: u64,
         name: &SecretString,
     ) -> FsResult<Option<FileAttr>> {
-        if !self.exists(parent) {
+        let _timer = self.metrics.lookup.start();
+        if !self.exists(parent).await {
             return Err(FsError::InodeNotFound);
         }
-        if !self.is_dir(parent) {
+        if !self.is_dir(parent).await {
             return Err(FsError::InvalidInodeType);
         }
-        let hash = crypto::hash_file_name(name);
-        let hash_path = self.contents_path(parent).join(HASH_DIR).join(hash);
-        if !hash_path.is_file() {
-            return Ok(None);
+        let mut entry = self.lookup_entry(parent, name).await?;
+        // heal the `hash` entries if they were lost
+        if entry.is_none() && !self.read_only && self.is_dir_index_damaged(parent).await? {
+            warn!(parent, "hash entries missing, rebuilding them");
+            self.rebuild_dir_index(parent).await?;
+            entry = self.lookup_entry(parent, name).await?;
         }
-        let lock = self
-            .serialize_dir_entries_hash_locks
-            .get_or_insert_with(hash_path.to_str().unwrap().to_owned(), || {
-                RwLock::new(false)
-            });
-        let guard = lock.read().await;
-        let (ino, _, _): (u64, FileType, String) = bincode::deserialize_from(crypto::create_read(
-            File::open(hash_path)?,
-            self.cipher,
-            &*self.key.get().await?,
-        ))?;
-        drop(guard);
+        let Some((ino, _, _)) = entry else {
+            return Ok(None);
+        };
         self.get_inode_from_cache_or_storage(ino).await.map(Some)
     }
 
     /// Count children of a directory. This **EXCLUDES** "." and "..".
     #[allow(clippy::missing_errors_doc)]
-    pub fn len(&self, ino: u64) -> FsResult<usize> {
-        if !self.is_dir(ino) {
+    pub async fn len(&self, ino: u64) -> FsResult<usize> {
+        if !self.is_dir(ino).await {
             return Err(FsError::InvalidInodeType);
         }
-        let mut count = fs::read_dir(self.contents_path(ino).join(LS_DIR))?.count();
+        let path = self.contents_path(ino).join(CHILD_COUNT_FILENAME);
+        let lock = self
+            .serialize_dir_entries_ls_locks
+            .get_or_insert_with(path.to_str().unwrap().to_owned(), || RwLock::new(false));
+        let _guard = lock.write().await;
+        let count = match self.read_child_count(ino).await {
+            Some(count) => count,
+            None => self.count_children(ino).await?,
+        };
+        #[allow(clippy::cast_possible_truncation)]
+        let count = count as usize;
         if ino == ROOT_INODE {
             // we don't count "."
-            count -= 1;
+            Ok(count.saturating_sub(1))
         } else {
             // we don't count "." and ".."
-            count -= 2;
+            Ok(count.saturating_sub(2))
         }
-        Ok(count)
     }
 
-    /// Delete a directory
-    #[allow(clippy::missing_panics_doc)]
-    #[allow(clippy::missing_errors_doc)]
+    /// Count the `ls` entries of the directory `ino` and keep it in [`CHILD_COUNT_FILENAME`], unless read-only.
+    ///
+    /// Call it with the lock on the count.
+    async fn count_children(&self, ino: u64) -> FsResult<u64> {
+        let count = self
+            .storage
+            .list(&self.contents_path(ino).join(LS_DIR))
+            .await?
+            .len() as u64;
+        if !self.read_only {
+            self.write_bound(
+                &self.contents_path(ino).join(CHILD_COUNT_FILENAME),
+                &count,
+                &child_count_aad(ino),
+            )
+            .await?;
+        }
+        Ok(count)
+    }
+
+    /// Like [`EncryptedFs::count_children`] but taking the lock on the count.
+    pub(crate) async fn recount_children(&self, ino: u64) -> FsResult<u64> {
+        let path = self.contents_path(ino).join(CHILD_COUNT_FILENAME);
+        let lock = self
+            .serialize_dir_entries_ls_locks
+            .get_or_insert_with(path.to_str().unwrap().to_owned(), || RwLock::new(false));
+        let _guard = lock.write().await;
+        self.count_children(ino).await
+    }
+
+    /// The count in [`CHILD_COUNT_FILENAME`] of the directory `ino`, `None` if it's missing or can't be read.
+    ///
+    /// Call it with the lock on the count.
+    async fn read_child_count(&self, ino: u64) -> Option<u64> {
+        let path = self.contents_path(ino).join(CHILD_COUNT_FILENAME);
+        if self.kind_at(&path).await != Some(EntryKind::File) {
+            return None;
+        }
+        self.read_bound(&path, &child_count_aad(ino))
+            .await
+            .map_err(|err| {
+                warn!(ino, err = %err, "reading child count, the entries will be counted");
+                err
+            })
+            .ok()
+    }
+
+    /// Add `delta` to the count in [`CHILD_COUNT_FILENAME`] of the directory `ino`, if it has one.
+    ///
+    /// Call it with the lock on the count, while adding or removing the entry.
+    async fn update_child_count(&self, ino: u64, delta: i64) -> FsResult<()> {
+        if let Some(count) = self.read_child_count(ino).await {
+            self.write_bound(
+                &self.contents_path(ino).join(CHILD_COUNT_FILENAME),
+                &count.saturating_add_signed(delta),
+                &child_count_aad(ino),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Delete a directory
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
     pub async fn remove_dir(&self, parent: u64, name: &SecretString) -> FsResult<()> {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
-        if !self.is_dir(parent) {
+        if !self.is_dir(parent).await {
             return Err(FsError::InvalidInodeType);
         }
 
-        if !self.exists_by_name(parent, name)? {
-            return Err(FsError::NotFound("name not found"));
+        if !self.exists_by_name(parent, name).await? {
+            return Err(self.name_not_found(parent, name).await);
         }
 
-        let attr = self
-            .find_by_name(parent, name)
-            .await?
-            .ok_or(FsError::NotFound("name not found"))?;
+        let Some(attr) = self.find_by_name(parent, name).await? else {
+            return Err(self.name_not_found(parent, name).await);
+        };
         if !matches!(attr.kind, FileType::Directory) {
             return Err(FsError::InvalidInodeType);
         }
         // check if it's empty
-        if self.len(attr.ino)? > 0 {
+        if self.len(attr.ino).await? > 0 {
             return Err(FsError::NotEmpty);
         }
-        let self_clone = self
-            .self_weak
-            .lock()
-            .unwrap()
-            .as_ref()
-            .unwrap()
-            .upgrade()
-            .unwrap();
+        let self_clone = self.self_arc()?;
         let name_clone = name.clone();
-        NOD_RT
+        self.runtime
             .spawn(async move {
-                // remove inode file
-                {
-                    let lock = self_clone
-                        .serialize_inode_locks
-                        .get_or_insert_with(attr.ino, || RwLock::new(false));
-                    let _guard = lock.write();
-                    fs::remove_file(self_clone.ino_file(attr.ino))?;
-                }
-
-                // remove contents directory
-                fs::remove_dir_all(self_clone.contents_path(attr.ino))?;
+                self_clone.remove_dir_inode(attr.ino).await?;
                 // remove from parent directory
                 self_clone
-                    .remove_directory_entry(parent, &name_clone)
+                    .remove_directory_entry(parent, &name_clone, self_clone.secure_delete)
                     .await?;
-                // remove from cache
-                self_clone
-                    .attr_cache
-                    .get()
-                    .await?
-                    .write()
-                    .await
-                    .demote(&attr.ino);
 
-                let now = SystemTime::now();
-                self_clone
-                    .set_attr(
-                        parent,
-                        SetFileAttr::default()
-                            .with_mtime(now)
-                            .with_ctime(now)
-                            .with_atime(now),
-                    )
-                    .await?;
+                self_clone.touch_dir(parent).await?;
+                self_clone.events.send(FsEvent::Removed {
+                    parent,
+                    ino: attr.ino,
+                    name: name_clone,
+                });
 
                 Ok(())
             })
             .await?
     }
 
+    /// Remove the inode and the contents of the empty directory `ino`, the caller removes its entry from the parent.
+    async fn remove_dir_inode(&self, ino: u64) -> FsResult<()> {
+        // remove inode file
+        {
+            let lock = self
+                .serialize_inode_locks
+                .get_or_insert_with(ino, || RwLock::new(false));
+            let _guard = lock.write();
+            self.remove_key(&self.ino_file(ino), self.secure_delete)
+                .await?;
+        }
+        self.pending_dir_times.lock().await.remove(&ino);
+
+        // remove contents directory
+        self.storage.remove_dir(&self.contents_path(ino)).await?;
+        // remove from cache
+        self.attr_cache.get().await?.write().await.demote(&ino);
+        self.record_removed(ino).await?;
+        self.quota_forget_dir(ino).await
+    }
+
     /// Delete a file
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
     pub async fn remove_file(&self, parent: u64, name: &SecretString) -> FsResult<()> {
+        self.remove_file_with(parent, name, self.secure_delete)
+            .await
+    }
+
+    /// Like [`EncryptedFs::remove_file`], but what is removed from the storage is overwritten first even without
+    /// [`FsOptions::secure_delete`]. The contents and the inode are overwritten only if this was the last link.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn delete_file_secure(&self, parent: u64, name: &SecretString) -> FsResult<()> {
+        self.remove_file_with(parent, name, true).await
+    }
+
+    async fn remove_file_with(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        secure: bool,
+    ) -> FsResult<()> {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
-        if !self.is_dir(parent) {
+        if !self.is_dir(parent).await {
             return Err(FsError::InvalidInodeType);
         }
-        if !self.exists_by_name(parent, name)? {
-            return Err(FsError::NotFound("name not found"));
+        if !self.exists_by_name(parent, name).await? {
+            return Err(self.name_not_found(parent, name).await);
         }
 
-        let attr = self
-            .find_by_name(parent, name)
-            .await?
-            .ok_or(FsError::NotFound("name not found"))?;
-        if !matches!(attr.kind, FileType::RegularFile) {
+        let Some(attr) = self.find_by_name(parent, name).await? else {
+            return Err(self.name_not_found(parent, name).await);
+        };
+        if matches!(attr.kind, FileType::Directory) {
             return Err(FsError::InvalidInodeType);
         }
         // todo move to method
-        let self_clone = self
-            .self_weak
-            .lock()
-            .unwrap()
-            .as_ref()
-            .unwrap()
-            .upgrade()
-            .unwrap();
+        let self_clone = self.self_arc()?;
         let name_clone = name.clone();
-        NOD_RT
+        self.runtime
             .spawn(async move {
-                // remove inode file
-                {
-                    let lock = self_clone
-                        .serialize_inode_locks
-                        .get_or_insert_with(attr.ino, || RwLock::new(false));
-                    let _guard = lock.write();
-                    fs::remove_file(self_clone.ino_file(attr.ino))?;
-                }
-
-                // remove from contents directory
-                fs::remove_file(self_clone.contents_path(attr.ino))?;
-                // remove from parent directory
+                // remove from parent directory first, if we crash after this we leave an orphan inode
+                // but never an entry pointing to a missing inode
                 self_clone
-                    .remove_directory_entry(parent, &name_clone)
+                    .remove_directory_entry(parent, &name_clone, secure)
                     .await?;
-                // remove from cache
                 self_clone
-                    .attr_cache
-                    .get()
-                    .await?
-                    .write()
-                    .await
-                    .demote(&attr.ino);
+                    .quota_remove_entry(parent, attr.ino, attr.size)
+                    .await;
+                self_clone.unlink_file_inode(attr.ino, secure).await?;
 
-                let now = SystemTime::now();
-                self_clone
-                    .set_attr(
-                        parent,
-                        SetFileAttr::default()
-                            .with_mtime(now)
-                            .with_ctime(now)
-                            .with_atime(now),
-                    )
-                    .await?;
+                self_clone.touch_dir(parent).await?;
+                self_clone.events.send(FsEvent::Removed {
+                    parent,
+                    ino: attr.ino,
+                    name: name_clone,
+                });
 
                 Ok(())
             })
             .await?
     }
 
-    #[allow(clippy::missing_panics_doc)]
+    /// Drop a link to the file `ino`, its inode and contents are removed if it was the last one, overwritten first if
+    /// `secure`. The caller removes the entry from the parent.
+    async fn unlink_file_inode(&self, ino: u64, secure: bool) -> FsResult<()> {
+        let attr = self
+            .update_nlink(ino, |nlink| nlink.saturating_sub(1))
+            .await?;
+
+        if attr.nlink == 0 {
+            // this was the last link
+            self.remove_file_inode(ino, secure).await?;
+        }
+        Ok(())
+    }
+
+    /// Remove the inode and the contents of the file `ino`, overwritten first if `secure`.
+    async fn remove_file_inode(&self, ino: u64, secure: bool) -> FsResult<()> {
+        let size = self.get_attr(ino).await.map_or(0, |attr| attr.size);
+        // remove inode file
+        {
+            let lock = self
+                .serialize_inode_locks
+                .get_or_insert_with(ino, || RwLock::new(false));
+            let _guard = lock.write();
+            self.remove_key(&self.ino_file(ino), secure).await?;
+        }
+
+        // remove from contents directory
+        let path = self.contents_path(ino);
+        if secure {
+            self.shred_contents(&path, 0).await?;
+        }
+        SegmentedFile::remove(&self.storage, &path).await?;
+        // remove from cache
+        self.block_cache.invalidate(ino);
+        self.forget_holes(ino);
+        self.attr_cache.get().await?.write().await.demote(&ino);
+        self.record_removed(ino).await?;
+        self.quota_forget(ino, size).await
+    }
+
+    /// Create a hard link to the file `ino` named `new_name` in `new_parent`.
+    ///
+    /// Both names share the same inode, so changes made through one are visible through the other.
+    /// The contents are removed only after the last link is deleted.
     #[allow(clippy::missing_errors_doc)]
-    pub fn exists_by_name(&self, parent: u64, name: &SecretString) -> FsResult<bool> {
-        if !self.exists(parent) {
+    pub async fn link(
+        &self,
+        ino: u64,
+        new_parent: u64,
+        new_name: &SecretString,
+    ) -> FsResult<FileAttr> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if *new_name.expose_secret() == "." || *new_name.expose_secret() == ".." {
+            return Err(FsError::invalid_input("name cannot be '.' or '..'"));
+        }
+        if !self.exists(ino).await {
+            return Err(FsError::InodeNotFound);
+        }
+        if !self.is_file(ino).await {
+            // hard links to directories are not allowed
+            return Err(FsError::InvalidInodeType);
+        }
+        if !self.exists(new_parent).await {
             return Err(FsError::InodeNotFound);
         }
-        if !self.is_dir(parent) {
+        if !self.is_dir(new_parent).await {
             return Err(FsError::InvalidInodeType);
         }
-        let hash = crypto::hash_file_name(name);
-        let hash_path = self.contents_path(parent).join(HASH_DIR).join(hash);
-        Ok(hash_path.is_file())
+        if self.exists_by_name(new_parent, new_name).await? {
+            return Err(FsError::AlreadyExists);
+        }
+        self.validate_filename(new_name)?;
+
+        let size = self.get_attr(ino).await?.size;
+        self.quota_add_entry(new_parent, ino, size).await?;
+        // increment first, if we crash before adding the entry we leave a higher nlink,
+        // which only delays removing the contents, instead of losing data
+        let attr = match self.update_nlink(ino, |nlink| nlink + 1).await {
+            Ok(attr) => attr,
+            Err(err) => {
+                self.quota_remove_entry(new_parent, ino, size).await;
+                return Err(err);
+            }
+        };
+        if let Err(err) = self
+            .insert_directory_entry(
+                new_parent,
+                &DirectoryEntry {
+                    ino,
+                    name: new_name.clone(),
+                    kind: attr.kind,
+                },
+            )
+            .await
+        {
+            self.quota_remove_entry(new_parent, ino, size).await;
+            self.update_nlink(ino, |nlink| nlink.saturating_sub(1))
+                .await?;
+            return Err(err);
+        }
+
+        self.touch_dir(new_parent).await?;
+        self.events.send(FsEvent::Created {
+            parent: new_parent,
+            ino,
+            name: new_name.clone(),
+        });
+
+        self.get_attr(ino).await
+    }
+
+    /// Change the number of hard links of an inode.
+    ///
+    /// The inode file is the source of truth for `nlink`, so it's read and written while holding the update lock.
+    async fn update_nlink(&self, ino: u64, f: impl FnOnce(u32) -> u32) -> FsResult<FileAttr> {
+        let serialize_update_lock = self
+            .serialize_update_inode_locks
+            .get_or_insert_with(ino, || Mutex::new(false));
+        let _serialize_update_guard = serialize_update_lock.lock().await;
+
+        let mut attr = self.get_attr(ino).await?;
+        attr.nlink = f(attr.nlink);
+        attr.ctime = SystemTime::now();
+        self.write_inode_to_storage(&attr).await?;
+
+        Ok(attr)
     }
 
+    #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
-    pub async fn read_dir(&self, ino: u64) -> FsResult<DirectoryEntryIterator> {
-        if !self.is_dir(ino) {
-            return Err(FsError::InvalidInodeType);
+    pub async fn exists_by_name(&self, parent: u64, name: &SecretString) -> FsResult<bool> {
+        if !self.exists(parent).await {
+            return Err(FsError::InodeNotFound);
         }
-        let ls_dir = self.contents_path(ino).join(LS_DIR);
-        if !ls_dir.is_dir() {
+        if !self.is_dir(parent).await {
             return Err(FsError::InvalidInodeType);
         }
+        Ok(self.lookup_entry(parent, name).await?.is_some())
+    }
+
+    /// The inode, the type and the encrypted name of the entry `name` of `parent`, `None` if it has none.
+    ///
+    /// It's found by its `hash` entry, which is trusted only if the name kept in it is `name`. Else it's the entry of
+    /// another name with the same hash, or a forged one, and the `ls` entries are scanned for `name`.
+    async fn lookup_entry(
+        &self,
+        parent: u64,
+        name: &SecretString,
+    ) -> FsResult<Option<(u64, FileType, String)>> {
+        let name = self.normalized_name(name);
+        let hash = self.hash_entry_name(parent, &name).await?;
+        let hash_path = self.contents_path(parent).join(HASH_DIR).join(&hash);
+        if self.kind_at(&hash_path).await != Some(EntryKind::File) {
+            return Ok(None);
+        }
+        let lock = self
+            .serialize_dir_entries_hash_locks
+            .get_or_insert_with(hash_path.to_str().unwrap().to_owned(), || {
+                RwLock::new(false)
+            });
+        let guard = lock.read().await;
+        let (ino, kind, encrypted_name): (u64, FileType, String) = self
+            .read_bound(&hash_path, &dir_entry_aad(parent, &hash))
+            .await
+            .map_err(|err| err.into_corrupted(&format!("directory entry {hash}"), Some(parent)))?;
+        if !self.is_entry_name(parent, &encrypted_name, &name).await? {
+            drop(guard);
+            warn!(
+                parent,
+                hash, "hash entry of another name, scanning the entries"
+            );
+            return self.scan_ls_entries(parent, &name).await;
+        }
+        // so listing the parent doesn't read and decrypt its `ls` entry again
+        let ls_name = ls_entry_name(&encrypted_name);
+        let ls_path = self.contents_path(parent).join(LS_DIR).join(&ls_name);
+        let ls_lock = self
+            .serialize_dir_entries_ls_locks
+            .get_or_insert_with(ls_path.to_str().unwrap().to_owned(), || RwLock::new(false));
+        let ls_guard = ls_lock.read().await;
+        self.cache_dir_entry(&ls_path, &ls_name, &name, ino, kind)
+            .await?;
+        drop(ls_guard);
+        drop(guard);
+        Ok(Some((ino, kind, encrypted_name)))
+    }
+
+    /// `true` if `encrypted_name`, of an entry of `parent`, is `name` encrypted.
+    async fn is_entry_name(
+        &self,
+        parent: u64,
+        encrypted_name: &str,
+        name: &SecretString,
+    ) -> FsResult<bool> {
+        let name = name.expose_secret();
+        match encrypted_name {
+            crypto::SELF_ENTRY_NAME => return Ok(*name == "." || *name == "$."),
+            crypto::PARENT_ENTRY_NAME => return Ok(*name == ".." || *name == "$.."),
+            _ => {}
+        }
+        let ls_name = ls_entry_name(encrypted_name);
+        if let Some(cached) = self
+            .get_dir_entries_name_cache()
+            .await?
+            .lock()
+            .await
+            .get(&ls_name)
+        {
+            return Ok(*cached.expose_secret() == *name);
+        }
+        match crypto::decrypt_file_name(
+            encrypted_name,
+            self.cipher,
+            &*self.inode_key(parent).await?,
+        ) {
+            Ok(decrypted) => Ok(*decrypted.expose_secret() == *name),
+            Err(err) => {
+                warn!(err = %err, parent, "decrypting file name");
+                Ok(false)
+            }
+        }
+    }
+
+    /// Look for the entry `name` of `parent` in all its `ls` entries, when its `hash` entry can't be trusted, see
+    /// [`EncryptedFs::lookup_entry`].
+    async fn scan_ls_entries(
+        &self,
+        parent: u64,
+        name: &SecretString,
+    ) -> FsResult<Option<(u64, FileType, String)>> {
+        let ls_dir = self.contents_path(parent).join(LS_DIR);
+        let key = self.key.get().await?;
+        for entry in self.storage.list(&ls_dir).await? {
+            let (ino, kind, encrypted_name) = self
+                .read_ls_entry(parent, &ls_dir.join(&entry.name), &key)
+                .await
+                .map_err(|err| err.into_corrupted("directory entry", Some(parent)))?;
+            if self.is_entry_name(parent, &encrypted_name, name).await? {
+                return Ok(Some((ino, kind, encrypted_name)));
+            }
+        }
+        Ok(None)
+    }
+
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_dir(&self, ino: u64) -> FsResult<DirectoryEntryIterator> {
+        let _timer = self.metrics.read_dir.start();
+        let iter = self.list_dir(ino).await?;
+        let entries = self.create_directory_entries(ino, iter).await?;
+        Ok(DirectoryEntryIterator(
+            entries.into_iter().map(|(_, entry)| entry).collect(),
+        ))
+    }
 
-        let iter = fs::read_dir(ls_dir)?;
-        let set_attr = SetFileAttr::default().with_atime(SystemTime::now());
-        self.set_attr(ino, set_attr).await?;
-        Ok(self.create_directory_entry_iterator(iter).await)
+    /// Like [`EncryptedFs::read_dir`] but an entry which can't be read is returned as
+    /// [`DirectoryEntryResult::Corrupted`], so the others can still be listed.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_dir_tolerant(&self, ino: u64) -> FsResult<DirectoryEntryResultIterator> {
+        let _timer = self.metrics.read_dir.start();
+        let iter = self.list_dir(ino).await?;
+        let ls_dir = self.contents_path(ino).join(LS_DIR);
+        let entries = self.create_directory_entries(ino, iter).await?;
+        Ok(DirectoryEntryResultIterator(
+            entries
+                .into_iter()
+                .map(|(encrypted_name, entry)| match entry {
+                    Ok(entry) => DirectoryEntryResult::Entry(entry),
+                    Err(error) => {
+                        warn!(err = %error, path = %ls_dir.join(&encrypted_name).display(), "corrupted directory entry");
+                        DirectoryEntryResult::Corrupted {
+                            encrypted_name,
+                            error,
+                        }
+                    }
+                })
+                .collect(),
+        ))
     }
 
     /// Like [`EncryptedFs::read_dir`] but with [`FileAttr`] so we don't need to query again for those.
     pub async fn read_dir_plus(&self, ino: u64) -> FsResult<DirectoryEntryPlusIterator> {
-        if !self.is_dir(ino) {
+        let _timer = self.metrics.read_dir.start();
+        let iter = self.list_dir(ino).await?;
+        self.create_directory_entry_plus_iterator(ino, iter).await
+    }
+
+    /// If reading something with these times `now` updates its access time, never in read-only mode.
+    fn should_update_atime(
+        &self,
+        atime: SystemTime,
+        mtime: SystemTime,
+        ctime: SystemTime,
+        now: SystemTime,
+    ) -> bool {
+        !self.read_only && self.atime.should_update(atime, mtime, ctime, now)
+    }
+
+    /// The `ls` entries of the directory `ino`, updating its access time as [`FsOptions::atime`] says.
+    async fn list_dir(&self, ino: u64) -> FsResult<Vec<StorageEntry>> {
+        if !self.is_dir(ino).await {
             return Err(FsError::InvalidInodeType);
         }
         let ls_dir = self.contents_path(ino).join(LS_DIR);
-        if !ls_dir.is_dir() {
+        if self.kind_at(&ls_dir).await != Some(EntryKind::Dir) {
             return Err(FsError::InvalidInodeType);
         }
 
-        let iter = fs::read_dir(ls_dir)?;
-        let set_attr = SetFileAttr::default().with_atime(SystemTime::now());
-        self.set_attr(ino, set_attr).await?;
-        Ok(self.create_directory_entry_plus_iterator(iter).await)
-    }
-
-    async fn create_directory_entry_plus(
-        &self,
-        entry: io::Result<DirEntry>,
-    ) -> FsResult<DirectoryEntryPlus> {
-        let entry = self.create_directory_entry(entry).await?;
-        let lock = self.serialize_inode_locks.clone();
-        let lock_ino = lock.get_or_insert_with(entry.ino, || RwLock::new(false));
-        let _ino_guard = lock_ino.read();
-        let attr = self.get_inode_from_cache_or_storage(entry.ino).await?;
-        Ok(DirectoryEntryPlus {
-            ino: entry.ino,
-            name: entry.name,
-            kind: entry.kind,
-            attr,
-        })
+        let iter = self.storage.list(&ls_dir).await?;
+        let attr = self.get_attr(ino).await?;
+        let now = SystemTime::now();
+        if self.should_update_atime(attr.atime, attr.mtime, attr.ctime, now) {
+            // keep the change time, only the access time changes
+            let set_attr = SetFileAttr::default()
+                .with_atime(now)
+                .with_ctime(attr.ctime);
+            self.update_attr(ino, set_attr).await?;
+        }
+        Ok(iter)
     }
 
     async fn create_directory_entry_plus_iterator(
         &self,
-        read_dir: ReadDir,
-    ) -> DirectoryEntryPlusIterator {
-        #[allow(clippy::cast_possible_truncation)]
-        let futures: Vec<_> = read_dir
+        parent: u64,
+        read_dir: Vec<StorageEntry>,
+    ) -> FsResult<DirectoryEntryPlusIterator> {
+        let entries = self.create_directory_entries(parent, read_dir).await?;
+
+        // take the cache lock once for all entries, and load only what is missing
+        let cache = self.attr_cache.get().await?;
+        let mut guard = cache.write().await;
+        let mut attrs: Vec<_> = entries
+            .iter()
+            .map(|(_, entry)| {
+                entry
+                    .as_ref()
+                    .ok()
+                    .and_then(|entry| {
+                        let attr = guard.get(&entry.ino).copied();
+                        self.metrics.attr_cache.record(attr.is_some());
+                        attr
+                    })
+                    .map(Ok)
+            })
+            .collect();
+        drop(guard);
+        let missing: Vec<_> = entries
+            .iter()
+            .zip(attrs.iter())
+            .filter_map(|((_, entry), attr)| match (entry, attr) {
+                (Ok(entry), None) => Some(entry.ino),
+                _ => None,
+            })
+            .collect();
+        let key = self.key.get().await?;
+        let loaded = self
+            .spawn_bounded(missing, move |fs, ino| {
+                let key = key.clone();
+                async move { fs.read_inode_with_key(ino, &key).await }
+            })
+            .await?;
+        let mut loaded = loaded.into_iter();
+        let mut guard = cache.write().await;
+        for ((_, entry), attr) in entries.iter().zip(attrs.iter_mut()) {
+            if entry.is_ok() && attr.is_none() {
+                let res = loaded.next().ok_or_else(|| {
+                    FsError::Internal("missing attributes of directory entry".to_owned())
+                })?;
+                if let Ok(loaded) = &res {
+                    guard.put(loaded.ino, *loaded);
+                }
+                *attr = Some(res);
+            }
+        }
+        drop(guard);
+
+        // merge the time updates of the directories not written yet
+        let pending_dir_times = self.pending_dir_times.lock().await;
+        let res = entries
             .into_iter()
-            .map(|entry| {
-                let fs = {
-                    self.self_weak
-                        .lock()
-                        .unwrap()
-                        .as_ref()
-                        .unwrap()
-                        .upgrade()
-                        .unwrap()
-                };
-                DIR_ENTRIES_RT.spawn(async move { fs.create_directory_entry_plus(entry).await })
+            .zip(attrs)
+            .map(|((_, entry), attr)| -> FsResult<DirectoryEntryPlus> {
+                let entry = entry?;
+                let mut attr = attr.ok_or_else(|| {
+                    FsError::Internal("missing attributes of directory entry".to_owned())
+                })??;
+                if let Some((_, time)) = pending_dir_times.get(&entry.ino) {
+                    merge_attr(&mut attr, &dir_times(*time), false);
+                }
+                Ok(DirectoryEntryPlus {
+                    ino: entry.ino,
+                    name: entry.name,
+                    kind: entry.kind,
+                    attr,
+                })
             })
             .collect();
+        Ok(DirectoryEntryPlusIterator(res))
+    }
 
-        // do these futures in parallel and return them
-        let mut res = VecDeque::with_capacity(futures.len());
-        for f in futures {
-            res.push_back(f.await.unwrap());
-        }
-        DirectoryEntryPlusIterator(res)
+    /// Run `f` for each of the `items` on the runtime, at most [`FsOptions::dir_entries_concurrency`] of them at
+    /// once, and return the results in the order of the `items`.
+    async fn spawn_bounded<T, R, F, Fut>(&self, items: Vec<T>, f: F) -> FsResult<Vec<FsResult<R>>>
+    where
+        F: Fn(Arc<Self>, T) -> Fut,
+        Fut: Future<Output = FsResult<R>> + Send + 'static,
+        R: Send + 'static,
+    {
+        let fs = self.self_arc()?;
+        Ok(futures_util::stream::iter(items)
+            .map(|item| self.runtime.spawn(f(fs.clone(), item)))
+            .buffered(self.dir_entries_concurrency.get())
+            .map(|res| res.unwrap_or_else(|err| Err(err.into())))
+            .collect()
+            .await)
     }
 
     async fn create_directory_entry(
         &self,
-        entry: io::Result<DirEntry>,
+        parent: u64,
+        entry: StorageEntry,
+        key: Option<&SecretVec<u8>>,
     ) -> FsResult<DirectoryEntry> {
-        if entry.is_err() {
-            return Err(entry.err().unwrap().into());
-        }
-        if let Err(e) = entry {
-            error!(err = %e, "reading directory entry");
-            return Err(e.into());
-        }
-        let entry = entry.unwrap();
-        let name = entry.file_name().to_string_lossy().to_string();
+        let entry_path = self.contents_path(parent).join(LS_DIR).join(&entry.name);
+        let name = entry.name;
         let name = {
-            if name == "$." {
+            if name == crypto::SELF_ENTRY_NAME {
                 SecretString::new(Box::new(".".into()))
-            } else if name == "$.." {
+            } else if name == crypto::PARENT_ENTRY_NAME {
                 SecretString::from_str("..").unwrap()
-            } else {
+            } else if let Some(key) = key {
                 // try from cache
                 let lock = self.get_dir_entries_name_cache().await?;
                 let mut cache = lock.lock().await;
                 if let Some(name_cached) = cache.get(&name).cloned() {
+                    self.metrics.name_cache.hit();
                     name_cached
                 } else {
                     drop(cache);
+                    self.metrics.name_cache.miss();
+                    // a long encrypted name is in the entry, see `ls_entry_name`
+                    let encrypted_name = if name.starts_with(LONG_NAME_PREFIX) {
+                        self.read_ls_entry(parent, &entry_path, &*self.key.get().await?)
+                            .await
+                            .map_err(|err| err.into_corrupted("directory entry", Some(parent)))?
+                            .2
+                    } else {
+                        name.clone()
+                    };
                     if let Ok(decrypted_name) =
-                        crypto::decrypt_file_name(&name, self.cipher, &*self.key.get().await?)
-                            .map_err(|err| {
+                        crypto::decrypt_file_name(&encrypted_name, self.cipher, key).map_err(
+                            |err| {
                                 error!(err = %err, "decrypting file name");
                                 err
-                            })
+                            },
+                        )
                     {
                         lock.lock().await.put(name.clone(), decrypted_name.clone());
                         decrypted_name
                     } else {
-                        return Err(FsError::InvalidInput("invalid file name"));
+                        return Err(FsError::Corrupted {
+                            what: "directory entry name".to_owned(),
+                            ino: Some(parent),
+                        });
                     }
                 }
+            } else {
+                dir_keys::locked_entry_name(&name)
             }
         };
 
         self.validate_filename(&name)?;
 
-        let file_path = entry.path().to_str().unwrap().to_owned();
+        let file_path = entry_path.to_str().unwrap().to_owned();
         // try from cache
         let lock = self.dir_entries_meta_cache.get().await?;
         let mut cache = lock.lock().await;
-        if let Some((ino, kind)) = cache.get(&file_path) {
+        let cached = cache.get(&file_path);
+        self.metrics.meta_cache.record(cached.is_some());
+        if let Some((ino, kind)) = cached {
             return Ok(DirectoryEntry {
                 ino: *ino,
                 name,
@@ -1182,25 +2525,23 @@ impl EncryptedFs {
             .serialize_dir_entries_ls_locks
             .get_or_insert_with(file_path.clone(), || RwLock::new(false));
         let guard = lock.read().await;
-        let file = File::open(entry.path())?;
-        let res: bincode::Result<(u64, FileType)> = bincode::deserialize_from(crypto::create_read(
-            file,
-            self.cipher,
-            &*self.key.get().await?,
-        ));
-        drop(guard);
+        let res = self
+            .read_ls_entry(parent, &entry_path, &*self.key.get().await?)
+            .await
+            .map_err(|err| err.into_corrupted("directory entry", Some(parent)));
         if let Err(e) = res {
             error!(err = %e, "deserializing directory entry");
-            return Err(e.into());
+            return Err(e);
         }
-        let (ino, kind): (u64, FileType) = res.unwrap();
-        // add to cache
+        let (ino, kind, _) = res.unwrap();
+        // add to cache while holding the lock, so we don't add it back after a change invalidated it
         self.dir_entries_meta_cache
             .get()
             .await?
             .lock()
             .await
             .put(file_path, (ino, kind));
+        drop(guard);
         Ok(DirectoryEntry { ino, name, kind })
     }
 
@@ -1210,107 +2551,361 @@ impl EncryptedFs {
         self.dir_entries_name_cache.get().await
     }
 
-    async fn create_directory_entry_iterator(&self, read_dir: ReadDir) -> DirectoryEntryIterator {
-        #[allow(clippy::cast_possible_truncation)]
-        let futures: Vec<_> = read_dir
-            .into_iter()
-            .map(|entry| {
-                let fs = {
-                    self.self_weak
-                        .lock()
-                        .unwrap()
-                        .as_ref()
-                        .unwrap()
-                        .upgrade()
-                        .unwrap()
-                };
-                DIR_ENTRIES_RT.spawn(async move { fs.create_directory_entry(entry).await })
-            })
-            .collect();
-
-        // do these futures in parallel and return them
-        let mut res = VecDeque::with_capacity(futures.len());
-        for f in futures {
-            res.push_back(f.await.unwrap());
-        }
-        DirectoryEntryIterator(res)
-    }
-
+    /// Keep in the caches the `ls` entry at `ls_path` named `ls_name`, after it was written or read from its `hash`
+    /// entry, so [`EncryptedFs::read_dir`] doesn't read and decrypt it again.
+    ///
+    /// Call it with a lock on the entry, so it's not added back after a change invalidated it.
+    async fn cache_dir_entry(
+        &self,
+        ls_path: &Path,
+        ls_name: &str,
+        name: &SecretString,
+        ino: u64,
+        kind: FileType,
+    ) -> FsResult<()> {
+        self.dir_entries_meta_cache
+            .get()
+            .await?
+            .lock()
+            .await
+            .put(ls_path.to_str().unwrap().to_owned(), (ino, kind));
+        // `.` and `..` are not encrypted
+        if ls_name != crypto::SELF_ENTRY_NAME && ls_name != crypto::PARENT_ENTRY_NAME {
+            self.get_dir_entries_name_cache()
+                .await?
+                .lock()
+                .await
+                .put(ls_name.to_owned(), name.clone());
+        }
+        Ok(())
+    }
+
+    /// Forget what the caches keep for the `ls` entry at `ls_path` named `ls_name`, after it was removed.
+    ///
+    /// Call it with the write lock on the entry, so a concurrent [`EncryptedFs::read_dir`] doesn't add it back.
+    async fn invalidate_dir_entry(&self, ls_path: &Path, ls_name: &str) -> FsResult<()> {
+        self.dir_entries_meta_cache
+            .get()
+            .await?
+            .lock()
+            .await
+            .pop(ls_path.to_str().unwrap());
+        self.get_dir_entries_name_cache()
+            .await?
+            .lock()
+            .await
+            .pop(ls_name);
+        Ok(())
+    }
+
+    /// Forget all the directory entries kept in the caches, after they were changed directly in the storage.
+    async fn clear_dir_entry_caches(&self) -> FsResult<()> {
+        self.dir_entries_meta_cache
+            .get()
+            .await?
+            .lock()
+            .await
+            .clear();
+        self.get_dir_entries_name_cache()
+            .await?
+            .lock()
+            .await
+            .clear();
+        Ok(())
+    }
+
+    /// The entries of `read_dir` with the name of their `ls` file.
+    async fn create_directory_entries(
+        &self,
+        parent: u64,
+        read_dir: Vec<StorageEntry>,
+    ) -> FsResult<Vec<(String, FsResult<DirectoryEntry>)>> {
+        let names: Vec<_> = read_dir.iter().map(|entry| entry.name.clone()).collect();
+        // the entries of a locked directory are listed with placeholder names
+        let key = match self.inode_key(parent).await {
+            Ok(key) => Some(key),
+            Err(FsError::Locked) => None,
+            Err(err) => return Err(err),
+        };
+        let entries = self
+            .spawn_bounded(read_dir, move |fs, entry| {
+                let key = key.clone();
+                async move {
+                    fs.create_directory_entry(parent, entry, key.as_deref())
+                        .await
+                }
+            })
+            .await?;
+        let mut entries: Vec<_> = names.into_iter().zip(entries).collect();
+        if self.dir_iteration_order == DirIterationOrder::ByNameAscending {
+            entries
+                .sort_by_cached_key(|(encrypted_name, entry)| dir_order_key(encrypted_name, entry));
+        }
+        Ok(entries)
+    }
+
     #[allow(clippy::missing_errors_doc)]
     async fn get_inode_from_storage(&self, ino: u64) -> FsResult<FileAttr> {
+        self.read_inode_with_key(ino, &*self.key.get().await?).await
+    }
+
+    /// Like [`EncryptedFs::get_inode_from_storage`], with a key already fetched for many of them.
+    async fn read_inode_with_key(&self, ino: u64, key: &SecretVec<u8>) -> FsResult<FileAttr> {
         let lock = self
             .serialize_inode_locks
             .get_or_insert_with(ino, || RwLock::new(false));
         let _guard = lock.read();
 
         let path = self.ino_file(ino);
-        if !path.is_file() {
+        if self.kind_at(&path).await != Some(EntryKind::File) {
             return Err(FsError::InodeNotFound);
         }
-        let file = OpenOptions::new().read(true).open(path).map_err(|err| {
-            error!(err = %err, "opening file");
+        let data = self.storage.read(&path).await.map_err(|err| {
+            error!(err = %err, "reading file");
             FsError::InodeNotFound
         })?;
-        Ok(bincode::deserialize_from(crypto::create_read(
-            file,
-            self.cipher,
-            &*self.key.get().await?,
-        ))?)
+        let mut attr: FileAttr =
+            deserialize_bound(data.as_slice(), self.cipher, key, &inode_aad(ino))
+                .map_err(|err| err.into_corrupted("inode file", Some(ino)))?;
+        // fixed up for the ones written without them
+        attr.fill_blocks();
+        Ok(attr)
     }
 
     async fn get_inode_from_cache_or_storage(&self, ino: u64) -> FsResult<FileAttr> {
         let lock = self.attr_cache.get().await?;
         let mut guard = lock.write().await;
-        let attr = guard.get(&ino);
-        if let Some(attr) = attr {
-            Ok(*attr)
+        let cached = guard.get(&ino).copied();
+        drop(guard);
+        self.metrics.attr_cache.record(cached.is_some());
+        let mut attr = if let Some(attr) = cached {
+            attr
         } else {
-            drop(guard);
             let attr = self.get_inode_from_storage(ino).await?;
             let mut guard = lock.write().await;
             guard.put(ino, attr);
-            Ok(attr)
+            attr
+        };
+
+        // merge the time updates of a directory not written yet
+        if let Some((_, time)) = self.pending_dir_times.lock().await.get(&ino) {
+            merge_attr(&mut attr, &dir_times(*time), false);
         }
+        Ok(attr)
     }
 
     /// Get metadata
+    ///
+    /// The open handles keep the times changed by their reads and writes until they are flushed or released. The size
+    /// is the largest of the write handles, the times are all taken from the inode or the handle changed last, by
+    /// ctime then atime, not mixed field by field. Only the handles with changes count, and an explicit change like
+    /// [`EncryptedFs::set_times`] takes over what they kept from before it.
     #[allow(clippy::missing_errors_doc)]
     pub async fn get_attr(&self, ino: u64) -> FsResult<FileAttr> {
         let mut attr = self.get_inode_from_cache_or_storage(ino).await?;
+        let mut times = TimesFileAttr::from(attr);
 
-        // merge time info with any open read handles
-        let open_reads = { self.opened_files_for_read.read().await.contains_key(&ino) };
+        // the access time updated by the read handles
+        let open_reads = { self.opened_files_for_read.contains_key(&ino).await };
         if open_reads {
-            let fhs = self.opened_files_for_read.read().await.get(&ino).cloned();
+            let fhs = self
+                .opened_files_for_read
+                .read(&ino)
+                .await
+                .get(&ino)
+                .cloned();
             if let Some(fhs) = fhs {
                 for fh in fhs {
-                    let lock = self.read_handles.read().await;
-                    if let Some(ctx) = lock.get(&fh) {
-                        let set_atr: SetFileAttr = ctx.lock().await.attr.clone().into();
-                        merge_attr(&mut attr, &set_atr, false);
+                    let lock = self.read_handles.read(&fh).await;
+                    let Some(ctx) = lock.get(&fh) else {
+                        drop(lock);
+                        self.repair_dangling_read_handle(ino, fh).await;
+                        continue;
+                    };
+                    let ctx = ctx.lock().await;
+                    if ctx.accessed {
+                        take_newer_times(&mut times, &ctx.attr);
                     }
                 }
             }
         }
 
-        // merge time info and size with any open write handles
-        let open_writes = { self.opened_files_for_write.read().await.contains_key(&ino) };
-        if open_writes {
-            let fh = self.opened_files_for_write.read().await.get(&ino).copied();
-            if let Some(fh) = fh {
-                let lock = self.write_handles.read().await;
+        // the size and the times of the write handles, the size of a writer wins over the one of the inode
+        let fhs = self
+            .opened_files_for_write
+            .read(&ino)
+            .await
+            .get(&ino)
+            .cloned();
+        let mut write_size = None;
+        if let Some(fhs) = fhs {
+            for fh in fhs {
+                let lock = self.write_handles.read(&fh).await;
                 if let Some(ctx) = lock.get(&fh) {
                     let ctx = ctx.lock().await;
-                    merge_attr(&mut attr, &ctx.attr.clone().into(), false);
+                    write_size = write_size.max(Some(ctx.attr.size));
+                    if ctx.attr_dirty {
+                        take_newer_times(&mut times, &ctx.attr.clone().into());
+                    }
                 }
             }
         }
 
+        attr.size = write_size.unwrap_or(attr.size);
+        attr.atime = times.atime;
+        attr.mtime = times.mtime;
+        attr.ctime = times.ctime;
         Ok(attr)
     }
 
-    /// Set metadata
-    pub async fn set_attr(&self, ino: u64, set_attr: SetFileAttr) -> FsResult<()> {
+    /// Set metadata.
+    ///
+    /// A `size` truncates or extends the contents like [`EncryptedFs::set_len`]. The other fields are recorded as they
+    /// are, the times only if newer. For validated changes use [`EncryptedFs::set_permissions`],
+    /// [`EncryptedFs::set_owner`] and [`EncryptedFs::set_times`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn set_attr(&self, ino: u64, mut set_attr: SetFileAttr) -> FsResult<()> {
+        if let Some(size) = set_attr.size.take() {
+            self.set_len(ino, size).await?;
+        }
+        self.update_attr(ino, set_attr).await?;
+        self.refresh_handle_times(ino).await?;
+        self.events.send(FsEvent::AttrChanged { ino });
+        Ok(())
+    }
+
+    /// Change the permission bits of `ino` like `chmod(2)`, `mode` can have only the bits in `0o7777`.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn set_permissions(&self, ino: u64, mode: u16) -> FsResult<()> {
+        if mode & !PERM_MASK != 0 {
+            return Err(FsError::invalid_input(format!(
+                "invalid permissions {mode:o}"
+            )));
+        }
+        self.change_attr(ino, |attr| {
+            // keep the file type bits the mount layers may have put in
+            attr.perm = (attr.perm & !PERM_MASK) | mode;
+        })
+        .await
+    }
+
+    /// Change the owner and the group of `ino` like `chown(2)`, `None` keeps them.
+    ///
+    /// `u32::MAX` is not a valid id, it's `-1` which means no change in `chown(2)`.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn set_owner(&self, ino: u64, uid: Option<u32>, gid: Option<u32>) -> FsResult<()> {
+        if uid == Some(u32::MAX) || gid == Some(u32::MAX) {
+            return Err(FsError::invalid_input("invalid user or group id"));
+        }
+        self.change_attr(ino, |attr| {
+            attr.uid = uid.unwrap_or(attr.uid);
+            attr.gid = gid.unwrap_or(attr.gid);
+        })
+        .await
+    }
+
+    /// Set the access and the modification times of `ino` like `utimensat(2)`, `None` keeps them.
+    ///
+    /// Unlike [`EncryptedFs::set_attr`] they can go back in time, but not before the Unix epoch.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn set_times(
+        &self,
+        ino: u64,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> FsResult<()> {
+        if [atime, mtime].into_iter().flatten().any(|t| t < UNIX_EPOCH) {
+            return Err(FsError::invalid_input("time before the Unix epoch"));
+        }
+        self.change_attr(ino, |attr| {
+            attr.atime = atime.unwrap_or(attr.atime);
+            attr.mtime = mtime.unwrap_or(attr.mtime);
+        })
+        .await
+    }
+
+    /// Apply a validated change to the attributes of `ino`, which also sets its ctime.
+    async fn change_attr(&self, ino: u64, f: impl FnOnce(&mut FileAttr) + Send) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        self.modify_attr(ino, |attr| {
+            f(attr);
+            attr.ctime = SystemTime::now();
+        })
+        .await?;
+        self.refresh_handle_times(ino).await?;
+        self.events.send(FsEvent::AttrChanged { ino });
+        Ok(())
+    }
+
+    /// Give the open handles of `ino` the times of the inode after an explicit change, so the ones they kept from
+    /// before don't take over it in [`EncryptedFs::get_attr`] or when they are released.
+    async fn refresh_handle_times(&self, ino: u64) -> FsResult<()> {
+        let attr = self.get_inode_from_cache_or_storage(ino).await?;
+        let fhs = self
+            .opened_files_for_read
+            .read(&ino)
+            .await
+            .get(&ino)
+            .cloned()
+            .unwrap_or_default();
+        for fh in fhs {
+            let lock = self.read_handles.read(&fh).await;
+            if let Some(ctx) = lock.get(&fh) {
+                let mut ctx = ctx.lock().await;
+                ctx.attr = attr.into();
+                ctx.accessed = false;
+            }
+        }
+        let fhs = self
+            .opened_files_for_write
+            .read(&ino)
+            .await
+            .get(&ino)
+            .cloned()
+            .unwrap_or_default();
+        for fh in fhs {
+            let lock = self.write_handles.read(&fh).await;
+            if let Some(ctx) = lock.get(&fh) {
+                let mut ctx = ctx.lock().await;
+                ctx.attr.atime = attr.atime;
+                ctx.attr.mtime = attr.mtime;
+                ctx.attr.ctime = attr.ctime;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the times kept by a handle of `ino`, and the size of a write handle, to its inode.
+    ///
+    /// Like in [`EncryptedFs::get_attr`] the times are taken together and only if the handle changed them after the
+    /// inode, so an explicit change made while it was open, like [`EncryptedFs::set_times`], is not undone by a handle
+    /// which kept older ones. The size is the largest.
+    async fn save_handle_attr(
+        &self,
+        ino: u64,
+        times: TimesFileAttr,
+        size: Option<u64>,
+    ) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        self.modify_attr(ino, |attr| {
+            let mut newer = TimesFileAttr::from(*attr);
+            take_newer_times(&mut newer, &times);
+            attr.atime = newer.atime;
+            attr.mtime = newer.mtime;
+            attr.ctime = newer.ctime;
+            if let Some(size) = size {
+                attr.size = attr.size.max(size);
+            }
+        })
+        .await
+    }
+
+    /// Like [`EncryptedFs::set_attr`], for our own updates which don't send [`FsEvent::AttrChanged`].
+    async fn update_attr(&self, ino: u64, set_attr: SetFileAttr) -> FsResult<()> {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
@@ -1323,16 +2918,34 @@ impl EncryptedFs {
         set_attr: SetFileAttr,
         overwrite_size: bool,
     ) -> FsResult<()> {
+        self.modify_attr(ino, |attr| {
+            merge_attr(attr, &set_attr, overwrite_size);
+            // keep the times explicitly set
+            let now = SystemTime::now();
+            if set_attr.ctime.is_none() {
+                attr.ctime = now;
+            }
+            if set_attr.atime.is_none() {
+                attr.atime = now;
+            }
+        })
+        .await
+    }
+
+    /// Read the attributes of `ino`, change them with `f` and write them, serialized with the other updates.
+    async fn modify_attr(&self, ino: u64, f: impl FnOnce(&mut FileAttr) + Send) -> FsResult<()> {
         let serialize_update_lock = self
             .serialize_update_inode_locks
             .get_or_insert_with(ino, || Mutex::new(false));
         let _serialize_update_guard = serialize_update_lock.lock().await;
 
+        // the pending time updates are written with this
+        let pending_times = self.pending_dir_times.lock().await.remove(&ino);
         let mut attr = self.get_attr(ino).await?;
-        merge_attr(&mut attr, &set_attr, overwrite_size);
-        let now = SystemTime::now();
-        attr.ctime = now;
-        attr.atime = now;
+        if let Some((_, time)) = pending_times {
+            merge_attr(&mut attr, &dir_times(time), false);
+        }
+        f(&mut attr);
 
         self.write_inode_to_storage(&attr).await?;
 
@@ -1344,29 +2957,111 @@ impl EncryptedFs {
             .serialize_inode_locks
             .get_or_insert_with(attr.ino, || RwLock::new(false));
         let guard = lock.write().await;
-        crypto::atomic_serialize_encrypt_into(
+        #[allow(clippy::cast_possible_truncation)]
+        let attr = FileAttr {
+            generation: self.log_change(attr.ino, attr.kind).await?,
+            blocks: match attr.kind {
+                FileType::RegularFile => self.allocated_blocks(attr.ino).await?,
+                FileType::Directory
+                | FileType::NamedPipe
+                | FileType::CharDevice
+                | FileType::BlockDevice
+                | FileType::Socket => attr.size.div_ceil(STAT_BLOCK_SIZE),
+            },
+            blksize: BLOCK_SIZE as u32,
+            ..*attr
+        };
+        let holes = match attr.kind {
+            FileType::RegularFile => self.holes_to_write(attr.ino).await?,
+            _ => Holes::default(),
+        };
+        self.ensure_shard_synced_with_policy(&self.ino_file(attr.ino))
+            .await?;
+        self.write_bound(
             &self.ino_file(attr.ino),
-            attr,
-            self.cipher,
-            &*self.key.get().await?,
-        )?;
+            &(&attr, &holes),
+            &inode_aad(attr.ino),
+        )
+        .await?;
         drop(guard);
         // update cache also
         {
             let lock = self.attr_cache.get().await?;
             let mut guard = lock.write().await;
-            guard.put(attr.ino, *attr);
+            guard.put(attr.ino, attr);
+        }
+        Ok(())
+    }
+
+    /// The holes of the contents of `ino`, shared by all its readers and writers, see [`RingCryptoRead::sparse`].
+    ///
+    /// They are kept in the inode after its attributes, so they are authenticated with it, and read from it the first
+    /// time. The ones no reader or writer uses anymore are dropped once written back to the inode.
+    ///
+    /// [`RingCryptoRead::sparse`]: crypto::read::RingCryptoRead::sparse
+    async fn contents_holes(&self, ino: u64) -> FsResult<SharedHoles> {
+        if let Some(holes) = self.contents_holes.lock().unwrap().get(&ino) {
+            return Ok(holes.clone());
+        }
+        let holes = self.read_holes(ino).await?;
+        let mut contents_holes = self.contents_holes.lock().unwrap();
+        contents_holes
+            .retain(|_, holes| Arc::strong_count(holes) > 1 || holes.lock().unwrap().changed());
+        Ok(contents_holes
+            .entry(ino)
+            .or_insert_with(|| Arc::new(std::sync::Mutex::new(holes)))
+            .clone())
+    }
+
+    /// Read the holes of the contents of `ino` from its inode, none if it's not written yet.
+    async fn read_holes(&self, ino: u64) -> FsResult<Holes> {
+        let path = self.ino_file(ino);
+        if self.kind_at(&path).await != Some(EntryKind::File) {
+            return Ok(Holes::default());
         }
+        let (_, holes): (FileAttr, Holes) = self
+            .read_bound(&path, &inode_aad(ino))
+            .await
+            .map_err(|err| err.into_corrupted("inode file", Some(ino)))?;
+        Ok(holes)
+    }
+
+    /// The holes of `ino` to write in its inode, from then on they are not changed.
+    async fn holes_to_write(&self, ino: u64) -> FsResult<Holes> {
+        let holes = self.contents_holes(ino).await?;
+        let mut holes = holes.lock().unwrap();
+        holes.take_changed();
+        Ok(holes.clone())
+    }
+
+    /// Read again the holes of `ino` from its inode, after it was changed outside of us. The readers and writers
+    /// using them see the new ones.
+    async fn reload_holes(&self, ino: u64) -> FsResult<()> {
+        let holes = self.contents_holes.lock().unwrap().get(&ino).cloned();
+        let Some(holes) = holes else {
+            return Ok(());
+        };
+        let stored = self.read_holes(ino).await?;
+        let mut holes = holes.lock().unwrap();
+        holes.replace(stored);
+        holes.take_changed();
         Ok(())
     }
 
+    /// Forget the holes of `ino`, after its inode is removed.
+    fn forget_holes(&self, ino: u64) {
+        self.contents_holes.lock().unwrap().remove(&ino);
+    }
+
     /// Read the contents from an `offset`.
     ///
-    /// If we try to read outside of file size, we return zero bytes.
+    /// If we try to read outside of file size, we return zero bytes, a zero is returned only at the end of the file.
+    /// If the offset is inside the file but can't be reached, it fails with [`FsError::SeekFailed`].
     /// If the file is not opened for read, it will return an error of type [FsError::InvalidFileHandle].
+    ///
+    /// With [`FsOptions::throttle`] it waits for what it read before returning it.
     #[instrument(skip(self, buf), fields(len = %buf.len()), ret(level = Level::DEBUG))]
     #[allow(clippy::missing_errors_doc)]
-    #[allow(clippy::cast_possible_truncation)]
     pub async fn read(
         &self,
         ino: u64,
@@ -1374,30 +3069,54 @@ impl EncryptedFs {
         buf: &mut [u8],
         handle: u64,
     ) -> FsResult<usize> {
-        if !self.exists(ino) {
+        let op = self.throttle.start_op().await;
+        let len = self.read_contents(ino, offset, buf, handle).await?;
+        drop(op);
+        self.throttle.wait_read(handle, len as u64).await;
+        Ok(len)
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    async fn read_contents(
+        &self,
+        ino: u64,
+        offset: u64,
+        buf: &mut [u8],
+        handle: u64,
+    ) -> FsResult<usize> {
+        let timer = self.metrics.read.start();
+        if !self.exists(ino).await {
             return Err(FsError::InodeNotFound);
         }
-        if !self.is_file(ino) {
+        if !self.is_file(ino).await || self.get_attr(ino).await?.kind.is_special() {
             return Err(FsError::InvalidInodeType);
         }
-        if !self.read_handles.read().await.contains_key(&handle) {
+        self.check_handle_id(handle).await?;
+        if !self.read_handles.contains_key(&handle).await {
             return Err(FsError::InvalidFileHandle);
         }
 
-        let _size = self.get_attr(ino).await?.size;
-
         let lock = self
             .read_write_locks
             .get_or_insert_with(ino, || RwLock::new(false));
+        if self.has_buffered_writes(ino).await {
+            // we read from the disk, so what the writers have buffered is written first
+            let _write_guard = lock.write().await;
+            self.reset_handles(ino, None, true).await?;
+        }
         let _read_guard = lock.read().await;
 
-        let guard = self.read_handles.read().await;
-        let mut ctx = guard.get(&handle).unwrap().lock().await;
+        let guard = self.read_handles.read(&handle).await;
+        let mut ctx = guard
+            .get(&handle)
+            .ok_or(FsError::InvalidFileHandle)?
+            .lock()
+            .await;
 
         if ctx.ino != ino {
             return Err(FsError::InvalidFileHandle);
         }
-        if self.is_dir(ino) {
+        if self.is_dir(ino).await {
             return Err(FsError::InvalidInodeType);
         }
         if buf.is_empty() {
@@ -1405,31 +3124,33 @@ impl EncryptedFs {
             return Ok(0);
         }
 
+        if ctx.reader.is_none() {
+            // suspended by `lock`
+            ctx.reader = Some(self.create_contents_reader(ino).await?);
+        }
+        let cached = self.block_cache.is_enabled()
+            && !self.get_inode_from_cache_or_storage(ino).await?.compressed;
         // read data
-        let (_buf, len) = {
+        let (_buf, len) = if cached {
+            let reader = ctx.reader.as_mut().unwrap();
+            let len = self
+                .read_with_block_cache(ino, offset, buf, &mut **reader)
+                .map_err(|err| {
+                    error!(err = %err, "reading");
+                    err
+                })?;
+            (buf, len)
+        } else {
             let reader = ctx.reader.as_mut().unwrap();
 
-            reader.seek(SeekFrom::Start(offset)).map_err(|err| {
+            let in_file = seek_for_read(&mut **reader, offset).map_err(|err| {
                 error!(err = %err, "seeking");
                 err
             })?;
-            let pos = reader.stream_position().map_err(|err| {
-                error!(err = %err, "getting position");
-                err
-            })?;
-            if pos != offset {
-                // we would need to seek after filesize
+            if !in_file {
+                // past the end of the file
                 return Ok(0);
             }
-            // keep block size to max the cipher can handle
-            #[allow(clippy::cast_possible_truncation)]
-            let buf = if offset + buf.len() as u64 > self.cipher.max_plaintext_len() as u64 {
-                warn!("reading more than max block size, truncating");
-                buf.split_at_mut(self.cipher.max_plaintext_len() - offset as usize)
-                    .0
-            } else {
-                buf
-            };
             let len = stream_util::read(reader, buf).map_err(|err| {
                 error!(err = %err, "reading");
                 err
@@ -1437,7 +3158,11 @@ impl EncryptedFs {
             (buf, len)
         };
 
-        ctx.attr.atime = SystemTime::now();
+        let now = SystemTime::now();
+        if self.should_update_atime(ctx.attr.atime, ctx.attr.mtime, ctx.attr.ctime, now) {
+            ctx.attr.atime = now;
+            ctx.accessed = true;
+        }
         drop(ctx);
 
         // self.sizes_read
@@ -1469,98 +3194,180 @@ impl EncryptedFs {
         //     });
         // }
 
+        timer.add_bytes(len as u64);
         Ok(len)
     }
 
+    /// Read from `offset` until `buf` is filled or the end of the file is reached, calling [`EncryptedFs::read`]
+    /// as many times as needed.
+    ///
+    /// Returns the number of bytes read, less than `buf.len()` only at the end of the file.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_full_at(
+        &self,
+        ino: u64,
+        offset: u64,
+        buf: &mut [u8],
+        fh: u64,
+    ) -> FsResult<usize> {
+        let mut pos = 0_usize;
+        while pos < buf.len() {
+            let len = self
+                .read(ino, offset + pos as u64, &mut buf[pos..], fh)
+                .await?;
+            if len == 0 {
+                break;
+            }
+            pos += len;
+        }
+        Ok(pos)
+    }
+
+    /// Like [`EncryptedFs::read_full_at`], but it's an error if the end of the file is reached before `buf` is
+    /// filled.
+    ///
+    /// That is an [`FsError::Io`] of kind [`io::ErrorKind::UnexpectedEof`], with how many bytes were read in the
+    /// message.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_exact_at(
+        &self,
+        ino: u64,
+        offset: u64,
+        buf: &mut [u8],
+        fh: u64,
+    ) -> FsResult<()> {
+        let len = self.read_full_at(ino, offset, buf, fh).await?;
+        if len < buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "read only {len} of {} bytes from offset {offset}",
+                    buf.len()
+                ),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Read the whole file.
+    ///
+    /// To not load a huge file in memory by mistake, it fails with [`FsError::InvalidInput`] if the file is larger
+    /// than `max_size`.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_all(&self, ino: u64, fh: u64, max_size: u64) -> FsResult<Vec<u8>> {
+        let too_large = |size: u64| {
+            FsError::invalid_input(format!(
+                "file {ino} has {size} bytes, more than the max of {max_size}"
+            ))
+        };
+        let size = self.get_attr(ino).await?.size;
+        if size > max_size {
+            return Err(too_large(size));
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        let mut data = vec![0; size as usize];
+        let mut len = self.read_full_at(ino, 0, &mut data, fh).await?;
+        data.truncate(len);
+        // it could have grown meanwhile
+        let mut chunk = [0; 8192];
+        loop {
+            let chunk_len = self.read(ino, len as u64, &mut chunk, fh).await?;
+            if chunk_len == 0 {
+                break;
+            }
+            len += chunk_len;
+            if len as u64 > max_size {
+                return Err(too_large(len as u64));
+            }
+            data.extend_from_slice(&chunk[..chunk_len]);
+        }
+        Ok(data)
+    }
+
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::too_many_lines)]
     pub async fn release(&self, handle: u64) -> FsResult<()> {
+        let _timer = self.metrics.release.start();
         if handle == 0 {
             // in the case of directory or if the file was crated
             // without being opened we don't use a handle
             return Ok(());
         }
+        // like closing a file, the owners which locked through it lose their locks on it
+        self.locks.release_handle(handle);
+        self.handle_ids.lock().unwrap().remove(&handle);
+        self.throttle.forget_handle(handle);
         let mut valid_fh = false;
 
         // read
-        let ctx = { self.read_handles.write().await.remove(&handle) };
+        let ino = match self.read_handles.read(&handle).await.get(&handle) {
+            Some(ctx) => Some(ctx.lock().await.ino),
+            None => None,
+        };
+        let ctx = match ino {
+            Some(ino) => self.remove_read_handle(ino, handle).await,
+            None => None,
+        };
         if let Some(ctx) = ctx {
             let ctx = ctx.lock().await;
 
-            {
-                let mut opened_files_for_read = self.opened_files_for_read.write().await;
-                opened_files_for_read
-                    .get_mut(&ctx.ino)
-                    .expect("handle is missing")
-                    .remove(&handle);
-                if opened_files_for_read
-                    .get(&ctx.ino)
-                    .expect("handle is missing")
-                    .is_empty()
-                {
-                    opened_files_for_read.remove(&ctx.ino);
-                }
-            }
-
             // write attr only here to avoid serializing it multiple times while reading
-            // it will merge time fields with existing data because it might got change while we kept the handle
-            let set_attr: SetFileAttr = ctx.attr.clone().into();
-            let ino = ctx.ino;
-            drop(ctx);
-            self.set_attr(ino, set_attr).await?;
+            // nothing is written if the access time was not updated, see `FsOptions::atime`
+            if ctx.accessed {
+                let times = ctx.attr.clone();
+                let ino = ctx.ino;
+                drop(ctx);
+                self.save_handle_attr(ino, times, None).await?;
+            }
 
             valid_fh = true;
         }
 
         // write
-        let ctx = { self.write_handles.write().await.remove(&handle) };
+        let ctx = { self.write_handles.remove(&handle).await };
         if let Some(ctx) = ctx {
             if self.read_only {
                 return Err(FsError::ReadOnly);
             }
             let mut ctx = ctx.lock().await;
 
-            let mut writer = ctx.writer.take().unwrap();
             let lock = self
                 .read_write_locks
                 .get_or_insert_with(ctx.ino, || RwLock::new(false));
             let write_guard = lock.write().await;
-            let file = writer.finish()?;
-            file.sync_all()?;
-            File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
+            ctx.write_buffer()?;
+            let writer = ctx.writer.take();
+            // if suspended by `lock` it was already written
+            if let Some(mut writer) = writer {
+                let mut file = writer.finish()?;
+                if self.durability() != DurabilityPolicy::Never {
+                    file.sync_all()?;
+                }
+            }
             // write attr only here to avoid serializing it multiple times while writing
-            // it will merge time fields with existing data because it might got change while we kept the handle
             let ino = ctx.ino;
-            let attr = ctx.attr.clone();
+            let attr = ctx.attr_dirty.then(|| ctx.attr.clone());
+            let written = ctx.written.take();
             drop(ctx);
-            self.set_attr(ino, attr.into()).await?;
+            if let Some(attr) = attr {
+                let size = attr.size;
+                self.save_handle_attr(ino, attr.into(), Some(size)).await?;
+            }
             let attr = self.get_attr(ino).await?;
             {
-                let write_size = self
-                    .sizes_write
-                    .lock()
-                    .await
-                    .get(&ino)
-                    .unwrap()
-                    .load(Ordering::SeqCst);
-                info!("written for {ino} {write_size}");
+                let size_of = |sizes: &HashMap<u64, AtomicU64>| {
+                    sizes
+                        .get(&ino)
+                        .map_or(0, |size| size.load(Ordering::SeqCst))
+                };
+                let write_size = size_of(&*self.sizes_write.lock().await);
+                debug!("written for {ino} {write_size}");
                 if attr.size != write_size {
                     // error!("size mismatch write {} {}", write_size, attr.size);
                 }
-                let requested_read = self
-                    .requested_read
-                    .lock()
-                    .await
-                    .get(&ino)
-                    .unwrap()
-                    .load(Ordering::SeqCst);
-                let read = self
-                    .sizes_read
-                    .lock()
-                    .await
-                    .get(&ino)
-                    .unwrap()
-                    .load(Ordering::SeqCst);
+                let requested_read = size_of(&*self.requested_read.lock().await);
+                let read = size_of(&*self.sizes_read.lock().await);
                 if requested_read != read {
                     error!(
                         "size mismatch read, size {} requested {} read {}",
@@ -1568,12 +3375,37 @@ impl EncryptedFs {
                     );
                 }
             }
-            self.sizes_write.lock().await.remove(&ino);
-            self.sizes_read.lock().await.remove(&ino);
-            self.requested_read.lock().await.remove(&ino);
+            let last_writer = {
+                let mut opened_files_for_write = self.opened_files_for_write.write(&ino).await;
+                let last_writer = opened_files_for_write.get_mut(&ino).is_none_or(|fhs| {
+                    if !fhs.remove(&handle) {
+                        warn!(
+                            ino,
+                            handle, "write handle was missing from the opened files"
+                        );
+                    }
+                    fhs.is_empty()
+                });
+                if last_writer {
+                    opened_files_for_write.remove(&ino);
+                }
+                last_writer
+            };
+            if last_writer {
+                self.quota_settle(ino, attr.size).await?;
+                self.sizes_write.lock().await.remove(&ino);
+                self.sizes_read.lock().await.remove(&ino);
+                self.requested_read.lock().await.remove(&ino);
+            }
             drop(write_guard);
-            self.opened_files_for_write.write().await.remove(&ino);
             self.reset_handles(ino, Some(handle), true).await?;
+            if let Some(written) = written {
+                self.events.send(FsEvent::Written {
+                    ino,
+                    offset: written.start,
+                    len: written.end - written.start,
+                });
+            }
 
             valid_fh = true;
         }
@@ -1581,90 +3413,190 @@ impl EncryptedFs {
         if !valid_fh {
             return Err(FsError::InvalidFileHandle);
         }
-        Ok(())
+        self.flush_dir_times().await?;
+        self.sync_pending().await
     }
 
     /// Check if a file is opened for reading with this handle.
     pub async fn is_read_handle(&self, fh: u64) -> bool {
-        self.read_handles.read().await.contains_key(&fh)
+        self.read_handles.contains_key(&fh).await
     }
 
     /// Check if a file is opened for writing with this handle.
     pub async fn is_write_handle(&self, fh: u64) -> bool {
-        self.write_handles.read().await.contains_key(&fh)
+        self.write_handles.contains_key(&fh).await
     }
 
     /// Writes the contents of `buf` to the file with `ino` starting at `offset`.
     ///
-    /// If we write outside file size, we fill up with zeros until the `offset`.
+    /// If we write outside file size, the gap until the `offset` is left as a hole which reads as zeros, only the
+    /// block the file ended in is written again. It writes at least one byte of a non-empty `buf`, if the `offset`
+    /// can't be reached it fails with [`FsError::SeekFailed`]. A write ending past the largest size of a file is not
+    /// shortened, it fails with [`FsError::MaxFilesizeExceeded`] and nothing is written.
     /// If the file is not opened for writing,
     /// it will return an error of type [FsError::InvalidFileHandle].
+    ///
+    /// Content is encrypted in blocks, so only the blocks touched by the write are re-encrypted, and only the read
+    /// handles positioned in those blocks are reset.
     #[instrument(skip(self, buf), fields(len = %buf.len()), ret(level = Level::DEBUG))]
     pub async fn write(&self, ino: u64, offset: u64, buf: &[u8], handle: u64) -> FsResult<usize> {
-        if self.read_only {
-            return Err(FsError::ReadOnly);
-        }
-        if !self.exists(ino) {
-            return Err(FsError::InodeNotFound);
-        }
-        if !self.is_file(ino) {
-            return Err(FsError::InvalidInodeType);
-        }
-        {
-            if !self.write_handles.read().await.contains_key(&handle) {
-                return Err(FsError::InvalidFileHandle);
-            }
-        }
-        {
-            let guard = self.write_handles.read().await;
-            let ctx = guard.get(&handle).unwrap().lock().await;
+        self.write_slices(ino, offset, &[IoSlice::new(buf)], handle)
+            .await
+    }
+
+    /// Like [`EncryptedFs::write`], but writes the `bufs` one after the other as if they were a single buffer.
+    ///
+    /// The handle is locked, the position is set and the size and times are updated only once for all of them, so
+    /// it's cheaper than writing them one by one. Like [`EncryptedFs::write`] it can write less than all the bytes,
+    /// the limit applies to their combined length, so it can stop in the middle of any of them.
+    #[instrument(skip(self, bufs), fields(len = %bufs.iter().map(|buf| buf.len()).sum::<usize>()), ret(level = Level::DEBUG))]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn write_vectored(
+        &self,
+        ino: u64,
+        offset: u64,
+        bufs: &[IoSlice<'_>],
+        handle: u64,
+    ) -> FsResult<usize> {
+        self.write_slices(ino, offset, bufs, handle).await
+    }
+
+    async fn write_slices(
+        &self,
+        ino: u64,
+        offset: u64,
+        bufs: &[IoSlice<'_>],
+        handle: u64,
+    ) -> FsResult<usize> {
+        let len = bufs.iter().map(|buf| buf.len() as u64).sum();
+        self.throttle.wait_write(handle, len).await;
+        let _op = self.throttle.start_op().await;
+        let timer = self.metrics.write.start();
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if !self.exists(ino).await {
+            return Err(FsError::InodeNotFound);
+        }
+        if !self.is_file(ino).await || self.get_attr(ino).await?.kind.is_special() {
+            return Err(FsError::InvalidInodeType);
+        }
+        self.check_handle_id(handle).await?;
+        {
+            if !self.write_handles.contains_key(&handle).await {
+                return Err(FsError::InvalidFileHandle);
+            }
+        }
+        {
+            let guard = self.write_handles.read(&handle).await;
+            let ctx = guard
+                .get(&handle)
+                .ok_or(FsError::InvalidFileHandle)?
+                .lock()
+                .await;
             if ctx.ino != ino {
                 return Err(FsError::InvalidFileHandle);
             }
         }
-        if buf.is_empty() {
+        let total_len = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+        if total_len == 0 {
             // no-op
             return Ok(0);
         }
+        check_file_size(self.cipher, offset, total_len as u64)?;
 
         let lock = self
             .read_write_locks
             .get_or_insert_with(ino, || RwLock::new(false));
         let write_guard = lock.write().await;
 
-        let guard = self.write_handles.read().await;
-        let mut ctx = guard.get(&handle).unwrap().lock().await;
+        let multiple_writers = self
+            .opened_files_for_write
+            .read(&ino)
+            .await
+            .get(&ino)
+            .is_some_and(|fhs| fhs.len() > 1);
+        let compressed = self.get_inode_from_cache_or_storage(ino).await?.compressed;
+        if compressed && offset != self.get_attr(ino).await?.size {
+            return Err(FsError::invalid_input(
+                "compressed files can only be written at the end",
+            ));
+        }
+        // the blocks the other writers wrote to are written before ours, and read again by their next write
+        let released = if multiple_writers {
+            self.release_other_writers_blocks(ino, handle).await?
+        } else {
+            vec![]
+        };
+
+        let guard = self.write_handles.read(&handle).await;
+        let mut ctx = guard
+            .get(&handle)
+            .ok_or(FsError::InvalidFileHandle)?
+            .lock()
+            .await;
 
+        // reserve what we could add, the writers of other files can't take it meanwhile
+        self.quota_grow(ino, ctx.attr.size, offset.saturating_add(total_len as u64))
+            .await?;
         // write new data
-        let (pos, len) = {
-            if offset > self.cipher.max_plaintext_len() as u64 {
-                return Err(FsError::MaxFilesizeExceeded(
-                    self.cipher.max_plaintext_len(),
-                ));
+        let size_before = ctx.attr.size;
+        let pos_before = self.handle_writer(&mut ctx).await?.stream_position()?;
+        // small writes following the buffered ones are kept with them, the others go after them
+        let buffering = !multiple_writers && total_len < self.write_buffer_size;
+        let buffer_end = ctx.buffer_offset + ctx.buffer.len() as u64;
+        let mut flushed_buffer = !ctx.buffer.is_empty()
+            && (!buffering
+                || offset != buffer_end
+                || ctx.buffer.len() + total_len > self.write_buffer_size);
+        if flushed_buffer {
+            ctx.write_buffer()?;
+        }
+        let (pos, len) = if buffering {
+            if ctx.buffer.is_empty() {
+                ctx.buffer_offset = offset;
             }
-            let writer = ctx.writer.as_mut().unwrap();
+            for buf in bufs {
+                ctx.buffer.extend_from_slice(buf);
+            }
+            (offset + total_len as u64, total_len)
+        } else {
+            let writer = self.handle_writer(&mut ctx).await?;
+            // the block we are leaving is written to disk on seek
             let pos = writer.seek(SeekFrom::Start(offset)).map_err(|err| {
                 error!(err = %err, "seeking");
                 err
             })?;
             if offset != pos {
-                // we could not seek to the desired position
-                return Ok(0);
+                // seeking past the end leaves a hole, so it can only stop before it on errors
+                error!(requested = offset, reached = pos, "seeking");
+                return Err(FsError::SeekFailed {
+                    requested: offset,
+                    reached: pos,
+                });
+            }
+            let mut len = 0;
+            for buf in bufs {
+                let buf_len = writer.write(buf).map_err(|err| {
+                    error!(err = %err, "writing");
+                    err
+                })?;
+                len += buf_len;
+                if buf_len < buf.len() {
+                    // reached the end of the block, the rest is for the next call
+                    break;
+                }
+            }
+            if len == 0 {
+                // the callers would retry it forever
+                return Err(io::Error::from(io::ErrorKind::WriteZero).into());
             }
-            // keep block size to max the cipher can handle
-            #[allow(clippy::cast_possible_truncation)]
-            let buf = if offset + buf.len() as u64 > self.cipher.max_plaintext_len() as u64 {
-                warn!("writing more than max block size, truncating");
-                &buf[..(self.cipher.max_plaintext_len() - offset as usize)]
-            } else {
-                buf
-            };
-            let len = writer.write(buf).map_err(|err| {
-                error!(err = %err, "writing");
-                err
-            })?;
             (writer.stream_position()?, len)
         };
+        if !ctx.buffer.is_empty() && ctx.buffer.len() >= self.write_buffer_size {
+            ctx.write_buffer()?;
+            flushed_buffer = true;
+        }
 
         // let size = ctx.attr.size;
         if pos > ctx.attr.size {
@@ -1676,10 +3608,48 @@ impl EncryptedFs {
         ctx.attr.mtime = now;
         ctx.attr.ctime = now;
         ctx.attr.atime = now;
+        ctx.attr_dirty = true;
+        if len > 0 {
+            let end = offset + len as u64;
+            ctx.written = Some(match ctx.written.take() {
+                Some(written) => written.start.min(offset)..written.end.max(end),
+                None => offset..end,
+            });
+        }
+        if multiple_writers && compressed {
+            // each writer adds a frame, ours ends here so the others add theirs after it
+            // if it fails the writer is created again by the next write
+            if let Some(mut writer) = ctx.writer.take() {
+                writer.finish()?;
+            }
+            ctx.writer = Some(self.create_contents_writer(ino).await?);
+        }
         drop(ctx);
+        drop(guard);
 
+        // blocks changed on disk, the previous one and the ones between the old EOF or `offset` and the new position
+        // the positions in a compressed file are not in the blocks, all its readers are reset, like when the buffer
+        // was written as it can be anywhere after the old EOF
+        // nothing changed on disk if we only added to the buffer, the readers are reset when it's written
+        // the blocks of the other writers were written before ours, the one before their position too if it's at the
+        // end of a block
+        let block_size = BLOCK_SIZE as u64;
+        let block_before = pos_before / block_size;
+        let first_block = offset.min(size_before) / block_size;
+        let last_block = pos / block_size;
+        let on_disk = !buffering || flushed_buffer;
+        self.reset_read_handles_in_blocks(ino, now, |block| {
+            (on_disk
+                && (compressed
+                    || flushed_buffer
+                    || block == block_before
+                    || (first_block..=last_block).contains(&block)))
+                || released
+                    .iter()
+                    .any(|released| block == *released || block + 1 == *released)
+        })
+        .await?;
         drop(write_guard);
-        self.reset_handles(ino, Some(handle), true).await?;
 
         self.sizes_write
             .lock()
@@ -1687,10 +3657,10 @@ impl EncryptedFs {
             .get_mut(&ino)
             .unwrap()
             .fetch_add(len as u64, Ordering::SeqCst);
-        if buf.len() != len {
+        if total_len != len {
             // error!(
             //     "size mismatch in write(), size {size} offset {offset} buf_len {} len {len}",
-            //     buf.len()
+            //     total_len
             // );
         }
         // warn!(
@@ -1703,10 +3673,35 @@ impl EncryptedFs {
         //         .load(Ordering::SeqCst)
         // );
 
+        timer.add_bytes(len as u64);
         Ok(len)
     }
 
+    /// Write the whole buffer starting at `offset`, calling [`EncryptedFs::write`] until all bytes are written,
+    /// then flush.
+    ///
+    /// Returns the number of bytes written, which is always `buf.len()` on success.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn write_all(&self, ino: u64, offset: u64, buf: &[u8], fh: u64) -> FsResult<usize> {
+        let mut pos = 0_usize;
+        while pos < buf.len() {
+            let len = self
+                .write(ino, offset + pos as u64, &buf[pos..], fh)
+                .await?;
+            if len == 0 {
+                return Err(FsError::other("Failed to write all bytes"));
+            }
+            pos += len;
+        }
+        self.flush(fh).await?;
+        Ok(pos)
+    }
+
     /// Flush the data to the underlying storage.
+    ///
+    /// If it was written since the last flush, the last incomplete block is written too and the new size and times
+    /// are written to the inode, so they are not lost if we crash before release. It's synced only with
+    /// [`DurabilityPolicy::Always`], the others sync it on release.
     #[allow(clippy::missing_panics_doc)]
     pub async fn flush(&self, handle: u64) -> FsResult<()> {
         if self.read_only {
@@ -1716,21 +3711,48 @@ impl EncryptedFs {
             // in the case of directory or if the file was crated without being opened we don't use a handle
             return Ok(());
         }
-        let lock = self.read_handles.read().await;
-        let mut valid_fh = lock.get(&handle).is_some();
-        let lock = self.write_handles.read().await;
-        if let Some(ctx) = lock.get(&handle) {
-            let mut ctx = ctx.lock().await;
+        let mut valid_fh = self.read_handles.contains_key(&handle).await;
+        let mut set_attr = None;
+        let ino = match self.write_handles.read(&handle).await.get(&handle) {
+            Some(ctx) => Some(ctx.lock().await.ino),
+            None => None,
+        };
+        if let Some(ino) = ino {
+            // the lock of the inode is taken before the handle, like in `write_slices`, which locks the other
+            // handles while holding it
             let lock = self
                 .read_write_locks
-                .get_or_insert_with(ctx.ino, || RwLock::new(false));
+                .get_or_insert_with(ino, || RwLock::new(false));
             let write_guard = lock.write().await;
-            ctx.writer.as_mut().expect("writer is missing").flush()?;
-            File::open(self.contents_path(ctx.ino))?.sync_all()?;
-            File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
-            drop(write_guard);
-            let ino = ctx.ino;
+            let guard = self.write_handles.read(&handle).await;
+            let mut ctx = guard
+                .get(&handle)
+                .ok_or(FsError::InvalidFileHandle)?
+                .lock()
+                .await;
+            if !ctx.buffer.is_empty() {
+                // finishing the writer could have failed after the writes were buffered
+                self.handle_writer(&mut ctx).await?;
+            }
+            ctx.write_buffer()?;
+            if ctx.attr_dirty {
+                // the size written to the inode must be readable, so finish the writer, then recreate it
+                // if it fails the writer is created again by the next write
+                if let Some(mut writer) = ctx.writer.take() {
+                    writer.finish()?;
+                    ctx.writer = Some(self.create_contents_writer(ctx.ino).await?);
+                }
+                ctx.attr_dirty = false;
+                set_attr = Some((ctx.ino, ctx.attr.clone()));
+            } else if let Some(writer) = ctx.writer.as_mut() {
+                writer.flush()?;
+            }
+            if self.durability() == DurabilityPolicy::Always {
+                self.open_contents(ctx.ino).await?.sync_all()?;
+            }
             drop(ctx);
+            drop(guard);
+            drop(write_guard);
             self.reset_handles(ino, Some(handle), true).await?;
             valid_fh = true;
         }
@@ -1738,102 +3760,330 @@ impl EncryptedFs {
         if !valid_fh {
             return Err(FsError::InvalidFileHandle);
         }
+        if let Some((ino, attr)) = set_attr {
+            let size = attr.size;
+            self.save_handle_attr(ino, attr.into(), Some(size)).await?;
+        }
 
         Ok(())
     }
 
+    /// Statistics of the filesystem, like `statfs(2)`.
+    ///
+    /// Space is from [`Storage::statvfs`], or from [`FsOptions::max_size_bytes`] if set. The count of used inodes is
+    /// cached for a few seconds.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn statfs(&self) -> FsResult<FsStats> {
+        if !self.read_only {
+            self.flush_dir_times().await?;
+        }
+        let used_inodes = *self.inodes_count.get().await?;
+        let stat = self.storage.statvfs().await?;
+        let to_plaintext = |blocks: u64| {
+            self.cipher
+                .plaintext_len(blocks.saturating_mul(stat.block_size))
+        };
+        let mut total_bytes = to_plaintext(stat.blocks);
+        let mut free_bytes = to_plaintext(stat.blocks_free);
+        let mut available_bytes = to_plaintext(stat.blocks_available);
+        if let Some((max, used)) = self.size_quota().await {
+            // the quota is the size, unless there is less space left than it allows
+            let left = max.saturating_sub(used);
+            total_bytes = max;
+            free_bytes = free_bytes.min(left);
+            available_bytes = available_bytes.min(left);
+        }
+        Ok(FsStats {
+            total_bytes,
+            free_bytes,
+            available_bytes,
+            used_inodes,
+            free_inodes: stat.files_free,
+            block_size: u32::try_from(stat.block_size).unwrap_or(u32::MAX),
+            max_name_len: u32::try_from(self.max_name_len()).unwrap_or(u32::MAX),
+            generation: self.current_generation().await,
+        })
+    }
+
+    /// Find the next data or hole at or after `offset`, like `lseek(2)` with `SEEK_DATA` or `SEEK_HOLE`.
+    ///
+    /// The holes are the blocks left as holes by [`crypto::create_sparse_write_seek`] and kept in the inode, and there
+    /// is always one at the end of the file. What was written with the open write handles is written to the storage
+    /// first, so it's seen as data, and the size includes it. Compressed files have no holes besides the one at the
+    /// end.
+    /// If `offset` is at or past the end of the file, or there is no data after it for [`SeekWhence::Data`], it
+    /// returns [`FsError::OffsetPastEof`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn lseek(
+        &self,
+        ino: u64,
+        handle: u64,
+        offset: u64,
+        whence: SeekWhence,
+    ) -> FsResult<u64> {
+        if !self.exists(ino).await {
+            return Err(FsError::InodeNotFound);
+        }
+        if !self.is_file(ino).await {
+            return Err(FsError::InvalidInodeType);
+        }
+        if handle != 0 && self.handle_ino(handle).await != Some(ino) {
+            return Err(FsError::InvalidFileHandle);
+        }
+        let size = self.get_attr(ino).await?.size;
+        if offset >= size {
+            return Err(FsError::OffsetPastEof);
+        }
+        if self.get_inode_from_cache_or_storage(ino).await?.compressed {
+            return match whence {
+                SeekWhence::Data => Ok(offset),
+                SeekWhence::Hole => Ok(size),
+            };
+        }
+        let has_writers = self
+            .opened_files_for_write
+            .read(&ino)
+            .await
+            .get(&ino)
+            .is_some_and(|fhs| !fhs.is_empty());
+        if has_writers {
+            // the blocks the writers have in memory would look like holes
+            self.reset_handles(ino, None, true).await?;
+        }
+        let block_size = BLOCK_SIZE as u64;
+        let holes = self.contents_holes(ino).await?.lock().unwrap().clone();
+        let mut contents = self.open_contents(ino).await?;
+        for block in offset / block_size..size.div_ceil(block_size) {
+            if self.is_hole(&mut contents, &holes, block)? == (whence == SeekWhence::Hole) {
+                return Ok((block * block_size).max(offset));
+            }
+        }
+        match whence {
+            SeekWhence::Data => Err(FsError::OffsetPastEof),
+            SeekWhence::Hole => Ok(size),
+        }
+    }
+
+    /// If the block `block` of the contents is a hole, in `holes` and all zeros, or past the end of what is stored.
+    fn is_hole(&self, contents: &mut SegmentedFile, holes: &Holes, block: u64) -> io::Result<bool> {
+        let block_len = self.cipher.ciphertext_block_size();
+        contents.seek(SeekFrom::Start(block * block_len))?;
+        let mut buf = vec![0; usize::try_from(block_len).map_err(io::Error::other)?];
+        let mut len = 0;
+        while len < buf.len() {
+            let read = contents.read(&mut buf[len..])?;
+            if read == 0 {
+                break;
+            }
+            len += read;
+        }
+        Ok(len == 0 || (holes.contains(block) && buf[..len].iter().all(|b| *b == 0)))
+    }
+
+    /// The inode opened with `handle`, if it's open.
+    async fn handle_ino(&self, handle: u64) -> Option<u64> {
+        if let Some(ctx) = self.read_handles.read(&handle).await.get(&handle) {
+            Some(ctx.lock().await.ino)
+        } else if let Some(ctx) = self.write_handles.read(&handle).await.get(&handle) {
+            Some(ctx.lock().await.ino)
+        } else {
+            None
+        }
+    }
+
+    /// Make sure the data written with this handle is persisted to disk, like `fsync(2)`.
+    ///
+    /// Unlike [`EncryptedFs::flush`] this also writes the last incomplete block and syncs the underlying file.
+    /// If `datasync` is `true` only the contents are synced, like `fdatasync(2)`, else the inode file is synced too.
+    /// The changes left for later by [`DurabilityPolicy::OnRelease`] are synced too, it syncs with any policy.
+    /// For read handles it's a no-op.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn fsync(&self, handle: u64, datasync: bool) -> FsResult<()> {
+        if handle == 0 {
+            // in the case of directory or if the file was crated without being opened we don't use a handle
+            return Ok(());
+        }
+        let is_read_handle = self.read_handles.contains_key(&handle).await;
+        let ino = match self.write_handles.read(&handle).await.get(&handle) {
+            Some(ctx) => ctx.lock().await.ino,
+            None if is_read_handle => return Ok(()),
+            None => return Err(FsError::InvalidFileHandle),
+        };
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let write_guard = lock.write().await;
+        let guard = self.write_handles.read(&handle).await;
+        let mut ctx = guard
+            .get(&handle)
+            .ok_or(FsError::InvalidFileHandle)?
+            .lock()
+            .await;
+        // finish the writer so the last block is written also, then recreate it
+        // if suspended by `lock` it was already written
+        if !ctx.buffer.is_empty() {
+            self.handle_writer(&mut ctx).await?;
+        }
+        ctx.write_buffer()?;
+        if let Some(mut writer) = ctx.writer.take() {
+            let mut file = writer.finish()?;
+            if datasync {
+                file.sync_data()?;
+            } else {
+                file.sync_all()?;
+            }
+            ctx.writer = Some(self.create_contents_writer(ino).await?);
+        }
+        let attr = ctx.attr.clone();
+        ctx.attr_dirty = false;
+        drop(ctx);
+        drop(guard);
+        // size is needed to read the data back, so we persist it even on datasync
+        self.save_handle_attr(ino, attr.clone().into(), Some(attr.size))
+            .await?;
+        if !datasync {
+            self.storage.sync(&self.ino_file(ino)).await?;
+        }
+        drop(write_guard);
+        self.reset_handles(ino, Some(handle), true).await?;
+
+        self.flush_dir_times().await?;
+        self.sync_pending().await
+    }
+
     /// Helpful when we want to copy just some portions of the file.
+    ///
+    /// It copies until `size` bytes are copied or the end of the source file is reached, in chunks of
+    /// [`COPY_FILE_RANGE_CHUNK_SIZE`] so we don't keep the whole range in memory. Returns the number of bytes copied.
+    #[allow(clippy::missing_errors_doc)]
     pub async fn copy_file_range(
         &self,
         file_range_req: &CopyFileRangeReq,
         size: usize,
+    ) -> FsResult<usize> {
+        self.copy_file_range_with(file_range_req, size, &OpControl::default())
+            .await
+    }
+
+    /// Like [`EncryptedFs::copy_file_range`], with the progress in bytes copied after each chunk.
+    ///
+    /// If `control` is cancelled it stops after the chunk being copied, what was copied until then is kept.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn copy_file_range_with(
+        &self,
+        file_range_req: &CopyFileRangeReq,
+        size: usize,
+        control: &OpControl,
+    ) -> FsResult<usize> {
+        self.copy_file_range_chunked(file_range_req, size, COPY_FILE_RANGE_CHUNK_SIZE, control)
+            .await
+    }
+
+    async fn copy_file_range_chunked(
+        &self,
+        file_range_req: &CopyFileRangeReq,
+        size: usize,
+        chunk_size: usize,
+        control: &OpControl,
     ) -> FsResult<usize> {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
-        if self.is_dir(file_range_req.src_ino) || self.is_dir(file_range_req.dest_ino) {
+        if self.is_dir(file_range_req.src_ino).await || self.is_dir(file_range_req.dest_ino).await {
             return Err(FsError::InvalidInodeType);
         }
-
-        let mut buf = vec![0; size];
-        let len = self
-            .read(
-                file_range_req.src_ino,
-                file_range_req.src_offset,
-                &mut buf,
-                file_range_req.src_fh,
-            )
-            .await?;
-        if len == 0 {
-            return Ok(0);
+        if !self.exists(file_range_req.src_ino).await || !self.exists(file_range_req.dest_ino).await
+        {
+            return Err(FsError::InodeNotFound);
         }
+
+        let mut buf = vec![0; size.min(chunk_size)];
         let mut copied = 0;
         while copied < size {
-            let len = self
-                .write(
-                    file_range_req.dest_ino,
-                    file_range_req.dest_offset,
-                    &buf[copied..len],
-                    file_range_req.dest_fh,
+            control.check()?;
+            let len = (size - copied).min(buf.len());
+            let read = self
+                .read(
+                    file_range_req.src_ino,
+                    file_range_req.src_offset + copied as u64,
+                    &mut buf[..len],
+                    file_range_req.src_fh,
                 )
                 .await?;
-            if len == 0 && copied < size {
-                error!(len, "Failed to copy all read bytes");
-                return Err(FsError::Other("Failed to copy all read bytes"));
+            if read == 0 {
+                // end of source file
+                break;
+            }
+            let mut written = 0;
+            while written < read {
+                let len = match self
+                    .write(
+                        file_range_req.dest_ino,
+                        file_range_req.dest_offset + (copied + written) as u64,
+                        &buf[written..read],
+                        file_range_req.dest_fh,
+                    )
+                    .await
+                {
+                    // like `write(2)`, what was copied before running out of quota is returned
+                    Err(FsError::QuotaExceeded) if copied + written > 0 => {
+                        return Ok(copied + written)
+                    }
+                    res => res?,
+                };
+                if len == 0 {
+                    error!(len, "Failed to copy all read bytes");
+                    return Err(FsError::other("Failed to copy all read bytes"));
+                }
+                written += len;
             }
-            copied += len;
+            copied += read;
+            control.progress(copied as u64, size as u64);
         }
-        Ok(len)
+        Ok(copied)
     }
 
-    /// Open a file. We can open multiple times for read but only one to write at a time.
+    /// Open a file. We can open multiple times for read and write, with more writers each write goes to disk right away
+    /// so the others see it.
     #[allow(clippy::missing_panics_doc)]
     pub async fn open(&self, ino: u64, read: bool, write: bool) -> FsResult<u64> {
         if write && self.read_only {
             return Err(FsError::ReadOnly);
         }
         if !read && !write {
-            return Err(FsError::InvalidInput(
+            return Err(FsError::invalid_input(
                 "read and write cannot be false at the same time",
             ));
         }
-        if self.is_dir(ino) {
+        if self.is_dir(ino).await
+            || self
+                .get_inode_from_cache_or_storage(ino)
+                .await
+                .is_ok_and(|attr| attr.kind.is_special())
+        {
             return Err(FsError::InvalidInodeType);
         }
 
-        let mut handle: Option<u64> = None;
+        let fh = self.next_handle();
         if read {
-            handle = Some(self.next_handle());
-            self.do_with_read_handle(
-                *handle.as_ref().unwrap(),
-                ReadHandleContextOperation::Create { ino },
-            )
-            .await?;
+            self.do_with_read_handle(fh, ReadHandleContextOperation::Create { ino })
+                .await?;
         }
         if write {
-            if self.opened_files_for_write.read().await.contains_key(&ino) {
-                return Err(FsError::AlreadyOpenForWrite);
-            }
-            if handle.is_none() {
-                handle = Some(self.next_handle());
-            }
             let res = self
-                .do_with_write_handle(
-                    *handle.as_ref().expect("handle is missing"),
-                    WriteHandleContextOperation::Create { ino },
-                )
+                .do_with_write_handle(fh, WriteHandleContextOperation::Create { ino })
                 .await;
             if res.is_err() && read {
-                // on error remove the read handle if it was added above,
-                // remove the read handle if it was added above
-                self.read_handles
-                    .write()
-                    .await
-                    .remove(handle.as_ref().unwrap());
+                // on error remove the read handle if it was added above
+                self.remove_read_handle(ino, fh).await;
             }
             res?;
         }
-        let fh = handle.unwrap();
         self.sizes_write
             .lock()
             .await
@@ -1852,16 +4102,119 @@ impl EncryptedFs {
         Ok(fh)
     }
 
+    /// Like [`EncryptedFs::open`], but only if `id` is still the file with that inode number, else it fails with
+    /// [`FsError::StaleFileId`]. The reads and writes with the handle fail the same way if the file is removed and
+    /// its number is given to a new one meanwhile.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn open_id(&self, id: FileId, read: bool, write: bool) -> FsResult<u64> {
+        self.get_attr_id(id).await?;
+        let fh = self.open(id.ino, read, write).await?;
+        self.handle_ids.lock().unwrap().insert(fh, id);
+        Ok(fh)
+    }
+
+    /// Like [`EncryptedFs::get_attr`], but only if `id` is still the file with that inode number, else it fails with
+    /// [`FsError::StaleFileId`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn get_attr_id(&self, id: FileId) -> FsResult<FileAttr> {
+        match self.get_attr(id.ino).await {
+            Ok(attr) if attr.ino_generation == id.generation => Ok(attr),
+            Ok(_) | Err(FsError::InodeNotFound) => Err(FsError::StaleFileId),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Fail with [`FsError::StaleFileId`] if `handle` was opened with [`EncryptedFs::open_id`] and its file is gone.
+    async fn check_handle_id(&self, handle: u64) -> FsResult<()> {
+        let id = self.handle_ids.lock().unwrap().get(&handle).copied();
+        if let Some(id) = id {
+            self.get_attr_id(id).await?;
+        }
+        Ok(())
+    }
+
+    /// Create a new node like [`EncryptedFs::create`], unless [`CreateFlags::exclusive`] is set an existing file with
+    /// the same name is opened instead, see [`EncryptedFs::open_with_flags`].
+    ///
+    /// Returns the handle and the attributes, the handle is `0` if neither `read` nor `write` is set.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn create_with_flags(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        create_attr: CreateFileAttr,
+        flags: CreateFlags,
+    ) -> FsResult<(u64, FileAttr)> {
+        if flags.truncate && !flags.write {
+            return Err(FsError::invalid_input("truncate needs write"));
+        }
+        let kind = create_attr.kind;
+        match self
+            .create(parent, name, create_attr, flags.read, flags.write)
+            .await
+        {
+            Err(FsError::AlreadyExists) if !flags.exclusive => {
+                let attr = self
+                    .find_by_name(parent, name)
+                    .await?
+                    .ok_or_else(|| FsError::not_found("removed in the meantime"))?;
+                if attr.kind != kind {
+                    return Err(FsError::AlreadyExists);
+                }
+                if !flags.read && !flags.write {
+                    return Ok((0, attr));
+                }
+                let fh = self.open_with_flags(attr.ino, flags.into()).await?;
+                Ok((fh, self.get_attr(attr.ino).await?))
+            }
+            res => res,
+        }
+    }
+
+    /// Open a file like [`EncryptedFs::open`], with [`OpenFlags::truncate`] it's also truncated to zero length.
+    ///
+    /// Truncating resets the other opened handles like [`EncryptedFs::set_len`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn open_with_flags(&self, ino: u64, flags: OpenFlags) -> FsResult<u64> {
+        if flags.truncate && !flags.write {
+            return Err(FsError::invalid_input("truncate needs write"));
+        }
+        // open first, so we don't truncate if it cannot be opened
+        let fh = self.open(ino, flags.read, flags.write).await?;
+        if flags.truncate {
+            if let Err(err) = self.set_len(ino, 0).await {
+                self.release(fh).await?;
+                return Err(err);
+            }
+        }
+        Ok(fh)
+    }
+
     /// Truncates or extends the underlying file, updating the size of this file to become size.
+    ///
+    /// Extending leaves a hole which reads as zeros, only the block the file ended in is written again. Truncating
+    /// writes the contents kept to a new file, as does extending a compressed file. It can't be extended past the
+    /// largest size of a file, see [`FsError::MaxFilesizeExceeded`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn set_len(&self, ino: u64, size: u64) -> FsResult<()> {
+        self.set_len_with(ino, size, &OpControl::default()).await
+    }
+
+    /// Like [`EncryptedFs::set_len`], with the progress of writing the contents kept to a new file, in bytes.
+    ///
+    /// If `control` is cancelled while they are written, the new file is dropped and the file is left as it was.
     #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
     #[allow(clippy::too_many_lines)]
-    pub async fn set_len(&self, ino: u64, size: u64) -> FsResult<()> {
+    pub async fn set_len_with(&self, ino: u64, size: u64, control: &OpControl) -> FsResult<()> {
+        control.check()?;
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
+        check_file_size(self.cipher, size, 0)?;
         info!("truncate {ino} to {size}");
         let attr = self.get_attr(ino).await?;
-        if matches!(attr.kind, FileType::Directory) {
+        if attr.kind != FileType::RegularFile {
             return Err(FsError::InvalidInodeType);
         }
 
@@ -1877,24 +4230,44 @@ impl EncryptedFs {
 
         // flush writers
         self.flush_and_reset_writers(ino).await?;
+        if size > attr.size {
+            self.quota_resize(ino, attr.size, size).await?;
+        }
 
         let file_path = self.contents_path(ino);
+        // kept until the inode is written with them below
+        let holes = self.contents_holes(ino).await?;
         if size == 0 {
             debug!("truncate to zero");
+            if self.secure_delete {
+                self.shred_contents(&file_path, 0).await?;
+            }
+            // it's truncated in place
+            self.unshare_contents(&file_path).await?;
             // truncate to zero
-            let file = File::create(&file_path)?;
-            file.set_len(0)?;
-            file.sync_all()?;
+            SegmentedFile::create(&self.storage, &file_path, self.segment_size())
+                .await?
+                .sync_all()?;
+            holes.lock().unwrap().clear();
+        } else if size > attr.size && !attr.compressed {
+            debug!("extend size to {}", size.to_formatted_string(&Locale::en));
+            let mut writer = self.create_write_seek_with_key(
+                self.open_contents_rw(ino).await?,
+                &*self.inode_key(ino).await?,
+                holes.clone(),
+            );
+            writer.seek(SeekFrom::Start(size))?;
+            writer.finish()?.sync_all()?;
         } else {
             debug!("truncate size to {}", size.to_formatted_string(&Locale::en));
 
-            let mut file = fs_util::open_atomic_write(&file_path)?;
-            {
+            // write the new content next to the current one and move it over when done
+            let tmp_path = file_path.with_extension("truncate");
+            let file = SegmentedFile::create(&self.storage, &tmp_path, self.segment_size()).await?;
+            let tmp_holes = SharedHoles::default();
+            let written: FsResult<()> = async {
                 // have a new scope, so we drop the reader before moving new content files
-                let mut reader = self.create_read(File::open(file_path.as_path())?).await?;
-
-                let mut writer = self.create_write(file).await?;
-
+                let key = self.inode_key(ino).await?;
                 let len = if size > attr.size {
                     // increase size, copy existing data until existing size
                     attr.size
@@ -1902,16 +4275,56 @@ impl EncryptedFs {
                     // decrease size, copy existing data until new size
                     size
                 };
-                stream_util::copy_exact(&mut reader, &mut writer, len)?;
-                if size > attr.size {
+                let reader = ControlledRead::new(
+                    self.create_read_with_key(self.open_contents(ino).await?, &key, holes.clone()),
+                    control,
+                    len,
+                );
+
+                let writer = self.create_write_with_key(file, &key, tmp_holes.clone());
+
+                let zeros = size.saturating_sub(attr.size);
+                let mut file = if attr.compressed {
+                    // compressed again in a single frame
+                    let mut reader = zstd::Decoder::new(reader)?;
+                    let mut writer = zstd::Encoder::new(writer, self.zstd_level())?;
+                    stream_util::copy_exact(&mut reader, &mut writer, len)?;
+                    stream_util::fill_zeros(&mut writer, zeros)?;
+                    writer.finish()?.finish()?
+                } else {
+                    let (mut reader, mut writer) = (reader, writer);
+                    stream_util::copy_exact(&mut reader, &mut writer, len)?;
                     // increase size, seek to new size will write zeros
-                    stream_util::fill_zeros(&mut writer, size - attr.size)?;
+                    stream_util::fill_zeros(&mut writer, zeros)?;
+                    writer.finish()?
+                };
+                file.sync_all()?;
+                control.check()
+            }
+            .await;
+            if let Err(err) = written {
+                // the current contents were not changed
+                SegmentedFile::remove(&self.storage, &tmp_path).await?;
+                if size > attr.size {
+                    self.quota_resize(ino, size, attr.size).await?;
                 }
-                file = writer.finish()?;
+                return Err(control.cancelled_or(err));
+            }
+            if self.secure_delete && size < attr.size {
+                // from the block the new end is in, the blocks before have the same plaintext as the new ones
+                // a compressed file has other plaintext from the start
+                let offset = if attr.compressed {
+                    0
+                } else {
+                    size / BLOCK_SIZE as u64 * self.cipher.ciphertext_block_size()
+                };
+                self.shred_contents(&file_path, offset).await?;
             }
-            file.commit()?;
+            SegmentedFile::rename(&self.storage, &tmp_path, &file_path).await?;
+            self.storage.sync(file_path.parent().unwrap()).await?;
+            let tmp_holes = tmp_holes.lock().unwrap().clone();
+            holes.lock().unwrap().replace(tmp_holes);
         }
-        File::open(file_path.parent().unwrap())?.sync_all()?;
 
         let now = SystemTime::now();
         let set_attr = SetFileAttr::default()
@@ -1920,6 +4333,10 @@ impl EncryptedFs {
             .with_ctime(now)
             .with_atime(now);
         self.set_attr2(ino, set_attr, true).await?;
+        drop(holes);
+        if size < attr.size {
+            self.quota_resize(ino, attr.size, size).await?;
+        }
 
         let attr = self.get_inode_from_storage(ino).await?;
         println!("attr 1: {:?}", attr.size);
@@ -1937,7 +4354,31 @@ impl EncryptedFs {
         if size != attr.size {
             error!("error truncating file expected {size} actual {}", attr.size);
         }
+        self.events.send(FsEvent::AttrChanged { ino });
+
+        Ok(())
+    }
 
+    /// Make sure there is space for `len` bytes from `offset` in the file `ino`, like `fallocate(2)` without flags.
+    ///
+    /// If they go past the end of the file it's extended with [`EncryptedFs::set_len`], the contents before the end are
+    /// always written. Fails with [`FsError::QuotaExceeded`] if it's over a quota. The extension is a hole, the quota
+    /// counts it but the storage may not keep space for it.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn fallocate(&self, ino: u64, offset: u64, len: u64) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let attr = self.get_attr(ino).await?;
+        if matches!(attr.kind, FileType::Directory) {
+            return Err(FsError::InvalidInodeType);
+        }
+        let end = offset
+            .checked_add(len)
+            .ok_or_else(|| FsError::invalid_input("offset and length are too large"))?;
+        if end > attr.size {
+            self.set_len(ino, end).await?;
+        }
         Ok(())
     }
 
@@ -1951,38 +4392,41 @@ impl EncryptedFs {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
-        let opened_files_for_write_guard = self.opened_files_for_write.read().await;
-        let handle = opened_files_for_write_guard.get(&ino);
-        if let Some(handle) = handle {
-            let write_handles_guard = self.write_handles.write().await;
-            let ctx = write_handles_guard.get(handle);
+        let fhs = self
+            .opened_files_for_write
+            .read(&ino)
+            .await
+            .get(&ino)
+            .cloned()
+            .unwrap_or_default();
+        for handle in fhs {
+            let write_handles_guard = self.write_handles.write(&handle).await;
+            let ctx = write_handles_guard.get(&handle);
             if let Some(lock) = ctx {
                 let mut ctx = lock.lock().await;
 
-                let mut writer = ctx.writer.take().unwrap();
-                let file = writer.finish()?;
-                file.sync_all()?;
-                File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
-                let handle = *handle;
-                let set_attr: SetFileAttr = ctx.attr.clone().into();
+                ctx.write_buffer()?;
+                if let Some(mut writer) = ctx.writer.take() {
+                    let mut file = writer.finish()?;
+                    file.sync_all()?;
+                }
+                let attr = ctx.attr_dirty.then(|| ctx.attr.clone());
                 drop(ctx);
-                drop(opened_files_for_write_guard);
                 drop(write_handles_guard);
-                self.set_attr(ino, set_attr).await?;
+                if let Some(attr) = attr {
+                    let size = attr.size;
+                    self.save_handle_attr(ino, attr.into(), Some(size)).await?;
+                }
                 self.reset_handles(ino, Some(handle), true).await?;
-                let write_handles_guard = self.write_handles.write().await;
-                let mut ctx = write_handles_guard.get(&handle).unwrap().lock().await;
-                let writer = self
-                    .create_write_seek(
-                        OpenOptions::new()
-                            .read(true)
-                            .write(true)
-                            .open(self.contents_path(ino))?,
-                    )
-                    .await?;
-                ctx.writer = Some(Box::new(writer));
+                let write_handles_guard = self.write_handles.write(&handle).await;
+                let Some(ctx) = write_handles_guard.get(&handle) else {
+                    continue;
+                };
+                let mut ctx = ctx.lock().await;
+                ctx.writer = Some(self.create_contents_writer(ino).await?);
                 let attr = self.get_inode_from_storage(ino).await?;
                 ctx.attr = attr.into();
+                ctx.attr_dirty = false;
             }
         }
         Ok(())
@@ -1999,20 +4443,20 @@ impl EncryptedFs {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
-        if !self.exists(parent) {
+        if !self.exists(parent).await {
             return Err(FsError::InodeNotFound);
         }
-        if !self.is_dir(parent) {
+        if !self.is_dir(parent).await {
             return Err(FsError::InvalidInodeType);
         }
-        if !self.exists(new_parent) {
+        if !self.exists(new_parent).await {
             return Err(FsError::InodeNotFound);
         }
-        if !self.is_dir(new_parent) {
+        if !self.is_dir(new_parent).await {
             return Err(FsError::InvalidInodeType);
         }
-        if !self.exists_by_name(parent, name)? {
-            return Err(FsError::NotFound("name not found"));
+        if !self.exists_by_name(parent, name).await? {
+            return Err(self.name_not_found(parent, name).await);
         }
         self.validate_filename(new_name)?;
 
@@ -2021,64 +4465,91 @@ impl EncryptedFs {
             return Ok(());
         }
 
-        // Only overwrite an existing directory if it's empty
-        if let Ok(Some(new_attr)) = self.find_by_name(new_parent, new_name).await {
-            if new_attr.kind == FileType::Directory && self.len(new_attr.ino)? > 0 {
-                return Err(FsError::NotEmpty);
+        let Some(attr) = self.find_by_name(parent, name).await? else {
+            return Err(self.name_not_found(parent, name).await);
+        };
+
+        // the entry replaced at the destination
+        let mut replaced_file = None;
+        let replaced = match self.find_by_name(new_parent, new_name).await? {
+            Some(new_attr) => {
+                if new_attr.ino == attr.ino {
+                    // both names are hard links to the same inode, POSIX says this is a no-op
+                    return Ok(());
+                }
+                // a directory only replaces a directory and a file only a file
+                if (attr.kind == FileType::Directory) != (new_attr.kind == FileType::Directory) {
+                    return Err(FsError::InvalidInodeType);
+                }
+                // Only overwrite an existing directory if it's empty
+                if new_attr.kind == FileType::Directory && self.len(new_attr.ino).await? > 0 {
+                    return Err(FsError::NotEmpty);
+                }
+                if new_attr.kind == FileType::RegularFile {
+                    replaced_file = Some((new_attr.ino, new_attr.size));
+                }
+                Some(ReplacedEntry {
+                    ino: new_attr.ino,
+                    kind: new_attr.kind,
+                    encrypted_name: self.entry_encrypted_name(new_parent, new_name).await?,
+                    nlink: new_attr.nlink,
+                })
             }
-        }
+            None => None,
+        };
+        let op = JournalOp::Rename {
+            parent,
+            name: name.expose_secret().to_string(),
+            new_parent,
+            new_name: new_name.expose_secret().to_string(),
+            ino: attr.ino,
+            kind: attr.kind,
+            replaced,
+        };
 
-        let attr = self
-            .find_by_name(parent, name)
-            .await?
-            .ok_or(FsError::NotFound("name not found"))?;
-        // remove from parent contents
-        self.remove_directory_entry(parent, name).await?;
-        // remove from new_parent contents, if exists
-        if self.exists_by_name(new_parent, new_name)? {
-            self.remove_directory_entry(new_parent, new_name).await?;
-        }
-        // add to new parent contents
-        self.insert_directory_entry(
+        let event = FsEvent::Renamed {
+            old_parent: parent,
+            old_name: name.clone(),
             new_parent,
-            &DirectoryEntry {
-                ino: attr.ino,
-                name: new_name.clone(),
-                kind: attr.kind,
-            },
-        )
-        .await?;
+            new_name: new_name.clone(),
+            ino: attr.ino,
+        };
 
-        if attr.kind == FileType::Directory {
-            // add the parent link to the new directory
-            self.insert_directory_entry(
-                attr.ino,
-                &DirectoryEntry {
-                    ino: new_parent,
-                    name: SecretBox::new(Box::new("$..".to_owned())),
-                    kind: FileType::Directory,
-                },
-            )
-            .await?;
+        // a file moved to another directory counts in its quota from now on
+        let moved_file = attr.kind == FileType::RegularFile && parent != new_parent;
+        if moved_file {
+            self.quota_add_entry(new_parent, attr.ino, attr.size)
+                .await?;
         }
+        // and so do the files under a directory
+        let moved_dir = if attr.kind == FileType::Directory && parent != new_parent {
+            Some(self.quota_move_dir(attr.ino, parent, new_parent).await?)
+        } else {
+            None
+        };
 
-        let now = SystemTime::now();
-        let set_attr = SetFileAttr::default()
-            .with_mtime(now)
-            .with_ctime(now)
-            .with_atime(now);
-        self.set_attr(parent, set_attr).await?;
-
-        let set_attr = SetFileAttr::default()
-            .with_mtime(now)
-            .with_ctime(now)
-            .with_atime(now);
-        self.set_attr(new_parent, set_attr).await?;
-
-        let set_attr = SetFileAttr::default().with_ctime(now).with_atime(now);
-        self.set_attr(attr.ino, set_attr).await?;
-
-        Ok(())
+        // spawn so it completes even if the caller is dropped
+        let self_clone = self.self_arc()?;
+        self.runtime
+            .spawn(async move {
+                let res = self_clone.run_journaled(op).await;
+                if moved_file {
+                    let dir = if res.is_ok() { parent } else { new_parent };
+                    self_clone
+                        .quota_remove_entry(dir, attr.ino, attr.size)
+                        .await;
+                }
+                if let Some(moved) = moved_dir {
+                    self_clone.quota_moved_dir(moved, res.is_ok()).await;
+                }
+                res?;
+                if let Some((ino, size)) = replaced_file {
+                    self_clone.quota_remove_entry(new_parent, ino, size).await;
+                }
+                self_clone.events.send(event);
+                Ok(())
+            })
+            .await?
     }
 
     /// Create a crypto writer using internal encryption info.
@@ -2086,11 +4557,23 @@ impl EncryptedFs {
         &self,
         file: W,
     ) -> FsResult<impl CryptoWrite<W>> {
-        Ok(crypto::create_write(
-            file,
-            self.cipher,
-            &*self.key.get().await?,
-        ))
+        Ok(
+            crypto::create_ring_write(file, self.cipher, &*self.key.get().await?, &[])
+                .parallel(self.crypto_pool.clone()),
+        )
+    }
+
+    /// Writer of the contents, the blocks of zeros are written as holes kept in `holes`, see
+    /// [`crypto::create_sparse_write`].
+    fn create_write_with_key<W: CryptoInnerWriter + Seek + Send + Sync + 'static>(
+        &self,
+        file: W,
+        key: &SecretVec<u8>,
+        holes: SharedHoles,
+    ) -> impl CryptoWrite<W> {
+        crypto::create_ring_write(file, self.cipher, key, &[])
+            .sparse(holes)
+            .parallel(self.crypto_pool.clone())
     }
 
     /// Create a crypto writer with seek using internal encryption info.
@@ -2098,11 +4581,23 @@ impl EncryptedFs {
         &self,
         file: W,
     ) -> FsResult<impl CryptoWriteSeek<W>> {
-        Ok(crypto::create_write_seek(
-            file,
-            self.cipher,
-            &*self.key.get().await?,
-        ))
+        Ok(
+            crypto::create_ring_write_seek(file, self.cipher, &*self.key.get().await?, &[])
+                .parallel(self.crypto_pool.clone()),
+        )
+    }
+
+    /// Writer with seek of the contents, seeking past the end and the blocks of zeros leave holes kept in `holes`,
+    /// see [`crypto::create_sparse_write_seek`].
+    fn create_write_seek_with_key<W: Write + Seek + Read + Send + Sync + 'static>(
+        &self,
+        file: W,
+        key: &SecretVec<u8>,
+        holes: SharedHoles,
+    ) -> impl CryptoWriteSeek<W> {
+        crypto::create_ring_write_seek(file, self.cipher, key, &[])
+            .sparse(holes)
+            .parallel(self.crypto_pool.clone())
     }
 
     /// Create a crypto reader using internal encryption info.
@@ -2110,11 +4605,22 @@ impl EncryptedFs {
         &self,
         reader: R,
     ) -> FsResult<impl CryptoRead<R>> {
-        Ok(crypto::create_read(
-            reader,
-            self.cipher,
-            &*self.key.get().await?,
-        ))
+        Ok(
+            crypto::create_ring_read(reader, self.cipher, &*self.key.get().await?, &[])
+                .parallel(self.crypto_pool.clone()),
+        )
+    }
+
+    /// Reader of the contents, the blocks in `holes` read as zeros.
+    fn create_read_with_key<R: Read + Send + Sync>(
+        &self,
+        reader: R,
+        key: &SecretVec<u8>,
+        holes: SharedHoles,
+    ) -> impl CryptoRead<R> {
+        crypto::create_ring_read(reader, self.cipher, key, &[])
+            .sparse(holes)
+            .parallel(self.crypto_pool.clone())
     }
 
     /// Create a crypto reader with seek using internal encryption info.
@@ -2122,42 +4628,392 @@ impl EncryptedFs {
         &self,
         reader: R,
     ) -> FsResult<impl CryptoReadSeek<R>> {
-        Ok(crypto::create_read_seek(
-            reader,
-            self.cipher,
-            &*self.key.get().await?,
-        ))
+        Ok(
+            crypto::create_ring_read_seek(reader, self.cipher, &*self.key.get().await?, &[])
+                .parallel(self.crypto_pool.clone()),
+        )
+    }
+
+    /// Reader with seek of the contents, the blocks in `holes` read as zeros.
+    fn create_read_seek_with_key<R: Read + Seek + Send + Sync>(
+        &self,
+        reader: R,
+        key: &SecretVec<u8>,
+        holes: SharedHoles,
+    ) -> impl CryptoReadSeek<R> {
+        crypto::create_ring_read_seek(reader, self.cipher, key, &[])
+            .sparse(holes)
+            .parallel(self.crypto_pool.clone())
+    }
+
+    /// Upgrade the data dir to the current on-disk format version.
+    ///
+    /// [`EncryptedFs::new`] also does this when opening older data dirs.
+    /// If the data dir was created by a newer version it will return [`FsError::UnsupportedVersion`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn migrate(
+        data_dir: &Path,
+        password_provider: Box<dyn AsyncPasswordProvider>,
+        cipher: Cipher,
+    ) -> FsResult<()> {
+        let storage = LocalStorage::new(data_dir);
+        check_structure(&storage, false).await?;
+        cipher_change::check_not_interrupted(&storage).await?;
+        let password = password_provider
+            .get_password()
+            .await
+            .ok_or(FsError::InvalidPassword)?;
+        let key = read_or_create_key(&storage, &key_path(), &password, cipher).await?;
+        run_migrations(&storage, cipher, &key).await
+    }
+
+    /// Check if `password` can access the encryption key, without opening the filesystem.
+    ///
+    /// Nothing is created, if the data dir doesn't exist it returns [`FsError::InvalidDataDirStructure`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn verify_password(
+        data_dir: &Path,
+        password: SecretString,
+        cipher: Cipher,
+    ) -> FsResult<()> {
+        Self::verify_password_timed(data_dir, password, cipher)
+            .await
+            .map(|_| ())
+    }
+
+    /// Like [`EncryptedFs::verify_password`], returns how long deriving the key from the password took.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn verify_password_timed(
+        data_dir: &Path,
+        password: SecretString,
+        cipher: Cipher,
+    ) -> FsResult<Duration> {
+        let storage = LocalStorage::new(data_dir);
+        check_structure(&storage, false).await?;
+        let start = Instant::now();
+        KeySlots::read_and_open(&storage, &key_path(), cipher, &password).await?;
+        Ok(start.elapsed())
     }
 
     /// Change the password of the filesystem used to access the encryption key.
+    ///
+    /// The slot `old_password` opens is replaced by one for `new_password`, see [`EncryptedFs::add_password_slot`].
+    /// `cipher` is used to encrypt the new slot.
+    #[allow(clippy::missing_errors_doc)]
     pub async fn passwd(
         data_dir: &Path,
         old_password: SecretString,
         new_password: SecretString,
         cipher: Cipher,
     ) -> FsResult<()> {
-        check_structure(data_dir, false).await?;
-        // decrypt key
-        let salt: Vec<u8> = bincode::deserialize_from(File::open(
-            data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME),
-        )?)?;
-        let initial_key = crypto::derive_key(&old_password, cipher, &salt)?;
-        let enc_file = data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME);
-        let reader = crypto::create_read(File::open(enc_file)?, cipher, &initial_key);
-        let key: Vec<u8> =
-            bincode::deserialize_from(reader).map_err(|_| FsError::InvalidPassword)?;
-        let key = SecretBox::new(Box::new(key));
-        // encrypt it with a new key derived from new password
-        let new_key = crypto::derive_key(&new_password, cipher, &salt)?;
-        crypto::atomic_serialize_encrypt_into(
-            &data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME),
-            &*key.expose_secret(),
-            cipher,
-            &new_key,
-        )?;
+        let storage = LocalStorage::new(data_dir);
+        check_structure(&storage, false).await?;
+        cipher_change::check_not_interrupted(&storage).await?;
+        let (mut slots, index, key) =
+            KeySlots::read_and_open(&storage, &key_path(), cipher, &old_password).await?;
+        slots.slots[index] = KeySlot::seal(&key, &new_password, cipher)?;
+        slots.write(&storage, &key_path()).await?;
+        key_slots::remove_legacy_salt(&storage, &key_path()).await?;
+        Ok(())
+    }
+
+    /// Add a password slot for `new_password`, after which either password can be used to access the encryption key.
+    ///
+    /// `existing_password` is any of the current passwords, `cipher` is used to encrypt the new slot.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn add_password_slot(
+        data_dir: &Path,
+        existing_password: SecretString,
+        new_password: SecretString,
+        cipher: Cipher,
+    ) -> FsResult<()> {
+        let storage = LocalStorage::new(data_dir);
+        check_structure(&storage, false).await?;
+        cipher_change::check_not_interrupted(&storage).await?;
+        let (mut slots, _, key) =
+            KeySlots::read_and_open(&storage, &key_path(), cipher, &existing_password).await?;
+        slots
+            .slots
+            .push(KeySlot::seal(&key, &new_password, cipher)?);
+        slots.write(&storage, &key_path()).await?;
+        key_slots::remove_legacy_salt(&storage, &key_path()).await?;
+        Ok(())
+    }
+
+    /// Remove the password slot `password` opens, it can't be used after that.
+    ///
+    /// The last slot can't be removed, it returns [`FsError::InvalidInput`] for it.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn remove_password_slot(
+        data_dir: &Path,
+        password: SecretString,
+        cipher: Cipher,
+    ) -> FsResult<()> {
+        let storage = LocalStorage::new(data_dir);
+        check_structure(&storage, false).await?;
+        cipher_change::check_not_interrupted(&storage).await?;
+        let (mut slots, index, _) =
+            KeySlots::read_and_open(&storage, &key_path(), cipher, &password).await?;
+        if slots.slots.len() == 1 {
+            return Err(FsError::invalid_input(
+                "can't remove the last password slot",
+            ));
+        }
+        slots.slots.remove(index);
+        slots.write(&storage, &key_path()).await?;
+        key_slots::remove_legacy_salt(&storage, &key_path()).await?;
+        Ok(())
+    }
+
+    /// Remove the encryption key from memory, like when the device is left unattended.
+    ///
+    /// Open write handles are flushed and, like the read handles, suspended as they keep keys derived from it.
+    /// The next operation needing the key calls the [`PasswordProvider`] again, it returns [`FsError::Locked`] if
+    /// that doesn't provide a password. The suspended handles are resumed when they are used after that.
+    /// The directory keys are removed too, see [`EncryptedFs::lock_directory_key`], and so are the decrypted blocks
+    /// of the contents, see [`FsOptions::block_cache_bytes`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn lock(&self) -> FsResult<()> {
+        self.suspend_handles(None).await?;
+        self.dir_keys.write().await.clear();
+        self.block_cache.clear();
+        self.key.clear().await;
+        Ok(())
+    }
+
+    /// Forget the attributes, the directory entries and the decrypted blocks kept in memory, they are read again
+    /// from the storage when needed. The open handles and the key are kept.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn drop_caches(&self) -> FsResult<()> {
+        self.attr_cache.get().await?.write().await.clear();
+        self.clear_dir_entry_caches().await?;
+        self.block_cache.clear();
+        Ok(())
+    }
+
+    /// Flush the write handles and suspend them and the read handles, see [`EncryptedFs::lock`]. Only the ones of
+    /// the files encrypted with the directory key `key_id` if it's given.
+    async fn suspend_handles(&self, key_id: Option<u32>) -> FsResult<()> {
+        let mut handles: Vec<(u64, u64)> = vec![];
+        for shard in self.write_handles.shards() {
+            for (fh, ctx) in shard.read().await.iter() {
+                handles.push((*fh, ctx.lock().await.ino));
+            }
+        }
+        let handles = self.handles_of_key(handles, key_id).await?;
+        for (fh, ino) in handles {
+            let lock = self
+                .read_write_locks
+                .get_or_insert_with(ino, || RwLock::new(false));
+            let _write_guard = lock.write().await;
+            let guard = self.write_handles.read(&fh).await;
+            let Some(ctx) = guard.get(&fh) else {
+                continue;
+            };
+            let mut ctx = ctx.lock().await;
+            ctx.write_buffer()?;
+            let Some(mut writer) = ctx.writer.take() else {
+                continue;
+            };
+            writer.finish()?.sync_all()?;
+            let attr = ctx.attr_dirty.then(|| ctx.attr.clone());
+            ctx.attr_dirty = false;
+            drop(ctx);
+            drop(guard);
+            if let Some(attr) = attr {
+                let size = attr.size;
+                self.save_handle_attr(ino, attr.into(), Some(size)).await?;
+            }
+        }
+        let mut handles: Vec<(u64, u64)> = vec![];
+        for shard in self.read_handles.shards() {
+            for (fh, ctx) in shard.read().await.iter() {
+                handles.push((*fh, ctx.lock().await.ino));
+            }
+        }
+        let handles = self.handles_of_key(handles, key_id).await?;
+        for (fh, ino) in handles {
+            let lock = self
+                .read_write_locks
+                .get_or_insert_with(ino, || RwLock::new(false));
+            let _write_guard = lock.write().await;
+            if let Some(ctx) = self.read_handles.read(&fh).await.get(&fh) {
+                ctx.lock().await.reader = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// The `(fh, ino)` of `handles` of the files encrypted with `key_id`, all of them if it's `None`.
+    async fn handles_of_key(
+        &self,
+        handles: Vec<(u64, u64)>,
+        key_id: Option<u32>,
+    ) -> FsResult<Vec<(u64, u64)>> {
+        if key_id.is_none() {
+            return Ok(handles);
+        }
+        let mut of_key = vec![];
+        for (fh, ino) in handles {
+            if self.get_inode_from_cache_or_storage(ino).await?.key_id == key_id {
+                of_key.push((fh, ino));
+            }
+        }
+        Ok(of_key)
+    }
+
+    /// Close all opened handles, writing what is buffered in the writers and the attributes of the files.
+    ///
+    /// Handles closed by this are not valid anymore. It's called when dropping [`EncryptedFs`], so data is not lost
+    /// if some handles were not released.
+    /// If some handles can't be closed it continues with the others and returns the first error.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn close_all(&self) -> FsResult<()> {
+        let mut result = Ok(());
+        let write_handles = self.write_handles.drain().await;
+        for (_, ctx) in write_handles {
+            let mut ctx = ctx.lock().await;
+            let ino = ctx.ino;
+            let lock = self
+                .read_write_locks
+                .get_or_insert_with(ino, || RwLock::new(false));
+            let _write_guard = lock.write().await;
+            // if suspended by `lock` it was already written
+            let buffer_result = ctx.write_buffer();
+            if let Some(mut writer) = ctx.writer.take() {
+                if let Err(err) = buffer_result
+                    .and_then(|()| writer.finish())
+                    .and_then(|mut file| file.sync_all())
+                    .map_err(FsError::from)
+                {
+                    error!(err = %err, ino, "closing write handle");
+                    result = result.and(Err(err));
+                }
+            }
+            let attr = ctx.attr_dirty.then(|| ctx.attr.clone());
+            drop(ctx);
+            let Some(attr) = attr else {
+                continue;
+            };
+            let size = attr.size;
+            if let Err(err) = self.save_handle_attr(ino, attr.into(), Some(size)).await {
+                error!(err = %err, ino, "writing attr of write handle");
+                result = result.and(Err(err));
+            }
+        }
+        let read_handles = self.read_handles.drain().await;
+        if !self.read_only {
+            for (_, ctx) in read_handles {
+                let ctx = ctx.lock().await;
+                if !ctx.accessed {
+                    continue;
+                }
+                let ino = ctx.ino;
+                let times = ctx.attr.clone();
+                drop(ctx);
+                if let Err(err) = self.save_handle_attr(ino, times, None).await {
+                    error!(err = %err, ino, "writing attr of read handle");
+                    result = result.and(Err(err));
+                }
+            }
+        }
+        self.opened_files_for_read.clear().await;
+        self.opened_files_for_write.clear().await;
+        if !self.read_only {
+            if let Err(err) = self.flush_dir_times().await {
+                error!(err = %err, "writing times of directories");
+                result = result.and(Err(err));
+            }
+        }
+        if let Err(err) = self.sync_pending().await {
+            error!(err = %err, "syncing pending changes");
+            result = result.and(Err(err));
+        }
+        result
+    }
+
+    /// Get the encryption key back after [`EncryptedFs::lock`], from the [`PasswordProvider`], and resume the
+    /// suspended handles.
+    ///
+    /// It returns [`FsError::Locked`] if the [`PasswordProvider`] doesn't provide a password.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn unlock(&self) -> FsResult<()> {
+        self.key.get().await?;
+        for shard in self.write_handles.shards() {
+            for ctx in shard.read().await.values() {
+                let mut ctx = ctx.lock().await;
+                if ctx.writer.is_none() {
+                    ctx.writer = Some(self.create_contents_writer(ctx.ino).await?);
+                }
+            }
+        }
+        for shard in self.read_handles.shards() {
+            for ctx in shard.read().await.values() {
+                let mut ctx = ctx.lock().await;
+                if ctx.reader.is_none() {
+                    ctx.reader = Some(self.create_contents_reader(ctx.ino).await?);
+                }
+            }
+        }
         Ok(())
     }
 
+    /// Encrypt `s` with the key of the data dir, in base64 so it can be kept as text.
+    ///
+    /// Get it back with [`EncryptedFs::decrypt_string`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn encrypt_string(&self, s: &SecretString) -> FsResult<String> {
+        Ok(crypto::encrypt(s, self.cipher, &*self.key.get().await?)?)
+    }
+
+    /// Decrypt `s` encrypted with [`EncryptedFs::encrypt_string`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn decrypt_string(&self, s: &str) -> FsResult<SecretString> {
+        Ok(crypto::decrypt(s, self.cipher, &*self.key.get().await?)?)
+    }
+
+    /// Serialize `value` encrypted with the key of the data dir, replacing atomically what was at `path`.
+    ///
+    /// It's for the data of the apps using us, like their config. `path` is relative to a directory of the data dir
+    /// kept for them, not to the files of the filesystem. The value is bound to its `path`, so it can't be read
+    /// from another one. They are not re-encrypted by [`EncryptedFs::change_cipher`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn serialize_encrypted<T: Serialize + ?Sized>(
+        &self,
+        path: &Path,
+        value: &T,
+    ) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let key = app_data_path(path)?;
+        let dir = key.parent().expect("oops, we don't have a parent");
+        if self.kind_at(dir).await.is_none() {
+            self.storage.create_dir(dir).await?;
+            // make the new directories durable
+            for ancestor in dir.ancestors().skip(1) {
+                if ancestor == Path::new(SECURITY_DIR) {
+                    break;
+                }
+                self.sync_with_policy(ancestor).await?;
+            }
+            self.sync_with_policy(Path::new(SECURITY_DIR)).await?;
+        }
+        self.write_bound(&key, value, &app_data_aad(path)).await
+    }
+
+    /// Deserialize the value written with [`EncryptedFs::serialize_encrypted`] at `path`.
+    ///
+    /// It returns [`FsError::NotFound`] if there is nothing at `path`.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn deserialize_encrypted<T: DeserializeOwned>(&self, path: &Path) -> FsResult<T> {
+        let key = app_data_path(path)?;
+        if self.kind_at(&key).await != Some(EntryKind::File) {
+            return Err(FsError::not_found(format!("app data {}", path.display())));
+        }
+        self.read_bound(&key, &app_data_aad(path)).await
+    }
+
     fn next_handle(&self) -> u64 {
         self.current_handle
             .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
@@ -2177,59 +5033,179 @@ impl EncryptedFs {
         skip_write_fh: Option<u64>,
         save_attr: bool,
     ) -> FsResult<()> {
-        let path = self.contents_path(ino);
-
-        // read
-        let lock = self.opened_files_for_read.read().await;
-        if let Some(set) = lock.get(&ino) {
-            for handle in set.iter().filter(|h| skip_write_fh != Some(**h)) {
-                let guard = self.read_handles.read().await;
-                let ctx = guard.get(handle).unwrap().lock().await;
-                let set_attr: SetFileAttr = ctx.attr.clone().into();
+        self.block_cache.invalidate(ino);
+        // write, first so the readers below don't merge the size of the writers from before the change
+        let fhs = self
+            .opened_files_for_write
+            .read(&ino)
+            .await
+            .get(&ino)
+            .cloned()
+            .unwrap_or_default();
+        for fh in fhs.iter().filter(|fh| skip_write_fh != Some(**fh)) {
+            let lock = self.write_handles.read(fh).await;
+            if let Some(lock) = lock.get(fh) {
+                let mut ctx = lock.lock().await;
+                ctx.write_buffer()?;
+                if let Some(writer) = ctx.writer.as_mut() {
+                    let mut file = writer.finish()?;
+                    file.sync_all()?;
+                }
+                let attr = (save_attr && ctx.attr_dirty).then(|| ctx.attr.clone());
                 drop(ctx);
-                self.set_attr(ino, set_attr).await?;
+                if let Some(attr) = attr {
+                    let size = attr.size;
+                    self.save_handle_attr(ino, attr.into(), Some(size)).await?;
+                }
+                let writer = self.create_contents_writer(ino).await?;
+                let mut ctx = lock.lock().await;
+                ctx.writer = Some(writer);
                 let attr = self.get_inode_from_storage(ino).await?;
-                let mut ctx = guard.get(handle).unwrap().lock().await;
-                let reader = self.create_read_seek(File::open(&path)?).await?;
-                ctx.reader = Some(Box::new(reader));
                 ctx.attr = attr.into();
+                ctx.attr_dirty &= !save_attr;
             }
         }
 
-        // write
-        let lock = self.opened_files_for_write.read().await;
-        if let Some(fh) = lock.get(&ino) {
-            if let Some(handle) = skip_write_fh {
-                if *fh == handle {
-                    return Ok(());
-                }
+        // read
+        let fhs = self
+            .opened_files_for_read
+            .read(&ino)
+            .await
+            .get(&ino)
+            .cloned()
+            .unwrap_or_default();
+        for handle in fhs.iter().filter(|h| skip_write_fh != Some(**h)) {
+            let guard = self.read_handles.read(handle).await;
+            let Some(lock) = guard.get(handle) else {
+                drop(guard);
+                self.repair_dangling_read_handle(ino, *handle).await;
+                continue;
+            };
+            let ctx = lock.lock().await;
+            let times = ctx.accessed.then(|| ctx.attr.clone());
+            drop(ctx);
+            if let Some(times) = times {
+                self.save_handle_attr(ino, times, None).await?;
             }
-            let lock = self.write_handles.read().await;
-            if let Some(lock) = lock.get(fh) {
-                let mut ctx = lock.lock().await;
-                let writer = ctx.writer.as_mut().unwrap();
-                let file = writer.finish()?;
-                file.sync_all()?;
-                File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
-                let set_attr: Option<SetFileAttr> = if save_attr {
-                    Some(ctx.attr.clone().into())
-                } else {
-                    None
-                };
-                drop(ctx);
-                if let Some(set_attr) = set_attr {
-                    self.set_attr(ino, set_attr).await?;
+            let attr = self.get_inode_from_storage(ino).await?;
+            let mut ctx = lock.lock().await;
+            ctx.reader = Some(self.create_contents_reader(ino).await?);
+            ctx.attr = attr.into();
+            ctx.accessed = false;
+        }
+
+        Ok(())
+    }
+
+    /// Whether a write handle of `ino` has writes in its buffer, see [`FsOptions::write_buffer_size`].
+    async fn has_buffered_writes(&self, ino: u64) -> bool {
+        let fhs = self
+            .opened_files_for_write
+            .read(&ino)
+            .await
+            .get(&ino)
+            .cloned()
+            .unwrap_or_default();
+        for fh in fhs {
+            let guard = self.write_handles.read(&fh).await;
+            if let Some(ctx) = guard.get(&fh) {
+                if !ctx.lock().await.buffer.is_empty() {
+                    return true;
                 }
-                let writer = self
-                    .create_write_seek(OpenOptions::new().read(true).write(true).open(&path)?)
-                    .await?;
-                let mut ctx = lock.lock().await;
-                ctx.writer = Some(Box::new(writer));
-                let attr = self.get_inode_from_storage(ino).await?;
-                ctx.attr = attr.into();
             }
         }
+        false
+    }
+
+    /// Write the block each other writer of `ino` than `handle` is in, with what it has buffered, and make it read
+    /// the block again on its next write, so the writers of a file see the changes of each other without being
+    /// finished. Returns the block each writer which wrote one was positioned in, at the end of a block it's the next
+    /// one.
+    /// > ⚠️ **Warning**
+    /// > Need to be called in a context with write lock on `self.read_write_inode.lock().await.get(ino)`.
+    async fn release_other_writers_blocks(&self, ino: u64, handle: u64) -> FsResult<Vec<u64>> {
+        let fhs = self
+            .opened_files_for_write
+            .read(&ino)
+            .await
+            .get(&ino)
+            .cloned()
+            .unwrap_or_default();
+        let mut released = vec![];
+        for fh in fhs.into_iter().filter(|fh| *fh != handle) {
+            let guard = self.write_handles.read(&fh).await;
+            let Some(ctx) = guard.get(&fh) else {
+                continue;
+            };
+            let mut ctx = ctx.lock().await;
+            ctx.write_buffer()?;
+            // suspended by `lock`, it has nothing pending
+            let Some(writer) = ctx.writer.as_mut() else {
+                continue;
+            };
+            let pos = writer.stream_position()?;
+            if writer.release_block()? {
+                released.push(pos / BLOCK_SIZE as u64);
+            }
+        }
+        Ok(released)
+    }
+
+    /// The writer of the handle, created again if it's missing, when it was suspended by `lock` or finishing it
+    /// failed.
+    async fn handle_writer<'a>(
+        &self,
+        ctx: &'a mut WriteHandleContext,
+    ) -> FsResult<&'a mut Box<dyn CryptoWriteSeek<SegmentedFile>>> {
+        let writer = match ctx.writer.take() {
+            Some(writer) => writer,
+            None => self.create_contents_writer(ctx.ino).await?,
+        };
+        Ok(ctx.writer.insert(writer))
+    }
 
+    /// Recreate the readers of `ino` positioned in one of the blocks matched by `changed`, so they don't keep serving
+    /// the previous decrypted content of the block. Readers in other blocks keep their reader. All of them get
+    /// `modified` as the modification and change time, which the access time is compared with, see
+    /// [`FsOptions::atime`].
+    /// > ⚠️ **Warning**
+    /// > Need to be called in a context with write lock on `self.read_write_inode.lock().await.get(ino)`.
+    async fn reset_read_handles_in_blocks(
+        &self,
+        ino: u64,
+        modified: SystemTime,
+        changed: impl Fn(u64) -> bool,
+    ) -> FsResult<()> {
+        self.block_cache.invalidate(ino);
+        let Some(fhs) = self
+            .opened_files_for_read
+            .read(&ino)
+            .await
+            .get(&ino)
+            .cloned()
+        else {
+            return Ok(());
+        };
+        for handle in &fhs {
+            let guard = self.read_handles.read(handle).await;
+            let Some(ctx) = guard.get(handle) else {
+                drop(guard);
+                self.repair_dangling_read_handle(ino, *handle).await;
+                continue;
+            };
+            let mut ctx = ctx.lock().await;
+            ctx.attr.mtime = ctx.attr.mtime.max(modified);
+            ctx.attr.ctime = ctx.attr.ctime.max(modified);
+            let Some(reader) = ctx.reader.as_mut() else {
+                continue;
+            };
+            let pos = reader.stream_position()?;
+            let block_size = BLOCK_SIZE as u64;
+            // at a block boundary the reader still holds the previous block
+            if changed(pos / block_size) || (pos > 0 && changed((pos - 1) / block_size)) {
+                ctx.reader = Some(self.create_contents_reader(ino).await?);
+            }
+        }
         Ok(())
     }
 
@@ -2239,58 +5215,95 @@ impl EncryptedFs {
         op: ReadHandleContextOperation,
     ) -> FsResult<()> {
         let ino = op.get_ino();
-        let path = self.contents_path(ino);
         let attr = self.get_inode_from_storage(ino).await?;
         match op {
             ReadHandleContextOperation::Create { ino } => {
                 let attr: TimesFileAttr = attr.into();
-                let reader = self.create_read_seek(File::open(&path)?).await?;
+                let reader = self.create_contents_reader(ino).await?;
                 let ctx = ReadHandleContext {
                     ino,
                     attr,
-                    reader: Some(Box::new(reader)),
+                    accessed: false,
+                    reader: Some(reader),
                 };
-                self.read_handles
-                    .write()
-                    .await
-                    .insert(handle, Mutex::new(ctx));
-                self.opened_files_for_read
-                    .write()
-                    .await
+                // add the handle while holding the lock on the opened files, so who sees it there finds the handle
+                let mut opened_files_for_read = self.opened_files_for_read.write(&ino).await;
+                opened_files_for_read
                     .entry(ino)
                     .or_insert_with(HashSet::new)
                     .insert(handle);
+                self.read_handles.insert(handle, Mutex::new(ctx)).await;
             }
         }
         Ok(())
     }
 
+    /// Remove the read handle `fh` of `ino` and its entry in the opened files, both while holding the lock on the
+    /// opened files, so who sees it there finds the handle.
+    async fn remove_read_handle(&self, ino: u64, fh: u64) -> Option<Mutex<ReadHandleContext>> {
+        let mut opened_files_for_read = self.opened_files_for_read.write(&ino).await;
+        let ctx = self.read_handles.remove(&fh).await;
+        if let Some(fhs) = opened_files_for_read.get_mut(&ino) {
+            fhs.remove(&fh);
+            if fhs.is_empty() {
+                opened_files_for_read.remove(&ino);
+            }
+        }
+        ctx
+    }
+
+    /// Remove `fh` from the opened files of `ino` when its read handle is missing, which should never happen.
+    async fn repair_dangling_read_handle(&self, ino: u64, fh: u64) {
+        let mut opened_files_for_read = self.opened_files_for_read.write(&ino).await;
+        let Some(fhs) = opened_files_for_read.get_mut(&ino) else {
+            return;
+        };
+        // released in the meantime, or it's fine
+        if !fhs.contains(&fh) || self.read_handles.contains_key(&fh).await {
+            return;
+        }
+        warn!(
+            ino,
+            fh, "read handle of an opened file is missing, removing it"
+        );
+        fhs.remove(&fh);
+        if fhs.is_empty() {
+            opened_files_for_read.remove(&ino);
+        }
+    }
+
     async fn do_with_write_handle(
         &self,
         handle: u64,
         op: WriteHandleContextOperation,
     ) -> FsResult<()> {
-        let ino = op.get_ino();
-        let path = self.contents_path(ino);
         match op {
             WriteHandleContextOperation::Create { ino } => {
+                let lock = self
+                    .read_write_locks
+                    .get_or_insert_with(ino, || RwLock::new(false));
+                let _write_guard = lock.write().await;
+                // write what the other writers have pending, from now on a writer writes the block it's in before
+                // another one writes, see `release_other_writers_blocks`
+                self.flush_and_reset_writers(ino).await?;
                 let attr = self.get_attr(ino).await?.into();
-                let writer = self
-                    .create_write_seek(OpenOptions::new().read(true).write(true).open(&path)?)
-                    .await?;
+                let writer = self.create_contents_writer(ino).await?;
                 let ctx = WriteHandleContext {
                     ino,
                     attr,
-                    writer: Some(Box::new(writer)),
+                    attr_dirty: false,
+                    writer: Some(writer),
+                    written: None,
+                    buffer: vec![],
+                    buffer_offset: 0,
                 };
-                self.write_handles
-                    .write()
-                    .await
-                    .insert(handle, Mutex::new(ctx));
                 self.opened_files_for_write
-                    .write()
+                    .write(&ino)
                     .await
-                    .insert(ino, handle);
+                    .entry(ino)
+                    .or_insert_with(HashSet::new)
+                    .insert(handle);
+                self.write_handles.insert(handle, Mutex::new(ctx)).await;
             }
         }
 
@@ -2298,7 +5311,7 @@ impl EncryptedFs {
     }
 
     async fn ensure_root_exists(&self) -> FsResult<()> {
-        if !self.exists(ROOT_INODE) {
+        if !self.exists(ROOT_INODE).await {
             let mut attr: FileAttr = CreateFileAttr {
                 kind: FileType::Directory,
                 perm: 0o755,
@@ -2306,9 +5319,11 @@ impl EncryptedFs {
                 gid: 0,
                 rdev: 0,
                 flags: 0,
+                compress: None,
             }
             .into();
             attr.ino = ROOT_INODE;
+            attr.ino_generation = self.next_generation().await?;
             #[cfg(any(target_os = "linux", target_os = "macos"))]
             unsafe {
                 attr.uid = libc::getuid();
@@ -2318,9 +5333,20 @@ impl EncryptedFs {
             self.write_inode_to_storage(&attr).await?;
 
             // create in contents directory
-            fs::create_dir(self.contents_path(attr.ino))?;
-            fs::create_dir(self.contents_path(attr.ino).join(LS_DIR))?;
-            fs::create_dir(self.contents_path(attr.ino).join(HASH_DIR))?;
+            let contents_dir = self.contents_path(attr.ino);
+            ensure_shard_created(&*self.storage, &contents_dir).await?;
+            self.storage.create_dir(&contents_dir).await?;
+            self.storage.create_dir(&contents_dir.join(LS_DIR)).await?;
+            self.storage
+                .create_dir(&contents_dir.join(HASH_DIR))
+                .await?;
+            write_name_salt(&*self.storage, &contents_dir).await?;
+            self.write_bound(
+                &contents_dir.join(CHILD_COUNT_FILENAME),
+                &0_u64,
+                &child_count_aad(attr.ino),
+            )
+            .await?;
 
             // add "." entry
             self.insert_directory_entry(
@@ -2342,275 +5368,1913 @@ impl EncryptedFs {
         ino_contents_dir: u64,
         entry: &DirectoryEntry,
     ) -> FsResult<()> {
+        // kept and listed normalized
+        let entry = &DirectoryEntry {
+            name: self.normalized_name(&entry.name).into_owned(),
+            ..entry.clone()
+        };
         let parent_path = self.contents_path(ino_contents_dir);
-        let encrypted_name =
-            crypto::encrypt_file_name(&entry.name, self.cipher, &*self.key.get().await?)?;
+        let encrypted_name = crypto::encrypt_file_name_with_padding(
+            &entry.name,
+            self.name_padding,
+            self.cipher,
+            &*self.inode_key(ino_contents_dir).await?,
+        )?;
+        let hash = self.hash_entry_name(ino_contents_dir, &entry.name).await?;
         // add to LS directory
-        let self_clone = self
-            .self_weak
-            .lock()
-            .unwrap()
-            .as_ref()
-            .unwrap()
-            .upgrade()
-            .unwrap();
+        let self_clone = self.self_arc()?;
         let parent_path_clone = parent_path.clone();
         let encrypted_name_clone = encrypted_name.clone();
         let entry_clone = entry.clone();
         // spawn a task to do concurrently with adding to HASH directory
-        let h = tokio::spawn(async move {
-            let file_path = parent_path_clone
-                .join(LS_DIR)
-                .join(encrypted_name_clone.clone());
-            let lock = self_clone
+        let h = self.runtime.spawn(async move {
+            let count_path = parent_path_clone.join(CHILD_COUNT_FILENAME);
+            let count_lock = self_clone
                 .serialize_dir_entries_ls_locks
-                .get_or_insert_with(file_path.to_str().unwrap().to_owned(), || {
+                .get_or_insert_with(count_path.to_str().unwrap().to_owned(), || {
                     RwLock::new(false)
                 });
-            let _guard = lock.write().await;
+            let _count_guard = count_lock.write().await;
             // write inode and file type
-            let entry = (entry_clone.ino, entry_clone.kind);
-            crypto::atomic_serialize_encrypt_into(
-                &file_path,
-                &entry,
+            let (ls_name, data) = serialize_ls_entry(
+                ino_contents_dir,
+                &encrypted_name_clone,
+                entry_clone.ino,
+                entry_clone.kind,
                 self_clone.cipher,
                 &*self_clone.key.get().await?,
             )?;
-            Ok::<(), FsError>(())
-        });
-        // add to HASH directory
-        let self_clone = self
-            .self_weak
-            .lock()
-            .unwrap()
-            .as_ref()
-            .unwrap()
-            .upgrade()
-            .unwrap();
-        let entry_hash = entry.clone();
-        tokio::spawn(async move {
-            let name = crypto::hash_file_name(&entry_hash.name);
-            let file_path = parent_path.join(HASH_DIR).join(name);
+            let file_path = parent_path_clone.join(LS_DIR).join(&ls_name);
             let lock = self_clone
-                .serialize_dir_entries_hash_locks
+                .serialize_dir_entries_ls_locks
                 .get_or_insert_with(file_path.to_str().unwrap().to_owned(), || {
                     RwLock::new(false)
                 });
             let _guard = lock.write().await;
-            // write inode and file type
-            // we save the encrypted name also because we need it to remove the entry on [`remove_directory_entry`]
-            let entry = (entry_hash.ino, entry_hash.kind, encrypted_name);
-            crypto::atomic_serialize_encrypt_into(
-                &file_path,
-                &entry,
-                self_clone.cipher,
-                &*self_clone.key.get().await?,
-            )?;
+            let added = self_clone.kind_at(&file_path).await.is_none();
+            self_clone.write_with_policy(&file_path, &data).await?;
+            // it might have been there before, pointing to another inode
+            self_clone
+                .cache_dir_entry(
+                    &file_path,
+                    &ls_name,
+                    &entry_clone.name,
+                    entry_clone.ino,
+                    entry_clone.kind,
+                )
+                .await?;
+            if added {
+                self_clone.update_child_count(ino_contents_dir, 1).await?;
+            }
             Ok::<(), FsError>(())
-        })
-        .await??;
+        });
+        // add to HASH directory
+        let self_clone = self.self_arc()?;
+        let entry_hash = entry.clone();
+        self.runtime
+            .spawn(async move {
+                let file_path = parent_path.join(HASH_DIR).join(&hash);
+                let lock = self_clone
+                    .serialize_dir_entries_hash_locks
+                    .get_or_insert_with(file_path.to_str().unwrap().to_owned(), || {
+                        RwLock::new(false)
+                    });
+                let _guard = lock.write().await;
+                // write inode and file type
+                // we save the encrypted name also because we need it to remove the entry on [`remove_directory_entry`]
+                let entry = (entry_hash.ino, entry_hash.kind, encrypted_name);
+                self_clone
+                    .write_bound(&file_path, &entry, &dir_entry_aad(ino_contents_dir, &hash))
+                    .await?;
+                Ok::<(), FsError>(())
+            })
+            .await??;
         h.await??;
         Ok(())
     }
 
-    fn ino_file(&self, ino: u64) -> PathBuf {
-        self.data_dir.join(INODES_DIR).join(ino.to_string())
+    /// Update the times of the directory `ino` after adding or removing an entry.
+    ///
+    /// The update is kept in memory for up to [`FsOptions::dir_times_flush_interval`] and merged by
+    /// [`EncryptedFs::get_attr`], see [`EncryptedFs::flush_dir_times`].
+    async fn touch_dir(&self, ino: u64) -> FsResult<()> {
+        let now = SystemTime::now();
+        let due = {
+            let mut pending = self.pending_dir_times.lock().await;
+            let (since, time) = pending.entry(ino).or_insert((Instant::now(), now));
+            *time = (*time).max(now);
+            since.elapsed() >= self.dir_times_flush_interval
+        };
+        if due {
+            // it takes the pending update
+            self.update_attr(ino, dir_times(now)).await?;
+        }
+        Ok(())
     }
 
-    fn contents_path(&self, ino: u64) -> PathBuf {
-        self.data_dir.join(CONTENTS_DIR).join(ino.to_string())
+    /// Write the time updates of the directories kept in memory by [`FsOptions::dir_times_flush_interval`].
+    async fn flush_dir_times(&self) -> FsResult<()> {
+        let pending: Vec<_> = self.pending_dir_times.lock().await.drain().collect();
+        for (ino, (_, time)) in pending {
+            match self.update_attr(ino, dir_times(time)).await {
+                // removed since
+                Err(FsError::InodeNotFound) => {}
+                res => res?,
+            }
+        }
+        Ok(())
     }
 
-    async fn remove_directory_entry(&self, parent: u64, name: &SecretString) -> FsResult<()> {
-        let parent_path = self.contents_path(parent);
-        // remove from HASH
-        let name = crypto::hash_file_name(name);
-        let path = parent_path.join(HASH_DIR).join(name);
-        let lock = self
-            .serialize_dir_entries_hash_locks
-            .get_or_insert_with(path.to_str().unwrap().to_owned(), || RwLock::new(false));
-        let guard = lock.write().await;
-        let (_, _, name): (u64, FileType, String) =
-            bincode::deserialize_from(crypto::create_read(
-                File::open(path.clone())?,
-                self.cipher,
-                &*self.key.get().await?,
-            ))?;
-        fs::remove_file(path)?;
-        drop(guard);
-        // remove from LS
-        let path = parent_path.join(LS_DIR).join(name);
-        let lock = self
-            .serialize_dir_entries_ls_locks
-            .get_or_insert_with(path.to_str().unwrap().to_owned(), || RwLock::new(false));
-        let _guard = lock.write().await;
-        fs::remove_file(path)?;
-        Ok(())
+    /// Write `value` encrypted with `aad` at `path`, synced according to the [`DurabilityPolicy`], see
+    /// [`serialize_bound_into`].
+    async fn write_bound<T: Serialize + ?Sized>(
+        &self,
+        path: &Path,
+        value: &T,
+        aad: &[u8],
+    ) -> FsResult<()> {
+        let data = crypto::serialize_encrypt_into(
+            Cursor::new(vec![]),
+            value,
+            self.cipher,
+            &*self.key.get().await?,
+            aad,
+        )?
+        .into_inner();
+        self.write_with_policy(path, &data).await
     }
 
-    fn generate_next_inode(&self) -> u64 {
-        loop {
-            let ino = crypto::create_rng().next_u64();
+    /// Replace the file at `key` with `data`, synced according to the [`DurabilityPolicy`].
+    async fn write_with_policy(&self, key: &Path, data: &[u8]) -> FsResult<()> {
+        if self.durability() == DurabilityPolicy::Always {
+            self.storage.write(key, data).await?;
+            return Ok(());
+        }
+        self.storage.write_unsynced(key, data).await?;
+        self.sync_with_policy(key).await?;
+        self.sync_with_policy(key.parent().expect("oops, we don't have a parent"))
+            .await
+    }
 
-            if ino <= ROOT_INODE {
-                continue;
-            }
-            if self.exists(ino) {
-                continue;
+    /// Sync the file or directory at `key` now, on the next release or never, according to the
+    /// [`DurabilityPolicy`].
+    async fn sync_with_policy(&self, key: &Path) -> FsResult<()> {
+        match self.durability() {
+            DurabilityPolicy::Always => self.storage.sync(key).await?,
+            DurabilityPolicy::OnRelease => {
+                self.pending_syncs.lock().await.insert(key.to_path_buf());
             }
+            DurabilityPolicy::Never => {}
+        }
+        Ok(())
+    }
 
-            return ino;
+    /// The [`DurabilityPolicy`] in effect, [`DurabilityPolicy::Always`] is taken as [`DurabilityPolicy::OnRelease`]
+    /// while [`EncryptedFs::import_dir`] runs.
+    fn durability(&self) -> DurabilityPolicy {
+        if self.durability == DurabilityPolicy::Always && self.imports.load(Ordering::Acquire) > 0 {
+            DurabilityPolicy::OnRelease
+        } else {
+            self.durability
         }
     }
-}
-pub struct CopyFileRangeReq {
-    src_ino: u64,
-    src_offset: u64,
-    dest_ino: u64,
-    dest_offset: u64,
-    src_fh: u64,
-    dest_fh: u64,
-}
 
-#[bon]
-impl CopyFileRangeReq {
-    #[builder]
-    pub fn new(
-        src_ino: u64,
-        src_offset: u64,
-        dest_ino: u64,
-        dest_offset: u64,
-        src_fh: u64,
-        dest_fh: u64,
-    ) -> Self {
-        Self {
-            src_ino,
-            src_offset,
-            dest_ino,
-            dest_offset,
-            src_fh,
-            dest_fh,
+    /// Sync what was left for later by [`DurabilityPolicy::OnRelease`].
+    async fn sync_pending(&self) -> FsResult<()> {
+        let keys: Vec<_> = self.pending_syncs.lock().await.drain().collect();
+        for key in keys {
+            match self.storage.sync(&key).await {
+                // removed since
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                res => res?,
+            }
         }
+        Ok(())
     }
-}
 
-fn read_or_create_key(
-    key_path: &PathBuf,
-    salt_path: &PathBuf,
-    password: &SecretString,
-    cipher: Cipher,
-) -> FsResult<SecretVec<u8>> {
-    let salt = if salt_path.exists() {
-        bincode::deserialize_from(File::open(salt_path)?).map_err(|_| FsError::InvalidPassword)?
-    } else {
-        let mut salt = vec![0; 16];
-        crypto::create_rng().fill_bytes(&mut salt);
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(salt_path)?;
-        bincode::serialize_into(&mut file, &salt)?;
-        file.flush()?;
-        file.sync_all()?;
-        File::open(salt_path.parent().expect("oops, we don't have a parent"))?.sync_all()?;
-        salt
-    };
-    // derive key from password
-    let derived_key = crypto::derive_key(password, cipher, &salt)?;
-    if key_path.exists() {
-        // read key
-        let reader = crypto::create_read(File::open(key_path)?, cipher, &derived_key);
-        let key: Vec<u8> =
-            bincode::deserialize_from(reader).map_err(|_| FsError::InvalidPassword)?;
-        Ok(SecretBox::new(Box::new(key)))
-    } else {
-        // first time, create a random key and encrypt it with the derived key from password
-        let mut key: Vec<u8> = vec![];
-        let key_len = cipher.key_len();
-        key.resize(key_len, 0);
-        crypto::create_rng().fill_bytes(&mut key);
-        let mut writer = crypto::create_write(
-            OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(key_path)?,
-            cipher,
-            &derived_key,
-        );
-        bincode::serialize_into(&mut writer, &key)?;
-        let file = writer.finish()?;
-        file.sync_all()?;
-        File::open(key_path.parent().unwrap())?.sync_all()?;
-        Ok(SecretBox::new(Box::new(key)))
+    /// Like [`ensure_shard_created`] but the new shard is synced according to the [`DurabilityPolicy`].
+    async fn ensure_shard_synced_with_policy(&self, path: &Path) -> FsResult<()> {
+        let shard = path.parent().expect("oops, we don't have a parent");
+        if self.storage.kind(shard).await?.is_none() {
+            self.storage.create_dir(shard).await?;
+            let shard_parent = shard.parent().expect("oops, we don't have a parent");
+            self.sync_with_policy(shard_parent).await?;
+            self.sync_with_policy(shard_parent.parent().expect("oops, we don't have a parent"))
+                .await?;
+        }
+        Ok(())
     }
-}
 
-async fn ensure_structure_created(data_dir: &PathBuf) -> FsResult<()> {
-    if data_dir.exists() {
-        check_structure(data_dir, true).await?;
-    } else {
-        fs::create_dir_all(data_dir)?;
+    /// Read the encrypted `T` from `path` bound to `aad`, see [`deserialize_bound`].
+    async fn read_bound<T: DeserializeOwned>(&self, path: &Path, aad: &[u8]) -> FsResult<T> {
+        deserialize_bound(
+            self.storage.read(path).await?.as_slice(),
+            self.cipher,
+            &*self.key.get().await?,
+            aad,
+        )
     }
 
-    // create directories
-    let dirs = vec![INODES_DIR, CONTENTS_DIR, SECURITY_DIR];
-    for dir in dirs {
-        let path = data_dir.join(dir);
-        if !path.exists() {
-            fs::create_dir_all(path)?;
-        }
+    /// Read the `ls` entry at `path` of the directory `parent`, see [`deserialize_ls_entry`].
+    pub(crate) async fn read_ls_entry(
+        &self,
+        parent: u64,
+        path: &Path,
+        key: &SecretVec<u8>,
+    ) -> FsResult<(u64, FileType, String)> {
+        let ls_name = path.file_name().unwrap_or_default().to_string_lossy();
+        deserialize_ls_entry(
+            self.storage.open(path, false).await?,
+            parent,
+            &ls_name,
+            self.cipher,
+            key,
+        )
     }
 
-    Ok(())
-}
+    /// A strong reference to us, to move in the spawned tasks.
+    fn self_arc(&self) -> FsResult<Arc<Self>> {
+        self.self_weak
+            .lock()
+            .map_err(|_| FsError::Internal("lock on the self reference is poisoned".to_owned()))?
+            .as_ref()
+            .and_then(Weak::upgrade)
+            .ok_or_else(|| FsError::Internal("self reference is gone".to_owned()))
+    }
 
-async fn check_structure(data_dir: &Path, ignore_empty: bool) -> FsResult<()> {
-    if !data_dir.exists() || !data_dir.is_dir() {
-        return Err(FsError::InvalidDataDirStructure);
+    /// [`FsError::NotFound`] for `name` in the directory `parent`, with its hashed name to find it in the storage.
+    async fn name_not_found(&self, parent: u64, name: &SecretString) -> FsError {
+        match self.hash_entry_name(parent, name).await {
+            Ok(hash) => FsError::not_found(format!("name {hash} in directory {parent}")),
+            Err(_) => FsError::not_found(format!("name in directory {parent}")),
+        }
     }
-    let mut vec = ReadDirStream::new(tokio::fs::read_dir(data_dir).await?)
-        .try_collect::<Vec<_>>()
-        .await?
-        .iter()
-        .map(|dir| dir.file_name().to_string_lossy().to_string())
-        .collect::<Vec<String>>();
-    if vec.is_empty() && ignore_empty {
-        return Ok(());
+
+    /// Name of the entry for `name` in the [`HASH_DIR`] of the directory `parent`.
+    async fn hash_entry_name(&self, parent: u64, name: &SecretString) -> FsResult<String> {
+        let name = self.normalized_name(name);
+        let salt = self
+            .storage
+            .read(&self.contents_path(parent).join(NAME_SALT_FILENAME))
+            .await?;
+        // `.` and `..` are not hashed, they are found in a locked directory too
+        let key = if matches!(name.expose_secret().as_str(), "." | ".." | "$." | "$..") {
+            self.key.get().await?
+        } else {
+            self.inode_key(parent).await?
+        };
+        Ok(crypto::hash_file_name(&name, &salt, &key))
     }
-    if vec.len() != 3 {
-        return Err(FsError::InvalidDataDirStructure);
+
+    /// Key of the inode file in the storage.
+    fn ino_file(&self, ino: u64) -> PathBuf {
+        shard_path(Path::new(INODES_DIR), ino)
     }
-    // make sure existing structure is ok
-    vec.sort_unstable();
-    let mut vec2 = vec![INODES_DIR, CONTENTS_DIR, SECURITY_DIR];
-    vec2.sort_unstable();
-    if vec != vec2
-        || !data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME).is_file()
-        || !data_dir
-            .join(SECURITY_DIR)
-            .join(KEY_SALT_FILENAME)
-            .is_file()
-    {
-        return Err(FsError::InvalidDataDirStructure);
+
+    /// Key of the contents in the storage, a file or a directory.
+    fn contents_path(&self, ino: u64) -> PathBuf {
+        shard_path(Path::new(CONTENTS_DIR), ino)
     }
 
-    Ok(())
-}
+    /// Size of the segments of the content files in the storage, see [`SegmentedFile`].
+    ///
+    /// Each segment holds whole encrypted blocks and stays under [`Cipher::max_plaintext_len`], or holds
+    /// [`FsOptions::segment_blocks`] of them.
+    fn segment_size(&self) -> u64 {
+        self.segment_blocks.map_or_else(
+            || cipher_segment_size(self.cipher),
+            |blocks| blocks.get() * self.cipher.ciphertext_block_size(),
+        )
+    }
 
-fn merge_attr(attr: &mut FileAttr, set_attr: &SetFileAttr, overwrite_size: bool) {
-    if let Some(size) = set_attr.size {
-        if overwrite_size {
-            attr.size = size;
-        } else {
-            attr.size = attr.size.max(size);
+    /// [`FileAttr::blocks`] of a file, from what its contents take in the storage.
+    async fn allocated_blocks(&self, ino: u64) -> FsResult<u64> {
+        if self.kind_at(&self.contents_path(ino)).await.is_none() {
+            // not created yet
+            return Ok(0);
         }
+        let allocated = self.open_contents(ino).await?.allocated()?;
+        Ok(allocated.div_ceil(STAT_BLOCK_SIZE))
     }
-    if let Some(atime) = set_attr.atime {
-        attr.atime = attr.atime.max(atime);
+
+    async fn open_contents(&self, ino: u64) -> io::Result<SegmentedFile> {
+        SegmentedFile::open(&self.storage, &self.contents_path(ino), self.segment_size()).await
+    }
+
+    async fn open_contents_rw(&self, ino: u64) -> io::Result<SegmentedFile> {
+        self.block_cache.invalidate(ino);
+        let path = self.contents_path(ino);
+        self.unshare_contents(&path).await?;
+        SegmentedFile::open_rw(&self.storage, &path, self.segment_size()).await
+    }
+
+    /// Copy the segments of the contents at `path` shared with a snapshot, so changing them in place doesn't change
+    /// the snapshot. Each copy replaces the segment atomically.
+    async fn unshare_contents(&self, path: &Path) -> io::Result<()> {
+        if !self.shared_contents.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        for index in 0..SegmentedFile::segments_count(&self.storage, path).await? {
+            let segment = SegmentedFile::segment_path(path, index);
+            if self.storage.link_count(&segment).await? < 2 {
+                continue;
+            }
+            let tmp = segment.with_extension("unshare");
+            {
+                let mut from = self.storage.open(&segment, false).await?;
+                let mut to = self.storage.create(&tmp).await?;
+                io::copy(&mut from, &mut to)?;
+                to.sync_all()?;
+            }
+            self.storage.rename(&tmp, &segment).await?;
+        }
+        Ok(())
+    }
+
+    /// Remove the file at `key` from the storage, overwritten first if `secure`, see [`FsOptions::secure_delete`].
+    async fn remove_key(&self, key: &Path, secure: bool) -> FsResult<()> {
+        if secure {
+            self.shred(key, 0).await?;
+        }
+        self.storage.remove(key).await?;
+        Ok(())
+    }
+
+    /// Overwrite the segments of the contents at `path` from `offset` to the end, see [`EncryptedFs::shred`].
+    #[allow(clippy::cast_possible_truncation)]
+    async fn shred_contents(&self, path: &Path, offset: u64) -> FsResult<()> {
+        let segment_size = self.segment_size();
+        for index in 0..SegmentedFile::segments_count(&self.storage, path).await? {
+            let start = index as u64 * segment_size;
+            if start + segment_size <= offset {
+                continue;
+            }
+            self.shred(
+                &SegmentedFile::segment_path(path, index),
+                offset.saturating_sub(start),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Overwrite the file at `key` with random bytes from `offset` to the end, synced before we return.
+    ///
+    /// Files shared with a snapshot are left as they are, they are overwritten when the snapshot is deleted.
+    #[allow(clippy::cast_possible_truncation)]
+    async fn shred(&self, key: &Path, offset: u64) -> FsResult<()> {
+        if self.storage.link_count(key).await? > 1 {
+            return Ok(());
+        }
+        let mut file = self.storage.open(key, true).await?;
+        let len = file.len()?;
+        if offset >= len {
+            return Ok(());
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        let mut rng = crypto::create_rng();
+        let mut buf = vec![0; (len - offset).min(SHRED_BUF_LEN) as usize];
+        let mut left = len - offset;
+        while left > 0 {
+            let buf = &mut buf[..left.min(SHRED_BUF_LEN) as usize];
+            rng.fill_bytes(buf);
+            file.write_all(buf)?;
+            left -= buf.len() as u64;
+        }
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// Remove the `hash` and `ls` entries of `name` in `parent`, overwritten first if `secure`.
+    async fn remove_directory_entry(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        secure: bool,
+    ) -> FsResult<()> {
+        let name = self.normalized_name(name);
+        let parent_path = self.contents_path(parent);
+        // remove from HASH
+        let hash = self.hash_entry_name(parent, &name).await?;
+        let path = parent_path.join(HASH_DIR).join(&hash);
+        let lock = self
+            .serialize_dir_entries_hash_locks
+            .get_or_insert_with(path.to_str().unwrap().to_owned(), || RwLock::new(false));
+        let guard = lock.write().await;
+        let (_, _, encrypted_name): (u64, FileType, String) = self
+            .read_bound(&path, &dir_entry_aad(parent, &hash))
+            .await?;
+        if self.is_entry_name(parent, &encrypted_name, &name).await? {
+            self.remove_key(&path, secure).await?;
+            drop(guard);
+            return self.remove_ls_entry(parent, &encrypted_name, secure).await;
+        }
+        drop(guard);
+        // the `hash` entry is of another name with the same hash, it stays
+        match self.scan_ls_entries(parent, &name).await? {
+            Some((_, _, encrypted_name)) => {
+                self.remove_ls_entry(parent, &encrypted_name, secure).await
+            }
+            None => Err(self.name_not_found(parent, &name).await),
+        }
+    }
+
+    /// Remove the `ls` entry of `parent` named `encrypted_name`, leaving its `hash` entry. It's overwritten first if
+    /// `secure`.
+    async fn remove_ls_entry(
+        &self,
+        parent: u64,
+        encrypted_name: &str,
+        secure: bool,
+    ) -> FsResult<()> {
+        let count_path = self.contents_path(parent).join(CHILD_COUNT_FILENAME);
+        let count_lock = self
+            .serialize_dir_entries_ls_locks
+            .get_or_insert_with(count_path.to_str().unwrap().to_owned(), || {
+                RwLock::new(false)
+            });
+        let _count_guard = count_lock.write().await;
+        let ls_name = ls_entry_name(encrypted_name);
+        let path = self.contents_path(parent).join(LS_DIR).join(&ls_name);
+        let lock = self
+            .serialize_dir_entries_ls_locks
+            .get_or_insert_with(path.to_str().unwrap().to_owned(), || RwLock::new(false));
+        let _guard = lock.write().await;
+        self.remove_key(&path, secure).await?;
+        self.invalidate_dir_entry(&path, &ls_name).await?;
+        self.update_child_count(parent, -1).await
+    }
+
+    /// The encrypted name of the entry `name` of `parent`, as kept in its `hash` entry, which names its `ls` entry.
+    async fn entry_encrypted_name(&self, parent: u64, name: &SecretString) -> FsResult<String> {
+        match self.lookup_entry(parent, name).await? {
+            Some((_, _, encrypted_name)) => Ok(encrypted_name),
+            None => Err(self.name_not_found(parent, name).await),
+        }
+    }
+
+    /// A free inode, reserved in [`EncryptedFs::reserved_inodes`] until the returned [`InodeReservation`] is
+    /// dropped, so a concurrent call doesn't give it again. The caller writes its inode file before that.
+    async fn generate_next_inode(&self) -> FsResult<InodeReservation<'_>> {
+        loop {
+            let ino = match self.inode_alloc {
+                InodeAlloc::Random => crypto::create_rng().next_u64(),
+                InodeAlloc::Sequential => match self.next_sequential_inode().await? {
+                    Some(ino) => ino,
+                    None => crypto::create_rng().next_u64(),
+                },
+            };
+
+            if ino <= ROOT_INODE {
+                continue;
+            }
+            // reserve before checking, or a call writing its inode file in between would not be seen
+            if !self.reserved_inodes.lock().unwrap().insert(ino) {
+                continue;
+            }
+            let reservation = InodeReservation { fs: self, ino };
+            if self.exists(ino).await {
+                continue;
+            }
+            return Ok(reservation);
+        }
+    }
+
+    /// Start [`InodeAlloc::Sequential`] from [`INODE_COUNTER_FILENAME`], or above the largest inode if there is none.
+    ///
+    /// Inodes made after it with [`InodeAlloc::Random`] can be above it, [`EncryptedFs::generate_next_inode`] skips
+    /// them.
+    async fn load_inode_counter(&self) -> FsResult<()> {
+        let path = Path::new(SECURITY_DIR).join(INODE_COUNTER_FILENAME);
+        let next = if self.kind_at(&path).await.is_some() {
+            String::from_utf8_lossy(&self.storage.read(&path).await?)
+                .trim()
+                .parse()?
+        } else {
+            sharded_inodes(&*self.storage, Path::new(INODES_DIR))
+                .await?
+                .into_iter()
+                .max()
+                .unwrap_or(ROOT_INODE)
+                .saturating_add(1)
+        };
+        *self.inode_counter.lock().await = next..next;
+        Ok(())
+    }
+
+    /// The next number of [`InodeAlloc::Sequential`], `None` if there are none left.
+    ///
+    /// When the batch is used up the next one is reserved in [`INODE_COUNTER_FILENAME`] before any of it is given,
+    /// so after a crash we continue after it.
+    async fn next_sequential_inode(&self) -> FsResult<Option<u64>> {
+        let mut counter = self.inode_counter.lock().await;
+        if counter.is_empty() {
+            let Some(end) = counter.end.checked_add(INODE_COUNTER_BATCH) else {
+                warn!("no sequential inode numbers left, using random ones");
+                return Ok(None);
+            };
+            // always synced, so after a crash we don't give again the numbers of the files removed since
+            self.storage
+                .write(
+                    &Path::new(SECURITY_DIR).join(INODE_COUNTER_FILENAME),
+                    end.to_string().as_bytes(),
+                )
+                .await?;
+            *counter = counter.end..end;
+        }
+        Ok(counter.next())
+    }
+}
+
+/// An inode given by [`EncryptedFs::generate_next_inode`], removed from [`EncryptedFs::reserved_inodes`] when
+/// dropped, also when the creation fails or its future is dropped.
+struct InodeReservation<'a> {
+    fs: &'a EncryptedFs,
+    ino: u64,
+}
+
+impl Drop for InodeReservation<'_> {
+    fn drop(&mut self) {
+        self.fs.reserved_inodes.lock().unwrap().remove(&self.ino);
+    }
+}
+
+impl Drop for EncryptedFs {
+    fn drop(&mut self) {
+        if self.write_handles.is_empty_mut() && self.read_handles.is_empty_mut() {
+            return;
+        }
+        if let Err(err) = async_util::call_async_blocking(self.close_all()) {
+            error!(err = %err, "closing handles on drop");
+        }
+    }
+}
+
+pub struct CopyFileRangeReq {
+    src_ino: u64,
+    src_offset: u64,
+    dest_ino: u64,
+    dest_offset: u64,
+    src_fh: u64,
+    dest_fh: u64,
+}
+
+#[bon]
+impl CopyFileRangeReq {
+    #[builder]
+    pub fn new(
+        src_ino: u64,
+        src_offset: u64,
+        dest_ino: u64,
+        dest_offset: u64,
+        src_fh: u64,
+        dest_fh: u64,
+    ) -> Self {
+        Self {
+            src_ino,
+            src_offset,
+            dest_ino,
+            dest_offset,
+            src_fh,
+            dest_fh,
+        }
+    }
+}
+
+/// Key of [`KEY_ENC_FILENAME`] in the storage.
+fn key_path() -> PathBuf {
+    Path::new(SECURITY_DIR).join(KEY_ENC_FILENAME)
+}
+
+/// Decrypt the key from `key_path` with the first slot `password` opens, see [`KeySlots`].
+///
+/// If there is no key yet a random one is created, with a slot for `password`.
+async fn read_or_create_key(
+    storage: &dyn Storage,
+    key_path: &Path,
+    password: &SecretString,
+    cipher: Cipher,
+) -> FsResult<SecretVec<u8>> {
+    if storage.kind(key_path).await?.is_some()
+        || storage
+            .kind(&key_slots::backup_path(key_path))
+            .await?
+            .is_some()
+    {
+        let (_, _, key) = KeySlots::read_and_open(storage, key_path, cipher, password).await?;
+        Ok(key)
+    } else {
+        // first time, create a random key and encrypt it with the derived key from password
+        let mut key: Vec<u8> = vec![];
+        let key_len = cipher.key_len();
+        key.resize(key_len, 0);
+        crypto::create_rng().fill_bytes(&mut key);
+        let key = SecretBox::new(Box::new(key));
+        let password = password.clone();
+        let (key, slot) = tokio::task::spawn_blocking(move || {
+            let slot = KeySlot::seal(&key, &password, cipher)?;
+            Ok::<_, FsError>((key, slot))
+        })
+        .await??;
+        KeySlots { slots: vec![slot] }
+            .write(storage, key_path)
+            .await?;
+        Ok(key)
+    }
+}
+
+/// Where a listed entry goes with [`DirIterationOrder::ByNameAscending`], by its encrypted name if it can't be read.
+fn dir_order_key(encrypted_name: &str, entry: &FsResult<DirectoryEntry>) -> (u8, String) {
+    match entry {
+        Ok(entry) => {
+            let name = entry.name.expose_secret().to_string();
+            let rank = match name.as_str() {
+                "." => 0,
+                ".." => 1,
+                _ => 2,
+            };
+            (rank, name)
+        }
+        Err(_) => (3, encrypted_name.to_owned()),
+    }
+}
+
+async fn ensure_structure_created(storage: &dyn Storage, normalize_unicode: bool) -> FsResult<()> {
+    let root = Path::new("");
+    if storage.kind(root).await?.is_some() {
+        check_structure(storage, true).await?;
+    } else {
+        storage.create_dir(root).await?;
+    }
+
+    // create directories
+    let dirs = vec![INODES_DIR, CONTENTS_DIR, SECURITY_DIR];
+    for dir in dirs {
+        storage.create_dir(Path::new(dir)).await?;
+    }
+    storage.create_dir(&journal::journal_dir()).await?;
+
+    if storage.kind(&key_path()).await? != Some(EntryKind::File) {
+        // new data dir, existing ones are upgraded by `run_migrations`
+        write_format_version(storage, FORMAT_VERSION).await?;
+        if normalize_unicode {
+            unicode::write_normalize_unicode(storage).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Key in the storage of the value at `path` of [`EncryptedFs::serialize_encrypted`].
+fn app_data_path(path: &Path) -> FsResult<PathBuf> {
+    if path.as_os_str().is_empty()
+        || !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(FsError::invalid_input(format!(
+            "app data path must be relative and without '.' or '..', got {}",
+            path.display()
+        )));
+    }
+    Ok(Path::new(SECURITY_DIR).join(APP_DATA_DIR).join(path))
+}
+
+/// Associated data binding a value of [`EncryptedFs::serialize_encrypted`] to its `path`.
+fn app_data_aad(path: &Path) -> Vec<u8> {
+    [b"app".as_slice(), path.as_os_str().as_encoded_bytes()].concat()
+}
+
+/// Path of `ino` inside the sharded `dir`, like `dir/<first byte>/<second byte>/<ino>` with the bytes
+/// taken from the low end of `ino` in hex.
+///
+/// This keeps the number of entries in each directory low even with millions of files.
+pub(crate) fn shard_path(dir: &Path, ino: u64) -> PathBuf {
+    dir.join(format!("{:02x}", ino & 0xff))
+        .join(format!("{:02x}", (ino >> 8) & 0xff))
+        .join(ino.to_string())
+}
+
+/// Size of the segments of the content files encrypted with `cipher`, see [`EncryptedFs::segment_size`].
+fn cipher_segment_size(cipher: Cipher) -> u64 {
+    let blocks = cipher.max_plaintext_len() as u64 / cipher.ciphertext_block_size();
+    blocks * cipher.ciphertext_block_size()
+}
+
+/// Fail with [`FsError::MaxFilesizeExceeded`] if `len` bytes at `offset` end past the largest size of a file encrypted
+/// with `cipher`, where the position of its last block in the storage would not fit in a `u64`.
+fn check_file_size(cipher: Cipher, offset: u64, len: u64) -> FsResult<()> {
+    let max = u64::MAX / cipher.ciphertext_block_size() * BLOCK_SIZE as u64;
+    if offset.checked_add(len).is_none_or(|end| end > max) {
+        return Err(FsError::MaxFilesizeExceeded);
+    }
+    Ok(())
+}
+
+/// Lock the data dir for the lifetime of an [`EncryptedFs`] or of an operation rewriting it.
+///
+/// Read-write instances take an `exclusive` lock and write their PID in the lock file, read-only ones take a shared
+/// lock so several of them can use the data dir at once.
+/// The lock is released when the returned file is dropped, and by the OS if the process dies, so a lock left by a
+/// crash is not held anymore and the PID in the file is replaced by the next holder. If it's already locked it returns
+/// [`FsError::AlreadyMounted`] with the PID of the holder, when known.
+pub(crate) fn lock_instance(data_dir: &Path, exclusive: bool) -> FsResult<File> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(data_dir.join(SECURITY_DIR).join(INSTANCE_LOCK_FILENAME))?;
+    let locked = if exclusive {
+        acquired(file.try_lock())?
+    } else {
+        acquired(file.try_lock_shared())?
+    };
+    if !locked {
+        // if only readers hold it the PID in the file is of a previous writer
+        let pid = if exclusive && acquired(file.try_lock_shared())? {
+            file.unlock()?;
+            None
+        } else {
+            let mut content = String::new();
+            file.read_to_string(&mut content)?;
+            content.trim().parse().ok()
+        };
+        return Err(FsError::AlreadyMounted { pid });
+    }
+    if exclusive {
+        file.set_len(0)?;
+        file.write_all(std::process::id().to_string().as_bytes())?;
+        file.sync_all()?;
+    }
+    Ok(file)
+}
+
+/// Whether a `try_lock` got the lock, `false` if another holder has it.
+fn acquired(res: Result<(), TryLockError>) -> io::Result<bool> {
+    match res {
+        Ok(()) => Ok(true),
+        Err(TryLockError::WouldBlock) => Ok(false),
+        Err(TryLockError::Error(err)) => Err(err),
+    }
+}
+
+/// Associated data binding an inode file to its inode, so inode files can't be swapped.
+pub(crate) fn inode_aad(ino: u64) -> Vec<u8> {
+    [b"inode".as_slice(), &ino.to_le_bytes()].concat()
+}
+
+/// Associated data binding the [`CHILD_COUNT_FILENAME`] of a directory to its inode.
+pub(crate) fn child_count_aad(ino: u64) -> Vec<u8> {
+    [b"count".as_slice(), &ino.to_le_bytes()].concat()
+}
+
+/// Associated data binding an entry in [`LS_DIR`] or [`HASH_DIR`] to its directory and its file name, so entries
+/// can't be swapped or moved to another directory.
+pub(crate) fn dir_entry_aad(parent: u64, file_name: &str) -> Vec<u8> {
+    [
+        b"entry".as_slice(),
+        &parent.to_le_bytes(),
+        file_name.as_bytes(),
+    ]
+    .concat()
+}
+
+/// Name of the file of the `ls` entry whose encrypted name is `encrypted_name`.
+///
+/// It's `encrypted_name`, unless that doesn't fit in a path component of the storage, see
+/// [`crypto::MAX_NAME_COMPONENT_LEN`]. Then it's [`LONG_NAME_PREFIX`] and the hex of its hash, and the encrypted name
+/// is kept in the entry, see [`serialize_ls_entry`].
+pub(crate) fn ls_entry_name(encrypted_name: &str) -> String {
+    if encrypted_name.len() <= crypto::MAX_NAME_COMPONENT_LEN {
+        encrypted_name.to_owned()
+    } else {
+        format!(
+            "{LONG_NAME_PREFIX}{}",
+            hex::encode(crypto::hash(encrypted_name.as_bytes()))
+        )
+    }
+}
+
+/// Encrypt the `ls` entry of the directory `parent` for `encrypted_name`, returns the name of its file, see
+/// [`ls_entry_name`], and its content.
+///
+/// It has the inode and the type of the entry, and `encrypted_name` too when it's not the name of the file. Read it
+/// with [`deserialize_ls_entry`].
+pub(crate) fn serialize_ls_entry(
+    parent: u64,
+    encrypted_name: &str,
+    ino: u64,
+    kind: FileType,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<(String, Vec<u8>)> {
+    let ls_name = ls_entry_name(encrypted_name);
+    let aad = dir_entry_aad(parent, &ls_name);
+    let cursor = if ls_name == encrypted_name {
+        crypto::serialize_encrypt_into(Cursor::new(vec![]), &(ino, kind), cipher, key, &aad)?
+    } else {
+        crypto::serialize_encrypt_into(
+            Cursor::new(vec![]),
+            &(ino, kind, encrypted_name),
+            cipher,
+            key,
+            &aad,
+        )?
+    };
+    Ok((ls_name, cursor.into_inner()))
+}
+
+/// Decrypt the `ls` entry of the directory `parent` named `ls_name` written by [`serialize_ls_entry`], returns its
+/// inode, its type and its encrypted name.
+pub(crate) fn deserialize_ls_entry<R: Read + Send + Sync>(
+    reader: R,
+    parent: u64,
+    ls_name: &str,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<(u64, FileType, String)> {
+    let aad = dir_entry_aad(parent, ls_name);
+    if ls_name.starts_with(LONG_NAME_PREFIX) {
+        let (ino, kind, encrypted_name): (u64, FileType, String) =
+            deserialize_bound(reader, cipher, key, &aad)?;
+        // the name of the file is the hash of the one inside
+        if ls_entry_name(&encrypted_name) != ls_name {
+            return Err(FsError::IntegrityViolation);
+        }
+        Ok((ino, kind, encrypted_name))
+    } else {
+        let (ino, kind): (u64, FileType) = deserialize_bound(reader, cipher, key, &aad)?;
+        Ok((ino, kind, ls_name.to_owned()))
+    }
+}
+
+/// Deserialize `T` encrypted with `aad` from `reader`.
+///
+/// If it can't be authenticated, like when it was written with another `aad`, it returns
+/// [`FsError::IntegrityViolation`].
+pub(crate) fn deserialize_bound<T: DeserializeOwned, R: Read + Send + Sync>(
+    reader: R,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    aad: &[u8],
+) -> FsResult<T> {
+    bincode::deserialize_from(crypto::create_read_with_aad(reader, cipher, key, aad)).map_err(
+        |err| match *err {
+            bincode::ErrorKind::Io(ref io_err) if io_err.kind() == io::ErrorKind::InvalidData => {
+                error!(err = %err, "authenticating");
+                FsError::IntegrityViolation
+            }
+            _ => err.into(),
+        },
+    )
+}
+
+/// Create the shard directories holding `path` if they don't exist.
+async fn ensure_shard_created(storage: &dyn Storage, path: &Path) -> io::Result<()> {
+    let shard = path.parent().expect("oops, we don't have a parent");
+    if storage.kind(shard).await?.is_none() {
+        storage.create_dir(shard).await?;
+        let shard_parent = shard.parent().expect("oops, we don't have a parent");
+        storage.sync(shard_parent).await?;
+        storage
+            .sync(shard_parent.parent().expect("oops, we don't have a parent"))
+            .await?;
+    }
+    Ok(())
+}
+
+/// Serialize `value` encrypted with `aad` and write it at `path` atomically, see [`deserialize_bound`].
+pub(crate) async fn serialize_bound_into<T: Serialize + ?Sized>(
+    storage: &dyn Storage,
+    path: &Path,
+    value: &T,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    aad: &[u8],
+) -> FsResult<()> {
+    let data =
+        crypto::serialize_encrypt_into(Cursor::new(vec![]), value, cipher, key, aad)?.into_inner();
+    storage.write(path, &data).await?;
+    Ok(())
+}
+
+/// Inodes in the sharded `dir`, see [`shard_path`].
+///
+/// Anything not named like an inode is skipped, like the extra segments of the contents or temp files.
+pub(crate) async fn sharded_inodes(storage: &dyn Storage, dir: &Path) -> FsResult<HashSet<u64>> {
+    let mut inodes = HashSet::new();
+    for shard in storage.list(dir).await? {
+        if shard.kind != EntryKind::Dir {
+            continue;
+        }
+        let shard = dir.join(shard.name);
+        for shard2 in storage.list(&shard).await? {
+            if shard2.kind != EntryKind::Dir {
+                continue;
+            }
+            for entry in storage.list(&shard.join(shard2.name)).await? {
+                if let Ok(ino) = entry.name.parse::<u64>() {
+                    inodes.insert(ino);
+                }
+            }
+        }
+    }
+    Ok(inodes)
+}
+
+/// Write a new [`NAME_SALT_FILENAME`] in the contents dir of a directory.
+async fn write_name_salt(storage: &dyn Storage, contents_dir: &Path) -> FsResult<()> {
+    storage
+        .write(
+            &contents_dir.join(NAME_SALT_FILENAME),
+            &crypto::create_name_salt(),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Upgrade the data dir to [`FORMAT_VERSION`], one version at a time.
+///
+/// The version file is updated after each step, so an interrupted migration continues from the last completed step.
+async fn run_migrations(
+    storage: &dyn Storage,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<()> {
+    let mut version = read_format_version(storage).await?;
+    if version > FORMAT_VERSION {
+        return Err(FsError::UnsupportedVersion(version));
+    }
+    while version < FORMAT_VERSION {
+        info!(from = version, to = version + 1, "migrating data dir");
+        migrate_step(storage, version, cipher, key).await?;
+        version += 1;
+        write_format_version(storage, version).await?;
+    }
+    Ok(())
+}
+
+/// Upgrade the data dir from `from_version` to the next one.
+///
+/// Steps that change the encrypted metadata get the `cipher` and `key` to re-encrypt it.
+async fn migrate_step(
+    storage: &dyn Storage,
+    from_version: u32,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<()> {
+    match from_version {
+        0 => migrate_to_sharded_layout(storage).await,
+        1 => migrate_to_keyed_name_hashes(storage, cipher, key).await,
+        2 => migrate_to_bound_metadata(storage, cipher, key).await,
+        3 => migrate_to_compression_flag(storage, cipher, key).await,
+        4 => migrate_to_generation(storage, cipher, key).await,
+        // nothing to change, only the older versions can't read the holes
+        5 => Ok(()),
+        6 => migrate_to_key_id(storage, cipher, key).await,
+        // nothing to change, only the older versions would keep the padding in the names
+        7 => Ok(()),
+        // nothing to change, only the older versions can't read the long names
+        8 => Ok(()),
+        9 => migrate_to_portable_names(storage, cipher, key).await,
+        10 => migrate_to_ino_generation(storage, cipher, key).await,
+        11 => migrate_to_inode_holes(storage, cipher, key).await,
+        12 => migrate_to_change_log(storage, cipher, key).await,
+        13 => migrate_to_inode_quota(storage, cipher, key).await,
+        _ => Err(FsError::UnsupportedVersion(from_version)),
+    }
+}
+
+async fn read_format_version(storage: &dyn Storage) -> FsResult<u32> {
+    let path = Path::new(SECURITY_DIR).join(VERSION_FILENAME);
+    if storage.kind(&path).await?.is_none() {
+        return Ok(0);
+    }
+    Ok(String::from_utf8_lossy(&storage.read(&path).await?)
+        .trim()
+        .parse()?)
+}
+
+/// The cipher kept in [`CIPHER_FILENAME`], `None` for the data dirs made before it was kept.
+pub(crate) async fn read_cipher(storage: &dyn Storage) -> FsResult<Option<Cipher>> {
+    let path = Path::new(SECURITY_DIR).join(CIPHER_FILENAME);
+    if storage.kind(&path).await?.is_none() {
+        return Ok(None);
+    }
+    let cipher = String::from_utf8_lossy(&storage.read(&path).await?)
+        .trim()
+        .parse()
+        .map_err(|_| FsError::Corrupted {
+            what: CIPHER_FILENAME.to_string(),
+            ino: None,
+        })?;
+    Ok(Some(cipher))
+}
+
+pub(crate) async fn write_cipher(storage: &dyn Storage, cipher: Cipher) -> FsResult<()> {
+    storage
+        .write(
+            &Path::new(SECURITY_DIR).join(CIPHER_FILENAME),
+            cipher.to_string().as_bytes(),
+        )
+        .await?;
+    Ok(())
+}
+
+async fn write_format_version(storage: &dyn Storage, version: u32) -> FsResult<()> {
+    storage
+        .write(
+            &Path::new(SECURITY_DIR).join(VERSION_FILENAME),
+            version.to_string().as_bytes(),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Move the inodes and contents from the flat layout of version `0` into shards.
+///
+/// Can be resumed if interrupted, shards created by a previous run are skipped.
+async fn migrate_to_sharded_layout(storage: &dyn Storage) -> FsResult<()> {
+    for dir in [INODES_DIR, CONTENTS_DIR] {
+        let dir = Path::new(dir);
+        for entry in storage.list(dir).await? {
+            let path = dir.join(&entry.name);
+            // directories in contents have `ls`, shards don't
+            if entry.kind == EntryKind::Dir
+                && storage.kind(&path.join(LS_DIR)).await? != Some(EntryKind::Dir)
+            {
+                continue;
+            }
+            let Ok(ino) = entry.name.parse::<u64>() else {
+                continue;
+            };
+            let dest = shard_path(dir, ino);
+            ensure_shard_created(storage, &dest).await?;
+            storage.rename(&path, &dest).await?;
+        }
+        storage.sync(dir).await?;
+    }
+    Ok(())
+}
+
+/// Recreate the [`HASH_DIR`] of all directories with keyed hashes and a new salt for each directory.
+///
+/// The new hashes are written in a temp dir from the `ls` entries, the salt is written when that is complete and then
+/// the temp dir replaces the old one. Directories with a salt and no temp dir are already migrated, so it can be
+/// resumed if interrupted.
+async fn migrate_to_keyed_name_hashes(
+    storage: &dyn Storage,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<()> {
+    let contents_dir = Path::new(CONTENTS_DIR);
+    for ino in sharded_inodes(storage, contents_dir).await? {
+        let dir = shard_path(contents_dir, ino);
+        if storage.kind(&dir.join(LS_DIR)).await? != Some(EntryKind::Dir) {
+            continue;
+        }
+        let salt_path = dir.join(NAME_SALT_FILENAME);
+        let tmp_hash_dir = dir.join(format!("{HASH_DIR}.tmp"));
+        let has_salt = storage.kind(&salt_path).await?.is_some();
+        if storage.kind(&tmp_hash_dir).await?.is_none() {
+            if has_salt {
+                continue;
+            }
+        } else if !has_salt {
+            // interrupted while writing the new hashes
+            storage.remove_dir(&tmp_hash_dir).await?;
+        }
+        if !has_salt {
+            let salt = crypto::create_name_salt();
+            storage.create_dir(&tmp_hash_dir).await?;
+            for entry in storage.list(&dir.join(LS_DIR)).await? {
+                let encrypted_name = entry.name;
+                let name = if encrypted_name == LEGACY_SELF_ENTRY_NAME
+                    || encrypted_name == LEGACY_PARENT_ENTRY_NAME
+                {
+                    SecretString::from_str(&encrypted_name).unwrap()
+                } else {
+                    // base64 up to version `9`
+                    crypto::decrypt_file_name(
+                        &crypto::reencode_legacy_file_name(&encrypted_name)?,
+                        cipher,
+                        key,
+                    )?
+                };
+                let (entry_ino, kind): (u64, FileType) =
+                    bincode::deserialize_from(crypto::create_read(
+                        storage
+                            .open(&dir.join(LS_DIR).join(&encrypted_name), false)
+                            .await?,
+                        cipher,
+                        key,
+                    ))?;
+                serialize_bound_into(
+                    storage,
+                    &tmp_hash_dir.join(crypto::hash_file_name(&name, &salt, key)),
+                    &(entry_ino, kind, encrypted_name),
+                    cipher,
+                    key,
+                    &[],
+                )
+                .await?;
+            }
+            storage.sync(&tmp_hash_dir).await?;
+            storage.write(&salt_path, &salt).await?;
+        }
+        let hash_dir = dir.join(HASH_DIR);
+        if storage.kind(&hash_dir).await?.is_some() {
+            storage.remove_dir(&hash_dir).await?;
+        }
+        storage.rename(&tmp_hash_dir, &hash_dir).await?;
+        storage.sync(&dir).await?;
+    }
+    Ok(())
+}
+
+/// Re-encrypt the inodes and the directory entries with the associated data binding them to their location.
+///
+/// Files re-encrypted by an interrupted run are detected by reading them with the new associated data, so it can be
+/// resumed.
+async fn migrate_to_bound_metadata(
+    storage: &dyn Storage,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<()> {
+    let inodes_dir = Path::new(INODES_DIR);
+    for ino in sharded_inodes(storage, inodes_dir).await? {
+        rebind::<FileAttrV3>(
+            storage,
+            &shard_path(inodes_dir, ino),
+            cipher,
+            key,
+            &inode_aad(ino),
+        )
+        .await?;
+    }
+    let contents_dir = Path::new(CONTENTS_DIR);
+    for ino in sharded_inodes(storage, contents_dir).await? {
+        let dir = shard_path(contents_dir, ino);
+        if storage.kind(&dir.join(LS_DIR)).await? != Some(EntryKind::Dir) {
+            continue;
+        }
+        for entry in storage.list(&dir.join(LS_DIR)).await? {
+            let aad = dir_entry_aad(ino, &entry.name);
+            let path = dir.join(LS_DIR).join(entry.name);
+            rebind::<(u64, FileType)>(storage, &path, cipher, key, &aad).await?;
+        }
+        for entry in storage.list(&dir.join(HASH_DIR)).await? {
+            let aad = dir_entry_aad(ino, &entry.name);
+            let path = dir.join(HASH_DIR).join(entry.name);
+            rebind::<(u64, FileType, String)>(storage, &path, cipher, key, &aad).await?;
+        }
+    }
+    Ok(())
+}
+
+/// [`FileAttr`] as it was written up to version `3`, without [`FileAttr::compressed`].
+#[derive(Serialize, Deserialize)]
+struct FileAttrV3 {
+    ino: u64,
+    size: u64,
+    blocks: u64,
+    atime: SystemTime,
+    mtime: SystemTime,
+    ctime: SystemTime,
+    crtime: SystemTime,
+    kind: FileType,
+    perm: u16,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u32,
+    blksize: u32,
+    flags: u32,
+}
+
+impl From<FileAttrV3> for FileAttrV4 {
+    fn from(value: FileAttrV3) -> Self {
+        Self {
+            ino: value.ino,
+            size: value.size,
+            blocks: value.blocks,
+            atime: value.atime,
+            mtime: value.mtime,
+            ctime: value.ctime,
+            crtime: value.crtime,
+            kind: value.kind,
+            perm: value.perm,
+            nlink: value.nlink,
+            uid: value.uid,
+            gid: value.gid,
+            rdev: value.rdev,
+            blksize: value.blksize,
+            flags: value.flags,
+            compressed: false,
+        }
+    }
+}
+
+/// [`FileAttr`] as it was written in version `4`, without [`FileAttr::generation`].
+#[derive(Serialize, Deserialize)]
+struct FileAttrV4 {
+    ino: u64,
+    size: u64,
+    blocks: u64,
+    atime: SystemTime,
+    mtime: SystemTime,
+    ctime: SystemTime,
+    crtime: SystemTime,
+    kind: FileType,
+    perm: u16,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u32,
+    blksize: u32,
+    flags: u32,
+    compressed: bool,
+}
+
+impl From<FileAttrV4> for FileAttrV5 {
+    fn from(value: FileAttrV4) -> Self {
+        Self {
+            ino: value.ino,
+            size: value.size,
+            blocks: value.blocks,
+            atime: value.atime,
+            mtime: value.mtime,
+            ctime: value.ctime,
+            crtime: value.crtime,
+            kind: value.kind,
+            perm: value.perm,
+            nlink: value.nlink,
+            uid: value.uid,
+            gid: value.gid,
+            rdev: value.rdev,
+            blksize: value.blksize,
+            flags: value.flags,
+            compressed: value.compressed,
+            generation: 0,
+        }
+    }
+}
+
+/// [`FileAttr`] as it was written in versions `5` and `6`, without [`FileAttr::key_id`]. The archives keep it like
+/// this.
+#[derive(Serialize, Deserialize)]
+struct FileAttrV5 {
+    ino: u64,
+    size: u64,
+    blocks: u64,
+    atime: SystemTime,
+    mtime: SystemTime,
+    ctime: SystemTime,
+    crtime: SystemTime,
+    kind: FileType,
+    perm: u16,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u32,
+    blksize: u32,
+    flags: u32,
+    compressed: bool,
+    generation: u64,
+}
+
+impl From<FileAttr> for FileAttrV5 {
+    fn from(value: FileAttr) -> Self {
+        Self {
+            ino: value.ino,
+            size: value.size,
+            blocks: value.blocks,
+            atime: value.atime,
+            mtime: value.mtime,
+            ctime: value.ctime,
+            crtime: value.crtime,
+            kind: value.kind,
+            perm: value.perm,
+            nlink: value.nlink,
+            uid: value.uid,
+            gid: value.gid,
+            rdev: value.rdev,
+            blksize: value.blksize,
+            flags: value.flags,
+            compressed: value.compressed,
+            generation: value.generation,
+        }
+    }
+}
+
+impl From<FileAttrV5> for FileAttr {
+    fn from(value: FileAttrV5) -> Self {
+        Self {
+            ino: value.ino,
+            size: value.size,
+            blocks: value.blocks,
+            atime: value.atime,
+            mtime: value.mtime,
+            ctime: value.ctime,
+            crtime: value.crtime,
+            kind: value.kind,
+            perm: value.perm,
+            nlink: value.nlink,
+            uid: value.uid,
+            gid: value.gid,
+            rdev: value.rdev,
+            blksize: value.blksize,
+            flags: value.flags,
+            compressed: value.compressed,
+            generation: value.generation,
+            key_id: None,
+            ino_generation: 0,
+            quota: None,
+        }
+    }
+}
+
+/// [`FileAttr`] as it was written in versions `7` to `10`, without [`FileAttr::ino_generation`].
+#[derive(Serialize, Deserialize)]
+struct FileAttrV10 {
+    ino: u64,
+    size: u64,
+    blocks: u64,
+    atime: SystemTime,
+    mtime: SystemTime,
+    ctime: SystemTime,
+    crtime: SystemTime,
+    kind: FileType,
+    perm: u16,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u32,
+    blksize: u32,
+    flags: u32,
+    compressed: bool,
+    generation: u64,
+    key_id: Option<u32>,
+}
+
+impl From<FileAttrV5> for FileAttrV10 {
+    fn from(value: FileAttrV5) -> Self {
+        Self {
+            ino: value.ino,
+            size: value.size,
+            blocks: value.blocks,
+            atime: value.atime,
+            mtime: value.mtime,
+            ctime: value.ctime,
+            crtime: value.crtime,
+            kind: value.kind,
+            perm: value.perm,
+            nlink: value.nlink,
+            uid: value.uid,
+            gid: value.gid,
+            rdev: value.rdev,
+            blksize: value.blksize,
+            flags: value.flags,
+            compressed: value.compressed,
+            generation: value.generation,
+            key_id: None,
+        }
+    }
+}
+
+impl From<FileAttrV10> for FileAttrV12 {
+    fn from(value: FileAttrV10) -> Self {
+        Self {
+            ino: value.ino,
+            size: value.size,
+            blocks: value.blocks,
+            atime: value.atime,
+            mtime: value.mtime,
+            ctime: value.ctime,
+            crtime: value.crtime,
+            kind: value.kind,
+            perm: value.perm,
+            nlink: value.nlink,
+            uid: value.uid,
+            gid: value.gid,
+            rdev: value.rdev,
+            blksize: value.blksize,
+            flags: value.flags,
+            compressed: value.compressed,
+            generation: value.generation,
+            key_id: value.key_id,
+            ino_generation: 0,
+        }
+    }
+}
+
+/// [`FileAttr`] as it was written in versions `11` to `13`, without [`FileAttr::quota`].
+#[derive(Serialize, Deserialize)]
+struct FileAttrV12 {
+    ino: u64,
+    size: u64,
+    blocks: u64,
+    atime: SystemTime,
+    mtime: SystemTime,
+    ctime: SystemTime,
+    crtime: SystemTime,
+    kind: FileType,
+    perm: u16,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u32,
+    blksize: u32,
+    flags: u32,
+    compressed: bool,
+    generation: u64,
+    key_id: Option<u32>,
+    ino_generation: u64,
+}
+
+impl From<FileAttrV12> for FileAttr {
+    fn from(value: FileAttrV12) -> Self {
+        Self {
+            ino: value.ino,
+            size: value.size,
+            blocks: value.blocks,
+            atime: value.atime,
+            mtime: value.mtime,
+            ctime: value.ctime,
+            crtime: value.crtime,
+            kind: value.kind,
+            perm: value.perm,
+            nlink: value.nlink,
+            uid: value.uid,
+            gid: value.gid,
+            rdev: value.rdev,
+            blksize: value.blksize,
+            flags: value.flags,
+            compressed: value.compressed,
+            generation: value.generation,
+            key_id: value.key_id,
+            ino_generation: value.ino_generation,
+            quota: None,
+        }
+    }
+}
+
+/// Rewrite the inodes with [`FileAttr::compressed`], the files written before it are not compressed.
+///
+/// Inodes rewritten by an interrupted run read as a [`FileAttrV4`], so it can be resumed.
+async fn migrate_to_compression_flag(
+    storage: &dyn Storage,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<()> {
+    let inodes_dir = Path::new(INODES_DIR);
+    for ino in sharded_inodes(storage, inodes_dir).await? {
+        let path = shard_path(inodes_dir, ino);
+        let aad = inode_aad(ino);
+        let data = storage.read(&path).await?;
+        match deserialize_bound::<FileAttrV4, _>(data.as_slice(), cipher, key, &aad) {
+            Ok(_) => continue,
+            Err(FsError::IntegrityViolation) => return Err(FsError::IntegrityViolation),
+            // the previous layout ends before the flag
+            Err(_) => {}
+        }
+        let attr: FileAttrV4 =
+            deserialize_bound::<FileAttrV3, _>(data.as_slice(), cipher, key, &aad)?.into();
+        serialize_bound_into(storage, &path, &attr, cipher, key, &aad).await?;
+    }
+    Ok(())
+}
+
+/// Rewrite the inodes with [`FileAttr::generation`], the ones written before it have generation `0`.
+///
+/// Inodes rewritten by an interrupted run read as a [`FileAttrV5`], so it can be resumed.
+async fn migrate_to_generation(
+    storage: &dyn Storage,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<()> {
+    let inodes_dir = Path::new(INODES_DIR);
+    for ino in sharded_inodes(storage, inodes_dir).await? {
+        let path = shard_path(inodes_dir, ino);
+        let aad = inode_aad(ino);
+        let data = storage.read(&path).await?;
+        match deserialize_bound::<FileAttrV5, _>(data.as_slice(), cipher, key, &aad) {
+            Ok(_) => continue,
+            Err(FsError::IntegrityViolation) => return Err(FsError::IntegrityViolation),
+            // the previous layout ends before the generation
+            Err(_) => {}
+        }
+        let attr: FileAttrV5 =
+            deserialize_bound::<FileAttrV4, _>(data.as_slice(), cipher, key, &aad)?.into();
+        serialize_bound_into(storage, &path, &attr, cipher, key, &aad).await?;
+    }
+    Ok(())
+}
+
+/// Rewrite the inodes with [`FileAttr::key_id`], the ones written before it are encrypted with the master key.
+///
+/// Inodes rewritten by an interrupted run read as a [`FileAttr`], so it can be resumed.
+async fn migrate_to_key_id(
+    storage: &dyn Storage,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<()> {
+    let inodes_dir = Path::new(INODES_DIR);
+    for ino in sharded_inodes(storage, inodes_dir).await? {
+        let path = shard_path(inodes_dir, ino);
+        let aad = inode_aad(ino);
+        let data = storage.read(&path).await?;
+        match deserialize_bound::<FileAttrV10, _>(data.as_slice(), cipher, key, &aad) {
+            Ok(_) => continue,
+            Err(FsError::IntegrityViolation) => return Err(FsError::IntegrityViolation),
+            // the previous layout ends before the key id
+            Err(_) => {}
+        }
+        let attr: FileAttrV10 =
+            deserialize_bound::<FileAttrV5, _>(data.as_slice(), cipher, key, &aad)?.into();
+        serialize_bound_into(storage, &path, &attr, cipher, key, &aad).await?;
+    }
+    Ok(())
+}
+
+/// Rewrite the inodes with [`FileAttr::ino_generation`], the ones written before it have `0`.
+///
+/// Inodes rewritten by an interrupted run read as a [`FileAttrV12`], so it can be resumed.
+async fn migrate_to_ino_generation(
+    storage: &dyn Storage,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<()> {
+    let inodes_dir = Path::new(INODES_DIR);
+    for ino in sharded_inodes(storage, inodes_dir).await? {
+        let path = shard_path(inodes_dir, ino);
+        let aad = inode_aad(ino);
+        let data = storage.read(&path).await?;
+        match deserialize_bound::<FileAttrV12, _>(data.as_slice(), cipher, key, &aad) {
+            Ok(_) => continue,
+            Err(FsError::IntegrityViolation) => return Err(FsError::IntegrityViolation),
+            // the previous layout ends before the inode generation
+            Err(_) => {}
+        }
+        let attr: FileAttrV12 =
+            deserialize_bound::<FileAttrV10, _>(data.as_slice(), cipher, key, &aad)?.into();
+        serialize_bound_into(storage, &path, &attr, cipher, key, &aad).await?;
+    }
+    Ok(())
+}
+
+/// Keep the holes of the contents of the files in their inodes, after the attributes, see
+/// [`EncryptedFs::contents_holes`]. Until now any block of zeros was read as a hole, so they are the blocks of zeros of
+/// the contents, found without decrypting them. The inodes which already have them are skipped, so it can be resumed.
+async fn migrate_to_inode_holes(
+    storage: &dyn Storage,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<()> {
+    let inodes_dir = Path::new(INODES_DIR);
+    for ino in sharded_inodes(storage, inodes_dir).await? {
+        let path = shard_path(inodes_dir, ino);
+        let aad = inode_aad(ino);
+        let data = storage.read(&path).await?;
+        match deserialize_bound::<(FileAttrV12, Holes), _>(data.as_slice(), cipher, key, &aad) {
+            Ok(_) => continue,
+            Err(FsError::IntegrityViolation) => return Err(FsError::IntegrityViolation),
+            // the previous layout ends after the attributes
+            Err(_) => {}
+        }
+        let attr: FileAttrV12 = deserialize_bound(data.as_slice(), cipher, key, &aad)?;
+        let holes = if attr.kind == FileType::RegularFile {
+            zero_blocks(storage, &shard_path(Path::new(CONTENTS_DIR), ino), cipher).await?
+        } else {
+            Holes::default()
+        };
+        serialize_bound_into(storage, &path, &(attr, holes), cipher, key, &aad).await?;
+    }
+    Ok(())
+}
+
+/// Log the inodes changed so far, in the order of their generations, so [`EncryptedFs::changes_since`] reads only the
+/// log. The ones written before the generations have `0` and are not in. The log is written again from the start, so it
+/// can be resumed.
+async fn migrate_to_change_log(
+    storage: &dyn Storage,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<()> {
+    let inodes_dir = Path::new(INODES_DIR);
+    let mut changes = vec![];
+    for ino in sharded_inodes(storage, inodes_dir).await? {
+        let attr: FileAttrV12 = deserialize_bound(
+            storage.open(&shard_path(inodes_dir, ino), false).await?,
+            cipher,
+            key,
+            &inode_aad(ino),
+        )?;
+        if attr.generation > 0 {
+            changes.push((attr.generation, ino, attr.kind));
+        }
+    }
+    changes.sort_unstable_by_key(|(generation, _, _)| *generation);
+    let (log, segments) = changes::log_of(&changes);
+    changes::write_log(
+        storage,
+        &changes::changes_dir(),
+        &log,
+        &segments,
+        cipher,
+        key,
+    )
+    .await
+}
+
+/// Rewrite the inodes with [`FileAttr::quota`], the limit of the directories which had one in the
+/// [`quota::QUOTA_FILENAME`] of their contents dir, which is removed. The total size in
+/// [`quota::USED_BYTES_FILENAME`] was not encrypted, it's removed and counted again when opened.
+///
+/// Inodes rewritten by an interrupted run are longer than a [`FileAttrV12`] with the holes, so it
+/// can be resumed.
+async fn migrate_to_inode_quota(
+    storage: &dyn Storage,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<()> {
+    let inodes_dir = Path::new(INODES_DIR);
+    for ino in sharded_inodes(storage, inodes_dir).await? {
+        let path = shard_path(inodes_dir, ino);
+        let aad = inode_aad(ino);
+        let mut data = vec![];
+        crypto::create_read_with_aad(storage.open(&path, false).await?, cipher, key, &aad)
+            .read_to_end(&mut data)
+            .map_err(|err| {
+                if err.kind() == io::ErrorKind::InvalidData {
+                    error!(err = %err, "authenticating");
+                    FsError::IntegrityViolation
+                } else {
+                    err.into()
+                }
+            })?;
+        // a new one can read as the previous layout, with the quota read as holes, but not with the same length
+        let (attr, holes): (FileAttrV12, Holes) = match bincode::deserialize(&data) {
+            Ok(inode) if bincode::serialized_size(&inode)? == data.len() as u64 => inode,
+            _ => continue,
+        };
+        let mut attr: FileAttr = attr.into();
+        let quota_path = shard_path(Path::new(CONTENTS_DIR), ino).join(quota::QUOTA_FILENAME);
+        let has_quota = storage.kind(&quota_path).await? == Some(EntryKind::File);
+        if has_quota {
+            attr.quota = Some(deserialize_bound(
+                storage.open(&quota_path, false).await?,
+                cipher,
+                key,
+                &quota::quota_aad(ino),
+            )?);
+        }
+        serialize_bound_into(storage, &path, &(attr, holes), cipher, key, &aad).await?;
+        if has_quota {
+            storage.remove(&quota_path).await?;
+        }
+    }
+    let used_path = quota::used_bytes_path();
+    if storage.kind(&used_path).await?.is_some() {
+        storage.remove(&used_path).await?;
+    }
+    Ok(())
+}
+
+/// The blocks of the contents at `path` which are all zeros in the storage, with the segments of
+/// [`cipher_segment_size`]. What a segment before the last one is missing reads as zeros, see [`SegmentedFile`].
+async fn zero_blocks(storage: &dyn Storage, path: &Path, cipher: Cipher) -> FsResult<Holes> {
+    let block_len = cipher.ciphertext_block_size();
+    let segment_blocks = cipher_segment_size(cipher) / block_len;
+    let mut count = 0;
+    while storage
+        .kind(&SegmentedFile::segment_path(path, count))
+        .await?
+        .is_some()
+    {
+        count += 1;
+    }
+    let mut holes = Holes::default();
+    let mut buf = vec![0; usize::try_from(block_len).map_err(io::Error::other)?];
+    for index in 0..count {
+        let first = index as u64 * segment_blocks;
+        let mut segment = storage
+            .open(&SegmentedFile::segment_path(path, index), false)
+            .await?;
+        let blocks = if index + 1 < count {
+            segment_blocks
+        } else {
+            segment.len()?.div_ceil(block_len)
+        };
+        for block in first..first + blocks {
+            let len = stream_util::read(&mut segment, &mut buf)?;
+            if buf[..len].iter().all(|b| *b == 0) {
+                holes.insert(block..block + 1);
+            }
+        }
+    }
+    Ok(holes)
+}
+
+/// Encode the names in [`LS_DIR`] and [`HASH_DIR`] of all directories like [`crypto::encrypt_file_name`] does now,
+/// base32 instead of base64, and rename the entries of `.` and `..`, so they are valid names on any filesystem. The
+/// names are not decrypted, so the directories with a locked directory key are migrated too.
+///
+/// The new entries are written in temp dirs, then the old `ls` is removed and the temp dirs replace the old ones. A
+/// directory whose `ls` still has the old entry of `.` is not migrated, else an interrupted run completes the renames,
+/// so it can be resumed.
+async fn migrate_to_portable_names(
+    storage: &dyn Storage,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<()> {
+    let contents_dir = Path::new(CONTENTS_DIR);
+    for ino in sharded_inodes(storage, contents_dir).await? {
+        let dir = shard_path(contents_dir, ino);
+        let ls_dir = dir.join(LS_DIR);
+        let hash_dir = dir.join(HASH_DIR);
+        let tmp_ls_dir = dir.join(format!("{LS_DIR}.tmp"));
+        let tmp_hash_dir = dir.join(format!("{HASH_DIR}.tmp"));
+        let mut changed = false;
+        if storage
+            .kind(&ls_dir.join(LEGACY_SELF_ENTRY_NAME))
+            .await?
+            .is_some()
+        {
+            for tmp_dir in [&tmp_ls_dir, &tmp_hash_dir] {
+                if storage.kind(tmp_dir).await?.is_some() {
+                    storage.remove_dir(tmp_dir).await?;
+                }
+                storage.create_dir(tmp_dir).await?;
+            }
+            for entry in storage.list(&ls_dir).await? {
+                let (entry_ino, kind, encrypted_name) = deserialize_ls_entry(
+                    storage.open(&ls_dir.join(&entry.name), false).await?,
+                    ino,
+                    &entry.name,
+                    cipher,
+                    key,
+                )?;
+                let encrypted_name = crypto::reencode_legacy_file_name(&encrypted_name)?;
+                let (ls_name, data) =
+                    serialize_ls_entry(ino, &encrypted_name, entry_ino, kind, cipher, key)?;
+                storage.write(&tmp_ls_dir.join(ls_name), &data).await?;
+            }
+            for entry in storage.list(&hash_dir).await? {
+                let (entry_ino, kind, encrypted_name): (u64, FileType, String) = deserialize_bound(
+                    storage.open(&hash_dir.join(&entry.name), false).await?,
+                    cipher,
+                    key,
+                    &dir_entry_aad(ino, &entry.name),
+                )?;
+                // the hashes of `.` and `..` are their names
+                let hash = match entry.name.as_str() {
+                    LEGACY_SELF_ENTRY_NAME | LEGACY_PARENT_ENTRY_NAME => {
+                        crypto::reencode_legacy_file_name(&entry.name)?
+                    }
+                    _ => entry.name,
+                };
+                serialize_bound_into(
+                    storage,
+                    &tmp_hash_dir.join(&hash),
+                    &(
+                        entry_ino,
+                        kind,
+                        crypto::reencode_legacy_file_name(&encrypted_name)?,
+                    ),
+                    cipher,
+                    key,
+                    &dir_entry_aad(ino, &hash),
+                )
+                .await?;
+            }
+            storage.sync(&tmp_ls_dir).await?;
+            storage.sync(&tmp_hash_dir).await?;
+            storage.remove_dir(&ls_dir).await?;
+            changed = true;
+        }
+        if storage.kind(&ls_dir).await?.is_none() && storage.kind(&tmp_ls_dir).await?.is_some() {
+            storage.rename(&tmp_ls_dir, &ls_dir).await?;
+            changed = true;
+        }
+        if storage.kind(&tmp_hash_dir).await?.is_some() {
+            if storage.kind(&hash_dir).await?.is_some() {
+                storage.remove_dir(&hash_dir).await?;
+            }
+            storage.rename(&tmp_hash_dir, &hash_dir).await?;
+            changed = true;
+        }
+        if changed {
+            storage.sync(&dir).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Re-encrypt the `T` at `path` with `aad`, unless it already is.
+async fn rebind<T: Serialize + DeserializeOwned>(
+    storage: &dyn Storage,
+    path: &Path,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    aad: &[u8],
+) -> FsResult<()> {
+    let data = storage.read(path).await?;
+    match deserialize_bound::<T, _>(data.as_slice(), cipher, key, &[]) {
+        Ok(value) => serialize_bound_into(storage, path, &value, cipher, key, aad).await,
+        Err(FsError::IntegrityViolation) => {
+            deserialize_bound::<T, _>(data.as_slice(), cipher, key, aad).map(|_| ())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+async fn check_structure(storage: &dyn Storage, ignore_empty: bool) -> FsResult<()> {
+    if storage.kind(Path::new("")).await? != Some(EntryKind::Dir) {
+        return Err(FsError::InvalidDataDirStructure);
+    }
+    let mut vec = storage
+        .list(Path::new(""))
+        .await?
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect::<Vec<String>>();
+    if vec.is_empty() && ignore_empty {
+        return Ok(());
+    }
+    if cfg!(feature = "snapshots") {
+        vec.retain(|name| name != SNAPSHOTS_DIR);
+    }
+    if vec.len() != 3 {
+        return Err(FsError::InvalidDataDirStructure);
+    }
+    // make sure existing structure is ok
+    vec.sort_unstable();
+    let mut vec2 = vec![INODES_DIR, CONTENTS_DIR, SECURITY_DIR];
+    vec2.sort_unstable();
+    if vec != vec2
+        || storage.kind(&key_path()).await? != Some(EntryKind::File)
+        || (key_slots::is_legacy_key_file(storage, &key_path()).await?
+            && storage
+                .kind(&Path::new(SECURITY_DIR).join(KEY_SALT_FILENAME))
+                .await?
+                != Some(EntryKind::File))
+    {
+        return Err(FsError::InvalidDataDirStructure);
+    }
+    let version = read_format_version(storage).await?;
+    if version > FORMAT_VERSION {
+        return Err(FsError::UnsupportedVersion(version));
+    }
+
+    Ok(())
+}
+
+/// The times set on a directory when adding or removing an entry at `time`.
+fn dir_times(time: SystemTime) -> SetFileAttr {
+    SetFileAttr::default()
+        .with_mtime(time)
+        .with_ctime(time)
+        .with_atime(time)
+}
+
+/// Take the times of `other` if it was changed after `times`, by ctime, then by atime for the reads which don't
+/// change the ctime, see [`EncryptedFs::get_attr`].
+fn take_newer_times(times: &mut TimesFileAttr, other: &TimesFileAttr) {
+    if (other.ctime, other.atime) > (times.ctime, times.atime) {
+        times.atime = other.atime;
+        times.mtime = other.mtime;
+        times.ctime = other.ctime;
+    }
+}
+
+/// Seek `reader` to `offset` to read from it. `false` if `offset` is past the end of the file, where the reads return
+/// nothing, and [`FsError::SeekFailed`] if it stopped before `offset` inside the file.
+fn seek_for_read(reader: &mut (impl Seek + ?Sized), offset: u64) -> FsResult<bool> {
+    let reached = reader.seek(SeekFrom::Start(offset))?;
+    if reached == offset {
+        return Ok(true);
+    }
+    if offset >= reader.seek(SeekFrom::End(0))? {
+        return Ok(false);
+    }
+    Err(FsError::SeekFailed {
+        requested: offset,
+        reached,
+    })
+}
+
+fn merge_attr(attr: &mut FileAttr, set_attr: &SetFileAttr, overwrite_size: bool) {
+    if let Some(size) = set_attr.size {
+        if overwrite_size {
+            attr.size = size;
+        } else {
+            attr.size = attr.size.max(size);
+        }
+    }
+    if let Some(atime) = set_attr.atime {
+        attr.atime = attr.atime.max(atime);
     }
     if let Some(mtime) = set_attr.mtime {
         attr.mtime = attr.mtime.max(mtime);
@@ -2630,39 +7294,37 @@ fn merge_attr(attr: &mut FileAttr, set_attr: &SetFileAttr, overwrite_size: bool)
     if let Some(gid) = set_attr.gid {
         attr.gid = gid;
     }
+    if let Some(rdev) = set_attr.rdev {
+        attr.rdev = rdev;
+    }
     if let Some(flags) = set_attr.flags {
         attr.flags = flags;
     }
 }
 
+/// Write the whole string starting at `offset`, see [`EncryptedFs::write_all`].
+#[deprecated(
+    since = "0.15.0",
+    note = "use `EncryptedFs::write_all` with `s.as_bytes()`"
+)]
 pub async fn write_all_string_to_fs(
     fs: &EncryptedFs,
     ino: u64,
     offset: u64,
     s: &str,
     fh: u64,
-) -> FsResult<()> {
-    write_all_bytes_to_fs(fs, ino, offset, s.as_bytes(), fh).await
+) -> FsResult<usize> {
+    fs.write_all(ino, offset, s.as_bytes(), fh).await
 }
 
-#[allow(clippy::missing_panics_doc)]
+/// Write the whole buffer starting at `offset`, see [`EncryptedFs::write_all`].
+#[deprecated(since = "0.15.0", note = "use `EncryptedFs::write_all`")]
 pub async fn write_all_bytes_to_fs(
     fs: &EncryptedFs,
     ino: u64,
     offset: u64,
     buf: &[u8],
     fh: u64,
-) -> FsResult<()> {
-    let mut pos = 0_usize;
-    loop {
-        let len = fs.write(ino, offset, &buf[pos..], fh).await?;
-        pos += len;
-        if pos == buf.len() {
-            break;
-        } else if len == 0 {
-            return Err(FsError::Other("Failed to write all bytes"));
-        }
-    }
-    fs.flush(fh).await?;
-    Ok(())
+) -> FsResult<usize> {
+    fs.write_all(ino, offset, buf, fh).await
 }
@@ -0,0 +1,530 @@
+//! Permission checks on behalf of a caller, see [`EncryptedFs::with_context`].
+//!
+//! The methods of [`EncryptedFs`] don't check the permissions, they are made by whoever owns the data dir. When it's
+//! shared with other users, like through a mount, the operations go through [`ContextFs`] which checks the mode, the
+//! owner and the group of the files against the [`RequestContext`] of the caller first, like a POSIX filesystem
+//! would. Root can read and write anything, and execute what has at least one of the execute bits.
+//!
+//! The SUID, SGID and sticky bits follow POSIX too: changing the contents or the owner of a file clears the SUID and
+//! SGID bits unless root does it, new nodes in a directory with the SGID bit get its group, and only the owners may
+//! remove the entries of a directory with the sticky bit.
+
+use std::time::SystemTime;
+
+use shush_rs::SecretString;
+
+use crate::encryptedfs::{
+    CopyFileRangeReq, CreateFileAttr, CreateFlags, DirectoryEntryIterator,
+    DirectoryEntryPlusIterator, DirectoryEntryResultIterator, EncryptedFs, FileAttr, FileType,
+    FsError, FsResult, OpenFlags, SetFileAttr, PERM_MASK,
+};
+
+/// Read permission, for [`RequestContext::can_access`].
+pub const ACCESS_READ: u32 = 4;
+/// Write permission, for [`RequestContext::can_access`].
+pub const ACCESS_WRITE: u32 = 2;
+/// Execute permission, or search for directories, for [`RequestContext::can_access`].
+pub const ACCESS_EXEC: u32 = 1;
+
+const S_ISUID: u16 = 0o4000;
+const S_ISGID: u16 = 0o2000;
+const S_ISVTX: u16 = 0o1000;
+const S_IXUGO: u16 = 0o111;
+const S_IXGRP: u16 = 0o010;
+
+/// A time to set with [`ContextFs::set_times`], like a `timespec` of `utimensat(2)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeOrNow {
+    SpecificTime(SystemTime),
+    /// The current time, like `UTIME_NOW`.
+    Now,
+}
+
+/// Who makes a request, the permissions are checked against it in [`ContextFs`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestContext {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: u32,
+    /// Supplementary groups, they get the permissions of the group of a file like [`RequestContext::gid`].
+    pub groups: Vec<u32>,
+}
+
+impl RequestContext {
+    #[must_use]
+    pub const fn new(uid: u32, gid: u32, pid: u32) -> Self {
+        Self {
+            uid,
+            gid,
+            pid,
+            groups: Vec::new(),
+        }
+    }
+
+    #[must_use]
+    pub fn with_groups(mut self, groups: Vec<u32>) -> Self {
+        self.groups = groups;
+        self
+    }
+
+    #[must_use]
+    pub const fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    /// If `gid` is the group of the caller or one of its supplementary groups.
+    #[must_use]
+    pub fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+
+    /// If the caller has all of the permissions in `mask` on `attr`, a combination of [`ACCESS_READ`],
+    /// [`ACCESS_WRITE`] and [`ACCESS_EXEC`].
+    ///
+    /// Only one class of the mode applies, the owner, then the group and then the others, like in POSIX.
+    #[must_use]
+    pub fn can_access(&self, attr: &FileAttr, mask: u32) -> bool {
+        let mode = u32::from(attr.perm);
+        if self.is_root() {
+            return mask & ACCESS_EXEC == 0 || mode & u32::from(S_IXUGO) != 0;
+        }
+        let granted = if self.uid == attr.uid {
+            mode >> 6
+        } else if self.in_group(attr.gid) {
+            mode >> 3
+        } else {
+            mode
+        };
+        mask & !granted & 0o7 == 0
+    }
+
+    const fn is_owner(&self, attr: &FileAttr) -> bool {
+        self.is_root() || self.uid == attr.uid
+    }
+}
+
+/// The operations of [`EncryptedFs`] which check the permissions of the caller first, see
+/// [`EncryptedFs::with_context`].
+///
+/// They fail with [`FsError::PermissionDenied`] when the mode doesn't allow it and with [`FsError::NotPermitted`]
+/// when only the owner or root may do it.
+pub struct ContextFs<'a> {
+    fs: &'a EncryptedFs,
+    ctx: RequestContext,
+}
+
+impl EncryptedFs {
+    /// Make the operations on behalf of `ctx`, checking its permissions.
+    ///
+    /// The methods of [`EncryptedFs`] itself don't check anything.
+    #[must_use]
+    pub const fn with_context(&self, ctx: RequestContext) -> ContextFs<'_> {
+        ContextFs { fs: self, ctx }
+    }
+}
+
+#[allow(clippy::missing_errors_doc)]
+impl ContextFs<'_> {
+    #[must_use]
+    pub const fn context(&self) -> &RequestContext {
+        &self.ctx
+    }
+
+    /// Check the permissions in `mask` on `ino`, like `access(2)`.
+    pub async fn access(&self, ino: u64, mask: u32) -> FsResult<()> {
+        self.check(ino, mask).await.map(|_| ())
+    }
+
+    /// Like [`EncryptedFs::find_by_name`], needs search permission on `parent`.
+    pub async fn find_by_name(
+        &self,
+        parent: u64,
+        name: &SecretString,
+    ) -> FsResult<Option<FileAttr>> {
+        self.check(parent, ACCESS_EXEC).await?;
+        self.fs.find_by_name(parent, name).await
+    }
+
+    /// Like [`EncryptedFs::create`], needs write and search permission on `parent`.
+    ///
+    /// The new node is owned by the caller, with the group of `parent` if it has the SGID bit, which new directories
+    /// also get. The SGID bit in `create_attr` is cleared if the caller is not in the group of the new file and is not
+    /// root.
+    pub async fn create(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        create_attr: CreateFileAttr,
+        read: bool,
+        write: bool,
+    ) -> FsResult<(u64, FileAttr)> {
+        let parent_attr = self.check(parent, ACCESS_WRITE | ACCESS_EXEC).await?;
+        self.fs
+            .create(
+                parent,
+                name,
+                self.owned(&parent_attr, create_attr),
+                read,
+                write,
+            )
+            .await
+    }
+
+    /// Like [`EncryptedFs::create_with_flags`], an existing file which is opened needs the permissions to open it
+    /// instead of the ones to create it.
+    pub async fn create_with_flags(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        create_attr: CreateFileAttr,
+        flags: CreateFlags,
+    ) -> FsResult<(u64, FileAttr)> {
+        let parent_attr = self.check(parent, ACCESS_EXEC).await?;
+        let existing = if flags.exclusive {
+            None
+        } else {
+            self.fs.find_by_name(parent, name).await?
+        };
+        match existing {
+            Some(attr) => self.check_attr(&attr, open_mask(flags.read, flags.write))?,
+            None => self.check_attr(&parent_attr, ACCESS_WRITE | ACCESS_EXEC)?,
+        }
+        self.fs
+            .create_with_flags(parent, name, self.owned(&parent_attr, create_attr), flags)
+            .await
+    }
+
+    /// Like [`EncryptedFs::open`], needs read or write permission on `ino` for what it's opened for.
+    pub async fn open(&self, ino: u64, read: bool, write: bool) -> FsResult<u64> {
+        self.check(ino, open_mask(read, write)).await?;
+        self.fs.open(ino, read, write).await
+    }
+
+    /// Like [`EncryptedFs::open_with_flags`], see [`ContextFs::open`].
+    pub async fn open_with_flags(&self, ino: u64, flags: OpenFlags) -> FsResult<u64> {
+        self.check(ino, open_mask(flags.read, flags.write)).await?;
+        self.fs.open_with_flags(ino, flags).await
+    }
+
+    /// Like [`EncryptedFs::remove_file`], needs write and search permission on `parent`. When it has the sticky bit
+    /// only the owner of the file or of `parent` may remove it.
+    pub async fn remove_file(&self, parent: u64, name: &SecretString) -> FsResult<()> {
+        self.check_remove(parent, name).await?;
+        self.fs.remove_file(parent, name).await
+    }
+
+    /// Like [`EncryptedFs::remove_dir`], see [`ContextFs::remove_file`].
+    pub async fn remove_dir(&self, parent: u64, name: &SecretString) -> FsResult<()> {
+        self.check_remove(parent, name).await?;
+        self.fs.remove_dir(parent, name).await
+    }
+
+    /// Like [`EncryptedFs::rename`], it's removed from `parent` and added to `new_parent`, so both are checked like
+    /// in [`ContextFs::remove_file`]. A directory moved to another parent also needs write permission on itself, as
+    /// its `..` changes.
+    pub async fn rename(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        new_parent: u64,
+        new_name: &SecretString,
+    ) -> FsResult<()> {
+        let attr = self.check_remove(parent, name).await?;
+        let new_parent_attr = self.check(new_parent, ACCESS_WRITE | ACCESS_EXEC).await?;
+        if let Some(existing) = self.fs.find_by_name(new_parent, new_name).await? {
+            self.check_sticky(&new_parent_attr, &existing)?;
+        }
+        if attr.kind == FileType::Directory && parent != new_parent {
+            self.check_attr(&attr, ACCESS_WRITE)?;
+        }
+        self.fs.rename(parent, name, new_parent, new_name).await
+    }
+
+    /// Like [`EncryptedFs::link`], needs write and search permission on `new_parent`.
+    pub async fn link(
+        &self,
+        ino: u64,
+        new_parent: u64,
+        new_name: &SecretString,
+    ) -> FsResult<FileAttr> {
+        self.check(new_parent, ACCESS_WRITE | ACCESS_EXEC).await?;
+        self.fs.link(ino, new_parent, new_name).await
+    }
+
+    /// Like [`EncryptedFs::set_attr`].
+    ///
+    /// Only the owner may change the mode, the SGID bit is cleared if it's not in the group of the file. Only root
+    /// may change the owner, the owner may change the group to one it's in. Changing either clears the SUID and SGID
+    /// bits. The size needs write permission. Explicit times need the owner, setting them to the current time with
+    /// [`SetFileAttr::times_now`] needs the owner or write permission.
+    pub async fn set_attr(&self, ino: u64, set_attr: SetFileAttr) -> FsResult<()> {
+        let attr = self.fs.get_attr(ino).await?;
+        let set_attr = self.check_set_attr(&attr, set_attr)?;
+        self.fs.set_attr(ino, set_attr).await
+    }
+
+    /// Like [`EncryptedFs::set_permissions`], only the owner may do it. The SGID bit is cleared if it's not in the
+    /// group of the file.
+    pub async fn set_permissions(&self, ino: u64, mode: u16) -> FsResult<()> {
+        let attr = self.fs.get_attr(ino).await?;
+        let mode = self.check_chmod(&attr, mode, None)?;
+        self.fs.set_permissions(ino, mode).await
+    }
+
+    /// Like [`EncryptedFs::set_owner`]. Only root may change the owner, the owner may change the group to one it's
+    /// in. Either clears the SUID and SGID bits.
+    pub async fn set_owner(&self, ino: u64, uid: Option<u32>, gid: Option<u32>) -> FsResult<()> {
+        let attr = self.fs.get_attr(ino).await?;
+        let perm = self.check_chown(&attr, uid, gid)?;
+        self.fs.set_owner(ino, uid, gid).await?;
+        if perm != attr.perm {
+            self.fs.set_permissions(ino, perm & PERM_MASK).await?;
+        }
+        Ok(())
+    }
+
+    /// Like [`EncryptedFs::set_times`]. Explicit times need the owner, setting them to [`TimeOrNow::Now`] needs the
+    /// owner or write permission, like `utimensat(2)`.
+    pub async fn set_times(
+        &self,
+        ino: u64,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+    ) -> FsResult<()> {
+        let attr = self.fs.get_attr(ino).await?;
+        let explicit = [atime, mtime]
+            .into_iter()
+            .any(|time| matches!(time, Some(TimeOrNow::SpecificTime(_))));
+        self.check_times(&attr, !explicit)?;
+        let now = SystemTime::now();
+        let resolve = |time: TimeOrNow| match time {
+            TimeOrNow::SpecificTime(time) => time,
+            TimeOrNow::Now => now,
+        };
+        self.fs
+            .set_times(ino, atime.map(resolve), mtime.map(resolve))
+            .await
+    }
+
+    /// Like [`EncryptedFs::set_len`], needs write permission. It clears the SUID and SGID bits like
+    /// [`ContextFs::write`].
+    pub async fn set_len(&self, ino: u64, size: u64) -> FsResult<()> {
+        self.check(ino, ACCESS_WRITE).await?;
+        self.fs.set_len(ino, size).await?;
+        self.kill_priv(ino).await
+    }
+
+    /// Like [`EncryptedFs::fallocate`], needs write permission. If it extends the file it clears the SUID and SGID
+    /// bits like [`ContextFs::write`].
+    pub async fn fallocate(&self, ino: u64, offset: u64, len: u64) -> FsResult<()> {
+        let attr = self.check(ino, ACCESS_WRITE).await?;
+        self.fs.fallocate(ino, offset, len).await?;
+        if offset.saturating_add(len) > attr.size {
+            self.kill_priv(ino).await?;
+        }
+        Ok(())
+    }
+
+    /// Like [`EncryptedFs::write`], the permissions were checked when `handle` was opened.
+    ///
+    /// Unless the caller is root, writing something clears the SUID bit, and the SGID bit if the group can execute
+    /// the file, so it can't be changed and still run with the rights of its owner.
+    pub async fn write(&self, ino: u64, offset: u64, buf: &[u8], handle: u64) -> FsResult<usize> {
+        let len = self.fs.write(ino, offset, buf, handle).await?;
+        if len > 0 {
+            self.kill_priv(ino).await?;
+        }
+        Ok(len)
+    }
+
+    /// Like [`EncryptedFs::copy_file_range`], the destination is changed like in [`ContextFs::write`].
+    pub async fn copy_file_range(
+        &self,
+        file_range_req: &CopyFileRangeReq,
+        size: usize,
+    ) -> FsResult<usize> {
+        let len = self.fs.copy_file_range(file_range_req, size).await?;
+        if len > 0 {
+            self.kill_priv(file_range_req.dest_ino).await?;
+        }
+        Ok(len)
+    }
+
+    /// Like [`EncryptedFs::read_dir`], needs read permission on `ino`.
+    pub async fn read_dir(&self, ino: u64) -> FsResult<DirectoryEntryIterator> {
+        self.check(ino, ACCESS_READ).await?;
+        self.fs.read_dir(ino).await
+    }
+
+    /// Like [`EncryptedFs::read_dir_tolerant`], see [`ContextFs::read_dir`].
+    pub async fn read_dir_tolerant(&self, ino: u64) -> FsResult<DirectoryEntryResultIterator> {
+        self.check(ino, ACCESS_READ).await?;
+        self.fs.read_dir_tolerant(ino).await
+    }
+
+    /// Like [`EncryptedFs::read_dir_plus`], the attributes of the entries also need search permission on `ino`.
+    pub async fn read_dir_plus(&self, ino: u64) -> FsResult<DirectoryEntryPlusIterator> {
+        self.check(ino, ACCESS_READ | ACCESS_EXEC).await?;
+        self.fs.read_dir_plus(ino).await
+    }
+
+    async fn check(&self, ino: u64, mask: u32) -> FsResult<FileAttr> {
+        let attr = self.fs.get_attr(ino).await?;
+        self.check_attr(&attr, mask)?;
+        Ok(attr)
+    }
+
+    fn check_attr(&self, attr: &FileAttr, mask: u32) -> FsResult<()> {
+        if self.ctx.can_access(attr, mask) {
+            Ok(())
+        } else {
+            Err(FsError::PermissionDenied)
+        }
+    }
+
+    /// Check if `name` can be removed from `parent`, returns its attributes.
+    async fn check_remove(&self, parent: u64, name: &SecretString) -> FsResult<FileAttr> {
+        let parent_attr = self.check(parent, ACCESS_WRITE | ACCESS_EXEC).await?;
+        let Some(attr) = self.fs.find_by_name(parent, name).await? else {
+            return Err(self.fs.name_not_found(parent, name).await);
+        };
+        self.check_sticky(&parent_attr, &attr)?;
+        Ok(attr)
+    }
+
+    /// In a directory with the sticky bit only the owners of the entry or of the directory may remove or replace it.
+    fn check_sticky(&self, parent: &FileAttr, attr: &FileAttr) -> FsResult<()> {
+        if parent.perm & S_ISVTX != 0 && !self.ctx.is_owner(parent) && self.ctx.uid != attr.uid {
+            return Err(FsError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    /// Clear the SUID and SGID bits of `ino` after its contents changed, if the caller is not root.
+    async fn kill_priv(&self, ino: u64) -> FsResult<()> {
+        if self.ctx.is_root() {
+            return Ok(());
+        }
+        let attr = self.fs.get_attr(ino).await?;
+        let perm = clear_suid_sgid(attr.perm);
+        if perm != attr.perm {
+            self.fs
+                .set_attr(ino, SetFileAttr::default().with_perm(perm))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// The attributes of a new node in `parent` made by the caller.
+    fn owned(&self, parent: &FileAttr, mut create_attr: CreateFileAttr) -> CreateFileAttr {
+        create_attr.uid = self.ctx.uid;
+        if parent.perm & S_ISGID == 0 {
+            create_attr.gid = self.ctx.gid;
+        } else {
+            create_attr.gid = parent.gid;
+            if create_attr.kind == FileType::Directory {
+                create_attr.perm |= S_ISGID;
+            }
+        }
+        if create_attr.kind != FileType::Directory
+            && !self.ctx.is_root()
+            && !self.ctx.in_group(create_attr.gid)
+        {
+            create_attr.perm &= !S_ISGID;
+        }
+        create_attr
+    }
+
+    fn check_set_attr(&self, attr: &FileAttr, mut set_attr: SetFileAttr) -> FsResult<SetFileAttr> {
+        let ctx = &self.ctx;
+        if let Some(perm) = set_attr.perm {
+            set_attr.perm = Some(self.check_chmod(attr, perm, set_attr.gid)?);
+        }
+        if set_attr.uid.is_some() || set_attr.gid.is_some() {
+            let perm = self.check_chown(attr, set_attr.uid, set_attr.gid)?;
+            set_attr.perm = set_attr.perm.or(Some(perm));
+        }
+        if set_attr.size.is_some() {
+            self.check_attr(attr, ACCESS_WRITE)?;
+            if !ctx.is_root() {
+                set_attr.perm = Some(clear_suid_sgid(set_attr.perm.unwrap_or(attr.perm)));
+            }
+        }
+        if set_attr.atime.is_some() || set_attr.mtime.is_some() {
+            self.check_times(attr, set_attr.times_now)?;
+        }
+        Ok(set_attr)
+    }
+
+    /// Check if the caller may change the mode of `attr` to `perm`, with its group changed to `gid`. Returns the
+    /// mode to set, without the SGID bit if the caller is not in the group.
+    fn check_chmod(&self, attr: &FileAttr, perm: u16, gid: Option<u32>) -> FsResult<u16> {
+        let ctx = &self.ctx;
+        if !ctx.is_owner(attr) {
+            return Err(FsError::NotPermitted);
+        }
+        if !ctx.is_root() && !ctx.in_group(gid.unwrap_or(attr.gid)) {
+            return Ok(perm & !S_ISGID);
+        }
+        Ok(perm)
+    }
+
+    /// Check if the caller may change the owner and the group of `attr`. Returns its mode after the change, without
+    /// the SUID and SGID bits it clears.
+    fn check_chown(&self, attr: &FileAttr, uid: Option<u32>, gid: Option<u32>) -> FsResult<u16> {
+        let ctx = &self.ctx;
+        if let Some(uid) = uid {
+            // no-op changes by the owner are allowed
+            if !ctx.is_root() && !(uid == attr.uid && ctx.uid == attr.uid) {
+                return Err(FsError::NotPermitted);
+            }
+        }
+        if let Some(gid) = gid {
+            if !ctx.is_root() && (ctx.uid != attr.uid || !ctx.in_group(gid)) {
+                return Err(FsError::NotPermitted);
+            }
+        }
+        let mut perm = attr.perm;
+        if perm & S_IXUGO != 0 || uid.is_some() {
+            perm &= !S_ISUID;
+        }
+        // without group execute SGID means mandatory locking, only a new group clears it then
+        if perm & S_IXGRP != 0 || (gid.is_some() && !ctx.is_root()) {
+            perm &= !S_ISGID;
+        }
+        Ok(perm)
+    }
+
+    /// Check if the caller may set the times of `attr`, to the current time if `now`.
+    fn check_times(&self, attr: &FileAttr, now: bool) -> FsResult<()> {
+        if self.ctx.is_owner(attr) {
+            return Ok(());
+        }
+        // like `utimensat(2)`, others may only set them to the current time
+        if !now {
+            return Err(FsError::NotPermitted);
+        }
+        self.check_attr(attr, ACCESS_WRITE)
+    }
+}
+
+/// Without the SUID bit, and without the SGID bit if the group can execute the file. Without group execute SGID means
+/// mandatory locking, which is kept.
+const fn clear_suid_sgid(mut perm: u16) -> u16 {
+    perm &= !S_ISUID;
+    if perm & S_IXGRP != 0 {
+        perm &= !S_ISGID;
+    }
+    perm
+}
+
+const fn open_mask(read: bool, write: bool) -> u32 {
+    let mut mask = 0;
+    if read {
+        mask |= ACCESS_READ;
+    }
+    if write {
+        mask |= ACCESS_WRITE;
+    }
+    mask
+}
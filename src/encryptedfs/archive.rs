@@ -0,0 +1,608 @@
+//! Backups of the whole filesystem to a single encrypted file, see [`EncryptedFs::export_archive`].
+//!
+//! An archive starts with [`ARCHIVE_MAGIC`] and a header with the version, the cipher and the Argon2 params and salt
+//! of the archive key, then has a sequence of records each encrypted on its own, and ends with a MAC over all that
+//! came before it. A record is the attributes of a node with its name in its directory, another name of a file, or a
+//! chunk of the contents of a file, so it can be written and read as a stream of any size. Each record is bound to
+//! its position in the stream and the last one counts them, so records can't be reordered, dropped or cut.
+//!
+//! The names and contents are decrypted and encrypted again with the key derived from the archive password, which
+//! doesn't need to be the one of the data dir, so an archive can be restored in any data dir.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Cursor, Read, Write};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use argon2::password_hash::rand_core::RngCore;
+use argon2::Params;
+use serde::{Deserialize, Serialize};
+use shush_rs::{ExposeSecret, SecretBox, SecretString, SecretVec};
+use tokio::sync::RwLock;
+
+use crate::crypto;
+use crate::crypto::write::CryptoWriteSeek;
+use crate::crypto::Cipher;
+use crate::encryptedfs::import::RelaxSyncs;
+use crate::encryptedfs::{
+    deserialize_bound, CreateFileAttr, EncryptedFs, FileAttr, FileAttrV5, FileType, FsError,
+    FsEvent, FsResult, SetFileAttr, ROOT_INODE,
+};
+use crate::segmented_file::SegmentedFile;
+use crate::stream_util;
+
+/// Start of an archive.
+const ARCHIVE_MAGIC: &[u8] = b"rencfs-archive";
+
+/// Version of the archive format, archives with newer ones are rejected.
+///
+/// - `2`: [`Record::Hole`]
+const ARCHIVE_VERSION: u32 = 2;
+
+/// Max bytes of contents in a record.
+const CHUNK_LEN: usize = 1024 * 1024;
+
+/// Max length of an encrypted record, so a corrupted length doesn't make us allocate too much.
+const MAX_RECORD_LEN: usize = 2 * CHUNK_LEN;
+
+/// Max length of the header.
+const MAX_HEADER_LEN: usize = 1024;
+
+const SALT_LEN: usize = 16;
+
+/// Contexts to derive the keys of an archive from the one derived from its password.
+const ENCRYPTION_KEY_CONTEXT: &str = "rencfs 2026-10-15 archive encryption key";
+const MAC_KEY_CONTEXT: &str = "rencfs 2026-10-15 archive mac key";
+
+#[derive(Serialize, Deserialize)]
+struct ArchiveHeader {
+    version: u32,
+    cipher: Cipher,
+    salt: Vec<u8>,
+    /// Argon2 memory cost.
+    m_cost: u32,
+    /// Argon2 iterations.
+    t_cost: u32,
+    /// Argon2 parallelism.
+    p_cost: u32,
+}
+
+/// A record of an archive, the inodes are the ones in the exported data dir.
+///
+/// The attributes are kept without [`FileAttr::key_id`], the restored files get the key of the directory they are
+/// restored in.
+#[derive(Serialize, Deserialize)]
+enum Record {
+    /// The attributes of the root, always the first record.
+    Root { attr: FileAttrV5 },
+    /// A node named `name` in the directory `parent`, before the ones in it or its contents.
+    Node {
+        parent: u64,
+        name: String,
+        attr: FileAttrV5,
+    },
+    /// Another name of a file already in the archive.
+    Link { parent: u64, name: String, ino: u64 },
+    /// The next chunk of the contents of the file in the last [`Record::Node`].
+    Contents { ino: u64, data: Vec<u8> },
+    /// The last record, with the number of records before it.
+    End { records: u64 },
+    /// The next chunk of the contents of the file in the last [`Record::Node`], which is all zeros, so it's kept as a
+    /// hole when restored.
+    Hole { ino: u64, len: u64 },
+}
+
+struct ArchiveKeys {
+    encryption: SecretVec<u8>,
+    mac: SecretBox<[u8; 32]>,
+}
+
+impl ArchiveKeys {
+    fn derive(password: &SecretString, header: &ArchiveHeader) -> FsResult<Self> {
+        let params = Params::new(header.m_cost, header.t_cost, header.p_cost, None)
+            .map_err(|_| corrupted("invalid Argon2 params"))?;
+        let key = crypto::derive_key_with_params(password, header.cipher, &header.salt, params)?;
+        let mut encryption = vec![0; header.cipher.key_len()];
+        blake3::derive_key(
+            ENCRYPTION_KEY_CONTEXT,
+            &key.expose_secret(),
+            &mut encryption,
+        );
+        let mut mac = SecretBox::new(Box::new([0_u8; 32]));
+        blake3::derive_key(
+            MAC_KEY_CONTEXT,
+            &key.expose_secret(),
+            &mut mac.expose_secret_mut()[..],
+        );
+        Ok(Self {
+            encryption: SecretVec::new(Box::new(encryption)),
+            mac,
+        })
+    }
+}
+
+/// Writes the records and the MAC over everything written.
+struct ArchiveWriter<W: Write> {
+    writer: W,
+    mac: blake3::Hasher,
+    cipher: Cipher,
+    keys: ArchiveKeys,
+    records: u64,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    fn new(writer: W, password: &SecretString, cipher: Cipher) -> FsResult<Self> {
+        let mut salt = vec![0; SALT_LEN];
+        crypto::create_rng().fill_bytes(&mut salt);
+        let params = Params::default();
+        let header = ArchiveHeader {
+            version: ARCHIVE_VERSION,
+            cipher,
+            salt,
+            m_cost: params.m_cost(),
+            t_cost: params.t_cost(),
+            p_cost: params.p_cost(),
+        };
+        let keys = ArchiveKeys::derive(password, &header)?;
+        let mut archive = Self {
+            writer,
+            mac: blake3::Hasher::new_keyed(&keys.mac.expose_secret()),
+            cipher,
+            keys,
+            records: 0,
+        };
+        archive.write_all(ARCHIVE_MAGIC)?;
+        archive.write_frame(&bincode::serialize(&header)?)?;
+        Ok(archive)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.mac.update(buf);
+        self.writer.write_all(buf)
+    }
+
+    /// Write `data` after its length.
+    fn write_frame(&mut self, data: &[u8]) -> io::Result<()> {
+        let len = u32::try_from(data.len()).expect("records are smaller than MAX_RECORD_LEN");
+        self.write_all(&len.to_le_bytes())?;
+        self.write_all(data)
+    }
+
+    fn write_record(&mut self, record: &Record) -> FsResult<()> {
+        let data = crypto::serialize_encrypt_into(
+            Cursor::new(vec![]),
+            record,
+            self.cipher,
+            &self.keys.encryption,
+            &record_aad(self.records),
+        )?
+        .into_inner();
+        self.write_frame(&data)?;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Write the [`Record::End`] and the MAC.
+    fn finish(mut self) -> FsResult<W> {
+        self.write_record(&Record::End {
+            records: self.records,
+        })?;
+        let mac = self.mac.finalize();
+        self.writer.write_all(mac.as_bytes())?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads the records while computing the MAC over what is read.
+struct ArchiveReader<R: Read> {
+    reader: R,
+    mac: blake3::Hasher,
+    cipher: Cipher,
+    keys: ArchiveKeys,
+    records: u64,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    fn new(mut reader: R, password: &SecretString) -> FsResult<Self> {
+        let mut magic = vec![0; ARCHIVE_MAGIC.len()];
+        reader
+            .read_exact(&mut magic)
+            .map_err(|err| truncated_or(err, "header"))?;
+        if magic != ARCHIVE_MAGIC {
+            return Err(FsError::invalid_input("not an archive"));
+        }
+        let header_data = read_frame(&mut reader, MAX_HEADER_LEN)?;
+        let header: ArchiveHeader =
+            bincode::deserialize(&header_data).map_err(|_| corrupted("header"))?;
+        if header.version == 0 || header.version > ARCHIVE_VERSION {
+            return Err(FsError::invalid_input(format!(
+                "unsupported archive version {}",
+                header.version
+            )));
+        }
+        let keys = ArchiveKeys::derive(password, &header)?;
+        let mut mac = blake3::Hasher::new_keyed(&keys.mac.expose_secret());
+        mac.update(&magic);
+        update_frame(&mut mac, &header_data);
+        Ok(Self {
+            reader,
+            mac,
+            cipher: header.cipher,
+            keys,
+            records: 0,
+        })
+    }
+
+    fn read_record(&mut self) -> FsResult<Record> {
+        let data = read_frame(&mut self.reader, MAX_RECORD_LEN)?;
+        update_frame(&mut self.mac, &data);
+        let record = deserialize_bound(
+            Cursor::new(data),
+            self.cipher,
+            &self.keys.encryption,
+            &record_aad(self.records),
+        )
+        .map_err(|err| match err {
+            // nothing can be decrypted with the wrong password
+            FsError::IntegrityViolation if self.records == 0 => FsError::InvalidPassword,
+            FsError::IntegrityViolation => corrupted("record can't be authenticated"),
+            err => err,
+        })?;
+        self.records += 1;
+        Ok(record)
+    }
+
+    /// Check the count of [`Record::End`] and the MAC after it.
+    fn finish(&mut self, records: u64) -> FsResult<()> {
+        if records + 1 != self.records {
+            return Err(corrupted("wrong number of records"));
+        }
+        let expected = self.mac.finalize();
+        let mut mac = [0; 32];
+        self.reader
+            .read_exact(&mut mac)
+            .map_err(|err| truncated_or(err, "MAC"))?;
+        // constant time comparison
+        if expected != blake3::Hash::from(mac) {
+            return Err(corrupted("MAC doesn't match"));
+        }
+        if self.reader.read(&mut [0])? != 0 {
+            return Err(corrupted("data after the end"));
+        }
+        Ok(())
+    }
+}
+
+/// Read the data after its length.
+fn read_frame<R: Read>(reader: &mut R, max_len: usize) -> FsResult<Vec<u8>> {
+    let mut len = [0; 4];
+    reader
+        .read_exact(&mut len)
+        .map_err(|err| truncated_or(err, "record"))?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > max_len {
+        return Err(corrupted("record is too large"));
+    }
+    let mut data = vec![0; len];
+    reader
+        .read_exact(&mut data)
+        .map_err(|err| truncated_or(err, "record"))?;
+    Ok(data)
+}
+
+/// Add a frame read by [`read_frame`] to `mac`, like it was written.
+#[allow(clippy::cast_possible_truncation)]
+fn update_frame(mac: &mut blake3::Hasher, data: &[u8]) {
+    mac.update(&(data.len() as u32).to_le_bytes());
+    mac.update(data);
+}
+
+/// Associated data binding a record to its position.
+fn record_aad(index: u64) -> Vec<u8> {
+    [b"archive".as_slice(), &index.to_le_bytes()].concat()
+}
+
+fn corrupted(what: &str) -> FsError {
+    FsError::Corrupted {
+        what: format!("archive, {what}"),
+        ino: None,
+    }
+}
+
+/// [`corrupted`] if the archive ended before `what`, the error otherwise.
+fn truncated_or(err: io::Error, what: &str) -> FsError {
+    if err.kind() == io::ErrorKind::UnexpectedEof {
+        corrupted(&format!("truncated before the {what}"))
+    } else {
+        err.into()
+    }
+}
+
+/// The contents of a file being restored.
+struct RestoredFile {
+    /// The inode in the archive.
+    archive_ino: u64,
+    ino: u64,
+    size: u64,
+    atime: SystemTime,
+    mtime: SystemTime,
+    writer: Option<Box<dyn CryptoWriteSeek<SegmentedFile>>>,
+    written: u64,
+}
+
+#[derive(Default)]
+struct Restore {
+    /// The new inodes of the ones in the archive.
+    inodes: HashMap<u64, u64>,
+    /// The directories, their times are restored after their entries are added.
+    dirs: Vec<(u64, SystemTime, SystemTime)>,
+    files: Vec<u64>,
+    file: Option<RestoredFile>,
+}
+
+impl EncryptedFs {
+    /// Write a backup of everything to `writer` in a single file encrypted with `password`, see
+    /// [`EncryptedFs::import_archive`].
+    ///
+    /// It's written as a stream, so `writer` can be a pipe or a socket. Changes made while it runs may be in it or
+    /// not, unmount or open the data dir read-only for a consistent backup.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn export_archive(&self, writer: impl Write, password: SecretString) -> FsResult<()> {
+        let mut archive = ArchiveWriter::new(writer, &password, self.cipher)?;
+        archive.write_record(&Record::Root {
+            attr: self.get_attr(ROOT_INODE).await?.into(),
+        })?;
+        // files with more names are written once
+        let mut exported = HashSet::new();
+        let mut dirs = vec![ROOT_INODE];
+        while let Some(dir) = dirs.pop() {
+            for entry in self.read_dir(dir).await? {
+                let entry = entry?;
+                let name = entry.name.expose_secret();
+                if *name == "." || *name == ".." {
+                    continue;
+                }
+                if !exported.insert(entry.ino) {
+                    archive.write_record(&Record::Link {
+                        parent: dir,
+                        name: name.clone(),
+                        ino: entry.ino,
+                    })?;
+                    continue;
+                }
+                let attr = self.get_attr(entry.ino).await?;
+                archive.write_record(&Record::Node {
+                    parent: dir,
+                    name: name.clone(),
+                    attr: attr.into(),
+                })?;
+                match attr.kind {
+                    FileType::Directory => dirs.push(attr.ino),
+                    FileType::RegularFile => self.export_contents(&mut archive, &attr).await?,
+                    // only their attributes
+                    FileType::NamedPipe
+                    | FileType::CharDevice
+                    | FileType::BlockDevice
+                    | FileType::Socket => {}
+                }
+            }
+        }
+        archive.finish()?;
+        Ok(())
+    }
+
+    async fn export_contents<W: Write>(
+        &self,
+        archive: &mut ArchiveWriter<W>,
+        attr: &FileAttr,
+    ) -> FsResult<()> {
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(attr.ino, || RwLock::new(false));
+        let _read_guard = lock.read().await;
+        let mut reader = self.create_contents_reader(attr.ino).await?.take(attr.size);
+        loop {
+            // read at once, so its blocks are decrypted in parallel
+            let mut data = vec![0; CHUNK_LEN];
+            let len = stream_util::read(&mut reader, &mut data)?;
+            if len == 0 {
+                break;
+            }
+            data.truncate(len);
+            let record = if data.iter().all(|b| *b == 0) {
+                Record::Hole {
+                    ino: attr.ino,
+                    len: data.len() as u64,
+                }
+            } else {
+                Record::Contents {
+                    ino: attr.ino,
+                    data,
+                }
+            };
+            archive.write_record(&record)?;
+        }
+        Ok(())
+    }
+
+    /// Restore the backup written by [`EncryptedFs::export_archive`] in `reader`, we must be empty.
+    ///
+    /// The archive can come from a data dir with another cipher or password. It's verified while it's read, if it's
+    /// corrupted or truncated it stops with [`FsError::Corrupted`], keeping what was restored until then. Use
+    /// [`EncryptedFs::verify_archive`] to check it first.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn import_archive(&self, reader: impl Read, password: SecretString) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if self.len(ROOT_INODE).await? > 0 {
+            return Err(FsError::NotEmpty);
+        }
+        let mut archive = ArchiveReader::new(reader, &password)?;
+        let mut restore = Restore::default();
+        let relax = RelaxSyncs::new(self);
+        let res = self.restore_records(&mut archive, &mut restore).await;
+        drop(relax);
+        // write what was restored even on error
+        self.sync_imported(restore.files).await?;
+        res
+    }
+
+    /// Check that the archive in `reader` is complete and was not changed, without restoring it.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn verify_archive(reader: impl Read, password: SecretString) -> FsResult<()> {
+        let mut archive = ArchiveReader::new(reader, &password)?;
+        loop {
+            if let Record::End { records } = archive.read_record()? {
+                return archive.finish(records);
+            }
+        }
+    }
+
+    async fn restore_records<R: Read>(
+        &self,
+        archive: &mut ArchiveReader<R>,
+        restore: &mut Restore,
+    ) -> FsResult<()> {
+        loop {
+            let record = archive.read_record()?;
+            if !matches!(record, Record::Contents { .. } | Record::Hole { .. }) {
+                self.finish_restored_file(restore.file.take()).await?;
+            }
+            match record {
+                Record::Root { attr } => {
+                    if archive.records != 1 {
+                        return Err(corrupted("root is not the first record"));
+                    }
+                    self.update_attr(
+                        ROOT_INODE,
+                        SetFileAttr {
+                            perm: Some(attr.perm),
+                            uid: Some(attr.uid),
+                            gid: Some(attr.gid),
+                            flags: Some(attr.flags),
+                            ..Default::default()
+                        },
+                    )
+                    .await?;
+                    restore.inodes.insert(attr.ino, ROOT_INODE);
+                    restore.dirs.push((ROOT_INODE, attr.atime, attr.mtime));
+                }
+                Record::Node { parent, name, attr } => {
+                    let parent = restored_ino(restore, parent)?;
+                    let (_, new_attr) = self
+                        .create(
+                            parent,
+                            &SecretString::from_str(&name).unwrap(),
+                            CreateFileAttr {
+                                kind: attr.kind,
+                                perm: attr.perm,
+                                uid: attr.uid,
+                                gid: attr.gid,
+                                rdev: attr.rdev,
+                                flags: attr.flags,
+                                compress: Some(attr.compressed),
+                            },
+                            false,
+                            false,
+                        )
+                        .await?;
+                    restore.inodes.insert(attr.ino, new_attr.ino);
+                    match attr.kind {
+                        FileType::Directory => {
+                            restore.dirs.push((new_attr.ino, attr.atime, attr.mtime));
+                        }
+                        FileType::RegularFile => {
+                            self.quota_resize(new_attr.ino, 0, attr.size).await?;
+                            restore.files.push(new_attr.ino);
+                            restore.file = Some(RestoredFile {
+                                archive_ino: attr.ino,
+                                ino: new_attr.ino,
+                                size: attr.size,
+                                atime: attr.atime,
+                                mtime: attr.mtime,
+                                writer: None,
+                                written: 0,
+                            });
+                        }
+                        FileType::NamedPipe
+                        | FileType::CharDevice
+                        | FileType::BlockDevice
+                        | FileType::Socket => {
+                            self.restore_times(new_attr.ino, None, attr.atime, attr.mtime)
+                                .await?;
+                        }
+                    }
+                }
+                Record::Link { parent, name, ino } => {
+                    let parent = restored_ino(restore, parent)?;
+                    let ino = restored_ino(restore, ino)?;
+                    self.link(ino, parent, &SecretString::from_str(&name).unwrap())
+                        .await?;
+                }
+                Record::Contents { ino, data } => {
+                    let Some(file) = restore.file.as_mut().filter(|file| file.archive_ino == ino)
+                    else {
+                        return Err(corrupted("contents not after their file"));
+                    };
+                    if file.writer.is_none() {
+                        file.writer = Some(self.create_contents_writer(file.ino).await?);
+                    }
+                    file.writer.as_mut().unwrap().write_all(&data)?;
+                    file.written += data.len() as u64;
+                }
+                Record::Hole { ino, len } => {
+                    let Some(file) = restore.file.as_mut().filter(|file| file.archive_ino == ino)
+                    else {
+                        return Err(corrupted("contents not after their file"));
+                    };
+                    if file.writer.is_none() {
+                        file.writer = Some(self.create_contents_writer(file.ino).await?);
+                    }
+                    // the blocks of zeros are written as holes
+                    stream_util::fill_zeros(file.writer.as_mut().unwrap(), len)?;
+                    file.written += len;
+                }
+                Record::End { records } => break archive.finish(records),
+            }
+        }?;
+        // after all their entries were added, which change their times
+        for (ino, atime, mtime) in restore.dirs.drain(..) {
+            self.restore_times(ino, None, atime, mtime).await?;
+        }
+        Ok(())
+    }
+
+    async fn finish_restored_file(&self, file: Option<RestoredFile>) -> FsResult<()> {
+        let Some(mut file) = file else {
+            return Ok(());
+        };
+        if let Some(mut writer) = file.writer.take() {
+            writer.finish()?;
+        }
+        if file.written != file.size {
+            // changed while it was exported
+            self.quota_resize(file.ino, file.size, file.written).await?;
+        }
+        self.restore_times(file.ino, Some(file.written), file.atime, file.mtime)
+            .await?;
+        if file.written > 0 {
+            self.events.send(FsEvent::Written {
+                ino: file.ino,
+                offset: 0,
+                len: file.written,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// The new inode of `ino` from the archive.
+fn restored_ino(restore: &Restore, ino: u64) -> FsResult<u64> {
+    restore
+        .inodes
+        .get(&ino)
+        .copied()
+        .ok_or_else(|| corrupted("entry before its node"))
+}
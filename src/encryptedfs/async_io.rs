@@ -0,0 +1,374 @@
+//! [`AsyncRead`], [`AsyncWrite`] and [`AsyncSeek`] over the files of [`EncryptedFs`], so they can be used with the
+//! tokio ecosystem, like [`tokio::io::copy`].
+//!
+//! Each operation runs as a task on the runtime of [`EncryptedFs`], the `poll_*` methods only check if it's done.
+//! Like with the other operations a task completes even if the reader or writer is dropped.
+
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+use tokio::task::JoinHandle;
+use tracing::error;
+
+use crate::encryptedfs::{EncryptedFs, FsError, FsResult};
+
+/// Most we read or write in one operation.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+impl EncryptedFs {
+    /// Open the file `ino` for reading as an [`AsyncRead`] and [`AsyncSeek`], starting at the beginning.
+    ///
+    /// The handle is released when the reader is dropped.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn open_async_reader(&self, ino: u64) -> FsResult<EncryptedFileReader> {
+        let fs = self.self_arc()?;
+        let fh = self.open(ino, true, false).await?;
+        Ok(EncryptedFileReader {
+            file: OpenedFile::new(fs, ino, fh),
+            buf: Vec::new(),
+            buf_pos: 0,
+            read: None,
+        })
+    }
+
+    /// Open the file `ino` for writing as an [`AsyncWrite`] and [`AsyncSeek`], starting at the beginning.
+    ///
+    /// The handle is released on [`AsyncWriteExt::shutdown`](tokio::io::AsyncWriteExt::shutdown), or when the writer
+    /// is dropped, but then the errors are only logged.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn open_async_writer(&self, ino: u64) -> FsResult<EncryptedFileWriter> {
+        let fs = self.self_arc()?;
+        let fh = self.open(ino, false, true).await?;
+        Ok(EncryptedFileWriter {
+            file: OpenedFile::new(fs, ino, fh),
+            write: None,
+            flush: None,
+        })
+    }
+}
+
+/// What the reader and the writer have in common, the handle, the position and seeking.
+struct OpenedFile {
+    fs: Arc<EncryptedFs>,
+    ino: u64,
+    fh: u64,
+    pos: u64,
+    seek: Option<Seek>,
+    released: bool,
+}
+
+enum Seek {
+    Done(u64),
+    /// Waiting for the size of the file, to seek from the end.
+    FromEnd(i64, JoinHandle<FsResult<u64>>),
+}
+
+impl OpenedFile {
+    const fn new(fs: Arc<EncryptedFs>, ino: u64, fh: u64) -> Self {
+        Self {
+            fs,
+            ino,
+            fh,
+            pos: 0,
+            seek: None,
+            released: false,
+        }
+    }
+
+    fn spawn<T, F>(
+        &self,
+        f: impl FnOnce(Arc<EncryptedFs>, u64, u64) -> F,
+    ) -> JoinHandle<FsResult<T>>
+    where
+        T: Send + 'static,
+        F: Future<Output = FsResult<T>> + Send + 'static,
+    {
+        self.fs.runtime.spawn(f(self.fs.clone(), self.ino, self.fh))
+    }
+
+    fn start_seek(&mut self, position: SeekFrom) -> io::Result<()> {
+        if self.seek.is_some() {
+            return Err(in_progress());
+        }
+        self.seek = Some(match position {
+            SeekFrom::Start(pos) => Seek::Done(pos),
+            SeekFrom::Current(offset) => Seek::Done(offset_pos(self.pos, offset)?),
+            SeekFrom::End(offset) => Seek::FromEnd(
+                offset,
+                self.spawn(|fs, ino, _| async move { Ok(fs.get_attr(ino).await?.size) }),
+            ),
+        });
+        Ok(())
+    }
+
+    /// Poll the seek started with [`OpenedFile::start_seek`], it returns the current position if none was started.
+    fn poll_seek(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let pos = match self.seek.as_mut() {
+            None => return Poll::Ready(Ok(self.pos)),
+            Some(Seek::Done(pos)) => Ok(*pos),
+            Some(Seek::FromEnd(offset, task)) => {
+                let offset = *offset;
+                ready!(poll_task(task, cx)).and_then(|size| offset_pos(size, offset))
+            }
+        };
+        self.seek = None;
+        if let Ok(pos) = pos {
+            self.pos = pos;
+        }
+        Poll::Ready(pos)
+    }
+
+    /// Release the handle in the background, after all we started completed.
+    fn release_on_drop(&mut self, pending: Option<JoinHandle<FsResult<usize>>>) {
+        if self.released {
+            return;
+        }
+        self.released = true;
+        let fs = self.fs.clone();
+        let fh = self.fh;
+        self.fs.runtime.spawn(async move {
+            if let Some(pending) = pending {
+                // it's logged by whoever polls it, but no one does anymore
+                if let Err(err) = pending.await.map_err(FsError::from).and_then(|res| res) {
+                    error!(err = %err, "writing from a dropped file");
+                }
+            }
+            if let Err(err) = fs.release(fh).await {
+                error!(err = %err, "releasing a dropped file");
+            }
+        });
+    }
+}
+
+/// Reads the file `ino` of [`EncryptedFs`], see [`EncryptedFs::open_async_reader`].
+pub struct EncryptedFileReader {
+    file: OpenedFile,
+    // read ahead of `file.pos`, from `buf_pos`
+    buf: Vec<u8>,
+    buf_pos: usize,
+    read: Option<JoinHandle<FsResult<Vec<u8>>>>,
+}
+
+impl EncryptedFileReader {
+    /// The handle of the file in [`EncryptedFs`].
+    pub const fn handle(&self) -> u64 {
+        self.file.fh
+    }
+}
+
+impl AsyncRead for EncryptedFileReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.file.seek.is_some() {
+            return Poll::Ready(Err(in_progress()));
+        }
+        if this.buf_pos == this.buf.len() {
+            let task = this.read.get_or_insert_with(|| {
+                let pos = this.file.pos;
+                this.file.spawn(|fs, ino, fh| async move {
+                    let mut data = vec![0; CHUNK_SIZE];
+                    let len = fs.read(ino, pos, &mut data, fh).await?;
+                    data.truncate(len);
+                    Ok(data)
+                })
+            });
+            let res = ready!(poll_task(task, cx));
+            this.read = None;
+            this.buf = res?;
+            this.buf_pos = 0;
+        }
+        let len = buf.remaining().min(this.buf.len() - this.buf_pos);
+        buf.put_slice(&this.buf[this.buf_pos..this.buf_pos + len]);
+        this.buf_pos += len;
+        this.file.pos += len as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for EncryptedFileReader {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        if this.read.is_some() {
+            return Err(in_progress());
+        }
+        this.file.start_seek(position)
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        let seeking = this.file.seek.is_some();
+        let pos = ready!(this.file.poll_seek(cx))?;
+        if seeking {
+            // what we read ahead is from the previous position
+            this.buf.clear();
+            this.buf_pos = 0;
+        }
+        Poll::Ready(Ok(pos))
+    }
+}
+
+impl Drop for EncryptedFileReader {
+    fn drop(&mut self) {
+        self.file.release_on_drop(None);
+    }
+}
+
+/// Writes the file `ino` of [`EncryptedFs`], see [`EncryptedFs::open_async_writer`].
+pub struct EncryptedFileWriter {
+    file: OpenedFile,
+    write: Option<JoinHandle<FsResult<usize>>>,
+    flush: Option<JoinHandle<FsResult<()>>>,
+}
+
+impl EncryptedFileWriter {
+    /// The handle of the file in [`EncryptedFs`].
+    pub const fn handle(&self) -> u64 {
+        self.file.fh
+    }
+
+    fn check_open(&self) -> io::Result<()> {
+        if self.file.released {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "writer is shut down",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Wait for the write in progress, if any.
+    fn poll_write_done(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let Some(task) = self.write.as_mut() else {
+            return Poll::Ready(Ok(0));
+        };
+        let res = ready!(poll_task(task, cx));
+        self.write = None;
+        let len = res?;
+        self.file.pos += len as u64;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_task_after_write(
+        &mut self,
+        cx: &mut Context<'_>,
+        op: impl FnOnce(Arc<EncryptedFs>, u64) -> JoinHandle<FsResult<()>>,
+    ) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_done(cx))?;
+        let task = self
+            .flush
+            .get_or_insert_with(|| op(self.file.fs.clone(), self.file.fh));
+        let res = ready!(poll_task(task, cx));
+        self.flush = None;
+        Poll::Ready(res)
+    }
+}
+
+impl AsyncWrite for EncryptedFileWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.check_open()?;
+        if this.file.seek.is_some() || this.flush.is_some() {
+            return Poll::Ready(Err(in_progress()));
+        }
+        if this.write.is_none() {
+            // like the other writers, on `Pending` we are called again with the same data
+            let pos = this.file.pos;
+            let data = buf[..buf.len().min(CHUNK_SIZE)].to_vec();
+            this.write = Some(
+                this.file
+                    .spawn(|fs, ino, fh| async move { fs.write(ino, pos, &data, fh).await }),
+            );
+        }
+        this.poll_write_done(cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.check_open()?;
+        let runtime = this.file.fs.runtime.clone();
+        this.poll_task_after_write(cx, |fs, fh| {
+            runtime.spawn(async move { fs.flush(fh).await })
+        })
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.file.released {
+            return Poll::Ready(Ok(()));
+        }
+        let runtime = this.file.fs.runtime.clone();
+        ready!(this.poll_task_after_write(cx, |fs, fh| {
+            runtime.spawn(async move { fs.release(fh).await })
+        }))?;
+        this.file.released = true;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for EncryptedFileWriter {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        this.check_open()?;
+        if this.write.is_some() || this.flush.is_some() {
+            return Err(in_progress());
+        }
+        this.file.start_seek(position)
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        // the position includes what is being written
+        ready!(this.poll_write_done(cx))?;
+        this.file.poll_seek(cx)
+    }
+}
+
+impl Drop for EncryptedFileWriter {
+    fn drop(&mut self) {
+        let pending = self.write.take();
+        self.file.release_on_drop(pending);
+    }
+}
+
+fn poll_task<T>(task: &mut JoinHandle<FsResult<T>>, cx: &mut Context<'_>) -> Poll<io::Result<T>> {
+    Pin::new(task).poll(cx).map(|res| match res {
+        Ok(res) => res.map_err(to_io_error),
+        Err(err) => Err(io::Error::other(err)),
+    })
+}
+
+fn to_io_error(err: FsError) -> io::Error {
+    match err {
+        FsError::Io { source, .. } => source,
+        err => io::Error::new(
+            io::Error::from_raw_os_error(err.to_errno()).kind(),
+            err.to_string(),
+        ),
+    }
+}
+
+/// `pos` moved by `offset`, it fails if it's before the start.
+fn offset_pos(pos: u64, offset: i64) -> io::Result<u64> {
+    pos.checked_add_signed(offset).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid seek to a negative or overflowing position",
+        )
+    })
+}
+
+fn in_progress() -> io::Error {
+    io::Error::other("another operation is in progress")
+}
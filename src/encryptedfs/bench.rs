@@ -9,9 +9,14 @@ use rand::Rng;
 use shush_rs::SecretString;
 
 #[allow(unused_imports)]
-use crate::encryptedfs::{DirectoryEntry, DirectoryEntryPlus, FileType, ROOT_INODE};
+use crate::crypto::Cipher;
 #[allow(unused_imports)]
-use crate::test_common::{create_attr, get_fs};
+use crate::encryptedfs::{
+    CreateFileAttr, DirectoryEntry, DirectoryEntryPlus, DurabilityPolicy, EncryptedFs, FileType,
+    FsOptions, ROOT_INODE,
+};
+#[allow(unused_imports)]
+use crate::test_common::{create_attr, get_fs, take_fs, PasswordProviderImpl};
 #[allow(unused_imports)]
 use crate::{async_util, test_common};
 
@@ -44,6 +49,59 @@ fn bench_create(b: &mut Bencher) {
     });
 }
 
+/// Like [`bench_create`] with another [`DurabilityPolicy`], `Always` is the default used there.
+#[allow(dead_code)]
+fn bench_create_durability(b: &mut Bencher, key: &'static str, durability: DurabilityPolicy) {
+    test_common::bench(key, 1, false, async {
+        let storage = take_fs().await.storage.clone();
+        let fs = EncryptedFs::with_storage(
+            storage,
+            Box::new(PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            false,
+            FsOptions::builder().durability(durability).build(),
+        )
+        .await
+        .unwrap();
+
+        let mut i = 1;
+        let i = &mut i;
+        b.iter(|| {
+            black_box({
+                async_util::call_async(async {
+                    let test_file = SecretString::from_str(&format!("test-file-{i}")).unwrap();
+                    let _ = fs
+                        .create(
+                            ROOT_INODE,
+                            &test_file,
+                            create_attr(FileType::RegularFile),
+                            false,
+                            false,
+                        )
+                        .await
+                        .unwrap();
+                });
+                *i += 1;
+                *i
+            })
+        });
+    });
+}
+
+#[bench]
+fn bench_create_durability_on_release(b: &mut Bencher) {
+    bench_create_durability(
+        b,
+        "bench_create_durability_on_release",
+        DurabilityPolicy::OnRelease,
+    );
+}
+
+#[bench]
+fn bench_create_durability_never(b: &mut Bencher) {
+    bench_create_durability(b, "bench_create_durability_never", DurabilityPolicy::Never);
+}
+
 #[bench]
 fn bench_exists_by_name(b: &mut Bencher) {
     test_common::bench("exists_by_name", 1, false, async {
@@ -58,6 +116,7 @@ fn bench_exists_by_name(b: &mut Bencher) {
                         &SecretString::from_str(&format!("test-file-{}", rnd.gen_range(1..100)))
                             .unwrap(),
                     )
+                    .await
                     .unwrap();
             });
             black_box(());
@@ -161,3 +220,165 @@ fn bench_read_dir_plus(b: &mut Bencher) {
         });
     });
 }
+
+/// Reads of 4K from 32 files at once, each with its own handle, on `worker_threads` threads. The handles are in
+/// sharded maps, so it should scale with the threads.
+#[allow(dead_code)]
+fn bench_concurrent_read(b: &mut Bencher, key: &'static str, worker_threads: usize) {
+    test_common::bench(key, worker_threads, false, async {
+        let fs = get_fs().await;
+
+        let mut files = vec![];
+        for i in 0..32 {
+            let test_file = SecretString::from_str(&format!("test-file-{i}")).unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.write(attr.ino, 0, &[42_u8; 4096], fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            files.push((attr.ino, fh));
+        }
+
+        b.iter(|| {
+            async_util::call_async(async {
+                let tasks: Vec<_> = files
+                    .iter()
+                    .map(|(ino, fh)| {
+                        let fs = fs.clone();
+                        let (ino, fh) = (*ino, *fh);
+                        tokio::spawn(async move {
+                            let mut buf = [0_u8; 4096];
+                            test_common::read_exact(&fs, ino, 0, &mut buf, fh).await;
+                            black_box(buf);
+                        })
+                    })
+                    .collect();
+                for task in tasks {
+                    task.await.unwrap();
+                }
+            });
+        });
+        for (_, fh) in files {
+            fs.release(fh).await.unwrap();
+        }
+    });
+}
+
+#[bench]
+fn bench_concurrent_read_1_thread(b: &mut Bencher) {
+    bench_concurrent_read(b, "bench_concurrent_read_1_thread", 1);
+}
+
+#[bench]
+fn bench_concurrent_read_8_threads(b: &mut Bencher) {
+    bench_concurrent_read(b, "bench_concurrent_read_8_threads", 8);
+}
+
+/// Appends of 16K of log lines to a new file until it has 1M, each file compressed if `compress`.
+#[allow(dead_code)]
+fn bench_append(b: &mut Bencher, key: &'static str, compress: bool) {
+    test_common::bench(key, 1, false, async {
+        let fs = get_fs().await;
+
+        let data: Vec<u8> = (0_u64..)
+            .flat_map(|i| format!("{i}: the same message as on the other lines\n").into_bytes())
+            .take(16 * 1024)
+            .collect();
+        let mut i = 1;
+        let i = &mut i;
+        b.iter(|| {
+            async_util::call_async(async {
+                let test_file = SecretString::from_str(&format!("test-file-{i}")).unwrap();
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &test_file,
+                        CreateFileAttr {
+                            compress: Some(compress),
+                            ..create_attr(FileType::RegularFile)
+                        },
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                for chunk in 0..64 {
+                    let offset = chunk * data.len() as u64;
+                    fs.write_all(attr.ino, offset, &data, fh).await.unwrap();
+                }
+                fs.release(fh).await.unwrap();
+            });
+            *i += 1;
+            black_box(*i)
+        });
+    });
+}
+
+#[bench]
+fn bench_append_plain(b: &mut Bencher) {
+    bench_append(b, "bench_append_plain", false);
+}
+
+#[bench]
+fn bench_append_compressed(b: &mut Bencher) {
+    bench_append(b, "bench_append_compressed", true);
+}
+
+/// Reads of 4K from the start to the end of a 1M file of log lines, compressed if `compress`.
+#[allow(dead_code)]
+fn bench_sequential_read(b: &mut Bencher, key: &'static str, compress: bool) {
+    test_common::bench(key, 1, false, async {
+        let fs = get_fs().await;
+
+        let data: Vec<u8> = (0_u64..)
+            .flat_map(|i| format!("{i}: the same message as on the other lines\n").into_bytes())
+            .take(1024 * 1024)
+            .collect();
+        let test_file = SecretString::from_str("test-file").unwrap();
+        let (fh, attr) = fs
+            .create(
+                ROOT_INODE,
+                &test_file,
+                CreateFileAttr {
+                    compress: Some(compress),
+                    ..create_attr(FileType::RegularFile)
+                },
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        fs.write_all(attr.ino, 0, &data, fh).await.unwrap();
+        fs.release(fh).await.unwrap();
+
+        b.iter(|| {
+            async_util::call_async(async {
+                let fh = fs.open(attr.ino, true, false).await.unwrap();
+                let mut buf = [0_u8; 4096];
+                for offset in (0..data.len() as u64).step_by(buf.len()) {
+                    test_common::read_exact(&fs, attr.ino, offset, &mut buf, fh).await;
+                }
+                fs.release(fh).await.unwrap();
+                black_box(buf);
+            });
+        });
+    });
+}
+
+#[bench]
+fn bench_sequential_read_plain(b: &mut Bencher) {
+    bench_sequential_read(b, "bench_sequential_read_plain", false);
+}
+
+#[bench]
+fn bench_sequential_read_compressed(b: &mut Bencher) {
+    bench_sequential_read(b, "bench_sequential_read_compressed", true);
+}
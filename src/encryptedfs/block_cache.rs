@@ -0,0 +1,184 @@
+//! Decrypted blocks of the contents, shared by all the read handles, see [`FsOptions::block_cache_bytes`].
+//!
+//! Blocks are kept by inode, index and the generation of the contents of the inode. Changing the contents moves the
+//! inode to the next generation and drops its blocks, so a block decrypted before the change is not found after it.
+//! The plaintext is zeroized when a block is dropped.
+//!
+//! [`FsOptions::block_cache_bytes`]: crate::encryptedfs::FsOptions::block_cache_bytes
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use lru::LruCache;
+use shush_rs::{ExposeSecret, SecretVec};
+
+use crate::crypto::read::CryptoReadSeek;
+use crate::crypto::write::BLOCK_SIZE;
+use crate::encryptedfs::{seek_for_read, EncryptedFs, FsResult};
+use crate::segmented_file::SegmentedFile;
+use crate::stream_util;
+
+pub(crate) struct BlockCache {
+    max_bytes: usize,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    // by (ino, block index, generation)
+    blocks: LruCache<(u64, u64, u64), SecretVec<u8>>,
+    bytes: usize,
+    // of the inodes changed since we were created, the others are at 0
+    generations: HashMap<u64, u64>,
+    next_generation: u64,
+}
+
+impl BlockCache {
+    pub(crate) fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            inner: Mutex::new(Inner {
+                blocks: LruCache::unbounded(),
+                bytes: 0,
+                generations: HashMap::new(),
+                next_generation: 1,
+            }),
+        }
+    }
+
+    pub(crate) const fn is_enabled(&self) -> bool {
+        self.max_bytes > 0
+    }
+
+    fn generation(&self, ino: u64) -> u64 {
+        let inner = self.inner.lock().expect("cannot obtain lock");
+        inner.generations.get(&ino).copied().unwrap_or(0)
+    }
+
+    /// Copy what the block has from `start` into `buf`, returning the number of bytes copied and the length of the
+    /// block, or `None` if it's not kept.
+    fn copy(&self, key: (u64, u64, u64), start: usize, buf: &mut [u8]) -> Option<(usize, usize)> {
+        let mut inner = self.inner.lock().expect("cannot obtain lock");
+        let block = inner.blocks.get(&key)?.expose_secret();
+        Some((copy_from(&block, start, buf), block.len()))
+    }
+
+    fn insert(&self, key: (u64, u64, u64), block: Vec<u8>) {
+        let len = block.len();
+        // zeroized even if it's not kept
+        let block = SecretVec::from(block);
+        if len > self.max_bytes {
+            return;
+        }
+        let mut inner = self.inner.lock().expect("cannot obtain lock");
+        if inner.generations.get(&key.0).copied().unwrap_or(0) != key.2 {
+            // changed since it was read
+            return;
+        }
+        inner.bytes += len;
+        if let Some(previous) = inner.blocks.put(key, block) {
+            inner.bytes -= previous.expose_secret().len();
+        }
+        while inner.bytes > self.max_bytes {
+            let Some((_, evicted)) = inner.blocks.pop_lru() else {
+                break;
+            };
+            inner.bytes -= evicted.expose_secret().len();
+        }
+    }
+
+    /// Drop all the blocks, like when the key of some of them is locked.
+    pub(crate) fn clear(&self) {
+        let mut inner = self.inner.lock().expect("cannot obtain lock");
+        inner.blocks.clear();
+        inner.bytes = 0;
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_empty(&self) -> bool {
+        let inner = self.inner.lock().expect("cannot obtain lock");
+        inner.blocks.is_empty()
+    }
+
+    /// The contents of `ino` changed, drop its blocks and move it to the next generation.
+    pub(crate) fn invalidate(&self, ino: u64) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut inner = self.inner.lock().expect("cannot obtain lock");
+        let generation = inner.next_generation;
+        inner.next_generation += 1;
+        inner.generations.insert(ino, generation);
+        let keys: Vec<_> = inner
+            .blocks
+            .iter()
+            .map(|(key, _)| *key)
+            .filter(|key| key.0 == ino)
+            .collect();
+        for key in keys {
+            if let Some(block) = inner.blocks.pop(&key) {
+                inner.bytes -= block.expose_secret().len();
+            }
+        }
+    }
+}
+
+fn copy_from(block: &[u8], start: usize, buf: &mut [u8]) -> usize {
+    if start >= block.len() {
+        return 0;
+    }
+    let len = buf.len().min(block.len() - start);
+    buf[..len].copy_from_slice(&block[start..start + len]);
+    len
+}
+
+impl EncryptedFs {
+    /// Read the contents of `ino` from `offset` taking the blocks from the cache, the missing ones are decrypted
+    /// with `reader` and added to it.
+    ///
+    /// Like [`stream_util::read`] it reads until `buf` is filled or the end of the file is reached, and fails with
+    /// [`FsError::SeekFailed`] if a block inside the file can't be reached.
+    ///
+    /// [`FsError::SeekFailed`]: crate::encryptedfs::FsError::SeekFailed
+    #[allow(clippy::cast_possible_truncation)]
+    pub(crate) fn read_with_block_cache(
+        &self,
+        ino: u64,
+        offset: u64,
+        buf: &mut [u8],
+        reader: &mut dyn CryptoReadSeek<SegmentedFile>,
+    ) -> FsResult<usize> {
+        let cache = &self.block_cache;
+        let block_size = BLOCK_SIZE as u64;
+        let generation = cache.generation(ino);
+        let mut len = 0;
+        while len < buf.len() {
+            let pos = offset + len as u64;
+            let index = pos / block_size;
+            let start = (pos % block_size) as usize;
+            let key = (ino, index, generation);
+            let (copied, block_len) = if let Some(copied) = cache.copy(key, start, &mut buf[len..])
+            {
+                self.metrics.block_cache.hit();
+                copied
+            } else {
+                self.metrics.block_cache.miss();
+                if !seek_for_read(&mut *reader, index * block_size)? {
+                    // past the end of the file
+                    break;
+                }
+                let mut block = vec![0; BLOCK_SIZE];
+                let block_len = stream_util::read(&mut *reader, &mut block)?;
+                block.truncate(block_len);
+                let copied = copy_from(&block, start, &mut buf[len..]);
+                cache.insert(key, block);
+                (copied, block_len)
+            };
+            len += copied;
+            if copied == 0 || block_len < BLOCK_SIZE {
+                // the last block
+                break;
+            }
+        }
+        Ok(len)
+    }
+}
@@ -0,0 +1,483 @@
+//! Generations of the changes, so backup tools can find what changed since they last ran without reading
+//! everything, see [`EncryptedFs::changes_since`].
+//!
+//! Each inode written gets the next generation in [`FileAttr::generation`], and the removed inodes are kept with the
+//! generation of their removal in [`TOMBSTONES_FILENAME`]. Generations are reserved in batches in
+//! [`GENERATION_FILENAME`], like the inode numbers of [`InodeAlloc::Sequential`], so after a crash the rest of the
+//! batch is skipped but they never go back.
+//!
+//! Before an inode is written, its generation is appended to the log in [`CHANGES_DIR`], so the changes after a
+//! generation are found by reading only the end of the log. Each record is encrypted on its own, bound to where it is in
+//! the log, and the segments of the log are kept in an index encrypted the same way.
+//!
+//! [`InodeAlloc::Sequential`]: crate::encryptedfs::InodeAlloc::Sequential
+
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, Cursor, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use shush_rs::SecretVec;
+
+use crate::crypto;
+use crate::crypto::Cipher;
+use crate::encryptedfs::{
+    deserialize_bound, serialize_bound_into, EncryptedFs, FileAttr, FileType, FsError, FsResult,
+    SECURITY_DIR,
+};
+use crate::storage::Storage;
+
+/// Next generation not reserved yet, kept in [`SECURITY_DIR`]. All the ones below it may be used.
+pub(crate) const GENERATION_FILENAME: &str = "generation";
+/// How many generations are reserved in [`GENERATION_FILENAME`] at once.
+const GENERATION_BATCH: u64 = 1024;
+/// The removed inodes, kept in [`SECURITY_DIR`].
+pub(crate) const TOMBSTONES_FILENAME: &str = "tombstones";
+pub(crate) const TOMBSTONES_AAD: &[u8] = b"tombstones";
+/// Max removals kept in [`TOMBSTONES_FILENAME`], the oldest ones are dropped after that.
+const MAX_TOMBSTONES: usize = 10_000;
+/// Directory in [`SECURITY_DIR`] with the log of the changed inodes, in segments named by the generation of their
+/// first record, and [`CHANGES_INDEX_FILENAME`].
+pub(crate) const CHANGES_DIR: &str = "changes";
+/// The segments of the log, see [`ChangeLog`].
+const CHANGES_INDEX_FILENAME: &str = "index";
+const CHANGES_INDEX_AAD: &[u8] = b"changes";
+/// Records in a segment of the log.
+#[cfg(not(test))]
+const CHANGE_SEGMENT_RECORDS: usize = 1024;
+#[cfg(test)]
+const CHANGE_SEGMENT_RECORDS: usize = 16;
+/// Max segments kept in [`CHANGES_DIR`], the oldest ones are dropped after that.
+#[cfg(not(test))]
+const MAX_CHANGE_SEGMENTS: usize = 1024;
+#[cfg(test)]
+const MAX_CHANGE_SEGMENTS: usize = 8;
+
+/// A change after a generation, see [`EncryptedFs::changes_since`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeRecord {
+    /// Created or changed, with the attributes it has now.
+    Changed(FileAttr),
+    /// Removed at `generation`.
+    Removed { ino: u64, generation: u64 },
+}
+
+impl ChangeRecord {
+    #[must_use]
+    pub const fn ino(&self) -> u64 {
+        match self {
+            Self::Changed(attr) => attr.ino,
+            Self::Removed { ino, .. } => *ino,
+        }
+    }
+
+    /// Generation of the last change.
+    #[must_use]
+    pub const fn generation(&self) -> u64 {
+        match self {
+            Self::Changed(attr) => attr.generation,
+            Self::Removed { generation, .. } => *generation,
+        }
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct Tombstones {
+    /// Generation and inode of the removals, oldest first.
+    removed: VecDeque<(u64, u64)>,
+    /// Generation of the last removal dropped, the ones up to it are not known anymore.
+    dropped: u64,
+}
+
+/// Index of the log in [`CHANGES_DIR`], in [`CHANGES_INDEX_FILENAME`].
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct ChangeLog {
+    /// First generation of each segment, oldest first. A segment has the changes up to the start of the next one.
+    segments: VecDeque<u64>,
+    /// Last generation of the segments dropped, the changes up to it are not known anymore.
+    dropped: u64,
+}
+
+/// A record of the log, the generation, inode and kind of a change.
+type Change = (u64, u64, FileType);
+
+pub(crate) struct Generations {
+    /// What is left of the batch reserved in [`GENERATION_FILENAME`].
+    counter: Range<u64>,
+    tombstones: Tombstones,
+    log: ChangeLog,
+    /// Records in the last segment of the log.
+    last_records: usize,
+}
+
+impl Generations {
+    pub(crate) fn new() -> Self {
+        Self {
+            counter: 1..1,
+            tombstones: Tombstones::default(),
+            log: ChangeLog::default(),
+            last_records: 0,
+        }
+    }
+}
+
+fn generation_path() -> PathBuf {
+    Path::new(SECURITY_DIR).join(GENERATION_FILENAME)
+}
+
+pub(crate) fn tombstones_path() -> PathBuf {
+    Path::new(SECURITY_DIR).join(TOMBSTONES_FILENAME)
+}
+
+pub(crate) fn changes_dir() -> PathBuf {
+    Path::new(SECURITY_DIR).join(CHANGES_DIR)
+}
+
+fn segment_path(dir: &Path, start: u64) -> PathBuf {
+    // zero padded, so they sort in the order they were made
+    dir.join(format!("{start:020}"))
+}
+
+/// Associated data binding a record to the segment `start` and its `offset` in it, so records can't be moved.
+fn record_aad(start: u64, offset: u64) -> Vec<u8> {
+    [
+        CHANGES_INDEX_AAD,
+        &start.to_le_bytes(),
+        &offset.to_le_bytes(),
+    ]
+    .concat()
+}
+
+/// The record of `change` at `offset` in the segment `start`, its length and then the encrypted change.
+fn encode_change(
+    change: &Change,
+    start: u64,
+    offset: u64,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<Vec<u8>> {
+    let data = crypto::serialize_encrypt_into(
+        Cursor::new(vec![]),
+        change,
+        cipher,
+        key,
+        &record_aad(start, offset),
+    )?
+    .into_inner();
+    #[allow(clippy::cast_possible_truncation)]
+    let len = data.len() as u32;
+    Ok([len.to_le_bytes().as_slice(), &data].concat())
+}
+
+/// The changes in `data` of the segment `start` and the length of their records. A record interrupted by a crash is
+/// left out.
+fn decode_segment(
+    data: &[u8],
+    start: u64,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<(Vec<Change>, usize)> {
+    let mut changes = vec![];
+    let mut offset = 0;
+    while let Some(len) = data.get(offset..offset + 4) {
+        let len = u32::from_le_bytes(len.try_into().expect("4 bytes")) as usize;
+        let Some(record) = data.get(offset + 4..offset + 4 + len) else {
+            break;
+        };
+        changes.push(deserialize_bound(
+            record,
+            cipher,
+            key,
+            &record_aad(start, offset as u64),
+        )?);
+        offset += 4 + len;
+    }
+    Ok((changes, offset))
+}
+
+/// Read the log in `dir` with its segments, see [`CHANGES_DIR`].
+pub(crate) async fn read_log(
+    storage: &dyn Storage,
+    dir: &Path,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<(ChangeLog, Vec<Vec<Change>>)> {
+    let log: ChangeLog = deserialize_bound(
+        storage
+            .open(&dir.join(CHANGES_INDEX_FILENAME), false)
+            .await?,
+        cipher,
+        key,
+        CHANGES_INDEX_AAD,
+    )?;
+    let mut segments = vec![];
+    for &start in &log.segments {
+        let data = storage.read(&segment_path(dir, start)).await?;
+        segments.push(decode_segment(&data, start, cipher, key)?.0);
+    }
+    Ok((log, segments))
+}
+
+/// Write the log in `dir`, with the changes of each segment of `log`, replacing what was there.
+pub(crate) async fn write_log(
+    storage: &dyn Storage,
+    dir: &Path,
+    log: &ChangeLog,
+    segments: &[Vec<Change>],
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<()> {
+    if storage.kind(dir).await?.is_some() {
+        storage.remove_dir(dir).await?;
+    }
+    storage.create_dir(dir).await?;
+    for (&start, changes) in log.segments.iter().zip(segments) {
+        let mut data = vec![];
+        for change in changes {
+            data.extend(encode_change(
+                change,
+                start,
+                data.len() as u64,
+                cipher,
+                key,
+            )?);
+        }
+        storage.write(&segment_path(dir, start), &data).await?;
+    }
+    serialize_bound_into(
+        storage,
+        &dir.join(CHANGES_INDEX_FILENAME),
+        log,
+        cipher,
+        key,
+        CHANGES_INDEX_AAD,
+    )
+    .await?;
+    storage
+        .sync(dir.parent().expect("oops, we don't have a parent"))
+        .await?;
+    Ok(())
+}
+
+/// The log of `changes`, ordered by generation, keeping only the last [`MAX_CHANGE_SEGMENTS`] segments.
+pub(crate) fn log_of(changes: &[Change]) -> (ChangeLog, Vec<Vec<Change>>) {
+    let mut log = ChangeLog::default();
+    let mut segments: Vec<_> = changes
+        .chunks(CHANGE_SEGMENT_RECORDS)
+        .map(<[Change]>::to_vec)
+        .collect();
+    let dropped = segments.len().saturating_sub(MAX_CHANGE_SEGMENTS);
+    if dropped > 0 {
+        log.dropped = segments[dropped][0].0 - 1;
+        segments.drain(..dropped);
+    }
+    log.segments = segments.iter().map(|changes| changes[0].0).collect();
+    (log, segments)
+}
+
+impl EncryptedFs {
+    /// The latest generation given to a change, the ones made after it will have larger ones.
+    ///
+    /// Keep it before calling [`EncryptedFs::changes_since`] to pass it to the next call.
+    pub async fn current_generation(&self) -> u64 {
+        self.generations.lock().await.counter.start - 1
+    }
+
+    /// The inodes created, changed or removed after `generation`, ordered by the generation of their last change.
+    ///
+    /// A directory is changed when entries are added to it or removed from it, so the changed names are found by
+    /// listing only the changed directories. Only the last [`MAX_TOMBSTONES`] removals and the last
+    /// [`MAX_CHANGE_SEGMENTS`] segments of the log are kept, if older changes may be missing it returns
+    /// [`FsError::InvalidInput`] and everything needs to be listed.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn changes_since(&self, generation: u64) -> FsResult<Vec<ChangeRecord>> {
+        if !self.read_only {
+            // so the directories changed by them are in
+            self.flush_dir_times().await?;
+        }
+        let not_kept = |dropped| {
+            FsError::invalid_input(format!(
+                "changes up to generation {dropped} are not kept anymore"
+            ))
+        };
+        let (mut changes, segments): (Vec<_>, Vec<_>) = {
+            let generations = self.generations.lock().await;
+            let tombstones = &generations.tombstones;
+            let log = &generations.log;
+            if generation < tombstones.dropped {
+                return Err(not_kept(tombstones.dropped));
+            }
+            if generation < log.dropped {
+                return Err(not_kept(log.dropped));
+            }
+            // the segment before the first one starting after it may have later changes too
+            let first = log
+                .segments
+                .partition_point(|start| *start <= generation)
+                .saturating_sub(1);
+            (
+                tombstones
+                    .removed
+                    .iter()
+                    .filter(|(removed, _)| *removed > generation)
+                    .map(|&(generation, ino)| ChangeRecord::Removed { ino, generation })
+                    .collect(),
+                log.segments.iter().skip(first).copied().collect(),
+            )
+        };
+        let mut inos = HashSet::new();
+        for start in segments {
+            let data = match self
+                .storage
+                .read(&segment_path(&changes_dir(), start))
+                .await
+            {
+                Ok(data) => data,
+                // dropped since we looked
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    return Err(not_kept(start));
+                }
+                Err(err) => return Err(err.into()),
+            };
+            let (segment, _) = decode_segment(&data, start, self.cipher, &*self.key.get().await?)?;
+            inos.extend(
+                segment
+                    .into_iter()
+                    .filter(|(changed, _, _)| *changed > generation)
+                    .map(|(_, ino, _)| ino),
+            );
+        }
+        for ino in inos {
+            match self.get_inode_from_cache_or_storage(ino).await {
+                // logged before it was written, it's older if the write didn't happen
+                Ok(attr) if attr.generation > generation => {
+                    changes.push(ChangeRecord::Changed(attr));
+                }
+                Ok(_) => {}
+                // removed since
+                Err(FsError::InodeNotFound) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        changes.sort_by_key(ChangeRecord::generation);
+        Ok(changes)
+    }
+
+    /// Read the generation counter, the removals and the index of the log, before anything is written.
+    ///
+    /// Data dirs without [`GENERATION_FILENAME`] are new or were migrated, all their inodes have generation `0`.
+    pub(crate) async fn load_generations(&self) -> FsResult<()> {
+        let mut generations = self.generations.lock().await;
+        let path = generation_path();
+        if self.kind_at(&path).await.is_some() {
+            let next = String::from_utf8_lossy(&self.storage.read(&path).await?)
+                .trim()
+                .parse()?;
+            generations.counter = next..next;
+        }
+        if self.kind_at(&tombstones_path()).await.is_some() {
+            generations.tombstones = self.read_bound(&tombstones_path(), TOMBSTONES_AAD).await?;
+        }
+        let index_path = changes_dir().join(CHANGES_INDEX_FILENAME);
+        if self.kind_at(&index_path).await.is_some() {
+            generations.log = self.read_bound(&index_path, CHANGES_INDEX_AAD).await?;
+            if let Some(&start) = generations.log.segments.back() {
+                let path = segment_path(&changes_dir(), start);
+                let data = self.storage.read(&path).await?;
+                let (changes, len) =
+                    decode_segment(&data, start, self.cipher, &*self.key.get().await?)?;
+                if len < data.len() && !self.read_only {
+                    // interrupted while appending, the next record goes after the complete ones
+                    self.storage.open(&path, true).await?.set_len(len as u64)?;
+                }
+                generations.last_records = changes.len();
+            }
+        }
+        Ok(())
+    }
+
+    /// The generation of a change of the inode `ino`, appended to the log before the change is written, so
+    /// [`EncryptedFs::changes_since`] finds it.
+    ///
+    /// When the last segment is full a new one is started, and the oldest one dropped after
+    /// [`MAX_CHANGE_SEGMENTS`].
+    pub(crate) async fn log_change(&self, ino: u64, kind: FileType) -> FsResult<u64> {
+        let mut generations = self.generations.lock().await;
+        let generation = self.next_generation_locked(&mut generations).await?;
+        let change = (generation, ino, kind);
+        let dir = changes_dir();
+        match generations.log.segments.back().copied() {
+            Some(start) if generations.last_records < CHANGE_SEGMENT_RECORDS => {
+                let path = segment_path(&dir, start);
+                let mut file = self.storage.open(&path, true).await?;
+                let offset = file.seek(SeekFrom::End(0))?;
+                let record =
+                    encode_change(&change, start, offset, self.cipher, &*self.key.get().await?)?;
+                file.write_all(&record)?;
+                drop(file);
+                self.sync_with_policy(&path).await?;
+                generations.last_records += 1;
+            }
+            _ => {
+                if self.kind_at(&dir).await.is_none() {
+                    self.storage.create_dir(&dir).await?;
+                }
+                let record =
+                    encode_change(&change, generation, 0, self.cipher, &*self.key.get().await?)?;
+                self.write_with_policy(&segment_path(&dir, generation), &record)
+                    .await?;
+                let log = &mut generations.log;
+                log.segments.push_back(generation);
+                let mut dropped = vec![];
+                while log.segments.len() > MAX_CHANGE_SEGMENTS {
+                    dropped.extend(log.segments.pop_front());
+                    log.dropped = log.segments[0] - 1;
+                }
+                // the segments are removed once the index doesn't have them
+                self.write_bound(&dir.join(CHANGES_INDEX_FILENAME), &*log, CHANGES_INDEX_AAD)
+                    .await?;
+                for start in dropped {
+                    self.storage.remove(&segment_path(&dir, start)).await?;
+                }
+                generations.last_records = 1;
+            }
+        }
+        Ok(generation)
+    }
+
+    /// The generation of a new change.
+    ///
+    /// When the batch is used up the next one is reserved in [`GENERATION_FILENAME`] before any of it is given, so
+    /// after a crash we continue after it.
+    pub(crate) async fn next_generation(&self) -> FsResult<u64> {
+        let mut generations = self.generations.lock().await;
+        self.next_generation_locked(&mut generations).await
+    }
+
+    async fn next_generation_locked(&self, generations: &mut Generations) -> FsResult<u64> {
+        if generations.counter.is_empty() {
+            let end = generations.counter.end + GENERATION_BATCH;
+            // always synced, so they never go back
+            self.storage
+                .write(&generation_path(), end.to_string().as_bytes())
+                .await?;
+            generations.counter = generations.counter.end..end;
+        }
+        Ok(generations.counter.next().expect("the batch is not empty"))
+    }
+
+    /// Keep the removal of the inode `ino` in [`TOMBSTONES_FILENAME`].
+    pub(crate) async fn record_removed(&self, ino: u64) -> FsResult<()> {
+        let mut generations = self.generations.lock().await;
+        let generation = self.next_generation_locked(&mut generations).await?;
+        let tombstones = &mut generations.tombstones;
+        tombstones.removed.push_back((generation, ino));
+        while tombstones.removed.len() > MAX_TOMBSTONES {
+            let (dropped, _) = tombstones.removed.pop_front().unwrap();
+            tombstones.dropped = dropped;
+        }
+        self.write_bound(&tombstones_path(), &*tombstones, TOMBSTONES_AAD)
+            .await
+    }
+}
@@ -0,0 +1,723 @@
+//! Integrity check of the data dir, like `fsck`.
+//!
+//! [`EncryptedFs::check`] cross-checks the inodes, the contents and the `ls` and `hash` entries of all directories
+//! and can repair some of the problems it finds, see [`CheckProblemKind`] for what is repaired.
+//! [`EncryptedFs::rebuild_dir_index`] recreates the `hash` entries of a directory from its `ls` entries.
+
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::de::DeserializeOwned;
+use shush_rs::{ExposeSecret, SecretString, SecretVec};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::crypto;
+use crate::crypto::write::BLOCK_SIZE;
+use crate::encryptedfs::{
+    child_count_aad, deserialize_bound, dir_entry_aad, ls_entry_name, serialize_bound_into,
+    serialize_ls_entry, sharded_inodes, write_name_salt, CreateFileAttr, DirectoryEntry,
+    EncryptedFs, FileAttr, FileType, FsError, FsResult, OpControl, CHILD_COUNT_FILENAME,
+    CONTENTS_DIR, HASH_DIR, INODES_DIR, LS_DIR, NAME_SALT_FILENAME, ROOT_INODE,
+};
+use crate::storage::{EntryKind, Storage};
+
+/// Directory under the root where [`EncryptedFs::check`] moves the orphaned inodes.
+pub const LOST_FOUND_DIR: &str = "lost+found";
+
+/// Problems found by [`EncryptedFs::check`].
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub problems: Vec<CheckProblem>,
+}
+
+impl CheckReport {
+    /// `true` if no problems were found.
+    #[must_use]
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn push(
+        &mut self,
+        kind: CheckProblemKind,
+        ino: Option<u64>,
+        parent: Option<u64>,
+        name: Option<SecretString>,
+        repaired: bool,
+    ) {
+        warn!(?kind, ino, parent, repaired, "check found a problem");
+        self.problems.push(CheckProblem {
+            kind,
+            ino,
+            parent,
+            name,
+            repaired,
+        });
+    }
+}
+
+/// A problem found by [`EncryptedFs::check`].
+#[derive(Debug)]
+pub struct CheckProblem {
+    pub kind: CheckProblemKind,
+    /// Inode the problem is about, `None` if it could not be read.
+    pub ino: Option<u64>,
+    /// Directory holding the entry, for problems with directory entries.
+    pub parent: Option<u64>,
+    /// Decrypted name of the entry, when available.
+    pub name: Option<SecretString>,
+    /// If it was repaired.
+    pub repaired: bool,
+}
+
+/// Kinds of problems found by [`EncryptedFs::check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckProblemKind {
+    /// The inode file can't be decrypted. Not repaired.
+    CorruptInode,
+    /// A file in `ls` or `hash` can't be decrypted. Removed on repair.
+    CorruptEntry,
+    /// A directory entry points to an inode that doesn't exist. Removed on repair.
+    DanglingEntry,
+    /// An `ls` entry without a matching `hash` entry. The `hash` entry is recreated on repair.
+    MissingHashEntry,
+    /// A `hash` entry without a matching `ls` entry. The `ls` entry is recreated on repair.
+    MissingLsEntry,
+    /// The inode has no contents. Not repaired.
+    MissingContents,
+    /// The size from the inode is not what can be decrypted from the contents. Not repaired.
+    SizeMismatch { attr_size: u64, contents_size: u64 },
+    /// The inode is not in any directory. Moved to [`LOST_FOUND_DIR`] on repair.
+    OrphanInode,
+    /// Contents without an inode. Not repaired, as we don't know the attributes to recreate the inode.
+    OrphanContents,
+    /// The count of entries kept for a directory is not how many it has, `stored` is `None` if it can't be read.
+    /// Written again on repair.
+    ChildCountMismatch { stored: Option<u64>, actual: u64 },
+    /// The total size of the files kept for [`FsOptions::max_size_bytes`] is not the sum of their sizes. Set to it on
+    /// repair.
+    ///
+    /// [`FsOptions::max_size_bytes`]: crate::encryptedfs::FsOptions::max_size_bytes
+    UsedBytesMismatch { stored: u64, actual: u64 },
+}
+
+impl EncryptedFs {
+    /// Check the integrity of the data dir and optionally `repair` the problems found.
+    ///
+    /// It walks all inodes and contents, and the `ls` and `hash` entries of each directory. Every problem is in the
+    /// returned report, marked if it was repaired. Run it when there are no open handles, like before mounting.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn check(&self, repair: bool) -> FsResult<CheckReport> {
+        self.check_with(repair, &OpControl::default()).await
+    }
+
+    /// Like [`EncryptedFs::check`], with the progress in inodes checked.
+    ///
+    /// If `control` is cancelled it stops after the inode being checked, the repairs done until then are kept.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn check_with(&self, repair: bool, control: &OpControl) -> FsResult<CheckReport> {
+        if repair && self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let mut report = CheckReport::default();
+        let inodes = sharded_inodes(&*self.storage, Path::new(INODES_DIR)).await?;
+        let contents = sharded_inodes(&*self.storage, Path::new(CONTENTS_DIR)).await?;
+
+        let mut attrs = Vec::with_capacity(inodes.len());
+        for ino in &inodes {
+            match self.get_inode_from_storage(*ino).await {
+                Ok(attr) => attrs.push(attr),
+                Err(err) => {
+                    warn!(ino, err = %err, "reading inode");
+                    report.push(
+                        CheckProblemKind::CorruptInode,
+                        Some(*ino),
+                        None,
+                        None,
+                        false,
+                    );
+                }
+            }
+        }
+
+        let mut referenced = HashSet::new();
+        for (done, attr) in attrs.iter().enumerate() {
+            control.check()?;
+            self.check_contents(attr, &inodes, &mut referenced, repair, &mut report)
+                .await?;
+            control.progress(done as u64 + 1, attrs.len() as u64);
+        }
+
+        let orphans: Vec<_> = attrs
+            .iter()
+            .filter(|attr| attr.ino != ROOT_INODE && !referenced.contains(&attr.ino))
+            .collect();
+        if !orphans.is_empty() {
+            let lost_found = if repair {
+                Some(self.lost_found().await?)
+            } else {
+                None
+            };
+            for attr in orphans {
+                if let Some(lost_found) = lost_found {
+                    self.move_to_lost_found(lost_found, attr).await?;
+                }
+                report.push(
+                    CheckProblemKind::OrphanInode,
+                    Some(attr.ino),
+                    None,
+                    None,
+                    repair,
+                );
+            }
+        }
+
+        for ino in contents.difference(&inodes) {
+            report.push(
+                CheckProblemKind::OrphanContents,
+                Some(*ino),
+                None,
+                None,
+                false,
+            );
+        }
+
+        if let Some((_, stored)) = self.size_quota().await {
+            let actual = attrs
+                .iter()
+                .filter(|attr| attr.kind == FileType::RegularFile)
+                .fold(0_u64, |used, attr| used.saturating_add(attr.size));
+            if stored != actual {
+                if repair {
+                    self.set_used_bytes(actual).await?;
+                }
+                report.push(
+                    CheckProblemKind::UsedBytesMismatch { stored, actual },
+                    None,
+                    None,
+                    None,
+                    repair,
+                );
+            }
+        }
+
+        if repair {
+            // the entries were changed directly in the storage
+            self.clear_dir_entry_caches().await?;
+        }
+        info!(problems = report.problems.len(), repair, "check done");
+        Ok(report)
+    }
+
+    /// Rebuild the `hash` entries of the directory `ino` from its `ls` entries.
+    ///
+    /// [`EncryptedFs::find_by_name`] and [`EncryptedFs::exists_by_name`] only look in `hash`, use this if it was
+    /// damaged. `ls` entries that can't be decrypted are skipped and `hash` entries without an `ls` entry are removed.
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn rebuild_dir_index(&self, ino: u64) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if !self.exists(ino).await {
+            return Err(FsError::InodeNotFound);
+        }
+        if !self.is_dir(ino).await {
+            return Err(FsError::InvalidInodeType);
+        }
+        let key = self.key.get().await?;
+        // the entries can't be told from the corrupt ones without their names
+        let names_key = self.inode_key(ino).await?;
+        let dir = self.contents_path(ino);
+        let hash_dir = dir.join(HASH_DIR);
+        if self.kind_at(&hash_dir).await != Some(EntryKind::Dir) {
+            self.storage.create_dir(&hash_dir).await?;
+            self.storage.sync(&dir).await?;
+        }
+        // all hashes are recreated, so we can start with a new salt if it was lost
+        if self.kind_at(&dir.join(NAME_SALT_FILENAME)).await != Some(EntryKind::File) {
+            write_name_salt(&*self.storage, &dir).await?;
+        }
+
+        let mut hashes = HashSet::new();
+        for entry in self.storage.list(&dir.join(LS_DIR)).await? {
+            let path = dir.join(LS_DIR).join(&entry.name);
+            let lock = self
+                .serialize_dir_entries_ls_locks
+                .get_or_insert_with(path.to_str().unwrap().to_owned(), || RwLock::new(false));
+            let guard = lock.read().await;
+            let ls_entry = self.read_ls_entry(ino, &path, &key).await;
+            drop(guard);
+            let Some((name, (entry_ino, kind, encrypted_name))) =
+                ls_entry.ok().and_then(|ls_entry| {
+                    Some((self.decrypt_entry_name(&ls_entry.2, &names_key)?, ls_entry))
+                })
+            else {
+                warn!(ino, "skipping corrupt ls entry");
+                continue;
+            };
+            let hash = self.hash_entry_name(ino, &name).await?;
+            let hash_path = hash_dir.join(&hash);
+            let lock = self
+                .serialize_dir_entries_hash_locks
+                .get_or_insert_with(hash_path.to_str().unwrap().to_owned(), || {
+                    RwLock::new(false)
+                });
+            let _guard = lock.write().await;
+            serialize_bound_into(
+                &*self.storage,
+                &hash_path,
+                &(entry_ino, kind, encrypted_name),
+                self.cipher,
+                &key,
+                &dir_entry_aad(ino, &hash),
+            )
+            .await?;
+            hashes.insert(hash);
+        }
+
+        for entry in self.storage.list(&hash_dir).await? {
+            if hashes.contains(&entry.name) {
+                continue;
+            }
+            let path = hash_dir.join(entry.name);
+            let lock = self
+                .serialize_dir_entries_hash_locks
+                .get_or_insert_with(path.to_str().unwrap().to_owned(), || RwLock::new(false));
+            let _guard = lock.write().await;
+            self.storage.remove(&path).await?;
+        }
+        info!(ino, entries = hashes.len(), "rebuilt dir index");
+        Ok(())
+    }
+
+    /// `true` if some `ls` entries of the directory `ino` don't have a `hash` entry.
+    ///
+    /// Only counts the entries, so it's cheaper than decrypting all names.
+    pub(crate) async fn is_dir_index_damaged(&self, ino: u64) -> FsResult<bool> {
+        let dir = self.contents_path(ino);
+        let hash_dir = dir.join(HASH_DIR);
+        if self.kind_at(&hash_dir).await != Some(EntryKind::Dir) {
+            return Ok(true);
+        }
+        Ok(self.storage.list(&hash_dir).await?.len()
+            < self.storage.list(&dir.join(LS_DIR)).await?.len())
+    }
+
+    async fn check_contents(
+        &self,
+        attr: &FileAttr,
+        inodes: &HashSet<u64>,
+        referenced: &mut HashSet<u64>,
+        repair: bool,
+        report: &mut CheckReport,
+    ) -> FsResult<()> {
+        let path = self.contents_path(attr.ino);
+        match attr.kind {
+            // the special files have an empty contents file
+            FileType::RegularFile
+            | FileType::NamedPipe
+            | FileType::CharDevice
+            | FileType::BlockDevice
+            | FileType::Socket => {
+                if self.kind_at(&path).await != Some(EntryKind::File) {
+                    report.push(
+                        CheckProblemKind::MissingContents,
+                        Some(attr.ino),
+                        None,
+                        None,
+                        false,
+                    );
+                    return Ok(());
+                }
+                let contents_size = self.decryptable_len(attr.ino, attr.compressed).await?;
+                if contents_size != attr.size {
+                    report.push(
+                        CheckProblemKind::SizeMismatch {
+                            attr_size: attr.size,
+                            contents_size,
+                        },
+                        Some(attr.ino),
+                        None,
+                        None,
+                        false,
+                    );
+                }
+            }
+            FileType::Directory => {
+                if self.kind_at(&path.join(LS_DIR)).await != Some(EntryKind::Dir)
+                    || self.kind_at(&path.join(HASH_DIR)).await != Some(EntryKind::Dir)
+                    || self.kind_at(&path.join(NAME_SALT_FILENAME)).await != Some(EntryKind::File)
+                {
+                    report.push(
+                        CheckProblemKind::MissingContents,
+                        Some(attr.ino),
+                        None,
+                        None,
+                        false,
+                    );
+                    return Ok(());
+                }
+                self.check_dir_entries(attr.ino, inodes, referenced, repair, report)
+                    .await?;
+                self.check_child_count(attr.ino, repair, report).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Bytes we can decrypt from the contents, and decompress if `compressed`, reading stops at the first block that
+    /// fails.
+    async fn decryptable_len(&self, ino: u64, compressed: bool) -> FsResult<u64> {
+        let reader = crypto::create_sparse_read(
+            self.open_contents(ino).await?,
+            self.cipher,
+            &*self.inode_key(ino).await?,
+            self.contents_holes(ino).await?,
+        );
+        let mut reader: Box<dyn Read> = if compressed {
+            Box::new(zstd::Decoder::new(reader)?)
+        } else {
+            Box::new(reader)
+        };
+        let mut buf = vec![0; BLOCK_SIZE];
+        let mut len = 0;
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(read) => len += read as u64,
+                Err(err) => {
+                    warn!(ino, err = %err, "decrypting contents");
+                    break;
+                }
+            }
+        }
+        Ok(len)
+    }
+
+    #[allow(clippy::too_many_lines)]
+    async fn check_dir_entries(
+        &self,
+        ino: u64,
+        inodes: &HashSet<u64>,
+        referenced: &mut HashSet<u64>,
+        repair: bool,
+        report: &mut CheckReport,
+    ) -> FsResult<()> {
+        let key = self.key.get().await?;
+        // the entries can't be told from the corrupt ones without their names
+        let names_key = self.inode_key(ino).await?;
+        let dir = self.contents_path(ino);
+
+        // hash entries by the name of the `ls` entry they point to, with the encrypted name
+        let mut hashes: HashMap<String, (PathBuf, u64, FileType, String)> = HashMap::new();
+        for entry in self.storage.list(&dir.join(HASH_DIR)).await? {
+            let path = dir.join(HASH_DIR).join(entry.name);
+            match self
+                .read_entry::<(u64, FileType, String)>(ino, &path, &key)
+                .await
+            {
+                Ok((entry_ino, kind, encrypted_name)) => {
+                    hashes.insert(
+                        ls_entry_name(&encrypted_name),
+                        (path, entry_ino, kind, encrypted_name),
+                    );
+                }
+                Err(err) => {
+                    warn!(ino, err = %err, "reading hash entry");
+                    if repair {
+                        self.storage.remove(&path).await?;
+                    }
+                    report.push(
+                        CheckProblemKind::CorruptEntry,
+                        None,
+                        Some(ino),
+                        None,
+                        repair,
+                    );
+                }
+            }
+        }
+
+        for entry in self.storage.list(&dir.join(LS_DIR)).await? {
+            let ls_name = entry.name;
+            let path = dir.join(LS_DIR).join(&ls_name);
+            let hash = hashes.remove(&ls_name);
+            let ls_entry = self.read_ls_entry(ino, &path, &key).await;
+            // a long encrypted name is only in the entry and in its hash entry
+            let encrypted_name = match (&ls_entry, &hash) {
+                (Ok((_, _, encrypted_name)), _) | (Err(_), Some((.., encrypted_name))) => {
+                    encrypted_name.clone()
+                }
+                (Err(_), None) => ls_name.clone(),
+            };
+            let name = self.decrypt_entry_name(&encrypted_name, &names_key);
+            let (Some(name), Ok((entry_ino, kind, _))) = (name.clone(), ls_entry) else {
+                if repair {
+                    remove_entry(
+                        &*self.storage,
+                        &path,
+                        hash.as_ref().map(|(path, ..)| path.as_path()),
+                    )
+                    .await?;
+                    self.adjust_child_count(ino, -1).await?;
+                }
+                report.push(
+                    CheckProblemKind::CorruptEntry,
+                    None,
+                    Some(ino),
+                    name,
+                    repair,
+                );
+                continue;
+            };
+            if !inodes.contains(&entry_ino) {
+                if repair {
+                    remove_entry(
+                        &*self.storage,
+                        &path,
+                        hash.as_ref().map(|(path, ..)| path.as_path()),
+                    )
+                    .await?;
+                    self.adjust_child_count(ino, -1).await?;
+                }
+                report.push(
+                    CheckProblemKind::DanglingEntry,
+                    Some(entry_ino),
+                    Some(ino),
+                    Some(name),
+                    repair,
+                );
+                continue;
+            }
+            if !is_special(&name) {
+                referenced.insert(entry_ino);
+            }
+            let hash_name = self.hash_entry_name(ino, &name).await?;
+            let hash_path = dir.join(HASH_DIR).join(&hash_name);
+            let hash_ok = hash.as_ref().is_some_and(|(path, hash_ino, hash_kind, _)| {
+                *path == hash_path && *hash_ino == entry_ino && *hash_kind == kind
+            });
+            if !hash_ok {
+                if repair {
+                    if let Some((path, ..)) = &hash {
+                        self.storage.remove(path).await?;
+                    }
+                    serialize_bound_into(
+                        &*self.storage,
+                        &hash_path,
+                        &(entry_ino, kind, encrypted_name),
+                        self.cipher,
+                        &key,
+                        &dir_entry_aad(ino, &hash_name),
+                    )
+                    .await?;
+                }
+                report.push(
+                    CheckProblemKind::MissingHashEntry,
+                    Some(entry_ino),
+                    Some(ino),
+                    Some(name),
+                    repair,
+                );
+            }
+        }
+
+        // what's left has no ls entry
+        for (ls_name, (path, entry_ino, kind, encrypted_name)) in hashes {
+            let name = self.decrypt_entry_name(&encrypted_name, &names_key);
+            if name.is_none() || !inodes.contains(&entry_ino) {
+                if repair {
+                    self.storage.remove(&path).await?;
+                }
+                report.push(
+                    CheckProblemKind::DanglingEntry,
+                    Some(entry_ino),
+                    Some(ino),
+                    name,
+                    repair,
+                );
+                continue;
+            }
+            if name.as_ref().is_some_and(|name| !is_special(name)) {
+                referenced.insert(entry_ino);
+            }
+            if repair {
+                let (_, data) =
+                    serialize_ls_entry(ino, &encrypted_name, entry_ino, kind, self.cipher, &key)?;
+                self.storage
+                    .write(&dir.join(LS_DIR).join(ls_name), &data)
+                    .await?;
+                self.adjust_child_count(ino, 1).await?;
+            }
+            report.push(
+                CheckProblemKind::MissingLsEntry,
+                Some(entry_ino),
+                Some(ino),
+                name,
+                repair,
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Keep the count in [`CHILD_COUNT_FILENAME`] right when repairing changes the `ls` entries.
+    async fn adjust_child_count(&self, ino: u64, delta: i64) -> FsResult<()> {
+        let path = self.contents_path(ino).join(CHILD_COUNT_FILENAME);
+        let lock = self
+            .serialize_dir_entries_ls_locks
+            .get_or_insert_with(path.to_str().unwrap().to_owned(), || RwLock::new(false));
+        let _guard = lock.write().await;
+        self.update_child_count(ino, delta).await
+    }
+
+    /// Compare the count in [`CHILD_COUNT_FILENAME`] with the entries left after [`Self::check_dir_entries`].
+    ///
+    /// A missing count is not a problem, it's made by the next [`EncryptedFs::len`].
+    async fn check_child_count(
+        &self,
+        ino: u64,
+        repair: bool,
+        report: &mut CheckReport,
+    ) -> FsResult<()> {
+        let path = self.contents_path(ino).join(CHILD_COUNT_FILENAME);
+        if self.kind_at(&path).await != Some(EntryKind::File) {
+            return Ok(());
+        }
+        let lock = self
+            .serialize_dir_entries_ls_locks
+            .get_or_insert_with(path.to_str().unwrap().to_owned(), || RwLock::new(false));
+        let _guard = lock.write().await;
+        let actual = self
+            .storage
+            .list(&self.contents_path(ino).join(LS_DIR))
+            .await?
+            .len() as u64;
+        let stored = self.read_child_count(ino).await;
+        if stored == Some(actual) {
+            return Ok(());
+        }
+        if repair {
+            self.write_bound(&path, &actual, &child_count_aad(ino))
+                .await?;
+        }
+        report.push(
+            CheckProblemKind::ChildCountMismatch { stored, actual },
+            Some(ino),
+            None,
+            None,
+            repair,
+        );
+        Ok(())
+    }
+
+    /// Read the entry at `path` in the directory `parent`.
+    async fn read_entry<T: DeserializeOwned>(
+        &self,
+        parent: u64,
+        path: &Path,
+        key: &SecretVec<u8>,
+    ) -> FsResult<T> {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        deserialize_bound(
+            self.storage.open(path, false).await?,
+            self.cipher,
+            key,
+            &dir_entry_aad(parent, &file_name),
+        )
+    }
+
+    fn decrypt_entry_name(
+        &self,
+        encrypted_name: &str,
+        key: &SecretVec<u8>,
+    ) -> Option<SecretString> {
+        match encrypted_name {
+            crypto::SELF_ENTRY_NAME => Some(SecretString::from_str(".").unwrap()),
+            crypto::PARENT_ENTRY_NAME => Some(SecretString::from_str("..").unwrap()),
+            _ => crypto::decrypt_file_name(encrypted_name, self.cipher, key)
+                .map_err(|err| {
+                    warn!(err = %err, "decrypting file name");
+                    err
+                })
+                .ok(),
+        }
+    }
+
+    /// Find [`LOST_FOUND_DIR`] in the root, creating it if missing.
+    async fn lost_found(&self) -> FsResult<u64> {
+        let name = SecretString::from_str(LOST_FOUND_DIR).unwrap();
+        if let Some(attr) = self.find_by_name(ROOT_INODE, &name).await? {
+            return Ok(attr.ino);
+        }
+        let (_, attr) = self
+            .create(
+                ROOT_INODE,
+                &name,
+                CreateFileAttr {
+                    kind: FileType::Directory,
+                    perm: 0o700,
+                    uid: 0,
+                    gid: 0,
+                    rdev: 0,
+                    flags: 0,
+                    compress: None,
+                },
+                false,
+                false,
+            )
+            .await?;
+        Ok(attr.ino)
+    }
+
+    /// Add an entry named after the inode in `lost_found`, directories get their `..` pointed to it.
+    async fn move_to_lost_found(&self, lost_found: u64, attr: &FileAttr) -> FsResult<()> {
+        self.insert_directory_entry(
+            lost_found,
+            &DirectoryEntry {
+                ino: attr.ino,
+                name: SecretString::from_str(&attr.ino.to_string()).unwrap(),
+                kind: attr.kind,
+            },
+        )
+        .await?;
+        if attr.kind == FileType::Directory
+            && self
+                .kind_at(&self.contents_path(attr.ino).join(LS_DIR))
+                .await
+                == Some(EntryKind::Dir)
+        {
+            self.insert_directory_entry(
+                attr.ino,
+                &DirectoryEntry {
+                    ino: lost_found,
+                    name: SecretString::from_str("$..").unwrap(),
+                    kind: FileType::Directory,
+                },
+            )
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+fn is_special(name: &SecretString) -> bool {
+    *name.expose_secret() == "." || *name.expose_secret() == ".."
+}
+
+/// Remove the `ls` entry and its `hash` entry if we have it.
+async fn remove_entry(
+    storage: &dyn Storage,
+    ls_path: &Path,
+    hash_path: Option<&Path>,
+) -> FsResult<()> {
+    storage.remove(ls_path).await?;
+    if let Some(hash_path) = hash_path {
+        storage.remove(hash_path).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,651 @@
+//! Re-encryption of the data dir with another [`Cipher`], see [`EncryptedFs::change_cipher`].
+//!
+//! Each item, the key, an inode, the entries of a directory or the contents of a file, is re-encrypted next to the
+//! current one and then moved over it. The journal in [`SECURITY_DIR`] records when an item was written and when it
+//! was moved, so an interrupted run can be continued by another one with the same ciphers.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use shush_rs::{SecretString, SecretVec};
+use tracing::info;
+
+use crate::crypto;
+use crate::crypto::holes::Holes;
+use crate::crypto::write::CryptoWrite;
+use crate::crypto::Cipher;
+use crate::encryptedfs::changes::{self, changes_dir, tombstones_path, Tombstones, TOMBSTONES_AAD};
+use crate::encryptedfs::dir_keys;
+use crate::encryptedfs::journal::has_pending;
+use crate::encryptedfs::key_slots::{self, KeySlot, KeySlots};
+use crate::encryptedfs::quota::{quota_dirs_path, used_bytes_path, QUOTA_DIRS_AAD, USED_BYTES_AAD};
+use crate::encryptedfs::{
+    check_structure, cipher_segment_size, deserialize_bound, deserialize_ls_entry, dir_entry_aad,
+    inode_aad, key_path, lock_instance, read_or_create_key, run_migrations, serialize_bound_into,
+    serialize_ls_entry, shard_path, sharded_inodes, write_cipher, EncryptedFs, FileAttr, FileType,
+    FsError, FsResult, OpControl, CHILD_COUNT_FILENAME, CONTENTS_DIR, HASH_DIR, INODES_DIR, LS_DIR,
+    SECURITY_DIR, SNAPSHOTS_DIR,
+};
+use crate::segmented_file::SegmentedFile;
+use crate::storage::{EntryKind, LocalStorage, Storage, StorageFile};
+
+/// Journal of [`EncryptedFs::change_cipher`], kept in [`SECURITY_DIR`] until it completes.
+pub(crate) const CIPHER_JOURNAL_FILENAME: &str = "cipher.journal";
+
+/// Extension of the files and directories written with the new cipher, before they are moved over the current ones.
+const TMP_EXTENSION: &str = "cipher";
+
+/// Returns [`FsError::CipherChangeInterrupted`] if a [`EncryptedFs::change_cipher`] didn't complete.
+pub(crate) async fn check_not_interrupted(storage: &dyn Storage) -> FsResult<()> {
+    if storage.kind(&journal_path()).await?.is_some() {
+        return Err(FsError::CipherChangeInterrupted);
+    }
+    Ok(())
+}
+
+fn journal_path() -> PathBuf {
+    Path::new(SECURITY_DIR).join(CIPHER_JOURNAL_FILENAME)
+}
+
+impl EncryptedFs {
+    /// Re-encrypt the data dir, from the `from` cipher to the `to` one.
+    ///
+    /// The key, the inodes, the directory entries and the contents are all re-encrypted, the key is done last.
+    /// The progress of `control` is called with the number of items done and the total number of items after each of
+    /// them.
+    ///
+    /// If interrupted, or `control` is cancelled, the data dir can't be opened until this is called again with the same
+    /// ciphers, which continues from where it stopped.
+    /// It returns [`FsError::AlreadyMounted`] if the data dir is used by an [`EncryptedFs`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn change_cipher(
+        data_dir: &Path,
+        password: SecretString,
+        from: Cipher,
+        to: Cipher,
+        control: &OpControl,
+    ) -> FsResult<()> {
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(data_dir));
+        check_structure(&*storage, false).await?;
+        let _lock = lock_instance(data_dir, true)?;
+        let key_path = key_path();
+        let journal_path = journal_path();
+        let mut journal = if storage.kind(&journal_path).await?.is_some() {
+            Journal::open(&*storage, &journal_path, from, to).await?
+        } else {
+            if from == to {
+                return Err(FsError::invalid_input("already using this cipher"));
+            }
+            // their records would not be readable with the new cipher
+            if has_pending(&*storage).await? {
+                return Err(FsError::invalid_input(
+                    "interrupted operations are pending, open the data dir once to complete them",
+                ));
+            }
+            // they are not re-encrypted
+            if storage.kind(Path::new(SNAPSHOTS_DIR)).await?.is_some()
+                && !storage.list(Path::new(SNAPSHOTS_DIR)).await?.is_empty()
+            {
+                return Err(FsError::invalid_input(
+                    "the data dir has snapshots, delete them first",
+                ));
+            }
+            // the trees encrypted with them would need them all unlocked
+            if dir_keys::has_directory_keys(&*storage).await? {
+                return Err(FsError::invalid_input(
+                    "the data dir has directory keys, their cipher can't be changed",
+                ));
+            }
+            // make sure the layout is the current one before we rewrite it
+            let key = read_or_create_key(&*storage, &key_path, &password, from).await?;
+            run_migrations(&*storage, from, &key).await?;
+            Journal::create(&*storage, &journal_path, from, to).await?
+        };
+
+        let key = if journal.built.contains_key(&Item::Key) {
+            // all the rest is done, only check the password
+            let tmp_key_path = tmp_path(&key_path);
+            let path = if storage.kind(&tmp_key_path).await?.is_some() {
+                tmp_key_path
+            } else {
+                key_path.clone()
+            };
+            read_or_create_key(&*storage, &path, &password, to).await?
+        } else {
+            read_or_create_key(&*storage, &key_path, &password, from).await?
+        };
+
+        let items = items(&*storage).await?;
+        let total = items.len() as u64;
+        info!(from = %from, to = %to, items = total, "changing cipher");
+        let change = CipherChange {
+            storage: &storage,
+            from,
+            to,
+            key: &key,
+            password: &password,
+        };
+        for (done, item) in items.iter().enumerate() {
+            // the journal has what was done so far
+            control.check()?;
+            if !journal.done.contains(item) {
+                let segments = match journal.built.get(item) {
+                    Some(segments) => *segments,
+                    None => {
+                        let segments = change.build(item).await?;
+                        journal.append(&format!("built {item} {segments}"))?;
+                        segments
+                    }
+                };
+                change.commit(item, segments).await?;
+                journal.append(&format!("done {item}"))?;
+            }
+            control.progress(done as u64 + 1, total);
+        }
+
+        write_cipher(&*storage, to).await?;
+        drop(journal);
+        storage.remove(&journal_path).await?;
+        storage.sync(Path::new(SECURITY_DIR)).await?;
+        Ok(())
+    }
+}
+
+/// What is re-encrypted at once by [`EncryptedFs::change_cipher`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Item {
+    Inode(u64),
+    Contents(u64),
+    Dir(u64),
+    Tombstones,
+    Changes,
+    Quota,
+    Key,
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Inode(ino) => write!(f, "inode/{ino}"),
+            Self::Contents(ino) => write!(f, "contents/{ino}"),
+            Self::Dir(ino) => write!(f, "dir/{ino}"),
+            Self::Tombstones => write!(f, "tombstones"),
+            Self::Changes => write!(f, "changes"),
+            Self::Quota => write!(f, "quota"),
+            Self::Key => write!(f, "key"),
+        }
+    }
+}
+
+impl FromStr for Item {
+    type Err = FsError;
+
+    fn from_str(s: &str) -> FsResult<Self> {
+        match s {
+            "key" => return Ok(Self::Key),
+            "tombstones" => return Ok(Self::Tombstones),
+            "changes" => return Ok(Self::Changes),
+            "quota" => return Ok(Self::Quota),
+            _ => {}
+        }
+        let (kind, ino) = s
+            .split_once('/')
+            .ok_or_else(|| FsError::invalid_input("invalid journal item"))?;
+        let ino = ino.parse()?;
+        match kind {
+            "inode" => Ok(Self::Inode(ino)),
+            "contents" => Ok(Self::Contents(ino)),
+            "dir" => Ok(Self::Dir(ino)),
+            _ => Err(FsError::invalid_input("invalid journal item")),
+        }
+    }
+}
+
+/// All the items of the data dir, the key is the last one.
+async fn items(storage: &dyn Storage) -> FsResult<Vec<Item>> {
+    let mut inodes: Vec<_> = sharded_inodes(storage, Path::new(INODES_DIR))
+        .await?
+        .into_iter()
+        .collect();
+    inodes.sort_unstable();
+    let contents_dir = Path::new(CONTENTS_DIR);
+    let mut contents: Vec<_> = sharded_inodes(storage, contents_dir)
+        .await?
+        .into_iter()
+        .collect();
+    contents.sort_unstable();
+    let mut items: Vec<_> = inodes.into_iter().map(Item::Inode).collect();
+    for ino in contents {
+        items.push(
+            if storage.kind(&shard_path(contents_dir, ino)).await? == Some(EntryKind::Dir) {
+                Item::Dir(ino)
+            } else {
+                Item::Contents(ino)
+            },
+        );
+    }
+    if storage.kind(&tombstones_path()).await?.is_some() {
+        items.push(Item::Tombstones);
+    }
+    if storage.kind(&changes_dir()).await?.is_some() {
+        items.push(Item::Changes);
+    }
+    if storage.kind(&used_bytes_path()).await?.is_some()
+        || storage.kind(&quota_dirs_path()).await?.is_some()
+    {
+        items.push(Item::Quota);
+    }
+    items.push(Item::Key);
+    Ok(items)
+}
+
+/// Items done by a previous run, from [`CIPHER_JOURNAL_FILENAME`].
+///
+/// Each line is `built <item> <segments>` after the item was written next to the current one, `<segments>` being
+/// the number of segments of the contents, or `done <item>` after it was moved over the current one.
+struct Journal {
+    file: Box<dyn StorageFile>,
+    built: HashMap<Item, usize>,
+    done: HashSet<Item>,
+}
+
+impl Journal {
+    async fn create(
+        storage: &dyn Storage,
+        path: &Path,
+        from: Cipher,
+        to: Cipher,
+    ) -> FsResult<Self> {
+        storage
+            .write(path, format!("{from} {to}\n").as_bytes())
+            .await?;
+        let mut file = storage.open(path, true).await?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            file,
+            built: HashMap::new(),
+            done: HashSet::new(),
+        })
+    }
+
+    async fn open(storage: &dyn Storage, path: &Path, from: Cipher, to: Cipher) -> FsResult<Self> {
+        let content = String::from_utf8(storage.read(path).await?)
+            .map_err(|_| FsError::invalid_input("invalid journal"))?;
+        // a line without the new line was interrupted while writing it
+        let mut lines = content
+            .split_inclusive('\n')
+            .filter_map(|line| line.strip_suffix('\n'));
+        if lines.next() != Some(format!("{from} {to}").as_str()) {
+            return Err(FsError::invalid_input(
+                "changing to another cipher was interrupted",
+            ));
+        }
+        let mut built = HashMap::new();
+        let mut done = HashSet::new();
+        for line in lines {
+            let mut parts = line.split(' ');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some("built"), Some(item), Some(segments)) => {
+                    built.insert(item.parse()?, segments.parse()?);
+                }
+                (Some("done"), Some(item), None) => {
+                    done.insert(item.parse()?);
+                }
+                _ => return Err(FsError::invalid_input("invalid journal line")),
+            }
+        }
+        let mut file = storage.open(path, true).await?;
+        file.seek(SeekFrom::End(0))?;
+        if !content.is_empty() && !content.ends_with('\n') {
+            file.write_all(b"\n")?;
+        }
+        Ok(Self { file, built, done })
+    }
+
+    fn append(&mut self, line: &str) -> io::Result<()> {
+        self.file.write_all(format!("{line}\n").as_bytes())?;
+        self.file.sync_data()
+    }
+}
+
+struct CipherChange<'a> {
+    storage: &'a Arc<dyn Storage>,
+    from: Cipher,
+    to: Cipher,
+    key: &'a SecretVec<u8>,
+    password: &'a SecretString,
+}
+
+impl CipherChange<'_> {
+    /// Write the item with the new cipher next to the current one, returns the number of segments written for
+    /// the contents.
+    async fn build(&self, item: &Item) -> FsResult<usize> {
+        let storage = &**self.storage;
+        match *item {
+            Item::Inode(ino) => {
+                let path = shard_path(Path::new(INODES_DIR), ino);
+                let aad = inode_aad(ino);
+                let inode: (FileAttr, Holes) = deserialize_bound(
+                    storage.open(&path, false).await?,
+                    self.from,
+                    self.key,
+                    &aad,
+                )?;
+                serialize_bound_into(storage, &tmp_path(&path), &inode, self.to, self.key, &aad)
+                    .await?;
+                Ok(0)
+            }
+            Item::Tombstones => {
+                let path = tombstones_path();
+                let tombstones: Tombstones = deserialize_bound(
+                    storage.open(&path, false).await?,
+                    self.from,
+                    self.key,
+                    TOMBSTONES_AAD,
+                )?;
+                serialize_bound_into(
+                    storage,
+                    &tmp_path(&path),
+                    &tombstones,
+                    self.to,
+                    self.key,
+                    TOMBSTONES_AAD,
+                )
+                .await?;
+                Ok(0)
+            }
+            Item::Changes => {
+                let (log, segments) =
+                    changes::read_log(storage, &changes_dir(), self.from, self.key).await?;
+                changes::write_log(
+                    storage,
+                    &tmp_path(&changes_dir()),
+                    &log,
+                    &segments,
+                    self.to,
+                    self.key,
+                )
+                .await?;
+                Ok(0)
+            }
+            Item::Quota => {
+                let path = used_bytes_path();
+                if storage.kind(&path).await?.is_some() {
+                    let used: u64 = deserialize_bound(
+                        storage.open(&path, false).await?,
+                        self.from,
+                        self.key,
+                        USED_BYTES_AAD,
+                    )?;
+                    serialize_bound_into(
+                        storage,
+                        &tmp_path(&path),
+                        &used,
+                        self.to,
+                        self.key,
+                        USED_BYTES_AAD,
+                    )
+                    .await?;
+                }
+                let path = quota_dirs_path();
+                if storage.kind(&path).await?.is_some() {
+                    let dirs: Vec<u64> = deserialize_bound(
+                        storage.open(&path, false).await?,
+                        self.from,
+                        self.key,
+                        QUOTA_DIRS_AAD,
+                    )?;
+                    serialize_bound_into(
+                        storage,
+                        &tmp_path(&path),
+                        &dirs,
+                        self.to,
+                        self.key,
+                        QUOTA_DIRS_AAD,
+                    )
+                    .await?;
+                }
+                Ok(0)
+            }
+            Item::Contents(ino) => {
+                let path = shard_path(Path::new(CONTENTS_DIR), ino);
+                let tmp = tmp_path(&path);
+                if storage.kind(&tmp).await?.is_some() {
+                    SegmentedFile::remove(self.storage, &tmp).await?;
+                }
+                // the inodes are already with the new cipher, the holes are the same blocks in both, and the blocks
+                // of zeros written before the holes are kept become holes too
+                let inode_path = shard_path(Path::new(INODES_DIR), ino);
+                let aad = inode_aad(ino);
+                let (attr, holes): (FileAttr, Holes) = deserialize_bound(
+                    storage.open(&inode_path, false).await?,
+                    self.to,
+                    self.key,
+                    &aad,
+                )?;
+                let holes = Arc::new(Mutex::new(holes));
+                let mut reader = crypto::create_sparse_read(
+                    SegmentedFile::open(self.storage, &path, cipher_segment_size(self.from))
+                        .await?,
+                    self.from,
+                    self.key,
+                    holes.clone(),
+                );
+                let mut writer = crypto::create_sparse_write(
+                    SegmentedFile::create(self.storage, &tmp, cipher_segment_size(self.to)).await?,
+                    self.to,
+                    self.key,
+                    holes.clone(),
+                );
+                io::copy(&mut reader, &mut writer)?;
+                writer.finish()?.sync_all()?;
+                // the holes are still holes, so the inode is right for the current contents too until they are moved
+                let holes = holes.lock().unwrap().clone();
+                if holes.changed() {
+                    serialize_bound_into(
+                        storage,
+                        &inode_path,
+                        &(attr, holes),
+                        self.to,
+                        self.key,
+                        &aad,
+                    )
+                    .await?;
+                }
+                storage
+                    .sync(path.parent().expect("oops, we don't have a parent"))
+                    .await?;
+                Ok(SegmentedFile::segments_count(self.storage, &tmp).await?)
+            }
+            Item::Dir(ino) => {
+                self.build_dir(ino).await?;
+                Ok(0)
+            }
+            Item::Key => {
+                // the other slots keep their cipher, we can't open them without their password
+                let key_path = key_path();
+                let (mut slots, index, _) =
+                    KeySlots::read_and_open(storage, &key_path, self.from, self.password).await?;
+                slots.slots[index] = KeySlot::seal(self.key, self.password, self.to)?;
+                // the backup is made from it once it's renamed in place
+                storage
+                    .write(&tmp_path(&key_path), &slots.to_bytes()?)
+                    .await?;
+                Ok(0)
+            }
+        }
+    }
+
+    /// Write the `ls` and `hash` entries of a directory with the new cipher in temp dirs.
+    ///
+    /// The names in `ls` are encrypted, so they change and so do the names kept in the `hash` entries. The hashes
+    /// are keyed with the key, which stays the same, so the names in `hash` don't change.
+    async fn build_dir(&self, ino: u64) -> FsResult<()> {
+        let storage = &**self.storage;
+        let dir = shard_path(Path::new(CONTENTS_DIR), ino);
+        let tmp_ls_dir = tmp_path(&dir.join(LS_DIR));
+        let tmp_hash_dir = tmp_path(&dir.join(HASH_DIR));
+        for tmp_dir in [&tmp_ls_dir, &tmp_hash_dir] {
+            if storage.kind(tmp_dir).await?.is_some() {
+                storage.remove_dir(tmp_dir).await?;
+            }
+            storage.create_dir(tmp_dir).await?;
+        }
+        let mut new_names = HashMap::new();
+        for entry in storage.list(&dir.join(LS_DIR)).await? {
+            let (entry_ino, kind, encrypted_name) = deserialize_ls_entry(
+                storage
+                    .open(&dir.join(LS_DIR).join(&entry.name), false)
+                    .await?,
+                ino,
+                &entry.name,
+                self.from,
+                self.key,
+            )?;
+            let new_name = self.re_encrypt_name(&encrypted_name)?;
+            // the new name may fit in the name of the entry when the old one didn't, or the other way around
+            let (ls_name, data) =
+                serialize_ls_entry(ino, &new_name, entry_ino, kind, self.to, self.key)?;
+            storage.write(&tmp_ls_dir.join(ls_name), &data).await?;
+            new_names.insert(encrypted_name, new_name);
+        }
+        for entry in storage.list(&dir.join(HASH_DIR)).await? {
+            let hash = entry.name;
+            let aad = dir_entry_aad(ino, &hash);
+            let (entry_ino, kind, encrypted_name): (u64, FileType, String) = deserialize_bound(
+                storage.open(&dir.join(HASH_DIR).join(&hash), false).await?,
+                self.from,
+                self.key,
+                &aad,
+            )?;
+            let new_name = match new_names.remove(&encrypted_name) {
+                Some(new_name) => new_name,
+                None => self.re_encrypt_name(&encrypted_name)?,
+            };
+            serialize_bound_into(
+                storage,
+                &tmp_hash_dir.join(&hash),
+                &(entry_ino, kind, new_name),
+                self.to,
+                self.key,
+                &aad,
+            )
+            .await?;
+        }
+        storage.sync(&tmp_ls_dir).await?;
+        storage.sync(&tmp_hash_dir).await?;
+        Ok(())
+    }
+
+    fn re_encrypt_name(&self, encrypted_name: &str) -> FsResult<String> {
+        if encrypted_name == crypto::SELF_ENTRY_NAME || encrypted_name == crypto::PARENT_ENTRY_NAME
+        {
+            return Ok(encrypted_name.to_string());
+        }
+        // keep the padding, so the name has the same length as before
+        let name = crypto::decrypt_padded_file_name(encrypted_name, self.from, self.key)?;
+        crypto::encrypt_file_name(&name, self.to, self.key)
+    }
+
+    /// Move the item written by [`Self::build`] over the current one.
+    ///
+    /// Can be repeated if interrupted.
+    async fn commit(&self, item: &Item, segments: usize) -> FsResult<()> {
+        let storage = &**self.storage;
+        let path = match *item {
+            Item::Inode(ino) => {
+                let path = shard_path(Path::new(INODES_DIR), ino);
+                rename_if_exists(storage, &tmp_path(&path), &path).await?;
+                path
+            }
+            Item::Contents(ino) => {
+                let path = shard_path(Path::new(CONTENTS_DIR), ino);
+                let tmp = tmp_path(&path);
+                // segments past the new ones, from the last one so they stay contiguous
+                let count = SegmentedFile::segments_count(self.storage, &path).await?;
+                for index in (segments..count.max(segments)).rev() {
+                    storage
+                        .remove(&SegmentedFile::segment_path(&path, index))
+                        .await?;
+                }
+                for index in 0..segments {
+                    rename_if_exists(
+                        storage,
+                        &SegmentedFile::segment_path(&tmp, index),
+                        &SegmentedFile::segment_path(&path, index),
+                    )
+                    .await?;
+                }
+                path
+            }
+            Item::Dir(ino) => {
+                let dir = shard_path(Path::new(CONTENTS_DIR), ino);
+                for name in [LS_DIR, HASH_DIR] {
+                    let path = dir.join(name);
+                    let tmp = tmp_path(&path);
+                    if storage.kind(&tmp).await?.is_some() {
+                        if storage.kind(&path).await?.is_some() {
+                            storage.remove_dir(&path).await?;
+                        }
+                        storage.rename(&tmp, &path).await?;
+                    }
+                }
+                // it's encrypted with the old cipher, the entries are counted again by the next `len`
+                let count_path = dir.join(CHILD_COUNT_FILENAME);
+                if storage.kind(&count_path).await?.is_some() {
+                    storage.remove(&count_path).await?;
+                }
+                dir.join(LS_DIR)
+            }
+            Item::Tombstones => {
+                let path = tombstones_path();
+                rename_if_exists(storage, &tmp_path(&path), &path).await?;
+                path
+            }
+            Item::Changes => {
+                let path = changes_dir();
+                let tmp = tmp_path(&path);
+                if storage.kind(&tmp).await?.is_some() {
+                    if storage.kind(&path).await?.is_some() {
+                        storage.remove_dir(&path).await?;
+                    }
+                    storage.rename(&tmp, &path).await?;
+                }
+                path
+            }
+            Item::Quota => {
+                let path = quota_dirs_path();
+                rename_if_exists(storage, &tmp_path(&path), &path).await?;
+                let path = used_bytes_path();
+                rename_if_exists(storage, &tmp_path(&path), &path).await?;
+                path
+            }
+            Item::Key => {
+                let path = key_path();
+                rename_if_exists(storage, &tmp_path(&path), &path).await?;
+                key_slots::write_backup(storage, &path).await?;
+                key_slots::remove_legacy_salt(storage, &path).await?;
+                path
+            }
+        };
+        storage
+            .sync(path.parent().expect("oops, we don't have a parent"))
+            .await?;
+        Ok(())
+    }
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    path.with_extension(TMP_EXTENSION)
+}
+
+async fn rename_if_exists(storage: &dyn Storage, from: &Path, to: &Path) -> io::Result<()> {
+    if storage.kind(from).await?.is_some() {
+        storage.rename(from, to).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,224 @@
+//! Compression of the contents before they are encrypted, for the files created with it, see
+//! [`FsOptions::compression_level`](crate::encryptedfs::FsOptions::compression_level).
+//!
+//! The plaintext of a compressed file is a zstd stream, with a frame for each writer that wrote something. Positions
+//! in the file don't map to positions in the stream, so compressed files can only be written at the end, other writes
+//! fail with [`FsError::InvalidInput`](crate::encryptedfs::FsError::InvalidInput). Reading forward decompresses what
+//! is skipped and reading backward decompresses again from the start, so they suit files written once and read
+//! sequentially, like logs and archives.
+
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+
+use zstd::stream::read::Decoder;
+use zstd::stream::write::Encoder;
+
+use crate::crypto::read::{CryptoRead, CryptoReadSeek};
+use crate::crypto::write::{CryptoWrite, CryptoWriteSeek};
+use crate::encryptedfs::{EncryptedFs, FsResult};
+use crate::segmented_file::SegmentedFile;
+
+type ContentsReader = Box<dyn CryptoReadSeek<SegmentedFile>>;
+type ContentsWriter = Box<dyn CryptoWriteSeek<SegmentedFile>>;
+
+/// Decompresses the contents of a compressed file while reading them.
+pub(crate) struct DecompressRead {
+    decoder: Option<Decoder<'static, BufReader<ContentsReader>>>,
+    pos: u64,
+}
+
+impl DecompressRead {
+    pub(crate) fn new(reader: ContentsReader) -> io::Result<Self> {
+        Ok(Self {
+            decoder: Some(Decoder::new(reader)?),
+            pos: 0,
+        })
+    }
+
+    fn take_reader(&mut self) -> ContentsReader {
+        self.decoder
+            .take()
+            .expect("decoder is missing")
+            .finish()
+            .into_inner()
+    }
+
+    /// Start decompressing again from the beginning.
+    fn rewind_stream(&mut self) -> io::Result<()> {
+        let mut reader = self.take_reader();
+        reader.seek(SeekFrom::Start(0))?;
+        self.decoder = Some(Decoder::new(reader)?);
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl Read for DecompressRead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self
+            .decoder
+            .as_mut()
+            .expect("decoder is missing")
+            .read(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for DecompressRead {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(pos) => self.pos.checked_add_signed(pos),
+            SeekFrom::End(pos) => {
+                // the length is known only after decompressing everything
+                io::copy(self, &mut io::sink())?;
+                self.pos.checked_add_signed(pos)
+            }
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "new position < 0"))?;
+        if new_pos < self.pos {
+            self.rewind_stream()?;
+        }
+        // like the crypto readers it stops at the end
+        let skip = new_pos - self.pos;
+        io::copy(&mut self.by_ref().take(skip), &mut io::sink())?;
+        Ok(self.pos)
+    }
+}
+
+impl CryptoRead<SegmentedFile> for DecompressRead {
+    fn into_inner(&mut self) -> SegmentedFile {
+        self.take_reader().into_inner()
+    }
+}
+
+impl CryptoReadSeek<SegmentedFile> for DecompressRead {}
+
+/// Compresses what is written at the end of a compressed file.
+///
+/// Seeking is allowed only to where it is, except before the first write, when the caller seeks to the size of the
+/// file after checking the write is at the end. Each frame starts at the end of the stream when it's written, so
+/// other writers can add to it between the frames.
+pub(crate) struct CompressWrite {
+    level: i32,
+    pos: u64,
+    writer: Option<ContentsWriter>,
+    encoder: Option<Encoder<'static, ContentsWriter>>,
+}
+
+impl CompressWrite {
+    /// The frames written are added after what `writer` has.
+    pub(crate) fn new(writer: ContentsWriter, level: i32) -> Self {
+        Self {
+            level,
+            pos: 0,
+            writer: Some(writer),
+            encoder: None,
+        }
+    }
+}
+
+impl Write for CompressWrite {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.encoder.is_none() {
+            // start the frame on the first write, so a writer finished without writing doesn't add an empty one
+            let mut writer = self.writer.take().ok_or_else(not_connected)?;
+            writer.seek(SeekFrom::End(0))?;
+            self.encoder = Some(Encoder::new(writer, self.level)?);
+        }
+        let len = self.encoder.as_mut().unwrap().write(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match (self.encoder.as_mut(), self.writer.as_mut()) {
+            (Some(encoder), _) => encoder.flush(),
+            (None, Some(writer)) => writer.flush(),
+            (None, None) => Err(not_connected()),
+        }
+    }
+}
+
+impl Seek for CompressWrite {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match pos {
+            SeekFrom::Start(pos) if self.encoder.is_none() => self.pos = pos,
+            SeekFrom::Start(pos) if pos == self.pos => {}
+            SeekFrom::Current(0) => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "compressed files can only be written at the end",
+                ))
+            }
+        }
+        Ok(self.pos)
+    }
+}
+
+impl CryptoWrite<SegmentedFile> for CompressWrite {
+    fn finish(&mut self) -> io::Result<SegmentedFile> {
+        let mut writer = match self.encoder.take() {
+            Some(encoder) => encoder.finish()?,
+            None => self.writer.take().ok_or_else(not_connected)?,
+        };
+        writer.finish()
+    }
+}
+
+impl CryptoWriteSeek<SegmentedFile> for CompressWrite {
+    /// Only between the frames, the next one starts at the end of the stream then.
+    fn release_block(&mut self) -> io::Result<bool> {
+        if self.encoder.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "a frame is being written",
+            ));
+        }
+        self.writer
+            .as_mut()
+            .ok_or_else(not_connected)?
+            .release_block()
+    }
+}
+
+fn not_connected() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "no writer")
+}
+
+impl EncryptedFs {
+    /// Level new compressed files are written with.
+    pub(crate) fn zstd_level(&self) -> i32 {
+        self.compression_level
+            .unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL)
+    }
+
+    /// Reader of the plaintext of `ino`, decompressing it if the file is compressed.
+    pub(crate) async fn create_contents_reader(&self, ino: u64) -> FsResult<ContentsReader> {
+        let reader: ContentsReader = Box::new(self.create_read_seek_with_key(
+            self.open_contents(ino).await?,
+            &*self.inode_key(ino).await?,
+            self.contents_holes(ino).await?,
+        ));
+        if self.get_inode_from_cache_or_storage(ino).await?.compressed {
+            Ok(Box::new(DecompressRead::new(reader)?))
+        } else {
+            Ok(reader)
+        }
+    }
+
+    /// Writer of the plaintext of `ino`, compressing it if the file is compressed.
+    pub(crate) async fn create_contents_writer(&self, ino: u64) -> FsResult<ContentsWriter> {
+        let writer: ContentsWriter = Box::new(self.create_write_seek_with_key(
+            self.open_contents_rw(ino).await?,
+            &*self.inode_key(ino).await?,
+            self.contents_holes(ino).await?,
+        ));
+        if self.get_inode_from_cache_or_storage(ino).await?.compressed {
+            Ok(Box::new(CompressWrite::new(writer, self.zstd_level())))
+        } else {
+            Ok(writer)
+        }
+    }
+}
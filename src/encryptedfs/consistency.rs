@@ -0,0 +1,302 @@
+//! Random sequences of operations on files, checked against a plain model of their contents, see
+//! [`check_consistency`].
+//!
+//! With the `fuzz` feature [`ConsistencyOp`] implements `arbitrary::Arbitrary`, so a `cargo fuzz` target can run it:
+//!
+//! ```ignore
+//! fuzz_target!(|ops: Vec<ConsistencyOp>| check_consistency_blocking(&ops));
+//! ```
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use shush_rs::SecretString;
+use tokio::sync::RwLock;
+
+use crate::crypto::write::BLOCK_SIZE;
+use crate::crypto::Cipher;
+use crate::encryptedfs::{CreateFileAttr, EncryptedFs, FileType, ROOT_INODE};
+use crate::test_util::{FixedPasswordProvider, TEST_PASSWORD};
+
+/// Names the operations are applied to, by the `file` index.
+const FILES: usize = 3;
+/// The offsets and the sizes go up to it, so the writes cross blocks and segments.
+const MAX_OFFSET: u64 = 8 * BLOCK_SIZE as u64;
+const MAX_LEN: usize = 3 * BLOCK_SIZE;
+
+/// An operation of [`check_consistency`]. The indexes wrap around the files and the open handles, the operations on
+/// handles are skipped when none is open, and the offsets and lengths are kept small.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub enum ConsistencyOp {
+    /// Open the file for read and write, it's created if it's missing.
+    Open {
+        file: u8,
+    },
+    /// Write at least one byte from `offset`, starting with `byte`.
+    Write {
+        handle: u8,
+        offset: u32,
+        len: u16,
+        byte: u8,
+    },
+    Read {
+        handle: u8,
+        offset: u32,
+        len: u16,
+    },
+    SetLen {
+        file: u8,
+        size: u32,
+    },
+    Flush {
+        handle: u8,
+    },
+    Release {
+        handle: u8,
+    },
+    /// Rename the file over another one, the handles of the one replaced are released first.
+    Rename {
+        file: u8,
+        to: u8,
+    },
+    /// Write what the handles of the file keep and recreate them, like a change from another handle does.
+    ResetHandles {
+        file: u8,
+    },
+}
+
+/// What the files should have.
+#[derive(Default)]
+struct Model {
+    names: [Option<u64>; FILES],
+    contents: HashMap<u64, Vec<u8>>,
+    // (handle, inode)
+    handles: Vec<(u64, u64)>,
+}
+
+impl Model {
+    fn handle(&self, index: u8) -> Option<(usize, u64, u64)> {
+        if self.handles.is_empty() {
+            return None;
+        }
+        let index = usize::from(index) % self.handles.len();
+        let (fh, ino) = self.handles[index];
+        Some((index, fh, ino))
+    }
+}
+
+/// Apply `ops` to a new fs in memory and to a model of the files, after each of them the size and the contents of the
+/// files must be the same as in the model. At the end the handles are released and [`EncryptedFs::check`] must find
+/// nothing wrong.
+///
+/// # Panics
+///
+/// If they differ or an operation fails, with the operation that did it.
+pub async fn check_consistency(ops: &[ConsistencyOp]) {
+    let fs = EncryptedFs::new_in_memory(
+        Box::new(FixedPasswordProvider::new(
+            SecretString::from_str(TEST_PASSWORD).unwrap(),
+        )),
+        Cipher::ChaCha20Poly1305,
+    )
+    .await
+    .unwrap();
+    let mut model = Model::default();
+    for op in ops {
+        apply(&fs, &mut model, op).await;
+        assert_files(&fs, &model, op).await;
+    }
+    for (fh, _) in model.handles.drain(..) {
+        fs.release(fh).await.unwrap();
+    }
+    assert_files(&fs, &model, &ConsistencyOp::Release { handle: 0 }).await;
+    assert!(fs.check(false).await.unwrap().is_clean());
+}
+
+/// [`check_consistency`] on a new runtime, for the fuzz targets.
+///
+/// # Panics
+///
+/// Like [`check_consistency`].
+pub fn check_consistency_blocking(ops: &[ConsistencyOp]) {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(check_consistency(ops));
+}
+
+fn file_name(file: usize) -> SecretString {
+    SecretString::from_str(&format!("file-{file}")).unwrap()
+}
+
+#[allow(clippy::too_many_lines)]
+async fn apply(fs: &EncryptedFs, model: &mut Model, op: &ConsistencyOp) {
+    match *op {
+        ConsistencyOp::Open { file } => {
+            let file = usize::from(file) % FILES;
+            let (fh, ino) = if let Some(ino) = model.names[file] {
+                (fs.open(ino, true, true).await.unwrap(), ino)
+            } else {
+                let attr = CreateFileAttr {
+                    kind: FileType::RegularFile,
+                    perm: 0o644,
+                    uid: 0,
+                    gid: 0,
+                    rdev: 0,
+                    flags: 0,
+                    compress: None,
+                };
+                let (fh, attr) = fs
+                    .create(ROOT_INODE, &file_name(file), attr, true, true)
+                    .await
+                    .unwrap();
+                model.names[file] = Some(attr.ino);
+                model.contents.insert(attr.ino, vec![]);
+                (fh, attr.ino)
+            };
+            model.handles.push((fh, ino));
+        }
+        ConsistencyOp::Write {
+            handle,
+            offset,
+            len,
+            byte,
+        } => {
+            let Some((_, fh, ino)) = model.handle(handle) else {
+                return;
+            };
+            let offset = u64::from(offset) % MAX_OFFSET;
+            let len = usize::from(len) % MAX_LEN + 1;
+            #[allow(clippy::cast_possible_truncation)]
+            let data: Vec<u8> = (0..len).map(|i| byte.wrapping_add(i as u8)).collect();
+            let mut written = 0;
+            while written < len {
+                let n = fs
+                    .write(ino, offset + written as u64, &data[written..], fh)
+                    .await
+                    .unwrap();
+                assert!(n > 0, "nothing written");
+                written += n;
+            }
+            let contents = model.contents.get_mut(&ino).unwrap();
+            #[allow(clippy::cast_possible_truncation)]
+            let offset = offset as usize;
+            if contents.len() < offset + len {
+                contents.resize(offset + len, 0);
+            }
+            contents[offset..offset + len].copy_from_slice(&data);
+        }
+        ConsistencyOp::Read {
+            handle,
+            offset,
+            len,
+        } => {
+            let Some((_, fh, ino)) = model.handle(handle) else {
+                return;
+            };
+            let offset = u64::from(offset) % MAX_OFFSET;
+            let mut buf = vec![0; usize::from(len) % MAX_LEN + 1];
+            let mut read = 0;
+            while read < buf.len() {
+                let n = fs
+                    .read(ino, offset + read as u64, &mut buf[read..], fh)
+                    .await
+                    .unwrap();
+                if n == 0 {
+                    break;
+                }
+                read += n;
+            }
+            let contents = &model.contents[&ino];
+            #[allow(clippy::cast_possible_truncation)]
+            let start = (offset as usize).min(contents.len());
+            let end = (start + buf.len()).min(contents.len());
+            assert!(
+                buf[..read] == contents[start..end],
+                "read {read} bytes at {offset} differs from the model, after {op:?}"
+            );
+        }
+        ConsistencyOp::SetLen { file, size } => {
+            let Some(ino) = model.names[usize::from(file) % FILES] else {
+                return;
+            };
+            let size = u64::from(size) % (MAX_OFFSET + 1);
+            fs.set_len(ino, size).await.unwrap();
+            #[allow(clippy::cast_possible_truncation)]
+            model
+                .contents
+                .get_mut(&ino)
+                .unwrap()
+                .resize(size as usize, 0);
+        }
+        ConsistencyOp::Flush { handle } => {
+            if let Some((_, fh, _)) = model.handle(handle) {
+                fs.flush(fh).await.unwrap();
+            }
+        }
+        ConsistencyOp::Release { handle } => {
+            if let Some((index, fh, _)) = model.handle(handle) {
+                fs.release(fh).await.unwrap();
+                model.handles.remove(index);
+            }
+        }
+        ConsistencyOp::Rename { file, to } => {
+            let (file, to) = (usize::from(file) % FILES, usize::from(to) % FILES);
+            let Some(ino) = model.names[file] else {
+                return;
+            };
+            if file == to {
+                return;
+            }
+            if let Some(replaced) = model.names[to] {
+                for (fh, _) in model.handles.iter().filter(|(_, i)| *i == replaced) {
+                    fs.release(*fh).await.unwrap();
+                }
+                model.handles.retain(|(_, i)| *i != replaced);
+                model.contents.remove(&replaced);
+            }
+            fs.rename(ROOT_INODE, &file_name(file), ROOT_INODE, &file_name(to))
+                .await
+                .unwrap();
+            model.names[to] = Some(ino);
+            model.names[file] = None;
+        }
+        ConsistencyOp::ResetHandles { file } => {
+            let Some(ino) = model.names[usize::from(file) % FILES] else {
+                return;
+            };
+            let lock = fs
+                .read_write_locks
+                .get_or_insert_with(ino, || RwLock::new(false));
+            let _guard = lock.write().await;
+            fs.reset_handles(ino, None, true).await.unwrap();
+        }
+    }
+}
+
+/// The size and the contents of the files must be the ones of the model.
+async fn assert_files(fs: &EncryptedFs, model: &Model, op: &ConsistencyOp) {
+    for (file, ino) in model
+        .names
+        .iter()
+        .enumerate()
+        .filter_map(|(file, ino)| ino.map(|ino| (file, ino)))
+    {
+        let contents = &model.contents[&ino];
+        let size = fs.get_attr(ino).await.unwrap().size;
+        assert_eq!(
+            contents.len() as u64,
+            size,
+            "size of file-{file} differs from the model, after {op:?}"
+        );
+        let fh = fs.open(ino, true, false).await.unwrap();
+        let data = fs.read_all(ino, fh, u64::MAX).await.unwrap();
+        fs.release(fh).await.unwrap();
+        assert!(
+            data == *contents,
+            "contents of file-{file} differ from the model, after {op:?}"
+        );
+    }
+}
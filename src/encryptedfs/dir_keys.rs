@@ -0,0 +1,188 @@
+//! Directory keys, so a tree can be locked apart from the rest like with fscrypt, see
+//! [`EncryptedFs::set_directory_key`].
+//!
+//! Each key is random and kept in [`DIR_KEYS_DIR`] encrypted with its own password slots, like the master key, see
+//! [`KeySlots`]. A directory with a key has it in [`FileAttr::key_id`], and the files and directories created in it
+//! inherit it. The names of the entries of such a directory and the contents of such a file are encrypted with it
+//! instead of the master key. The inodes and the rest of the entries are still encrypted with the master key, so the
+//! tree can be listed while its key is locked, with placeholder names.
+//!
+//! [`FileAttr::key_id`]: crate::encryptedfs::FileAttr::key_id
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use argon2::password_hash::rand_core::RngCore;
+use shush_rs::{SecretBox, SecretString, SecretVec};
+
+use crate::crypto;
+use crate::encryptedfs::key_slots::{KeySlot, KeySlots};
+use crate::encryptedfs::{EncryptedFs, FsError, FsResult, SECURITY_DIR};
+use crate::storage::Storage;
+
+/// Directory in [`SECURITY_DIR`] with the slots of each directory key, named by its id.
+pub(crate) const DIR_KEYS_DIR: &str = "dir_keys";
+/// Start of the names listed in a directory whose key is locked.
+const LOCKED_NAME_PREFIX: &str = "_";
+
+impl EncryptedFs {
+    /// Create a directory key encrypted with `password`, returns its id for [`EncryptedFs::set_directory_key`].
+    ///
+    /// The key is unlocked until [`EncryptedFs::lock_directory_key`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn create_directory_key(&self, password: SecretString) -> FsResult<u32> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        // so two keys don't get the same id
+        let mut dir_keys = self.dir_keys.write().await;
+        let dir = dir_keys_dir();
+        if self.storage.kind(&dir).await?.is_none() {
+            self.storage.create_dir(&dir).await?;
+            self.storage.sync(Path::new(SECURITY_DIR)).await?;
+        }
+        let key_id = self
+            .storage
+            .list(&dir)
+            .await?
+            .iter()
+            .filter_map(|entry| entry.name.split('.').next()?.parse::<u32>().ok())
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let mut key = vec![0; self.cipher.key_len()];
+        crypto::create_rng().fill_bytes(&mut key);
+        let key = SecretBox::new(Box::new(key));
+        let cipher = self.cipher;
+        // deriving the key from the password is slow, keep it off the runtime workers
+        let (key, slot) = tokio::task::spawn_blocking(move || {
+            let slot = KeySlot::seal(&key, &password, cipher)?;
+            Ok::<_, FsError>((key, slot))
+        })
+        .await??;
+        KeySlots { slots: vec![slot] }
+            .write(&*self.storage, &dir_key_path(key_id))
+            .await?;
+        self.storage.sync(&dir).await?;
+        dir_keys.insert(key_id, Arc::new(key));
+        Ok(key_id)
+    }
+
+    /// Decrypt the directory key `key_id` with `password`, so the trees with it can be used.
+    ///
+    /// It returns [`FsError::InvalidPassword`] if `password` doesn't open it.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn unlock_directory_key(&self, key_id: u32, password: SecretString) -> FsResult<()> {
+        let path = dir_key_path(key_id);
+        if self.storage.kind(&path).await?.is_none() {
+            return Err(FsError::invalid_input("no such directory key"));
+        }
+        let (_, _, key) =
+            KeySlots::read_and_open(&*self.storage, &path, self.cipher, &password).await?;
+        self.dir_keys.write().await.insert(key_id, Arc::new(key));
+        Ok(())
+    }
+
+    /// Remove the directory key `key_id` from memory, the trees with it are locked until
+    /// [`EncryptedFs::unlock_directory_key`].
+    ///
+    /// Like with [`EncryptedFs::lock`], the handles of the files with it are flushed and suspended. The names and the
+    /// contents read while it was unlocked are dropped from the caches. After this, listing such a directory gives
+    /// placeholder names and the rest fails with [`FsError::Locked`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn lock_directory_key(&self, key_id: u32) -> FsResult<()> {
+        self.suspend_handles(Some(key_id)).await?;
+        self.dir_keys.write().await.remove(&key_id);
+        self.get_dir_entries_name_cache()
+            .await?
+            .lock()
+            .await
+            .clear();
+        self.block_cache.clear();
+        Ok(())
+    }
+
+    /// Encrypt the names of the entries of the directory `ino` and everything created in it after this with the
+    /// directory key `key_id`, see [`EncryptedFs::create_directory_key`].
+    ///
+    /// The key must be unlocked and the directory empty, its key can't be changed after that.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn set_directory_key(&self, ino: u64, key_id: u32) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if !self.exists(ino).await {
+            return Err(FsError::InodeNotFound);
+        }
+        if !self.is_dir(ino).await {
+            return Err(FsError::InvalidInodeType);
+        }
+        if self.storage.kind(&dir_key_path(key_id)).await?.is_none() {
+            return Err(FsError::invalid_input("no such directory key"));
+        }
+        self.key_by_id(Some(key_id)).await?;
+        let mut attr = self.get_inode_from_cache_or_storage(ino).await?;
+        match attr.key_id {
+            Some(current) if current == key_id => return Ok(()),
+            Some(_) => {
+                return Err(FsError::invalid_input(
+                    "the directory already has a directory key",
+                ))
+            }
+            None => {}
+        }
+        // the names already in it are encrypted with the master key
+        if self.len(ino).await? > 0 {
+            return Err(FsError::invalid_input("the directory is not empty"));
+        }
+        attr.key_id = Some(key_id);
+        self.write_inode_to_storage(&attr).await
+    }
+
+    /// Key of the contents of the file `ino`, or of the names of the entries of the directory `ino`.
+    ///
+    /// It returns [`FsError::Locked`] if it has a directory key which is locked.
+    pub(crate) async fn inode_key(&self, ino: u64) -> FsResult<Arc<SecretVec<u8>>> {
+        let key_id = self.get_inode_from_cache_or_storage(ino).await?.key_id;
+        self.key_by_id(key_id).await
+    }
+
+    async fn key_by_id(&self, key_id: Option<u32>) -> FsResult<Arc<SecretVec<u8>>> {
+        match key_id {
+            None => self.key.get().await,
+            Some(key_id) => self
+                .dir_keys
+                .read()
+                .await
+                .get(&key_id)
+                .cloned()
+                .ok_or(FsError::Locked),
+        }
+    }
+}
+
+/// `true` if any directory key was created.
+pub(crate) async fn has_directory_keys(storage: &dyn Storage) -> FsResult<bool> {
+    let dir = dir_keys_dir();
+    Ok(storage.kind(&dir).await?.is_some() && !storage.list(&dir).await?.is_empty())
+}
+
+/// Name listed for the entry `encrypted_name` of a directory whose key is locked.
+///
+/// It's derived from the encrypted name, so it's the same each time it's listed.
+pub(crate) fn locked_entry_name(encrypted_name: &str) -> SecretString {
+    let hash = crypto::hash(encrypted_name.as_bytes());
+    SecretString::new(Box::new(format!(
+        "{LOCKED_NAME_PREFIX}{}",
+        hex::encode(&hash[..16])
+    )))
+}
+
+fn dir_keys_dir() -> PathBuf {
+    Path::new(SECURITY_DIR).join(DIR_KEYS_DIR)
+}
+
+/// Key of the slots of the directory key `key_id` in the storage.
+fn dir_key_path(key_id: u32) -> PathBuf {
+    dir_keys_dir().join(format!("{key_id}.enc"))
+}
@@ -0,0 +1,82 @@
+//! Notifications of the changes made through an [`EncryptedFs`], see [`EncryptedFs::subscribe`].
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use shush_rs::SecretString;
+use tokio::sync::broadcast;
+
+use crate::encryptedfs::EncryptedFs;
+
+/// How many events are kept for the receivers which didn't get them yet, the oldest ones are dropped after that.
+const EVENTS_CAPACITY: usize = 1024;
+
+/// A change made through [`EncryptedFs`], see [`EncryptedFs::subscribe`].
+#[derive(Debug, Clone)]
+pub enum FsEvent {
+    /// A file or directory was created in `parent`, or a hard link to `ino` was added to it.
+    Created {
+        parent: u64,
+        ino: u64,
+        name: SecretString,
+    },
+    /// The entry `name` of `parent` was removed.
+    Removed {
+        parent: u64,
+        ino: u64,
+        name: SecretString,
+    },
+    Renamed {
+        old_parent: u64,
+        old_name: SecretString,
+        new_parent: u64,
+        new_name: SecretString,
+        ino: u64,
+    },
+    /// A write handle of `ino` was released after writing `len` bytes from `offset`. All the writes of a handle are
+    /// sent at once, from the first byte written to the last one.
+    Written { ino: u64, offset: u64, len: u64 },
+    /// The attributes of `ino` were changed with [`EncryptedFs::set_attr`] or [`EncryptedFs::set_len`].
+    AttrChanged { ino: u64 },
+}
+
+pub(crate) struct EventSender {
+    tx: broadcast::Sender<FsEvent>,
+    dropped: AtomicU64,
+}
+
+impl EventSender {
+    pub(crate) fn new() -> Self {
+        Self {
+            tx: broadcast::channel(EVENTS_CAPACITY).0,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn send(&self, event: FsEvent) {
+        if self.tx.receiver_count() == 0 {
+            return;
+        }
+        if self.tx.len() >= EVENTS_CAPACITY {
+            // the oldest one is dropped for the receivers which didn't get it yet
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        // fails only if all receivers were dropped since
+        let _ = self.tx.send(event);
+    }
+}
+
+impl EncryptedFs {
+    /// Receive the changes made from now on, see [`FsEvent`].
+    ///
+    /// The events are kept until all receivers get them, up to a limit. When a receiver falls behind, the oldest
+    /// ones are dropped and it gets [`broadcast::error::RecvError::Lagged`], see also
+    /// [`EncryptedFs::dropped_events`].
+    pub fn subscribe(&self) -> broadcast::Receiver<FsEvent> {
+        self.events.tx.subscribe()
+    }
+
+    /// How many events were dropped before the slowest receiver got them.
+    pub fn dropped_events(&self) -> u64 {
+        self.events.dropped.load(Ordering::Relaxed)
+    }
+}
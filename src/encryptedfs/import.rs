@@ -0,0 +1,461 @@
+//! Bulk import of a plaintext directory tree, see [`EncryptedFs::import_dir`].
+
+use std::collections::HashSet;
+use std::fs::{File, Metadata};
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use bon::bon;
+use futures_util::StreamExt;
+use shush_rs::SecretString;
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+use crate::encryptedfs::{
+    CreateFileAttr, DurabilityPolicy, EncryptedFs, FileType, FsError, FsEvent, FsResult, OpControl,
+};
+use crate::stream_util;
+
+/// Files imported at once by default, see [`ImportOptions::concurrency`].
+const DEFAULT_IMPORT_CONCURRENCY: NonZeroUsize = match NonZeroUsize::new(16) {
+    Some(n) => n,
+    None => unreachable!(),
+};
+
+/// Decides if a path of the source tree, relative to it, is imported. A directory which is not is skipped with all
+/// that is in it.
+pub type ImportFilter = Arc<dyn Fn(&Path) -> bool + Send + Sync>;
+
+/// Called with the totals so far after each file is imported.
+pub type ImportProgressFn = Arc<dyn Fn(ImportProgress) + Send + Sync>;
+
+/// What to do when an entry of the source tree is already in the destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImportOverwrite {
+    /// Fail with [`FsError::AlreadyExists`].
+    #[default]
+    Fail,
+    /// Keep what is there, directories are still merged.
+    Skip,
+    /// Replace the existing files, directories are merged.
+    Replace,
+}
+
+/// Options of [`EncryptedFs::import_dir`], use [`ImportOptions::builder()`] to override the defaults.
+#[derive(Clone)]
+pub struct ImportOptions {
+    /// Import what the symlinks point to, otherwise they are skipped.
+    pub follow_symlinks: bool,
+    pub overwrite: ImportOverwrite,
+    pub filter: Option<ImportFilter>,
+    pub progress: Option<ImportProgressFn>,
+    /// Max number of files imported at once.
+    pub concurrency: NonZeroUsize,
+    /// Cancels the import, its progress is not called, see [`ImportOptions::progress`].
+    pub control: OpControl,
+}
+
+#[bon]
+impl ImportOptions {
+    #[builder]
+    pub fn new(
+        #[builder(default)] follow_symlinks: bool,
+        #[builder(default)] overwrite: ImportOverwrite,
+        filter: Option<ImportFilter>,
+        progress: Option<ImportProgressFn>,
+        #[builder(default = DEFAULT_IMPORT_CONCURRENCY)] concurrency: NonZeroUsize,
+        #[builder(default)] control: OpControl,
+    ) -> Self {
+        Self {
+            follow_symlinks,
+            overwrite,
+            filter,
+            progress,
+            concurrency,
+            control,
+        }
+    }
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+/// Totals of an import, see [`EncryptedFs::import_dir`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImportProgress {
+    pub files: u64,
+    pub bytes: u64,
+    pub dirs: u64,
+    /// Entries not imported, because of [`ImportOptions::filter`], [`ImportOverwrite::Skip`], or because they are
+    /// symlinks or special files.
+    pub skipped: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    files: AtomicU64,
+    bytes: AtomicU64,
+    dirs: AtomicU64,
+    skipped: AtomicU64,
+}
+
+impl Counters {
+    fn snapshot(&self) -> ImportProgress {
+        ImportProgress {
+            files: self.files.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            dirs: self.dirs.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Keeps [`DurabilityPolicy::Always`] relaxed while an import runs, see [`EncryptedFs::durability`].
+pub(crate) struct RelaxSyncs<'a>(&'a EncryptedFs);
+
+impl<'a> RelaxSyncs<'a> {
+    pub(crate) fn new(fs: &'a EncryptedFs) -> Self {
+        fs.imports.fetch_add(1, Ordering::AcqRel);
+        Self(fs)
+    }
+}
+
+impl Drop for RelaxSyncs<'_> {
+    fn drop(&mut self) {
+        self.0.imports.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// A file or directory of the source tree to import.
+struct Source {
+    path: PathBuf,
+    /// Relative to the root of the tree, given to [`ImportOptions::filter`].
+    rel_path: PathBuf,
+    name: SecretString,
+    metadata: Metadata,
+}
+
+impl EncryptedFs {
+    /// Copy the files and directories in `src`, a directory on the local filesystem, into the directory
+    /// `dest_parent`, keeping their permissions and modification times.
+    ///
+    /// It's much faster than creating and writing them one by one: the files are written at most
+    /// [`ImportOptions::concurrency`] at once, straight into their contents without handles, and with
+    /// [`DurabilityPolicy::Always`] the syncs are left for when it ends, as with [`DurabilityPolicy::OnRelease`]. That
+    /// applies to the other changes made while it runs too, and a crash before it ends may lose any of them.
+    ///
+    /// On error it stops, what was imported until then is kept. The same if [`ImportOptions::control`] is cancelled,
+    /// the files being imported are completed and it fails with [`FsError::Cancelled`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn import_dir(
+        &self,
+        src: &Path,
+        dest_parent: u64,
+        options: ImportOptions,
+    ) -> FsResult<ImportProgress> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if !self.exists(dest_parent).await {
+            return Err(FsError::InodeNotFound);
+        }
+        if !self.is_dir(dest_parent).await {
+            return Err(FsError::InvalidInodeType);
+        }
+        let counters = Counters::default();
+        let mut files = vec![];
+        let relax = RelaxSyncs::new(self);
+        let res = self
+            .import_tree(src, dest_parent, &options, &counters, &mut files)
+            .await;
+        drop(relax);
+        // write what was imported even on error
+        self.sync_imported(files).await?;
+        res?;
+        Ok(counters.snapshot())
+    }
+
+    /// Sync what was left for later by [`RelaxSyncs`] and the contents of the imported `files`.
+    pub(crate) async fn sync_imported(&self, files: Vec<u64>) -> FsResult<()> {
+        if self.durability != DurabilityPolicy::Never {
+            for ino in files {
+                self.open_contents(ino).await?.sync_all()?;
+            }
+        }
+        self.flush_dir_times().await?;
+        self.sync_pending().await
+    }
+
+    async fn import_tree(
+        &self,
+        src: &Path,
+        dest_parent: u64,
+        options: &ImportOptions,
+        counters: &Counters,
+        files: &mut Vec<u64>,
+    ) -> FsResult<()> {
+        let fs = self.self_arc()?;
+        // the directories seen, so symlinks can't make us loop
+        let mut visited = HashSet::from([tokio::fs::canonicalize(src).await?]);
+        let mut dirs = vec![(src.to_path_buf(), PathBuf::new(), dest_parent, None)];
+        while let Some((dir, rel_dir, ino, metadata)) = dirs.pop() {
+            options.control.check()?;
+            let mut subdirs = vec![];
+            let mut dir_files = vec![];
+            let mut entries = tokio::fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                let rel_path = rel_dir.join(entry.file_name());
+                let Some(name) = entry.file_name().to_str().map(ToOwned::to_owned) else {
+                    return Err(FsError::invalid_input(format!(
+                        "name is not valid UTF-8: {}",
+                        path.display()
+                    )));
+                };
+                let mut metadata = tokio::fs::symlink_metadata(&path).await?;
+                if metadata.is_symlink() && options.follow_symlinks {
+                    match tokio::fs::metadata(&path).await {
+                        Ok(target) => metadata = target,
+                        // dangling, skipped like when not followed
+                        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                        Err(err) => return Err(err.into()),
+                    }
+                }
+                let dir_seen =
+                    metadata.is_dir() && !visited.insert(tokio::fs::canonicalize(&path).await?);
+                let filtered = options
+                    .filter
+                    .as_ref()
+                    .is_some_and(|filter| !filter(&rel_path));
+                if filtered || dir_seen || !(metadata.is_file() || metadata.is_dir()) {
+                    debug!(path = %path.display(), "skipping");
+                    counters.skipped.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                let source = Source {
+                    path,
+                    rel_path,
+                    name: SecretString::from_str(&name).unwrap(),
+                    metadata,
+                };
+                if source.metadata.is_dir() {
+                    subdirs.push(source);
+                } else {
+                    dir_files.push(source);
+                }
+            }
+
+            for source in subdirs {
+                if let Some(sub_ino) = self.import_entry_dir(ino, &source, options).await? {
+                    counters.dirs.fetch_add(1, Ordering::Relaxed);
+                    dirs.push((source.path, source.rel_path, sub_ino, Some(source.metadata)));
+                } else {
+                    counters.skipped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+
+            let mut imports = futures_util::stream::iter(dir_files)
+                .map(|source| {
+                    let fs = fs.clone();
+                    let options = options.clone();
+                    self.runtime
+                        .spawn(async move { fs.import_file(ino, &source, &options).await })
+                })
+                .buffer_unordered(options.concurrency.get());
+            while let Some(res) = imports.next().await {
+                match res?? {
+                    Some((file_ino, len)) => {
+                        files.push(file_ino);
+                        counters.files.fetch_add(1, Ordering::Relaxed);
+                        counters.bytes.fetch_add(len, Ordering::Relaxed);
+                        if let Some(progress) = &options.progress {
+                            progress(counters.snapshot());
+                        }
+                    }
+                    None => {
+                        counters.skipped.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+
+            // after its entries were added, which change its times
+            if let Some(metadata) = metadata {
+                let (atime, mtime) = source_times(&metadata);
+                self.restore_times(ino, None, atime, mtime).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Create the directory for `source` in `parent`, or take the existing one, returning its inode or `None` if it's
+    /// skipped.
+    async fn import_entry_dir(
+        &self,
+        parent: u64,
+        source: &Source,
+        options: &ImportOptions,
+    ) -> FsResult<Option<u64>> {
+        if let Some(existing) = self.find_by_name(parent, &source.name).await? {
+            match (options.overwrite, existing.kind) {
+                (ImportOverwrite::Fail, _) => return Err(FsError::AlreadyExists),
+                (_, FileType::Directory) => return Ok(Some(existing.ino)),
+                (ImportOverwrite::Skip, _) => return Ok(None),
+                (ImportOverwrite::Replace, _) => self.remove_file(parent, &source.name).await?,
+            }
+        }
+        let (_, attr) = self
+            .create(
+                parent,
+                &source.name,
+                import_attr(FileType::Directory, &source.metadata),
+                false,
+                false,
+            )
+            .await?;
+        Ok(Some(attr.ino))
+    }
+
+    /// Import the file `source` into `parent`, returning its inode and size or `None` if it's skipped.
+    async fn import_file(
+        &self,
+        parent: u64,
+        source: &Source,
+        options: &ImportOptions,
+    ) -> FsResult<Option<(u64, u64)>> {
+        options.control.check()?;
+        if let Some(existing) = self.find_by_name(parent, &source.name).await? {
+            match (options.overwrite, existing.kind) {
+                (ImportOverwrite::Skip, _) => return Ok(None),
+                (ImportOverwrite::Replace, FileType::RegularFile) => {
+                    self.remove_file(parent, &source.name).await?;
+                }
+                _ => return Err(FsError::AlreadyExists),
+            }
+        }
+        let (_, attr) = self
+            .create(
+                parent,
+                &source.name,
+                import_attr(FileType::RegularFile, &source.metadata),
+                false,
+                false,
+            )
+            .await?;
+        let res = self.import_contents(attr.ino, source).await;
+        if res.is_err() {
+            // don't leave it half written
+            if let Err(err) = self.remove_file(parent, &source.name).await {
+                warn!(err = %err, path = %source.path.display(), "removing partly imported file");
+            }
+        }
+        Ok(Some((attr.ino, res?)))
+    }
+
+    /// Write the contents of `source` to the new file `ino`, returning its size.
+    async fn import_contents(&self, ino: u64, source: &Source) -> FsResult<u64> {
+        let len = source.metadata.len();
+        self.quota_resize(ino, 0, len).await?;
+        let mut file = File::open(&source.path)?;
+        let mut writer = self.create_contents_writer(ino).await?;
+        // in large chunks, so their blocks are encrypted in parallel
+        let copied = stream_util::copy(&mut file, &mut writer, u64::MAX, true)?;
+        writer.finish()?;
+        if copied != len {
+            // changed meanwhile
+            self.quota_resize(ino, len, copied).await?;
+        }
+        let (atime, mtime) = source_times(&source.metadata);
+        self.restore_times(ino, Some(copied), atime, mtime).await?;
+        if copied > 0 {
+            self.events.send(FsEvent::Written {
+                ino,
+                offset: 0,
+                len: copied,
+            });
+        }
+        Ok(copied)
+    }
+
+    /// Set the times of `ino` to the ones it had before it was imported, and its size if `size`.
+    ///
+    /// Not with [`EncryptedFs::set_attr`], it keeps the latest times and these are older.
+    pub(crate) async fn restore_times(
+        &self,
+        ino: u64,
+        size: Option<u64>,
+        atime: SystemTime,
+        mtime: SystemTime,
+    ) -> FsResult<()> {
+        let serialize_update_lock = self
+            .serialize_update_inode_locks
+            .get_or_insert_with(ino, || Mutex::new(false));
+        let _serialize_update_guard = serialize_update_lock.lock().await;
+        // the pending time updates of a directory are from the entries we added
+        self.pending_dir_times.lock().await.remove(&ino);
+        let mut attr = self.get_inode_from_storage(ino).await?;
+        if let Some(size) = size {
+            attr.size = size;
+        }
+        attr.atime = atime;
+        attr.mtime = mtime;
+        attr.ctime = SystemTime::now();
+        self.write_inode_to_storage(&attr).await
+    }
+}
+
+/// Access and modification times of the imported `metadata`, now if the platform doesn't have them.
+fn source_times(metadata: &Metadata) -> (SystemTime, SystemTime) {
+    let now = SystemTime::now();
+    (
+        metadata.accessed().unwrap_or(now),
+        metadata.modified().unwrap_or(now),
+    )
+}
+
+/// Attributes of a new node for the imported `metadata`.
+#[allow(clippy::cast_possible_truncation)]
+fn import_attr(kind: FileType, metadata: &Metadata) -> CreateFileAttr {
+    #[cfg(unix)]
+    let (perm, uid, gid) = {
+        use std::os::unix::fs::MetadataExt;
+        (
+            (metadata.mode() & 0o7777) as u16,
+            metadata.uid(),
+            metadata.gid(),
+        )
+    };
+    #[cfg(not(unix))]
+    let (perm, uid, gid) = {
+        let perm = match kind {
+            FileType::Directory => 0o755,
+            FileType::RegularFile
+            | FileType::NamedPipe
+            | FileType::CharDevice
+            | FileType::BlockDevice
+            | FileType::Socket => 0o644,
+        };
+        let perm = if metadata.permissions().readonly() {
+            perm & 0o555
+        } else {
+            perm
+        };
+        (perm, 0, 0)
+    };
+    CreateFileAttr {
+        kind,
+        perm,
+        uid,
+        gid,
+        rdev: 0,
+        flags: 0,
+        compress: None,
+    }
+}
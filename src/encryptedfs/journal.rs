@@ -0,0 +1,217 @@
+//! Write-ahead journal of the operations changing several directory entries at once, like [`EncryptedFs::rename`].
+//!
+//! Before changing anything an operation records what it's going to do in [`JOURNAL_DIR`] and removes the record when
+//! done. If we crash in between, the operations left are completed when the data dir is opened again. Each step can
+//! be done again without changing the result, so it doesn't matter where it stopped.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use shush_rs::{SecretBox, SecretString};
+use tracing::{info, warn};
+
+use crate::encryptedfs::{
+    ls_entry_name, DirectoryEntry, EncryptedFs, FileType, FsResult, SetFileAttr, LS_DIR,
+    SECURITY_DIR,
+};
+use crate::segmented_file::SegmentedFile;
+use crate::storage::{EntryKind, Storage};
+
+/// Directory in [`SECURITY_DIR`] with one file for each operation in progress, named by its sequence number.
+pub(crate) const JOURNAL_DIR: &str = "journal";
+
+pub(crate) fn journal_dir() -> PathBuf {
+    Path::new(SECURITY_DIR).join(JOURNAL_DIR)
+}
+
+fn journal_path(id: u64) -> PathBuf {
+    // zero padded, so they sort in the order they were made
+    journal_dir().join(format!("{id:020}"))
+}
+
+/// Associated data binding a journal record to its sequence number.
+fn journal_aad(id: u64) -> Vec<u8> {
+    [b"journal".as_slice(), &id.to_le_bytes()].concat()
+}
+
+/// `true` if there are operations to complete, they are completed by opening the data dir with [`EncryptedFs`].
+pub(crate) async fn has_pending(storage: &dyn Storage) -> FsResult<bool> {
+    Ok(storage.kind(&journal_dir()).await? == Some(EntryKind::Dir)
+        && !storage.list(&journal_dir()).await?.is_empty())
+}
+
+/// An operation recorded in the journal, with all it needs to be completed.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum JournalOp {
+    /// See [`EncryptedFs::rename`].
+    Rename {
+        parent: u64,
+        name: String,
+        new_parent: u64,
+        new_name: String,
+        ino: u64,
+        kind: FileType,
+        /// The entry at the destination it replaces.
+        replaced: Option<ReplacedEntry>,
+    },
+}
+
+/// The destination of a [`JournalOp::Rename`] which existed before.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ReplacedEntry {
+    pub(crate) ino: u64,
+    pub(crate) kind: FileType,
+    /// Name of its `ls` entry, which is not replaced by the new one.
+    pub(crate) encrypted_name: String,
+    /// Links before the rename, so we know if we already dropped the one of the destination.
+    pub(crate) nlink: u32,
+}
+
+impl EncryptedFs {
+    /// Record `op` in the journal, do it and remove the record.
+    pub(crate) async fn run_journaled(&self, op: JournalOp) -> FsResult<()> {
+        let id = self.journal_seq.fetch_add(1, Ordering::SeqCst);
+        self.write_bound(&journal_path(id), &op, &journal_aad(id))
+            .await?;
+        self.apply_journal_op(op).await?;
+        self.storage.remove(&journal_path(id)).await?;
+        self.sync_with_policy(&journal_dir()).await
+    }
+
+    /// Complete the operations left in the journal by a crash, in the order they were made.
+    pub(crate) async fn replay_journal(&self) -> FsResult<()> {
+        let mut ids = vec![];
+        for entry in self.storage.list(&journal_dir()).await? {
+            match entry.name.parse::<u64>() {
+                Ok(id) => ids.push(id),
+                Err(_) => warn!(name = entry.name, "skipping unknown file in journal"),
+            }
+        }
+        ids.sort_unstable();
+        for id in ids {
+            let op: JournalOp = self.read_bound(&journal_path(id), &journal_aad(id)).await?;
+            info!(id, "completing operation from journal");
+            let JournalOp::Rename {
+                parent, new_parent, ..
+            } = op;
+            self.apply_journal_op(op).await?;
+            // the crash could have been between changing the entries and their count
+            self.recount_children(parent).await?;
+            if new_parent != parent {
+                self.recount_children(new_parent).await?;
+            }
+            self.storage.remove(&journal_path(id)).await?;
+        }
+        self.storage.sync(&journal_dir()).await?;
+        Ok(())
+    }
+
+    async fn apply_journal_op(&self, op: JournalOp) -> FsResult<()> {
+        match op {
+            JournalOp::Rename {
+                parent,
+                name,
+                new_parent,
+                new_name,
+                ino,
+                kind,
+                replaced,
+            } => {
+                let name = SecretString::from_str(&name).unwrap();
+                let new_name = SecretString::from_str(&new_name).unwrap();
+                // add to new parent contents first, replacing the `hash` entry of the destination, so the inode is
+                // always reachable from one of the names
+                self.insert_directory_entry(
+                    new_parent,
+                    &DirectoryEntry {
+                        ino,
+                        name: new_name,
+                        kind,
+                    },
+                )
+                .await?;
+                if let Some(replaced) = &replaced {
+                    self.remove_ls_entry_if_exists(new_parent, &replaced.encrypted_name)
+                        .await?;
+                }
+                // remove from parent contents, unless it was already removed and the name was used again
+                if self.find_by_name(parent, &name).await?.map(|attr| attr.ino) == Some(ino) {
+                    self.remove_directory_entry(parent, &name, self.secure_delete)
+                        .await?;
+                }
+
+                if kind == FileType::Directory {
+                    // add the parent link to the new directory
+                    self.insert_directory_entry(
+                        ino,
+                        &DirectoryEntry {
+                            ino: new_parent,
+                            name: SecretBox::new(Box::new("$..".to_owned())),
+                            kind: FileType::Directory,
+                        },
+                    )
+                    .await?;
+                }
+
+                // nothing points to the replaced one anymore
+                if let Some(replaced) = replaced {
+                    self.drop_replaced(&replaced).await?;
+                }
+
+                self.touch_dir(parent).await?;
+                if new_parent != parent {
+                    self.touch_dir(new_parent).await?;
+                }
+                let now = SystemTime::now();
+                let set_attr = SetFileAttr::default().with_ctime(now).with_atime(now);
+                self.update_attr(ino, set_attr).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove what is left of the entry replaced by a rename.
+    async fn drop_replaced(&self, replaced: &ReplacedEntry) -> FsResult<()> {
+        let contents_path = self.contents_path(replaced.ino);
+        if !self.exists(replaced.ino).await {
+            // we crashed after removing the inode, but maybe before the contents
+            if self.kind_at(&contents_path).await.is_some() {
+                if replaced.kind == FileType::Directory {
+                    self.storage.remove_dir(&contents_path).await?;
+                } else {
+                    SegmentedFile::remove(&self.storage, &contents_path).await?;
+                }
+            }
+            return Ok(());
+        }
+        if replaced.kind == FileType::Directory {
+            return self.remove_dir_inode(replaced.ino).await;
+        }
+        let attr = self.get_attr(replaced.ino).await?;
+        if attr.nlink == replaced.nlink {
+            self.unlink_file_inode(replaced.ino, self.secure_delete)
+                .await
+        } else if attr.nlink == 0 {
+            // we crashed after dropping the last link, but before removing it
+            self.remove_file_inode(replaced.ino, self.secure_delete)
+                .await
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn remove_ls_entry_if_exists(&self, parent: u64, encrypted_name: &str) -> FsResult<()> {
+        let path = self
+            .contents_path(parent)
+            .join(LS_DIR)
+            .join(ls_entry_name(encrypted_name));
+        if self.kind_at(&path).await.is_some() {
+            self.remove_ls_entry(parent, encrypted_name, self.secure_delete)
+                .await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,281 @@
+//! Password slots of the master key, like LUKS key slots.
+//!
+//! [`KEY_ENC_FILENAME`](super::KEY_ENC_FILENAME) holds the master key encrypted separately with each password, each
+//! slot with its own salt and Argon2 params. Any of the passwords opens the data dir.
+//! Data dirs created before the slots have the key encrypted with a single password and the salt in
+//! [`KEY_SALT_FILENAME`], they are read as one slot and upgraded when the slots are next written.
+//!
+//! The slots are also kept in a backup next to the key file, with a checksum of the content, so a key file corrupted
+//! like by a partial upload can be restored from it, see [`KeySlots::read_and_open`]. The slots keep their salt, so
+//! the backup covers it too, a single password key file gets its backup when upgraded.
+
+use std::ffi::OsString;
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+
+use argon2::password_hash::rand_core::RngCore;
+use argon2::Params;
+use serde::{Deserialize, Serialize};
+use shush_rs::{ExposeSecret, SecretBox, SecretString, SecretVec};
+use tracing::warn;
+
+use crate::crypto;
+use crate::crypto::write::CryptoWrite;
+use crate::crypto::Cipher;
+use crate::encryptedfs::{FsError, FsResult, KEY_SALT_FILENAME};
+use crate::storage::Storage;
+
+/// Start of [`KEY_ENC_FILENAME`](super::KEY_ENC_FILENAME) with slots, the single password one starts with the random
+/// nonce.
+const KEY_SLOTS_MAGIC: &[u8] = b"rencfs-key-slots";
+/// Extension added to the key file for its backup.
+const BACKUP_EXTENSION: &str = "bak";
+/// Extension added to the key file for the checksum of its content, the backup has the same content.
+const CHECKSUM_EXTENSION: &str = "sum";
+
+/// The master key encrypted with a key derived from a password.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct KeySlot {
+    salt: Vec<u8>,
+    /// Argon2 memory cost.
+    m_cost: u32,
+    /// Argon2 iterations.
+    t_cost: u32,
+    /// Argon2 parallelism.
+    p_cost: u32,
+    cipher: Cipher,
+    encrypted_key: Vec<u8>,
+}
+
+impl KeySlot {
+    /// Encrypt `key` with `password`, with a new salt and the default Argon2 params.
+    pub(crate) fn seal(
+        key: &SecretVec<u8>,
+        password: &SecretString,
+        cipher: Cipher,
+    ) -> FsResult<Self> {
+        let mut salt = vec![0; 16];
+        crypto::create_rng().fill_bytes(&mut salt);
+        let params = Params::default();
+        let derived_key = crypto::derive_key_with_params(password, cipher, &salt, params.clone())?;
+        let mut writer = crypto::create_write(Cursor::new(vec![]), cipher, &derived_key);
+        bincode::serialize_into(&mut writer, &*key.expose_secret())?;
+        Ok(Self {
+            salt,
+            m_cost: params.m_cost(),
+            t_cost: params.t_cost(),
+            p_cost: params.p_cost(),
+            cipher,
+            encrypted_key: writer.finish()?.into_inner(),
+        })
+    }
+
+    /// The cipher the key is encrypted with.
+    pub(crate) const fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    /// Decrypt the key, `None` if it's not encrypted with `password`.
+    fn open(&self, password: &SecretString) -> FsResult<Option<SecretVec<u8>>> {
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, None)
+            .map_err(|_| FsError::invalid_input("invalid key slot params"))?;
+        let derived_key =
+            crypto::derive_key_with_params(password, self.cipher, &self.salt, params)?;
+        let reader =
+            crypto::create_read(Cursor::new(&self.encrypted_key), self.cipher, &derived_key);
+        Ok(bincode::deserialize_from::<_, Vec<u8>>(reader)
+            .ok()
+            .map(|key| SecretBox::new(Box::new(key))))
+    }
+}
+
+/// All the slots from [`KEY_ENC_FILENAME`](super::KEY_ENC_FILENAME).
+#[derive(Serialize, Deserialize)]
+pub(crate) struct KeySlots {
+    pub(crate) slots: Vec<KeySlot>,
+}
+
+impl KeySlots {
+    /// Read the slots from the `content` of the key file at `key_path`.
+    ///
+    /// A single password key file is read as one slot with the `cipher` and the salt next to it.
+    async fn parse(
+        storage: &dyn Storage,
+        key_path: &Path,
+        content: Vec<u8>,
+        cipher: Cipher,
+    ) -> FsResult<Self> {
+        if let Some(slots) = content.strip_prefix(KEY_SLOTS_MAGIC) {
+            return Ok(bincode::deserialize(slots)?);
+        }
+        let salt: Vec<u8> = bincode::deserialize(&storage.read(&legacy_salt_path(key_path)).await?)
+            .map_err(|_| FsError::InvalidPassword)?;
+        let params = Params::default();
+        Ok(Self {
+            slots: vec![KeySlot {
+                salt,
+                m_cost: params.m_cost(),
+                t_cost: params.t_cost(),
+                p_cost: params.p_cost(),
+                cipher,
+                encrypted_key: content,
+            }],
+        })
+    }
+
+    /// Read the slots from `key_path` and open them with `password`, see [`KeySlots::open`].
+    ///
+    /// If the key file can't be read or opened, the backup is tried, the copy matching the checksum first. When
+    /// opened from the backup, or the copies differ, the key file, the backup and the checksum are all written again
+    /// with the content that opened.
+    pub(crate) async fn read_and_open(
+        storage: &dyn Storage,
+        key_path: &Path,
+        cipher: Cipher,
+        password: &SecretString,
+    ) -> FsResult<(Self, usize, SecretVec<u8>)> {
+        let backup = read_if_exists(storage, &backup_path(key_path)).await?;
+        let checksum = read_if_exists(storage, &checksum_path(key_path)).await?;
+        let primary = match storage.read(key_path).await {
+            Ok(content) => Some(content),
+            Err(err) if backup.is_some() => {
+                warn!("cannot read the key file: {err}");
+                None
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let matches_checksum =
+            |content: &[u8]| checksum.as_deref() == Some(crypto::hash(content).as_slice());
+        let mut copies: Vec<_> = primary.iter().cloned().collect();
+        if let Some(backup) = &backup {
+            if !copies.contains(backup) {
+                copies.push(backup.clone());
+            }
+        }
+        // stable, so the key file is first of the ones matching
+        copies.sort_by_key(|content| !matches_checksum(content));
+
+        let mut first_err = None;
+        for content in copies {
+            let slots = match Self::parse(storage, key_path, content.clone(), cipher).await {
+                Ok(slots) => slots,
+                Err(err) => {
+                    first_err.get_or_insert(err);
+                    continue;
+                }
+            };
+            // deriving the key from the password is slow, keep it off the runtime workers
+            let password = password.clone();
+            let opened = tokio::task::spawn_blocking(move || {
+                slots.open(&password).map(|opened| (slots, opened))
+            })
+            .await?;
+            let (slots, (index, key)) = match opened {
+                Ok(opened) => opened,
+                Err(err) => {
+                    first_err.get_or_insert(err);
+                    continue;
+                }
+            };
+            let is_primary = primary.as_ref() == Some(&content);
+            if content.starts_with(KEY_SLOTS_MAGIC)
+                && (!is_primary || backup.as_ref() != Some(&content) || !matches_checksum(&content))
+            {
+                if !is_primary {
+                    warn!("the key file is corrupted, restoring it from the backup");
+                }
+                write_copies(storage, key_path, &content).await?;
+            }
+            return Ok((slots, index, key));
+        }
+        Err(first_err.unwrap_or(FsError::InvalidPassword))
+    }
+
+    /// Write the slots to `key_path` atomically, then the backup and the checksum, see [`write_backup`].
+    ///
+    /// If interrupted, either the new slots are in the key file or the previous ones are still in the backup.
+    /// Call [`remove_legacy_salt`] after it's in place, the salt of a single password key file is not needed anymore.
+    pub(crate) async fn write(&self, storage: &dyn Storage, key_path: &Path) -> FsResult<()> {
+        write_copies(storage, key_path, &self.to_bytes()?).await
+    }
+
+    /// The content of a key file with the slots.
+    pub(crate) fn to_bytes(&self) -> FsResult<Vec<u8>> {
+        let mut content = KEY_SLOTS_MAGIC.to_vec();
+        bincode::serialize_into(&mut content, self)?;
+        Ok(content)
+    }
+
+    /// Decrypt the key with the first slot `password` opens, returns the index of the slot and the key.
+    ///
+    /// If no slot can be opened it returns [`FsError::InvalidPassword`].
+    pub(crate) fn open(&self, password: &SecretString) -> FsResult<(usize, SecretVec<u8>)> {
+        for (index, slot) in self.slots.iter().enumerate() {
+            if let Some(key) = slot.open(password)? {
+                return Ok((index, key));
+            }
+        }
+        Err(FsError::InvalidPassword)
+    }
+}
+
+/// Write `content` to the key file and then to the backup, each is durable before the next one is written.
+async fn write_copies(storage: &dyn Storage, key_path: &Path, content: &[u8]) -> FsResult<()> {
+    storage.write(key_path, content).await?;
+    write_backup(storage, key_path).await
+}
+
+/// Copy the key file at `key_path` to the backup next to it and write the checksum of its content.
+pub(crate) async fn write_backup(storage: &dyn Storage, key_path: &Path) -> FsResult<()> {
+    let content = storage.read(key_path).await?;
+    storage.write(&backup_path(key_path), &content).await?;
+    storage
+        .write(&checksum_path(key_path), &crypto::hash(&content))
+        .await?;
+    Ok(())
+}
+
+async fn read_if_exists(storage: &dyn Storage, path: &Path) -> io::Result<Option<Vec<u8>>> {
+    if storage.kind(path).await?.is_none() {
+        return Ok(None);
+    }
+    storage.read(path).await.map(Some)
+}
+
+/// The backup of the key file at `key_path`.
+pub(crate) fn backup_path(key_path: &Path) -> PathBuf {
+    with_extension_added(key_path, BACKUP_EXTENSION)
+}
+
+/// The checksum of the key file at `key_path` and of its backup.
+pub(crate) fn checksum_path(key_path: &Path) -> PathBuf {
+    with_extension_added(key_path, CHECKSUM_EXTENSION)
+}
+
+fn with_extension_added(path: &Path, extension: &str) -> PathBuf {
+    let mut name = OsString::from(path.file_name().expect("key file has a name"));
+    name.push(".");
+    name.push(extension);
+    path.with_file_name(name)
+}
+
+/// `true` if the key file at `key_path` has a single password and no slots.
+pub(crate) async fn is_legacy_key_file(storage: &dyn Storage, key_path: &Path) -> io::Result<bool> {
+    Ok(!storage.read(key_path).await?.starts_with(KEY_SLOTS_MAGIC))
+}
+
+/// Remove the salt of the single password key file next to `key_path`, if any.
+pub(crate) async fn remove_legacy_salt(storage: &dyn Storage, key_path: &Path) -> io::Result<()> {
+    let salt_path = legacy_salt_path(key_path);
+    if storage.kind(&salt_path).await?.is_some() {
+        storage.remove(&salt_path).await?;
+        storage
+            .sync(key_path.parent().expect("oops, we don't have a parent"))
+            .await?;
+    }
+    Ok(())
+}
+
+fn legacy_salt_path(key_path: &Path) -> PathBuf {
+    key_path.with_file_name(KEY_SALT_FILENAME)
+}
@@ -0,0 +1,552 @@
+//! POSIX advisory record locks, see [`EncryptedFs::lock_range`].
+//!
+//! The locks are kept in memory, so they exclude the apps using this [`EncryptedFs`], like the ones using its mount,
+//! not other instances opening the same data dir, and they are gone when it's dropped. Like the `fcntl(2)` locks they
+//! belong to an owner, the process for a mount. The ranges of an owner never conflict with each other, they are split
+//! and merged as they change, and all the locks of an owner on a file are released when it closes a handle of it.
+//!
+//! The blocking requests are granted in the order they came, one is not granted before an earlier one it conflicts
+//! with, so readers coming all the time don't starve a writer. A request goes before the earlier ones waiting for its
+//! owner, like one upgrading a read lock to a write lock, and waiting is refused with [`FsError::Deadlock`] when the
+//! owners it waits for wait, directly or not, for its owner.
+//!
+//! The `flock(2)` locks of [`EncryptedFs::flock`] are kept apart, they lock the whole file for a handle and don't
+//! conflict with the record locks, like in the kernel.
+
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::Mutex;
+
+use tokio::sync::watch;
+use tracing::debug;
+
+use crate::encryptedfs::{EncryptedFs, FsError, FsResult};
+
+/// The type of a lock, any number of owners can have a read lock on a byte but only one can have a write lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockType {
+    Read,
+    Write,
+}
+
+impl LockType {
+    const fn conflicts(self, other: Self) -> bool {
+        matches!((self, other), (Self::Write, _) | (_, Self::Write))
+    }
+}
+
+/// The operation of [`EncryptedFs::flock`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flock {
+    /// Lock the file shared with other handles, like `LOCK_SH`.
+    Shared,
+    /// Lock the file for this handle only, like `LOCK_EX`.
+    Exclusive,
+    /// Like `LOCK_UN`.
+    Unlock,
+}
+
+/// A lock held on a range of a file, see [`EncryptedFs::test_lock`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockInfo {
+    pub owner: u64,
+    pub kind: LockType,
+    /// The end is exclusive, [`u64::MAX`] is up to the end of the file however long it gets.
+    pub range: Range<u64>,
+}
+
+const fn overlaps(a: &Range<u64>, b: &Range<u64>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// A request of [`LockTable`].
+#[derive(Debug, Clone)]
+struct LockRequest {
+    ino: u64,
+    owner: u64,
+    kind: LockType,
+    range: Range<u64>,
+}
+
+impl LockRequest {
+    fn conflicts(&self, ino: u64, owner: u64, kind: LockType, range: &Range<u64>) -> bool {
+        self.ino == ino
+            && self.owner != owner
+            && self.kind.conflicts(kind)
+            && overlaps(&self.range, range)
+    }
+}
+
+struct Waiter {
+    id: u64,
+    request: LockRequest,
+    /// It doesn't wait for the earlier requests, see [`LockTable::decide`].
+    ahead: bool,
+}
+
+enum Decision {
+    Grant,
+    /// Wait, going ahead of the earlier requests if `true`.
+    Wait(bool),
+    Deadlock,
+}
+
+#[derive(Default)]
+struct LockTable {
+    // by inode, sorted by start
+    held: HashMap<u64, Vec<LockInfo>>,
+    // in the order they came
+    waiting: Vec<Waiter>,
+    next_waiter: u64,
+    // the inode of each handle and the owners which locked through it
+    handles: HashMap<u64, (u64, HashSet<u64>)>,
+}
+
+impl LockTable {
+    /// The owners of the locks conflicting with `request`.
+    fn holders(&self, request: &LockRequest) -> HashSet<u64> {
+        self.held
+            .get(&request.ino)
+            .into_iter()
+            .flatten()
+            .filter(|lock| {
+                lock.owner != request.owner
+                    && lock.kind.conflicts(request.kind)
+                    && overlaps(&lock.range, &request.range)
+            })
+            .map(|lock| lock.owner)
+            .collect()
+    }
+
+    /// The owners `request` waits for, the [`LockTable::holders`] and the owners of the conflicting requests waiting
+    /// before it. `id` is its place in the queue, a new one is after all of them.
+    fn blockers(&self, request: &LockRequest, id: Option<u64>) -> HashSet<u64> {
+        let mut owners = self.holders(request);
+        owners.extend(
+            self.waiting
+                .iter()
+                .take_while(|waiter| Some(waiter.id) != id)
+                .filter(|waiter| {
+                    waiter.request.conflicts(
+                        request.ino,
+                        request.owner,
+                        request.kind,
+                        &request.range,
+                    )
+                })
+                .map(|waiter| waiter.request.owner),
+        );
+        owners
+    }
+
+    /// `true` if one of `owners` waits for `owner`, following the owners they wait for.
+    fn waits_for(&self, owners: HashSet<u64>, owner: u64) -> bool {
+        let mut seen = HashSet::new();
+        let mut next: Vec<u64> = owners.into_iter().collect();
+        while let Some(current) = next.pop() {
+            if current == owner {
+                return true;
+            }
+            if !seen.insert(current) {
+                continue;
+            }
+            for waiter in self
+                .waiting
+                .iter()
+                .filter(|waiter| waiter.request.owner == current)
+            {
+                if waiter.ahead {
+                    next.extend(self.holders(&waiter.request));
+                } else {
+                    next.extend(self.blockers(&waiter.request, Some(waiter.id)));
+                }
+            }
+        }
+        false
+    }
+
+    /// Whether `request` can be granted. Waiting behind the earlier requests would deadlock if one of them waits for
+    /// its owner, then it waits only for the holders.
+    fn decide(&self, request: &LockRequest, id: Option<u64>) -> Decision {
+        let blockers = self.blockers(request, id);
+        if blockers.is_empty() {
+            return Decision::Grant;
+        }
+        if !self.waits_for(blockers, request.owner) {
+            return Decision::Wait(false);
+        }
+        let holders = self.holders(request);
+        if holders.is_empty() {
+            Decision::Grant
+        } else if self.waits_for(holders, request.owner) {
+            Decision::Deadlock
+        } else {
+            Decision::Wait(true)
+        }
+    }
+
+    /// Set the range of `request` to its type for its owner, or unlock it if `kind` is `None`. The locks of the owner
+    /// it overlaps are cut, the ones of the same type it overlaps or touches are merged with it.
+    fn set(&mut self, ino: u64, owner: u64, kind: Option<LockType>, range: &Range<u64>) {
+        let mut kept = vec![];
+        for lock in self.held.remove(&ino).unwrap_or_default() {
+            if lock.owner != owner || !overlaps(&lock.range, range) {
+                kept.push(lock);
+                continue;
+            }
+            // the parts outside the range are kept
+            if lock.range.start < range.start {
+                kept.push(LockInfo {
+                    range: lock.range.start..range.start,
+                    ..lock.clone()
+                });
+            }
+            if range.end < lock.range.end {
+                kept.push(LockInfo {
+                    range: range.end..lock.range.end,
+                    ..lock
+                });
+            }
+        }
+        if let Some(kind) = kind {
+            // the locks of an owner don't overlap, so at most one on each side touches it
+            let mut range = range.clone();
+            kept.retain(|lock| {
+                let touches = lock.range.start <= range.end && range.start <= lock.range.end;
+                if lock.owner == owner && lock.kind == kind && touches {
+                    range = range.start.min(lock.range.start)..range.end.max(lock.range.end);
+                    false
+                } else {
+                    true
+                }
+            });
+            kept.push(LockInfo { owner, kind, range });
+        }
+        if !kept.is_empty() {
+            kept.sort_by_key(|lock| (lock.range.start, lock.owner));
+            self.held.insert(ino, kept);
+        }
+    }
+
+    /// Unlock all the locks of `owner` on `ino`.
+    fn release_owner(&mut self, ino: u64, owner: u64) {
+        self.set(ino, owner, None, &(0..u64::MAX));
+    }
+}
+
+/// The locks of an [`EncryptedFs`], see [`EncryptedFs::lock_range`] and [`EncryptedFs::flock`].
+pub(crate) struct FileLocks {
+    table: Mutex<LockTable>,
+    // the `flock` locks by inode, with the handles holding them
+    flocks: Mutex<HashMap<u64, HashMap<u64, LockType>>>,
+    // bumped when the locks are unlocked or a request stops waiting, the waiting ones look again then
+    changed: watch::Sender<u64>,
+}
+
+impl FileLocks {
+    pub(crate) fn new() -> Self {
+        Self {
+            table: Mutex::new(LockTable::default()),
+            flocks: Mutex::new(HashMap::new()),
+            changed: watch::Sender::new(0),
+        }
+    }
+
+    fn notify(&self) {
+        self.changed.send_modify(|n| *n = n.wrapping_add(1));
+    }
+
+    async fn lock(&self, fh: u64, request: LockRequest, blocking: bool) -> FsResult<()> {
+        let mut changed = self.changed.subscribe();
+        let mut waiting = WaitGuard {
+            locks: self,
+            id: None,
+        };
+        loop {
+            {
+                let mut table = self.table.lock().unwrap();
+                let decision = if blocking {
+                    table.decide(&request, waiting.id)
+                } else if table.holders(&request).is_empty() {
+                    Decision::Grant
+                } else {
+                    return Err(FsError::LockConflict);
+                };
+                match decision {
+                    Decision::Grant => {
+                        table.set(
+                            request.ino,
+                            request.owner,
+                            Some(request.kind),
+                            &request.range,
+                        );
+                        table
+                            .handles
+                            .entry(fh)
+                            .or_insert_with(|| (request.ino, HashSet::new()))
+                            .1
+                            .insert(request.owner);
+                        // a read lock replacing a write lock may let others in
+                        drop(table);
+                        drop(waiting);
+                        self.notify();
+                        return Ok(());
+                    }
+                    Decision::Deadlock => {
+                        debug!(
+                            ino = request.ino,
+                            owner = request.owner,
+                            "lock would deadlock"
+                        );
+                        return Err(FsError::Deadlock);
+                    }
+                    Decision::Wait(ahead) => {
+                        if let Some(id) = waiting.id {
+                            let waiter = table.waiting.iter_mut().find(|w| w.id == id).unwrap();
+                            waiter.ahead = ahead;
+                        } else {
+                            let id = table.next_waiter;
+                            table.next_waiter += 1;
+                            table.waiting.push(Waiter {
+                                id,
+                                request: request.clone(),
+                                ahead,
+                            });
+                            waiting.id = Some(id);
+                        }
+                        changed.borrow_and_update();
+                    }
+                }
+            }
+            if changed.changed().await.is_err() {
+                return Err(FsError::Internal("lock table dropped".to_owned()));
+            }
+        }
+    }
+
+    fn unlock(&self, ino: u64, owner: u64, range: &Range<u64>) {
+        self.table.lock().unwrap().set(ino, owner, None, range);
+        self.notify();
+    }
+
+    fn test(&self, request: &LockRequest) -> Option<LockInfo> {
+        self.table
+            .lock()
+            .unwrap()
+            .held
+            .get(&request.ino)?
+            .iter()
+            .find(|lock| {
+                lock.owner != request.owner
+                    && lock.kind.conflicts(request.kind)
+                    && overlaps(&lock.range, &request.range)
+            })
+            .cloned()
+    }
+
+    fn release_owner(&self, ino: u64, owner: u64) {
+        self.table.lock().unwrap().release_owner(ino, owner);
+        self.notify();
+    }
+
+    /// Unlock the locks of the owners which locked through `fh` and its `flock` lock, when it's released.
+    pub(crate) fn release_handle(&self, fh: u64) {
+        {
+            let mut table = self.table.lock().unwrap();
+            if let Some((ino, owners)) = table.handles.remove(&fh) {
+                for owner in owners {
+                    table.release_owner(ino, owner);
+                }
+            }
+        }
+        self.unflock(fh);
+    }
+
+    async fn flock(&self, ino: u64, fh: u64, kind: LockType, blocking: bool) -> FsResult<()> {
+        let mut changed = self.changed.subscribe();
+        {
+            let mut flocks = self.flocks.lock().unwrap();
+            let handles = flocks.entry(ino).or_default();
+            match handles.get(&fh) {
+                Some(held) if *held == kind => return Ok(()),
+                // like Linux, a conversion drops the lock first, so it's lost if the new one can't be taken
+                Some(_) => {
+                    handles.remove(&fh);
+                    drop(flocks);
+                    self.notify();
+                }
+                None => {}
+            }
+        }
+        loop {
+            {
+                let mut flocks = self.flocks.lock().unwrap();
+                let handles = flocks.entry(ino).or_default();
+                let conflict = handles
+                    .iter()
+                    .any(|(other, held)| *other != fh && held.conflicts(kind));
+                if !conflict {
+                    handles.insert(fh, kind);
+                    return Ok(());
+                }
+                if !blocking {
+                    return Err(FsError::LockConflict);
+                }
+                changed.borrow_and_update();
+            }
+            if changed.changed().await.is_err() {
+                return Err(FsError::Internal("lock table dropped".to_owned()));
+            }
+        }
+    }
+
+    fn unflock(&self, fh: u64) {
+        let mut flocks = self.flocks.lock().unwrap();
+        let mut unlocked = false;
+        flocks.retain(|_, handles| {
+            unlocked |= handles.remove(&fh).is_some();
+            !handles.is_empty()
+        });
+        drop(flocks);
+        if unlocked {
+            self.notify();
+        }
+    }
+
+    #[cfg(test)]
+    fn held(&self, ino: u64) -> Vec<LockInfo> {
+        self.table
+            .lock()
+            .unwrap()
+            .held
+            .get(&ino)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+/// Removes the request from the queue when it stops waiting, also when the future is dropped.
+struct WaitGuard<'a> {
+    locks: &'a FileLocks,
+    id: Option<u64>,
+}
+
+impl Drop for WaitGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id.take() {
+            self.locks
+                .table
+                .lock()
+                .unwrap()
+                .waiting
+                .retain(|waiter| waiter.id != id);
+            // the ones behind it may go now
+            self.locks.notify();
+        }
+    }
+}
+
+fn check_range(range: &Range<u64>) -> FsResult<()> {
+    if range.is_empty() {
+        return Err(FsError::invalid_input("empty lock range"));
+    }
+    Ok(())
+}
+
+impl EncryptedFs {
+    /// Lock `range` of `ino` for `owner` through the handle `fh`, which must be opened for read for a
+    /// [`LockType::Read`] and for write for a [`LockType::Write`].
+    ///
+    /// The locks `owner` already has on the range are replaced, the parts of them outside it are kept. If another owner
+    /// has a conflicting lock it fails with [`FsError::LockConflict`], or with `blocking` it waits until it can take
+    /// it. It fails with [`FsError::Deadlock`] instead of waiting for an owner which waits for `owner`. The locks are
+    /// released with [`EncryptedFs::unlock_range`], [`EncryptedFs::release_locks`] and when `fh` is released.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn lock_range(
+        &self,
+        ino: u64,
+        fh: u64,
+        owner: u64,
+        range: Range<u64>,
+        kind: LockType,
+        blocking: bool,
+    ) -> FsResult<()> {
+        check_range(&range)?;
+        let valid_fh = match kind {
+            LockType::Read => self.is_read_handle(fh).await,
+            LockType::Write => self.is_write_handle(fh).await,
+        };
+        if !valid_fh {
+            return Err(FsError::InvalidFileHandle);
+        }
+        let request = LockRequest {
+            ino,
+            owner,
+            kind,
+            range,
+        };
+        self.locks.lock(fh, request, blocking).await
+    }
+
+    /// Unlock `range` of `ino` for `owner`, the parts of its locks outside it are kept.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn unlock_range(&self, ino: u64, owner: u64, range: &Range<u64>) -> FsResult<()> {
+        check_range(range)?;
+        self.locks.unlock(ino, owner, range);
+        Ok(())
+    }
+
+    /// The first lock of another owner which conflicts with locking `range` of `ino` for `owner`, if any.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn test_lock(
+        &self,
+        ino: u64,
+        owner: u64,
+        range: Range<u64>,
+        kind: LockType,
+    ) -> FsResult<Option<LockInfo>> {
+        check_range(&range)?;
+        Ok(self.locks.test(&LockRequest {
+            ino,
+            owner,
+            kind,
+            range,
+        }))
+    }
+
+    /// Unlock all the locks of `owner` on `ino`, like when a process closes a file.
+    pub fn release_locks(&self, ino: u64, owner: u64) {
+        self.locks.release_owner(ino, owner);
+    }
+
+    /// Lock the whole file opened with `fh`, like `flock(2)`. The lock belongs to the handle, other handles of the
+    /// same file conflict with it even with the same owner.
+    ///
+    /// If another handle has a conflicting lock it waits until it can take it, or with `non_blocking` it fails with
+    /// [`FsError::LockConflict`]. Changing the type of the lock it has first releases it, like Linux, so another handle
+    /// can take it meanwhile and it's lost if the new one can't be taken. The lock is released with [`Flock::Unlock`]
+    /// and when `fh` is released.
+    ///
+    /// The FUSE mount leaves `flock(2)` to the kernel, `fuse3` doesn't pass these requests to us.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn flock(&self, fh: u64, op: Flock, non_blocking: bool) -> FsResult<()> {
+        let ino = self
+            .handle_ino(fh)
+            .await
+            .ok_or(FsError::InvalidFileHandle)?;
+        let kind = match op {
+            Flock::Shared => LockType::Read,
+            Flock::Exclusive => LockType::Write,
+            Flock::Unlock => {
+                self.locks.unflock(fh);
+                return Ok(());
+            }
+        };
+        self.locks.flock(ino, fh, kind, !non_blocking).await
+    }
+
+    #[cfg(test)]
+    pub(crate) fn held_locks(&self, ino: u64) -> Vec<LockInfo> {
+        self.locks.held(ino)
+    }
+}
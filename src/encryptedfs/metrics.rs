@@ -0,0 +1,242 @@
+//! Counters of the operations and the caches of [`EncryptedFs`], see [`EncryptedFs::metrics_snapshot`].
+//!
+//! They are atomics updated as the operations are made, without locks, so they are always kept. With the `metrics`
+//! feature they are also recorded with the [`metrics`](https://docs.rs/metrics) crate, to be exported by the
+//! recorder the app installs.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use crate::encryptedfs::{EncryptedFs, ThrottleStatus};
+
+/// Buckets of the latency histograms, the one at `i` counts the operations which took less than `2^i` microseconds
+/// and more than the previous one, the last one all that took longer.
+pub const LATENCY_BUCKETS: usize = 24;
+
+pub(crate) struct Metrics {
+    pub(crate) read: OpMetrics,
+    pub(crate) write: OpMetrics,
+    pub(crate) read_dir: OpMetrics,
+    pub(crate) lookup: OpMetrics,
+    pub(crate) create: OpMetrics,
+    pub(crate) release: OpMetrics,
+    pub(crate) attr_cache: CacheMetrics,
+    pub(crate) name_cache: CacheMetrics,
+    pub(crate) meta_cache: CacheMetrics,
+    pub(crate) block_cache: CacheMetrics,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Self {
+        Self {
+            read: OpMetrics::new("read"),
+            write: OpMetrics::new("write"),
+            read_dir: OpMetrics::new("read_dir"),
+            lookup: OpMetrics::new("lookup"),
+            create: OpMetrics::new("create"),
+            release: OpMetrics::new("release"),
+            attr_cache: CacheMetrics::new("attr"),
+            name_cache: CacheMetrics::new("name"),
+            meta_cache: CacheMetrics::new("meta"),
+            block_cache: CacheMetrics::new("block"),
+        }
+    }
+}
+
+pub(crate) struct OpMetrics {
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    name: &'static str,
+    count: AtomicU64,
+    bytes: AtomicU64,
+    latency_micros: AtomicU64,
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS],
+}
+
+impl OpMetrics {
+    fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            count: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            latency_micros: AtomicU64::new(0),
+            latency_buckets: Default::default(),
+        }
+    }
+
+    /// Count an operation, it's recorded with its latency when the returned timer is dropped.
+    pub(crate) fn start(&self) -> OpTimer<'_> {
+        OpTimer {
+            op: self,
+            start: Instant::now(),
+        }
+    }
+
+    fn snapshot(&self) -> OpStats {
+        OpStats {
+            count: self.count.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            latency_micros: self.latency_micros.load(Ordering::Relaxed),
+            latency_buckets: self
+                .latency_buckets
+                .iter()
+                .map(|bucket| bucket.load(Ordering::Relaxed))
+                .collect(),
+        }
+    }
+}
+
+/// Records an operation when dropped, see [`OpMetrics::start`].
+pub(crate) struct OpTimer<'a> {
+    op: &'a OpMetrics,
+    start: Instant,
+}
+
+impl OpTimer<'_> {
+    /// Add the bytes read or written by the operation.
+    pub(crate) fn add_bytes(&self, bytes: u64) {
+        self.op.bytes.fetch_add(bytes, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        metrics::counter!("rencfs_op_bytes_total", "op" => self.op.name).increment(bytes);
+    }
+}
+
+impl Drop for OpTimer<'_> {
+    #[allow(clippy::cast_possible_truncation)]
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        let micros = elapsed.as_micros() as u64;
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.op.count.fetch_add(1, Ordering::Relaxed);
+        self.op.latency_micros.fetch_add(micros, Ordering::Relaxed);
+        self.op.latency_buckets[bucket.min(LATENCY_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        {
+            metrics::counter!("rencfs_ops_total", "op" => self.op.name).increment(1);
+            metrics::histogram!("rencfs_op_duration_seconds", "op" => self.op.name)
+                .record(elapsed.as_secs_f64());
+        }
+    }
+}
+
+pub(crate) struct CacheMetrics {
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    name: &'static str,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CacheMetrics {
+    fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        metrics::counter!("rencfs_cache_hits_total", "cache" => self.name).increment(1);
+    }
+
+    pub(crate) fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        metrics::counter!("rencfs_cache_misses_total", "cache" => self.name).increment(1);
+    }
+
+    /// Count a lookup, a hit if `hit`.
+    pub(crate) fn record(&self, hit: bool) {
+        if hit {
+            self.hit();
+        } else {
+            self.miss();
+        }
+    }
+
+    fn snapshot(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The counters of an [`EncryptedFs`] at some point, see [`EncryptedFs::metrics_snapshot`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetricsSnapshot {
+    pub read: OpStats,
+    pub write: OpStats,
+    /// [`EncryptedFs::read_dir`], [`EncryptedFs::read_dir_tolerant`] and [`EncryptedFs::read_dir_plus`].
+    pub read_dir: OpStats,
+    /// [`EncryptedFs::find_by_name`].
+    pub lookup: OpStats,
+    pub create: OpStats,
+    pub release: OpStats,
+    pub attr_cache: CacheStats,
+    /// Decrypted names of the directory entries.
+    pub name_cache: CacheStats,
+    /// Inode and type of the directory entries.
+    pub meta_cache: CacheStats,
+    /// The key derived from the password, a miss derives it again.
+    pub key_cache: CacheStats,
+    /// Decrypted blocks of the contents, a miss decrypts the block, see [`FsOptions::block_cache_bytes`].
+    ///
+    /// [`FsOptions::block_cache_bytes`]: crate::encryptedfs::FsOptions::block_cache_bytes
+    pub block_cache: CacheStats,
+    pub read_handles: u64,
+    pub write_handles: u64,
+    /// See [`FsOptions::throttle`].
+    ///
+    /// [`FsOptions::throttle`]: crate::encryptedfs::FsOptions::throttle
+    pub throttle: ThrottleStatus,
+}
+
+/// Counters of an operation.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpStats {
+    /// Calls, including the failed ones.
+    pub count: u64,
+    /// Bytes read or written.
+    pub bytes: u64,
+    /// Total time of the calls.
+    pub latency_micros: u64,
+    /// Histogram of the latencies, see [`LATENCY_BUCKETS`].
+    pub latency_buckets: Vec<u64>,
+}
+
+/// Lookups in a cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl EncryptedFs {
+    /// The counters of the operations and the caches since we were created.
+    pub async fn metrics_snapshot(&self) -> MetricsSnapshot {
+        let metrics = &self.metrics;
+        MetricsSnapshot {
+            read: metrics.read.snapshot(),
+            write: metrics.write.snapshot(),
+            read_dir: metrics.read_dir.snapshot(),
+            lookup: metrics.lookup.snapshot(),
+            create: metrics.create.snapshot(),
+            release: metrics.release.snapshot(),
+            attr_cache: metrics.attr_cache.snapshot(),
+            name_cache: metrics.name_cache.snapshot(),
+            meta_cache: metrics.meta_cache.snapshot(),
+            key_cache: CacheStats {
+                hits: self.key.hits(),
+                misses: self.key.misses(),
+            },
+            block_cache: metrics.block_cache.snapshot(),
+            read_handles: self.read_handles.len().await as u64,
+            write_handles: self.write_handles.len().await as u64,
+            throttle: self.throttle.status(),
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Cancellation and progress of the long operations, see [`OpControl`].
+
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::encryptedfs::{FsError, FsResult};
+
+/// Called with the work done and the total, in the units of the operation, see [`OpControl::with_progress`].
+pub type OpProgressFn = Arc<dyn Fn(u64, u64) + Send + Sync>;
+
+/// Lets another task cancel a long operation and follow its progress, the clones share the cancellation.
+///
+/// The operations check it between their steps and stop with [`FsError::Cancelled`], the data dir is left consistent
+/// and each of them says what is kept. Once cancelled it stays so, use a new one for the next operation.
+#[derive(Clone, Default)]
+pub struct OpControl {
+    cancelled: Arc<AtomicBool>,
+    progress: Option<OpProgressFn>,
+}
+
+impl OpControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Call `progress` with the work done and the total after each step.
+    #[must_use]
+    pub fn with_progress(mut self, progress: impl Fn(u64, u64) + Send + Sync + 'static) -> Self {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    /// Stop the operation at its next step.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// [`FsError::Cancelled`] if it was cancelled.
+    pub(crate) fn check(&self) -> FsResult<()> {
+        if self.is_cancelled() {
+            return Err(FsError::Cancelled);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn progress(&self, done: u64, total: u64) {
+        if let Some(progress) = &self.progress {
+            progress(done, total);
+        }
+    }
+
+    /// [`FsError::Cancelled`] instead of `err` if it was cancelled, for the errors of [`ControlledRead`].
+    pub(crate) fn cancelled_or(&self, err: FsError) -> FsError {
+        if self.is_cancelled() {
+            FsError::Cancelled
+        } else {
+            err
+        }
+    }
+}
+
+/// Reads from `inner` reporting the bytes read out of `total`, and fails once the [`OpControl`] is cancelled, so a copy
+/// done with it stops there.
+pub(crate) struct ControlledRead<'a, R: Read> {
+    inner: R,
+    control: &'a OpControl,
+    done: u64,
+    total: u64,
+}
+
+impl<'a, R: Read> ControlledRead<'a, R> {
+    pub(crate) const fn new(inner: R, control: &'a OpControl, total: u64) -> Self {
+        Self {
+            inner,
+            control,
+            done: 0,
+            total,
+        }
+    }
+}
+
+impl<R: Read> Read for ControlledRead<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.control.is_cancelled() {
+            // not `Interrupted`, which the copies retry
+            return Err(io::Error::other("cancelled"));
+        }
+        let len = self.inner.read(buf)?;
+        self.done += len as u64;
+        self.control.progress(self.done, self.total);
+        Ok(len)
+    }
+}
@@ -0,0 +1,171 @@
+//! Path based helpers on top of the inode based API, handy for library users.
+//!
+//! Paths are resolved from the root, a leading `/` is optional. `.` is skipped and `..` is resolved with the `..`
+//! entry of each directory. Lookups go through [`EncryptedFs::find_by_name`] which uses the hashes of the names,
+//! so resolving a path doesn't decrypt the names of the siblings.
+
+use std::path::{Component, Path};
+use std::str::FromStr;
+
+use shush_rs::SecretString;
+
+use crate::encryptedfs::{
+    CreateFileAttr, EncryptedFs, FileAttr, FileType, FsError, FsResult, ROOT_INODE,
+};
+
+impl EncryptedFs {
+    /// Get the attributes of the node at `path`.
+    ///
+    /// If a component is missing it returns [`FsError::NotFound`], if an intermediate component is not a directory
+    /// it returns [`FsError::InvalidInodeType`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn resolve(&self, path: &Path) -> FsResult<FileAttr> {
+        let mut attr = self.get_attr(ROOT_INODE).await?;
+        for component in path.components() {
+            let Some(name) = component_name(component, attr.ino)? else {
+                continue;
+            };
+            if attr.kind != FileType::Directory {
+                return Err(FsError::InvalidInodeType);
+            }
+            attr = self
+                .find_by_name(attr.ino, &secret_name(name))
+                .await?
+                .ok_or_else(|| FsError::not_found("path not found"))?;
+        }
+        Ok(attr)
+    }
+
+    /// Create a file at `path`, the parent directory must exist.
+    ///
+    /// `create_attr.kind` must be [`FileType::RegularFile`]. Returns the handle and the attributes like [`EncryptedFs::create`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn create_file_by_path(
+        &self,
+        path: &Path,
+        create_attr: CreateFileAttr,
+        read: bool,
+        write: bool,
+    ) -> FsResult<(u64, FileAttr)> {
+        if create_attr.kind != FileType::RegularFile {
+            return Err(FsError::invalid_input("kind must be a regular file"));
+        }
+        let (parent, name) = split_parent(path)?;
+        let parent = self.resolve(parent).await?;
+        if parent.kind != FileType::Directory {
+            return Err(FsError::InvalidInodeType);
+        }
+        self.create(parent.ino, &name, create_attr, read, write)
+            .await
+    }
+
+    /// Create the directory at `path` and all the missing parents, like `mkdir -p`.
+    ///
+    /// `create_attr.kind` must be [`FileType::Directory`], it's used for all created directories.
+    /// Returns the attributes of the last directory.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn create_dir_all_by_path(
+        &self,
+        path: &Path,
+        create_attr: CreateFileAttr,
+    ) -> FsResult<FileAttr> {
+        if create_attr.kind != FileType::Directory {
+            return Err(FsError::invalid_input("kind must be a directory"));
+        }
+        let mut attr = self.get_attr(ROOT_INODE).await?;
+        for component in path.components() {
+            let Some(name) = component_name(component, attr.ino)? else {
+                continue;
+            };
+            let name = secret_name(name);
+            attr = match self.find_by_name(attr.ino, &name).await? {
+                Some(attr) => attr,
+                None => match self
+                    .create(attr.ino, &name, create_attr.clone(), false, false)
+                    .await
+                {
+                    Ok((_, attr)) => attr,
+                    // created in the meantime
+                    Err(FsError::AlreadyExists) => self
+                        .find_by_name(attr.ino, &name)
+                        .await?
+                        .ok_or_else(|| FsError::not_found("path not found"))?,
+                    Err(err) => return Err(err),
+                },
+            };
+            if attr.kind != FileType::Directory {
+                return Err(FsError::InvalidInodeType);
+            }
+        }
+        Ok(attr)
+    }
+
+    /// Open the file at `path`, see [`EncryptedFs::open`].
+    ///
+    /// Returns the handle and the attributes of the file.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn open_by_path(
+        &self,
+        path: &Path,
+        read: bool,
+        write: bool,
+    ) -> FsResult<(u64, FileAttr)> {
+        let attr = self.resolve(path).await?;
+        let fh = self.open(attr.ino, read, write).await?;
+        Ok((fh, attr))
+    }
+
+    /// Remove the file or the empty directory at `path`.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn remove_by_path(&self, path: &Path) -> FsResult<()> {
+        let (parent, name) = split_parent(path)?;
+        let parent = self.resolve(parent).await?;
+        if parent.kind != FileType::Directory {
+            return Err(FsError::InvalidInodeType);
+        }
+        let attr = self
+            .find_by_name(parent.ino, &name)
+            .await?
+            .ok_or_else(|| FsError::not_found("path not found"))?;
+        if attr.kind == FileType::Directory {
+            self.remove_dir(parent.ino, &name).await
+        } else {
+            self.remove_file(parent.ino, &name).await
+        }
+    }
+}
+
+/// Name to look up in the current directory `ino` for `component`, `None` if it doesn't change the directory.
+fn component_name(component: Component<'_>, ino: u64) -> FsResult<Option<&str>> {
+    match component {
+        Component::RootDir | Component::CurDir => Ok(None),
+        // root is its own parent
+        Component::ParentDir if ino == ROOT_INODE => Ok(None),
+        Component::ParentDir => Ok(Some("..")),
+        Component::Normal(name) => name
+            .to_str()
+            .map(Some)
+            .ok_or_else(|| FsError::invalid_input("path is not valid UTF-8")),
+        Component::Prefix(_) => Err(FsError::invalid_input("path prefix not allowed")),
+    }
+}
+
+fn secret_name(name: &str) -> SecretString {
+    SecretString::from_str(name).expect("cannot create secret string")
+}
+
+/// Split `path` in the parent path and the last component, which must be a regular name.
+pub(crate) fn split_parent(path: &Path) -> FsResult<(&Path, SecretString)> {
+    match path.components().next_back() {
+        Some(Component::Normal(name)) => {
+            let name = name
+                .to_str()
+                .ok_or_else(|| FsError::invalid_input("path is not valid UTF-8"))?;
+            let parent = path.parent().unwrap_or_else(|| Path::new("/"));
+            Ok((parent, secret_name(name)))
+        }
+        _ => Err(FsError::invalid_input(
+            "path must end with a name, not '/', '.' or '..'",
+        )),
+    }
+}
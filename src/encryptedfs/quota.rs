@@ -0,0 +1,526 @@
+//! Limits on the size of the files, see [`FsOptions::max_size_bytes`] and [`EncryptedFs::set_dir_quota`].
+//!
+//! Sizes are the plaintext ones from [`FileAttr::size`], not what the encrypted contents take on disk. The total is
+//! kept encrypted in [`USED_BYTES_FILENAME`] and updated as the files change, [`EncryptedFs::check`] counts it again.
+//! The limit of a directory is kept in its inode, in [`FileAttr::quota`], and what is used under it is counted when the
+//! data dir is opened.
+//!
+//! Growth is reserved before it's written, so concurrent writers can't go over a limit together.
+//!
+//! [`FsOptions::max_size_bytes`]: crate::encryptedfs::FsOptions::max_size_bytes
+//! [`FileAttr::size`]: crate::encryptedfs::FileAttr::size
+//! [`FileAttr::quota`]: crate::encryptedfs::FileAttr::quota
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use shush_rs::SecretString;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::crypto;
+use crate::encryptedfs::{
+    sharded_inodes, EncryptedFs, FileType, FsError, FsResult, INODES_DIR, LS_DIR, ROOT_INODE,
+    SECURITY_DIR,
+};
+
+/// Sum of the sizes of all files, kept in [`SECURITY_DIR`] while [`FsOptions::max_size_bytes`] is set. It's removed
+/// when opened without it, as the changes made then are not counted.
+///
+/// [`FsOptions::max_size_bytes`]: crate::encryptedfs::FsOptions::max_size_bytes
+pub(crate) const USED_BYTES_FILENAME: &str = "used_bytes";
+/// Associated data binding [`USED_BYTES_FILENAME`] to what it is.
+pub(crate) const USED_BYTES_AAD: &[u8] = b"used_bytes";
+/// Quota of a directory, kept in its contents dir before version `14`, now in its inode.
+pub(crate) const QUOTA_FILENAME: &str = "quota";
+/// The directories with a quota, kept in [`SECURITY_DIR`] so we don't look in all the inodes on open.
+pub(crate) const QUOTA_DIRS_FILENAME: &str = "quota_dirs";
+
+/// The quota of a directory, see [`EncryptedFs::dir_quota`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirQuota {
+    /// Max sum of the sizes of the files under the directory.
+    pub limit: u64,
+    /// Sum of the sizes of the files under the directory, in its subdirectories too. A file with more links under it
+    /// is counted once.
+    pub used: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct QuotaState {
+    max_size: Option<u64>,
+    used: u64,
+    /// Sizes of the files changed since opened, with what is reserved by the writes in progress.
+    sizes: HashMap<u64, u64>,
+    dirs: HashMap<u64, DirUsage>,
+}
+
+struct DirUsage {
+    limit: u64,
+    used: u64,
+    /// Files under the directory with their number of entries under it.
+    files: HashMap<u64, u32>,
+}
+
+/// Files under a directory, with their number of entries under it and their size.
+type TreeFiles = HashMap<u64, (u32, u64)>;
+
+/// The files under a directory moved to another one, counted in the quotas it's moved into, see
+/// [`EncryptedFs::quota_move_dir`].
+pub(crate) struct MovedDir {
+    files: TreeFiles,
+    /// The directories with a quota it's moved into.
+    added: Vec<u64>,
+    /// The directories with a quota it's moved out of.
+    removed: Vec<u64>,
+}
+
+impl QuotaState {
+    pub(crate) fn new(max_size: Option<u64>) -> Self {
+        Self {
+            max_size,
+            ..Self::default()
+        }
+    }
+
+    fn size_of(&self, ino: u64, current: u64) -> u64 {
+        self.sizes.get(&ino).copied().unwrap_or(current)
+    }
+
+    /// Change the size of `ino` to `new`, failing if it grows over a limit. `current` is its size if we don't have it.
+    fn resize(&mut self, ino: u64, current: u64, new: u64) -> FsResult<()> {
+        if self.max_size.is_none() && self.dirs.is_empty() {
+            // nothing to count it in
+            return Ok(());
+        }
+        let old = self.size_of(ino, current);
+        if new > old {
+            let grow = new - old;
+            if self
+                .max_size
+                .is_some_and(|max| self.used.saturating_add(grow) > max)
+            {
+                return Err(FsError::QuotaExceeded);
+            }
+            if self.dirs.values().any(|dir| {
+                dir.files.contains_key(&ino) && dir.used.saturating_add(grow) > dir.limit
+            }) {
+                return Err(FsError::QuotaExceeded);
+            }
+        }
+        self.apply(ino, old, new);
+        self.sizes.insert(ino, new);
+        Ok(())
+    }
+
+    fn apply(&mut self, ino: u64, old: u64, new: u64) {
+        self.used = self.used.saturating_sub(old).saturating_add(new);
+        for dir in self.dirs.values_mut() {
+            if dir.files.contains_key(&ino) {
+                dir.used = dir.used.saturating_sub(old).saturating_add(new);
+            }
+        }
+    }
+
+    /// Count the entries of `files` in the quotas of `dirs`, failing without counting them if a file not in a
+    /// directory yet takes it over its limit.
+    fn add_files(&mut self, dirs: &[u64], files: &TreeFiles) -> FsResult<()> {
+        let files: Vec<_> = files
+            .iter()
+            .map(|(ino, (entries, size))| (*ino, *entries, self.size_of(*ino, *size)))
+            .collect();
+        for dir in dirs.iter().filter_map(|ino| self.dirs.get(ino)) {
+            let mut new = files
+                .iter()
+                .filter(|(ino, _, _)| !dir.files.contains_key(ino))
+                .peekable();
+            if new.peek().is_none() {
+                continue;
+            }
+            let grow = new.fold(0_u64, |grow, (_, _, size)| grow.saturating_add(*size));
+            if dir.used.saturating_add(grow) > dir.limit {
+                return Err(FsError::QuotaExceeded);
+            }
+        }
+        for dir in dirs.iter().filter_map(|ino| self.dirs.get_mut(ino)) {
+            for (ino, entries, size) in &files {
+                let count = dir.files.entry(*ino).or_insert(0);
+                if *count == 0 {
+                    dir.used = dir.used.saturating_add(*size);
+                }
+                *count += entries;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop counting the entries of `files` in the quotas of `dirs`.
+    fn remove_files(&mut self, dirs: &[u64], files: &TreeFiles) {
+        let files: Vec<_> = files
+            .iter()
+            .map(|(ino, (entries, size))| (*ino, *entries, self.size_of(*ino, *size)))
+            .collect();
+        for dir in dirs.iter().filter_map(|ino| self.dirs.get_mut(ino)) {
+            for (ino, entries, size) in &files {
+                match dir.files.get(ino).copied() {
+                    Some(count) if count > *entries => {
+                        dir.files.insert(*ino, count - entries);
+                    }
+                    Some(_) => {
+                        dir.files.remove(ino);
+                        dir.used = dir.used.saturating_sub(*size);
+                    }
+                    None => {}
+                }
+            }
+        }
+    }
+}
+
+impl EncryptedFs {
+    /// The quota of the directory `ino`, `None` if it has none.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn dir_quota(&self, ino: u64) -> FsResult<Option<DirQuota>> {
+        if !self.is_dir(ino).await {
+            return Err(FsError::InvalidInodeType);
+        }
+        Ok(self.quota.lock().await.dirs.get(&ino).map(|dir| DirQuota {
+            limit: dir.limit,
+            used: dir.used,
+        }))
+    }
+
+    /// Limit the sum of the sizes of the files under the directory `ino`, in its subdirectories too, to `limit`
+    /// bytes, or remove its quota with `None`. The limit is kept in its inode, see [`FileAttr::quota`].
+    ///
+    /// Writes, [`EncryptedFs::set_len`] and [`EncryptedFs::fallocate`] growing a file over it, and links or renames
+    /// adding files under the directory over it, fail with [`FsError::QuotaExceeded`]. A limit below what is used
+    /// already only stops it from growing.
+    ///
+    /// [`FileAttr::quota`]: crate::encryptedfs::FileAttr::quota
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn set_dir_quota(&self, ino: u64, limit: Option<u64>) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if !self.is_dir(ino).await {
+            return Err(FsError::InvalidInodeType);
+        }
+        let usage = match limit {
+            Some(limit) => Some(self.dir_usage(ino, limit).await?),
+            None => None,
+        };
+        let mut quota = self.quota.lock().await;
+        // listed before the inode has it and after it doesn't anymore, so all the ones with a quota are listed
+        if let Some(usage) = usage {
+            quota.dirs.insert(ino, usage);
+            self.write_quota_dirs(&quota).await?;
+        }
+        self.modify_attr(ino, |attr| attr.quota = limit).await?;
+        if limit.is_none() && quota.dirs.remove(&ino).is_some() {
+            self.write_quota_dirs(&quota).await?;
+        }
+        Ok(())
+    }
+
+    /// Load the total size and the quotas of the directories, when opening.
+    pub(crate) async fn load_quota(&self) -> FsResult<()> {
+        let mut quota = self.quota.lock().await;
+        let used_path = used_bytes_path();
+        if quota.max_size.is_some() {
+            quota.used = if self.kind_at(&used_path).await.is_some() {
+                self.read_bound(&used_path, USED_BYTES_AAD).await?
+            } else {
+                let used = self.count_used_bytes().await?;
+                if !self.read_only {
+                    self.write_bound(&used_path, &used, USED_BYTES_AAD).await?;
+                }
+                used
+            };
+        } else if !self.read_only && self.kind_at(&used_path).await.is_some() {
+            self.storage.remove(&used_path).await?;
+        }
+
+        let dirs_path = quota_dirs_path();
+        if self.kind_at(&dirs_path).await.is_none() {
+            return Ok(());
+        }
+        let inos: Vec<u64> = self.read_bound(&dirs_path, QUOTA_DIRS_AAD).await?;
+        for ino in inos {
+            let limit = match self.get_inode_from_storage(ino).await {
+                Ok(attr) => attr.quota,
+                // removed with the directory
+                Err(FsError::InodeNotFound) => None,
+                Err(err) => return Err(err),
+            };
+            let Some(limit) = limit else {
+                continue;
+            };
+            let usage = self.dir_usage(ino, limit).await?;
+            quota.dirs.insert(ino, usage);
+        }
+        info!(
+            used = quota.used,
+            dirs = quota.dirs.len(),
+            "loaded quota usage"
+        );
+        Ok(())
+    }
+
+    /// Sum of the sizes of all files, from their inodes.
+    async fn count_used_bytes(&self) -> FsResult<u64> {
+        let mut used = 0_u64;
+        for ino in sharded_inodes(&*self.storage, Path::new(INODES_DIR)).await? {
+            match self.get_inode_from_storage(ino).await {
+                Ok(attr) if attr.kind == FileType::RegularFile => {
+                    used = used.saturating_add(attr.size);
+                }
+                Ok(_) => {}
+                Err(err) => warn!(ino, err = %err, "reading inode, its size is not counted"),
+            }
+        }
+        Ok(used)
+    }
+
+    /// Set the total size after it was counted again by [`EncryptedFs::check`].
+    pub(crate) async fn set_used_bytes(&self, used: u64) -> FsResult<()> {
+        let mut quota = self.quota.lock().await;
+        quota.used = used;
+        self.write_used_bytes(&quota).await
+    }
+
+    /// The max size and the total size of the files, if there is a max.
+    pub(crate) async fn size_quota(&self) -> Option<(u64, u64)> {
+        let quota = self.quota.lock().await;
+        quota.max_size.map(|max| (max, quota.used))
+    }
+
+    /// Reserve the growth of `ino` from `current` to `new` bytes, nothing is changed if it's not larger than what we
+    /// have.
+    pub(crate) async fn quota_grow(&self, ino: u64, current: u64, new: u64) -> FsResult<()> {
+        let mut quota = self.quota.lock().await;
+        if new <= quota.size_of(ino, current) {
+            return Ok(());
+        }
+        quota.resize(ino, current, new)
+    }
+
+    /// Change the size of `ino` from `current` to `new` bytes, failing if it grows over a limit.
+    pub(crate) async fn quota_resize(&self, ino: u64, current: u64, new: u64) -> FsResult<()> {
+        let mut quota = self.quota.lock().await;
+        quota.resize(ino, current, new)?;
+        // the writers were flushed, so it's what the inode has from now on
+        quota.sizes.remove(&ino);
+        self.write_used_bytes(&quota).await
+    }
+
+    /// Take the size of `ino` from its inode after the last write handle was released, dropping what was reserved by
+    /// the writes which failed.
+    pub(crate) async fn quota_settle(&self, ino: u64, size: u64) -> FsResult<()> {
+        let mut quota = self.quota.lock().await;
+        let Some(old) = quota.sizes.remove(&ino) else {
+            return Ok(());
+        };
+        quota.apply(ino, old, size);
+        self.write_used_bytes(&quota).await
+    }
+
+    /// Drop the size of the file `ino` after its inode was removed, `size` is the last one we know.
+    pub(crate) async fn quota_forget(&self, ino: u64, size: u64) -> FsResult<()> {
+        let mut quota = self.quota.lock().await;
+        let size = quota.sizes.remove(&ino).unwrap_or(size);
+        quota.used = quota.used.saturating_sub(size);
+        self.write_used_bytes(&quota).await
+    }
+
+    /// Count the file `ino` of `size` bytes in the quotas of `parent` and the directories above it before adding an
+    /// entry for it in `parent`, failing if it goes over one of them.
+    pub(crate) async fn quota_add_entry(&self, parent: u64, ino: u64, size: u64) -> FsResult<()> {
+        let dirs = self.quota_dirs_over(parent).await?;
+        if dirs.is_empty() {
+            return Ok(());
+        }
+        self.quota
+            .lock()
+            .await
+            .add_files(&dirs, &TreeFiles::from([(ino, (1, size))]))
+    }
+
+    /// Stop counting an entry of the file `ino` of `size` bytes in `parent` in the quotas of `parent` and the
+    /// directories above it, after it was removed or failed to be added.
+    pub(crate) async fn quota_remove_entry(&self, parent: u64, ino: u64, size: u64) {
+        let dirs = self.quota_dirs_over(parent).await.unwrap_or_else(|err| {
+            // counted again when opened
+            warn!(parent, err = %err, "finding the quotas of the directory");
+            vec![parent]
+        });
+        if dirs.is_empty() {
+            return;
+        }
+        self.quota
+            .lock()
+            .await
+            .remove_files(&dirs, &TreeFiles::from([(ino, (1, size))]));
+    }
+
+    /// Count the files under the directory `ino` in the quotas it's moved into, from `parent` to `new_parent`,
+    /// before it's moved, failing if it goes over one of them. Pass the result to [`EncryptedFs::quota_moved_dir`]
+    /// once it's done.
+    pub(crate) async fn quota_move_dir(
+        &self,
+        ino: u64,
+        parent: u64,
+        new_parent: u64,
+    ) -> FsResult<MovedDir> {
+        let from = self.quota_dirs_over(parent).await?;
+        let to = self.quota_dirs_over(new_parent).await?;
+        let added: Vec<_> = to
+            .iter()
+            .filter(|dir| !from.contains(dir))
+            .copied()
+            .collect();
+        let removed: Vec<_> = from
+            .iter()
+            .filter(|dir| !to.contains(dir))
+            .copied()
+            .collect();
+        let files = if added.is_empty() && removed.is_empty() {
+            TreeFiles::new()
+        } else {
+            self.tree_files(ino).await?
+        };
+        self.quota.lock().await.add_files(&added, &files)?;
+        Ok(MovedDir {
+            files,
+            added,
+            removed,
+        })
+    }
+
+    /// Stop counting the files of a directory moved by [`EncryptedFs::quota_move_dir`] in the quotas it left, or in
+    /// the ones it was going to if it failed to move.
+    pub(crate) async fn quota_moved_dir(&self, moved: MovedDir, done: bool) {
+        let dirs = if done { &moved.removed } else { &moved.added };
+        self.quota.lock().await.remove_files(dirs, &moved.files);
+    }
+
+    /// Drop the quota of the directory `ino` after it was removed.
+    pub(crate) async fn quota_forget_dir(&self, ino: u64) -> FsResult<()> {
+        let mut quota = self.quota.lock().await;
+        if quota.dirs.remove(&ino).is_some() {
+            self.write_quota_dirs(&quota).await?;
+        }
+        Ok(())
+    }
+
+    /// The directories with a quota among the directory `ino` and the ones above it, found from their `..`.
+    async fn quota_dirs_over(&self, ino: u64) -> FsResult<Vec<u64>> {
+        if self.quota.lock().await.dirs.is_empty() {
+            return Ok(vec![]);
+        }
+        let parent_name = SecretString::from_str("..").unwrap();
+        let mut dirs = vec![ino];
+        let mut dir = ino;
+        while dir != ROOT_INODE {
+            let Some(parent) = self.find_by_name(dir, &parent_name).await? else {
+                break;
+            };
+            if dirs.contains(&parent.ino) {
+                break;
+            }
+            dir = parent.ino;
+            dirs.push(dir);
+        }
+        let quota = self.quota.lock().await;
+        dirs.retain(|dir| quota.dirs.contains_key(dir));
+        Ok(dirs)
+    }
+
+    /// Count the files under the directory `ino`.
+    async fn dir_usage(&self, ino: u64, limit: u64) -> FsResult<DirUsage> {
+        let files = self.tree_files(ino).await?;
+        Ok(DirUsage {
+            limit,
+            used: files
+                .values()
+                .fold(0_u64, |used, (_, size)| used.saturating_add(*size)),
+            files: files
+                .into_iter()
+                .map(|(ino, (entries, _))| (ino, entries))
+                .collect(),
+        })
+    }
+
+    /// The files under the directory `ino`, in its subdirectories too.
+    async fn tree_files(&self, ino: u64) -> FsResult<TreeFiles> {
+        let key = self.key.get().await?;
+        let mut files = TreeFiles::new();
+        let mut dirs = vec![ino];
+        while let Some(dir) = dirs.pop() {
+            let ls_dir = self.contents_path(dir).join(LS_DIR);
+            for entry in self.storage.list(&ls_dir).await? {
+                if entry.name == crypto::SELF_ENTRY_NAME || entry.name == crypto::PARENT_ENTRY_NAME
+                {
+                    continue;
+                }
+                let path = ls_dir.join(&entry.name);
+                let lock = self
+                    .serialize_dir_entries_ls_locks
+                    .get_or_insert_with(path.to_str().unwrap().to_owned(), || RwLock::new(false));
+                let guard = lock.read().await;
+                let entry = self.read_ls_entry(dir, &path, &key).await;
+                drop(guard);
+                match entry {
+                    Ok((entry_ino, FileType::RegularFile, _)) => {
+                        let (entries, size) = files.entry(entry_ino).or_insert((0, 0));
+                        if *entries == 0 {
+                            *size = self.get_attr(entry_ino).await?.size;
+                        }
+                        *entries += 1;
+                    }
+                    Ok((entry_ino, FileType::Directory, _)) => dirs.push(entry_ino),
+                    Ok(_) => {}
+                    Err(err) => {
+                        warn!(ino = dir, err = %err, "reading directory entry, it's not counted in the quota");
+                    }
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /// Keep the total size in [`USED_BYTES_FILENAME`], if we count it. Call it with the lock on the quota, so the
+    /// last one written is the last one we have.
+    async fn write_used_bytes(&self, quota: &QuotaState) -> FsResult<()> {
+        if quota.max_size.is_none() || self.read_only {
+            return Ok(());
+        }
+        self.write_bound(&used_bytes_path(), &quota.used, USED_BYTES_AAD)
+            .await
+    }
+
+    /// Keep the list of the directories with a quota in [`QUOTA_DIRS_FILENAME`]. Call it with the lock on the quota.
+    async fn write_quota_dirs(&self, quota: &QuotaState) -> FsResult<()> {
+        let mut inos: Vec<u64> = quota.dirs.keys().copied().collect();
+        inos.sort_unstable();
+        self.write_bound(&quota_dirs_path(), &inos, QUOTA_DIRS_AAD)
+            .await
+    }
+}
+
+/// Associated data binding [`QUOTA_DIRS_FILENAME`] to what it is.
+pub(crate) const QUOTA_DIRS_AAD: &[u8] = b"quota_dirs";
+
+pub(crate) fn used_bytes_path() -> PathBuf {
+    Path::new(SECURITY_DIR).join(USED_BYTES_FILENAME)
+}
+
+pub(crate) fn quota_dirs_path() -> PathBuf {
+    Path::new(SECURITY_DIR).join(QUOTA_DIRS_FILENAME)
+}
+
+/// Associated data binding the [`QUOTA_FILENAME`] of a directory to its inode.
+pub(crate) fn quota_aad(ino: u64) -> Vec<u8> {
+    [b"quota".as_slice(), &ino.to_le_bytes()].concat()
+}
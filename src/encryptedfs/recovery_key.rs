@@ -0,0 +1,201 @@
+//! Printable recovery key of the master key, see [`EncryptedFs::generate_recovery_key`].
+//!
+//! The recovery key is random, encoded in base32 in groups of [`GROUP_LEN`] letters so it can be printed and typed
+//! back. It opens a slot kept apart from the password slots in [`RECOVERY_KEY_FILENAME`], so it can be replaced or
+//! revoked without knowing it, and it's used once, see [`EncryptedFs::recover_with_key`].
+
+use std::path::{Path, PathBuf};
+
+use argon2::password_hash::rand_core::RngCore;
+use shush_rs::{ExposeSecret, SecretString, SecretVec};
+
+use crate::crypto;
+use crate::crypto::Cipher;
+use crate::encryptedfs::key_slots::{self, KeySlot, KeySlots};
+use crate::encryptedfs::{
+    check_structure, cipher_change, key_path, EncryptedFs, FsError, FsResult, SECURITY_DIR,
+};
+use crate::storage::{LocalStorage, Storage};
+
+/// The slot of the recovery key, in [`SECURITY_DIR`] next to the password slots.
+pub(crate) const RECOVERY_KEY_FILENAME: &str = "key.recovery.enc";
+/// Random bytes of the recovery key, 160 bits, which are 32 letters of base32.
+const RECOVERY_KEY_LEN: usize = 20;
+/// Letters in each group of the printed recovery key.
+const GROUP_LEN: usize = 4;
+const GROUP_SEPARATOR: char = '-';
+/// RFC 4648 base32, it has no 0, 1, 8 and 9 to be mistaken for the letters.
+const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+impl EncryptedFs {
+    /// Create a recovery key which can set a new password if all of them are forgotten, see
+    /// [`EncryptedFs::recover_with_key`].
+    ///
+    /// `password` is any of the current passwords, `cipher` is used to encrypt the recovery slot. It replaces the
+    /// previous recovery key, if any. The returned key is shown only once, it's not kept anywhere.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn generate_recovery_key(
+        data_dir: &Path,
+        password: SecretString,
+        cipher: Cipher,
+    ) -> FsResult<SecretString> {
+        let storage = LocalStorage::new(data_dir);
+        check_structure(&storage, false).await?;
+        cipher_change::check_not_interrupted(&storage).await?;
+        let (_, _, key) = KeySlots::read_and_open(&storage, &key_path(), cipher, &password).await?;
+        let mut recovery_key = SecretVec::new(Box::new(vec![0; RECOVERY_KEY_LEN]));
+        crypto::create_rng().fill_bytes(&mut recovery_key.expose_secret_mut());
+        let slot = KeySlot::seal(&key, &encode(&recovery_key.expose_secret(), None), cipher)?;
+        KeySlots { slots: vec![slot] }
+            .write(&storage, &recovery_key_path())
+            .await?;
+        storage.sync(Path::new(SECURITY_DIR)).await?;
+        Ok(encode(
+            &recovery_key.expose_secret(),
+            Some((GROUP_LEN, GROUP_SEPARATOR)),
+        ))
+    }
+
+    /// Set `new_password` with the recovery key from [`EncryptedFs::generate_recovery_key`].
+    ///
+    /// All the password slots are replaced by one for `new_password`, like a reset. The recovery key can't be used
+    /// again after that, generate another one. The case and the separators of `recovery_key` don't matter, if it's
+    /// not a recovery key it returns [`FsError::InvalidInput`], and [`FsError::InvalidPassword`] if it's not the
+    /// current one.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn recover_with_key(
+        data_dir: &Path,
+        recovery_key: SecretString,
+        new_password: SecretString,
+    ) -> FsResult<()> {
+        let storage = LocalStorage::new(data_dir);
+        check_structure(&storage, false).await?;
+        cipher_change::check_not_interrupted(&storage).await?;
+        let recovery_key = decode(&recovery_key.expose_secret())?;
+        let recovery_path = recovery_key_path();
+        if storage.kind(&recovery_path).await?.is_none()
+            && storage
+                .kind(&key_slots::backup_path(&recovery_path))
+                .await?
+                .is_none()
+        {
+            return Err(FsError::InvalidPassword);
+        }
+        // the recovery slot is never a single password key file, the cipher is only used for them
+        let (slots, index, key) = KeySlots::read_and_open(
+            &storage,
+            &recovery_path,
+            Cipher::recommended(),
+            &encode(&recovery_key.expose_secret(), None),
+        )
+        .await?;
+        let cipher = slots.slots[index].cipher();
+        KeySlots {
+            slots: vec![KeySlot::seal(&key, &new_password, cipher)?],
+        }
+        .write(&storage, &key_path())
+        .await?;
+        key_slots::remove_legacy_salt(&storage, &key_path()).await?;
+        remove_recovery_key(&storage).await
+    }
+
+    /// Revoke the recovery key, after which it can't be used.
+    ///
+    /// `password` is any of the current passwords. Nothing is done if there is no recovery key.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn revoke_recovery_key(
+        data_dir: &Path,
+        password: SecretString,
+        cipher: Cipher,
+    ) -> FsResult<()> {
+        let storage = LocalStorage::new(data_dir);
+        check_structure(&storage, false).await?;
+        cipher_change::check_not_interrupted(&storage).await?;
+        KeySlots::read_and_open(&storage, &key_path(), cipher, &password).await?;
+        remove_recovery_key(&storage).await
+    }
+}
+
+/// Key of [`RECOVERY_KEY_FILENAME`] in the storage.
+fn recovery_key_path() -> PathBuf {
+    Path::new(SECURITY_DIR).join(RECOVERY_KEY_FILENAME)
+}
+
+async fn remove_recovery_key(storage: &dyn Storage) -> FsResult<()> {
+    let path = recovery_key_path();
+    for path in [
+        key_slots::backup_path(&path),
+        key_slots::checksum_path(&path),
+        path,
+    ] {
+        if storage.kind(&path).await?.is_some() {
+            storage.remove(&path).await?;
+        }
+    }
+    storage.sync(Path::new(SECURITY_DIR)).await?;
+    Ok(())
+}
+
+/// Encode `bytes` in base32, in groups separated like `(len, separator)` if given.
+///
+/// The string has the capacity it needs from the start, so no copy of it is left behind when it grows.
+fn encode(bytes: &[u8], groups: Option<(usize, char)>) -> SecretString {
+    let letters = (bytes.len() * 8).div_ceil(5);
+    let separators = groups.map_or(0, |(len, _)| letters.saturating_sub(1) / len);
+    let mut encoded = String::with_capacity(letters + separators);
+    let mut buffer = 0_u16;
+    let mut bits = 0;
+    let push = |encoded: &mut String, index: u16| {
+        if let Some((len, separator)) = groups {
+            if !encoded.is_empty() && (encoded.len() + 1) % (len + 1) == 0 {
+                encoded.push(separator);
+            }
+        }
+        encoded.push(char::from(ALPHABET[usize::from(index & 0x1f)]));
+    };
+    for byte in bytes {
+        buffer = (buffer << 8) | u16::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            push(&mut encoded, buffer >> bits);
+        }
+    }
+    if bits > 0 {
+        push(&mut encoded, buffer << (5 - bits));
+    }
+    SecretString::new(Box::new(encoded))
+}
+
+/// Decode a recovery key encoded by [`encode`], ignoring the case, the separators and the whitespace.
+#[allow(clippy::cast_possible_truncation)]
+fn decode(recovery_key: &str) -> FsResult<SecretVec<u8>> {
+    let invalid = || FsError::invalid_input("invalid recovery key");
+    let mut bytes = SecretVec::new(Box::new(Vec::with_capacity(RECOVERY_KEY_LEN)));
+    let mut buffer = 0_u16;
+    let mut bits = 0;
+    for c in recovery_key.chars() {
+        if c == GROUP_SEPARATOR || c.is_whitespace() {
+            continue;
+        }
+        let index = ALPHABET
+            .iter()
+            .position(|a| char::from(*a) == c.to_ascii_uppercase())
+            .ok_or_else(invalid)?;
+        buffer = (buffer << 5) | index as u16;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            let mut bytes = bytes.expose_secret_mut();
+            if bytes.len() == RECOVERY_KEY_LEN {
+                return Err(invalid());
+            }
+            bytes.push((buffer >> bits) as u8);
+        }
+        buffer &= (1 << bits) - 1;
+    }
+    if bytes.expose_secret().len() != RECOVERY_KEY_LEN || buffer != 0 {
+        return Err(invalid());
+    }
+    Ok(bytes)
+}
@@ -0,0 +1,423 @@
+//! Point-in-time snapshots of the files and directories, see [`EncryptedFs::create_snapshot`]. Needs the `snapshots`
+//! feature.
+//!
+//! Each snapshot is a data dir in [`SNAPSHOTS_DIR`] with copies of the inodes, of the directory entries and of the
+//! files in [`SECURITY_DIR`], while the contents of the files are hard linked with [`Storage::hard_link`], so they
+//! take space only once they change. The contents are changed in place only through
+//! [`EncryptedFs::open_contents_rw`] and when truncated to zero, both copy the segments still shared with a snapshot
+//! first, see [`EncryptedFs::unshare_contents`]. The other writes replace the files, which keeps the snapshots as
+//! they were.
+//!
+//! The space kept by the snapshots is not counted in [`FsOptions::max_size_bytes`] or in the quotas of the
+//! directories, only in what [`EncryptedFs::statfs`] reports as free from the storage.
+//!
+//! [`FsOptions::max_size_bytes`]: crate::encryptedfs::FsOptions::max_size_bytes
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::sync::RwLock;
+use tracing::info;
+
+use crate::encryptedfs::journal::JOURNAL_DIR;
+use crate::encryptedfs::{
+    sharded_inodes, EncryptedFs, FsError, FsOptions, FsResult, KeyProvider, SetFileAttr,
+    CONTENTS_DIR, INODES_DIR, INSTANCE_LOCK_FILENAME, SECURITY_DIR, SNAPSHOTS_DIR,
+};
+use crate::fs_util::StatVfs;
+use crate::segmented_file::SegmentedFile;
+use crate::storage::{EntryKind, Storage, StorageEntry, StorageFile};
+
+/// Key of the snapshot `name` in the storage.
+///
+/// The names are kept in plaintext, so they are limited to what is safe as a file name everywhere.
+fn snapshot_dir(name: &str) -> FsResult<PathBuf> {
+    if name.is_empty()
+        || name.starts_with('.')
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err(FsError::invalid_input(format!(
+            "invalid snapshot name {name:?}"
+        )));
+    }
+    Ok(Path::new(SNAPSHOTS_DIR).join(name))
+}
+
+/// Where a snapshot is built before it's moved to its name, or moved to before it's removed.
+fn tmp_snapshot_dir(name: &str) -> PathBuf {
+    Path::new(SNAPSHOTS_DIR).join(format!(".{name}.tmp"))
+}
+
+/// The data dir of a snapshot, under its key in the storage of the vault.
+struct SnapshotStorage {
+    inner: Arc<dyn Storage>,
+    dir: PathBuf,
+}
+
+impl SnapshotStorage {
+    fn key(&self, key: &Path) -> PathBuf {
+        self.dir.join(key)
+    }
+}
+
+#[async_trait]
+impl Storage for SnapshotStorage {
+    async fn open(&self, key: &Path, write: bool) -> io::Result<Box<dyn StorageFile>> {
+        self.inner.open(&self.key(key), write).await
+    }
+
+    async fn create(&self, key: &Path) -> io::Result<Box<dyn StorageFile>> {
+        self.inner.create(&self.key(key)).await
+    }
+
+    async fn create_new(&self, key: &Path) -> io::Result<Box<dyn StorageFile>> {
+        self.inner.create_new(&self.key(key)).await
+    }
+
+    async fn read(&self, key: &Path) -> io::Result<Vec<u8>> {
+        self.inner.read(&self.key(key)).await
+    }
+
+    async fn write(&self, key: &Path, data: &[u8]) -> io::Result<()> {
+        self.inner.write(&self.key(key), data).await
+    }
+
+    async fn write_unsynced(&self, key: &Path, data: &[u8]) -> io::Result<()> {
+        self.inner.write_unsynced(&self.key(key), data).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(&self.key(from), &self.key(to)).await
+    }
+
+    async fn remove(&self, key: &Path) -> io::Result<()> {
+        self.inner.remove(&self.key(key)).await
+    }
+
+    async fn create_dir(&self, key: &Path) -> io::Result<()> {
+        self.inner.create_dir(&self.key(key)).await
+    }
+
+    async fn remove_dir(&self, key: &Path) -> io::Result<()> {
+        self.inner.remove_dir(&self.key(key)).await
+    }
+
+    async fn list(&self, key: &Path) -> io::Result<Vec<StorageEntry>> {
+        self.inner.list(&self.key(key)).await
+    }
+
+    async fn kind(&self, key: &Path) -> io::Result<Option<EntryKind>> {
+        self.inner.kind(&self.key(key)).await
+    }
+
+    async fn sync(&self, key: &Path) -> io::Result<()> {
+        self.inner.sync(&self.key(key)).await
+    }
+
+    async fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.hard_link(&self.key(from), &self.key(to)).await
+    }
+
+    async fn link_count(&self, key: &Path) -> io::Result<u64> {
+        self.inner.link_count(&self.key(key)).await
+    }
+
+    async fn statvfs(&self) -> io::Result<StatVfs> {
+        self.inner.statvfs().await
+    }
+}
+
+impl EncryptedFs {
+    /// Keep the current state of the files and directories as the snapshot `name`, to read it later with
+    /// [`EncryptedFs::open_snapshot`].
+    ///
+    /// The names are kept in plaintext in the data dir, so they can only have ASCII letters, digits, `-`, `_` and
+    /// `.`, but not first. Each file is taken at once, with what the open handles wrote to it, but what is changed
+    /// in other files while it runs may be in it or not.
+    ///
+    /// It fails with [`FsError::AlreadyExists`] if there is a snapshot with this name, and if the storage doesn't
+    /// support [`Storage::hard_link`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn create_snapshot(&self, name: &str) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let dir = snapshot_dir(name)?;
+        if self.kind_at(&dir).await.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+        // built next to it and moved there when complete, so a half made one is never seen
+        let tmp = tmp_snapshot_dir(name);
+        if self.kind_at(&tmp).await.is_some() {
+            self.storage.remove_dir(&tmp).await?;
+        }
+        // from now on the contents are copied before they are changed in place
+        self.shared_contents.store(true, Ordering::Release);
+        self.flush_dir_times().await?;
+
+        for dir in [INODES_DIR, CONTENTS_DIR] {
+            self.storage.create_dir(&tmp.join(dir)).await?;
+        }
+        let mut inodes: Vec<_> = sharded_inodes(&*self.storage, Path::new(INODES_DIR))
+            .await?
+            .into_iter()
+            .collect();
+        inodes.sort_unstable();
+        for ino in inodes {
+            match self.snapshot_inode(&tmp, ino).await {
+                // removed since we listed them
+                Err(FsError::Io { source, .. }) if source.kind() == io::ErrorKind::NotFound => {}
+                res => res?,
+            }
+        }
+        // the key is read from the vault, this one is only there for a complete data dir
+        self.copy_tree(
+            Path::new(SECURITY_DIR),
+            &tmp.join(SECURITY_DIR),
+            &[INSTANCE_LOCK_FILENAME, JOURNAL_DIR],
+        )
+        .await?;
+        self.storage
+            .create_dir(&tmp.join(SECURITY_DIR).join(JOURNAL_DIR))
+            .await?;
+
+        self.sync_tree(&tmp).await?;
+        self.storage.rename(&tmp, &dir).await?;
+        self.storage.sync(Path::new(SNAPSHOTS_DIR)).await?;
+        info!(name, "snapshot created");
+        Ok(())
+    }
+
+    /// Names of the snapshots, sorted.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn list_snapshots(&self) -> FsResult<Vec<String>> {
+        let dir = Path::new(SNAPSHOTS_DIR);
+        if self.kind_at(dir).await.is_none() {
+            return Ok(vec![]);
+        }
+        let mut names: Vec<_> = self
+            .storage
+            .list(dir)
+            .await?
+            .into_iter()
+            .filter(|entry| entry.kind == EntryKind::Dir && !entry.name.starts_with('.'))
+            .map(|entry| entry.name)
+            .collect();
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    /// A read-only [`EncryptedFs`] with the files and directories of the snapshot `name`, as they were when it was
+    /// created. It uses the key and the password provider of this one.
+    ///
+    /// It fails with [`FsError::NotFound`] if there is no snapshot with this name.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn open_snapshot(&self, name: &str) -> FsResult<Arc<Self>> {
+        let dir = snapshot_dir(name)?;
+        if self.kind_at(&dir).await != Some(EntryKind::Dir) {
+            return Err(FsError::not_found(format!("snapshot {name}")));
+        }
+        let key_provider = KeyProvider {
+            storage: self.storage.clone(),
+            password_provider: self.key.provider().password_provider.clone(),
+            cipher: self.cipher,
+        };
+        let mut options = FsOptions::builder()
+            .runtime_handle(self.runtime.clone())
+            .build();
+        // the contents are the same files
+        options.segment_blocks = self.segment_blocks;
+        Self::with_key_provider(
+            Arc::new(SnapshotStorage {
+                inner: self.storage.clone(),
+                dir,
+            }),
+            key_provider,
+            true,
+            options,
+        )
+        .await
+    }
+
+    /// Remove the snapshot `name`, with [`FsOptions::secure_delete`] what only it has is overwritten first.
+    ///
+    /// The views of it opened with [`EncryptedFs::open_snapshot`] can't be used after that. It fails with
+    /// [`FsError::NotFound`] if there is no snapshot with this name.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn delete_snapshot(&self, name: &str) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let dir = snapshot_dir(name)?;
+        if self.kind_at(&dir).await != Some(EntryKind::Dir) {
+            return Err(FsError::not_found(format!("snapshot {name}")));
+        }
+        // out of the list first, so it's not seen half removed
+        let tmp = tmp_snapshot_dir(name);
+        if self.kind_at(&tmp).await.is_some() {
+            self.storage.remove_dir(&tmp).await?;
+        }
+        self.storage.rename(&dir, &tmp).await?;
+        self.storage.sync(Path::new(SNAPSHOTS_DIR)).await?;
+        if self.secure_delete {
+            // the contents still shared with the vault or with other snapshots are skipped
+            for key in self.tree_files(&tmp).await? {
+                self.shred(&key, 0).await?;
+            }
+        }
+        self.storage.remove_dir(&tmp).await?;
+        info!(name, "snapshot deleted");
+        Ok(())
+    }
+
+    /// See if there are snapshots, before anything is written, and remove what is left of the interrupted
+    /// [`EncryptedFs::create_snapshot`] and [`EncryptedFs::delete_snapshot`].
+    pub(crate) async fn load_snapshots(&self) -> FsResult<()> {
+        let dir = Path::new(SNAPSHOTS_DIR);
+        if self.kind_at(dir).await.is_none() {
+            return Ok(());
+        }
+        let entries = self.storage.list(dir).await?;
+        if !self.read_only {
+            for entry in entries.iter().filter(|entry| entry.name.starts_with('.')) {
+                self.storage.remove_dir(&dir.join(&entry.name)).await?;
+            }
+        }
+        // the ones left by a read-only instance still share contents
+        self.shared_contents
+            .store(!entries.is_empty(), Ordering::Release);
+        Ok(())
+    }
+
+    /// Add the inode `ino` with its contents to the snapshot being built in `tmp`.
+    async fn snapshot_inode(&self, tmp: &Path, ino: u64) -> FsResult<()> {
+        // no writes to it meanwhile
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _write_guard = lock.write().await;
+        self.suspend_writers(ino).await?;
+
+        let contents = self.contents_path(ino);
+        match self.storage.kind(&contents).await? {
+            Some(EntryKind::Dir) => self.copy_tree(&contents, &tmp.join(&contents), &[]).await?,
+            Some(EntryKind::File) => {
+                self.storage
+                    .create_dir(&tmp.join(contents.parent().unwrap()))
+                    .await?;
+                for index in 0..SegmentedFile::segments_count(&self.storage, &contents).await? {
+                    let segment = SegmentedFile::segment_path(&contents, index);
+                    self.storage
+                        .hard_link(&segment, &tmp.join(&segment))
+                        .await?;
+                }
+            }
+            None => {}
+        }
+        let inode = self.ino_file(ino);
+        self.storage
+            .create_dir(&tmp.join(inode.parent().unwrap()))
+            .await?;
+        self.storage
+            .write_unsynced(&tmp.join(&inode), &self.storage.read(&inode).await?)
+            .await?;
+        Ok(())
+    }
+
+    /// Write what the open handles of `ino` have and close their writers, like [`EncryptedFs::lock`] does. The next
+    /// write opens them again, after the contents are copied if shared with a snapshot.
+    async fn suspend_writers(&self, ino: u64) -> FsResult<()> {
+        let fhs = self
+            .opened_files_for_write
+            .read(&ino)
+            .await
+            .get(&ino)
+            .cloned()
+            .unwrap_or_default();
+        for fh in fhs {
+            let guard = self.write_handles.read(&fh).await;
+            let Some(ctx) = guard.get(&fh) else {
+                continue;
+            };
+            let mut ctx = ctx.lock().await;
+            ctx.write_buffer()?;
+            let Some(mut writer) = ctx.writer.take() else {
+                continue;
+            };
+            writer.finish()?.sync_all()?;
+            let set_attr: SetFileAttr = ctx.attr.clone().into();
+            drop(ctx);
+            drop(guard);
+            self.update_attr(ino, set_attr).await?;
+        }
+        Ok(())
+    }
+
+    /// Copy the files and directories in `from` to `to`, except the ones named in `skip` directly in `from` and the
+    /// temporary ones.
+    async fn copy_tree(&self, from: &Path, to: &Path, skip: &[&str]) -> FsResult<()> {
+        let mut dirs = vec![(from.to_path_buf(), to.to_path_buf())];
+        while let Some((from_dir, to_dir)) = dirs.pop() {
+            self.storage.create_dir(&to_dir).await?;
+            for entry in self.storage.list(&from_dir).await? {
+                if entry.name.starts_with('.')
+                    || (from_dir == from && skip.contains(&entry.name.as_str()))
+                {
+                    continue;
+                }
+                let (from, to) = (from_dir.join(&entry.name), to_dir.join(&entry.name));
+                match entry.kind {
+                    EntryKind::Dir => dirs.push((from, to)),
+                    EntryKind::File => {
+                        self.storage
+                            .write_unsynced(&to, &self.storage.read(&from).await?)
+                            .await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// All the files under `dir`.
+    async fn tree_files(&self, dir: &Path) -> FsResult<Vec<PathBuf>> {
+        let mut files = vec![];
+        let mut dirs = vec![dir.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for entry in self.storage.list(&dir).await? {
+                let path = dir.join(entry.name);
+                match entry.kind {
+                    EntryKind::Dir => dirs.push(path),
+                    EntryKind::File => files.push(path),
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /// Make the files under `dir` and the directories durable, from the deepest ones.
+    async fn sync_tree(&self, dir: &Path) -> FsResult<()> {
+        for file in self.tree_files(dir).await? {
+            self.storage.sync(&file).await?;
+        }
+        let mut dirs = vec![dir.to_path_buf()];
+        let mut all = vec![];
+        while let Some(dir) = dirs.pop() {
+            for entry in self.storage.list(&dir).await? {
+                if entry.kind == EntryKind::Dir {
+                    dirs.push(dir.join(entry.name));
+                }
+            }
+            all.push(dir);
+        }
+        for dir in all.iter().rev() {
+            self.storage.sync(dir).await?;
+        }
+        Ok(())
+    }
+}
@@ -1,27 +1,115 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fs::File;
+use std::io::{self, IoSlice, Read};
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::string::ToString;
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-use shush_rs::{ExposeSecret, SecretString};
+use async_trait::async_trait;
+use base64::Engine;
+use futures_util::FutureExt;
+use proptest::prelude::*;
+use serde::{Deserialize, Serialize};
+use shush_rs::{ExposeSecret, SecretString, SecretVec};
+use strum::IntoEnumIterator;
 use tracing_test::traced_test;
 
-use crate::crypto::Cipher;
-use crate::encryptedfs::write_all_bytes_to_fs;
+use crate::crypto::holes::Holes;
+use crate::crypto::write::{CryptoWrite, BLOCK_SIZE};
+use crate::crypto::{Cipher, CipherChoice};
+use crate::encryptedfs::changes::changes_dir;
+use crate::encryptedfs::journal::journal_dir;
+use crate::encryptedfs::quota::{QUOTA_FILENAME, USED_BYTES_FILENAME};
+use crate::encryptedfs::DirQuota;
+use crate::encryptedfs::DEFAULT_WRITE_BUFFER_SIZE;
 use crate::encryptedfs::INODES_DIR;
 use crate::encryptedfs::KEY_ENC_FILENAME;
 use crate::encryptedfs::KEY_SALT_FILENAME;
-use crate::encryptedfs::SECURITY_DIR;
-use crate::encryptedfs::{CopyFileRangeReq, HASH_DIR};
 use crate::encryptedfs::{
-    DirectoryEntry, DirectoryEntryPlus, EncryptedFs, FileType, FsError, FsResult, SetFileAttr,
-    CONTENTS_DIR, ROOT_INODE,
+    app_data_path, check_file_size, child_count_aad, deserialize_bound, deserialize_ls_entry,
+    dir_entry_aad, inode_aad, key_slots, read_or_create_key, seek_for_read, serialize_bound_into,
+    serialize_ls_entry, shard_path, sharded_inodes, FileAttrV3, FORMAT_VERSION, LONG_NAME_PREFIX,
+    LS_DIR, NAME_SALT_FILENAME, VERSION_FILENAME,
 };
+use crate::encryptedfs::{
+    check_consistency, check_consistency_blocking, CheckProblemKind, CheckReport, ConsistencyOp,
+    CopyFileRangeReq, HASH_DIR, LOST_FOUND_DIR,
+};
+use crate::encryptedfs::{
+    AsyncPasswordProvider, AtimePolicy, ChangeRecord, CreateFileAttr, CreateFlags,
+    DirIterationOrder, DirectoryEntry, DirectoryEntryPlus, DirectoryEntryResult, DurabilityPolicy,
+    EncryptedFs, FileAttr, FileId, FileType, Flock, FsError, FsEvent, FsOptions, FsResult,
+    ImportOptions, ImportOverwrite, ImportProgress, InodeAlloc, LockInfo, LockType, OpControl,
+    OpenFlags, PasswordProvider, RequestContext, SeekWhence, SetFileAttr, ThrottleLimits,
+    TimeOrNow, ACCESS_EXEC, CHILD_COUNT_FILENAME, CONTENTS_DIR, LATENCY_BUCKETS, ROOT_INODE,
+};
+use crate::encryptedfs::{
+    CIPHER_FILENAME, INODE_COUNTER_FILENAME, INSTANCE_LOCK_FILENAME, SECURITY_DIR,
+};
+use crate::fs_util::StatVfs;
+use crate::segmented_file::SegmentedFile;
+use crate::storage::{EntryKind, InMemoryStorage, Storage, StorageEntry, StorageFile};
 use crate::test_common::run_test;
 use crate::test_common::TestSetup;
-use crate::test_common::{create_attr, get_fs, PasswordProviderImpl};
+use crate::test_common::{create_attr, get_fs, take_fs, PasswordProviderImpl};
+use crate::test_util::read_to_vec;
 use crate::{crypto, test_common};
 
-static ROOT_INODE_STR: &str = "1";
+/// What is at `key` in the storage of `fs`.
+/// Encrypted blocks per segment of the contents for [`small_segments`].
+const SEGMENT_BLOCKS: u64 = 3;
+
+/// Options with segments of [`SEGMENT_BLOCKS`], so the tests cross their boundaries.
+fn small_segments() -> FsOptions {
+    FsOptions::default().with_segment_blocks(NonZeroU64::new(SEGMENT_BLOCKS).unwrap())
+}
+
+/// Open the data dir in `storage` with `options`.
+async fn open_storage_with(storage: Arc<dyn Storage>, options: FsOptions) -> Arc<EncryptedFs> {
+    EncryptedFs::with_storage(
+        storage,
+        Box::new(PasswordProviderImpl {}),
+        Cipher::ChaCha20Poly1305,
+        false,
+        options,
+    )
+    .await
+    .unwrap()
+}
+
+/// Create a file in `parent` with `data`, returning its inode.
+async fn create_file(fs: &EncryptedFs, parent: u64, name: &str, data: &[u8]) -> u64 {
+    let (fh, attr) = fs
+        .create(
+            parent,
+            &SecretString::from_str(name).unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    fs.write_all(attr.ino, 0, data, fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+    attr.ino
+}
+
+async fn kind(fs: &EncryptedFs, key: impl AsRef<Path>) -> Option<EntryKind> {
+    fs.storage.kind(key.as_ref()).await.unwrap()
+}
+
+/// The data dir of `fs`, `None` if it's not kept on the local filesystem.
+///
+/// The tests working on the data dir directly, like the migrations, are skipped without it.
+fn local_data_dir(fs: &EncryptedFs) -> Option<PathBuf> {
+    fs.storage.local_path().map(Path::to_path_buf)
+}
 
 #[tokio::test]
 #[traced_test]
@@ -47,7 +135,7 @@ async fn test_write() {
                 .await
                 .unwrap();
             let data = "test-42";
-            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+            fs.write_all(attr.ino, 0, data.as_bytes(), fh)
                 .await
                 .unwrap();
             fs.flush(fh).await.unwrap();
@@ -59,7 +147,7 @@ async fn test_write() {
             // offset greater than current position
             let data = "37";
             let fh = fs.open(attr.ino, false, true).await.unwrap();
-            write_all_bytes_to_fs(&fs, attr.ino, 5, data.as_bytes(), fh)
+            fs.write_all(attr.ino, 5, data.as_bytes(), fh)
                 .await
                 .unwrap();
             fs.flush(fh).await.unwrap();
@@ -72,7 +160,7 @@ async fn test_write() {
             // offset after the file end
             let data = "37";
             let fh = fs.open(attr.ino, false, true).await.unwrap();
-            write_all_bytes_to_fs(&fs, attr.ino, 42, data.as_bytes(), fh)
+            fs.write_all(attr.ino, 42, data.as_bytes(), fh)
                 .await
                 .unwrap();
             fs.flush(fh).await.unwrap();
@@ -95,15 +183,15 @@ async fn test_write() {
                 .await
                 .unwrap();
             let data = "test-42-37-42";
-            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+            fs.write_all(attr.ino, 0, data.as_bytes(), fh)
                 .await
                 .unwrap();
             let data1 = "01";
-            write_all_bytes_to_fs(&fs, attr.ino, 5, data1.as_bytes(), fh)
+            fs.write_all(attr.ino, 5, data1.as_bytes(), fh)
                 .await
                 .unwrap();
             let data2 = "02";
-            write_all_bytes_to_fs(&fs, attr.ino, 8, data2.as_bytes(), fh)
+            fs.write_all(attr.ino, 8, data2.as_bytes(), fh)
                 .await
                 .unwrap();
             fs.flush(fh).await.unwrap();
@@ -127,13 +215,11 @@ async fn test_write() {
                 .await
                 .unwrap();
             let data = "test-42-37";
-            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
-                .await
-                .unwrap();
-            write_all_bytes_to_fs(&fs, attr.ino, 5, b"37", fh)
+            fs.write_all(attr.ino, 0, data.as_bytes(), fh)
                 .await
                 .unwrap();
-            write_all_bytes_to_fs(&fs, attr.ino, data.len() as u64, b"-42", fh)
+            fs.write_all(attr.ino, 5, b"37", fh).await.unwrap();
+            fs.write_all(attr.ino, data.len() as u64, b"-42", fh)
                 .await
                 .unwrap();
             fs.flush(fh).await.unwrap();
@@ -171,6 +257,268 @@ async fn test_write() {
     .await;
 }
 
+#[tokio::test]
+#[traced_test]
+async fn test_write_resets_readers_in_changed_blocks() {
+    run_test(
+        TestSetup {
+            key: "test_write_resets_readers_in_changed_blocks",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data = vec![b'a'; BLOCK_SIZE * 3];
+            fs.write_all(attr.ino, 0, &data, fh).await.unwrap();
+            fs.flush(fh).await.unwrap();
+
+            // readers with the first and the second block decrypted
+            let fh_read = fs.open(attr.ino, true, false).await.unwrap();
+            let fh_read2 = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = [0; 10];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh_read).await;
+            assert_eq!([b'a'; 10], buf);
+            test_common::read_exact(&fs, attr.ino, BLOCK_SIZE as u64, &mut buf, fh_read2).await;
+            assert_eq!([b'a'; 10], buf);
+
+            // change the first block, it's written to disk when the writer moves to the third block
+            fs.write(attr.ino, 5, b"bbbb", fh).await.unwrap();
+            fs.write(attr.ino, BLOCK_SIZE as u64 * 2, b"cccc", fh)
+                .await
+                .unwrap();
+
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh_read).await;
+            assert_eq!(b"aaaaabbbba", &buf);
+            test_common::read_exact(&fs, attr.ino, BLOCK_SIZE as u64, &mut buf, fh_read2).await;
+            assert_eq!([b'a'; 10], buf);
+
+            fs.release(fh).await.unwrap();
+            fs.release(fh_read).await.unwrap();
+            fs.release(fh_read2).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_write_updates_readers_in_other_blocks() {
+    let fs = open_storage_with(Arc::new(InMemoryStorage::new()), FsOptions::default()).await;
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    fs.write_all(attr.ino, 0, &[b'a'; BLOCK_SIZE], fh)
+        .await
+        .unwrap();
+    fs.flush(fh).await.unwrap();
+
+    let fh_read = fs.open(attr.ino, true, false).await.unwrap();
+    let mut buf = [0; 10];
+    test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh_read).await;
+    assert_eq!([b'a'; 10], buf);
+
+    // extend the file in blocks the reader is not in
+    fs.write_all(attr.ino, BLOCK_SIZE as u64 * 2, b"bbbb", fh)
+        .await
+        .unwrap();
+    let mtime = fs.get_attr(attr.ino).await.unwrap().mtime;
+    let times = {
+        let guard = fs.read_handles.read(&fh_read).await;
+        let ctx = guard.get(&fh_read).unwrap().lock().await;
+        ctx.attr.clone()
+    };
+    assert_eq!(times.mtime, mtime);
+    assert_eq!(times.ctime, mtime);
+
+    test_common::read_exact(&fs, attr.ino, BLOCK_SIZE as u64 * 2, &mut buf[..4], fh_read).await;
+    assert_eq!(b"bbbb", &buf[..4]);
+
+    fs.release(fh).await.unwrap();
+    fs.release(fh_read).await.unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_write_across_segments() {
+    let fs = open_storage_with(Arc::new(InMemoryStorage::new()), small_segments()).await;
+
+    let test_file = SecretString::from_str("test-file").unwrap();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &test_file,
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    let segment_len = SEGMENT_BLOCKS as usize * BLOCK_SIZE;
+    // starts before the end of the first segment and ends in the third one
+    let offset = segment_len as u64 - 42;
+    let data: Vec<u8> = (0..segment_len + 84).map(|i| (i % 251) as u8).collect();
+    fs.write_all(attr.ino, offset, &data, fh).await.unwrap();
+    fs.flush(fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+
+    let path = fs.contents_path(attr.ino);
+    assert_eq!(
+        kind(&fs, SegmentedFile::segment_path(&path, 2)).await,
+        Some(EntryKind::File)
+    );
+    assert_eq!(kind(&fs, SegmentedFile::segment_path(&path, 3)).await, None);
+    assert_eq!(
+        offset + data.len() as u64,
+        fs.get_attr(attr.ino).await.unwrap().size
+    );
+
+    let fh = fs.open(attr.ino, true, false).await.unwrap();
+    let mut buf = vec![0; data.len()];
+    test_common::read_exact(&fs, attr.ino, offset, &mut buf, fh).await;
+    assert_eq!(data, buf);
+    let mut buf = vec![1; 42];
+    test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+    assert_eq!(vec![0; 42], buf);
+    fs.release(fh).await.unwrap();
+
+    // overwrite across the boundary
+    let fh = fs.open(attr.ino, false, true).await.unwrap();
+    fs.write_all(attr.ino, segment_len as u64 - 2, b"abcd", fh)
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+    let fh = fs.open(attr.ino, true, false).await.unwrap();
+    let mut buf = [0; 4];
+    test_common::read_exact(&fs, attr.ino, segment_len as u64 - 2, &mut buf, fh).await;
+    assert_eq!(b"abcd", &buf);
+    fs.release(fh).await.unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_set_len_across_segments() {
+    let fs = open_storage_with(Arc::new(InMemoryStorage::new()), small_segments()).await;
+
+    let test_file = SecretString::from_str("test-file").unwrap();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &test_file,
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    let segment_len = SEGMENT_BLOCKS as usize * BLOCK_SIZE;
+    let data: Vec<u8> = (0..segment_len * 3).map(|i| (i % 251) as u8).collect();
+    fs.write_all(attr.ino, 0, &data, fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+    let path = fs.contents_path(attr.ino);
+    assert_eq!(
+        kind(&fs, SegmentedFile::segment_path(&path, 2)).await,
+        Some(EntryKind::File)
+    );
+
+    // truncate into the first segment
+    let size = segment_len as u64 - 10;
+    fs.set_len(attr.ino, size).await.unwrap();
+    assert_eq!(size, fs.get_attr(attr.ino).await.unwrap().size);
+    assert_eq!(kind(&fs, SegmentedFile::segment_path(&path, 1)).await, None);
+    assert_eq!(kind(&fs, path.with_extension("truncate")).await, None);
+    let fh = fs.open(attr.ino, true, false).await.unwrap();
+    #[allow(clippy::cast_possible_truncation)]
+    let mut buf = vec![0; size as usize];
+    test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+    assert_eq!(data[..buf.len()], buf);
+    fs.release(fh).await.unwrap();
+
+    // extend into the second segment, it's filled with zeros
+    let size = segment_len as u64 + 10;
+    fs.set_len(attr.ino, size).await.unwrap();
+    assert_eq!(
+        kind(&fs, SegmentedFile::segment_path(&path, 1)).await,
+        Some(EntryKind::File)
+    );
+    let fh = fs.open(attr.ino, true, false).await.unwrap();
+    let mut buf = [1; 20];
+    test_common::read_exact(&fs, attr.ino, size - 20, &mut buf, fh).await;
+    assert_eq!([0; 20], buf);
+    fs.release(fh).await.unwrap();
+
+    // remove all segments
+    fs.remove_file(ROOT_INODE, &test_file).await.unwrap();
+    assert_eq!(kind(&fs, &path).await, None);
+    assert_eq!(kind(&fs, SegmentedFile::segment_path(&path, 1)).await, None);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_write_all_bytes_short_writes() {
+    run_test(
+        TestSetup {
+            key: "test_write_all_bytes_short_writes",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            // spanning several blocks forces `write` to return short counts
+            let data: Vec<u8> = (0..BLOCK_SIZE * 3 + 42).map(|i| (i % 251) as u8).collect();
+            let len = fs.write_all(attr.ino, 0, &data, fh).await.unwrap();
+            assert_eq!(data.len(), len);
+            // from an offset inside the first block
+            let data2: Vec<u8> = (0..BLOCK_SIZE * 2).map(|i| (i % 7) as u8).collect();
+            let offset = BLOCK_SIZE as u64 / 2;
+            let len = fs.write_all(attr.ino, offset, &data2, fh).await.unwrap();
+            assert_eq!(data2.len(), len);
+            fs.release(fh).await.unwrap();
+
+            let mut expected = data.clone();
+            expected[offset as usize..offset as usize + data2.len()].copy_from_slice(&data2);
+            assert_eq!(
+                expected.len() as u64,
+                fs.get_attr(attr.ino).await.unwrap().size
+            );
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![0; expected.len()];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+            fs.release(fh).await.unwrap();
+            assert_eq!(expected, buf);
+        },
+    )
+    .await;
+}
+
 #[tokio::test]
 #[traced_test]
 #[allow(clippy::too_many_lines)]
@@ -198,9 +546,7 @@ async fn test_read() {
                 .unwrap();
             let data = b"test-42";
             let mut buf = [0; 7];
-            write_all_bytes_to_fs(&fs, attr.ino, 0, data, fh)
-                .await
-                .unwrap();
+            fs.write_all(attr.ino, 0, data, fh).await.unwrap();
             fs.flush(fh).await.unwrap();
             fs.release(fh).await.unwrap();
             let fh = fs.open(attr.ino, true, false).await.unwrap();
@@ -215,9 +561,7 @@ async fn test_read() {
             let data = b"test-37";
             let mut buf = [0; 2];
             let fh = fs.open(attr.ino, false, true).await.unwrap();
-            write_all_bytes_to_fs(&fs, attr.ino, 0, data, fh)
-                .await
-                .unwrap();
+            fs.write_all(attr.ino, 0, data, fh).await.unwrap();
             fs.flush(fh).await.unwrap();
             fs.release(fh).await.unwrap();
             let fh = fs.open(attr.ino, true, false).await.unwrap();
@@ -242,7 +586,7 @@ async fn test_read() {
                 .await
                 .unwrap();
             let data = "test-42";
-            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+            fs.write_all(attr.ino, 0, data.as_bytes(), fh)
                 .await
                 .unwrap();
             fs.flush(fh).await.unwrap();
@@ -251,7 +595,7 @@ async fn test_read() {
             test_common::read_exact(&fs, attr.ino, 0, &mut [0_u8; 1], fh).await;
             let fh_2 = fs.open(attr.ino, false, true).await.unwrap();
             let new_data = "37";
-            write_all_bytes_to_fs(&fs, attr.ino, 5, new_data.as_bytes(), fh_2)
+            fs.write_all(attr.ino, 5, new_data.as_bytes(), fh_2)
                 .await
                 .unwrap();
             fs.flush(fh_2).await.unwrap();
@@ -273,7 +617,7 @@ async fn test_read() {
                 .await
                 .unwrap();
             let data = "test-42-37";
-            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+            fs.write_all(attr.ino, 0, data.as_bytes(), fh)
                 .await
                 .unwrap();
             fs.flush(fh).await.unwrap();
@@ -282,7 +626,7 @@ async fn test_read() {
             test_common::read_exact(&fs, attr.ino, 8, &mut [0_u8; 1], fh).await;
             let fh_2 = fs.open(attr.ino, false, true).await.unwrap();
             let new_data = "37";
-            write_all_bytes_to_fs(&fs, attr.ino, 5, new_data.as_bytes(), fh_2)
+            fs.write_all(attr.ino, 5, new_data.as_bytes(), fh_2)
                 .await
                 .unwrap();
             fs.flush(fh_2).await.unwrap();
@@ -304,7 +648,7 @@ async fn test_read() {
                 .await
                 .unwrap();
             let data = "test-42-37";
-            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+            fs.write_all(attr.ino, 0, data.as_bytes(), fh)
                 .await
                 .unwrap();
             fs.flush(fh).await.unwrap();
@@ -313,7 +657,7 @@ async fn test_read() {
             test_common::read_exact(&fs, attr.ino, 7, &mut [0_u8; 1], fh).await;
             let fh_2 = fs.open(attr.ino, false, true).await.unwrap();
             let new_data = "37";
-            write_all_bytes_to_fs(&fs, attr.ino, 5, new_data.as_bytes(), fh_2)
+            fs.write_all(attr.ino, 5, new_data.as_bytes(), fh_2)
                 .await
                 .unwrap();
             fs.flush(fh_2).await.unwrap();
@@ -377,7 +721,7 @@ async fn test_set_len() {
                 .await
                 .unwrap();
             let data = "test-42";
-            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+            fs.write_all(attr.ino, 0, data.as_bytes(), fh)
                 .await
                 .unwrap();
             fs.flush(fh).await.unwrap();
@@ -386,7 +730,7 @@ async fn test_set_len() {
             // size increase, preserve opened writer content
             let fh = fs.open(attr.ino, false, true).await.unwrap();
             let data = "37";
-            write_all_bytes_to_fs(&fs, attr.ino, 5, data.as_bytes(), fh)
+            fs.write_all(attr.ino, 5, data.as_bytes(), fh)
                 .await
                 .unwrap();
             fs.set_len(attr.ino, 10).await.unwrap();
@@ -408,7 +752,7 @@ async fn test_set_len() {
             // size decrease, preserve opened writer content
             let fh = fs.open(attr.ino, false, true).await.unwrap();
             let data = "37";
-            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+            fs.write_all(attr.ino, 0, data.as_bytes(), fh)
                 .await
                 .unwrap();
             fs.set_len(attr.ino, 4).await.unwrap();
@@ -452,7 +796,7 @@ async fn test_copy_file_range() {
                 .await
                 .unwrap();
             let data = "test-42";
-            write_all_bytes_to_fs(&fs, attr_1.ino, 0, data.as_bytes(), fh)
+            fs.write_all(attr_1.ino, 0, data.as_bytes(), fh)
                 .await
                 .unwrap();
             fs.flush(fh).await.unwrap();
@@ -482,7 +826,7 @@ async fn test_copy_file_range() {
             // offset
             let data_37 = "37";
             let fh = fs.open(attr_1.ino, false, true).await.unwrap();
-            write_all_bytes_to_fs(&fs, attr_1.ino, 7, data_37.as_bytes(), fh)
+            fs.write_all(attr_1.ino, 7, data_37.as_bytes(), fh)
                 .await
                 .unwrap();
             fs.flush(fh).await.unwrap();
@@ -530,6 +874,98 @@ async fn test_copy_file_range() {
     .await;
 }
 
+#[tokio::test]
+#[traced_test]
+async fn test_copy_file_range_in_chunks() {
+    run_test(
+        TestSetup {
+            key: "test_copy_file_range_in_chunks",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file_1 = SecretString::from_str("test-file-1").unwrap();
+            let (fh, attr_1) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file_1,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data: Vec<u8> = (0..BLOCK_SIZE * 10 + 7).map(|i| (i % 251) as u8).collect();
+            fs.write_all(attr_1.ino, 0, &data, fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let test_file_2 = SecretString::from_str("test-file-2").unwrap();
+            let (fh2, attr_2) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file_2,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let fh = fs.open(attr_1.ino, true, false).await.unwrap();
+            let chunk_size = 64;
+
+            // asking for much more than the source has copies until the end of it in one call
+            let file_range_req = CopyFileRangeReq::builder()
+                .src_ino(attr_1.ino)
+                .src_offset(0)
+                .dest_ino(attr_2.ino)
+                .dest_offset(3)
+                .src_fh(fh)
+                .dest_fh(fh2)
+                .build();
+            let len = fs
+                .copy_file_range_chunked(
+                    &file_range_req,
+                    100 * 1024 * 1024,
+                    chunk_size,
+                    &OpControl::default(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(data.len(), len);
+            fs.release(fh2).await.unwrap();
+            let fh2 = fs.open(attr_2.ino, true, false).await.unwrap();
+            let mut buf = vec![0; data.len()];
+            test_common::read_exact(&fs, attr_2.ino, 3, &mut buf, fh2).await;
+            assert_eq!(data, buf);
+            fs.release(fh2).await.unwrap();
+
+            // a range in the middle, both offsets advance with each chunk
+            let fh2 = fs.open(attr_2.ino, false, true).await.unwrap();
+            let file_range_req = CopyFileRangeReq::builder()
+                .src_ino(attr_1.ino)
+                .src_offset(50)
+                .dest_ino(attr_2.ino)
+                .dest_offset(0)
+                .src_fh(fh)
+                .dest_fh(fh2)
+                .build();
+            let len = fs
+                .copy_file_range_chunked(&file_range_req, 333, chunk_size, &OpControl::default())
+                .await
+                .unwrap();
+            assert_eq!(333, len);
+            fs.release(fh2).await.unwrap();
+            let fh2 = fs.open(attr_2.ino, true, false).await.unwrap();
+            let mut buf = vec![0; 333];
+            test_common::read_exact(&fs, attr_2.ino, 0, &mut buf, fh2).await;
+            assert_eq!(data[50..383], buf);
+            fs.release(fh2).await.unwrap();
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
 #[tokio::test]
 #[traced_test]
 #[allow(clippy::too_many_lines)]
@@ -994,9 +1430,10 @@ async fn test_exists_by_name() {
                     .await
                     .unwrap();
 
-                assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+                assert!(fs.exists_by_name(ROOT_INODE, &test_file).await.unwrap());
                 assert!(
                     !(fs.exists_by_name(ROOT_INODE, &SecretString::from_str("42").unwrap())
+                        .await
                         .unwrap())
                 );
             }
@@ -1029,9 +1466,9 @@ async fn test_remove_dir() {
                     .await
                     .unwrap();
 
-                assert!(fs.exists_by_name(ROOT_INODE, &test_dir).unwrap());
+                assert!(fs.exists_by_name(ROOT_INODE, &test_dir).await.unwrap());
                 fs.remove_dir(ROOT_INODE, &test_dir).await.unwrap();
-                assert!(!fs.exists_by_name(ROOT_INODE, &test_dir).unwrap());
+                assert!(!fs.exists_by_name(ROOT_INODE, &test_dir).await.unwrap());
                 assert_eq!(None, fs.find_by_name(ROOT_INODE, &test_dir).await.unwrap());
                 assert_eq!(
                     0,
@@ -1074,9 +1511,9 @@ async fn test_remove_file() {
                     .await
                     .unwrap();
 
-                assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+                assert!(fs.exists_by_name(ROOT_INODE, &test_file).await.unwrap());
                 fs.remove_file(ROOT_INODE, &test_file).await.unwrap();
-                assert!(!fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+                assert!(!fs.exists_by_name(ROOT_INODE, &test_file).await.unwrap());
                 assert_eq!(None, fs.find_by_name(ROOT_INODE, &test_file).await.unwrap());
                 assert_eq!(
                     0,
@@ -1134,14 +1571,17 @@ async fn test_find_by_name_exists_by_name100files() {
                 .unwrap();
 
             let test_file = SecretString::from_str("test-file-42").unwrap();
-            assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+            assert!(fs.exists_by_name(ROOT_INODE, &test_file).await.unwrap());
             assert!(fs
                 .find_by_name(ROOT_INODE, &test_file)
                 .await
                 .unwrap()
                 .is_some());
 
-            assert!(fs.exists_by_name(ROOT_INODE, &special_test_file).unwrap());
+            assert!(fs
+                .exists_by_name(ROOT_INODE, &special_test_file)
+                .await
+                .unwrap());
             assert!(fs
                 .find_by_name(ROOT_INODE, &special_test_file)
                 .await
@@ -1164,25 +1604,30 @@ async fn test_create_structure_and_root() {
         async {
             let fs = get_fs().await;
 
-            assert!(fs.exists(ROOT_INODE));
-            assert!(fs.is_dir(ROOT_INODE));
+            assert!(fs.exists(ROOT_INODE).await);
+            assert!(fs.is_dir(ROOT_INODE).await);
 
-            assert!(fs.data_dir.join(INODES_DIR).is_dir());
-            assert!(fs.data_dir.join(CONTENTS_DIR).is_dir());
-            assert!(fs.data_dir.join(SECURITY_DIR).is_dir());
-            assert!(fs
-                .data_dir
-                .join(SECURITY_DIR)
-                .join(KEY_ENC_FILENAME)
-                .is_file());
-            assert!(fs
-                .data_dir
-                .join(SECURITY_DIR)
-                .join(KEY_SALT_FILENAME)
-                .is_file());
+            assert_eq!(kind(&fs, INODES_DIR).await, Some(EntryKind::Dir));
+            assert_eq!(kind(&fs, CONTENTS_DIR).await, Some(EntryKind::Dir));
+            assert_eq!(kind(&fs, SECURITY_DIR).await, Some(EntryKind::Dir));
+            assert_eq!(
+                kind(&fs, Path::new(SECURITY_DIR).join(KEY_ENC_FILENAME)).await,
+                Some(EntryKind::File)
+            );
+            // the salt is in the key slot
+            assert_eq!(
+                kind(&fs, Path::new(SECURITY_DIR).join(KEY_SALT_FILENAME)).await,
+                None
+            );
 
-            assert!(fs.data_dir.join(INODES_DIR).join(ROOT_INODE_STR).is_file());
-            assert!(fs.data_dir.join(CONTENTS_DIR).join(ROOT_INODE_STR).is_dir());
+            assert_eq!(
+                kind(&fs, shard_path(Path::new(INODES_DIR), ROOT_INODE)).await,
+                Some(EntryKind::File)
+            );
+            assert_eq!(
+                kind(&fs, shard_path(Path::new(CONTENTS_DIR), ROOT_INODE)).await,
+                Some(EntryKind::Dir)
+            );
         },
     )
     .await;
@@ -1214,24 +1659,25 @@ async fn test_create() {
                 .unwrap();
             assert_ne!(fh, 0);
             assert_ne!(attr.ino, 0);
-            assert!(fs
-                .data_dir
-                .join(INODES_DIR)
-                .join(attr.ino.to_string())
-                .is_file());
-            assert!(fs
-                .data_dir
-                .join(CONTENTS_DIR)
-                .join(attr.ino.to_string())
-                .is_file());
-            assert!(fs
-                .data_dir
-                .join(CONTENTS_DIR)
-                .join(ROOT_INODE_STR)
-                .join(HASH_DIR)
-                .join(crypto::hash_file_name(&test_file))
-                .is_file());
-            assert!(fs.exists(attr.ino));
+            assert_eq!(
+                kind(&fs, shard_path(Path::new(INODES_DIR), attr.ino)).await,
+                Some(EntryKind::File)
+            );
+            assert_eq!(
+                kind(&fs, shard_path(Path::new(CONTENTS_DIR), attr.ino)).await,
+                Some(EntryKind::File)
+            );
+            assert_eq!(
+                kind(
+                    &fs,
+                    shard_path(Path::new(CONTENTS_DIR), ROOT_INODE)
+                        .join(HASH_DIR)
+                        .join(fs.hash_entry_name(ROOT_INODE, &test_file).await.unwrap())
+                )
+                .await,
+                Some(EntryKind::File)
+            );
+            assert!(fs.exists(attr.ino).await);
             assert_eq!(attr, fs.get_attr(attr.ino).await.unwrap());
             let mut entries: Vec<DirectoryEntryPlus> = fs
                 .read_dir_plus(ROOT_INODE)
@@ -1241,7 +1687,7 @@ async fn test_create() {
                 .collect();
             entries.sort_by(|a, b| a.name.expose_secret().cmp(&*b.name.expose_secret()));
             assert_eq!(attr, entries[1].attr);
-            assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+            assert!(fs.exists_by_name(ROOT_INODE, &test_file).await.unwrap());
             assert_eq!(
                 attr,
                 fs.find_by_name(ROOT_INODE, &test_file)
@@ -1263,26 +1709,27 @@ async fn test_create() {
                 .await
                 .unwrap();
             assert_ne!(attr.ino, 0);
-            assert!(fs
-                .data_dir
-                .join(INODES_DIR)
-                .join(attr.ino.to_string())
-                .is_file());
-            assert!(fs
-                .data_dir
-                .join(CONTENTS_DIR)
-                .join(attr.ino.to_string())
-                .is_dir());
-            assert!(fs
-                .data_dir
-                .join(CONTENTS_DIR)
-                .join(ROOT_INODE_STR)
-                .join(HASH_DIR)
-                .join(crypto::hash_file_name(&test_dir))
-                .is_file());
-            assert!(fs.exists(attr.ino));
+            assert_eq!(
+                kind(&fs, shard_path(Path::new(INODES_DIR), attr.ino)).await,
+                Some(EntryKind::File)
+            );
+            assert_eq!(
+                kind(&fs, shard_path(Path::new(CONTENTS_DIR), attr.ino)).await,
+                Some(EntryKind::Dir)
+            );
+            assert_eq!(
+                kind(
+                    &fs,
+                    shard_path(Path::new(CONTENTS_DIR), ROOT_INODE)
+                        .join(HASH_DIR)
+                        .join(fs.hash_entry_name(ROOT_INODE, &test_dir).await.unwrap())
+                )
+                .await,
+                Some(EntryKind::File)
+            );
+            assert!(fs.exists(attr.ino).await);
             assert_eq!(attr, fs.get_attr(attr.ino).await.unwrap());
-            assert!(fs.is_dir(attr.ino));
+            assert!(fs.is_dir(attr.ino).await);
             let mut entries: Vec<DirectoryEntryPlus> = fs
                 .read_dir_plus(ROOT_INODE)
                 .await
@@ -1292,7 +1739,7 @@ async fn test_create() {
             entries.sort_by(|a, b| a.name.expose_secret().cmp(&*b.name.expose_secret()));
             assert_eq!(ROOT_INODE, entries[0].attr.ino);
             assert_eq!(attr, entries[1].attr);
-            assert!(fs.exists_by_name(ROOT_INODE, &test_dir).unwrap());
+            assert!(fs.exists_by_name(ROOT_INODE, &test_dir).await.unwrap());
             assert_eq!(
                 attr,
                 fs.find_by_name(ROOT_INODE, &test_dir)
@@ -1314,26 +1761,27 @@ async fn test_create() {
                 )
                 .await
                 .unwrap();
-            assert!(fs
-                .data_dir
-                .join(INODES_DIR)
-                .join(attr.ino.to_string())
-                .is_file());
-            assert!(fs
-                .data_dir
-                .join(CONTENTS_DIR)
-                .join(attr.ino.to_string())
-                .is_dir());
-            assert!(fs
-                .data_dir
-                .join(CONTENTS_DIR)
-                .join(parent.to_string())
-                .join(HASH_DIR)
-                .join(crypto::hash_file_name(&test_dir_2))
-                .is_file());
-            assert!(fs.exists(attr.ino));
+            assert_eq!(
+                kind(&fs, shard_path(Path::new(INODES_DIR), attr.ino)).await,
+                Some(EntryKind::File)
+            );
+            assert_eq!(
+                kind(&fs, shard_path(Path::new(CONTENTS_DIR), attr.ino)).await,
+                Some(EntryKind::Dir)
+            );
+            assert_eq!(
+                kind(
+                    &fs,
+                    shard_path(Path::new(CONTENTS_DIR), parent)
+                        .join(HASH_DIR)
+                        .join(fs.hash_entry_name(parent, &test_dir_2).await.unwrap())
+                )
+                .await,
+                Some(EntryKind::File)
+            );
+            assert!(fs.exists(attr.ino).await);
             assert_eq!(attr, fs.get_attr(attr.ino).await.unwrap());
-            assert!(fs.is_dir(attr.ino));
+            assert!(fs.is_dir(attr.ino).await);
             let mut entries: Vec<DirectoryEntryPlus> = fs
                 .read_dir_plus(parent)
                 .await
@@ -1343,7 +1791,7 @@ async fn test_create() {
             entries.sort_by(|a, b| a.name.expose_secret().cmp(&*b.name.expose_secret()));
             assert_eq!(attr, entries[2].attr);
             assert_eq!(parent, entries[0].attr.ino);
-            assert!(fs.exists_by_name(parent, &test_dir_2).unwrap());
+            assert!(fs.exists_by_name(parent, &test_dir_2).await.unwrap());
             assert_eq!(
                 attr,
                 fs.find_by_name(parent, &test_dir_2).await.unwrap().unwrap()
@@ -1408,14 +1856,14 @@ async fn test_rename() {
             fs.rename(ROOT_INODE, &file_1, new_parent, &file_1_new)
                 .await
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &file_1_new).unwrap());
+            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).await.unwrap());
+            assert!(fs.exists_by_name(new_parent, &file_1_new).await.unwrap());
             let new_attr = fs
                 .find_by_name(new_parent, &file_1_new)
                 .await
                 .unwrap()
                 .unwrap();
-            assert!(fs.is_file(new_attr.ino));
+            assert!(fs.is_file(new_attr.ino).await);
             assert_eq!(new_attr.ino, attr.ino);
             assert_eq!(new_attr.kind, attr.kind);
             assert_eq!(
@@ -1454,14 +1902,14 @@ async fn test_rename() {
             fs.rename(ROOT_INODE, &dir_1, new_parent, &dir_1_new)
                 .await
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &dir_1_new).unwrap());
+            assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).await.unwrap());
+            assert!(fs.exists_by_name(new_parent, &dir_1_new).await.unwrap());
             let new_attr = fs
                 .find_by_name(new_parent, &dir_1_new)
                 .await
                 .unwrap()
                 .unwrap();
-            assert!(fs.is_dir(new_attr.ino));
+            assert!(fs.is_dir(new_attr.ino).await);
             assert_eq!(new_attr.ino, attr.ino);
             assert_eq!(new_attr.kind, attr.kind);
             assert_eq!(
@@ -1543,10 +1991,10 @@ async fn test_rename() {
             fs.rename(ROOT_INODE, &file_1, new_parent, &file_2)
                 .await
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &file_2).unwrap());
+            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).await.unwrap());
+            assert!(fs.exists_by_name(new_parent, &file_2).await.unwrap());
             let new_attr = fs.find_by_name(new_parent, &file_2).await.unwrap().unwrap();
-            assert!(fs.is_file(new_attr.ino));
+            assert!(fs.is_file(new_attr.ino).await);
             assert_eq!(new_attr.ino, attr.ino);
             assert_eq!(new_attr.kind, attr.kind);
             assert_eq!(
@@ -1595,10 +2043,10 @@ async fn test_rename() {
             fs.rename(ROOT_INODE, &dir_1, new_parent, &dir_2)
                 .await
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &dir_2).unwrap());
+            assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).await.unwrap());
+            assert!(fs.exists_by_name(new_parent, &dir_2).await.unwrap());
             let new_attr = fs.find_by_name(new_parent, &dir_2).await.unwrap().unwrap();
-            assert!(fs.is_dir(new_attr.ino));
+            assert!(fs.is_dir(new_attr.ino).await);
             assert_eq!(new_attr.ino, attr.ino);
             assert_eq!(new_attr.kind, attr.kind);
             assert_eq!(
@@ -1688,10 +2136,10 @@ async fn test_rename() {
             fs.rename(ROOT_INODE, &file_1, new_parent, &file_2)
                 .await
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &file_2).unwrap());
+            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).await.unwrap());
+            assert!(fs.exists_by_name(new_parent, &file_2).await.unwrap());
             let new_attr = fs.find_by_name(new_parent, &file_2).await.unwrap().unwrap();
-            assert!(fs.is_file(new_attr.ino));
+            assert!(fs.is_file(new_attr.ino).await);
             assert_eq!(new_attr.ino, attr.ino);
             assert_eq!(new_attr.kind, attr.kind);
             assert_eq!(
@@ -1738,10 +2186,10 @@ async fn test_rename() {
             fs.rename(ROOT_INODE, &dir_1, new_parent, &dir_2)
                 .await
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &dir_2).unwrap());
+            assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).await.unwrap());
+            assert!(fs.exists_by_name(new_parent, &dir_2).await.unwrap());
             let new_attr = fs.find_by_name(new_parent, &dir_2).await.unwrap().unwrap();
-            assert!(fs.is_dir(new_attr.ino));
+            assert!(fs.is_dir(new_attr.ino).await);
             assert_eq!(new_attr.ino, attr.ino);
             assert_eq!(new_attr.kind, attr.kind);
             assert_eq!(
@@ -1820,10 +2268,10 @@ async fn test_rename() {
             fs.rename(ROOT_INODE, &file_1, new_parent, &file_1)
                 .await
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &file_1).unwrap());
+            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).await.unwrap());
+            assert!(fs.exists_by_name(new_parent, &file_1).await.unwrap());
             let new_attr = fs.find_by_name(new_parent, &file_1).await.unwrap().unwrap();
-            assert!(fs.is_file(new_attr.ino));
+            assert!(fs.is_file(new_attr.ino).await);
             assert_eq!(new_attr.ino, attr.ino);
             assert_eq!(new_attr.kind, attr.kind);
             assert_eq!(
@@ -1870,10 +2318,10 @@ async fn test_rename() {
             fs.rename(ROOT_INODE, &dir_1, new_parent, &dir_1)
                 .await
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &dir_1).unwrap());
+            assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).await.unwrap());
+            assert!(fs.exists_by_name(new_parent, &dir_1).await.unwrap());
             let new_attr = fs.find_by_name(new_parent, &dir_1).await.unwrap().unwrap();
-            assert!(fs.is_dir(new_attr.ino));
+            assert!(fs.is_dir(new_attr.ino).await);
             assert_eq!(new_attr.ino, attr.ino);
             assert_eq!(new_attr.kind, attr.kind);
             assert_eq!(
@@ -1952,10 +2400,10 @@ async fn test_rename() {
             fs.rename(ROOT_INODE, &file_1, new_parent, &dir_1)
                 .await
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &dir_1).unwrap());
+            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).await.unwrap());
+            assert!(fs.exists_by_name(new_parent, &dir_1).await.unwrap());
             let new_attr = fs.find_by_name(new_parent, &dir_1).await.unwrap().unwrap();
-            assert!(fs.is_file(new_attr.ino));
+            assert!(fs.is_file(new_attr.ino).await);
             assert_eq!(new_attr.ino, attr.ino);
             assert_eq!(new_attr.kind, attr.kind);
             assert_eq!(
@@ -2003,10 +2451,10 @@ async fn test_rename() {
             fs.rename(ROOT_INODE, &dir_3, new_parent, &file_1)
                 .await
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &dir_3).unwrap());
-            assert!(fs.exists_by_name(new_parent, &file_1).unwrap());
+            assert!(!fs.exists_by_name(ROOT_INODE, &dir_3).await.unwrap());
+            assert!(fs.exists_by_name(new_parent, &file_1).await.unwrap());
             let new_attr = fs.find_by_name(new_parent, &file_1).await.unwrap().unwrap();
-            assert!(fs.is_dir(new_attr.ino));
+            assert!(fs.is_dir(new_attr.ino).await);
             assert_eq!(new_attr.ino, attr.ino);
             assert_eq!(new_attr.kind, attr.kind);
             assert_eq!(
@@ -2078,12 +2526,12 @@ async fn test_rename() {
                 fs.rename(ROOT_INODE, &dir_3, new_parent, &name_2).await,
                 Err(FsError::NotEmpty)
             ));
-            assert!(fs.exists_by_name(ROOT_INODE, &dir_3).unwrap());
-            assert!(fs.exists_by_name(new_parent, &name_2).unwrap());
+            assert!(fs.exists_by_name(ROOT_INODE, &dir_3).await.unwrap());
+            assert!(fs.exists_by_name(new_parent, &name_2).await.unwrap());
             let attr_3 = fs.find_by_name(ROOT_INODE, &dir_3).await.unwrap().unwrap();
-            assert!(fs.is_dir(attr_3.ino));
+            assert!(fs.is_dir(attr_3.ino).await);
             let attr_2 = fs.find_by_name(new_parent, &name_2).await.unwrap().unwrap();
-            assert!(fs.is_dir(attr_2.ino));
+            assert!(fs.is_dir(attr_2.ino).await);
             let new_attr = fs.find_by_name(new_parent, &dir_3).await.unwrap().unwrap();
             assert_eq!(new_attr.ino, attr.ino);
             assert_eq!(new_attr.kind, attr.kind);
@@ -2157,9 +2605,9 @@ async fn test_rename() {
             fs.rename(ROOT_INODE, &file_3, new_parent, &file_3)
                 .await
                 .unwrap();
-            assert!(fs.exists_by_name(new_parent, &file_3).unwrap());
+            assert!(fs.exists_by_name(new_parent, &file_3).await.unwrap());
             let new_attr = fs.find_by_name(new_parent, &file_3).await.unwrap().unwrap();
-            assert!(fs.is_file(new_attr.ino));
+            assert!(fs.is_file(new_attr.ino).await);
             assert_eq!(new_attr.ino, attr.ino);
             assert_eq!(new_attr.kind, attr.kind);
             assert_eq!(
@@ -2188,9 +2636,9 @@ async fn test_rename() {
             fs.rename(ROOT_INODE, &dir_5, new_parent, &dir_5)
                 .await
                 .unwrap();
-            assert!(fs.exists_by_name(new_parent, &dir_5).unwrap());
+            assert!(fs.exists_by_name(new_parent, &dir_5).await.unwrap());
             let new_attr = fs.find_by_name(new_parent, &dir_5).await.unwrap().unwrap();
-            assert!(fs.is_dir(new_attr.ino));
+            assert!(fs.is_dir(new_attr.ino).await);
             assert_eq!(new_attr.ino, attr.ino);
             assert_eq!(new_attr.kind, attr.kind);
             assert_eq!(
@@ -2258,7 +2706,7 @@ async fn test_rename() {
             ));
             assert!(matches!(
                 fs.rename(ROOT_INODE, &invalid, ROOT_INODE, &invalid).await,
-                Err(FsError::NotFound(_))
+                Err(FsError::NotFound { .. })
             ));
             assert!(matches!(
                 fs.rename(ROOT_INODE, &existing_file, 0, &invalid).await,
@@ -2276,55 +2724,162 @@ async fn test_rename() {
 
 #[tokio::test]
 #[traced_test]
-async fn test_open() {
+async fn test_create_and_open_with_flags() {
     run_test(
         TestSetup {
-            key: "test_open",
+            key: "test_create_and_open_with_flags",
             read_only: false,
         },
         async {
             let fs = get_fs().await;
 
             let test_file = SecretString::from_str("test-file").unwrap();
-            let (_fh, attr) = fs
-                .create(
+            let exclusive = CreateFlags {
+                write: true,
+                exclusive: true,
+                ..Default::default()
+            };
+            let (fh, attr) = fs
+                .create_with_flags(
                     ROOT_INODE,
                     &test_file,
                     create_attr(FileType::RegularFile),
-                    false,
-                    false,
+                    exclusive,
                 )
                 .await
                 .unwrap();
-            // single read
-            let fh = fs.open(attr.ino, true, false).await.unwrap();
-            assert_ne!(fh, 0);
-            // multiple read
-            let fh_2 = fs.open(attr.ino, true, false).await.unwrap();
-            assert_ne!(fh_2, 0);
-            // write and read
-            let _ = fs.open(attr.ino, false, true).await.unwrap();
-            // ensure cannot open multiple write
+            fs.write_all(attr.ino, 0, b"test-42", fh).await.unwrap();
+            fs.release(fh).await.unwrap();
             assert!(matches!(
-                fs.open(attr.ino, false, true).await,
-                Err(FsError::AlreadyOpenForWrite)
-            ));
-        },
+                fs.create_with_flags(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    exclusive
+                )
+                .await,
+                Err(FsError::AlreadyExists)
+            ));
+
+            // not exclusive opens the existing file, keeping the content
+            let (fh, attr_existing) = fs
+                .create_with_flags(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    CreateFlags {
+                        read: true,
+                        ..Default::default()
+                    },
+                )
+                .await
+                .unwrap();
+            assert_eq!(attr.ino, attr_existing.ino);
+            assert_eq!(7, attr_existing.size);
+            let mut buf = [0; 4];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+            assert_eq!(b"test", &buf);
+
+            // a different kind is not opened
+            assert!(matches!(
+                fs.create_with_flags(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::Directory),
+                    CreateFlags::default()
+                )
+                .await,
+                Err(FsError::AlreadyExists)
+            ));
+
+            // truncate the existing file, the opened reader sees it
+            let (fh_write, attr_existing) = fs
+                .create_with_flags(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    CreateFlags {
+                        write: true,
+                        truncate: true,
+                        ..Default::default()
+                    },
+                )
+                .await
+                .unwrap();
+            assert_eq!(0, attr_existing.size);
+            assert_eq!(0, fs.read(attr.ino, 4, &mut buf, fh).await.unwrap());
+            fs.write_all(attr.ino, 0, b"37", fh_write).await.unwrap();
+            fs.release(fh_write).await.unwrap();
+            assert_eq!("37", test_common::read_to_string(attr.ino, &fs).await);
+
+            // truncate on open
+            assert!(matches!(
+                fs.open_with_flags(
+                    attr.ino,
+                    OpenFlags {
+                        read: true,
+                        truncate: true,
+                        ..Default::default()
+                    }
+                )
+                .await,
+                Err(FsError::InvalidInput { .. })
+            ));
+            let fh_write = fs
+                .open_with_flags(
+                    attr.ino,
+                    OpenFlags {
+                        write: true,
+                        truncate: true,
+                        ..Default::default()
+                    },
+                )
+                .await
+                .unwrap();
+            assert_eq!(0, fs.get_attr(attr.ino).await.unwrap().size);
+            assert_eq!(0, fs.read(attr.ino, 0, &mut buf, fh).await.unwrap());
+            fs.release(fh_write).await.unwrap();
+            fs.release(fh).await.unwrap();
+        },
     )
     .await;
 }
 
-// #[tokio::test]
-// #[traced_test]
-#[allow(clippy::too_many_lines)]
-async fn _test_sample() {
+#[tokio::test]
+#[traced_test]
+async fn test_open() {
     run_test(
         TestSetup {
-            key: "test_sample",
+            key: "test_open",
             read_only: false,
         },
         async {
-            let _ = get_fs().await;
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (_fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            // single read
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            assert_ne!(fh, 0);
+            // multiple read
+            let fh_2 = fs.open(attr.ino, true, false).await.unwrap();
+            assert_ne!(fh_2, 0);
+            // write and read
+            let fh_3 = fs.open(attr.ino, false, true).await.unwrap();
+            assert_ne!(fh_3, 0);
+            // multiple write
+            let fh_4 = fs.open(attr.ino, false, true).await.unwrap();
+            assert_ne!(fh_4, 0);
+            assert_ne!(fh_3, fh_4);
         },
     )
     .await;
@@ -2332,28 +2887,63 @@ async fn _test_sample() {
 
 #[tokio::test]
 #[traced_test]
-#[allow(clippy::too_many_lines)]
-async fn test_read_only_create() {
+async fn test_multiple_writers() {
     run_test(
         TestSetup {
-            key: "test_read_only_create",
-            read_only: true,
+            key: "test_multiple_writers",
+            read_only: false,
         },
         async {
             let fs = get_fs().await;
 
-            // Check creating a file in a read only fs
             let test_file = SecretString::from_str("test-file").unwrap();
-            let create_file_result = fs
+            let (fh, attr) = fs
                 .create(
                     ROOT_INODE,
                     &test_file,
                     create_attr(FileType::RegularFile),
-                    true,
                     false,
+                    true,
                 )
-                .await;
-            assert!(matches!(create_file_result, Err(FsError::ReadOnly)));
+                .await
+                .unwrap();
+            // pending in the first writer when the second one opens
+            fs.write_all(attr.ino, 0, b"aaaa", fh).await.unwrap();
+            let fh2 = fs.open(attr.ino, false, true).await.unwrap();
+            let fh_read = fs.open(attr.ino, true, false).await.unwrap();
+
+            // interleave writes to disjoint ranges, in the same block and in different blocks
+            let block = BLOCK_SIZE as u64;
+            for i in 0..5 {
+                fs.write(attr.ino, 4 + i * 2, b"b", fh).await.unwrap();
+                fs.write(attr.ino, 5 + i * 2, b"c", fh2).await.unwrap();
+                fs.write(attr.ino, block + i, b"d", fh).await.unwrap();
+                fs.write(attr.ino, block * 2 + i, b"e", fh2).await.unwrap();
+            }
+            let size = block * 2 + 5;
+            assert_eq!(size, fs.get_attr(attr.ino).await.unwrap().size);
+
+            // the reader sees the writes of both
+            let mut buf = [0; 14];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh_read).await;
+            assert_eq!(b"aaaabcbcbcbcbc", &buf);
+
+            // the one with the smaller size doesn't shrink the file on release
+            fs.release(fh).await.unwrap();
+            fs.release(fh2).await.unwrap();
+            fs.release(fh_read).await.unwrap();
+            assert_eq!(size, fs.get_attr(attr.ino).await.unwrap().size);
+
+            let fh_read = fs.open(attr.ino, true, false).await.unwrap();
+            #[allow(clippy::cast_possible_truncation)]
+            let mut buf = vec![0; size as usize];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh_read).await;
+            let mut expected = vec![0; buf.len()];
+            expected[..14].copy_from_slice(b"aaaabcbcbcbcbc");
+            expected[BLOCK_SIZE..BLOCK_SIZE + 5].copy_from_slice(b"ddddd");
+            expected[BLOCK_SIZE * 2..].copy_from_slice(b"eeeee");
+            assert_eq!(expected, buf);
+            fs.release(fh_read).await.unwrap();
         },
     )
     .await;
@@ -2361,97 +2951,2275 @@ async fn test_read_only_create() {
 
 #[tokio::test]
 #[traced_test]
-#[allow(clippy::too_many_lines)]
-async fn test_read_only_write() {
+async fn test_multiple_writers_write_cost() {
     run_test(
         TestSetup {
-            key: "read_only_test_write",
+            key: "test_multiple_writers_write_cost",
             read_only: false,
         },
         async {
-            let fs_rw = get_fs().await;
-            let data_dir = fs_rw.data_dir.clone();
-            let cipher = Cipher::ChaCha20Poly1305;
-            let file1 = SecretString::from_str("file1").unwrap();
-            let file_dest = SecretString::from_str("file_dest").unwrap();
-            let dir1 = SecretString::from_str("dir1").unwrap();
-            let data = "Hello, world!";
+            let (fs, storage) = open_counting(FsOptions::default()).await;
+            let ino = create_file(&fs, ROOT_INODE, "test-file", &[42; BLOCK_SIZE * 8]).await;
+            let mut fhs = vec![];
+            for _ in 0..2 {
+                fhs.push(fs.open(ino, false, true).await.unwrap());
+            }
 
-            let (fh, attr) = fs_rw
+            // each writer in its own block, returns the files opened by the write
+            let block = BLOCK_SIZE as u64;
+            let write = |i: u64, fh: u64| {
+                let fs = fs.clone();
+                let storage = storage.clone();
+                async move {
+                    let before = storage.opens();
+                    fs.write(ino, block * i + 1, b"a", fh).await.unwrap();
+                    storage.opens() - before
+                }
+            };
+            // the writers are kept, by the next write of the same one and after the others wrote
+            assert_eq!(0, write(0, fhs[0]).await);
+            assert_eq!(0, write(0, fhs[0]).await);
+            assert_eq!(0, write(1, fhs[1]).await);
+
+            for _ in 0..6 {
+                fhs.push(fs.open(ino, false, true).await.unwrap());
+            }
+            for (i, fh) in fhs.iter().enumerate().skip(2) {
+                assert_eq!(0, write(i as u64, *fh).await);
+            }
+            assert_eq!(0, write(0, fhs[0]).await);
+
+            for fh in fhs {
+                fs.release(fh).await.unwrap();
+            }
+            let fh = fs.open(ino, true, false).await.unwrap();
+            for i in 0..8_u64 {
+                let mut buf = [0; 3];
+                test_common::read_exact(&fs, ino, block * i, &mut buf, fh).await;
+                assert_eq!(&[42, b'a', 42], &buf);
+            }
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_fsync() {
+    run_test(
+        TestSetup {
+            key: "test_fsync",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
                 .create(
                     ROOT_INODE,
-                    &file1,
+                    &test_file,
                     create_attr(FileType::RegularFile),
-                    true,
+                    false,
                     true,
                 )
                 .await
-                .expect("read_only_test_create: Error creating file.");
-            let (fh_dest, attr_dest) = fs_rw
+                .unwrap();
+            // not a multiple of block size, so the last block is only in the writer buffer
+            let data: Vec<u8> = (0..BLOCK_SIZE * 2 + 42).map(|i| (i % 251) as u8).collect();
+            fs.write_all(attr.ino, 0, &data, fh).await.unwrap();
+            fs.fsync(fh, false).await.unwrap();
+            fs.fsync(fh, true).await.unwrap();
+
+            // read handles are a no-op, unknown handles are an error
+            let fh_read = fs.open(attr.ino, true, false).await.unwrap();
+            fs.fsync(fh_read, false).await.unwrap();
+            assert!(matches!(
+                fs.fsync(42_000, false).await,
+                Err(FsError::InvalidFileHandle)
+            ));
+
+            // simulate a crash, read what's on disk without releasing the handle
+            assert_eq!(
+                data.len() as u64,
+                fs.get_inode_from_storage(attr.ino).await.unwrap().size
+            );
+            let mut reader = fs
+                .create_read(fs.open_contents(attr.ino).await.unwrap())
+                .await
+                .unwrap();
+            let mut buf = vec![];
+            reader.read_to_end(&mut buf).unwrap();
+            assert_eq!(data, buf);
+
+            fs.release(fh_read).await.unwrap();
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_link() {
+    run_test(
+        TestSetup {
+            key: "test_link",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
                 .create(
                     ROOT_INODE,
-                    &file_dest,
+                    &test_file,
                     create_attr(FileType::RegularFile),
-                    true,
+                    false,
                     true,
                 )
                 .await
-                .expect("read_only_test_create: Error creating file.");
-            let (_, _) = fs_rw
+                .unwrap();
+            assert_eq!(1, attr.nlink);
+            fs.write_all(attr.ino, 0, b"test-42", fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            let test_dir = SecretString::from_str("test-dir").unwrap();
+            let (_, dir_attr) = fs
                 .create(
                     ROOT_INODE,
-                    &dir1,
+                    &test_dir,
                     create_attr(FileType::Directory),
                     false,
-                    true,
+                    false,
                 )
                 .await
-                .expect("read_only_test_create: Error creating dir.");
+                .unwrap();
 
-            // Create a succesful write on the file
-            crate::encryptedfs::write_all_string_to_fs(&fs_rw, attr.ino, 0, data, fh)
+            // link in another directory
+            let test_link = SecretString::from_str("test-link").unwrap();
+            let link_attr = fs.link(attr.ino, dir_attr.ino, &test_link).await.unwrap();
+            assert_eq!(attr.ino, link_attr.ino);
+            assert_eq!(2, link_attr.nlink);
+            assert_eq!(
+                attr.ino,
+                fs.find_by_name(dir_attr.ino, &test_link)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+            // both names share the attr
+            let entry = fs
+                .read_dir_plus(dir_attr.ino)
                 .await
+                .unwrap()
+                .map(Result::unwrap)
+                .find(|entry| entry.name.expose_secret() == test_link.expose_secret())
                 .unwrap();
-            fs_rw.flush(fh).await.unwrap();
-            fs_rw.release(fh).await.unwrap();
-            drop(fs_rw);
-            let fs_ro = EncryptedFs::new(data_dir, Box::new(PasswordProviderImpl {}), cipher, true)
+            assert_eq!(2, entry.attr.nlink);
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap(), entry.attr);
+
+            // writing through one name is visible through the other
+            let ino = fs
+                .find_by_name(dir_attr.ino, &test_link)
                 .await
-                .expect("test_read_only_write: Error creating rw fs.");
-            let fh = fs_ro
-                .open(attr.ino, true, false)
+                .unwrap()
+                .unwrap()
+                .ino;
+            let fh = fs.open(ino, false, true).await.unwrap();
+            fs.write_all(ino, 5, b"37", fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(
+                "test-37",
+                test_common::read_to_string(
+                    fs.find_by_name(ROOT_INODE, &test_file)
+                        .await
+                        .unwrap()
+                        .unwrap()
+                        .ino,
+                    &fs
+                )
                 .await
-                .expect("read_only_test_create: Error opening file in ro.");
+            );
 
-            // Test a succesful reading the file in rw mode
-            let mut buf = vec![0; data.len()];
-            fs_ro.read(attr.ino, 0, &mut buf, fh).await.unwrap();
-            assert_eq!(data, String::from_utf8(buf).unwrap());
+            // cannot link over an existing name or link a directory
+            assert!(matches!(
+                fs.link(attr.ino, ROOT_INODE, &test_file).await,
+                Err(FsError::AlreadyExists)
+            ));
+            assert!(matches!(
+                fs.link(dir_attr.ino, ROOT_INODE, &test_link).await,
+                Err(FsError::InvalidInodeType)
+            ));
 
-            // Test creating a file
-            let file2 = SecretString::from_str("file2").unwrap();
-            let create_file_result = fs_ro
+            // renaming a link over another link of the same inode is a no-op
+            fs.rename(dir_attr.ino, &test_link, ROOT_INODE, &test_file)
+                .await
+                .unwrap();
+            assert!(fs.exists_by_name(dir_attr.ino, &test_link).await.unwrap());
+            assert!(fs.exists_by_name(ROOT_INODE, &test_file).await.unwrap());
+            assert_eq!(2, fs.get_attr(attr.ino).await.unwrap().nlink);
+
+            // removing one name keeps the contents
+            fs.remove_file(ROOT_INODE, &test_file).await.unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &test_file).await.unwrap());
+            assert!(fs.exists(attr.ino).await);
+            assert!(fs.is_file(attr.ino).await);
+            assert_eq!(1, fs.get_attr(attr.ino).await.unwrap().nlink);
+            assert_eq!("test-37", test_common::read_to_string(attr.ino, &fs).await);
+
+            // removing the last name removes the contents
+            fs.remove_file(dir_attr.ino, &test_link).await.unwrap();
+            assert!(!fs.exists(attr.ino).await);
+            assert!(!fs.is_file(attr.ino).await);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_set_attr_round_trip() {
+    run_test(
+        TestSetup {
+            key: "test_set_attr_round_trip",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (_, attr) = fs
                 .create(
                     ROOT_INODE,
-                    &file2,
+                    &test_file,
                     create_attr(FileType::RegularFile),
-                    true,
-                    true,
+                    false,
+                    false,
                 )
-                .await;
-            assert!(matches!(create_file_result, Err(FsError::ReadOnly)));
-            // Test renaming the file
-            let new_file = SecretString::from_str("file1").unwrap();
-            let rename_result = fs_ro
-                .rename(ROOT_INODE, &file1, ROOT_INODE, &new_file)
-                .await;
-            assert!(matches!(rename_result, Err(FsError::ReadOnly)));
-            // Test removing a file
-            let remove_file_result = fs_ro.remove_file(ROOT_INODE, &file1).await;
-            assert!(matches!(remove_file_result, Err(FsError::ReadOnly)));
-            // Test copy file range
-            let file_range_req = CopyFileRangeReq::builder()
-                .src_ino(attr.ino)
+                .await
+                .unwrap();
+            let ino = attr.ino;
+            // times are merged keeping the newest, so use one in the future
+            let time = SystemTime::now() + Duration::from_secs(3600);
+
+            type Check = fn(&FileAttr) -> bool;
+            let cases: Vec<(SetFileAttr, Check)> = vec![
+                (SetFileAttr::default().with_size(42), |a| a.size == 42),
+                (SetFileAttr::default().with_atime(time), |a| {
+                    a.atime > SystemTime::now()
+                }),
+                (SetFileAttr::default().with_mtime(time), |a| {
+                    a.mtime > SystemTime::now()
+                }),
+                (SetFileAttr::default().with_ctime(time), |a| {
+                    a.ctime > SystemTime::now()
+                }),
+                (SetFileAttr::default().with_crtime(time), |a| {
+                    a.crtime > SystemTime::now()
+                }),
+                (SetFileAttr::default().with_perm(0o600), |a| a.perm == 0o600),
+                (SetFileAttr::default().with_uid(42), |a| a.uid == 42),
+                (SetFileAttr::default().with_gid(43), |a| a.gid == 43),
+                (SetFileAttr::default().with_rdev(44), |a| a.rdev == 44),
+                (SetFileAttr::default().with_flags(45), |a| a.flags == 45),
+            ];
+            for (i, (set_attr, check)) in cases.into_iter().enumerate() {
+                fs.set_attr(ino, set_attr).await.unwrap();
+                // read it back from storage
+                fs.attr_cache.get().await.unwrap().write().await.clear();
+                let attr = fs.get_attr(ino).await.unwrap();
+                assert!(check(&attr), "field {i} not persisted: {attr:?}");
+            }
+
+            // fields set before are kept
+            let attr = fs.get_attr(ino).await.unwrap();
+            assert_eq!(42, attr.size);
+            assert_eq!(0o600, attr.perm);
+            assert_eq!(42, attr.uid);
+            assert_eq!(43, attr.gid);
+            assert_eq!(44, attr.rdev);
+            assert_eq!(45, attr.flags);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_set_attr_size_truncates() {
+    run_test(
+        TestSetup {
+            key: "test_set_attr_size_truncates",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data = vec![42; BLOCK_SIZE * 2];
+            fs.write_all(attr.ino, 0, &data, fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            // the contents are truncated, not only the size recorded
+            fs.set_attr(attr.ino, SetFileAttr::default().with_size(10))
+                .await
+                .unwrap();
+            assert_eq!(10, fs.get_attr(attr.ino).await.unwrap().size);
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            assert_eq!(
+                vec![42; 10],
+                fs.read_all(attr.ino, fh, u64::MAX).await.unwrap()
+            );
+            fs.release(fh).await.unwrap();
+
+            // and extended with zeros
+            fs.set_attr(attr.ino, SetFileAttr::default().with_size(20))
+                .await
+                .unwrap();
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut expected = vec![42; 10];
+            expected.resize(20, 0);
+            assert_eq!(expected, fs.read_all(attr.ino, fh, u64::MAX).await.unwrap());
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_set_permissions_owner_and_times() {
+    run_test(
+        TestSetup {
+            key: "test_set_permissions_owner_and_times",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let ino = attr.ino;
+
+            fs.set_permissions(ino, 0o4755).await.unwrap();
+            let changed = fs.get_attr(ino).await.unwrap();
+            assert_eq!(0o4755, changed.perm);
+            assert!(changed.ctime >= attr.ctime);
+            assert!(matches!(
+                fs.set_permissions(ino, 0o10644).await,
+                Err(FsError::InvalidInput { .. })
+            ));
+
+            fs.set_owner(ino, Some(42), None).await.unwrap();
+            fs.set_owner(ino, None, Some(43)).await.unwrap();
+            let changed = fs.get_attr(ino).await.unwrap();
+            assert_eq!((42, 43), (changed.uid, changed.gid));
+            assert!(matches!(
+                fs.set_owner(ino, Some(u32::MAX), None).await,
+                Err(FsError::InvalidInput { .. })
+            ));
+
+            // unlike set_attr they can go back
+            let past = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+            fs.set_times(ino, Some(past), None).await.unwrap();
+            fs.set_times(ino, None, Some(past)).await.unwrap();
+            fs.attr_cache.get().await.unwrap().write().await.clear();
+            let changed = fs.get_attr(ino).await.unwrap();
+            assert_eq!((past, past), (changed.atime, changed.mtime));
+            assert!(changed.ctime > past);
+            assert!(matches!(
+                fs.set_times(
+                    ino,
+                    Some(SystemTime::UNIX_EPOCH - Duration::from_secs(1)),
+                    None
+                )
+                .await,
+                Err(FsError::InvalidInput { .. })
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_context_set_permissions_owner_and_times() {
+    let fs =
+        EncryptedFs::new_in_memory(Box::new(PasswordProviderImpl {}), Cipher::ChaCha20Poly1305)
+            .await
+            .unwrap();
+    let owner_fs = fs.with_context(RequestContext::new(1000, 1000, 1));
+    let writer_fs = fs.with_context(RequestContext::new(1001, 1000, 2));
+    let root_fs = fs.with_context(RequestContext::new(0, 0, 3));
+    let (fh, file) = owner_fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("file").unwrap(),
+            CreateFileAttr {
+                perm: 0o664,
+                ..create_attr(FileType::RegularFile)
+            },
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+
+    // like `touch -d` with an older date, which set_attr ignores
+    let past = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+    owner_fs
+        .set_times(file.ino, None, Some(TimeOrNow::SpecificTime(past)))
+        .await
+        .unwrap();
+    assert_eq!(past, fs.get_attr(file.ino).await.unwrap().mtime);
+    assert!(matches!(
+        writer_fs
+            .set_times(file.ino, None, Some(TimeOrNow::SpecificTime(past)))
+            .await,
+        Err(FsError::NotPermitted)
+    ));
+    writer_fs
+        .set_times(file.ino, Some(TimeOrNow::Now), Some(TimeOrNow::Now))
+        .await
+        .unwrap();
+    assert!(fs.get_attr(file.ino).await.unwrap().mtime > past);
+
+    // only the owner changes the mode, only root the owner, which clears SUID
+    assert!(matches!(
+        writer_fs.set_permissions(file.ino, 0o4775).await,
+        Err(FsError::NotPermitted)
+    ));
+    owner_fs.set_permissions(file.ino, 0o4775).await.unwrap();
+    assert!(matches!(
+        owner_fs.set_owner(file.ino, Some(1001), None).await,
+        Err(FsError::NotPermitted)
+    ));
+    root_fs.set_owner(file.ino, Some(1001), None).await.unwrap();
+    let attr = fs.get_attr(file.ino).await.unwrap();
+    assert_eq!((1001, 0o775), (attr.uid, attr.perm & 0o7777));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_attr_cache_capacity() {
+    run_test(
+        TestSetup {
+            key: "test_attr_cache_capacity",
+            read_only: false,
+        },
+        async {
+            let storage = take_fs().await.storage.clone();
+            let fs = open_storage_with(
+                storage,
+                FsOptions::builder()
+                    .attr_cache_capacity(NonZeroUsize::new(2).unwrap())
+                    .build(),
+            )
+            .await;
+
+            let mut inodes = vec![];
+            for i in 0..3 {
+                let name = SecretString::from_str(&format!("file-{i}")).unwrap();
+                let (_, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &name,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        false,
+                    )
+                    .await
+                    .unwrap();
+                inodes.push(attr.ino);
+            }
+
+            for ino in &inodes {
+                fs.get_attr(*ino).await.unwrap();
+            }
+            // only the last 2 are kept, the first one was evicted
+            {
+                let cache = fs.attr_cache.get().await.unwrap();
+                let cache = cache.read().await;
+                assert_eq!(2, cache.len());
+                assert!(!cache.contains(&inodes[0]));
+                assert!(cache.contains(&inodes[1]));
+                assert!(cache.contains(&inodes[2]));
+            }
+
+            // evicted entry is loaded back from storage and evicts the least recently used one
+            assert_eq!(inodes[0], fs.get_attr(inodes[0]).await.unwrap().ino);
+            let cache = fs.attr_cache.get().await.unwrap();
+            let cache = cache.read().await;
+            assert_eq!(2, cache.len());
+            assert!(cache.contains(&inodes[0]));
+            assert!(!cache.contains(&inodes[1]));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_statfs() {
+    run_test(
+        TestSetup {
+            key: "test_statfs",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let stats = fs.statfs().await.unwrap();
+            // only the root
+            assert_eq!(1, stats.used_inodes);
+            assert!(stats.block_size > 0);
+            assert!(stats.total_bytes > 0);
+            assert!(stats.free_bytes <= stats.total_bytes);
+            assert!(stats.available_bytes <= stats.free_bytes);
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            fs.create(
+                ROOT_INODE,
+                &test_file,
+                create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+            // inodes count is cached
+            assert_eq!(1, fs.statfs().await.unwrap().used_inodes);
+            fs.inodes_count.clear().await;
+            assert_eq!(2, fs.statfs().await.unwrap().used_inodes);
+        },
+    )
+    .await;
+}
+
+/// Move everything back to the flat layout used before the version file.
+fn flatten_layout(data_dir: &Path) {
+    for dir in [INODES_DIR, CONTENTS_DIR] {
+        let dir = data_dir.join(dir);
+        for shard in std::fs::read_dir(&dir).unwrap() {
+            let shard = shard.unwrap().path();
+            for shard2 in std::fs::read_dir(&shard).unwrap() {
+                let shard2 = shard2.unwrap().path();
+                for entry in std::fs::read_dir(&shard2).unwrap() {
+                    let entry = entry.unwrap();
+                    std::fs::rename(entry.path(), dir.join(entry.file_name())).unwrap();
+                }
+                std::fs::remove_dir(shard2).unwrap();
+            }
+            std::fs::remove_dir(shard).unwrap();
+        }
+    }
+    std::fs::remove_file(data_dir.join(SECURITY_DIR).join(VERSION_FILENAME)).unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_migrate_flat_layout() {
+    run_test(
+        TestSetup {
+            key: "test_migrate_flat_layout",
+            read_only: false,
+        },
+        async {
+            let fs = take_fs().await;
+            let Some(data_dir) = local_data_dir(&fs) else {
+                return;
+            };
+
+            let test_dir = SecretString::from_str("test-dir").unwrap();
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_dir,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, file_attr) = fs
+                .create(
+                    dir_attr.ino,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.write_all(file_attr.ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            unportable_file_names(&fs).await;
+            unbind_metadata(&fs).await;
+            unkey_name_hashes(&fs).await;
+            drop(fs);
+
+            flatten_layout(&data_dir);
+            assert!(data_dir
+                .join(INODES_DIR)
+                .join(ROOT_INODE.to_string())
+                .is_file());
+            assert!(data_dir
+                .join(CONTENTS_DIR)
+                .join(file_attr.ino.to_string())
+                .is_file());
+
+            let fs = EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+                FsOptions::default(),
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(
+                FORMAT_VERSION.to_string(),
+                std::fs::read_to_string(data_dir.join(SECURITY_DIR).join(VERSION_FILENAME))
+                    .unwrap()
+            );
+            for ino in [ROOT_INODE, dir_attr.ino, file_attr.ino] {
+                assert!(!data_dir.join(INODES_DIR).join(ino.to_string()).exists());
+                assert!(!data_dir.join(CONTENTS_DIR).join(ino.to_string()).exists());
+                assert!(shard_path(&data_dir.join(INODES_DIR), ino).is_file());
+                assert!(shard_path(&data_dir.join(CONTENTS_DIR), ino).exists());
+            }
+            assert!(fs.is_dir(ROOT_INODE).await);
+            assert!(fs.is_dir(dir_attr.ino).await);
+            assert_eq!(
+                file_attr.ino,
+                fs.find_by_name(dir_attr.ino, &test_file)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+            assert_eq!(
+                dir_attr.ino,
+                fs.find_by_name(ROOT_INODE, &test_dir)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+            assert_eq!(
+                "test-42",
+                test_common::read_to_string(file_attr.ino, &fs).await
+            );
+        },
+    )
+    .await;
+}
+
+/// Go back to the plain hashes of the names used before version `2`.
+async fn unkey_name_hashes(fs: &EncryptedFs) {
+    let data_dir = local_data_dir(fs).unwrap();
+    let key = fs.key.get().await.unwrap();
+    let contents_dir = data_dir.join(CONTENTS_DIR);
+    for ino in sharded_inodes(&*fs.storage, Path::new(CONTENTS_DIR))
+        .await
+        .unwrap()
+    {
+        let dir = shard_path(&contents_dir, ino);
+        if !dir.join(LS_DIR).is_dir() {
+            continue;
+        }
+        fs::remove_dir_all(dir.join(HASH_DIR)).unwrap();
+        fs::create_dir(dir.join(HASH_DIR)).unwrap();
+        fs::remove_file(dir.join(NAME_SALT_FILENAME)).unwrap();
+        for entry in fs::read_dir(dir.join(LS_DIR)).unwrap() {
+            let entry = entry.unwrap();
+            let encrypted_name = entry.file_name().to_string_lossy().to_string();
+            let hash = if encrypted_name == "$." || encrypted_name == "$.." {
+                encrypted_name.clone()
+            } else {
+                let name = crypto::decrypt_file_name(
+                    &crypto::reencode_legacy_file_name(&encrypted_name).unwrap(),
+                    fs.cipher,
+                    &key,
+                )
+                .unwrap();
+                hex::encode(crypto::hash_secret_string(&name))
+            };
+            let entry: (u64, FileType) = bincode::deserialize_from(crypto::create_read(
+                File::open(entry.path()).unwrap(),
+                fs.cipher,
+                &key,
+            ))
+            .unwrap();
+            crypto::atomic_serialize_encrypt_into(
+                &dir.join(HASH_DIR).join(hash),
+                &(entry.0, entry.1, encrypted_name),
+                fs.cipher,
+                &key,
+                &[],
+            )
+            .unwrap();
+        }
+    }
+    fs::write(data_dir.join(SECURITY_DIR).join(VERSION_FILENAME), "1").unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_migrate_keyed_name_hashes() {
+    run_test(
+        TestSetup {
+            key: "test_migrate_keyed_name_hashes",
+            read_only: false,
+        },
+        async {
+            let fs = take_fs().await;
+            let Some(data_dir) = local_data_dir(&fs) else {
+                return;
+            };
+
+            // the same name in two directories
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let mut dirs = vec![];
+            for name in ["dir-1", "dir-2"] {
+                let (_, dir_attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &SecretString::from_str(name).unwrap(),
+                        create_attr(FileType::Directory),
+                        false,
+                        false,
+                    )
+                    .await
+                    .unwrap();
+                let (_, file_attr) = fs
+                    .create(
+                        dir_attr.ino,
+                        &test_file,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        false,
+                    )
+                    .await
+                    .unwrap();
+                dirs.push((dir_attr.ino, file_attr.ino));
+            }
+            let hash_1 = fs.hash_entry_name(dirs[0].0, &test_file).await.unwrap();
+            let hash_2 = fs.hash_entry_name(dirs[1].0, &test_file).await.unwrap();
+            assert_ne!(hash_1, hash_2);
+            assert_ne!(hex::encode(crypto::hash_secret_string(&test_file)), hash_1);
+
+            unportable_file_names(&fs).await;
+            unbind_metadata(&fs).await;
+            unkey_name_hashes(&fs).await;
+            let dir_contents = shard_path(&data_dir.join(CONTENTS_DIR), dirs[0].0);
+            // interrupted while writing the new hashes of a directory
+            fs::create_dir(dir_contents.join("hash.tmp")).unwrap();
+            fs::write(dir_contents.join("hash.tmp").join("42"), b"42").unwrap();
+            drop(fs);
+
+            let fs = EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+                FsOptions::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                FORMAT_VERSION.to_string(),
+                fs::read_to_string(data_dir.join(SECURITY_DIR).join(VERSION_FILENAME)).unwrap()
+            );
+            assert!(!dir_contents.join("hash.tmp").exists());
+            assert!(!dir_contents.join(HASH_DIR).join("42").exists());
+            for (dir, file) in &dirs {
+                assert!(shard_path(&data_dir.join(CONTENTS_DIR), *dir)
+                    .join(NAME_SALT_FILENAME)
+                    .is_file());
+                assert_eq!(
+                    *file,
+                    fs.find_by_name(*dir, &test_file)
+                        .await
+                        .unwrap()
+                        .unwrap()
+                        .ino
+                );
+            }
+            assert_ne!(
+                fs.hash_entry_name(dirs[0].0, &test_file).await.unwrap(),
+                fs.hash_entry_name(dirs[1].0, &test_file).await.unwrap()
+            );
+            assert!(fs.check(false).await.unwrap().is_clean());
+        },
+    )
+    .await;
+}
+
+/// Re-encrypt the inodes and the directory entries without associated data, like before version `3`.
+async fn unbind_metadata(fs: &EncryptedFs) {
+    fn unbind<T: serde::Serialize + serde::de::DeserializeOwned>(
+        fs: &EncryptedFs,
+        key: &SecretVec<u8>,
+        path: &Path,
+        aad: &[u8],
+    ) {
+        let value: T = deserialize_bound(File::open(path).unwrap(), fs.cipher, key, aad).unwrap();
+        crypto::atomic_serialize_encrypt_into(path, &value, fs.cipher, key, &[]).unwrap();
+    }
+
+    let data_dir = local_data_dir(fs).unwrap();
+    let key = fs.key.get().await.unwrap();
+    let inodes_dir = data_dir.join(INODES_DIR);
+    for ino in sharded_inodes(&*fs.storage, Path::new(INODES_DIR))
+        .await
+        .unwrap()
+    {
+        unbind::<FileAttr>(fs, &key, &shard_path(&inodes_dir, ino), &inode_aad(ino));
+    }
+    let contents_dir = data_dir.join(CONTENTS_DIR);
+    for ino in sharded_inodes(&*fs.storage, Path::new(CONTENTS_DIR))
+        .await
+        .unwrap()
+    {
+        let dir = shard_path(&contents_dir, ino);
+        if !dir.join(LS_DIR).is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(dir.join(LS_DIR)).unwrap() {
+            let entry = entry.unwrap();
+            let aad = dir_entry_aad(ino, &entry.file_name().to_string_lossy());
+            unbind::<(u64, FileType)>(fs, &key, &entry.path(), &aad);
+        }
+        for entry in fs::read_dir(dir.join(HASH_DIR)).unwrap() {
+            let entry = entry.unwrap();
+            let aad = dir_entry_aad(ino, &entry.file_name().to_string_lossy());
+            unbind::<(u64, FileType, String)>(fs, &key, &entry.path(), &aad);
+        }
+    }
+    fs::write(data_dir.join(SECURITY_DIR).join(VERSION_FILENAME), "2").unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_migrate_bound_metadata() {
+    run_test(
+        TestSetup {
+            key: "test_migrate_bound_metadata",
+            read_only: false,
+        },
+        async {
+            let fs = take_fs().await;
+            let Some(data_dir) = local_data_dir(&fs) else {
+                return;
+            };
+
+            let test_dir = SecretString::from_str("test-dir").unwrap();
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_dir,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, file_attr) = fs
+                .create(
+                    dir_attr.ino,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.write_all(file_attr.ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            unportable_file_names(&fs).await;
+            unbind_metadata(&fs).await;
+            // the root was already migrated by an interrupted run
+            let root_attr: FileAttr = bincode::deserialize_from(crypto::create_read(
+                File::open(data_dir.join(fs.ino_file(ROOT_INODE))).unwrap(),
+                fs.cipher,
+                &fs.key.get().await.unwrap(),
+            ))
+            .unwrap();
+            crypto::atomic_serialize_encrypt_into(
+                &data_dir.join(fs.ino_file(ROOT_INODE)),
+                &root_attr,
+                fs.cipher,
+                &fs.key.get().await.unwrap(),
+                &inode_aad(ROOT_INODE),
+            )
+            .unwrap();
+            drop(fs);
+
+            let fs = EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+                FsOptions::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                FORMAT_VERSION.to_string(),
+                fs::read_to_string(data_dir.join(SECURITY_DIR).join(VERSION_FILENAME)).unwrap()
+            );
+            assert_eq!(
+                dir_attr.ino,
+                fs.find_by_name(ROOT_INODE, &test_dir)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+            assert_eq!(
+                file_attr.ino,
+                fs.find_by_name(dir_attr.ino, &test_file)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+            // ".", ".." and the file
+            let entries: Vec<_> = fs.read_dir(dir_attr.ino).await.unwrap().collect();
+            assert_eq!(3, entries.len());
+            assert!(entries.iter().all(Result::is_ok));
+            assert_eq!(
+                "test-42",
+                test_common::read_to_string(file_attr.ino, &fs).await
+            );
+            assert!(fs.check(false).await.unwrap().is_clean());
+        },
+    )
+    .await;
+}
+
+/// Rewrite the inodes without [`FileAttr::compressed`], like before version `4`.
+async fn drop_compression_flag(fs: &EncryptedFs) {
+    let data_dir = local_data_dir(fs).unwrap();
+    let key = fs.key.get().await.unwrap();
+    let inodes_dir = data_dir.join(INODES_DIR);
+    for ino in sharded_inodes(&*fs.storage, Path::new(INODES_DIR))
+        .await
+        .unwrap()
+    {
+        let path = shard_path(&inodes_dir, ino);
+        let attr: FileAttr =
+            deserialize_bound(File::open(&path).unwrap(), fs.cipher, &key, &inode_aad(ino))
+                .unwrap();
+        let attr = FileAttrV3 {
+            ino: attr.ino,
+            size: attr.size,
+            blocks: attr.blocks,
+            atime: attr.atime,
+            mtime: attr.mtime,
+            ctime: attr.ctime,
+            crtime: attr.crtime,
+            kind: attr.kind,
+            perm: attr.perm,
+            nlink: attr.nlink,
+            uid: attr.uid,
+            gid: attr.gid,
+            rdev: attr.rdev,
+            blksize: attr.blksize,
+            flags: attr.flags,
+        };
+        crypto::atomic_serialize_encrypt_into(&path, &attr, fs.cipher, &key, &inode_aad(ino))
+            .unwrap();
+    }
+    fs::write(data_dir.join(SECURITY_DIR).join(VERSION_FILENAME), "3").unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_migrate_compression_flag() {
+    run_test(
+        TestSetup {
+            key: "test_migrate_compression_flag",
+            read_only: false,
+        },
+        async {
+            let fs = take_fs().await;
+            let Some(data_dir) = local_data_dir(&fs) else {
+                return;
+            };
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.write_all(attr.ino, 0, b"test-42", fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            unportable_file_names(&fs).await;
+            drop_compression_flag(&fs).await;
+            drop(fs);
+
+            let fs = EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+                FsOptions::builder().compression_level(3).build(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                FORMAT_VERSION.to_string(),
+                fs::read_to_string(data_dir.join(SECURITY_DIR).join(VERSION_FILENAME)).unwrap()
+            );
+            let migrated = fs.get_attr(attr.ino).await.unwrap();
+            assert_eq!(7, migrated.size);
+            assert!(!migrated.compressed);
+            // not changed since the migration
+            assert_eq!(0, migrated.generation);
+            assert_eq!(0, migrated.ino_generation);
+            assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs).await);
+            assert!(fs.check(false).await.unwrap().is_clean());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_integrity_violation() {
+    run_test(
+        TestSetup {
+            key: "test_integrity_violation",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let mut dirs = vec![];
+            for name in ["dir-1", "dir-2"] {
+                let (_, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &SecretString::from_str(name).unwrap(),
+                        create_attr(FileType::Directory),
+                        false,
+                        false,
+                    )
+                    .await
+                    .unwrap();
+                dirs.push(attr.ino);
+            }
+            let mut files = vec![];
+            for name in ["a", "b"] {
+                let name = SecretString::from_str(name).unwrap();
+                let (_, attr) = fs
+                    .create(
+                        dirs[0],
+                        &name,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        false,
+                    )
+                    .await
+                    .unwrap();
+                files.push((name, attr.ino));
+            }
+            let swap = async |a: &Path, b: &Path| {
+                let tmp = a.with_extension("swap");
+                fs.storage.rename(a, &tmp).await.unwrap();
+                fs.storage.rename(b, a).await.unwrap();
+                fs.storage.rename(&tmp, b).await.unwrap();
+            };
+
+            // swapped inode files
+            let inode_a = shard_path(Path::new(INODES_DIR), files[0].1);
+            let inode_b = shard_path(Path::new(INODES_DIR), files[1].1);
+            swap(&inode_a, &inode_b).await;
+            fs.attr_cache.get().await.unwrap().write().await.clear();
+            assert!(matches!(
+                fs.get_attr(files[0].1).await,
+                Err(FsError::IntegrityViolation)
+            ));
+            assert!(matches!(
+                fs.get_attr(files[1].1).await,
+                Err(FsError::IntegrityViolation)
+            ));
+            swap(&inode_a, &inode_b).await;
+            assert_eq!(files[0].1, fs.get_attr(files[0].1).await.unwrap().ino);
+
+            // swapped ls entries in the same directory
+            let dir_contents = shard_path(Path::new(CONTENTS_DIR), dirs[0]);
+            let ls_entries: Vec<_> = fs
+                .storage
+                .list(&dir_contents.join(LS_DIR))
+                .await
+                .unwrap()
+                .into_iter()
+                .filter(|entry| !entry.name.starts_with('$'))
+                .map(|entry| dir_contents.join(LS_DIR).join(entry.name))
+                .collect();
+            assert_eq!(2, ls_entries.len());
+            swap(&ls_entries[0], &ls_entries[1]).await;
+            assert!(fs
+                .read_dir(dirs[0])
+                .await
+                .unwrap()
+                .any(|entry| matches!(entry, Err(FsError::IntegrityViolation))));
+            swap(&ls_entries[0], &ls_entries[1]).await;
+
+            // hash entry copied to another directory
+            let hash = fs.hash_entry_name(dirs[0], &files[0].0).await.unwrap();
+            let hash_other = fs.hash_entry_name(dirs[1], &files[0].0).await.unwrap();
+            let data = fs
+                .storage
+                .read(&dir_contents.join(HASH_DIR).join(hash))
+                .await
+                .unwrap();
+            fs.storage
+                .write(
+                    &shard_path(Path::new(CONTENTS_DIR), dirs[1])
+                        .join(HASH_DIR)
+                        .join(hash_other),
+                    &data,
+                )
+                .await
+                .unwrap();
+            assert!(matches!(
+                fs.find_by_name(dirs[1], &files[0].0).await,
+                Err(FsError::IntegrityViolation)
+            ));
+            assert_eq!(
+                files[0].1,
+                fs.find_by_name(dirs[0], &files[0].0)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_migrate() {
+    run_test(
+        TestSetup {
+            key: "test_migrate",
+            read_only: false,
+        },
+        async {
+            let fs = take_fs().await;
+            let Some(data_dir) = local_data_dir(&fs) else {
+                return;
+            };
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let version_file = data_dir.join(SECURITY_DIR).join(VERSION_FILENAME);
+            assert_eq!(
+                FORMAT_VERSION.to_string(),
+                std::fs::read_to_string(&version_file).unwrap()
+            );
+            unportable_file_names(&fs).await;
+            unbind_metadata(&fs).await;
+            unkey_name_hashes(&fs).await;
+            drop(fs);
+
+            flatten_layout(&data_dir);
+            EncryptedFs::migrate(
+                &data_dir,
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                FORMAT_VERSION.to_string(),
+                std::fs::read_to_string(&version_file).unwrap()
+            );
+            assert!(shard_path(&data_dir.join(INODES_DIR), attr.ino).is_file());
+            assert!(shard_path(&data_dir.join(CONTENTS_DIR), attr.ino).is_file());
+            // already up to date
+            EncryptedFs::migrate(
+                &data_dir,
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap();
+
+            // created by a newer version
+            std::fs::write(&version_file, (FORMAT_VERSION + 1).to_string()).unwrap();
+            assert!(matches!(
+                EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    false,
+                    FsOptions::default(),
+                )
+                .await,
+                Err(FsError::UnsupportedVersion(v)) if v == FORMAT_VERSION + 1
+            ));
+            assert!(matches!(
+                EncryptedFs::migrate(
+                    &data_dir,
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                )
+                .await,
+                Err(FsError::UnsupportedVersion(v)) if v == FORMAT_VERSION + 1
+            ));
+        },
+    )
+    .await;
+}
+
+/// Encode the names back in base64 with `|` instead of `/` and rename the entries of `.` and `..` to `$.` and `$..`,
+/// like before version `10`.
+async fn unportable_file_names(fs: &EncryptedFs) {
+    fn legacy_name(name: &str) -> String {
+        match name {
+            crypto::SELF_ENTRY_NAME => "$.".to_owned(),
+            crypto::PARENT_ENTRY_NAME => "$..".to_owned(),
+            _ => crypto::BASE64
+                .encode(crypto::base32::decode(name).unwrap())
+                .replace('/', "|"),
+        }
+    }
+
+    let data_dir = local_data_dir(fs).unwrap();
+    let key = fs.key.get().await.unwrap();
+    let contents_dir = data_dir.join(CONTENTS_DIR);
+    for ino in sharded_inodes(&*fs.storage, Path::new(CONTENTS_DIR))
+        .await
+        .unwrap()
+    {
+        let dir = shard_path(&contents_dir, ino);
+        if !dir.join(LS_DIR).is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(dir.join(LS_DIR)).unwrap() {
+            let entry = entry.unwrap();
+            let (entry_ino, kind, encrypted_name) = deserialize_ls_entry(
+                File::open(entry.path()).unwrap(),
+                ino,
+                &entry.file_name().to_string_lossy(),
+                fs.cipher,
+                &key,
+            )
+            .unwrap();
+            let (ls_name, data) = serialize_ls_entry(
+                ino,
+                &legacy_name(&encrypted_name),
+                entry_ino,
+                kind,
+                fs.cipher,
+                &key,
+            )
+            .unwrap();
+            fs::remove_file(entry.path()).unwrap();
+            fs::write(dir.join(LS_DIR).join(ls_name), data).unwrap();
+        }
+        for entry in fs::read_dir(dir.join(HASH_DIR)).unwrap() {
+            let entry = entry.unwrap();
+            let hash = entry.file_name().to_string_lossy().to_string();
+            let (entry_ino, kind, encrypted_name): (u64, FileType, String) = deserialize_bound(
+                File::open(entry.path()).unwrap(),
+                fs.cipher,
+                &key,
+                &dir_entry_aad(ino, &hash),
+            )
+            .unwrap();
+            // the hashes of `.` and `..` are their names
+            let hash = match hash.as_str() {
+                crypto::SELF_ENTRY_NAME | crypto::PARENT_ENTRY_NAME => legacy_name(&hash),
+                _ => hash,
+            };
+            fs::remove_file(entry.path()).unwrap();
+            crypto::atomic_serialize_encrypt_into(
+                &dir.join(HASH_DIR).join(&hash),
+                &(entry_ino, kind, legacy_name(&encrypted_name)),
+                fs.cipher,
+                &key,
+                &dir_entry_aad(ino, &hash),
+            )
+            .unwrap();
+        }
+    }
+    fs::write(data_dir.join(SECURITY_DIR).join(VERSION_FILENAME), "9").unwrap();
+}
+
+/// Names which are not valid on Windows or clash on a case-insensitive filesystem.
+const UNPORTABLE_NAMES: [&str; 8] = [
+    "CON",
+    "nul.txt",
+    "a:b*c?d\"<e>|f\\g",
+    "ends with dot.",
+    "ends with space ",
+    "Case",
+    "case",
+    "CASE",
+];
+
+/// Whether `name` is a valid name on Windows, like on NTFS, FAT and exFAT.
+fn is_portable_name(name: &str) -> bool {
+    const RESERVED: [&str; 22] = [
+        "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+        "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+    ];
+    let stem = name.split('.').next().unwrap().to_ascii_uppercase();
+    !name.is_empty()
+        && !name.ends_with('.')
+        && !name.ends_with(' ')
+        && !name
+            .chars()
+            .any(|c| c.is_control() || "/\\:*?\"<>|".contains(c))
+        && !RESERVED.contains(&stem.as_str())
+}
+
+/// Assert all the names in `dir` are portable, see [`is_portable_name`], and stay apart if their case is folded.
+fn assert_portable_dir(dir: &Path) {
+    let mut folded = HashSet::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let entry = entry.unwrap();
+        let name = entry.file_name().to_string_lossy().to_string();
+        assert!(is_portable_name(&name), "{name} in {}", dir.display());
+        assert!(
+            folded.insert(name.to_lowercase()),
+            "{name} in {}",
+            dir.display()
+        );
+        if entry.file_type().unwrap().is_dir() {
+            assert_portable_dir(&entry.path());
+        }
+    }
+}
+
+/// Copy `from` to `to` recursively.
+fn copy_dir(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(&entry.path(), &to.join(entry.file_name()));
+        } else {
+            fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+        }
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_portable_data_dir() {
+    run_test(
+        TestSetup {
+            key: "test_portable_data_dir",
+            read_only: false,
+        },
+        async {
+            let fs = take_fs().await;
+            let Some(data_dir) = local_data_dir(&fs) else {
+                return;
+            };
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("AUX").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let mut expected = HashMap::new();
+            for name in UNPORTABLE_NAMES {
+                let ino = create_file(&fs, ROOT_INODE, name, &[]).await;
+                expected.insert(name.to_owned(), ino);
+                fs.create(
+                    dir_attr.ino,
+                    &SecretString::from_str(name).unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            }
+            let long_name = "L".repeat(255);
+            expected.insert(
+                long_name.clone(),
+                create_file(&fs, ROOT_INODE, &long_name, &[]).await,
+            );
+            drop(fs);
+
+            assert_portable_dir(&data_dir);
+
+            // like moved to a filesystem which keeps the names apart only by their folded case
+            let copy = tempfile::tempdir().unwrap();
+            copy_dir(&data_dir, copy.path());
+            let fs = EncryptedFs::new(
+                copy.path().to_path_buf(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+                FsOptions::default(),
+            )
+            .await
+            .unwrap();
+            let entries = dir_entries(&fs, ROOT_INODE).await;
+            for (name, ino) in &expected {
+                assert_eq!(Some(ino), entries.get(name), "{name}");
+            }
+            let entries = dir_entries(&fs, dir_attr.ino).await;
+            for name in UNPORTABLE_NAMES {
+                assert!(entries.contains_key(name), "{name}");
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_migrate_portable_names() {
+    run_test(
+        TestSetup {
+            key: "test_migrate_portable_names",
+            read_only: false,
+        },
+        async {
+            let fs = take_fs().await;
+            let Some(data_dir) = local_data_dir(&fs) else {
+                return;
+            };
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let mut expected = HashMap::new();
+            for name in UNPORTABLE_NAMES {
+                expected.insert(
+                    name.to_owned(),
+                    create_file(&fs, ROOT_INODE, name, &[]).await,
+                );
+            }
+            // some have a `/` in base64
+            for i in 0..32 {
+                let name = format!("file-{i}");
+                expected.insert(name.clone(), create_file(&fs, ROOT_INODE, &name, &[]).await);
+            }
+            unportable_file_names(&fs).await;
+            let dir_contents = shard_path(&data_dir.join(CONTENTS_DIR), dir_attr.ino);
+            assert!(dir_contents.join(LS_DIR).join("$.").is_file());
+            let root_ls_dir = shard_path(&data_dir.join(CONTENTS_DIR), ROOT_INODE).join(LS_DIR);
+            assert!(fs::read_dir(root_ls_dir).unwrap().any(|entry| entry
+                .unwrap()
+                .file_name()
+                .to_string_lossy()
+                .contains('|')));
+            drop(fs);
+
+            let fs = EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+                FsOptions::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                FORMAT_VERSION.to_string(),
+                fs::read_to_string(data_dir.join(SECURITY_DIR).join(VERSION_FILENAME)).unwrap()
+            );
+            let entries = dir_entries(&fs, ROOT_INODE).await;
+            for (name, ino) in &expected {
+                assert_eq!(Some(ino), entries.get(name), "{name}");
+                assert_eq!(
+                    *ino,
+                    fs.find_by_name(ROOT_INODE, &SecretString::from_str(name).unwrap())
+                        .await
+                        .unwrap()
+                        .unwrap()
+                        .ino
+                );
+            }
+            assert_eq!(
+                ROOT_INODE,
+                fs.find_by_name(dir_attr.ino, &SecretString::from_str("..").unwrap())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+            assert!(dir_contents
+                .join(LS_DIR)
+                .join(crypto::SELF_ENTRY_NAME)
+                .is_file());
+            assert!(!dir_contents.join(LS_DIR).join("$.").exists());
+            drop(fs);
+            assert_portable_dir(&data_dir);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_path_helpers() {
+    run_test(
+        TestSetup {
+            key: "test_path_helpers",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            assert_eq!(ROOT_INODE, fs.resolve(Path::new("/")).await.unwrap().ino);
+            assert_eq!(ROOT_INODE, fs.resolve(Path::new("")).await.unwrap().ino);
+
+            // deep path
+            let dir_attr = fs
+                .create_dir_all_by_path(Path::new("/a/b/c/d"), create_attr(FileType::Directory))
+                .await
+                .unwrap();
+            assert_eq!(FileType::Directory, dir_attr.kind);
+            assert_eq!(
+                dir_attr.ino,
+                fs.resolve(Path::new("/a/b/c/d")).await.unwrap().ino
+            );
+            assert_eq!(
+                dir_attr.ino,
+                fs.resolve(Path::new("a/./b/../b/c/d/")).await.unwrap().ino
+            );
+            assert_eq!(
+                ROOT_INODE,
+                fs.resolve(Path::new("/../a/..")).await.unwrap().ino
+            );
+            // existing directories are reused
+            assert_eq!(
+                dir_attr.ino,
+                fs.create_dir_all_by_path(Path::new("/a/b/c/d"), create_attr(FileType::Directory))
+                    .await
+                    .unwrap()
+                    .ino
+            );
+
+            let (fh, file_attr) = fs
+                .create_file_by_path(
+                    Path::new("/a/b/c/d/file"),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.write_all(file_attr.ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(
+                file_attr.ino,
+                fs.find_by_name(dir_attr.ino, &SecretString::from_str("file").unwrap())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+            let (fh, attr) = fs
+                .open_by_path(Path::new("/a/b/c/d/file"), true, false)
+                .await
+                .unwrap();
+            assert_eq!(file_attr.ino, attr.ino);
+            let mut buf = [0; 7];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+            assert_eq!(b"test-42", &buf);
+            fs.release(fh).await.unwrap();
+
+            // missing intermediate directories
+            assert!(matches!(
+                fs.resolve(Path::new("/a/missing/c")).await,
+                Err(FsError::NotFound { .. })
+            ));
+            assert!(matches!(
+                fs.create_file_by_path(
+                    Path::new("/a/missing/file"),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await,
+                Err(FsError::NotFound { .. })
+            ));
+            assert!(matches!(
+                fs.open_by_path(Path::new("/a/missing/file"), true, false)
+                    .await,
+                Err(FsError::NotFound { .. })
+            ));
+            // a file is not a directory
+            assert!(matches!(
+                fs.resolve(Path::new("/a/b/c/d/file/x")).await,
+                Err(FsError::InvalidInodeType)
+            ));
+            assert!(matches!(
+                fs.create_dir_all_by_path(
+                    Path::new("/a/b/c/d/file/x"),
+                    create_attr(FileType::Directory)
+                )
+                .await,
+                Err(FsError::InvalidInodeType)
+            ));
+            // must end with a name
+            for path in ["/", "/a/.."] {
+                assert!(matches!(
+                    fs.remove_by_path(Path::new(path)).await,
+                    Err(FsError::InvalidInput { .. })
+                ));
+            }
+
+            assert!(matches!(
+                fs.remove_by_path(Path::new("/a/b/c")).await,
+                Err(FsError::NotEmpty)
+            ));
+            fs.remove_by_path(Path::new("/a/b/c/d/file")).await.unwrap();
+            assert!(!fs.exists(file_attr.ino).await);
+            fs.remove_by_path(Path::new("/a/b/c/d")).await.unwrap();
+            assert!(!fs.exists(dir_attr.ino).await);
+            assert!(matches!(
+                fs.resolve(Path::new("/a/b/c/d")).await,
+                Err(FsError::NotFound { .. })
+            ));
+            assert!(fs.resolve(Path::new("/a/b/c")).await.is_ok());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_lseek() {
+    run_test(
+        TestSetup {
+            key: "test_lseek",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            // empty file
+            assert!(matches!(
+                fs.lseek(attr.ino, fh, 0, SeekWhence::Data).await,
+                Err(FsError::OffsetPastEof)
+            ));
+            // size of the write handle is used before flush
+            let data = b"test-42";
+            fs.write(attr.ino, 0, data, fh).await.unwrap();
+            let len = data.len() as u64;
+
+            for whence in [SeekWhence::Data, SeekWhence::Hole] {
+                let expected = |offset| {
+                    if whence == SeekWhence::Data {
+                        offset
+                    } else {
+                        len
+                    }
+                };
+                assert_eq!(
+                    expected(0),
+                    fs.lseek(attr.ino, fh, 0, whence).await.unwrap()
+                );
+                assert_eq!(
+                    expected(len / 2),
+                    fs.lseek(attr.ino, fh, len / 2, whence).await.unwrap()
+                );
+                assert!(matches!(
+                    fs.lseek(attr.ino, fh, len, whence).await,
+                    Err(FsError::OffsetPastEof)
+                ));
+                assert!(matches!(
+                    fs.lseek(attr.ino, fh, len + 42, whence).await,
+                    Err(FsError::OffsetPastEof)
+                ));
+            }
+            fs.release(fh).await.unwrap();
+
+            // read handle
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            assert_eq!(
+                len / 2,
+                fs.lseek(attr.ino, fh, len / 2, SeekWhence::Data)
+                    .await
+                    .unwrap()
+            );
+            // handle of another file
+            let (fh2, _) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file-2").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    true,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert!(matches!(
+                fs.lseek(attr.ino, fh2, 0, SeekWhence::Data).await,
+                Err(FsError::InvalidFileHandle)
+            ));
+            assert!(matches!(
+                fs.lseek(ROOT_INODE, 0, 0, SeekWhence::Data).await,
+                Err(FsError::InvalidInodeType)
+            ));
+            fs.release(fh).await.unwrap();
+            fs.release(fh2).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_lseek_holes() {
+    let fs = open_storage_with(Arc::new(InMemoryStorage::new()), FsOptions::default()).await;
+    let block = BLOCK_SIZE as u64;
+    let ino = create_file(&fs, ROOT_INODE, "file", &[]).await;
+    let fh = fs.open(ino, true, true).await.unwrap();
+    // data in the first and the fourth block, a hole in the second and the third
+    fs.write_all(ino, 0, &[1; 10], fh).await.unwrap();
+    fs.write(ino, 3 * block, &[2; 10], fh).await.unwrap();
+    let size = 3 * block + 10;
+
+    // the last write is still in the handle
+    assert_eq!(
+        3 * block,
+        fs.lseek(ino, fh, block + 5, SeekWhence::Data)
+            .await
+            .unwrap()
+    );
+    for handle in [fh, 0] {
+        assert_eq!(0, fs.lseek(ino, handle, 0, SeekWhence::Data).await.unwrap());
+        assert_eq!(5, fs.lseek(ino, handle, 5, SeekWhence::Data).await.unwrap());
+        assert_eq!(
+            block,
+            fs.lseek(ino, handle, 5, SeekWhence::Hole).await.unwrap()
+        );
+        assert_eq!(
+            block + 5,
+            fs.lseek(ino, handle, block + 5, SeekWhence::Hole)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            3 * block,
+            fs.lseek(ino, handle, 2 * block, SeekWhence::Data)
+                .await
+                .unwrap()
+        );
+        // the end of the file is a hole
+        assert_eq!(
+            size,
+            fs.lseek(ino, handle, 3 * block, SeekWhence::Hole)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            size - 1,
+            fs.lseek(ino, handle, size - 1, SeekWhence::Data)
+                .await
+                .unwrap()
+        );
+    }
+
+    // a hole at the end, before the size set with set_len
+    fs.release(fh).await.unwrap();
+    fs.set_len(ino, 6 * block).await.unwrap();
+    assert_eq!(
+        4 * block,
+        fs.lseek(ino, 0, 3 * block, SeekWhence::Hole).await.unwrap()
+    );
+    assert!(matches!(
+        fs.lseek(ino, 0, 4 * block, SeekWhence::Data).await,
+        Err(FsError::OffsetPastEof)
+    ));
+    let fh = fs.open(ino, true, false).await.unwrap();
+    let mut buf = vec![1; block as usize];
+    fs.read_exact_at(ino, 4 * block, &mut buf, fh)
+        .await
+        .unwrap();
+    assert!(buf.iter().all(|b| *b == 0));
+    fs.release(fh).await.unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_check() {
+    run_test(
+        TestSetup {
+            key: "test_check",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (_, dir) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let mut files = vec![];
+            for name in ["a", "b", "c", "d"] {
+                let name = SecretString::from_str(name).unwrap();
+                let (fh, attr) = fs
+                    .create(
+                        dir.ino,
+                        &name,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                fs.write_all(attr.ino, 0, b"test-42", fh).await.unwrap();
+                fs.release(fh).await.unwrap();
+                files.push((name, attr.ino));
+            }
+            assert!(fs.check(false).await.unwrap().is_clean());
+
+            let dir_contents = shard_path(Path::new(CONTENTS_DIR), dir.ino);
+            // missing hash entry
+            fs.storage
+                .remove(
+                    &dir_contents
+                        .join(HASH_DIR)
+                        .join(fs.hash_entry_name(dir.ino, &files[0].0).await.unwrap()),
+                )
+                .await
+                .unwrap();
+            // dangling entry
+            fs.storage
+                .remove(&shard_path(Path::new(INODES_DIR), files[1].1))
+                .await
+                .unwrap();
+            // orphan inode
+            fs.remove_directory_entry(dir.ino, &files[2].0, false)
+                .await
+                .unwrap();
+            // size mismatch
+            let mut attr = fs.get_inode_from_storage(files[3].1).await.unwrap();
+            attr.size = 42;
+            fs.write_inode_to_storage(&attr).await.unwrap();
+            // orphan contents
+            let orphan_contents = shard_path(Path::new(CONTENTS_DIR), 42);
+            fs.storage
+                .create_dir(orphan_contents.parent().unwrap())
+                .await
+                .unwrap();
+            fs.storage.write(&orphan_contents, b"42").await.unwrap();
+
+            let kinds = |report: &CheckReport| {
+                let mut kinds: Vec<_> = report
+                    .problems
+                    .iter()
+                    .map(|p| (format!("{:?}", p.kind), p.ino))
+                    .collect();
+                kinds.sort();
+                kinds
+            };
+            let mut expected = vec![
+                (
+                    format!("{:?}", CheckProblemKind::MissingHashEntry),
+                    Some(files[0].1),
+                ),
+                (
+                    format!("{:?}", CheckProblemKind::DanglingEntry),
+                    Some(files[1].1),
+                ),
+                (
+                    format!("{:?}", CheckProblemKind::OrphanInode),
+                    Some(files[2].1),
+                ),
+                (
+                    format!(
+                        "{:?}",
+                        CheckProblemKind::SizeMismatch {
+                            attr_size: 42,
+                            contents_size: 7
+                        }
+                    ),
+                    Some(files[3].1),
+                ),
+                (format!("{:?}", CheckProblemKind::OrphanContents), Some(42)),
+                // the contents of the removed inode
+                (
+                    format!("{:?}", CheckProblemKind::OrphanContents),
+                    Some(files[1].1),
+                ),
+            ];
+            expected.sort();
+            let report = fs.check(false).await.unwrap();
+            assert_eq!(expected, kinds(&report));
+            assert!(report.problems.iter().all(|p| !p.repaired));
+            let dangling = report
+                .problems
+                .iter()
+                .find(|p| p.kind == CheckProblemKind::DanglingEntry)
+                .unwrap();
+            assert_eq!(Some(dir.ino), dangling.parent);
+            assert_eq!(
+                "b",
+                dangling.name.as_ref().unwrap().expose_secret().as_str()
+            );
+
+            // nothing changed without repair
+            assert_eq!(expected, kinds(&fs.check(false).await.unwrap()));
+
+            let report = fs.check(true).await.unwrap();
+            assert_eq!(expected, kinds(&report));
+            for p in &report.problems {
+                let repairable = !matches!(
+                    p.kind,
+                    CheckProblemKind::SizeMismatch { .. } | CheckProblemKind::OrphanContents
+                );
+                assert_eq!(repairable, p.repaired);
+            }
+
+            // only what can't be repaired is left
+            let report = fs.check(false).await.unwrap();
+            assert_eq!(3, report.problems.len());
+            assert!(report.problems.iter().all(|p| matches!(
+                p.kind,
+                CheckProblemKind::SizeMismatch { .. } | CheckProblemKind::OrphanContents
+            )));
+
+            assert_eq!(
+                files[0].1,
+                fs.find_by_name(dir.ino, &files[0].0)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+            assert!(!fs.exists_by_name(dir.ino, &files[1].0).await.unwrap());
+            let lost_found = fs
+                .find_by_name(ROOT_INODE, &SecretString::from_str(LOST_FOUND_DIR).unwrap())
+                .await
+                .unwrap()
+                .unwrap();
+            let orphan = SecretString::from_str(&files[2].1.to_string()).unwrap();
+            assert_eq!(
+                files[2].1,
+                fs.find_by_name(lost_found.ino, &orphan)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+            assert_eq!(
+                "test-42",
+                test_common::read_to_string(files[2].1, &fs).await
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_rebuild_dir_index() {
+    run_test(
+        TestSetup {
+            key: "test_rebuild_dir_index",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let mut files = vec![];
+            for name in ["a", "b", "c"] {
+                let name = SecretString::from_str(name).unwrap();
+                let (_, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &name,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        false,
+                    )
+                    .await
+                    .unwrap();
+                files.push((name, attr.ino));
+            }
+            let hash_dir = shard_path(Path::new(CONTENTS_DIR), ROOT_INODE).join(HASH_DIR);
+
+            // the whole dir is lost, `find_by_name` heals it
+            fs.storage.remove_dir(&hash_dir).await.unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &files[0].0).await.unwrap());
+            let attr = fs
+                .find_by_name(ROOT_INODE, &files[0].0)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(files[0].1, attr.ino);
+            for (name, _) in &files {
+                assert!(fs.exists_by_name(ROOT_INODE, name).await.unwrap());
+            }
+            // a name that doesn't exist doesn't trigger a rebuild on a healthy dir
+            assert!(fs
+                .find_by_name(ROOT_INODE, &SecretString::from_str("d").unwrap())
+                .await
+                .unwrap()
+                .is_none());
+
+            // some entries lost and a stale one
+            fs.storage
+                .remove(&hash_dir.join(fs.hash_entry_name(ROOT_INODE, &files[1].0).await.unwrap()))
+                .await
+                .unwrap();
+            let stale = SecretString::from_str("stale").unwrap();
+            fs.storage
+                .write(
+                    &hash_dir.join(fs.hash_entry_name(ROOT_INODE, &stale).await.unwrap()),
+                    b"42",
+                )
+                .await
+                .unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &files[1].0).await.unwrap());
+            fs.rebuild_dir_index(ROOT_INODE).await.unwrap();
+            assert!(fs.exists_by_name(ROOT_INODE, &files[1].0).await.unwrap());
+            assert!(!fs.exists_by_name(ROOT_INODE, &stale).await.unwrap());
+            assert!(fs.check(false).await.unwrap().is_clean());
+
+            assert!(matches!(
+                fs.rebuild_dir_index(files[2].1).await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+// #[tokio::test]
+// #[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn _test_sample() {
+    run_test(
+        TestSetup {
+            key: "test_sample",
+            read_only: false,
+        },
+        async {
+            let _ = get_fs().await;
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_read_only_create() {
+    run_test(
+        TestSetup {
+            key: "test_read_only_create",
+            read_only: true,
+        },
+        async {
+            let fs = get_fs().await;
+
+            // Check creating a file in a read only fs
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let create_file_result = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    true,
+                    false,
+                )
+                .await;
+            assert!(matches!(create_file_result, Err(FsError::ReadOnly)));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_read_only_write() {
+    run_test(
+        TestSetup {
+            key: "read_only_test_write",
+            read_only: false,
+        },
+        async {
+            let fs_rw = take_fs().await;
+            let storage = fs_rw.storage.clone();
+            let cipher = Cipher::ChaCha20Poly1305;
+            let file1 = SecretString::from_str("file1").unwrap();
+            let file_dest = SecretString::from_str("file_dest").unwrap();
+            let dir1 = SecretString::from_str("dir1").unwrap();
+            let data = "Hello, world!";
+
+            let (fh, attr) = fs_rw
+                .create(
+                    ROOT_INODE,
+                    &file1,
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .expect("read_only_test_create: Error creating file.");
+            let (fh_dest, attr_dest) = fs_rw
+                .create(
+                    ROOT_INODE,
+                    &file_dest,
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .expect("read_only_test_create: Error creating file.");
+            let (_, _) = fs_rw
+                .create(
+                    ROOT_INODE,
+                    &dir1,
+                    create_attr(FileType::Directory),
+                    false,
+                    true,
+                )
+                .await
+                .expect("read_only_test_create: Error creating dir.");
+
+            // Create a succesful write on the file
+            fs_rw
+                .write_all(attr.ino, 0, data.as_bytes(), fh)
+                .await
+                .unwrap();
+            fs_rw.flush(fh).await.unwrap();
+            fs_rw.release(fh).await.unwrap();
+            drop(fs_rw);
+            let fs_ro = EncryptedFs::with_storage(
+                storage,
+                Box::new(PasswordProviderImpl {}),
+                cipher,
+                true,
+                FsOptions::default(),
+            )
+            .await
+            .expect("test_read_only_write: Error creating rw fs.");
+            let fh = fs_ro
+                .open(attr.ino, true, false)
+                .await
+                .expect("read_only_test_create: Error opening file in ro.");
+
+            // Test a succesful reading the file in rw mode
+            let mut buf = vec![0; data.len()];
+            fs_ro.read(attr.ino, 0, &mut buf, fh).await.unwrap();
+            assert_eq!(data, String::from_utf8(buf).unwrap());
+
+            // Test creating a file
+            let file2 = SecretString::from_str("file2").unwrap();
+            let create_file_result = fs_ro
+                .create(
+                    ROOT_INODE,
+                    &file2,
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await;
+            assert!(matches!(create_file_result, Err(FsError::ReadOnly)));
+            // Test renaming the file
+            let new_file = SecretString::from_str("file1").unwrap();
+            let rename_result = fs_ro
+                .rename(ROOT_INODE, &file1, ROOT_INODE, &new_file)
+                .await;
+            assert!(matches!(rename_result, Err(FsError::ReadOnly)));
+            // Test removing a file
+            let remove_file_result = fs_ro.remove_file(ROOT_INODE, &file1).await;
+            assert!(matches!(remove_file_result, Err(FsError::ReadOnly)));
+            // Test copy file range
+            let file_range_req = CopyFileRangeReq::builder()
+                .src_ino(attr.ino)
                 .src_offset(0)
                 .dest_ino(attr_dest.ino)
                 .dest_offset(0)
@@ -2471,8 +5239,7 @@ async fn test_read_only_write() {
             let set_attr_result = fs_ro.set_attr(attr.ino, set_attr).await;
             assert!(matches!(set_attr_result, Err(FsError::ReadOnly)));
             // Test writing to file with Read Only enabled.
-            let write_all_strings_result =
-                crate::encryptedfs::write_all_string_to_fs(&fs_ro, attr.ino, 0, data, fh).await;
+            let write_all_strings_result = fs_ro.write_all(attr.ino, 0, data.as_bytes(), fh).await;
             assert!(matches!(write_all_strings_result, Err(FsError::ReadOnly)));
             // Test flushing data to file
             let flush_result = fs_ro.flush(fh).await;
@@ -2481,3 +5248,7475 @@ async fn test_read_only_write() {
     )
     .await;
 }
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_change_cipher() {
+    run_test(
+        TestSetup {
+            key: "test_change_cipher",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let Some(data_dir) = local_data_dir(&fs) else {
+                return;
+            };
+            let password = SecretString::from_str("password").unwrap();
+
+            let test_dir = SecretString::from_str("test-dir").unwrap();
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_dir,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, file_attr) = fs
+                .create(
+                    dir_attr.ino,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            // a few blocks
+            let data: Vec<u8> = (0..BLOCK_SIZE * 10 + 7).map(|i| (i % 251) as u8).collect();
+            fs.write_all(file_attr.ino, 0, &data, fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            fs.set_dir_quota(dir_attr.ino, Some(1 << 20)).await.unwrap();
+
+            // refused while in use
+            assert!(matches!(
+                EncryptedFs::change_cipher(
+                    &data_dir,
+                    password.clone(),
+                    Cipher::ChaCha20Poly1305,
+                    Cipher::Aes256Gcm,
+                    &OpControl::default(),
+                )
+                .await,
+                Err(FsError::AlreadyMounted { .. })
+            ));
+            drop(fs);
+            drop(take_fs().await);
+
+            // interrupted after a few items
+            let interrupt =
+                OpControl::new().with_progress(|done, _| assert!(done < 3, "interrupted"));
+            let interrupted = AssertUnwindSafe(EncryptedFs::change_cipher(
+                &data_dir,
+                password.clone(),
+                Cipher::ChaCha20Poly1305,
+                Cipher::Aes256Gcm,
+                &interrupt,
+            ))
+            .catch_unwind()
+            .await;
+            assert!(interrupted.is_err());
+
+            // and cancelled after a few more
+            let cancel = OpControl::new();
+            let control = cancel.clone().with_progress(move |done, _| {
+                if done == 4 {
+                    cancel.cancel();
+                }
+            });
+            assert!(matches!(
+                EncryptedFs::change_cipher(
+                    &data_dir,
+                    password.clone(),
+                    Cipher::ChaCha20Poly1305,
+                    Cipher::Aes256Gcm,
+                    &control,
+                )
+                .await,
+                Err(FsError::Cancelled)
+            ));
+            assert!(matches!(
+                EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    false,
+                    FsOptions::default(),
+                )
+                .await,
+                Err(FsError::CipherChangeInterrupted)
+            ));
+
+            // continue it
+            let progress = Arc::new(std::sync::Mutex::new(vec![]));
+            let progress_clone = progress.clone();
+            EncryptedFs::change_cipher(
+                &data_dir,
+                password.clone(),
+                Cipher::ChaCha20Poly1305,
+                Cipher::Aes256Gcm,
+                &OpControl::new().with_progress(move |done, total| {
+                    progress_clone.lock().unwrap().push((done, total));
+                }),
+            )
+            .await
+            .unwrap();
+            let progress = progress.lock().unwrap().clone();
+            // 3 inodes, the contents of the 2 directories and the file, the change log, the quota and the key
+            assert_eq!((1..=9).map(|done| (done, 9)).collect::<Vec<_>>(), progress);
+
+            let fs = EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::Aes256Gcm,
+                false,
+                FsOptions::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                dir_attr.ino,
+                fs.find_by_name(ROOT_INODE, &test_dir)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+            assert_eq!(
+                file_attr.ino,
+                fs.find_by_name(dir_attr.ino, &test_file)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+            let names: Vec<_> = fs
+                .read_dir(dir_attr.ino)
+                .await
+                .unwrap()
+                .map(|entry| entry.unwrap().name.expose_secret().clone())
+                .collect();
+            assert!(names.contains(&"test-file".to_string()));
+            let fh = fs.open(file_attr.ino, true, false).await.unwrap();
+            let mut buf = vec![0; data.len()];
+            test_common::read_exact(&fs, file_attr.ino, 0, &mut buf, fh).await;
+            assert_eq!(data, buf);
+            fs.release(fh).await.unwrap();
+            assert_eq!(
+                fs.dir_quota(dir_attr.ino).await.unwrap(),
+                Some(DirQuota {
+                    limit: 1 << 20,
+                    used: data.len() as u64
+                })
+            );
+            assert!(fs.check(false).await.unwrap().is_clean());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_password_slots() {
+    run_test(
+        TestSetup {
+            key: "test_password_slots",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let Some(data_dir) = local_data_dir(&fs) else {
+                return;
+            };
+            let key_path = Path::new(SECURITY_DIR).join(KEY_ENC_FILENAME);
+            let cipher = fs.cipher;
+            let key = fs.key.get().await.unwrap();
+            let password = SecretString::from_str("password").unwrap();
+            let recovery = SecretString::from_str("recovery").unwrap();
+            let daily = SecretString::from_str("daily").unwrap();
+            let opens = async |password: &SecretString| {
+                read_or_create_key(&*fs.storage, &key_path, password, cipher)
+                    .await
+                    .map(|k| *k.expose_secret() == *key.expose_secret())
+            };
+
+            EncryptedFs::add_password_slot(&data_dir, password.clone(), recovery.clone(), cipher)
+                .await
+                .unwrap();
+            assert!(opens(&password).await.unwrap());
+            assert!(opens(&recovery).await.unwrap());
+            assert!(matches!(opens(&daily).await, Err(FsError::InvalidPassword)));
+            assert!(matches!(
+                EncryptedFs::add_password_slot(&data_dir, daily.clone(), daily.clone(), cipher)
+                    .await,
+                Err(FsError::InvalidPassword)
+            ));
+
+            // replaces only the slot of the old password
+            EncryptedFs::passwd(&data_dir, password.clone(), daily.clone(), cipher)
+                .await
+                .unwrap();
+            assert!(matches!(
+                opens(&password).await,
+                Err(FsError::InvalidPassword)
+            ));
+            assert!(opens(&recovery).await.unwrap());
+            assert!(opens(&daily).await.unwrap());
+
+            EncryptedFs::remove_password_slot(&data_dir, recovery.clone(), cipher)
+                .await
+                .unwrap();
+            assert!(matches!(
+                opens(&recovery).await,
+                Err(FsError::InvalidPassword)
+            ));
+            assert!(opens(&daily).await.unwrap());
+            assert!(matches!(
+                EncryptedFs::remove_password_slot(&data_dir, daily.clone(), cipher).await,
+                Err(FsError::InvalidInput { .. })
+            ));
+            assert!(opens(&daily).await.unwrap());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_password_slots_upgrade_single_password_key() {
+    run_test(
+        TestSetup {
+            key: "test_password_slots_upgrade_single_password_key",
+            read_only: false,
+        },
+        async {
+            let fs = take_fs().await;
+            let Some(data_dir) = local_data_dir(&fs) else {
+                return;
+            };
+            let storage = fs.storage.clone();
+            let security_dir = data_dir.join(SECURITY_DIR);
+            let key_file = security_dir.join(KEY_ENC_FILENAME);
+            let key_path = Path::new(SECURITY_DIR).join(KEY_ENC_FILENAME);
+            let cipher = fs.cipher;
+            let key = fs.key.get().await.unwrap();
+            let password = SecretString::from_str("password").unwrap();
+            drop(fs);
+
+            // the key file before the slots, encrypted with one password and the salt next to it
+            let salt = vec![7; 16];
+            bincode::serialize_into(
+                File::create(security_dir.join(KEY_SALT_FILENAME)).unwrap(),
+                &salt,
+            )
+            .unwrap();
+            let derived_key = crypto::derive_key(&password, cipher, &salt).unwrap();
+            let mut writer =
+                crypto::create_write(File::create(&key_file).unwrap(), cipher, &derived_key);
+            bincode::serialize_into(&mut writer, &*key.expose_secret()).unwrap();
+            writer.finish().unwrap();
+            assert!(key_slots::is_legacy_key_file(&*storage, &key_path)
+                .await
+                .unwrap());
+
+            let fs = EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                cipher,
+                false,
+                FsOptions::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                *key.expose_secret(),
+                *fs.key.get().await.unwrap().expose_secret()
+            );
+            drop(fs);
+
+            let new_password = SecretString::from_str("new-password").unwrap();
+            EncryptedFs::passwd(&data_dir, password, new_password.clone(), cipher)
+                .await
+                .unwrap();
+            assert!(!key_slots::is_legacy_key_file(&*storage, &key_path)
+                .await
+                .unwrap());
+            assert!(!security_dir.join(KEY_SALT_FILENAME).exists());
+            assert_eq!(
+                *key.expose_secret(),
+                *read_or_create_key(&*storage, &key_path, &new_password, cipher)
+                    .await
+                    .unwrap()
+                    .expose_secret()
+            );
+        },
+    )
+    .await;
+}
+
+/// Provides the password only while `available`.
+struct ToggledPasswordProvider {
+    available: Arc<AtomicBool>,
+}
+
+impl PasswordProvider for ToggledPasswordProvider {
+    fn get_password(&self) -> Option<SecretString> {
+        self.available
+            .load(Ordering::SeqCst)
+            .then(|| SecretString::from_str("password").unwrap())
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_lock_unlock() {
+    run_test(
+        TestSetup {
+            key: "test_lock_unlock",
+            read_only: false,
+        },
+        async {
+            let storage = take_fs().await.storage.clone();
+            let available = Arc::new(AtomicBool::new(true));
+            let fs = EncryptedFs::with_storage(
+                storage,
+                Box::new(ToggledPasswordProvider {
+                    available: available.clone(),
+                }),
+                Cipher::ChaCha20Poly1305,
+                false,
+                FsOptions::default(),
+            )
+            .await
+            .unwrap();
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data: Vec<u8> = (0..BLOCK_SIZE * 2 + 7).map(|i| (i % 251) as u8).collect();
+            fs.write_all(attr.ino, 0, &data, fh).await.unwrap();
+            let read_fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![0; 10];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, read_fh).await;
+            assert_eq!(data[..10], buf);
+
+            // the pending data of the writer is written
+            fs.lock().await.unwrap();
+            available.store(false, Ordering::SeqCst);
+            assert_eq!(data.len() as u64, fs.get_attr(attr.ino).await.unwrap().size);
+            assert!(matches!(
+                fs.read(attr.ino, 10, &mut buf, read_fh).await,
+                Err(FsError::Locked)
+            ));
+            assert!(matches!(
+                fs.write(attr.ino, 0, b"test", fh).await,
+                Err(FsError::Locked)
+            ));
+            assert!(matches!(fs.unlock().await, Err(FsError::Locked)));
+
+            // the same handles continue
+            available.store(true, Ordering::SeqCst);
+            fs.unlock().await.unwrap();
+            test_common::read_exact(&fs, attr.ino, 10, &mut buf, read_fh).await;
+            assert_eq!(data[10..20], buf);
+            fs.write(attr.ino, 0, b"test", fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            fs.release(read_fh).await.unwrap();
+
+            // resumed on use, without `unlock`
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            fs.lock().await.unwrap();
+            let mut buf = vec![0; data.len()];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+            assert_eq!(b"test", &buf[..4]);
+            assert_eq!(data[4..], buf[4..]);
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+/// Waits for the password, like when it comes from a secrets service.
+struct SlowPasswordProvider {}
+
+#[async_trait]
+impl AsyncPasswordProvider for SlowPasswordProvider {
+    async fn get_password(&self) -> Option<SecretString> {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        Some(SecretString::from_str("password").unwrap())
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_async_password_provider() {
+    run_test(
+        TestSetup {
+            key: "test_async_password_provider",
+            read_only: false,
+        },
+        async {
+            let storage = take_fs().await.storage.clone();
+            let fs = EncryptedFs::with_storage(
+                storage,
+                Box::new(SlowPasswordProvider {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+                FsOptions::default(),
+            )
+            .await
+            .unwrap();
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.write_all(attr.ino, 0, b"test-42", fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            // asked again after the key was removed
+            fs.lock().await.unwrap();
+            assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs).await);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_verify_password() {
+    run_test(
+        TestSetup {
+            key: "test_verify_password",
+            read_only: false,
+        },
+        async {
+            let Some(data_dir) = local_data_dir(&*get_fs().await) else {
+                return;
+            };
+            let cipher = Cipher::ChaCha20Poly1305;
+
+            EncryptedFs::verify_password(
+                &data_dir,
+                SecretString::from_str("password").unwrap(),
+                cipher,
+            )
+            .await
+            .unwrap();
+            assert!(
+                EncryptedFs::verify_password_timed(
+                    &data_dir,
+                    SecretString::from_str("password").unwrap(),
+                    cipher,
+                )
+                .await
+                .unwrap()
+                .as_nanos()
+                    > 0
+            );
+            assert!(matches!(
+                EncryptedFs::verify_password(
+                    &data_dir,
+                    SecretString::from_str("wrong").unwrap(),
+                    cipher,
+                )
+                .await,
+                Err(FsError::InvalidPassword)
+            ));
+
+            // nothing is created for a missing data dir
+            let missing = data_dir.join("missing");
+            assert!(matches!(
+                EncryptedFs::verify_password(
+                    &missing,
+                    SecretString::from_str("password").unwrap(),
+                    cipher,
+                )
+                .await,
+                Err(FsError::InvalidDataDirStructure)
+            ));
+            assert!(!missing.exists());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_single_instance_lock() {
+    run_test(
+        TestSetup {
+            key: "test_single_instance_lock",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let Some(data_dir) = local_data_dir(&fs) else {
+                return;
+            };
+            let open = |read_only| {
+                EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    read_only,
+                    FsOptions::default(),
+                )
+            };
+
+            // the holder is reported for read-write and read-only instances
+            let pid = Some(std::process::id());
+            assert!(matches!(
+                open(false).await,
+                Err(FsError::AlreadyMounted { pid: holder }) if holder == pid
+            ));
+            assert!(matches!(
+                open(true).await,
+                Err(FsError::AlreadyMounted { pid: holder }) if holder == pid
+            ));
+            drop(fs);
+
+            // released on drop, readers can use it together
+            drop(take_fs().await);
+            let fs_ro = open(true).await.unwrap();
+            let fs_ro2 = open(true).await.unwrap();
+            assert!(matches!(
+                open(false).await,
+                Err(FsError::AlreadyMounted { pid: None })
+            ));
+            drop(fs_ro);
+            drop(fs_ro2);
+
+            // a lock file left by a crash doesn't hold the lock
+            fs::write(
+                data_dir.join(SECURITY_DIR).join(INSTANCE_LOCK_FILENAME),
+                "4242",
+            )
+            .unwrap();
+            let _fs = open(false).await.unwrap();
+            assert_eq!(
+                std::process::id().to_string(),
+                fs::read_to_string(data_dir.join(SECURITY_DIR).join(INSTANCE_LOCK_FILENAME))
+                    .unwrap()
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_close_all_on_drop() {
+    run_test(
+        TestSetup {
+            key: "test_close_all_on_drop",
+            read_only: false,
+        },
+        async {
+            let fs = take_fs().await;
+            let storage = fs.storage.clone();
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            // not a multiple of block size, so the last block is only in the writer buffer
+            let data: Vec<u8> = (0..BLOCK_SIZE * 2 + 42).map(|i| (i % 251) as u8).collect();
+            fs.write_all(attr.ino, 0, &data, fh).await.unwrap();
+            let _fh_read = fs.open(attr.ino, true, false).await.unwrap();
+            drop(fs);
+
+            let fs = open_storage_with(storage, FsOptions::default()).await;
+            assert_eq!(data.len() as u64, fs.get_attr(attr.ino).await.unwrap().size);
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![0; data.len()];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+            assert_eq!(data, buf);
+
+            // handles are not valid after closing
+            fs.close_all().await.unwrap();
+            assert!(matches!(
+                fs.read(attr.ino, 0, &mut buf, fh).await,
+                Err(FsError::InvalidFileHandle)
+            ));
+            assert!(fs.opened_files_for_read.is_empty().await);
+        },
+    )
+    .await;
+}
+
+/// Counts the syncs made through it, including the durable [`Storage::write`]s and the syncs of the opened files,
+/// and the writes of each key. It also keeps what the files removed or replaced by a rename had at that time.
+struct CountingStorage {
+    inner: Arc<dyn Storage>,
+    syncs: Arc<AtomicUsize>,
+    // the files opened to write
+    opens: AtomicUsize,
+    writes: std::sync::Mutex<HashMap<PathBuf, usize>>,
+    removed: std::sync::Mutex<HashMap<PathBuf, Vec<u8>>>,
+}
+
+impl CountingStorage {
+    fn syncs(&self) -> usize {
+        self.syncs.load(Ordering::SeqCst)
+    }
+
+    fn opens(&self) -> usize {
+        self.opens.load(Ordering::SeqCst)
+    }
+
+    fn writes(&self, key: &Path) -> usize {
+        self.writes.lock().unwrap().get(key).copied().unwrap_or(0)
+    }
+
+    fn count_write(&self, key: &Path) {
+        *self
+            .writes
+            .lock()
+            .unwrap()
+            .entry(key.to_path_buf())
+            .or_default() += 1;
+    }
+
+    /// What the file at `key` had when it was last removed or replaced.
+    fn removed(&self, key: &Path) -> Option<Vec<u8>> {
+        self.removed.lock().unwrap().get(key).cloned()
+    }
+
+    async fn keep_removed(&self, key: &Path) {
+        if self.inner.kind(key).await.unwrap() == Some(EntryKind::File) {
+            let data = self.inner.read(key).await.unwrap();
+            self.removed.lock().unwrap().insert(key.to_path_buf(), data);
+        }
+    }
+
+    fn wrap(&self, file: Box<dyn StorageFile>) -> Box<dyn StorageFile> {
+        Box::new(SyncCountingFile {
+            inner: file,
+            syncs: self.syncs.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl Storage for CountingStorage {
+    async fn open(&self, key: &Path, write: bool) -> io::Result<Box<dyn StorageFile>> {
+        if write {
+            self.opens.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(self.wrap(self.inner.open(key, write).await?))
+    }
+
+    async fn create(&self, key: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(self.wrap(self.inner.create(key).await?))
+    }
+
+    async fn create_new(&self, key: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(self.wrap(self.inner.create_new(key).await?))
+    }
+
+    async fn write(&self, key: &Path, data: &[u8]) -> io::Result<()> {
+        self.syncs.fetch_add(1, Ordering::SeqCst);
+        self.count_write(key);
+        self.inner.write(key, data).await
+    }
+
+    async fn write_unsynced(&self, key: &Path, data: &[u8]) -> io::Result<()> {
+        self.count_write(key);
+        self.inner.write_unsynced(key, data).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.keep_removed(to).await;
+        self.inner.rename(from, to).await
+    }
+
+    async fn remove(&self, key: &Path) -> io::Result<()> {
+        self.keep_removed(key).await;
+        self.inner.remove(key).await
+    }
+
+    async fn create_dir(&self, key: &Path) -> io::Result<()> {
+        self.inner.create_dir(key).await
+    }
+
+    async fn remove_dir(&self, key: &Path) -> io::Result<()> {
+        self.inner.remove_dir(key).await
+    }
+
+    async fn list(&self, key: &Path) -> io::Result<Vec<StorageEntry>> {
+        self.inner.list(key).await
+    }
+
+    async fn kind(&self, key: &Path) -> io::Result<Option<EntryKind>> {
+        self.inner.kind(key).await
+    }
+
+    async fn sync(&self, key: &Path) -> io::Result<()> {
+        self.syncs.fetch_add(1, Ordering::SeqCst);
+        self.inner.sync(key).await
+    }
+
+    async fn statvfs(&self) -> io::Result<StatVfs> {
+        self.inner.statvfs().await
+    }
+
+    fn local_path(&self) -> Option<&Path> {
+        self.inner.local_path()
+    }
+}
+
+struct SyncCountingFile {
+    inner: Box<dyn StorageFile>,
+    syncs: Arc<AtomicUsize>,
+}
+
+impl Read for SyncCountingFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl io::Write for SyncCountingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl io::Seek for SyncCountingFile {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl StorageFile for SyncCountingFile {
+    fn len(&self) -> io::Result<u64> {
+        self.inner.len()
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        self.inner.set_len(size)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.syncs.fetch_add(1, Ordering::SeqCst);
+        self.inner.sync_all()
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.syncs.fetch_add(1, Ordering::SeqCst);
+        self.inner.sync_data()
+    }
+}
+
+/// Open the storage of the test setup with `durability`, counting the syncs.
+async fn open_counting(options: FsOptions) -> (Arc<EncryptedFs>, Arc<CountingStorage>) {
+    let storage = Arc::new(CountingStorage {
+        inner: take_fs().await.storage.clone(),
+        syncs: Arc::new(AtomicUsize::new(0)),
+        opens: AtomicUsize::new(0),
+        writes: std::sync::Mutex::new(HashMap::new()),
+        removed: std::sync::Mutex::new(HashMap::new()),
+    });
+    let fs = open_storage_with(storage.clone(), options).await;
+    (fs, storage)
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_durability_always() {
+    run_test(
+        TestSetup {
+            key: "test_durability_always",
+            read_only: false,
+        },
+        async {
+            let (fs, storage) = open_counting(
+                FsOptions::builder()
+                    .durability(DurabilityPolicy::Always)
+                    .build(),
+            )
+            .await;
+
+            let before = storage.syncs();
+            // created and written with the same handle, so the syncs of the creation are still pending
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.write_all(attr.ino, 0, b"test-42", fh).await.unwrap();
+            // the contents, its parent, the inode and both directory entries
+            assert!(storage.syncs() - before >= 5);
+            let before = storage.syncs();
+            fs.release(fh).await.unwrap();
+            assert!(storage.syncs() > before);
+            assert!(fs.pending_syncs.lock().await.is_empty());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_durability_on_release() {
+    run_test(
+        TestSetup {
+            key: "test_durability_on_release",
+            read_only: false,
+        },
+        async {
+            let (fs, storage) = open_counting(
+                FsOptions::builder()
+                    .durability(DurabilityPolicy::OnRelease)
+                    .build(),
+            )
+            .await;
+
+            let before = storage.syncs();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.write_all(attr.ino, 0, b"test-42", fh).await.unwrap();
+            assert_eq!(before, storage.syncs());
+            assert!(!fs.pending_syncs.lock().await.is_empty());
+            fs.release(fh).await.unwrap();
+            assert!(storage.syncs() > before);
+            assert!(fs.pending_syncs.lock().await.is_empty());
+
+            // synced by fsync too
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file-2").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.write_all(attr.ino, 0, b"test-42", fh).await.unwrap();
+            assert!(!fs.pending_syncs.lock().await.is_empty());
+            fs.fsync(fh, false).await.unwrap();
+            assert!(fs.pending_syncs.lock().await.is_empty());
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_durability_never() {
+    run_test(
+        TestSetup {
+            key: "test_durability_never",
+            read_only: false,
+        },
+        async {
+            let (fs, storage) = open_counting(
+                FsOptions::builder()
+                    .durability(DurabilityPolicy::Never)
+                    .build(),
+            )
+            .await;
+
+            let before = storage.syncs();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.write_all(attr.ino, 0, b"test-42", fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(before, storage.syncs());
+            assert!(fs.pending_syncs.lock().await.is_empty());
+
+            // still synced when asked for
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file-2").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.write_all(attr.ino, 0, b"test-42", fh).await.unwrap();
+            fs.fsync(fh, false).await.unwrap();
+            assert!(storage.syncs() > before);
+            fs.release(fh).await.unwrap();
+
+            // nothing lost while running
+            let attr = fs
+                .find_by_name(ROOT_INODE, &SecretString::from_str("test-file").unwrap())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs).await);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_dir_times_coalesced() {
+    run_test(
+        TestSetup {
+            key: "test_dir_times_coalesced",
+            read_only: false,
+        },
+        async {
+            let (fs, storage) = open_counting(
+                FsOptions::builder()
+                    .dir_times_flush_interval(Duration::from_secs(3600))
+                    .build(),
+            )
+            .await;
+            let root_key = shard_path(Path::new(INODES_DIR), ROOT_INODE);
+            let before = storage.writes(&root_key);
+            let mtime = fs.get_attr(ROOT_INODE).await.unwrap().mtime;
+
+            let mut handles = vec![];
+            for i in 0..1000 {
+                let (fh, _) = fs
+                    .create(
+                        ROOT_INODE,
+                        &SecretString::from_str(&format!("file-{i}")).unwrap(),
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                handles.push(fh);
+            }
+            let flushed = storage.writes(&root_key) - before;
+            assert!(flushed < 100, "root inode written {flushed} times");
+            // written on release
+            for fh in handles {
+                fs.release(fh).await.unwrap();
+            }
+            assert!(fs.pending_dir_times.lock().await.is_empty());
+
+            // merged before being written
+            let before = storage.writes(&root_key);
+            fs.remove_file(ROOT_INODE, &SecretString::from_str("file-0").unwrap())
+                .await
+                .unwrap();
+            assert_eq!(before, storage.writes(&root_key));
+            let attr = fs.get_attr(ROOT_INODE).await.unwrap();
+            assert!(attr.mtime > mtime);
+
+            // written on statfs
+            fs.statfs().await.unwrap();
+            assert_eq!(before + 1, storage.writes(&root_key));
+            assert!(fs.pending_dir_times.lock().await.is_empty());
+            assert_eq!(attr.mtime, fs.get_attr(ROOT_INODE).await.unwrap().mtime);
+        },
+    )
+    .await;
+}
+
+/// A [`Storage`] on a slow disk, each call waits `delay` on the blocking threads first.
+struct SlowStorage {
+    inner: Arc<dyn Storage>,
+    delay: Duration,
+    /// Only wait while set, to fill the storage fast first.
+    slow: AtomicBool,
+    in_flight: AtomicUsize,
+    /// The most calls waiting at once.
+    peak: AtomicUsize,
+}
+
+impl SlowStorage {
+    fn new(inner: Arc<dyn Storage>, delay: Duration) -> Self {
+        Self {
+            inner,
+            delay,
+            slow: AtomicBool::new(true),
+            in_flight: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        }
+    }
+
+    async fn wait(&self) {
+        if !self.slow.load(Ordering::SeqCst) {
+            return;
+        }
+        let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak.fetch_max(in_flight, Ordering::SeqCst);
+        let delay = self.delay;
+        tokio::task::spawn_blocking(move || std::thread::sleep(delay))
+            .await
+            .unwrap();
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[async_trait]
+impl Storage for SlowStorage {
+    async fn open(&self, key: &Path, write: bool) -> io::Result<Box<dyn StorageFile>> {
+        self.wait().await;
+        self.inner.open(key, write).await
+    }
+
+    async fn create(&self, key: &Path) -> io::Result<Box<dyn StorageFile>> {
+        self.wait().await;
+        self.inner.create(key).await
+    }
+
+    async fn create_new(&self, key: &Path) -> io::Result<Box<dyn StorageFile>> {
+        self.wait().await;
+        self.inner.create_new(key).await
+    }
+
+    async fn read(&self, key: &Path) -> io::Result<Vec<u8>> {
+        self.wait().await;
+        self.inner.read(key).await
+    }
+
+    async fn write(&self, key: &Path, data: &[u8]) -> io::Result<()> {
+        self.wait().await;
+        self.inner.write(key, data).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.wait().await;
+        self.inner.rename(from, to).await
+    }
+
+    async fn remove(&self, key: &Path) -> io::Result<()> {
+        self.wait().await;
+        self.inner.remove(key).await
+    }
+
+    async fn create_dir(&self, key: &Path) -> io::Result<()> {
+        self.wait().await;
+        self.inner.create_dir(key).await
+    }
+
+    async fn remove_dir(&self, key: &Path) -> io::Result<()> {
+        self.wait().await;
+        self.inner.remove_dir(key).await
+    }
+
+    async fn list(&self, key: &Path) -> io::Result<Vec<StorageEntry>> {
+        self.wait().await;
+        self.inner.list(key).await
+    }
+
+    async fn kind(&self, key: &Path) -> io::Result<Option<EntryKind>> {
+        self.wait().await;
+        self.inner.kind(key).await
+    }
+
+    async fn sync(&self, key: &Path) -> io::Result<()> {
+        self.wait().await;
+        self.inner.sync(key).await
+    }
+
+    async fn statvfs(&self) -> io::Result<StatVfs> {
+        self.inner.statvfs().await
+    }
+
+    fn local_path(&self) -> Option<&Path> {
+        self.inner.local_path()
+    }
+}
+
+/// Create, look up, list and remove files from many tasks at once on a slow disk, while checking the runtime
+/// keeps running other tasks.
+async fn stress_metadata_ops(options: FsOptions) {
+    let storage = Arc::new(SlowStorage::new(
+        take_fs().await.storage.clone(),
+        Duration::from_millis(2),
+    ));
+    let fs = open_storage_with(storage, options).await;
+
+    let ticks = Arc::new(AtomicUsize::new(0));
+    let stop = Arc::new(AtomicBool::new(false));
+    let heartbeat = {
+        let ticks = ticks.clone();
+        let stop = stop.clone();
+        tokio::spawn(async move {
+            while !stop.load(Ordering::SeqCst) {
+                ticks.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+    };
+
+    let mut tasks = vec![];
+    for i in 0..32 {
+        let fs = fs.clone();
+        tasks.push(tokio::spawn(async move {
+            let name = SecretString::from_str(&format!("file-{i}")).unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            assert!(fs.exists_by_name(ROOT_INODE, &name).await.unwrap());
+            let found = fs.find_by_name(ROOT_INODE, &name).await.unwrap().unwrap();
+            assert_eq!(attr.ino, found.ino);
+            assert_eq!(attr.ino, fs.get_attr(attr.ino).await.unwrap().ino);
+            assert!(fs.read_dir(ROOT_INODE).await.unwrap().count() > 0);
+            fs.remove_file(ROOT_INODE, &name).await.unwrap();
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+    stop.store(true, Ordering::SeqCst);
+    heartbeat.await.unwrap();
+
+    // only . and ..
+    assert_eq!(2, fs.read_dir(ROOT_INODE).await.unwrap().count());
+    assert!(ticks.load(Ordering::SeqCst) > 1);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_concurrent_metadata_ops_on_slow_storage() {
+    run_test(
+        TestSetup {
+            key: "test_concurrent_metadata_ops_on_slow_storage",
+            read_only: false,
+        },
+        stress_metadata_ops(FsOptions::default()),
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_runtime_handle() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()
+        .unwrap();
+    run_test(
+        TestSetup {
+            key: "test_runtime_handle",
+            read_only: false,
+        },
+        stress_metadata_ops(
+            FsOptions::builder()
+                .runtime_handle(runtime.handle().clone())
+                .build(),
+        ),
+    )
+    .await;
+    runtime.shutdown_background();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[traced_test]
+async fn test_concurrent_open_release() {
+    run_test(
+        TestSetup {
+            key: "test_concurrent_open_release",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.write_all(attr.ino, 0, b"test-42", fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            let mut tasks = vec![];
+            for i in 0..32 {
+                let fs = fs.clone();
+                tasks.push(tokio::spawn(async move {
+                    for _ in 0..10 {
+                        // some of them also write, so the handles of the readers are reset
+                        let fh = fs.open(attr.ino, true, i % 8 == 0).await.unwrap();
+                        let mut buf = [0; 7];
+                        test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+                        assert_eq!(b"test-42", &buf);
+                        if i % 8 == 0 {
+                            fs.write(attr.ino, 0, b"test-42", fh).await.unwrap();
+                        }
+                        assert_eq!(7, fs.get_attr(attr.ino).await.unwrap().size);
+                        fs.release(fh).await.unwrap();
+                    }
+                }));
+            }
+            for task in tasks {
+                task.await.unwrap();
+            }
+
+            assert!(fs.read_handles.is_empty().await);
+            assert!(fs.write_handles.is_empty().await);
+            assert!(fs.opened_files_for_read.is_empty().await);
+            assert!(fs.opened_files_for_write.is_empty().await);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_dir_entry_caches_invalidated_on_rename() {
+    run_test(
+        TestSetup {
+            key: "test_dir_entry_caches_invalidated_on_rename",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let name_a = SecretString::from_str("a").unwrap();
+            let name_b = SecretString::from_str("b").unwrap();
+            let (fh, attr_a) = fs
+                .create(
+                    ROOT_INODE,
+                    &name_a,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            // fill the caches
+            assert_eq!(3, fs.read_dir(ROOT_INODE).await.unwrap().count());
+            assert_eq!(3, fs.read_dir_plus(ROOT_INODE).await.unwrap().count());
+
+            fs.rename(ROOT_INODE, &name_a, ROOT_INODE, &name_b)
+                .await
+                .unwrap();
+            let (fh, attr_new_a) = fs
+                .create(
+                    ROOT_INODE,
+                    &name_a,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            let entries: HashMap<String, (u64, FileType)> = fs
+                .read_dir(ROOT_INODE)
+                .await
+                .unwrap()
+                .map(|entry| {
+                    let entry = entry.unwrap();
+                    (
+                        entry.name.expose_secret().to_string(),
+                        (entry.ino, entry.kind),
+                    )
+                })
+                .collect();
+            assert_eq!(
+                Some(&(attr_new_a.ino, FileType::Directory)),
+                entries.get("a")
+            );
+            assert_eq!(Some(&(attr_a.ino, FileType::RegularFile)), entries.get("b"));
+
+            let entries: HashMap<String, u64> = fs
+                .read_dir_plus(ROOT_INODE)
+                .await
+                .unwrap()
+                .map(|entry| {
+                    let entry = entry.unwrap();
+                    (entry.name.expose_secret().to_string(), entry.attr.ino)
+                })
+                .collect();
+            assert_eq!(Some(&attr_new_a.ino), entries.get("a"));
+            assert_eq!(Some(&attr_a.ino), entries.get("b"));
+
+            // and after removing it
+            fs.remove_dir(ROOT_INODE, &name_a).await.unwrap();
+            assert!(fs.read_dir(ROOT_INODE).await.unwrap().all(|entry| *entry
+                .unwrap()
+                .name
+                .expose_secret()
+                != "a"));
+        },
+    )
+    .await;
+}
+
+/// Names of the entries of `ino` with their inodes, from `ls`.
+async fn dir_entries(fs: &EncryptedFs, ino: u64) -> HashMap<String, u64> {
+    fs.read_dir(ino)
+        .await
+        .unwrap()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            (entry.name.expose_secret().to_string(), entry.ino)
+        })
+        .collect()
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_rename_replace_file() {
+    run_test(
+        TestSetup {
+            key: "test_rename_replace_file",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let src = SecretString::from_str("src").unwrap();
+            let dest = SecretString::from_str("dest").unwrap();
+            let (fh, src_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &src,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.write_all(src_attr.ino, 0, b"src", fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let (fh, dest_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &dest,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.write_all(dest_attr.ino, 0, b"dest", fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let mtime = fs.get_attr(ROOT_INODE).await.unwrap().mtime;
+
+            fs.rename(ROOT_INODE, &src, ROOT_INODE, &dest)
+                .await
+                .unwrap();
+
+            // only one entry for dest, pointing to src
+            let entries = dir_entries(&fs, ROOT_INODE).await;
+            assert_eq!(3, entries.len());
+            assert_eq!(Some(&src_attr.ino), entries.get("dest"));
+            assert!(!fs.exists_by_name(ROOT_INODE, &src).await.unwrap());
+            assert_eq!(
+                src_attr.ino,
+                fs.find_by_name(ROOT_INODE, &dest)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+            assert_eq!("src", test_common::read_to_string(src_attr.ino, &fs).await);
+            // the replaced file is removed
+            assert!(!fs.exists(dest_attr.ino).await);
+            assert_eq!(kind(&fs, fs.contents_path(dest_attr.ino)).await, None);
+            // the times of the parent are updated
+            assert!(fs.get_attr(ROOT_INODE).await.unwrap().mtime > mtime);
+
+            // a file can't replace a directory
+            let dir = SecretString::from_str("dir").unwrap();
+            fs.create(
+                ROOT_INODE,
+                &dir,
+                create_attr(FileType::Directory),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+            assert!(matches!(
+                fs.rename(ROOT_INODE, &dest, ROOT_INODE, &dir).await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_rename_replace_file_with_links() {
+    run_test(
+        TestSetup {
+            key: "test_rename_replace_file_with_links",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let src = SecretString::from_str("src").unwrap();
+            let dest = SecretString::from_str("dest").unwrap();
+            let link = SecretString::from_str("link").unwrap();
+            fs.create(
+                ROOT_INODE,
+                &src,
+                create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+            let (_, dest_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &dest,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.link(dest_attr.ino, ROOT_INODE, &link).await.unwrap();
+
+            fs.rename(ROOT_INODE, &src, ROOT_INODE, &dest)
+                .await
+                .unwrap();
+
+            // still reachable from the other link
+            let attr = fs.get_attr(dest_attr.ino).await.unwrap();
+            assert_eq!(1, attr.nlink);
+            assert_eq!(
+                Some(&dest_attr.ino),
+                dir_entries(&fs, ROOT_INODE).await.get("link")
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_rename_replace_empty_dir() {
+    run_test(
+        TestSetup {
+            key: "test_rename_replace_empty_dir",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let src = SecretString::from_str("src").unwrap();
+            let dest = SecretString::from_str("dest").unwrap();
+            let (_, src_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &src,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (_, dest_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &dest,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (_, child_attr) = fs
+                .create(
+                    dest_attr.ino,
+                    &SecretString::from_str("child").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+
+            // not empty
+            assert!(matches!(
+                fs.rename(ROOT_INODE, &src, ROOT_INODE, &dest).await,
+                Err(FsError::NotEmpty)
+            ));
+            fs.remove_file(dest_attr.ino, &SecretString::from_str("child").unwrap())
+                .await
+                .unwrap();
+            assert!(!fs.exists(child_attr.ino).await);
+
+            fs.rename(ROOT_INODE, &src, ROOT_INODE, &dest)
+                .await
+                .unwrap();
+
+            let entries = dir_entries(&fs, ROOT_INODE).await;
+            assert_eq!(3, entries.len());
+            assert_eq!(Some(&src_attr.ino), entries.get("dest"));
+            assert!(!fs.exists(dest_attr.ino).await);
+            assert_eq!(kind(&fs, fs.contents_path(dest_attr.ino)).await, None);
+            // a directory can't replace a file
+            let file = SecretString::from_str("file").unwrap();
+            fs.create(
+                ROOT_INODE,
+                &file,
+                create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+            assert!(matches!(
+                fs.rename(ROOT_INODE, &dest, ROOT_INODE, &file).await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_rename_same_name() {
+    run_test(
+        TestSetup {
+            key: "test_rename_same_name",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let name = SecretString::from_str("file").unwrap();
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let ctime = fs.get_attr(attr.ino).await.unwrap().ctime;
+
+            fs.rename(ROOT_INODE, &name, ROOT_INODE, &name)
+                .await
+                .unwrap();
+
+            let entries = dir_entries(&fs, ROOT_INODE).await;
+            assert_eq!(3, entries.len());
+            assert_eq!(Some(&attr.ino), entries.get("file"));
+            let attr = fs.get_attr(attr.ino).await.unwrap();
+            assert_eq!(1, attr.nlink);
+            assert_eq!(ctime, attr.ctime);
+        },
+    )
+    .await;
+}
+
+/// A [`Storage`] which crashes after some changes, all the changes after fail like nothing was written anymore.
+struct CrashingStorage {
+    inner: Arc<dyn Storage>,
+    // changes left before crashing
+    left: AtomicUsize,
+}
+
+impl CrashingStorage {
+    fn change(&self) -> io::Result<()> {
+        self.left
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                left.checked_sub(1)
+            })
+            .map(|_| ())
+            .map_err(|_| io::Error::other("crashed"))
+    }
+}
+
+#[async_trait]
+impl Storage for CrashingStorage {
+    async fn open(&self, key: &Path, write: bool) -> io::Result<Box<dyn StorageFile>> {
+        if write {
+            self.change()?;
+        }
+        self.inner.open(key, write).await
+    }
+
+    async fn create(&self, key: &Path) -> io::Result<Box<dyn StorageFile>> {
+        self.change()?;
+        self.inner.create(key).await
+    }
+
+    async fn create_new(&self, key: &Path) -> io::Result<Box<dyn StorageFile>> {
+        self.change()?;
+        self.inner.create_new(key).await
+    }
+
+    async fn write(&self, key: &Path, data: &[u8]) -> io::Result<()> {
+        self.change()?;
+        self.inner.write(key, data).await
+    }
+
+    async fn write_unsynced(&self, key: &Path, data: &[u8]) -> io::Result<()> {
+        self.change()?;
+        self.inner.write_unsynced(key, data).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.change()?;
+        self.inner.rename(from, to).await
+    }
+
+    async fn remove(&self, key: &Path) -> io::Result<()> {
+        self.change()?;
+        self.inner.remove(key).await
+    }
+
+    async fn create_dir(&self, key: &Path) -> io::Result<()> {
+        self.change()?;
+        self.inner.create_dir(key).await
+    }
+
+    async fn remove_dir(&self, key: &Path) -> io::Result<()> {
+        self.change()?;
+        self.inner.remove_dir(key).await
+    }
+
+    async fn list(&self, key: &Path) -> io::Result<Vec<StorageEntry>> {
+        self.inner.list(key).await
+    }
+
+    async fn kind(&self, key: &Path) -> io::Result<Option<EntryKind>> {
+        self.inner.kind(key).await
+    }
+
+    async fn sync(&self, key: &Path) -> io::Result<()> {
+        self.inner.sync(key).await
+    }
+
+    async fn statvfs(&self) -> io::Result<StatVfs> {
+        self.inner.statvfs().await
+    }
+
+    fn local_path(&self) -> Option<&Path> {
+        self.inner.local_path()
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_rename_crash_recovery() {
+    run_test(
+        TestSetup {
+            key: "test_rename_crash_recovery",
+            read_only: false,
+        },
+        async {
+            let src = SecretString::from_str("src").unwrap();
+            let dest = SecretString::from_str("dest").unwrap();
+            let sub = SecretString::from_str("sub").unwrap();
+            let dir = SecretString::from_str("dir").unwrap();
+            // crash after each change the renames make, until they complete
+            for crash_after in 0.. {
+                let storage: Arc<dyn Storage> = Arc::new(InMemoryStorage::new());
+                let fs = open_storage_with(storage.clone(), FsOptions::default()).await;
+                let (fh, src_attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &src,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                fs.write_all(src_attr.ino, 0, b"src", fh).await.unwrap();
+                fs.release(fh).await.unwrap();
+                let (_, dest_attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &dest,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        false,
+                    )
+                    .await
+                    .unwrap();
+                let (_, sub_attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &sub,
+                        create_attr(FileType::Directory),
+                        false,
+                        false,
+                    )
+                    .await
+                    .unwrap();
+                let (_, dir_attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &dir,
+                        create_attr(FileType::Directory),
+                        false,
+                        false,
+                    )
+                    .await
+                    .unwrap();
+                drop(fs);
+
+                let crashing = Arc::new(CrashingStorage {
+                    inner: storage.clone(),
+                    left: AtomicUsize::new(usize::MAX),
+                });
+                let fs = open_storage_with(crashing.clone(), FsOptions::default()).await;
+                crashing.left.store(crash_after, Ordering::SeqCst);
+                let completed = fs.rename(ROOT_INODE, &src, ROOT_INODE, &dest).await.is_ok()
+                    && fs
+                        .rename(ROOT_INODE, &dir, sub_attr.ino, &dir)
+                        .await
+                        .is_ok();
+                drop(fs);
+
+                // completed on open
+                let fs = open_storage_with(storage, FsOptions::default()).await;
+                assert_eq!(Some(EntryKind::Dir), kind(&fs, journal_dir()).await);
+                assert!(fs.storage.list(&journal_dir()).await.unwrap().is_empty());
+                let report = fs.check(false).await.unwrap();
+                assert!(report.is_clean(), "{crash_after}: {report:?}");
+
+                let root = dir_entries(&fs, ROOT_INODE).await;
+                let in_sub = dir_entries(&fs, sub_attr.ino).await;
+                if root.contains_key("src") {
+                    // the journal record was not written
+                    assert_eq!(Some(&dest_attr.ino), root.get("dest"));
+                    assert_eq!(Some(&src_attr.ino), root.get("src"));
+                } else {
+                    assert_eq!(Some(&src_attr.ino), root.get("dest"));
+                    assert!(!fs.exists(dest_attr.ino).await);
+                    assert_eq!("src", test_common::read_to_string(src_attr.ino, &fs).await);
+                }
+                if root.contains_key("dir") {
+                    assert!(!in_sub.contains_key("dir"));
+                } else {
+                    assert_eq!(Some(&dir_attr.ino), in_sub.get("dir"));
+                    assert_eq!(
+                        Some(&sub_attr.ino),
+                        dir_entries(&fs, dir_attr.ino).await.get("..")
+                    );
+                }
+
+                if completed {
+                    break;
+                }
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_dir_tolerant() {
+    run_test(
+        TestSetup {
+            key: "test_read_dir_tolerant",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let good = SecretString::from_str("good").unwrap();
+            let bad = SecretString::from_str("bad").unwrap();
+            let (_, good_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &good,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.create(
+                ROOT_INODE,
+                &bad,
+                create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+
+            // truncate the ls entry of one of them
+            let encrypted_name = fs.entry_encrypted_name(ROOT_INODE, &bad).await.unwrap();
+            let ls_path = fs
+                .contents_path(ROOT_INODE)
+                .join(LS_DIR)
+                .join(&encrypted_name);
+            let data = fs.storage.read(&ls_path).await.unwrap();
+            fs.storage
+                .write(&ls_path, &data[..data.len() / 2])
+                .await
+                .unwrap();
+            fs.clear_dir_entry_caches().await.unwrap();
+
+            // strict listing fails on it
+            let errors = fs
+                .read_dir(ROOT_INODE)
+                .await
+                .unwrap()
+                .filter(Result::is_err)
+                .count();
+            assert_eq!(1, errors);
+
+            let mut names = vec![];
+            let mut corrupted = vec![];
+            for entry in fs.read_dir_tolerant(ROOT_INODE).await.unwrap() {
+                match entry {
+                    DirectoryEntryResult::Entry(entry) => {
+                        names.push((entry.name.expose_secret().to_string(), entry.ino));
+                    }
+                    DirectoryEntryResult::Corrupted { encrypted_name, .. } => {
+                        corrupted.push(encrypted_name);
+                    }
+                }
+            }
+            names.sort();
+            assert_eq!(
+                vec![
+                    (".".to_string(), ROOT_INODE),
+                    ("good".to_string(), good_attr.ino)
+                ],
+                names
+            );
+            assert_eq!(vec![encrypted_name], corrupted);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_fs_error_to_errno() {
+    let join_error = {
+        let handle = tokio::spawn(std::future::pending::<()>());
+        handle.abort();
+        handle.await.unwrap_err()
+    };
+    let errors = vec![
+        (
+            FsError::from(io::Error::from_raw_os_error(libc::ENOSPC)),
+            libc::ENOSPC,
+        ),
+        (FsError::from(io::Error::other("other")), libc::EIO),
+        (
+            FsError::from(bincode::deserialize::<u64>(&[]).unwrap_err()),
+            libc::EIO,
+        ),
+        (FsError::not_found("name"), libc::ENOENT),
+        (FsError::InodeNotFound, libc::ENOENT),
+        (FsError::invalid_input("input"), libc::EINVAL),
+        (FsError::InvalidInodeType, libc::EINVAL),
+        (FsError::InvalidFileHandle, libc::EBADF),
+        (FsError::AlreadyExists, libc::EEXIST),
+        (FsError::NotEmpty, libc::ENOTEMPTY),
+        (FsError::other("other"), libc::EIO),
+        (FsError::InvalidPassword, libc::EACCES),
+        (FsError::InvalidDataDirStructure, libc::EIO),
+        (
+            FsError::from(crypto::Error::from(io::Error::other("crypto"))),
+            libc::EIO,
+        ),
+        (FsError::from(keyring::Error::NoEntry), libc::EIO),
+        (FsError::from("a".parse::<u32>().unwrap_err()), libc::EIO),
+        (FsError::from(join_error), libc::EIO),
+        (FsError::ReadOnly, libc::EROFS),
+        (FsError::UnsupportedVersion(0), libc::EIO),
+        (FsError::OffsetPastEof, libc::ENXIO),
+        (FsError::IntegrityViolation, libc::EIO),
+        (FsError::AlreadyMounted { pid: None }, libc::EBUSY),
+        (FsError::CipherChangeInterrupted, libc::EIO),
+        (FsError::Locked, libc::EACCES),
+        (
+            FsError::Corrupted {
+                what: "inode file".to_owned(),
+                ino: Some(ROOT_INODE),
+            },
+            libc::EIO,
+        ),
+        (FsError::Internal("internal".to_owned()), libc::EIO),
+    ];
+    for (err, errno) in errors {
+        assert_eq!(errno, err.to_errno(), "{err}");
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_corrupted_inode() {
+    run_test(
+        TestSetup {
+            key: "test_corrupted_inode",
+            read_only: false,
+        },
+        async {
+            let fs = take_fs().await;
+            let name = SecretString::from_str("file").unwrap();
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            // like after a partial write
+            fs.storage.write(&fs.ino_file(attr.ino), &[]).await.unwrap();
+            let storage = fs.storage.clone();
+            drop(fs);
+
+            // open again so it's not in the cache
+            let fs = open_storage_with(storage, FsOptions::default()).await;
+            match fs.get_attr(attr.ino).await {
+                Err(FsError::Corrupted { ino, .. }) => assert_eq!(Some(attr.ino), ino),
+                res => panic!("expected corrupted, got {res:?}"),
+            }
+            assert!(matches!(
+                fs.find_by_name(ROOT_INODE, &name).await,
+                Err(FsError::Corrupted { .. })
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_not_found_error_context() {
+    run_test(
+        TestSetup {
+            key: "test_not_found_error_context",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let name = SecretString::from_str("missing").unwrap();
+            let hash = fs.hash_entry_name(ROOT_INODE, &name).await.unwrap();
+            let assert_context = |res: FsResult<()>| match res {
+                Err(FsError::NotFound { what }) => {
+                    assert!(what.contains(&hash), "{what}");
+                    assert!(what.contains(&ROOT_INODE.to_string()), "{what}");
+                }
+                res => panic!("expected not found, got {res:?}"),
+            };
+            assert_context(fs.remove_file(ROOT_INODE, &name).await);
+            assert_context(fs.remove_dir(ROOT_INODE, &name).await);
+            let new_name = SecretString::from_str("new").unwrap();
+            assert_context(fs.rename(ROOT_INODE, &name, ROOT_INODE, &new_name).await);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_dangling_read_handle() {
+    run_test(
+        TestSetup {
+            key: "test_dangling_read_handle",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let name = SecretString::from_str("file").unwrap();
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+
+            // lose the handle but keep it in the opened files
+            fs.read_handles.remove(&fh).await;
+            assert!(fs.opened_files_for_read.contains_key(&attr.ino).await);
+
+            // it's removed instead of failing
+            fs.get_attr(attr.ino).await.unwrap();
+            assert!(!fs.opened_files_for_read.contains_key(&attr.ino).await);
+            assert!(matches!(
+                fs.release(fh).await,
+                Err(FsError::InvalidFileHandle)
+            ));
+
+            // and opening it again works
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert!(!fs.opened_files_for_read.contains_key(&attr.ino).await);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_missing_self_reference() {
+    let fs =
+        EncryptedFs::new_in_memory(Box::new(PasswordProviderImpl {}), Cipher::ChaCha20Poly1305)
+            .await
+            .unwrap();
+    let name = SecretString::from_str("file").unwrap();
+
+    // gone
+    let self_weak = fs.self_weak.lock().unwrap().take();
+    assert!(matches!(
+        fs.read_dir(ROOT_INODE).await,
+        Err(FsError::Internal(_))
+    ));
+    assert!(matches!(
+        fs.read_dir_plus(ROOT_INODE).await,
+        Err(FsError::Internal(_))
+    ));
+    assert!(matches!(
+        fs.create(
+            ROOT_INODE,
+            &name,
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await,
+        Err(FsError::Internal(_))
+    ));
+    *fs.self_weak.lock().unwrap() = self_weak;
+    assert_eq!(1, fs.read_dir(ROOT_INODE).await.unwrap().count());
+
+    // poisoned by a panic while holding the lock
+    let _ = std::thread::spawn({
+        let fs = fs.clone();
+        move || {
+            let _guard = fs.self_weak.lock().unwrap();
+            panic!("poison");
+        }
+    })
+    .join();
+    assert!(matches!(
+        fs.read_dir(ROOT_INODE).await,
+        Err(FsError::Internal(_))
+    ));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_encrypt_string_and_serialize_encrypted() {
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Config {
+        name: String,
+        values: Vec<u64>,
+    }
+
+    for cipher in Cipher::iter() {
+        let fs = EncryptedFs::new_in_memory(Box::new(PasswordProviderImpl {}), cipher)
+            .await
+            .unwrap();
+
+        for s in [String::new(), "a".to_string(), "x".repeat(10 * 1024)] {
+            let encrypted = fs
+                .encrypt_string(&SecretString::from_str(&s).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(
+                s,
+                *fs.decrypt_string(&encrypted).await.unwrap().expose_secret()
+            );
+        }
+
+        let path = Path::new("my-app").join("config");
+        for config in [
+            Config {
+                name: String::new(),
+                values: vec![],
+            },
+            Config {
+                name: "y".repeat(5 * 1024),
+                values: (0..1024).collect(),
+            },
+        ] {
+            fs.serialize_encrypted(&path, &config).await.unwrap();
+            let read: Config = fs.deserialize_encrypted(&path).await.unwrap();
+            assert_eq!(config, read);
+        }
+
+        // bound to the path
+        let other = Path::new("my-app").join("other");
+        let data = fs
+            .storage
+            .read(&app_data_path(&path).unwrap())
+            .await
+            .unwrap();
+        fs.storage
+            .write(&app_data_path(&other).unwrap(), &data)
+            .await
+            .unwrap();
+        assert!(matches!(
+            fs.deserialize_encrypted::<Config>(&other).await,
+            Err(FsError::IntegrityViolation)
+        ));
+
+        assert!(matches!(
+            fs.deserialize_encrypted::<Config>(Path::new("missing"))
+                .await,
+            Err(FsError::NotFound { .. })
+        ));
+        for path in ["", "/abs", "../up", "a/../b"] {
+            assert!(matches!(
+                fs.serialize_encrypted(Path::new(path), &1).await,
+                Err(FsError::InvalidInput { .. })
+            ));
+        }
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_async_reader_and_writer() {
+    use std::io::SeekFrom;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+    let fs =
+        EncryptedFs::new_in_memory(Box::new(PasswordProviderImpl {}), Cipher::ChaCha20Poly1305)
+            .await
+            .unwrap();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+
+    #[allow(clippy::cast_possible_truncation)]
+    let data: Vec<u8> = (0..50 * 1024 * 1024_u32)
+        .map(|i| (i.wrapping_mul(31) % 251) as u8)
+        .collect();
+
+    let mut writer = fs.open_async_writer(attr.ino).await.unwrap();
+    let copied = tokio::io::copy(&mut data.as_slice(), &mut writer)
+        .await
+        .unwrap();
+    assert_eq!(data.len() as u64, copied);
+    writer.shutdown().await.unwrap();
+    assert_eq!(data.len() as u64, fs.get_attr(attr.ino).await.unwrap().size);
+
+    let mut reader = fs.open_async_reader(attr.ino).await.unwrap();
+    let mut read = Vec::with_capacity(data.len());
+    tokio::io::copy(&mut reader, &mut read).await.unwrap();
+    assert_eq!(crypto::hash(&data), crypto::hash(&read));
+
+    // seek
+    let pos = reader.seek(SeekFrom::End(-10)).await.unwrap();
+    assert_eq!(data.len() as u64 - 10, pos);
+    let mut tail = vec![];
+    reader.read_to_end(&mut tail).await.unwrap();
+    assert_eq!(&data[data.len() - 10..], tail.as_slice());
+    reader.seek(SeekFrom::Start(42)).await.unwrap();
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&data[42..50], &buf);
+    assert!(reader.seek(SeekFrom::Current(-100)).await.is_err());
+
+    let mut writer = fs.open_async_writer(attr.ino).await.unwrap();
+    writer.seek(SeekFrom::Start(42)).await.unwrap();
+    writer.write_all(b"overwrite").await.unwrap();
+    writer.flush().await.unwrap();
+    drop(writer);
+    drop(reader);
+
+    let mut reader = fs.open_async_reader(attr.ino).await.unwrap();
+    reader.seek(SeekFrom::Start(40)).await.unwrap();
+    let mut buf = [0; 13];
+    reader.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&data[40..42], &buf[..2]);
+    assert_eq!(b"overwrite", &buf[2..11]);
+    assert_eq!(&data[51..53], &buf[11..]);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_exact_at_and_read_all() {
+    run_test(
+        TestSetup {
+            key: "test_read_exact_at_and_read_all",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            #[allow(clippy::cast_possible_truncation)]
+            let data: Vec<u8> = (0..3 * BLOCK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+            assert_eq!(
+                data.len(),
+                fs.write_all(attr.ino, 0, &data, fh).await.unwrap()
+            );
+            fs.release(fh).await.unwrap();
+
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            // straddling the blocks
+            for (offset, len) in [
+                (0, BLOCK_SIZE + 1),
+                (BLOCK_SIZE - 5, 10),
+                (BLOCK_SIZE - 1, BLOCK_SIZE + 2),
+                (2 * BLOCK_SIZE - 3, BLOCK_SIZE + 103),
+            ] {
+                let mut buf = vec![0; len];
+                fs.read_exact_at(attr.ino, offset as u64, &mut buf, fh)
+                    .await
+                    .unwrap();
+                assert_eq!(&data[offset..offset + len], buf.as_slice());
+            }
+
+            // past the end
+            let offset = 3 * BLOCK_SIZE - 10;
+            let mut buf = vec![0; 200];
+            let len = fs
+                .read_full_at(attr.ino, offset as u64, &mut buf, fh)
+                .await
+                .unwrap();
+            assert_eq!(110, len);
+            assert_eq!(&data[offset..], &buf[..len]);
+            match fs
+                .read_exact_at(attr.ino, offset as u64, &mut buf, fh)
+                .await
+            {
+                Err(FsError::Io { source, .. }) => {
+                    assert_eq!(io::ErrorKind::UnexpectedEof, source.kind());
+                }
+                res => panic!("unexpected {res:?}"),
+            }
+
+            assert_eq!(
+                data,
+                fs.read_all(attr.ino, fh, data.len() as u64).await.unwrap()
+            );
+            assert!(matches!(
+                fs.read_all(attr.ino, fh, data.len() as u64 - 1).await,
+                Err(FsError::InvalidInput { .. })
+            ));
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_write_vectored() {
+    run_test(
+        TestSetup {
+            key: "test_write_vectored",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+
+            let a = vec![1_u8; 10];
+            let b = vec![2_u8; 20];
+            let c = vec![3_u8; 30];
+            let len = fs
+                .write_vectored(
+                    attr.ino,
+                    0,
+                    &[IoSlice::new(&a), IoSlice::new(&[]), IoSlice::new(&b)],
+                    fh,
+                )
+                .await
+                .unwrap();
+            assert_eq!(30, len);
+            assert_eq!(30, fs.get_attr(attr.ino).await.unwrap().size);
+
+            // the end of the block is in the third one
+            let offset = (BLOCK_SIZE - a.len() - b.len() - 5) as u64;
+            let bufs = [IoSlice::new(&a), IoSlice::new(&b), IoSlice::new(&c)];
+            let len = fs
+                .write_vectored(attr.ino, offset, &bufs, fh)
+                .await
+                .unwrap();
+            assert_eq!(a.len() + b.len() + 5, len);
+            assert_eq!(
+                offset + len as u64,
+                fs.get_attr(attr.ino).await.unwrap().size
+            );
+            fs.write_all(attr.ino, offset + len as u64, &c[5..], fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let data = fs.read_all(attr.ino, fh, u64::MAX).await.unwrap();
+            fs.release(fh).await.unwrap();
+            #[allow(clippy::cast_possible_truncation)]
+            let offset = offset as usize;
+            assert_eq!([a.as_slice(), &b].concat(), data[..30]);
+            assert!(data[30..offset].iter().all(|byte| *byte == 0));
+            assert_eq!([a, b, c].concat(), data[offset..]);
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[traced_test]
+async fn test_read_dir_plus_bounded_concurrency() {
+    const ENTRIES: usize = 50_000;
+    const CONCURRENCY: usize = 8;
+
+    let storage = Arc::new(SlowStorage::new(
+        Arc::new(InMemoryStorage::new()),
+        Duration::from_micros(100),
+    ));
+    storage.slow.store(false, Ordering::SeqCst);
+    let fs = open_storage_with(
+        storage.clone(),
+        FsOptions::builder()
+            .durability(DurabilityPolicy::Never)
+            .dir_entries_concurrency(NonZeroUsize::new(CONCURRENCY).unwrap())
+            .build(),
+    )
+    .await;
+
+    let mut tasks = vec![];
+    for i in 0..8 {
+        let fs = fs.clone();
+        tasks.push(tokio::spawn(async move {
+            for j in (i..ENTRIES).step_by(8) {
+                let (fh, _) = fs
+                    .create(
+                        ROOT_INODE,
+                        &SecretString::from_str(&format!("file-{j}")).unwrap(),
+                        create_attr(FileType::RegularFile),
+                        false,
+                        false,
+                    )
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+            }
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    storage.slow.store(true, Ordering::SeqCst);
+    let entries: Vec<_> = fs
+        .read_dir_plus(ROOT_INODE)
+        .await
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    storage.slow.store(false, Ordering::SeqCst);
+
+    // the entries are read concurrently, but never more than the limit at once
+    let peak = storage.peak.load(Ordering::SeqCst);
+    assert!(peak > 1 && peak <= CONCURRENCY, "peak {peak}");
+    assert_eq!(ENTRIES + 2, entries.len());
+    // in the order of the listing
+    let names: Vec<_> = fs
+        .read_dir(ROOT_INODE)
+        .await
+        .unwrap()
+        .map(|entry| entry.unwrap().name.expose_secret().clone())
+        .collect();
+    assert_eq!(
+        names,
+        entries
+            .iter()
+            .map(|entry| entry.name.expose_secret().clone())
+            .collect::<Vec<_>>()
+    );
+    for entry in entries {
+        assert_eq!(entry.ino, entry.attr.ino);
+        if entry.kind == FileType::RegularFile {
+            assert_eq!(0, entry.attr.size);
+        }
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_child_count() {
+    run_test(
+        TestSetup {
+            key: "test_child_count",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let name = |s: &str| SecretString::from_str(s).unwrap();
+            let stored_count = |ino: u64| {
+                let fs = fs.clone();
+                async move {
+                    fs.read_bound::<u64>(
+                        &fs.contents_path(ino).join(CHILD_COUNT_FILENAME),
+                        &child_count_aad(ino),
+                    )
+                    .await
+                    .unwrap()
+                }
+            };
+
+            let (_, dir) = fs
+                .create(
+                    ROOT_INODE,
+                    &name("dir"),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert_eq!(0, fs.len(dir.ino).await.unwrap());
+            // with "." and ".."
+            assert_eq!(2, stored_count(dir.ino).await);
+            for i in 0..5 {
+                let (fh, _) = fs
+                    .create(
+                        dir.ino,
+                        &name(&format!("file-{i}")),
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+            }
+            assert_eq!(5, fs.len(dir.ino).await.unwrap());
+            assert_eq!(1, fs.len(ROOT_INODE).await.unwrap());
+
+            fs.remove_file(dir.ino, &name("file-0")).await.unwrap();
+            assert_eq!(4, fs.len(dir.ino).await.unwrap());
+            // in the same directory
+            fs.rename(dir.ino, &name("file-1"), dir.ino, &name("file-1-new"))
+                .await
+                .unwrap();
+            assert_eq!(4, fs.len(dir.ino).await.unwrap());
+            // replacing another one
+            fs.rename(dir.ino, &name("file-2"), dir.ino, &name("file-3"))
+                .await
+                .unwrap();
+            assert_eq!(3, fs.len(dir.ino).await.unwrap());
+            // to another directory
+            fs.rename(dir.ino, &name("file-4"), ROOT_INODE, &name("file-4"))
+                .await
+                .unwrap();
+            assert_eq!(2, fs.len(dir.ino).await.unwrap());
+            assert_eq!(2, fs.len(ROOT_INODE).await.unwrap());
+            assert_eq!(4, stored_count(dir.ino).await);
+            assert_eq!(3, stored_count(ROOT_INODE).await);
+
+            // made from the entries when missing, like in data dirs from before it existed
+            fs.storage
+                .remove(&fs.contents_path(dir.ino).join(CHILD_COUNT_FILENAME))
+                .await
+                .unwrap();
+            assert_eq!(2, fs.len(dir.ino).await.unwrap());
+            assert_eq!(4, stored_count(dir.ino).await);
+
+            // check finds a wrong count and repairs it
+            fs.write_bound(
+                &fs.contents_path(dir.ino).join(CHILD_COUNT_FILENAME),
+                &42_u64,
+                &child_count_aad(dir.ino),
+            )
+            .await
+            .unwrap();
+            let report = fs.check(true).await.unwrap();
+            assert_eq!(1, report.problems.len());
+            assert_eq!(
+                CheckProblemKind::ChildCountMismatch {
+                    stored: Some(42),
+                    actual: 4
+                },
+                report.problems[0].kind
+            );
+            assert!(report.problems[0].repaired);
+            assert!(fs.check(false).await.unwrap().is_clean());
+            assert_eq!(2, fs.len(dir.ino).await.unwrap());
+
+            fs.remove_file(dir.ino, &name("file-1-new")).await.unwrap();
+            fs.remove_file(dir.ino, &name("file-3")).await.unwrap();
+            fs.remove_dir(ROOT_INODE, &name("dir")).await.unwrap();
+            assert_eq!(1, fs.len(ROOT_INODE).await.unwrap());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_find_by_name_fills_caches() {
+    run_test(
+        TestSetup {
+            key: "test_find_by_name_fills_caches",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let name = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let encrypted_name = fs.entry_encrypted_name(ROOT_INODE, &name).await.unwrap();
+            let ls_path = fs
+                .contents_path(ROOT_INODE)
+                .join(LS_DIR)
+                .join(&encrypted_name);
+            let cached = |fs: Arc<EncryptedFs>| {
+                let ls_path = ls_path.clone();
+                let encrypted_name = encrypted_name.clone();
+                async move {
+                    let meta = fs
+                        .dir_entries_meta_cache
+                        .get()
+                        .await
+                        .unwrap()
+                        .lock()
+                        .await
+                        .peek(ls_path.to_str().unwrap())
+                        .copied();
+                    let name = fs
+                        .get_dir_entries_name_cache()
+                        .await
+                        .unwrap()
+                        .lock()
+                        .await
+                        .peek(&encrypted_name)
+                        .map(|name| name.expose_secret().clone());
+                    (meta, name)
+                }
+            };
+            let expected = (
+                Some((attr.ino, FileType::RegularFile)),
+                Some("test-file".to_string()),
+            );
+
+            // by create
+            assert_eq!(expected, cached(fs.clone()).await);
+
+            // by find_by_name
+            fs.clear_dir_entry_caches().await.unwrap();
+            assert_eq!((None, None), cached(fs.clone()).await);
+            assert_eq!(
+                attr.ino,
+                fs.find_by_name(ROOT_INODE, &name)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+            assert_eq!(expected, cached(fs.clone()).await);
+
+            // listing doesn't read the `ls` entry anymore, it would fail now
+            fs.storage.write(&ls_path, b"garbage").await.unwrap();
+            let entry = fs
+                .read_dir_plus(ROOT_INODE)
+                .await
+                .unwrap()
+                .map(Result::unwrap)
+                .find(|entry| entry.ino == attr.ino)
+                .unwrap();
+            assert_eq!("test-file", entry.name.expose_secret().as_str());
+            assert_eq!(attr.ino, entry.attr.ino);
+
+            // and it's forgotten when removed
+            fs.remove_file(ROOT_INODE, &name).await.unwrap();
+            assert_eq!((None, None), cached(fs.clone()).await);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_invalidate_external_change() {
+    run_test(
+        TestSetup {
+            key: "test_invalidate_external_change",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.write_all(attr.ino, 0, b"test-42", fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let changed = Arc::new(std::sync::Mutex::new(vec![]));
+            let changed2 = changed.clone();
+            fs.on_external_change(move |ino| changed2.lock().unwrap().push(ino));
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, 7);
+
+            // change the inode file like another instance would
+            let mut changed_attr = fs.get_inode_from_storage(attr.ino).await.unwrap();
+            changed_attr.size = 42;
+            serialize_bound_into(
+                &*fs.storage,
+                &fs.ino_file(attr.ino),
+                &(changed_attr, Holes::default()),
+                fs.cipher,
+                &*fs.key.get().await.unwrap(),
+                &inode_aad(attr.ino),
+            )
+            .await
+            .unwrap();
+            // still cached
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, 7);
+
+            fs.invalidate_external_change(&fs.ino_file(attr.ino))
+                .await
+                .unwrap();
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, 42);
+            assert_eq!(*changed.lock().unwrap(), vec![attr.ino]);
+            fs.release(fh).await.unwrap();
+
+            // keys not made by us are ignored
+            fs.invalidate_external_change(Path::new(SECURITY_DIR).join(KEY_ENC_FILENAME).as_path())
+                .await
+                .unwrap();
+            assert_eq!(changed.lock().unwrap().len(), 1);
+        },
+    )
+    .await;
+}
+
+#[cfg(feature = "watch")]
+#[tokio::test]
+#[traced_test]
+async fn test_watch_external_changes() {
+    run_test(
+        TestSetup {
+            key: "test_watch_external_changes",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            if local_data_dir(&fs).is_none() {
+                return;
+            }
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, 0);
+            let _watcher = fs.watch_external_changes().unwrap();
+
+            let mut changed_attr = fs.get_inode_from_storage(attr.ino).await.unwrap();
+            changed_attr.size = 42;
+            serialize_bound_into(
+                &*fs.storage,
+                &fs.ino_file(attr.ino),
+                &(changed_attr, Holes::default()),
+                fs.cipher,
+                &*fs.key.get().await.unwrap(),
+                &inode_aad(attr.ino),
+            )
+            .await
+            .unwrap();
+
+            tokio::time::timeout(Duration::from_secs(10), async {
+                while fs.get_attr(attr.ino).await.unwrap().size != 42 {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .expect("the change was not seen");
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_concurrent_create_unique_inodes() {
+    run_test(
+        TestSetup {
+            key: "test_concurrent_create_unique_inodes",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let mut tasks = vec![];
+            for i in 0..2000 {
+                let fs = fs.clone();
+                tasks.push(tokio::spawn(async move {
+                    let (fh, attr) = fs
+                        .create(
+                            ROOT_INODE,
+                            &SecretString::from_str(&format!("test-file-{i}")).unwrap(),
+                            create_attr(FileType::RegularFile),
+                            false,
+                            true,
+                        )
+                        .await
+                        .unwrap();
+                    fs.write_all(attr.ino, 0, format!("test-{i}").as_bytes(), fh)
+                        .await
+                        .unwrap();
+                    fs.release(fh).await.unwrap();
+                    attr.ino
+                }));
+            }
+            let mut inos = vec![];
+            for task in tasks {
+                inos.push(task.await.unwrap());
+            }
+
+            let unique: std::collections::HashSet<_> = inos.iter().copied().collect();
+            assert_eq!(inos.len(), unique.len());
+            for (i, ino) in inos.iter().enumerate() {
+                assert_eq!(
+                    format!("test-{i}"),
+                    test_common::read_to_string(*ino, &fs).await
+                );
+            }
+            assert!(fs.reserved_inodes.lock().unwrap().is_empty());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_failed_create_releases_inode() {
+    let storage = Arc::new(CrashingStorage {
+        inner: Arc::new(InMemoryStorage::new()),
+        left: AtomicUsize::new(usize::MAX),
+    });
+    let fs = open_storage_with(storage.clone(), FsOptions::default()).await;
+
+    // fail at each change a create makes in turn, the inode is never left reserved
+    for left in 0..10 {
+        storage.left.store(left, Ordering::SeqCst);
+        let res = fs
+            .create(
+                ROOT_INODE,
+                &SecretString::from_str(&format!("test-file-{left}")).unwrap(),
+                create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await;
+        if res.is_ok() {
+            break;
+        }
+        assert!(fs.reserved_inodes.lock().unwrap().is_empty());
+    }
+}
+
+async fn read_inode_counter(storage: &dyn Storage) -> u64 {
+    String::from_utf8(
+        storage
+            .read(&Path::new(SECURITY_DIR).join(INODE_COUNTER_FILENAME))
+            .await
+            .unwrap(),
+    )
+    .unwrap()
+    .parse()
+    .unwrap()
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_sequential_inode_alloc() {
+    run_test(
+        TestSetup {
+            key: "test_sequential_inode_alloc",
+            read_only: false,
+        },
+        async {
+            let storage = take_fs().await.storage.clone();
+            let fs = open_storage_with(
+                storage.clone(),
+                FsOptions::builder()
+                    .inode_alloc(InodeAlloc::Sequential)
+                    .build(),
+            )
+            .await;
+
+            // starts above the root, the first batch is reserved before it's used
+            assert_eq!(
+                create_file(&fs, ROOT_INODE, "file-1", &[]).await,
+                ROOT_INODE + 1
+            );
+            assert_eq!(
+                create_file(&fs, ROOT_INODE, "file-2", &[]).await,
+                ROOT_INODE + 2
+            );
+            assert_eq!(read_inode_counter(&*storage).await, ROOT_INODE + 1 + 1024);
+            let dir = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap()
+                .1
+                .ino;
+            assert_eq!(dir, ROOT_INODE + 3);
+
+            // a crash, the rest of the batch is skipped
+            drop(fs);
+            let fs = open_storage_with(
+                storage.clone(),
+                FsOptions::builder()
+                    .inode_alloc(InodeAlloc::Sequential)
+                    .build(),
+            )
+            .await;
+            assert_eq!(
+                create_file(&fs, ROOT_INODE, "file-3", &[]).await,
+                ROOT_INODE + 1 + 1024
+            );
+            assert_eq!(read_inode_counter(&*storage).await, ROOT_INODE + 1 + 2048);
+            assert_eq!(fs.len(ROOT_INODE).await.unwrap(), 4);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_sequential_inode_alloc_mixed() {
+    run_test(
+        TestSetup {
+            key: "test_sequential_inode_alloc_mixed",
+            read_only: false,
+        },
+        async {
+            let storage = take_fs().await.storage.clone();
+            let fs = open_storage_with(
+                storage.clone(),
+                FsOptions::builder().inode_alloc(InodeAlloc::Random).build(),
+            )
+            .await;
+            let random = create_file(&fs, ROOT_INODE, "random", &[]).await;
+            assert_eq!(
+                kind(&fs, Path::new(SECURITY_DIR).join(INODE_COUNTER_FILENAME)).await,
+                None
+            );
+
+            // without a counter it starts above the largest inode
+            drop(fs);
+            let fs = open_storage_with(
+                storage.clone(),
+                FsOptions::builder()
+                    .inode_alloc(InodeAlloc::Sequential)
+                    .build(),
+            )
+            .await;
+            assert_eq!(
+                create_file(&fs, ROOT_INODE, "sequential", &[]).await,
+                random + 1
+            );
+
+            // an inode taken by another one is skipped
+            let mut attr = fs.get_inode_from_storage(random).await.unwrap();
+            attr.ino = random + 2;
+            fs.write_inode_to_storage(&attr).await.unwrap();
+            assert_eq!(
+                create_file(&fs, ROOT_INODE, "after-taken", &[]).await,
+                random + 3
+            );
+        },
+    )
+    .await;
+}
+
+/// The keys of the contents segments, the inode and the directory entries of the file `name` in the root, with
+/// what they have now.
+async fn file_keys(fs: &EncryptedFs, name: &SecretString, ino: u64) -> Vec<(PathBuf, Vec<u8>)> {
+    let contents = fs.contents_path(ino);
+    let mut keys: Vec<_> = (0..SegmentedFile::segments_count(&fs.storage, &contents)
+        .await
+        .unwrap())
+        .map(|index| SegmentedFile::segment_path(&contents, index))
+        .collect();
+    keys.push(fs.ino_file(ino));
+    let root = fs.contents_path(ROOT_INODE);
+    keys.push(
+        root.join(LS_DIR)
+            .join(fs.entry_encrypted_name(ROOT_INODE, name).await.unwrap()),
+    );
+    keys.push(
+        root.join(HASH_DIR)
+            .join(fs.hash_entry_name(ROOT_INODE, name).await.unwrap()),
+    );
+    let mut res = vec![];
+    for key in keys {
+        let data = fs.storage.read(&key).await.unwrap();
+        res.push((key, data));
+    }
+    res
+}
+
+/// `true` if no block of `ciphertext_block_size` of `old` is in `new` at the same offset.
+fn overwritten(old: &[u8], new: &[u8], ciphertext_block_size: u64) -> bool {
+    let size = usize::try_from(ciphertext_block_size).unwrap();
+    old.len() == new.len() && old.chunks(size).zip(new.chunks(size)).all(|(a, b)| a != b)
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_secure_delete() {
+    run_test(
+        TestSetup {
+            key: "test_secure_delete",
+            read_only: false,
+        },
+        async {
+            let (fs, storage) = open_counting(
+                FsOptions::builder()
+                    .secure_delete(true)
+                    .build()
+                    .with_segment_blocks(NonZeroU64::new(SEGMENT_BLOCKS).unwrap()),
+            )
+            .await;
+            let block_size = fs.cipher.ciphertext_block_size();
+            let name = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.write_all(attr.ino, 0, &[42; BLOCK_SIZE * 10], fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let keys = file_keys(&fs, &name, attr.ino).await;
+            // several segments
+            assert_eq!(keys.len(), 4 + 3);
+
+            fs.remove_file(ROOT_INODE, &name).await.unwrap();
+            for (key, old) in keys {
+                assert_eq!(kind(&fs, &key).await, None);
+                let removed = storage.removed(&key).unwrap();
+                assert!(overwritten(&old, &removed, block_size), "{key:?}");
+            }
+
+            // truncate overwrites from the block of the new end
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.write_all(attr.ino, 0, &[42; BLOCK_SIZE * 10], fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let keys = file_keys(&fs, &name, attr.ino).await;
+            fs.set_len(attr.ino, BLOCK_SIZE as u64 * 2 + 50)
+                .await
+                .unwrap();
+            let (first, old) = &keys[0];
+            let replaced = storage.removed(first).unwrap();
+            let kept = usize::try_from(block_size * 2).unwrap();
+            assert_eq!(&old[..kept], &replaced[..kept]);
+            assert!(overwritten(&old[kept..], &replaced[kept..], block_size));
+            for (key, old) in &keys[1..4] {
+                let removed = storage.removed(key).unwrap();
+                assert!(overwritten(old, &removed, block_size), "{key:?}");
+            }
+            assert_eq!(
+                test_common::read_to_string(attr.ino, &fs)
+                    .await
+                    .into_bytes(),
+                vec![42; BLOCK_SIZE * 2 + 50]
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_delete_file_secure() {
+    run_test(
+        TestSetup {
+            key: "test_delete_file_secure",
+            read_only: false,
+        },
+        async {
+            let (fs, storage) = open_counting(FsOptions::default()).await;
+            let block_size = fs.cipher.ciphertext_block_size();
+            let mut names = vec![];
+            for i in 0..2 {
+                let name = SecretString::from_str(&format!("test-file-{i}")).unwrap();
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &name,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                fs.write_all(attr.ino, 0, &[42; BLOCK_SIZE * 2], fh)
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+                let keys = file_keys(&fs, &name, attr.ino).await;
+                names.push((name, keys));
+            }
+
+            // without the option only the explicit call overwrites
+            fs.remove_file(ROOT_INODE, &names[0].0).await.unwrap();
+            let (contents, old) = &names[0].1[0];
+            assert_eq!(&storage.removed(contents).unwrap(), old);
+            fs.delete_file_secure(ROOT_INODE, &names[1].0)
+                .await
+                .unwrap();
+            for (key, old) in &names[1].1 {
+                assert_eq!(kind(&fs, key).await, None);
+                let removed = storage.removed(key).unwrap();
+                assert!(overwritten(old, &removed, block_size), "{key:?}");
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_subscribe() {
+    run_test(
+        TestSetup {
+            key: "test_subscribe",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let mut events = fs.subscribe();
+
+            let name = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            // coalesced on release
+            fs.write_all(attr.ino, 10, b"test-42", fh).await.unwrap();
+            fs.write_all(attr.ino, 0, b"test", fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            fs.set_attr(attr.ino, SetFileAttr::default().with_perm(0o600))
+                .await
+                .unwrap();
+            let new_name = SecretString::from_str("test-file-2").unwrap();
+            fs.rename(ROOT_INODE, &name, ROOT_INODE, &new_name)
+                .await
+                .unwrap();
+            fs.remove_file(ROOT_INODE, &new_name).await.unwrap();
+
+            match events.recv().await.unwrap() {
+                FsEvent::Created { parent, ino, name } => {
+                    assert_eq!((parent, ino), (ROOT_INODE, attr.ino));
+                    assert_eq!(name.expose_secret().as_str(), "test-file");
+                }
+                event => panic!("unexpected {event:?}"),
+            }
+            match events.recv().await.unwrap() {
+                FsEvent::Written { ino, offset, len } => {
+                    assert_eq!((ino, offset, len), (attr.ino, 0, 17));
+                }
+                event => panic!("unexpected {event:?}"),
+            }
+            match events.recv().await.unwrap() {
+                FsEvent::AttrChanged { ino } => assert_eq!(ino, attr.ino),
+                event => panic!("unexpected {event:?}"),
+            }
+            match events.recv().await.unwrap() {
+                FsEvent::Renamed {
+                    old_parent,
+                    old_name,
+                    new_parent,
+                    new_name,
+                    ino,
+                } => {
+                    assert_eq!(
+                        (old_parent, new_parent, ino),
+                        (ROOT_INODE, ROOT_INODE, attr.ino)
+                    );
+                    assert_eq!(old_name.expose_secret().as_str(), "test-file");
+                    assert_eq!(new_name.expose_secret().as_str(), "test-file-2");
+                }
+                event => panic!("unexpected {event:?}"),
+            }
+            match events.recv().await.unwrap() {
+                FsEvent::Removed { parent, ino, name } => {
+                    assert_eq!((parent, ino), (ROOT_INODE, attr.ino));
+                    assert_eq!(name.expose_secret().as_str(), "test-file-2");
+                }
+                event => panic!("unexpected {event:?}"),
+            }
+            assert!(events.try_recv().is_err());
+            assert_eq!(fs.dropped_events(), 0);
+
+            // a receiver which falls behind loses the oldest ones
+            for _ in 0..1030 {
+                fs.set_attr(ROOT_INODE, SetFileAttr::default().with_perm(0o600))
+                    .await
+                    .unwrap();
+            }
+            assert!(fs.dropped_events() > 0);
+            assert!(matches!(
+                events.recv().await,
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_))
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_metrics_snapshot() {
+    run_test(
+        TestSetup {
+            key: "test_metrics_snapshot",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let name = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.write_all(attr.ino, 0, b"test-42", fh).await.unwrap();
+            let metrics = fs.metrics_snapshot().await;
+            assert_eq!(metrics.create.count, 1);
+            assert_eq!(metrics.write.bytes, 7);
+            assert_eq!(metrics.write_handles, 1);
+            fs.release(fh).await.unwrap();
+
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = [0; 4];
+            fs.read(attr.ino, 0, &mut buf, fh).await.unwrap();
+            assert_eq!(fs.metrics_snapshot().await.read_handles, 1);
+            fs.release(fh).await.unwrap();
+
+            let before = fs.metrics_snapshot().await;
+            assert!(fs.find_by_name(ROOT_INODE, &name).await.unwrap().is_some());
+            let metrics = fs.metrics_snapshot().await;
+            assert_eq!(metrics.lookup.count, before.lookup.count + 1);
+            assert!(metrics.attr_cache.hits > before.attr_cache.hits);
+
+            // the second listing finds the name and the metadata in the caches
+            assert_eq!(fs.read_dir(ROOT_INODE).await.unwrap().count(), 3);
+            let before = fs.metrics_snapshot().await;
+            assert_eq!(fs.read_dir_plus(ROOT_INODE).await.unwrap().count(), 3);
+            let metrics = fs.metrics_snapshot().await;
+            assert_eq!(metrics.read_dir.count, before.read_dir.count + 1);
+            assert_eq!(metrics.name_cache.hits, before.name_cache.hits + 1);
+            assert_eq!(metrics.name_cache.misses, before.name_cache.misses);
+            assert_eq!(metrics.meta_cache.misses, before.meta_cache.misses);
+
+            assert_eq!(metrics.release.count, 2);
+            assert_eq!(metrics.read.count, 1);
+            assert_eq!(metrics.read.bytes, 4);
+            assert_eq!((metrics.read_handles, metrics.write_handles), (0, 0));
+            assert_eq!(
+                metrics.read.latency_buckets.iter().sum::<u64>(),
+                metrics.read.count
+            );
+            assert_eq!(metrics.read.latency_buckets.len(), LATENCY_BUCKETS);
+            assert!(metrics.key_cache.hits > 0);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_max_size_bytes() {
+    run_test(
+        TestSetup {
+            key: "test_max_size_bytes",
+            read_only: false,
+        },
+        async {
+            let fs = take_fs().await;
+            create_file(&fs, ROOT_INODE, "file-1", &[42; 100]).await;
+            let storage = fs.storage.clone();
+            drop(fs);
+
+            // counted from the inodes the first time
+            let fs = open_storage_with(
+                storage.clone(),
+                FsOptions::builder().max_size_bytes(300).build(),
+            )
+            .await;
+            let stats = fs.statfs().await.unwrap();
+            assert_eq!(stats.total_bytes, 300);
+            assert_eq!(stats.free_bytes, 200);
+            assert_eq!(stats.available_bytes, 200);
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("file-2").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.write_all(attr.ino, 0, &[42; 150], fh).await.unwrap();
+            assert!(matches!(
+                fs.write(attr.ino, 150, &[42; 51], fh).await,
+                Err(FsError::QuotaExceeded)
+            ));
+            // rewriting doesn't add
+            fs.write_all(attr.ino, 0, &[43; 150], fh).await.unwrap();
+            fs.write_all(attr.ino, 150, &[42; 50], fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(fs.statfs().await.unwrap().free_bytes, 0);
+            assert!(matches!(
+                fs.set_len(attr.ino, 201).await,
+                Err(FsError::QuotaExceeded)
+            ));
+            assert!(matches!(
+                fs.fallocate(attr.ino, 200, 1).await,
+                Err(FsError::QuotaExceeded)
+            ));
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, 200);
+
+            // shrinking and removing give it back
+            fs.set_len(attr.ino, 100).await.unwrap();
+            fs.fallocate(attr.ino, 100, 50).await.unwrap();
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, 150);
+            fs.remove_file(ROOT_INODE, &SecretString::from_str("file-1").unwrap())
+                .await
+                .unwrap();
+            assert_eq!(fs.statfs().await.unwrap().free_bytes, 150);
+
+            // kept when opened again
+            drop(fs);
+            let fs = open_storage_with(
+                storage.clone(),
+                FsOptions::builder().max_size_bytes(300).build(),
+            )
+            .await;
+            assert_eq!(fs.statfs().await.unwrap().free_bytes, 150);
+            assert!(fs.check(false).await.unwrap().is_clean());
+
+            // check finds a wrong total and repairs it
+            fs.set_used_bytes(42).await.unwrap();
+            drop(fs);
+            let fs = open_storage_with(
+                storage.clone(),
+                FsOptions::builder().max_size_bytes(300).build(),
+            )
+            .await;
+            let report = fs.check(true).await.unwrap();
+            assert_eq!(1, report.problems.len());
+            assert_eq!(
+                CheckProblemKind::UsedBytesMismatch {
+                    stored: 42,
+                    actual: 150
+                },
+                report.problems[0].kind
+            );
+            assert!(report.problems[0].repaired);
+            assert_eq!(fs.statfs().await.unwrap().free_bytes, 150);
+
+            // it's encrypted, so it can't be changed from outside
+            drop(fs);
+            storage
+                .write(&Path::new(SECURITY_DIR).join(USED_BYTES_FILENAME), b"42")
+                .await
+                .unwrap();
+            assert!(EncryptedFs::with_storage(
+                storage.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+                FsOptions::builder().max_size_bytes(300).build(),
+            )
+            .await
+            .is_err());
+
+            // not counted without a max, so it's counted again the next time
+            drop(fs);
+            let fs = open_storage_with(
+                storage.clone(),
+                FsOptions::builder().inode_alloc(InodeAlloc::Random).build(),
+            )
+            .await;
+            assert!(fs
+                .storage
+                .kind(&Path::new(SECURITY_DIR).join(USED_BYTES_FILENAME))
+                .await
+                .unwrap()
+                .is_none());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_max_size_bytes_concurrent_writes() {
+    run_test(
+        TestSetup {
+            key: "test_max_size_bytes_concurrent_writes",
+            read_only: false,
+        },
+        async {
+            let storage = take_fs().await.storage.clone();
+            let fs =
+                open_storage_with(storage, FsOptions::builder().max_size_bytes(1000).build()).await;
+
+            let mut join_set = tokio::task::JoinSet::new();
+            for i in 0..10 {
+                let fs = fs.clone();
+                join_set.spawn(async move {
+                    let (fh, attr) = fs
+                        .create(
+                            ROOT_INODE,
+                            &SecretString::from_str(&format!("file-{i}")).unwrap(),
+                            create_attr(FileType::RegularFile),
+                            false,
+                            true,
+                        )
+                        .await
+                        .unwrap();
+                    let mut written = 0;
+                    loop {
+                        match fs.write(attr.ino, written, &[42; 30], fh).await {
+                            Ok(len) => written += len as u64,
+                            Err(FsError::QuotaExceeded) => break,
+                            Err(err) => panic!("{err}"),
+                        }
+                    }
+                    fs.release(fh).await.unwrap();
+                    written
+                });
+            }
+            let mut written = 0;
+            while let Some(res) = join_set.join_next().await {
+                written += res.unwrap();
+            }
+            // each write reserves what it adds, so together they don't go over
+            assert!(written <= 1000);
+            let stats = fs.statfs().await.unwrap();
+            assert_eq!(stats.free_bytes, 1000 - written);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_dir_quota() {
+    run_test(
+        TestSetup {
+            key: "test_dir_quota",
+            read_only: false,
+        },
+        async {
+            let fs = take_fs().await;
+            let name = |name: &str| SecretString::from_str(name).unwrap();
+            let dir = fs
+                .create(
+                    ROOT_INODE,
+                    &name("dir"),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap()
+                .1
+                .ino;
+            assert_eq!(fs.dir_quota(dir).await.unwrap(), None);
+            let outside = create_file(&fs, ROOT_INODE, "outside", &[42; 100]).await;
+            let small = create_file(&fs, ROOT_INODE, "small", &[42; 40]).await;
+            fs.set_dir_quota(dir, Some(150)).await.unwrap();
+            // kept in the inode
+            assert_eq!(fs.get_attr(dir).await.unwrap().quota, Some(150));
+            assert!(fs
+                .storage
+                .kind(&fs.contents_path(dir).join(QUOTA_FILENAME))
+                .await
+                .unwrap()
+                .is_none());
+
+            let (fh, attr) = fs
+                .create(
+                    dir,
+                    &name("file"),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.write_all(attr.ino, 0, &[42; 100], fh).await.unwrap();
+            assert!(matches!(
+                fs.write(attr.ino, 100, &[42; 51], fh).await,
+                Err(FsError::QuotaExceeded)
+            ));
+            fs.release(fh).await.unwrap();
+            // files outside are not limited
+            fs.set_len(outside, 200).await.unwrap();
+
+            // nor adding a file over it
+            assert!(matches!(
+                fs.link(outside, dir, &name("link")).await,
+                Err(FsError::QuotaExceeded)
+            ));
+            assert!(matches!(
+                fs.rename(ROOT_INODE, &name("outside"), dir, &name("outside"))
+                    .await,
+                Err(FsError::QuotaExceeded)
+            ));
+            assert!(!fs.exists_by_name(dir, &name("link")).await.unwrap());
+            assert_eq!(fs.get_attr(outside).await.unwrap().nlink, 1);
+            fs.rename(ROOT_INODE, &name("small"), dir, &name("small"))
+                .await
+                .unwrap();
+            // another link of a file in it is counted once
+            fs.link(small, dir, &name("small-2")).await.unwrap();
+            assert_eq!(
+                fs.dir_quota(dir).await.unwrap(),
+                Some(DirQuota {
+                    limit: 150,
+                    used: 140
+                })
+            );
+
+            // counted again when opened
+            let storage = fs.storage.clone();
+            drop(fs);
+            let fs = open_storage_with(
+                storage.clone(),
+                FsOptions::builder().inode_alloc(InodeAlloc::Random).build(),
+            )
+            .await;
+            assert_eq!(fs.dir_quota(dir).await.unwrap().unwrap().used, 140);
+
+            fs.remove_file(dir, &name("small")).await.unwrap();
+            assert_eq!(fs.dir_quota(dir).await.unwrap().unwrap().used, 140);
+            fs.remove_file(dir, &name("small-2")).await.unwrap();
+            assert_eq!(fs.dir_quota(dir).await.unwrap().unwrap().used, 100);
+            fs.rename(dir, &name("file"), ROOT_INODE, &name("file"))
+                .await
+                .unwrap();
+            assert_eq!(fs.dir_quota(dir).await.unwrap().unwrap().used, 0);
+
+            // the files in the subdirectories count too
+            let sub = fs
+                .create(
+                    dir,
+                    &name("sub"),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap()
+                .1
+                .ino;
+            let nested = create_file(&fs, sub, "nested", &[42; 100]).await;
+            assert_eq!(fs.dir_quota(dir).await.unwrap().unwrap().used, 100);
+            assert!(matches!(
+                fs.set_len(nested, 151).await,
+                Err(FsError::QuotaExceeded)
+            ));
+            // moving a directory with files in it over the quota
+            let other = fs
+                .create(
+                    ROOT_INODE,
+                    &name("other"),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap()
+                .1
+                .ino;
+            create_file(&fs, other, "big", &[42; 60]).await;
+            assert!(matches!(
+                fs.rename(ROOT_INODE, &name("other"), sub, &name("other"))
+                    .await,
+                Err(FsError::QuotaExceeded)
+            ));
+            assert_eq!(fs.dir_quota(dir).await.unwrap().unwrap().used, 100);
+            // and out of it
+            fs.rename(dir, &name("sub"), ROOT_INODE, &name("sub"))
+                .await
+                .unwrap();
+            assert_eq!(fs.dir_quota(dir).await.unwrap().unwrap().used, 0);
+            fs.rename(ROOT_INODE, &name("other"), dir, &name("other"))
+                .await
+                .unwrap();
+            assert_eq!(fs.dir_quota(dir).await.unwrap().unwrap().used, 60);
+            // counted again when opened
+            drop(fs);
+            let fs = open_storage_with(
+                storage.clone(),
+                FsOptions::builder().inode_alloc(InodeAlloc::Random).build(),
+            )
+            .await;
+            assert_eq!(fs.dir_quota(dir).await.unwrap().unwrap().used, 60);
+
+            fs.set_dir_quota(dir, None).await.unwrap();
+            assert_eq!(fs.dir_quota(dir).await.unwrap(), None);
+            drop(fs);
+            let fs = open_storage_with(
+                storage,
+                FsOptions::builder().inode_alloc(InodeAlloc::Random).build(),
+            )
+            .await;
+            assert_eq!(fs.dir_quota(dir).await.unwrap(), None);
+        },
+    )
+    .await;
+}
+
+/// `len` bytes of lines like in a log, which compress well.
+fn log_lines(len: usize) -> Vec<u8> {
+    (0_u64..)
+        .flat_map(|i| format!("{i}: the same message as on the other lines\n").into_bytes())
+        .take(len)
+        .collect()
+}
+
+/// Length of the plaintext kept for `ino`, compressed if the file is.
+async fn stored_len(fs: &EncryptedFs, ino: u64) -> usize {
+    let mut reader = fs
+        .create_read(fs.open_contents(ino).await.unwrap())
+        .await
+        .unwrap();
+    let mut buf = vec![];
+    reader.read_to_end(&mut buf).unwrap()
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_compression() {
+    run_test(
+        TestSetup {
+            key: "test_compression",
+            read_only: false,
+        },
+        async {
+            let storage = take_fs().await.storage.clone();
+            let fs = open_storage_with(
+                storage,
+                FsOptions::builder()
+                    .maybe_compression_level(Some(3))
+                    .build(),
+            )
+            .await;
+            let data = log_lines(10_000);
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            assert!(attr.compressed);
+            for offset in (0..5000).step_by(1000) {
+                fs.write_all(attr.ino, offset as u64, &data[offset..offset + 1000], fh)
+                    .await
+                    .unwrap();
+            }
+            fs.release(fh).await.unwrap();
+
+            // a reader opened before the next writes sees them
+            let fh_read = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![0; 1000];
+            fs.read_exact_at(attr.ino, 4000, &mut buf, fh_read)
+                .await
+                .unwrap();
+            assert_eq!(&data[4000..5000], buf);
+
+            // appended by two writers
+            let fh = fs.open(attr.ino, false, true).await.unwrap();
+            let fh2 = fs.open(attr.ino, false, true).await.unwrap();
+            fs.write_all(attr.ino, 5000, &data[5000..7000], fh)
+                .await
+                .unwrap();
+            fs.write_all(attr.ino, 7000, &data[7000..], fh2)
+                .await
+                .unwrap();
+            // only at the end
+            assert!(matches!(
+                fs.write(attr.ino, 100, b"test", fh).await,
+                Err(FsError::InvalidInput { .. })
+            ));
+            assert!(matches!(
+                fs.write(attr.ino, 20_000, b"test", fh).await,
+                Err(FsError::InvalidInput { .. })
+            ));
+            fs.release(fh).await.unwrap();
+            fs.release(fh2).await.unwrap();
+            assert_eq!(data.len() as u64, fs.get_attr(attr.ino).await.unwrap().size);
+
+            // backward and forward
+            for offset in [9000, 0, 6000, 3000] {
+                fs.read_exact_at(attr.ino, offset, &mut buf, fh_read)
+                    .await
+                    .unwrap();
+                assert_eq!(&data[offset as usize..offset as usize + 1000], buf);
+            }
+            fs.release(fh_read).await.unwrap();
+            assert_eq!(
+                String::from_utf8(data.clone()).unwrap(),
+                test_common::read_to_string(attr.ino, &fs).await
+            );
+            assert!(stored_len(&fs, attr.ino).await * 4 < data.len());
+
+            // truncated and extended with zeros
+            fs.set_len(attr.ino, 3000).await.unwrap();
+            assert_eq!(
+                String::from_utf8(data[..3000].to_vec()).unwrap(),
+                test_common::read_to_string(attr.ino, &fs).await
+            );
+            fs.set_len(attr.ino, 4000).await.unwrap();
+            let mut expected = data[..3000].to_vec();
+            expected.extend_from_slice(&[0; 1000]);
+            assert_eq!(
+                String::from_utf8(expected).unwrap(),
+                test_common::read_to_string(attr.ino, &fs).await
+            );
+            assert!(fs.check(false).await.unwrap().is_clean());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_compression_per_file() {
+    run_test(
+        TestSetup {
+            key: "test_compression_per_file",
+            read_only: false,
+        },
+        async {
+            let storage = take_fs().await.storage.clone();
+            let data = log_lines(5000);
+            let create = |fs: Arc<EncryptedFs>, name: &'static str, compress: Option<bool>| {
+                let data = data.clone();
+                async move {
+                    let (fh, attr) = fs
+                        .create(
+                            ROOT_INODE,
+                            &SecretString::from_str(name).unwrap(),
+                            CreateFileAttr {
+                                compress,
+                                ..create_attr(FileType::RegularFile)
+                            },
+                            false,
+                            true,
+                        )
+                        .await
+                        .unwrap();
+                    fs.write_all(attr.ino, 0, &data, fh).await.unwrap();
+                    fs.release(fh).await.unwrap();
+                    attr.ino
+                }
+            };
+
+            let fs = open_storage_with(
+                storage.clone(),
+                FsOptions::builder().maybe_compression_level(None).build(),
+            )
+            .await;
+            let plain = create(fs.clone(), "plain", None).await;
+            let compressed = create(fs.clone(), "compressed", Some(true)).await;
+            assert!(!fs.get_attr(plain).await.unwrap().compressed);
+            assert!(fs.get_attr(compressed).await.unwrap().compressed);
+            assert_eq!(data.len(), stored_len(&fs, plain).await);
+            assert!(stored_len(&fs, compressed).await * 4 < data.len());
+            // plain files are still written anywhere
+            let fh = fs.open(plain, false, true).await.unwrap();
+            fs.write_all(plain, 0, b"test", fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            drop(fs);
+
+            // files are read the way they were created
+            let fs = open_storage_with(
+                storage,
+                FsOptions::builder()
+                    .maybe_compression_level(Some(19))
+                    .build(),
+            )
+            .await;
+            let not_compressed = create(fs.clone(), "not-compressed", Some(false)).await;
+            assert!(!fs.get_attr(not_compressed).await.unwrap().compressed);
+            assert!(!fs.get_attr(plain).await.unwrap().compressed);
+            for ino in [compressed, not_compressed] {
+                assert_eq!(
+                    String::from_utf8(data.clone()).unwrap(),
+                    test_common::read_to_string(ino, &fs).await
+                );
+            }
+            // directories are never compressed
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert!(!dir_attr.compressed);
+            assert!(fs.check(false).await.unwrap().is_clean());
+        },
+    )
+    .await;
+}
+
+/// A tree with a file of each size around the block size in each directory.
+fn write_source_tree(root: &Path) {
+    let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    for dir in ["", "a", "a/b", "a/b/c", "d"] {
+        let dir = root.join(dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (i, len) in [0, 1, BLOCK_SIZE - 1, BLOCK_SIZE, BLOCK_SIZE * 3 + 7]
+            .into_iter()
+            .enumerate()
+        {
+            let path = dir.join(format!("file-{i}"));
+            let data: Vec<u8> = (0..len).map(|j| (j * 31 + i) as u8).collect();
+            fs::write(&path, data).unwrap();
+            File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+        }
+        fs::write(dir.join("skip.tmp"), b"test").unwrap();
+    }
+}
+
+fn checksum(data: &[u8]) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(data);
+    hasher.finalize().to_hex().to_string()
+}
+
+/// Checksums of the files under `root`, by their path relative to it.
+fn source_checksums(root: &Path, rel: &Path, checksums: &mut HashMap<PathBuf, String>) {
+    for entry in fs::read_dir(root.join(rel)).unwrap() {
+        let entry = entry.unwrap();
+        let rel = rel.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            source_checksums(root, &rel, checksums);
+        } else if entry.file_type().unwrap().is_file() && !rel.to_str().unwrap().ends_with(".tmp") {
+            checksums.insert(rel, checksum(&fs::read(entry.path()).unwrap()));
+        }
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_import_dir() {
+    run_test(
+        TestSetup {
+            key: "test_import_dir",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let src = tempfile::tempdir().unwrap();
+            write_source_tree(src.path());
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(
+                    src.path().join("a/file-1"),
+                    fs::Permissions::from_mode(0o600),
+                )
+                .unwrap();
+                std::os::unix::fs::symlink(src.path().join("a"), src.path().join("d/link"))
+                    .unwrap();
+            }
+            let mut checksums = HashMap::new();
+            source_checksums(src.path(), Path::new(""), &mut checksums);
+
+            let (_, dest) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("dest").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let updates = Arc::new(std::sync::Mutex::new(vec![]));
+            let updates_clone = updates.clone();
+            let options = ImportOptions::builder()
+                .filter(Arc::new(|path: &Path| {
+                    !path.to_str().unwrap().ends_with(".tmp")
+                }))
+                .progress(Arc::new(move |progress: ImportProgress| {
+                    updates_clone.lock().unwrap().push(progress);
+                }))
+                .concurrency(NonZeroUsize::new(3).unwrap())
+                .build();
+            let progress = fs
+                .import_dir(src.path(), dest.ino, options.clone())
+                .await
+                .unwrap();
+            assert_eq!(checksums.len() as u64, progress.files);
+            assert_eq!(4, progress.dirs);
+            let total: u64 = checksums
+                .keys()
+                .map(|path| fs::metadata(src.path().join(path)).unwrap().len())
+                .sum();
+            assert_eq!(total, progress.bytes);
+            {
+                let updates = updates.lock().unwrap();
+                assert_eq!(checksums.len(), updates.len());
+                assert_eq!(progress.bytes, updates.last().unwrap().bytes);
+                assert!(updates.windows(2).all(|w| w[0].files < w[1].files));
+            }
+
+            for (path, sum) in &checksums {
+                let dest_path = Path::new("dest").join(path);
+                assert_eq!(*sum, checksum(&read_to_vec(&fs, &dest_path).await.unwrap()));
+                let attr = fs.resolve(&dest_path).await.unwrap();
+                let metadata = fs::metadata(src.path().join(path)).unwrap();
+                assert_eq!(metadata.len(), attr.size);
+                assert_eq!(metadata.modified().unwrap(), attr.mtime);
+            }
+            assert!(fs.resolve(Path::new("dest/skip.tmp")).await.is_err());
+            assert_eq!(
+                fs::metadata(src.path().join("a/b"))
+                    .unwrap()
+                    .modified()
+                    .unwrap(),
+                fs.resolve(Path::new("dest/a/b")).await.unwrap().mtime
+            );
+            #[cfg(unix)]
+            {
+                assert_eq!(
+                    0o600,
+                    fs.resolve(Path::new("dest/a/file-1")).await.unwrap().perm
+                );
+                // symlinks are skipped by default
+                assert!(fs.resolve(Path::new("dest/d/link")).await.is_err());
+            }
+
+            // what is there already
+            assert!(matches!(
+                fs.import_dir(src.path(), dest.ino, options.clone()).await,
+                Err(FsError::AlreadyExists)
+            ));
+            let skipped = fs
+                .import_dir(
+                    src.path(),
+                    dest.ino,
+                    ImportOptions::builder()
+                        .overwrite(ImportOverwrite::Skip)
+                        .build(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(0, skipped.files);
+            fs::write(src.path().join("a/file-1"), b"changed").unwrap();
+            let replaced = fs
+                .import_dir(
+                    src.path(),
+                    dest.ino,
+                    ImportOptions::builder()
+                        .overwrite(ImportOverwrite::Replace)
+                        .filter(Arc::new(|path: &Path| {
+                            path == Path::new("a") || path == Path::new("a/file-1")
+                        }))
+                        .build(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(1, replaced.files);
+            assert_eq!(
+                b"changed".to_vec(),
+                read_to_vec(&fs, Path::new("dest/a/file-1")).await.unwrap()
+            );
+
+            // following the symlink imports what it points to
+            #[cfg(unix)]
+            {
+                let followed = fs
+                    .import_dir(
+                        &src.path().join("d"),
+                        ROOT_INODE,
+                        ImportOptions::builder().follow_symlinks(true).build(),
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    read_to_vec(&fs, Path::new("dest/a/b/c/file-4"))
+                        .await
+                        .unwrap(),
+                    read_to_vec(&fs, Path::new("link/b/c/file-4"))
+                        .await
+                        .unwrap()
+                );
+                assert!(followed.files > 5);
+            }
+            assert!(fs.check(false).await.unwrap().is_clean());
+        },
+    )
+    .await;
+}
+
+/// Nested directories, a file larger than a record of an archive, an empty one, a compressed one and a hard link.
+async fn populate_for_archive(fs: &EncryptedFs) {
+    let dir_attr = CreateFileAttr {
+        perm: 0o750,
+        ..create_attr(FileType::Directory)
+    };
+    fs.create_dir_all_by_path(Path::new("a/b/c"), dir_attr.clone())
+        .await
+        .unwrap();
+    fs.create_dir_all_by_path(Path::new("d"), dir_attr)
+        .await
+        .unwrap();
+    for (path, len, compress) in [
+        ("file", 10, None),
+        ("a/large", 2 * 1024 * 1024 + 17, None),
+        ("a/b/c/empty", 0, None),
+        ("d/log", 100_000, Some(true)),
+    ] {
+        let (fh, attr) = fs
+            .create_file_by_path(
+                Path::new(path),
+                CreateFileAttr {
+                    perm: 0o640,
+                    uid: 1000,
+                    compress,
+                    ..create_attr(FileType::RegularFile)
+                },
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        fs.write_all(attr.ino, 0, &log_lines(len), fh)
+            .await
+            .unwrap();
+        fs.release(fh).await.unwrap();
+    }
+    let large = fs.resolve(Path::new("a/large")).await.unwrap();
+    let d = fs.resolve(Path::new("d")).await.unwrap();
+    fs.link(
+        large.ino,
+        d.ino,
+        &SecretString::from_str("large-link").unwrap(),
+    )
+    .await
+    .unwrap();
+}
+
+/// What an archive keeps of each node, by path: the kind, permissions, owner, mtime, links and checksum of the
+/// contents.
+async fn archive_snapshot(
+    fs: &EncryptedFs,
+) -> HashMap<PathBuf, (FileType, u16, u32, SystemTime, u32, bool, String)> {
+    let mut snapshot = HashMap::new();
+    let mut dirs = vec![(ROOT_INODE, PathBuf::new())];
+    while let Some((dir, dir_path)) = dirs.pop() {
+        let attr = fs.get_attr(dir).await.unwrap();
+        snapshot.insert(
+            dir_path.clone(),
+            (
+                attr.kind,
+                attr.perm,
+                attr.uid,
+                attr.mtime,
+                attr.nlink,
+                false,
+                String::new(),
+            ),
+        );
+        for entry in fs.read_dir(dir).await.unwrap() {
+            let entry = entry.unwrap();
+            let name = entry.name.expose_secret().clone();
+            if name == "." || name == ".." {
+                continue;
+            }
+            let path = dir_path.join(name);
+            if entry.kind == FileType::Directory {
+                dirs.push((entry.ino, path));
+                continue;
+            }
+            let attr = fs.get_attr(entry.ino).await.unwrap();
+            let fh = fs.open(entry.ino, true, false).await.unwrap();
+            let data = fs.read_all(entry.ino, fh, u64::MAX).await.unwrap();
+            fs.release(fh).await.unwrap();
+            snapshot.insert(
+                path,
+                (
+                    attr.kind,
+                    attr.perm,
+                    attr.uid,
+                    attr.mtime,
+                    attr.nlink,
+                    attr.compressed,
+                    checksum(&data),
+                ),
+            );
+        }
+    }
+    snapshot
+}
+
+fn archive_password() -> SecretString {
+    SecretString::from_str("archive password").unwrap()
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_archive_round_trip() {
+    for (from, to) in [
+        (Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm),
+        (Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305),
+    ] {
+        let src = EncryptedFs::new_in_memory(Box::new(PasswordProviderImpl {}), from)
+            .await
+            .unwrap();
+        populate_for_archive(&src).await;
+        let mut archive = vec![];
+        src.export_archive(&mut archive, archive_password())
+            .await
+            .unwrap();
+        // names and contents are encrypted
+        assert!(!archive.windows(10).any(|w| w == b"large-link"));
+        let data = log_lines(20);
+        assert!(!archive.windows(20).any(|w| w == data));
+        EncryptedFs::verify_archive(archive.as_slice(), archive_password()).unwrap();
+
+        let dest = EncryptedFs::new_in_memory(Box::new(PasswordProviderImpl {}), to)
+            .await
+            .unwrap();
+        dest.import_archive(archive.as_slice(), archive_password())
+            .await
+            .unwrap();
+        let snapshot = archive_snapshot(&src).await;
+        assert_eq!(10, snapshot.len());
+        assert_eq!(snapshot, archive_snapshot(&dest).await);
+        assert!(dest.check(false).await.unwrap().is_clean());
+
+        // only into an empty filesystem
+        assert!(matches!(
+            dest.import_archive(archive.as_slice(), archive_password())
+                .await,
+            Err(FsError::NotEmpty)
+        ));
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_archive_truncated_or_changed() {
+    let src =
+        EncryptedFs::new_in_memory(Box::new(PasswordProviderImpl {}), Cipher::ChaCha20Poly1305)
+            .await
+            .unwrap();
+    populate_for_archive(&src).await;
+    let mut archive = vec![];
+    src.export_archive(&mut archive, archive_password())
+        .await
+        .unwrap();
+    let corrupted = |res: FsResult<()>| matches!(res, Err(FsError::Corrupted { .. }));
+
+    // in the magic, the header, a record, the end record and the MAC
+    for len in [
+        4,
+        20,
+        archive.len() / 2,
+        archive.len() - 33,
+        archive.len() - 1,
+    ] {
+        assert!(
+            corrupted(EncryptedFs::verify_archive(
+                &archive[..len],
+                archive_password()
+            )),
+            "truncated to {len}"
+        );
+    }
+    let mut changed = archive.clone();
+    changed[archive.len() / 2] ^= 1;
+    assert!(corrupted(EncryptedFs::verify_archive(
+        changed.as_slice(),
+        archive_password()
+    )));
+    let mut changed = archive.clone();
+    *changed.last_mut().unwrap() ^= 1;
+    assert!(corrupted(EncryptedFs::verify_archive(
+        changed.as_slice(),
+        archive_password()
+    )));
+    let mut changed = archive.clone();
+    changed.push(0);
+    assert!(corrupted(EncryptedFs::verify_archive(
+        changed.as_slice(),
+        archive_password()
+    )));
+    assert!(matches!(
+        EncryptedFs::verify_archive(archive.as_slice(), SecretString::from_str("wrong").unwrap()),
+        Err(FsError::InvalidPassword)
+    ));
+
+    // what was restored before is kept
+    let dest =
+        EncryptedFs::new_in_memory(Box::new(PasswordProviderImpl {}), Cipher::ChaCha20Poly1305)
+            .await
+            .unwrap();
+    assert!(corrupted(
+        dest.import_archive(&archive[..archive.len() - 1], archive_password())
+            .await
+    ));
+    assert_eq!(
+        archive_snapshot(&src).await.len(),
+        archive_snapshot(&dest).await.len()
+    );
+}
+
+/// The inodes changed and removed, by kind.
+fn changed_and_removed(changes: &[ChangeRecord]) -> (HashSet<u64>, HashSet<u64>) {
+    let mut changed = HashSet::new();
+    let mut removed = HashSet::new();
+    for change in changes {
+        match change {
+            ChangeRecord::Changed(attr) => changed.insert(attr.ino),
+            ChangeRecord::Removed { ino, .. } => removed.insert(*ino),
+        };
+    }
+    (changed, removed)
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_changes_since() {
+    let storage: Arc<dyn Storage> = Arc::new(InMemoryStorage::new());
+    let fs = open_storage_with(storage.clone(), FsOptions::default()).await;
+    let a = create_file(&fs, ROOT_INODE, "a", &[42; 10]).await;
+    let b = create_file(&fs, ROOT_INODE, "b", &[42; 10]).await;
+    let (_, dir) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("dir").unwrap(),
+            create_attr(FileType::Directory),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    let c = create_file(&fs, dir.ino, "c", &[42; 10]).await;
+    let (changed, removed) = changed_and_removed(&fs.changes_since(0).await.unwrap());
+    assert_eq!(HashSet::from([ROOT_INODE, a, b, dir.ino, c]), changed);
+    assert!(removed.is_empty());
+
+    let generation = fs.current_generation().await;
+    assert_eq!(generation, fs.statfs().await.unwrap().generation);
+    assert!(fs.changes_since(generation).await.unwrap().is_empty());
+
+    let fh = fs.open(a, false, true).await.unwrap();
+    fs.write_all(a, 10, b"more", fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+    fs.remove_file(ROOT_INODE, &SecretString::from_str("b").unwrap())
+        .await
+        .unwrap();
+    let d = create_file(&fs, dir.ino, "d", &[42; 10]).await;
+    let changes = fs.changes_since(generation).await.unwrap();
+    assert!(changes
+        .windows(2)
+        .all(|w| w[0].generation() < w[1].generation()));
+    assert!(changes.iter().all(|c| c.generation() > generation));
+    let (changed, removed) = changed_and_removed(&changes);
+    // the parents of the created and removed ones too
+    assert_eq!(HashSet::from([ROOT_INODE, a, dir.ino, d]), changed);
+    assert_eq!(HashSet::from([b]), removed);
+
+    // the counter and the removals are kept
+    let last = fs.current_generation().await;
+    drop(fs);
+    let fs = open_storage_with(storage, FsOptions::default()).await;
+    assert!(fs.current_generation().await >= last);
+    assert_eq!(
+        (
+            HashSet::from([ROOT_INODE, a, dir.ino, d]),
+            HashSet::from([b])
+        ),
+        changed_and_removed(&fs.changes_since(generation).await.unwrap())
+    );
+    fs.set_len(c, 5).await.unwrap();
+    let changes = fs.changes_since(last).await.unwrap();
+    assert_eq!(1, changes.len());
+    assert_eq!(c, changes[0].ino());
+    assert!(changes[0].generation() > last);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_changes_log() {
+    use std::io::{Seek, SeekFrom, Write};
+
+    let storage: Arc<dyn Storage> = Arc::new(InMemoryStorage::new());
+    let fs = open_storage_with(storage.clone(), FsOptions::default()).await;
+    let a = create_file(&fs, ROOT_INODE, "a", &[42; 10]).await;
+    let b = create_file(&fs, ROOT_INODE, "b", &[42; 10]).await;
+    let (changed, _) = changed_and_removed(&fs.changes_since(0).await.unwrap());
+    assert_eq!(HashSet::from([ROOT_INODE, a, b]), changed);
+    let generation = fs.current_generation().await;
+    let inos =
+        |changes: Vec<ChangeRecord>| changes.iter().map(ChangeRecord::ino).collect::<Vec<_>>();
+
+    // a crash while appending leaves a part of a record, the next ones go after the complete ones
+    drop(fs);
+    let last = storage
+        .list(&changes_dir())
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .filter(|name| name.parse::<u64>().is_ok())
+        .max()
+        .unwrap();
+    let mut file = storage.open(&changes_dir().join(last), true).await.unwrap();
+    file.seek(SeekFrom::End(0)).unwrap();
+    file.write_all(&[20, 0, 0, 0, 1, 2]).unwrap();
+    drop(file);
+    let fs = open_storage_with(storage.clone(), FsOptions::default()).await;
+    fs.set_len(a, 5).await.unwrap();
+    assert_eq!(vec![a], inos(fs.changes_since(generation).await.unwrap()));
+
+    // the data dirs of the previous version log the inodes they have
+    drop(fs);
+    storage.remove_dir(&changes_dir()).await.unwrap();
+    storage
+        .write(&Path::new(SECURITY_DIR).join(VERSION_FILENAME), b"12")
+        .await
+        .unwrap();
+    let fs = open_storage_with(storage.clone(), FsOptions::default()).await;
+    let (changed, _) = changed_and_removed(&fs.changes_since(0).await.unwrap());
+    assert_eq!(HashSet::from([ROOT_INODE, a, b]), changed);
+    assert_eq!(vec![a], inos(fs.changes_since(generation).await.unwrap()));
+
+    // only the last changes are kept
+    for len in 0..200 {
+        fs.set_len(b, len).await.unwrap();
+    }
+    assert!(matches!(
+        fs.changes_since(0).await,
+        Err(FsError::InvalidInput { .. })
+    ));
+    let generation = fs.current_generation().await;
+    fs.set_len(a, 1).await.unwrap();
+    assert_eq!(vec![a], inos(fs.changes_since(generation).await.unwrap()));
+}
+
+#[cfg(feature = "snapshots")]
+#[tokio::test]
+#[traced_test]
+async fn test_snapshots() {
+    let fs = open_storage_with(Arc::new(InMemoryStorage::new()), small_segments()).await;
+    // a few segments
+    let data: Vec<u8> = (0..BLOCK_SIZE * SEGMENT_BLOCKS as usize * 2 + 7)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    let a = create_file(&fs, ROOT_INODE, "a", &[]).await;
+    let fh = fs.open(a, false, true).await.unwrap();
+    fs.write_all(a, 0, &data, fh).await.unwrap();
+    let (_, dir) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("dir").unwrap(),
+            create_attr(FileType::Directory),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    let b = create_file(&fs, dir.ino, "b", &[42; 10]).await;
+
+    // with what the open handle wrote
+    fs.create_snapshot("s1").await.unwrap();
+    assert!(matches!(
+        fs.create_snapshot("s1").await,
+        Err(FsError::AlreadyExists)
+    ));
+    for name in ["", ".s", "a/b", "a b"] {
+        assert!(matches!(
+            fs.create_snapshot(name).await,
+            Err(FsError::InvalidInput { .. })
+        ));
+    }
+    assert_eq!(vec!["s1".to_string()], fs.list_snapshots().await.unwrap());
+
+    // changed in place, replaced, removed and added after it
+    fs.write_all(a, 0, b"changed", fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+    fs.set_len(b, 0).await.unwrap();
+    fs.remove_file(dir.ino, &SecretString::from_str("b").unwrap())
+        .await
+        .unwrap();
+    let c = create_file(&fs, ROOT_INODE, "c", &[42; 10]).await;
+
+    let snapshot = fs.open_snapshot("s1").await.unwrap();
+    let fh = snapshot.open(a, true, false).await.unwrap();
+    assert_eq!(data, snapshot.read_all(a, fh, u64::MAX).await.unwrap());
+    snapshot.release(fh).await.unwrap();
+    let found = snapshot
+        .find_by_name(dir.ino, &SecretString::from_str("b").unwrap())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!((b, 10), (found.ino, found.size));
+    assert_eq!(vec![42; 10], {
+        let fh = snapshot.open(b, true, false).await.unwrap();
+        snapshot.read_all(b, fh, u64::MAX).await.unwrap()
+    });
+    assert!(!snapshot.exists(c).await);
+    assert!(matches!(
+        snapshot.set_len(a, 0).await,
+        Err(FsError::ReadOnly)
+    ));
+    let mut changed = data.clone();
+    changed[..7].copy_from_slice(b"changed");
+    let fh = fs.open(a, true, false).await.unwrap();
+    assert_eq!(changed, fs.read_all(a, fh, u64::MAX).await.unwrap());
+    fs.release(fh).await.unwrap();
+
+    // truncated in place
+    fs.set_len(a, 0).await.unwrap();
+    let fh = snapshot.open(a, true, false).await.unwrap();
+    assert_eq!(data, snapshot.read_all(a, fh, u64::MAX).await.unwrap());
+    snapshot.release(fh).await.unwrap();
+    drop(snapshot);
+
+    fs.delete_snapshot("s1").await.unwrap();
+    assert!(fs.list_snapshots().await.unwrap().is_empty());
+    assert!(matches!(
+        fs.open_snapshot("s1").await,
+        Err(FsError::NotFound { .. })
+    ));
+    assert!(fs.check(false).await.unwrap().is_clean());
+}
+
+#[tokio::test]
+async fn test_access_checks() {
+    let fs =
+        EncryptedFs::new_in_memory(Box::new(PasswordProviderImpl {}), Cipher::ChaCha20Poly1305)
+            .await
+            .unwrap();
+    let name = |name: &str| SecretString::from_str(name).unwrap();
+    let owner_fs = fs.with_context(RequestContext::new(1000, 1000, 1));
+    let other_fs = fs.with_context(RequestContext::new(1001, 1001, 2));
+    let root_fs = fs.with_context(RequestContext::new(0, 0, 3));
+
+    // shared with the sticky bit, like /tmp
+    let (_, dir) = fs
+        .create(
+            ROOT_INODE,
+            &name("tmp"),
+            CreateFileAttr {
+                perm: 0o1777,
+                ..create_attr(FileType::Directory)
+            },
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    let (fh, file) = owner_fs
+        .create(
+            dir.ino,
+            &name("file"),
+            CreateFileAttr {
+                perm: 0o644,
+                ..create_attr(FileType::RegularFile)
+            },
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+    assert_eq!((1000, 1000), (file.uid, file.gid));
+
+    // the others can read it but not change it
+    let fh = other_fs.open(file.ino, true, false).await.unwrap();
+    fs.release(fh).await.unwrap();
+    assert!(matches!(
+        other_fs.open(file.ino, false, true).await,
+        Err(FsError::PermissionDenied)
+    ));
+    assert!(matches!(
+        other_fs.set_len(file.ino, 0).await,
+        Err(FsError::PermissionDenied)
+    ));
+    assert!(matches!(
+        other_fs
+            .set_attr(file.ino, SetFileAttr::default().with_perm(0o666))
+            .await,
+        Err(FsError::NotPermitted)
+    ));
+    assert!(matches!(
+        other_fs
+            .set_attr(file.ino, SetFileAttr::default().with_uid(1001))
+            .await,
+        Err(FsError::NotPermitted)
+    ));
+    assert!(matches!(
+        other_fs.remove_file(dir.ino, &name("file")).await,
+        Err(FsError::PermissionDenied)
+    ));
+    assert!(matches!(
+        other_fs
+            .rename(dir.ino, &name("file"), dir.ino, &name("moved"))
+            .await,
+        Err(FsError::PermissionDenied)
+    ));
+
+    owner_fs
+        .set_attr(file.ino, SetFileAttr::default().with_perm(0o600))
+        .await
+        .unwrap();
+    assert!(matches!(
+        other_fs.open(file.ino, true, false).await,
+        Err(FsError::PermissionDenied)
+    ));
+    let (_, private) = owner_fs
+        .create(
+            dir.ino,
+            &name("private"),
+            CreateFileAttr {
+                perm: 0o700,
+                ..create_attr(FileType::Directory)
+            },
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    assert!(matches!(
+        other_fs.read_dir(private.ino).await,
+        Err(FsError::PermissionDenied)
+    ));
+    assert!(matches!(
+        other_fs.find_by_name(private.ino, &name("a")).await,
+        Err(FsError::PermissionDenied)
+    ));
+
+    // root can read and write anything, but execute only with an execute bit
+    let fh = root_fs.open(file.ino, true, true).await.unwrap();
+    fs.release(fh).await.unwrap();
+    assert!(root_fs.read_dir(private.ino).await.is_ok());
+    assert!(matches!(
+        root_fs.access(file.ino, ACCESS_EXEC).await,
+        Err(FsError::PermissionDenied)
+    ));
+    root_fs
+        .set_attr(file.ino, SetFileAttr::default().with_uid(1001))
+        .await
+        .unwrap();
+    other_fs.remove_file(dir.ino, &name("file")).await.unwrap();
+
+    // nothing is checked without a context
+    assert!(fs.read_dir(private.ino).await.is_ok());
+    fs.remove_dir(dir.ino, &name("private")).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_access_checks_times() {
+    let fs =
+        EncryptedFs::new_in_memory(Box::new(PasswordProviderImpl {}), Cipher::ChaCha20Poly1305)
+            .await
+            .unwrap();
+    let owner_fs = fs.with_context(RequestContext::new(1000, 1000, 1));
+    let writer_fs = fs.with_context(RequestContext::new(1001, 1000, 2));
+    let other_fs = fs.with_context(RequestContext::new(1002, 1002, 3));
+    let (fh, file) = owner_fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("file").unwrap(),
+            CreateFileAttr {
+                perm: 0o664,
+                ..create_attr(FileType::RegularFile)
+            },
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+    let explicit = SystemTime::now() + Duration::from_secs(3600);
+
+    // like `utimensat(2)`, with write permission only the current time may be set
+    writer_fs
+        .set_attr(file.ino, SetFileAttr::default().with_times_now())
+        .await
+        .unwrap();
+    assert!(matches!(
+        writer_fs
+            .set_attr(file.ino, SetFileAttr::default().with_mtime(explicit))
+            .await,
+        Err(FsError::NotPermitted)
+    ));
+    assert!(matches!(
+        other_fs
+            .set_attr(file.ino, SetFileAttr::default().with_times_now())
+            .await,
+        Err(FsError::PermissionDenied)
+    ));
+    assert_ne!(explicit, fs.get_attr(file.ino).await.unwrap().mtime);
+
+    owner_fs
+        .set_attr(file.ino, SetFileAttr::default().with_mtime(explicit))
+        .await
+        .unwrap();
+    assert_eq!(explicit, fs.get_attr(file.ino).await.unwrap().mtime);
+}
+
+#[tokio::test]
+async fn test_suid_sgid() {
+    let fs =
+        EncryptedFs::new_in_memory(Box::new(PasswordProviderImpl {}), Cipher::ChaCha20Poly1305)
+            .await
+            .unwrap();
+    let name = |name: &str| SecretString::from_str(name).unwrap();
+    let owner_fs = fs.with_context(RequestContext::new(1000, 1000, 1));
+    let member_fs = fs.with_context(RequestContext::new(1001, 1001, 2).with_groups(vec![2000]));
+    let other_fs = fs.with_context(RequestContext::new(1002, 1002, 3));
+    let root_fs = fs.with_context(RequestContext::new(0, 0, 4));
+    let perm = |ino| {
+        let fs = &fs;
+        async move { fs.get_attr(ino).await.unwrap().perm }
+    };
+
+    let (_, dir) = fs
+        .create(
+            ROOT_INODE,
+            &name("dir"),
+            CreateFileAttr {
+                perm: 0o777,
+                ..create_attr(FileType::Directory)
+            },
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    let (fh, file) = owner_fs
+        .create(
+            dir.ino,
+            &name("file"),
+            CreateFileAttr {
+                perm: 0o6777,
+                ..create_attr(FileType::RegularFile)
+            },
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    // the owner is in the group of the file, so both are kept
+    assert_eq!(0o6777, file.perm);
+
+    // a write clears them, unless root makes it
+    assert_eq!(1, root_fs.write(file.ino, 0, b"a", fh).await.unwrap());
+    assert_eq!(0o6777, perm(file.ino).await);
+    assert_eq!(0, owner_fs.write(file.ino, 1, b"", fh).await.unwrap());
+    assert_eq!(0o6777, perm(file.ino).await);
+    assert_eq!(1, owner_fs.write(file.ino, 1, b"b", fh).await.unwrap());
+    assert_eq!(0o777, perm(file.ino).await);
+    fs.release(fh).await.unwrap();
+
+    // without group execute the SGID bit means mandatory locking and stays
+    fs.set_permissions(file.ino, 0o6767).await.unwrap();
+    let fh = other_fs.open(file.ino, false, true).await.unwrap();
+    other_fs.write(file.ino, 0, b"c", fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+    assert_eq!(0o2767, perm(file.ino).await);
+
+    // so does truncating it
+    fs.set_permissions(file.ino, 0o6777).await.unwrap();
+    root_fs.set_len(file.ino, 1).await.unwrap();
+    assert_eq!(0o6777, perm(file.ino).await);
+    other_fs.set_len(file.ino, 0).await.unwrap();
+    assert_eq!(0o777, perm(file.ino).await);
+    fs.set_permissions(file.ino, 0o6777).await.unwrap();
+    owner_fs
+        .set_attr(file.ino, SetFileAttr::default().with_size(2))
+        .await
+        .unwrap();
+    assert_eq!(0o777, perm(file.ino).await);
+    fs.set_permissions(file.ino, 0o6777).await.unwrap();
+    root_fs
+        .set_attr(file.ino, SetFileAttr::default().with_size(0))
+        .await
+        .unwrap();
+    assert_eq!(0o6777, perm(file.ino).await);
+
+    // and so does extending it with fallocate, allocating inside it changes nothing
+    owner_fs.fallocate(file.ino, 0, 0).await.unwrap();
+    assert_eq!(0o6777, perm(file.ino).await);
+    owner_fs.fallocate(file.ino, 0, 10).await.unwrap();
+    assert_eq!(0o777, perm(file.ino).await);
+    assert_eq!(10, fs.get_attr(file.ino).await.unwrap().size);
+    fs.set_permissions(file.ino, 0o6775).await.unwrap();
+    assert!(matches!(
+        other_fs.fallocate(file.ino, 0, 20).await,
+        Err(FsError::PermissionDenied)
+    ));
+    fs.set_attr(
+        file.ino,
+        SetFileAttr::default().with_size(0).with_perm(0o6777),
+    )
+    .await
+    .unwrap();
+
+    // changing the owner clears them, also for root
+    root_fs
+        .set_attr(file.ino, SetFileAttr::default().with_uid(1001))
+        .await
+        .unwrap();
+    assert_eq!(0o777, perm(file.ino).await);
+    fs.set_permissions(file.ino, 0o6777).await.unwrap();
+    member_fs
+        .set_attr(file.ino, SetFileAttr::default().with_gid(2000))
+        .await
+        .unwrap();
+    assert_eq!(0o777, perm(file.ino).await);
+
+    // the new nodes in a directory with the SGID bit get its group, the directories also the bit
+    fs.set_attr(
+        dir.ino,
+        SetFileAttr::default().with_gid(2000).with_perm(0o2777),
+    )
+    .await
+    .unwrap();
+    let (_, sub_dir) = other_fs
+        .create(
+            dir.ino,
+            &name("sub_dir"),
+            CreateFileAttr {
+                perm: 0o755,
+                ..create_attr(FileType::Directory)
+            },
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        (1002, 2000, 0o2755),
+        (sub_dir.uid, sub_dir.gid, sub_dir.perm)
+    );
+    // the SGID bit of a new file is kept only if the caller is in its group, or is root
+    for (ctx_fs, file_name, expected) in [
+        (&other_fs, "other", 0o4755),
+        (&member_fs, "member", 0o6755),
+        (&root_fs, "root", 0o6755),
+    ] {
+        let (_, attr) = ctx_fs
+            .create(
+                dir.ino,
+                &name(file_name),
+                CreateFileAttr {
+                    perm: 0o6755,
+                    ..create_attr(FileType::RegularFile)
+                },
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!((2000, expected), (attr.gid, attr.perm));
+    }
+}
+
+#[tokio::test]
+async fn test_atime_policy() {
+    async fn read_file_and_dir(fs: &EncryptedFs, ino: u64) {
+        let fh = fs.open(ino, true, false).await.unwrap();
+        fs.read_all(ino, fh, u64::MAX).await.unwrap();
+        fs.release(fh).await.unwrap();
+        fs.read_dir(ROOT_INODE).await.unwrap();
+    }
+
+    async fn inodes(fs: &EncryptedFs, ino: u64) -> (Vec<u8>, Vec<u8>) {
+        (
+            fs.storage.read(&fs.ino_file(ino)).await.unwrap(),
+            fs.storage.read(&fs.ino_file(ROOT_INODE)).await.unwrap(),
+        )
+    }
+
+    for atime in [
+        AtimePolicy::Never,
+        AtimePolicy::Relatime,
+        AtimePolicy::Always,
+    ] {
+        let fs = open_storage_with(
+            Arc::new(InMemoryStorage::new()),
+            FsOptions::builder()
+                .atime(atime)
+                .dir_times_flush_interval(Duration::ZERO)
+                .build(),
+        )
+        .await;
+        let ino = create_file(&fs, ROOT_INODE, "a", &[42; 10]).await;
+
+        let before = inodes(&fs, ino).await;
+        read_file_and_dir(&fs, ino).await;
+        let after_first = inodes(&fs, ino).await;
+        read_file_and_dir(&fs, ino).await;
+        let after_second = inodes(&fs, ino).await;
+        match atime {
+            AtimePolicy::Never => {
+                // the inode files are not written at all
+                assert!(before == after_first && after_first == after_second);
+            }
+            AtimePolicy::Relatime => {
+                // only the first read after the change updates it
+                assert!(before.0 != after_first.0 && before.1 != after_first.1);
+                assert!(after_first == after_second);
+            }
+            AtimePolicy::Always => {
+                assert!(before.0 != after_first.0 && after_first.0 != after_second.0);
+                assert!(before.1 != after_first.1 && after_first.1 != after_second.1);
+            }
+        }
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_blocks() {
+    run_test(
+        TestSetup {
+            key: "test_blocks",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            // like `du`, what the encrypted contents take in 512-byte units rounded up
+            for (i, len) in [0, 1, 512, 513, 4097].into_iter().enumerate() {
+                let ino = create_file(&fs, ROOT_INODE, &format!("file-{i}"), &vec![42; len]).await;
+                let attr = fs.get_attr(ino).await.unwrap();
+                let allocated = fs.open_contents(ino).await.unwrap().allocated().unwrap();
+                assert_eq!(
+                    (allocated.div_ceil(512), BLOCK_SIZE as u32),
+                    (attr.blocks, attr.blksize),
+                    "len {len}"
+                );
+                assert!(attr.blocks * 512 >= len as u64, "len {len}");
+            }
+
+            let ino = create_file(&fs, ROOT_INODE, "changed", &[42; 1000]).await;
+            let blocks = fs.get_attr(ino).await.unwrap().blocks;
+            fs.set_len(ino, 100).await.unwrap();
+            assert!(fs.get_attr(ino).await.unwrap().blocks < blocks);
+
+            // inodes written without them are fixed up when read
+            let attr = FileAttr {
+                blocks: 0,
+                blksize: 0,
+                ..fs.get_attr(ino).await.unwrap()
+            };
+            fs.write_bound(
+                &fs.ino_file(ino),
+                &(attr, Holes::default()),
+                &inode_aad(ino),
+            )
+            .await
+            .unwrap();
+            fs.attr_cache.get().await.unwrap().write().await.clear();
+            let attr = fs.get_attr(ino).await.unwrap();
+            assert_eq!((1, BLOCK_SIZE as u32), (attr.blocks, attr.blksize));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_sparse_write() {
+    async fn stored(fs: &EncryptedFs, ino: u64) -> u64 {
+        fs.open_contents(ino).await.unwrap().allocated().unwrap()
+    }
+
+    async fn read_all(fs: &EncryptedFs, ino: u64) -> Vec<u8> {
+        let fh = fs.open(ino, true, false).await.unwrap();
+        let data = fs.read_all(ino, fh, u64::MAX).await.unwrap();
+        fs.release(fh).await.unwrap();
+        data
+    }
+
+    let fs =
+        EncryptedFs::new_in_memory(Box::new(PasswordProviderImpl {}), Cipher::ChaCha20Poly1305)
+            .await
+            .unwrap();
+    // as many blocks as 1 GB has with the block size outside tests
+    let offset = 4096 * BLOCK_SIZE;
+    let data = vec![42; 1024];
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("sparse").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    let ino = attr.ino;
+    fs.write_all(ino, offset as u64, &data, fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+
+    // only the blocks written are kept, not the zeros before them
+    assert!(stored(&fs, ino).await < 4 * 1024);
+    let attr = fs.get_attr(ino).await.unwrap();
+    assert_eq!((offset + data.len()) as u64, attr.size);
+    assert!(attr.blocks < 8);
+    let contents = read_all(&fs, ino).await;
+    assert!(contents[..offset].iter().all(|b| *b == 0));
+    assert_eq!(data, contents[offset..]);
+
+    // extending leaves a hole too
+    fs.set_len(ino, 2 * offset as u64).await.unwrap();
+    assert!(stored(&fs, ino).await < 4 * 1024);
+    let contents = read_all(&fs, ino).await;
+    assert_eq!(2 * offset, contents.len());
+    assert_eq!(data, contents[offset..offset + data.len()]);
+    assert!(contents[offset + data.len()..].iter().all(|b| *b == 0));
+
+    // the copied zeros are holes
+    let (dest_fh, dest_attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("copy").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    let src_fh = fs.open(ino, true, false).await.unwrap();
+    test_common::copy_all_file_range(&fs, ino, 0, dest_attr.ino, 0, 2 * offset, src_fh, dest_fh)
+        .await;
+    fs.release(src_fh).await.unwrap();
+    fs.release(dest_fh).await.unwrap();
+    assert!(stored(&fs, dest_attr.ino).await < 4 * 1024);
+    assert_eq!(contents, read_all(&fs, dest_attr.ino).await);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_sparse_holes_authenticated() {
+    use std::io::{Seek, SeekFrom, Write};
+
+    let fs =
+        EncryptedFs::new_in_memory(Box::new(PasswordProviderImpl {}), Cipher::ChaCha20Poly1305)
+            .await
+            .unwrap();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("sparse").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    let ino = attr.ino;
+    fs.write_all(ino, 2 * BLOCK_SIZE as u64, &[42; 10], fh)
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+
+    // kept in the inode, read from there
+    fs.contents_holes.lock().unwrap().clear();
+    assert_eq!(
+        vec![0..2],
+        fs.read_holes(ino).await.unwrap().iter().collect::<Vec<_>>()
+    );
+    let fh = fs.open(ino, true, false).await.unwrap();
+    let mut buf = vec![1; 2 * BLOCK_SIZE];
+    assert_eq!(buf.len(), fs.read(ino, 0, &mut buf, fh).await.unwrap());
+    assert!(buf.iter().all(|b| *b == 0));
+    fs.release(fh).await.unwrap();
+
+    // a block of data zeroed in the storage is not a hole
+    let mut contents = fs.open_contents_rw(ino).await.unwrap();
+    let block_len = fs.cipher.ciphertext_block_size();
+    let len = contents.len().unwrap() - 2 * block_len;
+    contents.seek(SeekFrom::Start(2 * block_len)).unwrap();
+    contents.write_all(&vec![0; len as usize]).unwrap();
+    drop(contents);
+    fs.block_cache.invalidate(ino);
+    let fh = fs.open(ino, true, false).await.unwrap();
+    let mut buf = vec![0; 10];
+    assert!(fs
+        .read(ino, 2 * BLOCK_SIZE as u64, &mut buf, fh)
+        .await
+        .is_err());
+    fs.release(fh).await.unwrap();
+    assert_eq!(
+        Ok(2 * BLOCK_SIZE as u64),
+        fs.lseek(ino, 0, 0, SeekWhence::Data).await.map_err(|_| ())
+    );
+}
+
+#[tokio::test]
+async fn test_write_buffer() {
+    async fn stored(fs: &EncryptedFs, ino: u64) -> u64 {
+        fs.open_contents(ino).await.unwrap().allocated().unwrap()
+    }
+
+    let fs = open_storage_with(
+        Arc::new(InMemoryStorage::new()),
+        FsOptions::builder()
+            .write_buffer_size(10 * BLOCK_SIZE)
+            .build(),
+    )
+    .await;
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("buffered").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    let ino = attr.ino;
+    let mut data: Vec<u8> = (0..4 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+    for (i, chunk) in data.chunks(BLOCK_SIZE / 4).enumerate() {
+        let offset = (i * BLOCK_SIZE / 4) as u64;
+        assert_eq!(chunk.len(), fs.write(ino, offset, chunk, fh).await.unwrap());
+    }
+
+    // kept in the buffer, but the size includes it
+    assert_eq!(0, stored(&fs, ino).await);
+    assert_eq!(data.len() as u64, fs.get_attr(ino).await.unwrap().size);
+
+    // reading through another handle writes it first
+    let read_fh = fs.open(ino, true, false).await.unwrap();
+    assert_eq!(data, fs.read_all(ino, read_fh, u64::MAX).await.unwrap());
+    assert!(stored(&fs, ino).await > 0);
+
+    // a write before the buffered ones writes them and starts the buffer again
+    fs.write(ino, data.len() as u64, b"tail", fh).await.unwrap();
+    data.extend_from_slice(b"tail");
+    fs.write(ino, 1, b"head", fh).await.unwrap();
+    data[1..5].copy_from_slice(b"head");
+    let mut buf = vec![0; data.len()];
+    fs.read_exact_at(ino, 0, &mut buf, read_fh).await.unwrap();
+    assert_eq!(data, buf);
+
+    // a full buffer is written without reading
+    let stored_before = stored(&fs, ino).await;
+    let more = vec![7; BLOCK_SIZE / 2];
+    for _ in 0..20 {
+        let offset = data.len() as u64;
+        fs.write(ino, offset, &more, fh).await.unwrap();
+        data.extend_from_slice(&more);
+    }
+    assert!(stored(&fs, ino).await > stored_before);
+
+    fs.release(read_fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+    let fh = fs.open(ino, true, false).await.unwrap();
+    assert_eq!(data, fs.read_all(ino, fh, u64::MAX).await.unwrap());
+    fs.release(fh).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_block_cache() {
+    let fs = open_storage_with(
+        Arc::new(InMemoryStorage::new()),
+        FsOptions::builder()
+            .block_cache_bytes(100 * BLOCK_SIZE)
+            .build(),
+    )
+    .await;
+    let data: Vec<u8> = (0..10 * BLOCK_SIZE + 42).map(|i| (i % 251) as u8).collect();
+    let ino = create_file(&fs, ROOT_INODE, "cached", &[]).await;
+    let fh = fs.open(ino, false, true).await.unwrap();
+    fs.write_all(ino, 0, &data, fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+
+    let fh1 = fs.open(ino, true, false).await.unwrap();
+    let fh2 = fs.open(ino, true, false).await.unwrap();
+    let offset = BLOCK_SIZE / 2;
+    let mut buf = vec![0; 5 * BLOCK_SIZE];
+    fs.read_exact_at(ino, offset as u64, &mut buf, fh1)
+        .await
+        .unwrap();
+    assert_eq!(data[offset..offset + buf.len()], buf);
+    let before = fs.metrics_snapshot().await.block_cache;
+    assert!(before.misses > 0);
+
+    // the other handle decrypts nothing
+    let mut buf = vec![0; 5 * BLOCK_SIZE];
+    fs.read_exact_at(ino, offset as u64, &mut buf, fh2)
+        .await
+        .unwrap();
+    assert_eq!(data[offset..offset + buf.len()], buf);
+    let after = fs.metrics_snapshot().await.block_cache;
+    assert_eq!(before.misses, after.misses);
+    assert!(after.hits > before.hits);
+
+    // a write drops the blocks of the file
+    let fh = fs.open(ino, false, true).await.unwrap();
+    fs.write_all(ino, offset as u64, b"changed", fh)
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+    let mut buf = vec![0; 7];
+    fs.read_exact_at(ino, offset as u64, &mut buf, fh2)
+        .await
+        .unwrap();
+    assert_eq!(b"changed", &buf[..]);
+    assert!(fs.metrics_snapshot().await.block_cache.misses > after.misses);
+
+    // the end of the file
+    let mut buf = vec![0; 2 * BLOCK_SIZE];
+    let len = fs
+        .read(ino, (data.len() - 10) as u64, &mut buf, fh1)
+        .await
+        .unwrap();
+    assert_eq!(10, len);
+    assert_eq!(data[data.len() - 10..], buf[..len]);
+    fs.release(fh1).await.unwrap();
+    fs.release(fh2).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_block_cache_dropped() {
+    let fs = open_storage_with(
+        Arc::new(InMemoryStorage::new()),
+        FsOptions::builder()
+            .block_cache_bytes(100 * BLOCK_SIZE)
+            .build(),
+    )
+    .await;
+    let data: Vec<u8> = (0..3 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+    let ino = create_file(&fs, ROOT_INODE, "cached", &data).await;
+    let fh = fs.open(ino, true, false).await.unwrap();
+    let mut buf = vec![0; data.len()];
+
+    // no plaintext is left in memory after locking
+    fs.read_exact_at(ino, 0, &mut buf, fh).await.unwrap();
+    assert!(!fs.block_cache.is_empty());
+    fs.lock().await.unwrap();
+    assert!(fs.block_cache.is_empty());
+
+    // nor the blocks of contents changed outside
+    fs.read_exact_at(ino, 0, &mut buf, fh).await.unwrap();
+    assert_eq!(data, buf);
+    assert!(!fs.block_cache.is_empty());
+    fs.invalidate_external_change(&fs.contents_path(ino))
+        .await
+        .unwrap();
+    assert!(fs.block_cache.is_empty());
+    let misses = fs.metrics_snapshot().await.block_cache.misses;
+    fs.read_exact_at(ino, 0, &mut buf, fh).await.unwrap();
+    assert_eq!(data, buf);
+    assert!(fs.metrics_snapshot().await.block_cache.misses > misses);
+    fs.release(fh).await.unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_cipher_auto() {
+    let storage: Arc<dyn Storage> = Arc::new(InMemoryStorage::new());
+    let open = |cipher: CipherChoice| {
+        EncryptedFs::with_storage(
+            storage.clone(),
+            Box::new(PasswordProviderImpl {}),
+            cipher,
+            false,
+            FsOptions::default(),
+        )
+    };
+    let cipher_path = Path::new(SECURITY_DIR).join(CIPHER_FILENAME);
+
+    // a new data dir gets the recommended one, whether the CPU has AES instructions or not
+    let fs = open(CipherChoice::Auto).await.unwrap();
+    let recommended = Cipher::recommended();
+    assert_eq!(recommended, fs.cipher);
+    assert_eq!(
+        recommended.to_string().as_bytes(),
+        storage.read(&cipher_path).await.unwrap()
+    );
+    let test_file = SecretString::from_str("test-file").unwrap();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &test_file,
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    fs.write_all(attr.ino, 0, b"data", fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+    drop(fs);
+
+    // and is opened with it without giving it
+    let fs = open(CipherChoice::Auto).await.unwrap();
+    assert_eq!(recommended, fs.cipher);
+    let fh = fs.open(attr.ino, true, false).await.unwrap();
+    let mut buf = [0; 4];
+    assert_eq!(4, fs.read(attr.ino, 0, &mut buf, fh).await.unwrap());
+    assert_eq!(b"data", &buf);
+    fs.release(fh).await.unwrap();
+    drop(fs);
+    assert!(open(recommended.into()).await.is_ok());
+    let other = Cipher::iter().find(|c| *c != recommended).unwrap();
+    assert!(matches!(
+        open(other.into()).await,
+        Err(FsError::InvalidInput { .. })
+    ));
+
+    // made before the cipher was kept, it's kept when it's given
+    storage.remove(&cipher_path).await.unwrap();
+    assert!(matches!(
+        open(CipherChoice::Auto).await,
+        Err(FsError::InvalidInput { .. })
+    ));
+    drop(open(recommended.into()).await.unwrap());
+    assert_eq!(recommended, open(CipherChoice::Auto).await.unwrap().cipher);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_ciphers_round_trip() {
+    for cipher in Cipher::iter() {
+        let storage: Arc<dyn Storage> = Arc::new(InMemoryStorage::new());
+        let open = || {
+            EncryptedFs::with_storage(
+                storage.clone(),
+                Box::new(PasswordProviderImpl {}),
+                cipher,
+                false,
+                FsOptions::default(),
+            )
+        };
+        let fs = open().await.unwrap();
+        let test_file = SecretString::from_str("test-file").unwrap();
+        let (fh, attr) = fs
+            .create(
+                ROOT_INODE,
+                &test_file,
+                create_attr(FileType::RegularFile),
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        let data: Vec<u8> = (0..BLOCK_SIZE * 3 + 42).map(|i| (i % 251) as u8).collect();
+        fs.write_all(attr.ino, 0, &data, fh).await.unwrap();
+        fs.release(fh).await.unwrap();
+        drop(fs);
+
+        // the key is decrypted again, then the names and the contents
+        let fs = open().await.unwrap();
+        let found = fs
+            .find_by_name(ROOT_INODE, &test_file)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(attr.ino, found.ino);
+        let fh = fs.open(attr.ino, true, false).await.unwrap();
+        let mut buf = vec![0; data.len()];
+        assert_eq!(
+            data.len(),
+            fs.read(attr.ino, 0, &mut buf, fh).await.unwrap(),
+            "{cipher}"
+        );
+        assert_eq!(data, buf, "{cipher}");
+        fs.release(fh).await.unwrap();
+        assert!(fs.check(false).await.unwrap().is_clean(), "{cipher}");
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_key_backup() {
+    let storage: Arc<dyn Storage> = Arc::new(InMemoryStorage::new());
+    let cipher = Cipher::ChaCha20Poly1305;
+    let fs = EncryptedFs::with_storage(
+        storage.clone(),
+        Box::new(PasswordProviderImpl {}),
+        cipher,
+        false,
+        FsOptions::default(),
+    )
+    .await
+    .unwrap();
+    let key = fs.key.get().await.unwrap();
+    drop(fs);
+    let key_path = Path::new(SECURITY_DIR).join(KEY_ENC_FILENAME);
+    let backup_path = key_slots::backup_path(&key_path);
+    let password = SecretString::from_str("password").unwrap();
+    let content = storage.read(&key_path).await.unwrap();
+    assert_eq!(content, storage.read(&backup_path).await.unwrap());
+    let read_key = |password: SecretString| {
+        let storage = storage.clone();
+        let key_path = key_path.clone();
+        async move { read_or_create_key(&*storage, &key_path, &password, cipher).await }
+    };
+
+    // a partially written key file is restored from the backup
+    storage
+        .write(&key_path, &content[..content.len() / 2])
+        .await
+        .unwrap();
+    assert_eq!(
+        *key.expose_secret(),
+        *read_key(password.clone()).await.unwrap().expose_secret()
+    );
+    assert_eq!(content, storage.read(&key_path).await.unwrap());
+
+    // and the backup from the key file
+    storage.write(&backup_path, b"garbage").await.unwrap();
+    assert_eq!(
+        *key.expose_secret(),
+        *read_key(password.clone()).await.unwrap().expose_secret()
+    );
+    assert_eq!(content, storage.read(&backup_path).await.unwrap());
+
+    assert!(matches!(
+        read_key(SecretString::from_str("wrong").unwrap()).await,
+        Err(FsError::InvalidPassword)
+    ));
+
+    // interrupted after the key file of a password change was written, the previous password still opens it
+    let new_password = SecretString::from_str("new-password").unwrap();
+    let slots = key_slots::KeySlots {
+        slots: vec![key_slots::KeySlot::seal(&key, &new_password, cipher).unwrap()],
+    };
+    storage
+        .write(&key_path, &slots.to_bytes().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(
+        *key.expose_secret(),
+        *read_key(password).await.unwrap().expose_secret()
+    );
+    assert_eq!(content, storage.read(&key_path).await.unwrap());
+    assert_eq!(
+        crypto::hash(&content).as_slice(),
+        storage
+            .read(&key_slots::checksum_path(&key_path))
+            .await
+            .unwrap()
+    );
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_recovery_key() {
+    run_test(
+        TestSetup {
+            key: "test_recovery_key",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let Some(data_dir) = local_data_dir(&fs) else {
+                return;
+            };
+            let key_path = Path::new(SECURITY_DIR).join(KEY_ENC_FILENAME);
+            let cipher = fs.cipher;
+            let key = fs.key.get().await.unwrap();
+            let password = SecretString::from_str("password").unwrap();
+            let opens = async |password: &SecretString| {
+                read_or_create_key(&*fs.storage, &key_path, password, cipher)
+                    .await
+                    .map(|k| *k.expose_secret() == *key.expose_secret())
+            };
+
+            assert!(matches!(
+                EncryptedFs::generate_recovery_key(
+                    &data_dir,
+                    SecretString::from_str("wrong").unwrap(),
+                    cipher
+                )
+                .await,
+                Err(FsError::InvalidPassword)
+            ));
+            let recovery_key =
+                EncryptedFs::generate_recovery_key(&data_dir, password.clone(), cipher)
+                    .await
+                    .unwrap();
+            // 8 groups of 4 letters
+            let printed = recovery_key.expose_secret().to_string();
+            assert_eq!(39, printed.len());
+            assert_eq!(7, printed.matches('-').count());
+            assert!(opens(&password).await.unwrap());
+
+            assert!(matches!(
+                EncryptedFs::recover_with_key(
+                    &data_dir,
+                    SecretString::from_str("not-a-recovery-key").unwrap(),
+                    password.clone()
+                )
+                .await,
+                Err(FsError::InvalidInput { .. })
+            ));
+            let other = EncryptedFs::generate_recovery_key(&data_dir, password.clone(), cipher)
+                .await
+                .unwrap();
+            // replaced by the new one
+            assert!(matches!(
+                EncryptedFs::recover_with_key(&data_dir, recovery_key, password.clone()).await,
+                Err(FsError::InvalidPassword)
+            ));
+
+            // typed back in lower case without the separators
+            let typed = other.expose_secret().replace('-', " ").to_lowercase();
+            let new_password = SecretString::from_str("new-password").unwrap();
+            EncryptedFs::recover_with_key(
+                &data_dir,
+                SecretString::from_str(&typed).unwrap(),
+                new_password.clone(),
+            )
+            .await
+            .unwrap();
+            assert!(opens(&new_password).await.unwrap());
+            assert!(matches!(
+                opens(&password).await,
+                Err(FsError::InvalidPassword)
+            ));
+            // used once
+            assert!(matches!(
+                EncryptedFs::recover_with_key(
+                    &data_dir,
+                    SecretString::from_str(&typed).unwrap(),
+                    password.clone()
+                )
+                .await,
+                Err(FsError::InvalidPassword)
+            ));
+
+            let revoked =
+                EncryptedFs::generate_recovery_key(&data_dir, new_password.clone(), cipher)
+                    .await
+                    .unwrap();
+            EncryptedFs::revoke_recovery_key(&data_dir, new_password.clone(), cipher)
+                .await
+                .unwrap();
+            assert!(matches!(
+                EncryptedFs::recover_with_key(&data_dir, revoked, password).await,
+                Err(FsError::InvalidPassword)
+            ));
+            assert!(opens(&new_password).await.unwrap());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_directory_keys() {
+    let fs =
+        EncryptedFs::new_in_memory(Box::new(PasswordProviderImpl {}), Cipher::ChaCha20Poly1305)
+            .await
+            .unwrap();
+    let work_password = SecretString::from_str("work").unwrap();
+    let key_id = fs
+        .create_directory_key(work_password.clone())
+        .await
+        .unwrap();
+    let name = |name: &str| SecretString::from_str(name).unwrap();
+    let (_, work) = fs
+        .create(
+            ROOT_INODE,
+            &name("work"),
+            create_attr(FileType::Directory),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    let (_, personal) = fs
+        .create(
+            ROOT_INODE,
+            &name("personal"),
+            create_attr(FileType::Directory),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    assert!(matches!(
+        fs.set_directory_key(work.ino, key_id + 1).await,
+        Err(FsError::InvalidInput { .. })
+    ));
+    fs.set_directory_key(work.ino, key_id).await.unwrap();
+    assert_eq!(Some(key_id), fs.get_attr(work.ino).await.unwrap().key_id);
+
+    // inherited by what is created in it
+    let (fh, file) = fs
+        .create(
+            work.ino,
+            &name("report"),
+            create_attr(FileType::RegularFile),
+            true,
+            true,
+        )
+        .await
+        .unwrap();
+    assert_eq!(Some(key_id), file.key_id);
+    fs.write_all(file.ino, 0, b"quarterly numbers", fh)
+        .await
+        .unwrap();
+    fs.flush(fh).await.unwrap();
+    let (_, sub) = fs
+        .create(
+            work.ino,
+            &name("sub"),
+            create_attr(FileType::Directory),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    assert_eq!(Some(key_id), sub.key_id);
+    let (_, other) = fs
+        .create(
+            personal.ino,
+            &name("notes"),
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    assert_eq!(None, other.key_id);
+    assert!(matches!(
+        fs.set_directory_key(work.ino, key_id).await,
+        Ok(())
+    ));
+    assert!(matches!(
+        fs.set_directory_key(personal.ino, key_id).await,
+        Err(FsError::InvalidInput { .. })
+    ));
+
+    // the names are not encrypted with the master key
+    let master_key = fs.key.get().await.unwrap();
+    for entry in fs
+        .storage
+        .list(&fs.contents_path(work.ino).join(LS_DIR))
+        .await
+        .unwrap()
+    {
+        if !entry.name.starts_with('$') {
+            assert!(crypto::decrypt_file_name(&entry.name, fs.cipher, &master_key).is_err());
+        }
+    }
+
+    fs.lock_directory_key(key_id).await.unwrap();
+    let mut names: Vec<String> = fs
+        .read_dir(work.ino)
+        .await
+        .unwrap()
+        .map(|entry| entry.unwrap().name.expose_secret().clone())
+        .filter(|name| name != "." && name != "..")
+        .collect();
+    names.sort();
+    assert_eq!(2, names.len());
+    assert!(names.iter().all(|name| name.starts_with('_')));
+    assert!(matches!(
+        fs.find_by_name(work.ino, &name("report")).await,
+        Err(FsError::Locked)
+    ));
+    assert!(matches!(
+        fs.open(file.ino, true, false).await,
+        Err(FsError::Locked)
+    ));
+    // the open handle is suspended
+    let mut buf = [0; 17];
+    assert!(matches!(
+        fs.read(file.ino, 0, &mut buf, fh).await,
+        Err(FsError::Locked)
+    ));
+    assert!(matches!(
+        fs.create(
+            work.ino,
+            &name("new"),
+            create_attr(FileType::RegularFile),
+            false,
+            false
+        )
+        .await,
+        Err(FsError::Locked)
+    ));
+    // the rest is not locked
+    assert!(fs
+        .find_by_name(personal.ino, &name("notes"))
+        .await
+        .unwrap()
+        .is_some());
+
+    assert!(matches!(
+        fs.unlock_directory_key(key_id, name("wrong")).await,
+        Err(FsError::InvalidPassword)
+    ));
+    fs.unlock_directory_key(key_id, work_password)
+        .await
+        .unwrap();
+    assert_eq!(
+        file.ino,
+        fs.find_by_name(work.ino, &name("report"))
+            .await
+            .unwrap()
+            .unwrap()
+            .ino
+    );
+    assert_eq!(17, fs.read(file.ino, 0, &mut buf, fh).await.unwrap());
+    assert_eq!(b"quarterly numbers", &buf);
+    fs.release(fh).await.unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_name_padding() {
+    let fs = open_storage_with(
+        Arc::new(crate::storage::InMemoryStorage::new()),
+        FsOptions::builder().name_padding(32).build(),
+    )
+    .await;
+    let name = |name: &str| SecretString::from_str(name).unwrap();
+    for file in ["a", "a longer name.txt"] {
+        fs.create(
+            ROOT_INODE,
+            &name(file),
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    }
+    // both are padded to 32 bytes
+    let ls_dir = fs.contents_path(ROOT_INODE).join(LS_DIR);
+    let encrypted_lens: HashSet<_> = fs
+        .storage
+        .list(&ls_dir)
+        .await
+        .unwrap()
+        .iter()
+        .filter(|entry| !entry.name.starts_with('$'))
+        .map(|entry| entry.name.len())
+        .collect();
+    assert_eq!(1, encrypted_lens.len());
+    assert!(fs
+        .find_by_name(ROOT_INODE, &name("a"))
+        .await
+        .unwrap()
+        .is_some());
+    let mut names: Vec<_> = fs
+        .read_dir(ROOT_INODE)
+        .await
+        .unwrap()
+        .map(|entry| entry.unwrap().name.expose_secret().to_string())
+        .filter(|name| name != "." && name != "..")
+        .collect();
+    names.sort();
+    assert_eq!(vec!["a", "a longer name.txt"], names);
+
+    assert_eq!(
+        fs.max_name_len(),
+        fs.statfs().await.unwrap().max_name_len as usize
+    );
+    fs.create(
+        ROOT_INODE,
+        &name(&"a".repeat(fs.max_name_len())),
+        create_attr(FileType::RegularFile),
+        false,
+        false,
+    )
+    .await
+    .unwrap();
+    assert!(matches!(
+        fs.create(
+            ROOT_INODE,
+            &name(&"b".repeat(fs.max_name_len() + 1)),
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await,
+        Err(FsError::NameTooLong)
+    ));
+    assert_eq!(libc::ENAMETOOLONG, FsError::NameTooLong.to_errno());
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_long_names() {
+    for cipher in Cipher::iter() {
+        let fs = EncryptedFs::new_in_memory(Box::new(PasswordProviderImpl {}), cipher)
+            .await
+            .unwrap();
+        let name = |name: &str| SecretString::from_str(name).unwrap();
+        let short = "s".repeat(crypto::max_file_name_len(cipher, 0));
+        let names = [short, "a".repeat(255), "é".repeat(400)];
+        let (_, dir) = fs
+            .create(
+                ROOT_INODE,
+                &name("dir"),
+                create_attr(FileType::Directory),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+        for file in &names {
+            let (fh, attr) = fs
+                .create(
+                    dir.ino,
+                    &name(file),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.write(attr.ino, 0, &file.as_bytes()[..1], fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+        }
+        // only the long ones are named by a hash, all fit in a path component
+        let ls_dir = fs.contents_path(dir.ino).join(LS_DIR);
+        let ls_names: Vec<_> = fs
+            .storage
+            .list(&ls_dir)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert!(ls_names
+            .iter()
+            .all(|ls_name| ls_name.len() <= crypto::MAX_NAME_COMPONENT_LEN));
+        assert_eq!(
+            2,
+            ls_names
+                .iter()
+                .filter(|ls_name| ls_name.starts_with(LONG_NAME_PREFIX))
+                .count()
+        );
+
+        let mut listed: Vec<_> = fs
+            .read_dir(dir.ino)
+            .await
+            .unwrap()
+            .map(|entry| entry.unwrap().name.expose_secret().to_string())
+            .filter(|name| name != "." && name != "..")
+            .collect();
+        listed.sort();
+        let mut expected = names.to_vec();
+        expected.sort();
+        assert_eq!(expected, listed);
+        // not from the caches
+        fs.clear_dir_entry_caches().await.unwrap();
+        assert_eq!(
+            3,
+            fs.read_dir_plus(dir.ino)
+                .await
+                .unwrap()
+                .filter(|entry| entry.as_ref().unwrap().kind == FileType::RegularFile)
+                .count()
+        );
+        for file in &names {
+            let attr = fs
+                .find_by_name(dir.ino, &name(file))
+                .await
+                .unwrap()
+                .unwrap();
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = [0; 1];
+            assert_eq!(1, fs.read(attr.ino, 0, &mut buf, fh).await.unwrap());
+            assert_eq!(file.as_bytes()[..1], buf);
+            fs.release(fh).await.unwrap();
+        }
+
+        // renamed from a long name to another one, and back to a short one
+        let longer = "b".repeat(400);
+        fs.rename(dir.ino, &name(&names[1]), dir.ino, &name(&longer))
+            .await
+            .unwrap();
+        assert!(fs
+            .find_by_name(dir.ino, &name(&names[1]))
+            .await
+            .unwrap()
+            .is_none());
+        fs.rename(dir.ino, &name(&longer), ROOT_INODE, &name("short"))
+            .await
+            .unwrap();
+        assert!(fs
+            .find_by_name(ROOT_INODE, &name("short"))
+            .await
+            .unwrap()
+            .is_some());
+        fs.remove_file(dir.ino, &name(&names[2])).await.unwrap();
+        assert_eq!(1, fs.len(dir.ino).await.unwrap());
+        assert_eq!(
+            1,
+            fs.storage
+                .list(&ls_dir)
+                .await
+                .unwrap()
+                .iter()
+                .filter(|entry| !entry.name.starts_with('$'))
+                .count()
+        );
+        assert!(fs.check(false).await.unwrap().is_clean());
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_normalize_unicode() {
+    let fs =
+        EncryptedFs::new_in_memory(Box::new(PasswordProviderImpl {}), Cipher::ChaCha20Poly1305)
+            .await
+            .unwrap();
+    let name = |name: &str| SecretString::from_str(name).unwrap();
+    let nfc = "caf\u{e9}";
+    let nfd = "cafe\u{301}";
+
+    let (_, attr) = fs
+        .create(
+            ROOT_INODE,
+            &name(nfd),
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    // found by both spellings
+    for spelling in [nfc, nfd] {
+        assert!(fs
+            .exists_by_name(ROOT_INODE, &name(spelling))
+            .await
+            .unwrap());
+        assert_eq!(
+            attr.ino,
+            fs.find_by_name(ROOT_INODE, &name(spelling))
+                .await
+                .unwrap()
+                .unwrap()
+                .ino
+        );
+    }
+    assert!(matches!(
+        fs.create(
+            ROOT_INODE,
+            &name(nfc),
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await,
+        Err(FsError::AlreadyExists)
+    ));
+    // listed as kept
+    assert_eq!(
+        HashMap::from([(nfc.to_owned(), attr.ino)]),
+        dir_entries(&fs, ROOT_INODE)
+            .await
+            .into_iter()
+            .filter(|(name, _)| name != "." && name != "..")
+            .collect()
+    );
+
+    fs.rename(
+        ROOT_INODE,
+        &name(nfc),
+        ROOT_INODE,
+        &name("e\u{301}te\u{301}"),
+    )
+    .await
+    .unwrap();
+    assert!(dir_entries(&fs, ROOT_INODE)
+        .await
+        .contains_key("\u{e9}t\u{e9}"));
+    fs.remove_file(ROOT_INODE, &name("\u{e9}t\u{e9}"))
+        .await
+        .unwrap();
+    assert!(!fs.exists_by_name(ROOT_INODE, &name(nfd)).await.unwrap());
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_merge_unicode_duplicates() {
+    let storage = Arc::new(InMemoryStorage::new());
+    let open = |normalize_unicode: bool| {
+        open_storage_with(
+            storage.clone(),
+            FsOptions::builder()
+                .normalize_unicode(normalize_unicode)
+                .build(),
+        )
+    };
+    let name = |name: &str| SecretString::from_str(name).unwrap();
+    let nfc = "caf\u{e9}";
+    let nfd = "cafe\u{301}";
+
+    let fs = open(false).await.unwrap();
+    let mut inodes = HashMap::new();
+    for spelling in [nfc, nfd] {
+        let (_, attr) = fs
+            .create(
+                ROOT_INODE,
+                &name(spelling),
+                create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await;
+        inodes.insert(spelling, attr.ino);
+    }
+    // two directories whose entries are merged
+    let mut dirs = vec![];
+    for spelling in [nfd, nfc] {
+        let (_, dir) = fs
+            .create(
+                ROOT_INODE,
+                &name(&format!("dir-{spelling}")),
+                create_attr(FileType::Directory),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+        fs.create(
+            dir.ino,
+            &name(&format!("in-{spelling}")),
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+        dirs.push(dir.ino);
+    }
+    let (_, only_nfd) = fs
+        .create(
+            ROOT_INODE,
+            &name("nai\u{308}ve"),
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    let not_dots = |name: &&String| *name != "." && *name != "..";
+    assert_eq!(
+        5,
+        dir_entries(&fs, ROOT_INODE)
+            .await
+            .keys()
+            .filter(not_dots)
+            .count()
+    );
+    drop(fs);
+
+    // it's kept in the data dir
+    let fs = open(true).await.unwrap();
+    assert_eq!(
+        inodes[nfd],
+        fs.find_by_name(ROOT_INODE, &name(nfd))
+            .await
+            .unwrap()
+            .unwrap()
+            .ino
+    );
+
+    let renamed = fs.merge_unicode_duplicates().await.unwrap();
+    assert_eq!(
+        1,
+        renamed
+            .iter()
+            .filter(|renamed| renamed.ino == inodes[nfd] && renamed.conflict)
+            .count()
+    );
+    let entries = dir_entries(&fs, ROOT_INODE).await;
+    assert_eq!(inodes[nfc], entries[nfc]);
+    assert_eq!(inodes[nfd], entries[&format!("{nfc} (2)")]);
+    assert_eq!(only_nfd.ino, entries["na\u{ef}ve"]);
+    assert_eq!(dirs[1], entries[&format!("dir-{nfc}")]);
+    assert!(!entries.contains_key(&format!("dir-{nfd}")));
+    assert_eq!(4, entries.keys().filter(not_dots).count());
+    // both files are in the kept directory, both named in NFC
+    let merged = dir_entries(&fs, dirs[1]).await;
+    assert!(merged.contains_key(&format!("in-{nfc}")));
+    assert!(merged.contains_key(&format!("in-{nfc} (2)")));
+    assert!(!fs.exists(dirs[0]).await);
+
+    // both spellings find it from now on
+    assert_eq!(
+        only_nfd.ino,
+        fs.find_by_name(ROOT_INODE, &name("nai\u{308}ve"))
+            .await
+            .unwrap()
+            .unwrap()
+            .ino
+    );
+    drop(fs);
+    let fs = open(false).await.unwrap();
+    assert_eq!(
+        inodes[nfc],
+        fs.find_by_name(ROOT_INODE, &name(nfd))
+            .await
+            .unwrap()
+            .unwrap()
+            .ino
+    );
+    assert!(fs.merge_unicode_duplicates().await.unwrap().is_empty());
+    assert!(fs.check(false).await.unwrap().is_clean());
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_hash_collision() {
+    let fs =
+        EncryptedFs::new_in_memory(Box::new(PasswordProviderImpl {}), Cipher::ChaCha20Poly1305)
+            .await
+            .unwrap();
+    let name = |name: &str| SecretString::from_str(name).unwrap();
+    let key = fs.key.get().await.unwrap();
+    let mut inodes = HashMap::new();
+    for file in ["a", "b"] {
+        inodes.insert(file, create_file(&fs, ROOT_INODE, file, &[]).await);
+    }
+    let a_ino = inodes["a"];
+    // put the entry of `a` where the one of `colliding` is, like if both had the same hash
+    let forge = |colliding: &'static str| {
+        let fs = &fs;
+        let key = &key;
+        async move {
+            let encrypted_name = fs
+                .entry_encrypted_name(ROOT_INODE, &name("a"))
+                .await
+                .unwrap();
+            let hash = fs
+                .hash_entry_name(ROOT_INODE, &name(colliding))
+                .await
+                .unwrap();
+            serialize_bound_into(
+                &*fs.storage,
+                &fs.contents_path(ROOT_INODE).join(HASH_DIR).join(&hash),
+                &(a_ino, FileType::RegularFile, encrypted_name),
+                fs.cipher,
+                key,
+                &dir_entry_aad(ROOT_INODE, &hash),
+            )
+            .await
+            .unwrap();
+        }
+    };
+
+    // a name which doesn't exist
+    forge("c").await;
+    assert!(fs
+        .find_by_name(ROOT_INODE, &name("c"))
+        .await
+        .unwrap()
+        .is_none());
+    assert!(!fs.exists_by_name(ROOT_INODE, &name("c")).await.unwrap());
+    let c = create_file(&fs, ROOT_INODE, "c", &[]).await;
+    for (file, ino) in [("a", inodes["a"]), ("c", c)] {
+        assert_eq!(
+            ino,
+            fs.find_by_name(ROOT_INODE, &name(file))
+                .await
+                .unwrap()
+                .unwrap()
+                .ino
+        );
+    }
+
+    // a name which exists
+    forge("b").await;
+    assert_eq!(
+        inodes["b"],
+        fs.find_by_name(ROOT_INODE, &name("b"))
+            .await
+            .unwrap()
+            .unwrap()
+            .ino
+    );
+    assert!(matches!(
+        fs.create(
+            ROOT_INODE,
+            &name("b"),
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await,
+        Err(FsError::AlreadyExists)
+    ));
+    fs.remove_file(ROOT_INODE, &name("b")).await.unwrap();
+    assert!(fs
+        .find_by_name(ROOT_INODE, &name("b"))
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        inodes["a"],
+        fs.find_by_name(ROOT_INODE, &name("a"))
+            .await
+            .unwrap()
+            .unwrap()
+            .ino
+    );
+    let listed = dir_entries(&fs, ROOT_INODE).await;
+    assert_eq!(Some(&inodes["a"]), listed.get("a"));
+    assert_eq!(Some(&c), listed.get("c"));
+    assert!(!listed.contains_key("b"));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_flush_persists_attr() {
+    let fs =
+        EncryptedFs::new_in_memory(Box::new(PasswordProviderImpl {}), Cipher::ChaCha20Poly1305)
+            .await
+            .unwrap();
+    let key = fs.key.get().await.unwrap();
+    let stored_attr = |raw: Vec<u8>, ino: u64| -> FileAttr {
+        deserialize_bound(io::Cursor::new(raw), fs.cipher, &key, &inode_aad(ino)).unwrap()
+    };
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    let data = b"not a whole block";
+    fs.write(attr.ino, 0, data, fh).await.unwrap();
+    let raw = fs.storage.read(&fs.ino_file(attr.ino)).await.unwrap();
+    assert_eq!(0, stored_attr(raw, attr.ino).size);
+
+    fs.flush(fh).await.unwrap();
+    let raw = fs.storage.read(&fs.ino_file(attr.ino)).await.unwrap();
+    assert_eq!(data.len() as u64, stored_attr(raw.clone(), attr.ino).size);
+    // the size kept can be read, while the file is still open
+    let read_fh = fs.open(attr.ino, true, false).await.unwrap();
+    assert_eq!(
+        data.to_vec(),
+        fs.read_all(attr.ino, read_fh, u64::MAX).await.unwrap()
+    );
+    fs.release(read_fh).await.unwrap();
+
+    // nothing changed, the inode is not written again
+    fs.flush(fh).await.unwrap();
+    assert_eq!(raw, fs.storage.read(&fs.ino_file(attr.ino)).await.unwrap());
+
+    fs.write(attr.ino, data.len() as u64, data, fh)
+        .await
+        .unwrap();
+    fs.flush(fh).await.unwrap();
+    let raw = fs.storage.read(&fs.ino_file(attr.ino)).await.unwrap();
+    assert_eq!(2 * data.len() as u64, stored_attr(raw, attr.ino).size);
+    fs.release(fh).await.unwrap();
+    assert_eq!(
+        2 * data.len() as u64,
+        fs.get_attr(attr.ino).await.unwrap().size
+    );
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_get_attr_freshest_handle() {
+    let fs =
+        EncryptedFs::new_in_memory(Box::new(PasswordProviderImpl {}), Cipher::ChaCha20Poly1305)
+            .await
+            .unwrap();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-file").unwrap(),
+            create_attr(FileType::RegularFile),
+            true,
+            true,
+        )
+        .await
+        .unwrap();
+    let ino = attr.ino;
+    let data = b"not a whole block";
+    fs.write(ino, 0, data, fh).await.unwrap();
+    fs.flush(fh).await.unwrap();
+    let read_fh1 = fs.open(ino, true, false).await.unwrap();
+    let read_fh2 = fs.open(ino, true, false).await.unwrap();
+    fs.read_all(ino, read_fh1, u64::MAX).await.unwrap();
+    fs.write(ino, data.len() as u64, data, fh).await.unwrap();
+    fs.read_all(ino, read_fh2, u64::MAX).await.unwrap();
+
+    // the explicit change wins over the times the handles kept
+    let past = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+    fs.set_times(ino, Some(past), Some(past)).await.unwrap();
+    let changed = fs.get_attr(ino).await.unwrap();
+    assert_eq!((past, past), (changed.atime, changed.mtime));
+    assert!(changed.ctime > past);
+    // the size is still the one of the writer
+    assert_eq!(2 * data.len() as u64, changed.size);
+
+    // and it's kept when the handles are released
+    fs.release(read_fh1).await.unwrap();
+    fs.release(fh).await.unwrap();
+    fs.release(read_fh2).await.unwrap();
+    let released = fs.get_attr(ino).await.unwrap();
+    assert_eq!((past, past), (released.atime, released.mtime));
+    assert_eq!(2 * data.len() as u64, released.size);
+
+    // a write after it is newer, its times are taken whole
+    let fh = fs.open(ino, false, true).await.unwrap();
+    let read_fh = fs.open(ino, true, false).await.unwrap();
+    fs.write(ino, 0, data, fh).await.unwrap();
+    let written = fs.get_attr(ino).await.unwrap();
+    assert!(written.mtime > past);
+    assert_eq!(written.mtime, written.ctime);
+    fs.release(fh).await.unwrap();
+    fs.release(read_fh).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_release_keeps_explicit_times() {
+    let fs =
+        EncryptedFs::new_in_memory(Box::new(PasswordProviderImpl {}), Cipher::ChaCha20Poly1305)
+            .await
+            .unwrap();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    let ino = attr.ino;
+    let data = b"not a whole block";
+    fs.write(ino, 0, data, fh).await.unwrap();
+    let read_fh1 = fs.open(ino, true, false).await.unwrap();
+    let read_fh2 = fs.open(ino, true, false).await.unwrap();
+    fs.read_all(ino, read_fh1, u64::MAX).await.unwrap();
+    fs.read_all(ino, read_fh2, u64::MAX).await.unwrap();
+    let before = fs.get_attr(ino).await.unwrap();
+
+    let past = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+    fs.set_times(ino, Some(past), Some(past)).await.unwrap();
+    // the handles still have the newer times they kept from before the change, like when they changed in between
+    {
+        let guard = fs.write_handles.read(&fh).await;
+        let mut ctx = guard.get(&fh).unwrap().lock().await;
+        ctx.attr.atime = before.atime;
+        ctx.attr.mtime = before.mtime;
+        ctx.attr.ctime = before.ctime;
+        ctx.attr_dirty = true;
+    }
+    for read_fh in [read_fh1, read_fh2] {
+        let guard = fs.read_handles.read(&read_fh).await;
+        let mut ctx = guard.get(&read_fh).unwrap().lock().await;
+        ctx.attr = before.into();
+        ctx.accessed = true;
+    }
+
+    // they are older than the explicit change, which is kept when they are flushed and released
+    fs.flush(fh).await.unwrap();
+    fs.release(read_fh1).await.unwrap();
+    fs.release(fh).await.unwrap();
+    fs.release(read_fh2).await.unwrap();
+    fs.attr_cache.get().await.unwrap().write().await.clear();
+    let released = fs.get_attr(ino).await.unwrap();
+    assert_eq!((past, past), (released.atime, released.mtime));
+    assert_eq!(data.len() as u64, released.size);
+}
+
+#[tokio::test]
+async fn test_read_write_past_end() {
+    for block_cache_bytes in [0, 100 * BLOCK_SIZE] {
+        let fs = open_storage_with(
+            Arc::new(InMemoryStorage::new()),
+            FsOptions::builder()
+                .block_cache_bytes(block_cache_bytes)
+                .build(),
+        )
+        .await;
+        let ino = create_file(&fs, ROOT_INODE, "test-file", &[]).await;
+        let fh = fs.open(ino, true, true).await.unwrap();
+        let data = b"past the end";
+
+        // a write past the end extends the file, it never writes nothing
+        let offset = 2 * BLOCK_SIZE as u64 + 7;
+        assert_eq!(data.len(), fs.write(ino, offset, data, fh).await.unwrap());
+        fs.flush(fh).await.unwrap();
+        let size = offset + data.len() as u64;
+        assert_eq!(size, fs.get_attr(ino).await.unwrap().size);
+
+        // only the reads at or past the end read nothing
+        let mut buf = vec![1; data.len()];
+        assert_eq!(
+            data.len(),
+            fs.read(ino, offset, &mut buf, fh).await.unwrap()
+        );
+        assert_eq!(data.to_vec(), buf);
+        assert_eq!(1, fs.read(ino, size - 1, &mut buf, fh).await.unwrap());
+        assert_eq!(0, fs.read(ino, size, &mut buf, fh).await.unwrap());
+        assert_eq!(0, fs.read(ino, size + 1, &mut buf, fh).await.unwrap());
+        assert_eq!(0, fs.read(ino, u64::MAX / 2, &mut buf, fh).await.unwrap());
+        let mut buf = vec![1; 10];
+        assert_eq!(10, fs.read(ino, 5, &mut buf, fh).await.unwrap());
+        assert_eq!(vec![0; 10], buf);
+        fs.release(fh).await.unwrap();
+    }
+}
+
+/// Stops the seeks at `limit` but has `len` bytes, like a reader failing to reach an offset inside the file.
+struct ShortSeek {
+    pos: u64,
+    limit: u64,
+    len: u64,
+}
+
+impl io::Seek for ShortSeek {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            io::SeekFrom::Start(pos) => pos.min(self.limit),
+            io::SeekFrom::End(0) => self.len,
+            _ => unimplemented!(),
+        };
+        Ok(self.pos)
+    }
+}
+
+#[test]
+fn test_seek_for_read() {
+    let mut reader = ShortSeek {
+        pos: 0,
+        limit: 10,
+        len: 20,
+    };
+    assert!(seek_for_read(&mut reader, 5).unwrap());
+    assert!(seek_for_read(&mut reader, 10).unwrap());
+    // inside the file, it's not the end of it
+    assert!(matches!(
+        seek_for_read(&mut reader, 15),
+        Err(FsError::SeekFailed {
+            requested: 15,
+            reached: 10
+        })
+    ));
+    // past the end
+    reader.limit = 20;
+    assert!(seek_for_read(&mut reader, 20).unwrap());
+    assert!(!seek_for_read(&mut reader, 25).unwrap());
+}
+
+#[tokio::test]
+async fn test_max_file_size() {
+    let cipher = Cipher::ChaCha20Poly1305;
+    let max = u64::MAX / cipher.ciphertext_block_size() * BLOCK_SIZE as u64;
+    let len = 10;
+    assert!(check_file_size(cipher, max - len, len).is_ok());
+    assert!(check_file_size(cipher, max - len - 1, len).is_ok());
+    assert!(matches!(
+        check_file_size(cipher, max - len + 1, len),
+        Err(FsError::MaxFilesizeExceeded)
+    ));
+    assert!(matches!(
+        check_file_size(cipher, u64::MAX, len),
+        Err(FsError::MaxFilesizeExceeded)
+    ));
+
+    // the write is not shortened to the limit, so the callers don't retry it forever
+    let fs = EncryptedFs::new_in_memory(Box::new(PasswordProviderImpl {}), cipher)
+        .await
+        .unwrap();
+    let ino = create_file(&fs, ROOT_INODE, "test-file", &[]).await;
+    let fh = fs.open(ino, false, true).await.unwrap();
+    let data = vec![1; len as usize];
+    assert!(matches!(
+        fs.write(ino, max - len + 1, &data, fh).await,
+        Err(FsError::MaxFilesizeExceeded)
+    ));
+    assert!(matches!(
+        fs.write_all(ino, max - len + 1, &data, fh).await,
+        Err(FsError::MaxFilesizeExceeded)
+    ));
+    assert!(matches!(
+        fs.set_len(ino, max + 1).await,
+        Err(FsError::MaxFilesizeExceeded)
+    ));
+    fs.release(fh).await.unwrap();
+    assert_eq!(0, fs.get_attr(ino).await.unwrap().size);
+}
+
+fn consistency_op() -> impl Strategy<Value = ConsistencyOp> {
+    prop_oneof![
+        3 => any::<u8>().prop_map(|file| ConsistencyOp::Open { file }),
+        6 => (any::<u8>(), any::<u32>(), any::<u16>(), any::<u8>()).prop_map(
+            |(handle, offset, len, byte)| ConsistencyOp::Write {
+                handle,
+                offset,
+                len,
+                byte,
+            }
+        ),
+        3 => (any::<u8>(), any::<u32>(), any::<u16>()).prop_map(|(handle, offset, len)| {
+            ConsistencyOp::Read {
+                handle,
+                offset,
+                len,
+            }
+        }),
+        2 => (any::<u8>(), any::<u32>()).prop_map(|(file, size)| ConsistencyOp::SetLen { file, size }),
+        1 => any::<u8>().prop_map(|handle| ConsistencyOp::Flush { handle }),
+        2 => any::<u8>().prop_map(|handle| ConsistencyOp::Release { handle }),
+        1 => (any::<u8>(), any::<u8>()).prop_map(|(file, to)| ConsistencyOp::Rename { file, to }),
+        1 => any::<u8>().prop_map(|file| ConsistencyOp::ResetHandles { file }),
+    ]
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn test_consistency(ops in proptest::collection::vec(consistency_op(), 1..40)) {
+        check_consistency_blocking(&ops);
+    }
+}
+
+#[tokio::test]
+#[allow(clippy::cast_possible_truncation)]
+async fn test_consistency_sequence() {
+    let offset = 2 * BLOCK_SIZE as u32 + 7;
+    check_consistency(&[
+        ConsistencyOp::Open { file: 0 },
+        ConsistencyOp::Open { file: 0 },
+        ConsistencyOp::Write {
+            handle: 0,
+            offset,
+            len: 10,
+            byte: 1,
+        },
+        ConsistencyOp::Write {
+            handle: 1,
+            offset: 3,
+            len: BLOCK_SIZE as u16,
+            byte: 2,
+        },
+        ConsistencyOp::Read {
+            handle: 0,
+            offset: 0,
+            len: 3 * BLOCK_SIZE as u16,
+        },
+        ConsistencyOp::ResetHandles { file: 0 },
+        ConsistencyOp::SetLen {
+            file: 0,
+            size: offset + 3,
+        },
+        ConsistencyOp::Open { file: 1 },
+        ConsistencyOp::Write {
+            handle: 2,
+            offset: 0,
+            len: 5,
+            byte: 3,
+        },
+        ConsistencyOp::Flush { handle: 2 },
+        ConsistencyOp::Rename { file: 0, to: 1 },
+        ConsistencyOp::Write {
+            handle: 0,
+            offset: 1,
+            len: 1,
+            byte: 4,
+        },
+        ConsistencyOp::Release { handle: 1 },
+    ])
+    .await;
+}
+
+/// Cancelled once the progress reaches `at`.
+fn cancel_at(at: u64) -> OpControl {
+    let cancel = OpControl::new();
+    cancel.clone().with_progress(move |done, _| {
+        if done >= at {
+            cancel.cancel();
+        }
+    })
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_op_control() {
+    let fs =
+        EncryptedFs::new_in_memory(Box::new(PasswordProviderImpl {}), Cipher::ChaCha20Poly1305)
+            .await
+            .unwrap();
+    let data: Vec<u8> = (0..BLOCK_SIZE * 50 + 7).map(|i| (i % 251) as u8).collect();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    let ino = attr.ino;
+    fs.write_all(ino, 0, &data, fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+
+    // a truncate cancelled midway leaves the file as it was
+    let size = BLOCK_SIZE as u64 * 40;
+    assert!(matches!(
+        fs.set_len_with(ino, size, &cancel_at(BLOCK_SIZE as u64 * 10))
+            .await,
+        Err(FsError::Cancelled)
+    ));
+    assert_eq!(data.len() as u64, fs.get_attr(ino).await.unwrap().size);
+    assert_eq!(data, read_to_vec(&fs, "test-file").await.unwrap());
+    assert!(kind(&fs, fs.contents_path(ino).with_extension("truncate"))
+        .await
+        .is_none());
+    let progress = Arc::new(std::sync::Mutex::new(vec![]));
+    let progress_clone = progress.clone();
+    let control = OpControl::new()
+        .with_progress(move |done, total| progress_clone.lock().unwrap().push((done, total)));
+    fs.set_len_with(ino, size, &control).await.unwrap();
+    assert_eq!(Some(&(size, size)), progress.lock().unwrap().last());
+    assert_eq!(
+        data[..size as usize],
+        read_to_vec(&fs, "test-file").await.unwrap()
+    );
+
+    // a copy keeps what it copied
+    let dest_ino = create_file(&fs, ROOT_INODE, "dest", &[]).await;
+    let src_fh = fs.open(ino, true, false).await.unwrap();
+    let dest_fh = fs.open(dest_ino, false, true).await.unwrap();
+    let file_range_req = CopyFileRangeReq::builder()
+        .src_ino(ino)
+        .src_offset(0)
+        .dest_ino(dest_ino)
+        .dest_offset(0)
+        .src_fh(src_fh)
+        .dest_fh(dest_fh)
+        .build();
+    let chunk_size = BLOCK_SIZE;
+    assert!(matches!(
+        fs.copy_file_range_chunked(
+            &file_range_req,
+            size as usize,
+            chunk_size,
+            &cancel_at(2 * chunk_size as u64)
+        )
+        .await,
+        Err(FsError::Cancelled)
+    ));
+    fs.release(dest_fh).await.unwrap();
+    fs.release(src_fh).await.unwrap();
+    assert_eq!(
+        data[..2 * chunk_size],
+        read_to_vec(&fs, "dest").await.unwrap()
+    );
+
+    // a check stops after the inode being checked
+    assert!(matches!(
+        fs.check_with(false, &cancel_at(1)).await,
+        Err(FsError::Cancelled)
+    ));
+    assert!(fs
+        .check_with(false, &OpControl::new())
+        .await
+        .unwrap()
+        .is_clean());
+
+    let cancelled = OpControl::new();
+    cancelled.cancel();
+    assert!(matches!(
+        fs.set_len_with(ino, 0, &cancelled).await,
+        Err(FsError::Cancelled)
+    ));
+    assert_eq!(size, fs.get_attr(ino).await.unwrap().size);
+}
+
+/// A new fs with an empty file, opened for read and write.
+async fn lock_test_fs() -> (Arc<EncryptedFs>, u64, u64) {
+    let fs =
+        EncryptedFs::new_in_memory(Box::new(PasswordProviderImpl {}), Cipher::ChaCha20Poly1305)
+            .await
+            .unwrap();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("locked").unwrap(),
+            create_attr(FileType::RegularFile),
+            true,
+            true,
+        )
+        .await
+        .unwrap();
+    (fs, attr.ino, fh)
+}
+
+const fn lock(owner: u64, kind: LockType, range: std::ops::Range<u64>) -> LockInfo {
+    LockInfo { owner, kind, range }
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_lock_split_merge() {
+    use LockType::{Read as R, Write as W};
+    const END: u64 = u64::MAX;
+    // the locks of owner 1, the range set, to a type or unlocked, and the locks after
+    #[allow(clippy::type_complexity)]
+    let cases: Vec<(
+        Vec<(std::ops::Range<u64>, LockType)>,
+        (std::ops::Range<u64>, Option<LockType>),
+        Vec<(std::ops::Range<u64>, LockType)>,
+    )> = vec![
+        // same type
+        (vec![], (0..10, Some(R)), vec![(0..10, R)]),
+        (vec![(0..10, R)], (10..20, Some(R)), vec![(0..20, R)]),
+        (vec![(10..20, R)], (0..10, Some(R)), vec![(0..20, R)]),
+        (vec![(0..10, R)], (5..15, Some(R)), vec![(0..15, R)]),
+        (vec![(0..30, W)], (10..20, Some(W)), vec![(0..30, W)]),
+        (vec![(10..20, W)], (0..30, Some(W)), vec![(0..30, W)]),
+        (
+            vec![(0..10, R)],
+            (11..20, Some(R)),
+            vec![(0..10, R), (11..20, R)],
+        ),
+        (
+            vec![(0..10, R), (20..30, R)],
+            (10..20, Some(R)),
+            vec![(0..30, R)],
+        ),
+        (
+            vec![(0..10, R), (20..30, R)],
+            (5..25, Some(R)),
+            vec![(0..30, R)],
+        ),
+        (vec![(0..10, W)], (5..END, Some(W)), vec![(0..END, W)]),
+        // other type
+        (
+            vec![(0..30, R)],
+            (10..20, Some(W)),
+            vec![(0..10, R), (10..20, W), (20..30, R)],
+        ),
+        (vec![(10..20, R)], (0..30, Some(W)), vec![(0..30, W)]),
+        (vec![(10..20, R)], (10..20, Some(W)), vec![(10..20, W)]),
+        (
+            vec![(0..20, R)],
+            (10..30, Some(W)),
+            vec![(0..10, R), (10..30, W)],
+        ),
+        (
+            vec![(10..30, R)],
+            (0..20, Some(W)),
+            vec![(0..20, W), (20..30, R)],
+        ),
+        (
+            vec![(0..10, R)],
+            (10..20, Some(W)),
+            vec![(0..10, R), (10..20, W)],
+        ),
+        (
+            vec![(0..10, R), (10..20, W), (20..30, R)],
+            (10..20, Some(R)),
+            vec![(0..30, R)],
+        ),
+        (
+            vec![(0..10, W), (10..20, R), (20..30, W)],
+            (5..25, Some(W)),
+            vec![(0..30, W)],
+        ),
+        (
+            vec![(0..END, R)],
+            (100..200, Some(W)),
+            vec![(0..100, R), (100..200, W), (200..END, R)],
+        ),
+        // unlocked
+        (vec![], (0..10, None), vec![]),
+        (vec![(0..10, W)], (20..30, None), vec![(0..10, W)]),
+        (vec![(0..10, W)], (10..20, None), vec![(0..10, W)]),
+        (vec![(10..20, W)], (0..30, None), vec![]),
+        (vec![(10..20, W)], (10..20, None), vec![]),
+        (
+            vec![(0..30, W)],
+            (10..20, None),
+            vec![(0..10, W), (20..30, W)],
+        ),
+        (vec![(0..30, R)], (0..10, None), vec![(10..30, R)]),
+        (vec![(0..30, R)], (20..30, None), vec![(0..20, R)]),
+        (
+            vec![(0..10, R), (10..20, W), (20..30, R)],
+            (5..25, None),
+            vec![(0..5, R), (25..30, R)],
+        ),
+        (vec![(0..END, W)], (100..END, None), vec![(0..100, W)]),
+        (vec![(0..END, W)], (0..END, None), vec![]),
+    ];
+    let (fs, ino, fh) = lock_test_fs().await;
+    // the locks of the other owners are not changed
+    fs.lock_range(ino, fh, 1, 1000..1010, R, false)
+        .await
+        .unwrap();
+    for (owner, (initial, (range, kind), expected)) in (2..).zip(cases) {
+        for (range, kind) in &initial {
+            fs.lock_range(ino, fh, owner, range.clone(), *kind, false)
+                .await
+                .unwrap();
+        }
+        match kind {
+            Some(kind) => fs
+                .lock_range(ino, fh, owner, range.clone(), kind, false)
+                .await
+                .unwrap(),
+            None => fs.unlock_range(ino, owner, &range).unwrap(),
+        }
+        let held: Vec<_> = fs
+            .held_locks(ino)
+            .into_iter()
+            .filter(|lock| lock.owner == owner)
+            .collect();
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|(range, kind)| lock(owner, kind, range))
+            .collect();
+        assert_eq!(expected, held, "{initial:?} then {range:?} set to {kind:?}");
+        fs.release_locks(ino, owner);
+    }
+    assert_eq!(vec![lock(1, R, 1000..1010)], fs.held_locks(ino));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_lock_conflicts() {
+    let (fs, ino, fh) = lock_test_fs().await;
+    fs.lock_range(ino, fh, 1, 0..10, LockType::Read, false)
+        .await
+        .unwrap();
+    // readers share
+    fs.lock_range(ino, fh, 2, 5..15, LockType::Read, false)
+        .await
+        .unwrap();
+    assert!(matches!(
+        fs.lock_range(ino, fh, 2, 5..15, LockType::Write, false)
+            .await,
+        Err(FsError::LockConflict)
+    ));
+    assert!(matches!(
+        fs.lock_range(ino, fh, 3, 9..10, LockType::Write, false)
+            .await,
+        Err(FsError::LockConflict)
+    ));
+    // the failed request didn't change the lock it had
+    assert!(fs.held_locks(ino).contains(&lock(2, LockType::Read, 5..15)));
+    fs.lock_range(ino, fh, 2, 10..20, LockType::Write, false)
+        .await
+        .unwrap();
+    fs.lock_range(ino, fh, 3, 20..u64::MAX, LockType::Write, false)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        Some(lock(1, LockType::Read, 0..10)),
+        fs.test_lock(ino, 3, 0..5, LockType::Write).unwrap()
+    );
+    assert_eq!(None, fs.test_lock(ino, 3, 0..5, LockType::Read).unwrap());
+    assert_eq!(
+        Some(lock(2, LockType::Write, 10..20)),
+        fs.test_lock(ino, 1, 0..15, LockType::Read).unwrap()
+    );
+    assert_eq!(
+        Some(lock(3, LockType::Write, 20..u64::MAX)),
+        fs.test_lock(ino, 1, 1 << 40..(1 << 40) + 1, LockType::Read)
+            .unwrap()
+    );
+    // its own locks don't conflict
+    assert_eq!(None, fs.test_lock(ino, 3, 20..30, LockType::Write).unwrap());
+
+    // the locks are per file
+    let other = create_file(&fs, ROOT_INODE, "other", &[]).await;
+    let other_fh = fs.open(other, true, true).await.unwrap();
+    fs.lock_range(other, other_fh, 3, 0..u64::MAX, LockType::Write, false)
+        .await
+        .unwrap();
+
+    // the handle must be opened for the type
+    let read_fh = fs.open(ino, true, false).await.unwrap();
+    assert!(matches!(
+        fs.lock_range(ino, read_fh, 4, 100..110, LockType::Write, false)
+            .await,
+        Err(FsError::InvalidFileHandle)
+    ));
+    fs.lock_range(ino, read_fh, 4, 0..5, LockType::Read, false)
+        .await
+        .unwrap();
+    assert!(matches!(
+        fs.lock_range(ino, fh, 4, 5..5, LockType::Read, false).await,
+        Err(FsError::InvalidInput { .. })
+    ));
+    assert!(matches!(
+        fs.unlock_range(ino, 4, &(5..5)),
+        Err(FsError::InvalidInput { .. })
+    ));
+
+    // closing a file drops the locks of its owner, also the ones taken through other handles
+    fs.release_locks(ino, 2);
+    assert_eq!(None, fs.test_lock(ino, 1, 0..20, LockType::Read).unwrap());
+    fs.release(read_fh).await.unwrap();
+    assert!(!fs.held_locks(ino).iter().any(|lock| lock.owner == 4));
+    fs.release(fh).await.unwrap();
+    assert_eq!(Vec::<LockInfo>::new(), fs.held_locks(ino));
+    assert_eq!(1, fs.held_locks(other).len());
+    fs.release(other_fh).await.unwrap();
+    assert_eq!(Vec::<LockInfo>::new(), fs.held_locks(other));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_lock_blocking() {
+    let (fs, ino, fh) = lock_test_fs().await;
+    fs.lock_range(ino, fh, 1, 0..10, LockType::Write, false)
+        .await
+        .unwrap();
+    // it waits until the lock is released, and not when the future is dropped
+    assert!(tokio::time::timeout(
+        Duration::from_millis(50),
+        fs.lock_range(ino, fh, 2, 5..15, LockType::Read, true)
+    )
+    .await
+    .is_err());
+    let (locked, ()) = tokio::join!(
+        fs.lock_range(ino, fh, 2, 5..15, LockType::Read, true),
+        async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(
+                Some(lock(1, LockType::Write, 0..10)),
+                fs.test_lock(ino, 2, 5..15, LockType::Read).unwrap()
+            );
+            // a part is not enough
+            fs.unlock_range(ino, 1, &(0..7)).unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert!(!fs.held_locks(ino).iter().any(|lock| lock.owner == 2));
+            fs.unlock_range(ino, 1, &(7..10)).unwrap();
+        }
+    );
+    locked.unwrap();
+    assert_eq!(vec![lock(2, LockType::Read, 5..15)], fs.held_locks(ino));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_lock_fairness() {
+    let (fs, ino, fh) = lock_test_fs().await;
+    fs.lock_range(ino, fh, 1, 0..10, LockType::Read, false)
+        .await
+        .unwrap();
+    let order = std::sync::Mutex::new(vec![]);
+    tokio::join!(
+        async {
+            fs.lock_range(ino, fh, 2, 0..10, LockType::Write, true)
+                .await
+                .unwrap();
+            order.lock().unwrap().push(2);
+            fs.unlock_range(ino, 2, &(0..10)).unwrap();
+        },
+        async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            // a blocking reader waits behind the writer
+            assert!(tokio::time::timeout(
+                Duration::from_millis(50),
+                fs.lock_range(ino, fh, 3, 0..10, LockType::Read, true)
+            )
+            .await
+            .is_err());
+            // one not blocking is given the lock if it's free
+            fs.lock_range(ino, fh, 3, 0..10, LockType::Read, false)
+                .await
+                .unwrap();
+            fs.unlock_range(ino, 3, &(0..10)).unwrap();
+            tokio::join!(
+                async {
+                    fs.lock_range(ino, fh, 3, 0..10, LockType::Read, true)
+                        .await
+                        .unwrap();
+                    order.lock().unwrap().push(3);
+                },
+                async {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    // the owner the writer waits for upgrades its lock before it
+                    fs.lock_range(ino, fh, 1, 0..10, LockType::Write, true)
+                        .await
+                        .unwrap();
+                    order.lock().unwrap().push(1);
+                    fs.release_locks(ino, 1);
+                }
+            );
+        }
+    );
+    assert_eq!(vec![1, 2, 3], *order.lock().unwrap());
+    assert_eq!(vec![lock(3, LockType::Read, 0..10)], fs.held_locks(ino));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_lock_deadlock() {
+    let (fs, ino, fh) = lock_test_fs().await;
+    fs.lock_range(ino, fh, 1, 0..10, LockType::Write, false)
+        .await
+        .unwrap();
+    fs.lock_range(ino, fh, 2, 10..20, LockType::Write, false)
+        .await
+        .unwrap();
+    fs.lock_range(ino, fh, 3, 20..30, LockType::Write, false)
+        .await
+        .unwrap();
+    let (first, ()) = tokio::join!(
+        fs.lock_range(ino, fh, 1, 10..20, LockType::Write, true),
+        async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            // 1 waits for 2, 2 would wait for 3 which would wait for 1
+            let (second, ()) = tokio::join!(
+                fs.lock_range(ino, fh, 2, 20..30, LockType::Write, true),
+                async {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    assert!(matches!(
+                        fs.lock_range(ino, fh, 3, 0..10, LockType::Write, true)
+                            .await,
+                        Err(FsError::Deadlock)
+                    ));
+                    fs.release_locks(ino, 3);
+                }
+            );
+            second.unwrap();
+            fs.release_locks(ino, 2);
+        }
+    );
+    first.unwrap();
+    assert_eq!(vec![lock(1, LockType::Write, 0..20)], fs.held_locks(ino));
+    fs.release_locks(ino, 1);
+
+    fs.lock_range(ino, fh, 1, 0..10, LockType::Read, false)
+        .await
+        .unwrap();
+    fs.lock_range(ino, fh, 2, 0..10, LockType::Read, false)
+        .await
+        .unwrap();
+    let (first, ()) = tokio::join!(
+        fs.lock_range(ino, fh, 1, 0..10, LockType::Write, true),
+        async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert!(matches!(
+                fs.lock_range(ino, fh, 2, 0..10, LockType::Write, true)
+                    .await,
+                Err(FsError::Deadlock)
+            ));
+            fs.release_locks(ino, 2);
+        }
+    );
+    first.unwrap();
+    assert_eq!(vec![lock(1, LockType::Write, 0..10)], fs.held_locks(ino));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_flock() {
+    let (fs, ino, fh) = lock_test_fs().await;
+    let other_fh = fs.open(ino, true, false).await.unwrap();
+
+    // shared with the other handles
+    fs.flock(fh, Flock::Shared, true).await.unwrap();
+    fs.flock(other_fh, Flock::Shared, true).await.unwrap();
+    assert!(matches!(
+        fs.flock(fh, Flock::Exclusive, true).await,
+        Err(FsError::LockConflict)
+    ));
+    // the failed upgrade dropped the shared lock, like Linux
+    fs.flock(other_fh, Flock::Exclusive, true).await.unwrap();
+    assert!(matches!(
+        fs.flock(fh, Flock::Shared, true).await,
+        Err(FsError::LockConflict)
+    ));
+    // taking it again is a no-op
+    fs.flock(other_fh, Flock::Exclusive, true).await.unwrap();
+
+    // the record locks are apart
+    fs.lock_range(ino, fh, 1, 0..u64::MAX, LockType::Write, false)
+        .await
+        .unwrap();
+
+    // waits until the other handle downgrades its lock
+    let (locked, ()) = tokio::join!(fs.flock(fh, Flock::Shared, false), async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        fs.flock(other_fh, Flock::Shared, true).await.unwrap();
+    });
+    locked.unwrap();
+    fs.flock(other_fh, Flock::Unlock, true).await.unwrap();
+    fs.flock(other_fh, Flock::Unlock, true).await.unwrap();
+
+    // released with the handle
+    let (locked, ()) = tokio::join!(fs.flock(other_fh, Flock::Exclusive, false), async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        fs.release(fh).await.unwrap();
+    });
+    locked.unwrap();
+    assert!(fs.held_locks(ino).is_empty());
+    assert!(matches!(
+        fs.flock(fh, Flock::Shared, true).await,
+        Err(FsError::InvalidFileHandle)
+    ));
+
+    // the handles of another file don't conflict
+    let dest = create_file(&fs, ROOT_INODE, "other", &[]).await;
+    let dest_fh = fs.open(dest, true, false).await.unwrap();
+    fs.flock(dest_fh, Flock::Exclusive, true).await.unwrap();
+    fs.release(dest_fh).await.unwrap();
+    fs.release(other_fh).await.unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_dir_iteration_order() {
+    let fs = open_storage_with(
+        Arc::new(InMemoryStorage::new()),
+        FsOptions::builder()
+            .dir_iteration_order(DirIterationOrder::ByNameAscending)
+            .build(),
+    )
+    .await;
+    let listed = |fs: Arc<EncryptedFs>| async move {
+        let names: Vec<String> = fs
+            .read_dir(ROOT_INODE)
+            .await
+            .unwrap()
+            .map(|entry| entry.unwrap().name.expose_secret().to_string())
+            .collect();
+        let plus: Vec<String> = fs
+            .read_dir_plus(ROOT_INODE)
+            .await
+            .unwrap()
+            .map(|entry| entry.unwrap().name.expose_secret().to_string())
+            .collect();
+        assert_eq!(names, plus);
+        names
+    };
+    let mut expected = vec![".".to_string(), "..".to_string()];
+    for name in ["zeta", "alpha", "Beta", "mu", "10", "9", "été", "alpha2"] {
+        create_file(&fs, ROOT_INODE, name, &[]).await;
+        expected.push(name.to_string());
+    }
+    fs.create(
+        ROOT_INODE,
+        &SecretString::from_str("dir").unwrap(),
+        create_attr(FileType::Directory),
+        false,
+        false,
+    )
+    .await
+    .unwrap();
+    expected.push("dir".to_string());
+    expected[2..].sort();
+    assert_eq!(expected, listed(fs.clone()).await);
+    // the same each time
+    assert_eq!(expected, listed(fs.clone()).await);
+
+    // and after adding entries, the ones before keep their order
+    for name in ["beta", "0", "zz"] {
+        create_file(&fs, ROOT_INODE, name, &[]).await;
+        expected.push(name.to_string());
+    }
+    expected[2..].sort();
+    assert_eq!(expected, listed(fs.clone()).await);
+    assert_eq!(
+        vec!["zeta", "zz", "\u{e9}t\u{e9}"],
+        expected[expected.len() - 3..]
+    );
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_file_id() {
+    let fs = open_storage_with(
+        Arc::new(InMemoryStorage::new()),
+        FsOptions::builder()
+            .inode_alloc(InodeAlloc::Sequential)
+            .build(),
+    )
+    .await;
+    let name = SecretString::from_str("file").unwrap();
+    let ino = create_file(&fs, ROOT_INODE, "file", &[]).await;
+    let old = fs.get_attr(ino).await.unwrap();
+    assert_ne!(0, old.ino_generation);
+    let root = fs.get_attr(ROOT_INODE).await.unwrap();
+    assert_ne!(root.ino_generation, old.ino_generation);
+    assert!(fs
+        .get_attr_id(FileId {
+            ino: ROOT_INODE,
+            generation: root.ino_generation,
+        })
+        .await
+        .is_ok());
+    let stale = fs.open_id(old.file_id(), true, true).await.unwrap();
+    assert_eq!(1, fs.write(ino, 0, b"a", stale).await.unwrap());
+    fs.flush(stale).await.unwrap();
+
+    // removed and created again with the same number
+    fs.remove_file(ROOT_INODE, &name).await.unwrap();
+    assert!(matches!(
+        fs.get_attr_id(old.file_id()).await,
+        Err(FsError::StaleFileId)
+    ));
+    *fs.inode_counter.lock().await = ino..ino + 1;
+    assert_eq!(ino, create_file(&fs, ROOT_INODE, "file", &[]).await);
+    let new = fs.get_attr(ino).await.unwrap();
+    assert_ne!(old.ino_generation, new.ino_generation);
+
+    // the old id finds nothing, the handle opened with it fails
+    assert!(matches!(
+        fs.open_id(old.file_id(), true, false).await,
+        Err(FsError::StaleFileId)
+    ));
+    let mut buf = [0; 1];
+    assert!(matches!(
+        fs.read(ino, 0, &mut buf, stale).await,
+        Err(FsError::StaleFileId)
+    ));
+    assert!(matches!(
+        fs.write(ino, 0, b"b", stale).await,
+        Err(FsError::StaleFileId)
+    ));
+    fs.release(stale).await.unwrap();
+
+    // the new one works
+    let fh = fs.open_id(new.file_id(), false, true).await.unwrap();
+    assert_eq!(1, fs.write(ino, 0, b"c", fh).await.unwrap());
+    fs.release(fh).await.unwrap();
+    assert_eq!("c", test_common::read_to_string(ino, &fs).await);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_throttle() {
+    const LIMIT: u64 = 256 * 1024;
+    let limits = ThrottleLimits {
+        read_bytes_per_sec: NonZeroU64::new(LIMIT),
+        write_bytes_per_sec: NonZeroU64::new(LIMIT),
+        max_concurrent_ops: NonZeroUsize::new(2),
+    };
+    let fs = open_storage_with(
+        Arc::new(InMemoryStorage::new()),
+        FsOptions::builder().throttle(limits).build(),
+    )
+    .await;
+    // the first 100ms of the limit can go at once
+    let in_tolerance = |len: u64, elapsed: Duration| {
+        let min = Duration::from_secs_f64(len as f64 / LIMIT as f64 - 0.1);
+        elapsed >= min.mul_f64(0.9) && elapsed <= min.mul_f64(1.5) + Duration::from_millis(300)
+    };
+    let data = vec![42; 2 * LIMIT as usize];
+
+    // writes and reads go at the limit
+    let ino = create_file(&fs, ROOT_INODE, "file", &[]).await;
+    let fh = fs.open(ino, true, true).await.unwrap();
+    let start = Instant::now();
+    for (i, chunk) in data.chunks(32 * 1024).enumerate() {
+        fs.write_all(ino, (i * 32 * 1024) as u64, chunk, fh)
+            .await
+            .unwrap();
+    }
+    fs.flush(fh).await.unwrap();
+    assert!(in_tolerance(data.len() as u64, start.elapsed()));
+    let start = Instant::now();
+    let mut buf = vec![0; data.len()];
+    fs.read_exact_at(ino, 0, &mut buf, fh).await.unwrap();
+    assert!(in_tolerance(data.len() as u64, start.elapsed()));
+    assert_eq!(data, buf);
+    let status = fs.metrics_snapshot().await.throttle;
+    assert_eq!(limits, status.limits);
+    assert_eq!(0, status.running_ops);
+    assert!(status.read_wait_micros > 0 && status.write_wait_micros > 0);
+
+    // a handle reading a lot doesn't starve the others
+    let other = fs.open(ino, true, false).await.unwrap();
+    let greedy = {
+        let fs = fs.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 2 * LIMIT as usize];
+            fs.read_exact_at(ino, 0, &mut buf, fh).await.unwrap();
+            Instant::now()
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut buf = vec![0; 16 * 1024];
+    fs.read_exact_at(ino, 0, &mut buf, other).await.unwrap();
+    let other_done = Instant::now();
+    assert!(other_done + Duration::from_millis(500) < greedy.await.unwrap());
+
+    // without limits
+    fs.set_throttle(ThrottleLimits::default());
+    let start = Instant::now();
+    let mut buf = vec![0; data.len()];
+    fs.read_exact_at(ino, 0, &mut buf, other).await.unwrap();
+    assert!(start.elapsed() < Duration::from_millis(500));
+    assert_eq!(
+        ThrottleLimits::default(),
+        fs.metrics_snapshot().await.throttle.limits
+    );
+    fs.release(fh).await.unwrap();
+    fs.release(other).await.unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_special_files() {
+    // the existing vaults only stored these two, by the index of the variant
+    assert_eq!(
+        bincode::serialize(&0_u32).unwrap(),
+        bincode::serialize(&FileType::Directory).unwrap()
+    );
+    assert_eq!(
+        bincode::serialize(&1_u32).unwrap(),
+        bincode::serialize(&FileType::RegularFile).unwrap()
+    );
+
+    let fs = open_storage_with(Arc::new(InMemoryStorage::new()), FsOptions::default()).await;
+    for (name, kind, rdev) in [
+        ("pipe", FileType::NamedPipe, 0),
+        ("char", FileType::CharDevice, 0x0103),
+        ("block", FileType::BlockDevice, 0x0801),
+        ("socket", FileType::Socket, 0),
+    ] {
+        let mut attr = create_attr(kind);
+        attr.rdev = rdev;
+        let (fh, attr) = fs
+            .create(
+                ROOT_INODE,
+                &SecretString::from_str(name).unwrap(),
+                attr,
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!(0, fh);
+        let attr = fs.get_attr(attr.ino).await.unwrap();
+        assert_eq!(kind, attr.kind);
+        assert_eq!(rdev, attr.rdev);
+        assert_eq!(0, attr.size);
+
+        let entry = fs
+            .read_dir_plus(ROOT_INODE)
+            .await
+            .unwrap()
+            .map(Result::unwrap)
+            .find(|entry| *entry.name.expose_secret() == name)
+            .unwrap();
+        assert_eq!(kind, entry.kind);
+        assert_eq!(kind, entry.attr.kind);
+        assert_eq!(rdev, entry.attr.rdev);
+
+        // the contents can't be read or written
+        assert!(matches!(
+            fs.open(attr.ino, true, true).await,
+            Err(FsError::InvalidInodeType)
+        ));
+        let mut buf = [0; 1];
+        assert!(matches!(
+            fs.read(attr.ino, 0, &mut buf, 1).await,
+            Err(FsError::InvalidInodeType)
+        ));
+        assert!(matches!(
+            fs.write(attr.ino, 0, b"a", 1).await,
+            Err(FsError::InvalidInodeType)
+        ));
+
+        fs.remove_file(ROOT_INODE, &SecretString::from_str(name).unwrap())
+            .await
+            .unwrap();
+        assert!(!fs
+            .exists_by_name(ROOT_INODE, &SecretString::from_str(name).unwrap())
+            .await
+            .unwrap());
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[traced_test]
+async fn test_write_flush_other_handle() {
+    let fs = open_storage_with(Arc::new(InMemoryStorage::new()), FsOptions::default()).await;
+    let ino = create_file(&fs, ROOT_INODE, "file", &[]).await;
+    let fh_a = fs.open(ino, false, true).await.unwrap();
+    let fh_b = fs.open(ino, false, true).await.unwrap();
+
+    // with two writers the write locks the other handle and the flush its own, they must not wait for each other
+    let writer = {
+        let fs = fs.clone();
+        tokio::spawn(async move {
+            for i in 0..200_u64 {
+                fs.write(ino, i * 10, &[1; 10], fh_a).await.unwrap();
+            }
+        })
+    };
+    let flusher = {
+        let fs = fs.clone();
+        tokio::spawn(async move {
+            for i in 0..200_u64 {
+                fs.write(ino, 10_000 + i, &[2], fh_b).await.unwrap();
+                fs.flush(fh_b).await.unwrap();
+            }
+        })
+    };
+    tokio::time::timeout(Duration::from_secs(60), async {
+        writer.await.unwrap();
+        flusher.await.unwrap();
+    })
+    .await
+    .expect("write and flush deadlocked");
+
+    fs.flush(fh_a).await.unwrap();
+    fs.release(fh_a).await.unwrap();
+    fs.release(fh_b).await.unwrap();
+    assert_eq!(10_200, fs.get_attr(ino).await.unwrap().size);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_write_missing_writer() {
+    async fn drop_writer(fs: &EncryptedFs, fh: u64) {
+        // like after finishing it failed
+        fs.write_handles
+            .read(&fh)
+            .await
+            .get(&fh)
+            .unwrap()
+            .lock()
+            .await
+            .writer = None;
+    }
+
+    let fs = open_storage_with(Arc::new(InMemoryStorage::new()), FsOptions::default()).await;
+    let ino = create_file(&fs, ROOT_INODE, "file", &[]).await;
+    let fh = fs.open(ino, true, true).await.unwrap();
+
+    // the buffered writes are given to a new writer
+    fs.write(ino, 0, b"abc", fh).await.unwrap();
+    drop_writer(&fs, fh).await;
+    fs.write(ino, 3, b"def", fh).await.unwrap();
+    drop_writer(&fs, fh).await;
+    fs.flush(fh).await.unwrap();
+    assert_eq!("abcdef", test_common::read_to_string(ino, &fs).await);
+
+    // the writes not buffered too
+    drop_writer(&fs, fh).await;
+    let data = vec![b'g'; DEFAULT_WRITE_BUFFER_SIZE * 2];
+    fs.write_all(ino, 6, &data, fh).await.unwrap();
+    fs.write(ino, 6 + data.len() as u64, b"h", fh)
+        .await
+        .unwrap();
+    drop_writer(&fs, fh).await;
+    fs.fsync(fh, false).await.unwrap();
+    fs.release(fh).await.unwrap();
+    let contents = test_common::read_to_string(ino, &fs).await;
+    assert_eq!(7 + data.len(), contents.len());
+    assert!(contents.ends_with("gh"));
+}
@@ -0,0 +1,280 @@
+//! Limits on the bytes read and written per second and on the reads and writes running at once, see
+//! [`FsOptions::throttle`] and [`EncryptedFs::set_throttle`].
+//!
+//! The bytes are taken from a token bucket holding what can be moved in [`BURST`], waiting for it to fill when it's
+//! empty. Large requests take them in chunks of the bucket size, and each handle waits for one chunk at a time in the
+//! order they asked, so the handles take turns and one reading or writing a lot doesn't starve the others.
+//!
+//! Writes wait before they are made, reads after, with what they have read, so a read at the end of a file doesn't
+//! wait for what it asked and didn't get.
+//!
+//! [`FsOptions::throttle`]: crate::encryptedfs::FsOptions::throttle
+
+use std::collections::HashMap;
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::encryptedfs::EncryptedFs;
+
+/// What the buckets hold, the bytes that can go at once after being idle.
+const BURST: Duration = Duration::from_millis(100);
+/// Max time a waiter sleeps before looking at the limits again, so it sees when they are changed.
+const MAX_SLEEP: Duration = Duration::from_millis(100);
+
+/// Limits on the reads and writes of the contents, see [`FsOptions::throttle`]. `None` doesn't limit it.
+///
+/// [`FsOptions::throttle`]: crate::encryptedfs::FsOptions::throttle
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThrottleLimits {
+    /// Max bytes read per second by all the handles, [`EncryptedFs::copy_file_range`] counts what it reads.
+    pub read_bytes_per_sec: Option<NonZeroU64>,
+    /// Max bytes written per second by all the handles, [`EncryptedFs::copy_file_range`] counts what it writes.
+    pub write_bytes_per_sec: Option<NonZeroU64>,
+    /// Max reads and writes running at once, the others wait for one of them to finish.
+    pub max_concurrent_ops: Option<NonZeroUsize>,
+}
+
+/// The throttling now, in [`MetricsSnapshot::throttle`](crate::encryptedfs::MetricsSnapshot::throttle).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThrottleStatus {
+    pub limits: ThrottleLimits,
+    /// Reads and writes running now.
+    pub running_ops: u64,
+    /// Reads and writes waiting for [`ThrottleLimits::max_concurrent_ops`].
+    pub waiting_ops: u64,
+    /// Total time the reads waited for [`ThrottleLimits::read_bytes_per_sec`], of all the handles.
+    pub read_wait_micros: u64,
+    /// Total time the writes waited for [`ThrottleLimits::write_bytes_per_sec`], of all the handles.
+    pub write_wait_micros: u64,
+}
+
+pub(crate) struct Throttle {
+    read: TokenBucket,
+    write: TokenBucket,
+    ops: Mutex<Ops>,
+    /// Notified when an operation finishes or the limits change.
+    ops_changed: Notify,
+    /// Each handle waits for one chunk at a time, see the module docs.
+    handles: Mutex<HashMap<u64, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+struct Ops {
+    max: Option<NonZeroUsize>,
+    running: u64,
+    waiting: u64,
+}
+
+struct TokenBucket {
+    /// Bytes per second, `0` for no limit.
+    rate: AtomicU64,
+    /// Taken in the order they are asked.
+    state: tokio::sync::Mutex<BucketState>,
+    wait_micros: AtomicU64,
+}
+
+struct BucketState {
+    tokens: u64,
+    last: Instant,
+}
+
+/// A read or write counted in [`ThrottleLimits::max_concurrent_ops`] until it's dropped.
+pub(crate) struct OpPermit<'a> {
+    throttle: &'a Throttle,
+}
+
+impl Drop for OpPermit<'_> {
+    fn drop(&mut self) {
+        self.throttle
+            .ops
+            .lock()
+            .expect("cannot obtain lock")
+            .running -= 1;
+        self.throttle.ops_changed.notify_one();
+    }
+}
+
+impl Throttle {
+    pub(crate) fn new(limits: ThrottleLimits) -> Self {
+        Self {
+            read: TokenBucket::new(limits.read_bytes_per_sec),
+            write: TokenBucket::new(limits.write_bytes_per_sec),
+            ops: Mutex::new(Ops {
+                max: limits.max_concurrent_ops,
+                running: 0,
+                waiting: 0,
+            }),
+            ops_changed: Notify::new(),
+            handles: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn set_limits(&self, limits: ThrottleLimits) {
+        self.read.set_rate(limits.read_bytes_per_sec);
+        self.write.set_rate(limits.write_bytes_per_sec);
+        self.ops.lock().expect("cannot obtain lock").max = limits.max_concurrent_ops;
+        self.ops_changed.notify_waiters();
+    }
+
+    pub(crate) fn status(&self) -> ThrottleStatus {
+        let ops = self.ops.lock().expect("cannot obtain lock");
+        ThrottleStatus {
+            limits: ThrottleLimits {
+                read_bytes_per_sec: self.read.rate(),
+                write_bytes_per_sec: self.write.rate(),
+                max_concurrent_ops: ops.max,
+            },
+            running_ops: ops.running,
+            waiting_ops: ops.waiting,
+            read_wait_micros: self.read.wait_micros.load(Ordering::Relaxed),
+            write_wait_micros: self.write.wait_micros.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Wait until one more read or write can run.
+    pub(crate) async fn start_op(&self) -> OpPermit<'_> {
+        let mut waiting = false;
+        loop {
+            let notified = self.ops_changed.notified();
+            tokio::pin!(notified);
+            // registered before looking, so a permit dropped meanwhile wakes us
+            notified.as_mut().enable();
+            {
+                let mut ops = self.ops.lock().expect("cannot obtain lock");
+                if !ops.max.is_some_and(|max| ops.running >= max.get() as u64) {
+                    ops.running += 1;
+                    if waiting {
+                        ops.waiting -= 1;
+                    }
+                    return OpPermit { throttle: self };
+                }
+                if !waiting {
+                    ops.waiting += 1;
+                    waiting = true;
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Wait until `len` bytes can be read by `handle`.
+    pub(crate) async fn wait_read(&self, handle: u64, len: u64) {
+        self.wait(&self.read, handle, len).await;
+    }
+
+    /// Wait until `len` bytes can be written by `handle`.
+    pub(crate) async fn wait_write(&self, handle: u64, len: u64) {
+        self.wait(&self.write, handle, len).await;
+    }
+
+    async fn wait(&self, bucket: &TokenBucket, handle: u64, len: u64) {
+        if bucket.rate().is_none() || len == 0 {
+            return;
+        }
+        let turn = self
+            .handles
+            .lock()
+            .expect("cannot obtain lock")
+            .entry(handle)
+            .or_default()
+            .clone();
+        let _turn = turn.lock().await;
+        let start = Instant::now();
+        let mut left = len;
+        while left > 0 {
+            let Some(taken) = bucket.take(left).await else {
+                // the limit was removed meanwhile
+                break;
+            };
+            left -= taken;
+        }
+        bucket.wait_micros.fetch_add(
+            u64::try_from(start.elapsed().as_micros()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// The handle was released.
+    pub(crate) fn forget_handle(&self, handle: u64) {
+        self.handles
+            .lock()
+            .expect("cannot obtain lock")
+            .remove(&handle);
+    }
+}
+
+impl TokenBucket {
+    fn new(rate: Option<NonZeroU64>) -> Self {
+        let mut bucket = Self {
+            rate: AtomicU64::new(rate.map_or(0, NonZeroU64::get)),
+            state: tokio::sync::Mutex::new(BucketState {
+                tokens: 0,
+                last: Instant::now(),
+            }),
+            wait_micros: AtomicU64::new(0),
+        };
+        // it starts full
+        let size = bucket.size();
+        bucket.state.get_mut().tokens = size;
+        bucket
+    }
+
+    fn rate(&self) -> Option<NonZeroU64> {
+        NonZeroU64::new(self.rate.load(Ordering::Relaxed))
+    }
+
+    fn set_rate(&self, rate: Option<NonZeroU64>) {
+        self.rate
+            .store(rate.map_or(0, NonZeroU64::get), Ordering::Relaxed);
+    }
+
+    /// Bytes it holds when full, at least one.
+    #[allow(clippy::cast_possible_truncation)]
+    fn size(&self) -> u64 {
+        let rate = self.rate.load(Ordering::Relaxed);
+        (u128::from(rate) * BURST.as_millis() / 1000).max(1) as u64
+    }
+
+    /// Take up to `len` bytes, at most the size of the bucket, waiting for them. `None` if there is no limit.
+    #[allow(clippy::cast_possible_truncation)]
+    async fn take(&self, len: u64) -> Option<u64> {
+        let mut state = self.state.lock().await;
+        loop {
+            let rate = self.rate.load(Ordering::Relaxed);
+            if rate == 0 {
+                return None;
+            }
+            let size = self.size();
+            let chunk = len.min(size);
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.last);
+            let refill = (u128::from(rate) * elapsed.as_nanos() / 1_000_000_000) as u64;
+            if refill > 0 {
+                state.tokens = state.tokens.saturating_add(refill).min(size);
+                state.last = now;
+            }
+            if state.tokens >= chunk {
+                state.tokens -= chunk;
+                return Some(chunk);
+            }
+            let missing = chunk - state.tokens;
+            let wait = Duration::from_nanos(
+                (u128::from(missing) * 1_000_000_000 / u128::from(rate)).max(1) as u64,
+            );
+            tokio::time::sleep(wait.min(MAX_SLEEP)).await;
+        }
+    }
+}
+
+impl EncryptedFs {
+    /// Change the limits set with [`FsOptions::throttle`], the operations waiting now use the new ones.
+    ///
+    /// [`FsOptions::throttle`]: crate::encryptedfs::FsOptions::throttle
+    pub fn set_throttle(&self, limits: ThrottleLimits) {
+        self.throttle.set_limits(limits);
+    }
+}
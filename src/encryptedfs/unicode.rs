@@ -0,0 +1,221 @@
+//! Unicode normalization of the names, see [`FsOptions::normalize_unicode`].
+//!
+//! macOS makes the names in NFD and most of the rest in NFC, so the same name can come spelled in two ways. When
+//! it's enabled the names are normalized to NFC before they are hashed and encrypted, so both spellings find the
+//! same entry, and they are listed in NFC. It's kept in [`NORMALIZE_UNICODE_FILENAME`], so the data dir keeps it
+//! whatever it's opened with. Data dirs made without it get it from [`EncryptedFs::merge_unicode_duplicates`].
+//!
+//! [`FsOptions::normalize_unicode`]: crate::encryptedfs::FsOptions::normalize_unicode
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+use shush_rs::{ExposeSecret, SecretString};
+use tracing::info;
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+use crate::encryptedfs::{
+    DirectoryEntry, EncryptedFs, FileType, FsError, FsResult, ROOT_INODE, SECURITY_DIR,
+};
+use crate::storage::Storage;
+
+/// Kept in [`SECURITY_DIR`] when the names are normalized, with the form they are normalized to.
+pub(crate) const NORMALIZE_UNICODE_FILENAME: &str = "normalize_unicode";
+
+/// An entry renamed by [`EncryptedFs::merge_unicode_duplicates`].
+#[derive(Debug)]
+pub struct NormalizedName {
+    /// Directory of the entry, where it was moved to if it was in a merged directory.
+    pub parent: u64,
+    pub ino: u64,
+    /// Name it has now.
+    pub name: SecretString,
+    /// `true` if another entry had the same normalized name, so it was given another one.
+    pub conflict: bool,
+}
+
+/// `name` in NFC, borrowed if it already is.
+pub(crate) fn to_nfc(name: &SecretString) -> Cow<'_, SecretString> {
+    if is_nfc(&name.expose_secret()) {
+        Cow::Borrowed(name)
+    } else {
+        Cow::Owned(SecretString::new(Box::new(
+            name.expose_secret().nfc().collect(),
+        )))
+    }
+}
+
+/// `true` if the names of the data dir are normalized, see [`NORMALIZE_UNICODE_FILENAME`].
+pub(crate) async fn read_normalize_unicode(storage: &dyn Storage) -> FsResult<bool> {
+    Ok(storage.kind(&normalize_unicode_path()).await?.is_some())
+}
+
+pub(crate) async fn write_normalize_unicode(storage: &dyn Storage) -> FsResult<()> {
+    storage.write(&normalize_unicode_path(), b"NFC").await?;
+    storage.sync(Path::new(SECURITY_DIR)).await?;
+    Ok(())
+}
+
+fn normalize_unicode_path() -> PathBuf {
+    Path::new(SECURITY_DIR).join(NORMALIZE_UNICODE_FILENAME)
+}
+
+impl EncryptedFs {
+    /// `name` as it's hashed and encrypted, in NFC if the names are normalized.
+    pub(crate) fn normalized_name<'a>(&self, name: &'a SecretString) -> Cow<'a, SecretString> {
+        if self.normalize_unicode.load(Ordering::SeqCst) {
+            to_nfc(name)
+        } else {
+            Cow::Borrowed(name)
+        }
+    }
+
+    /// Rename the entries whose names are not in NFC to their NFC form and normalize the names from now on, like
+    /// [`FsOptions::normalize_unicode`] does for new data dirs.
+    ///
+    /// When more entries of a directory have the same name in NFC, the one already in NFC, or else the first, keeps
+    /// it. If they are all directories, the entries of the others are moved into it and the others are removed, the
+    /// rest get `name (2)`, `name (3)` and so on. The entries renamed are returned.
+    ///
+    /// The directory keys must be unlocked. Run it while nothing else uses the filesystem, the names not in NFC are
+    /// looked up as they are until it's done.
+    ///
+    /// [`FsOptions::normalize_unicode`]: crate::encryptedfs::FsOptions::normalize_unicode
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn merge_unicode_duplicates(&self) -> FsResult<Vec<NormalizedName>> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let enabled = self.normalize_unicode.swap(false, Ordering::SeqCst);
+        let mut renamed = vec![];
+        let res = self.merge_unicode_duplicates_from(&mut renamed).await;
+        let res = match res {
+            Ok(()) if !enabled => write_normalize_unicode(&*self.storage).await,
+            res => res,
+        };
+        self.normalize_unicode
+            .store(enabled || res.is_ok(), Ordering::SeqCst);
+        res?;
+        info!(renamed = renamed.len(), "unicode names normalized");
+        Ok(renamed)
+    }
+
+    async fn merge_unicode_duplicates_from(
+        &self,
+        renamed: &mut Vec<NormalizedName>,
+    ) -> FsResult<()> {
+        let mut dirs = vec![ROOT_INODE];
+        while let Some(dir) = dirs.pop() {
+            self.normalize_dir(dir, renamed).await?;
+            dirs.extend(
+                self.dir_entries(dir)
+                    .await?
+                    .into_iter()
+                    .filter(|entry| entry.kind == FileType::Directory)
+                    .map(|entry| entry.ino),
+            );
+        }
+        Ok(())
+    }
+
+    /// Rename the entries of `parent` to NFC, see [`EncryptedFs::merge_unicode_duplicates`].
+    async fn normalize_dir(&self, parent: u64, renamed: &mut Vec<NormalizedName>) -> FsResult<()> {
+        let mut groups: HashMap<String, Vec<DirectoryEntry>> = HashMap::new();
+        for entry in self.dir_entries(parent).await? {
+            let nfc = to_nfc(&entry.name).expose_secret().to_string();
+            groups.entry(nfc).or_default().push(entry);
+        }
+        for (nfc, mut group) in groups {
+            // the one already in NFC keeps its name
+            group.sort_by_key(|entry| *entry.name.expose_secret() != nfc);
+            let nfc = SecretString::new(Box::new(nfc));
+            // the inode and the type of the one keeping the name
+            let mut kept: Option<(u64, FileType)> = None;
+            for entry in group {
+                match kept {
+                    None if *entry.name.expose_secret() == *nfc.expose_secret() => {
+                        kept = Some((entry.ino, entry.kind));
+                    }
+                    None => {
+                        self.rename(parent, &entry.name, parent, &nfc).await?;
+                        renamed.push(NormalizedName {
+                            parent,
+                            ino: entry.ino,
+                            name: nfc.clone(),
+                            conflict: false,
+                        });
+                        kept = Some((entry.ino, entry.kind));
+                    }
+                    Some((into, FileType::Directory)) if entry.kind == FileType::Directory => {
+                        self.merge_dirs(entry.ino, into, renamed).await?;
+                        self.remove_dir(parent, &entry.name).await?;
+                    }
+                    Some(_) => {
+                        let name = self.free_name(parent, &nfc).await?;
+                        self.rename(parent, &entry.name, parent, &name).await?;
+                        renamed.push(NormalizedName {
+                            parent,
+                            ino: entry.ino,
+                            name,
+                            conflict: true,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Move the entries of the directory `from` into `into`, in NFC, the ones whose names are taken get free ones.
+    async fn merge_dirs(
+        &self,
+        from: u64,
+        into: u64,
+        renamed: &mut Vec<NormalizedName>,
+    ) -> FsResult<()> {
+        for entry in self.dir_entries(from).await? {
+            let nfc = to_nfc(&entry.name).into_owned();
+            let (name, conflict) = if self.exists_by_name(into, &nfc).await? {
+                (self.free_name(into, &nfc).await?, true)
+            } else {
+                (nfc, false)
+            };
+            self.rename(from, &entry.name, into, &name).await?;
+            renamed.push(NormalizedName {
+                parent: into,
+                ino: entry.ino,
+                name,
+                conflict,
+            });
+        }
+        Ok(())
+    }
+
+    /// `name (2)`, `name (3)` and so on, the first one not in `parent`.
+    async fn free_name(&self, parent: u64, name: &SecretString) -> FsResult<SecretString> {
+        for i in 2.. {
+            let candidate = SecretString::new(Box::new(format!("{} ({i})", name.expose_secret())));
+            if !self.exists_by_name(parent, &candidate).await? {
+                return Ok(candidate);
+            }
+        }
+        unreachable!("all the names are taken")
+    }
+
+    /// The entries of `dir` without `.` and `..`, it fails with [`FsError::Locked`] if its directory key is locked.
+    async fn dir_entries(&self, dir: u64) -> FsResult<Vec<DirectoryEntry>> {
+        // the names listed in a locked directory are placeholders
+        self.inode_key(dir).await?;
+        self.read_dir(dir)
+            .await?
+            .filter(|entry| {
+                entry.as_ref().map_or(true, |entry| {
+                    let name = entry.name.expose_secret();
+                    *name != "." && *name != ".."
+                })
+            })
+            .collect()
+    }
+}
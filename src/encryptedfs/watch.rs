@@ -0,0 +1,241 @@
+//! Changes made to the data dir by someone else, like another machine with a synced copy of it.
+//!
+//! [`EncryptedFs`] keeps the attributes and the directory entries in caches and the decrypted blocks in the block cache
+//! and in the readers of the open handles, so it doesn't see those changes until they expire. [`EncryptedFs::invalidate_external_change`]
+//! forgets what we keep for a changed key of the storage and [`EncryptedFs::on_external_change`] tells the mount
+//! layer about it, so it can drop what the kernel caches too. With the `watch` feature
+//! [`EncryptedFs::watch_external_changes`] calls it for the changes it sees in a local data dir.
+//!
+//! It's meant for read-only instances, the changes of a writable one can still conflict with the ones made outside.
+
+use std::path::{Component, Path, PathBuf};
+
+use tokio::sync::RwLock;
+use tracing::debug;
+
+use crate::encryptedfs::{EncryptedFs, FsError, FsResult, CONTENTS_DIR, INODES_DIR, LS_DIR};
+
+/// Called with the inode changed outside, see [`EncryptedFs::on_external_change`].
+pub(crate) type ExternalChangeListener = Box<dyn Fn(u64) + Send + Sync>;
+
+/// What a changed key of the storage is for.
+#[derive(Debug, PartialEq, Eq)]
+enum ExternalChange {
+    /// The inode file, with the attributes.
+    Inode(u64),
+    /// The contents of a file or of a directory, other than its `ls` entries.
+    Contents(u64),
+    /// An `ls` entry of the directory `parent`.
+    DirEntry {
+        parent: u64,
+        ls_path: PathBuf,
+        ls_name: String,
+    },
+}
+
+impl ExternalChange {
+    /// Parse a key made by [`EncryptedFs::ino_file`] or [`EncryptedFs::contents_path`], or by what is under them.
+    fn parse(path: &Path) -> Option<Self> {
+        let components: Vec<&str> = path
+            .components()
+            .map(|c| match c {
+                Component::Normal(s) => s.to_str(),
+                _ => None,
+            })
+            .collect::<Option<_>>()?;
+        match components.as_slice() {
+            [dir, _, _, ino] if *dir == INODES_DIR => ino.parse().ok().map(Self::Inode),
+            [dir, _, _, ino, ls, name] if *dir == CONTENTS_DIR && *ls == LS_DIR => {
+                Some(Self::DirEntry {
+                    parent: ino.parse().ok()?,
+                    ls_path: path.to_path_buf(),
+                    ls_name: (*name).to_owned(),
+                })
+            }
+            [dir, _, _, ino, ..] if *dir == CONTENTS_DIR => ino.parse().ok().map(Self::Contents),
+            _ => None,
+        }
+    }
+}
+
+impl EncryptedFs {
+    /// Call `listener` with the inode each time [`EncryptedFs::invalidate_external_change`] forgets something about
+    /// it, so the mount layer can tell the kernel to drop its caches too.
+    ///
+    /// It's called from async code, so it should return fast.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn on_external_change(&self, listener: impl Fn(u64) + Send + Sync + 'static) {
+        self.external_change_listeners
+            .lock()
+            .expect("cannot obtain lock")
+            .push(Box::new(listener));
+    }
+
+    /// Forget what we keep in memory for the key `path` of the storage, relative to the data dir, after it was
+    /// changed by someone else.
+    ///
+    /// For an inode file its attributes and the holes of its contents are loaded again, also in the open read
+    /// handles. For both the decrypted blocks of the contents are dropped and the open read handles are recreated,
+    /// so they don't serve the previous blocks. For an `ls`
+    /// entry it's removed from the directory entry caches. Other keys are ignored.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn invalidate_external_change(&self, path: &Path) -> FsResult<()> {
+        let Some(change) = ExternalChange::parse(path) else {
+            return Ok(());
+        };
+        debug!(?change, "external change");
+        let ino = match change {
+            ExternalChange::Inode(ino) => {
+                self.attr_cache.get().await?.write().await.pop(&ino);
+                self.reload_holes(ino).await?;
+                self.block_cache.invalidate(ino);
+                self.refresh_read_handles(ino).await?;
+                ino
+            }
+            ExternalChange::Contents(ino) => {
+                self.block_cache.invalidate(ino);
+                self.refresh_read_handles(ino).await?;
+                ino
+            }
+            ExternalChange::DirEntry {
+                parent,
+                ls_path,
+                ls_name,
+            } => {
+                let lock = self
+                    .serialize_dir_entries_ls_locks
+                    .get_or_insert_with(ls_path.to_str().unwrap().to_owned(), || {
+                        RwLock::new(false)
+                    });
+                let _guard = lock.write().await;
+                self.invalidate_dir_entry(&ls_path, &ls_name).await?;
+                parent
+            }
+        };
+        for listener in self
+            .external_change_listeners
+            .lock()
+            .map_err(|_| FsError::Internal("lock on the listeners is poisoned".to_owned()))?
+            .iter()
+        {
+            listener(ino);
+        }
+        Ok(())
+    }
+
+    /// Load again the times of the read handles of `ino` and recreate their readers.
+    async fn refresh_read_handles(&self, ino: u64) -> FsResult<()> {
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _guard = lock.write().await;
+        let fhs = self
+            .opened_files_for_read
+            .read(&ino)
+            .await
+            .get(&ino)
+            .cloned()
+            .unwrap_or_default();
+        if fhs.is_empty() {
+            return Ok(());
+        }
+        let attr = match self.get_inode_from_storage(ino).await {
+            Ok(attr) => attr,
+            // removed outside, the handles keep what they have until released
+            Err(FsError::InodeNotFound) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        for fh in fhs {
+            let guard = self.read_handles.read(&fh).await;
+            let Some(ctx) = guard.get(&fh) else {
+                continue;
+            };
+            let mut ctx = ctx.lock().await;
+            // a reader suspended by `lock` is created again on the next read
+            if ctx.reader.is_some() {
+                ctx.reader = Some(self.create_contents_reader(ino).await?);
+            }
+            ctx.attr = attr.into();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "watch")]
+mod watcher {
+    use std::sync::Arc;
+
+    use notify::{EventKind, RecursiveMode, Watcher};
+    use tracing::warn;
+
+    use crate::encryptedfs::{EncryptedFs, FsError, FsResult, CONTENTS_DIR, INODES_DIR};
+
+    /// Watches a local data dir for changes made outside, see [`EncryptedFs::watch_external_changes`]. It stops
+    /// when dropped.
+    pub struct ExternalChangeWatcher {
+        _watcher: notify::RecommendedWatcher,
+    }
+
+    impl EncryptedFs {
+        /// Watch the inodes and the contents of the data dir and call [`EncryptedFs::invalidate_external_change`]
+        /// for what changes, until the returned watcher is dropped. Needs the `watch` feature.
+        ///
+        /// Our own changes are seen too, so it's meant for read-only instances.
+        ///
+        /// # Errors
+        ///
+        /// [`FsError::InvalidInput`] if the storage is not a local dir, see [`crate::storage::Storage::local_path`].
+        pub fn watch_external_changes(&self) -> FsResult<ExternalChangeWatcher> {
+            let data_dir = self
+                .storage
+                .local_path()
+                .ok_or_else(|| FsError::InvalidInput {
+                    reason: "only a local data dir can be watched".to_owned(),
+                })?
+                .to_path_buf();
+            let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+            let mut watcher =
+                notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
+                    Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+                        for path in event.paths {
+                            let _ = tx.send(path);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(err) => warn!(err = %err, "watching data dir"),
+                })
+                .map_err(watch_error)?;
+            for dir in [INODES_DIR, CONTENTS_DIR] {
+                watcher
+                    .watch(&data_dir.join(dir), RecursiveMode::Recursive)
+                    .map_err(watch_error)?;
+            }
+            // don't keep us alive, it ends when the watcher is dropped or we are
+            let fs = Arc::downgrade(&self.self_arc()?);
+            self.runtime.spawn(async move {
+                while let Some(path) = rx.recv().await {
+                    let Some(fs) = fs.upgrade() else {
+                        break;
+                    };
+                    let Ok(key) = path.strip_prefix(&data_dir) else {
+                        continue;
+                    };
+                    if let Err(err) = fs.invalidate_external_change(key).await {
+                        warn!(err = %err, path = ?key, "invalidating external change");
+                    }
+                }
+            });
+            Ok(ExternalChangeWatcher { _watcher: watcher })
+        }
+    }
+
+    #[allow(clippy::needless_pass_by_value)]
+    fn watch_error(err: notify::Error) -> FsError {
+        FsError::Other {
+            message: format!("watching data dir: {err}"),
+        }
+    }
+}
+
+#[cfg(feature = "watch")]
+pub use watcher::ExternalChangeWatcher;
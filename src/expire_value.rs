@@ -1,11 +1,12 @@
 use std::error::Error;
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Weak};
 use std::time::Duration;
 
 use async_trait::async_trait;
 use retainer::Cache;
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
 use tokio::task::JoinHandle;
 
 const KEY: &str = "key";
@@ -26,9 +27,13 @@ pub struct ExpireValue<
 > {
     cache: Arc<Cache<String, Arc<T>>>,
     weak: RwLock<Option<Weak<T>>>,
+    // only one caller waits for the provider at a time
+    provide_lock: Mutex<()>,
     monitor: Option<JoinHandle<()>>,
     provider: P,
     duration: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
     _marker: PhantomData<E>,
 }
 
@@ -42,9 +47,12 @@ impl<
         let mut s = Self {
             cache: Arc::new(Cache::new()),
             weak: RwLock::new(None),
+            provide_lock: Mutex::new(()),
             monitor: None,
             provider,
             duration,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
             _marker: PhantomData {},
         };
         let clone = s.cache.clone();
@@ -57,8 +65,16 @@ impl<
 
     pub async fn get(&self) -> Result<Arc<T>, E> {
         if let Some(value) = self.get_from_ref_or_cache().await {
+            self.hits.fetch_add(1, Ordering::Relaxed);
             return Ok(value);
         }
+        let _guard = self.provide_lock.lock().await;
+        // it might have been provided while we waited
+        if let Some(value) = self.get_from_ref_or_cache().await {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(value);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
         let value = self.provider.provide().await?;
         let v = Arc::new(value);
         self.cache
@@ -84,8 +100,24 @@ impl<
         None
     }
 
+    /// Calls of [`ExpireValue::get`] which got the value kept in memory.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Calls of [`ExpireValue::get`] which asked the provider for it.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// What gives the value when it's not kept in memory.
+    pub fn provider(&self) -> &P {
+        &self.provider
+    }
+
     pub async fn clear(&self) {
         self.cache.clear().await;
+        *self.weak.write().await = None;
     }
 }
 
@@ -150,4 +182,30 @@ mod tests {
         let called = called.clone();
         assert_eq!(called.load(Ordering::SeqCst), 3);
     }
+
+    struct SlowProvider {
+        called: Arc<AtomicUsize>,
+    }
+    #[async_trait]
+    impl ValueProvider<String, Infallible> for SlowProvider {
+        async fn provide(&self) -> Result<String, Infallible> {
+            self.called.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok("test".to_owned())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expire_value_provides_once_for_concurrent_gets() {
+        let called = Arc::new(AtomicUsize::new(0));
+        let provider = SlowProvider {
+            called: called.clone(),
+        };
+
+        let expire_value = ExpireValue::new(provider, Duration::from_secs(10));
+        let (v1, v2) = tokio::join!(expire_value.get(), expire_value.get());
+        assert_eq!(*v1.unwrap(), "test");
+        assert_eq!(*v2.unwrap(), "test");
+        assert_eq!(called.load(Ordering::SeqCst), 1);
+    }
 }
@@ -43,3 +43,46 @@ pub fn open_atomic_write(file: &Path) -> io::Result<AtomicWriteFile> {
     opt.preserve_mode(true).preserve_owner(true);
     opt.open(file)
 }
+
+/// Space and inodes statistics of a mounted filesystem, see [`statvfs`].
+#[derive(Debug, Clone, Copy)]
+pub struct StatVfs {
+    /// Fragment size, the unit of the blocks counts.
+    pub block_size: u64,
+    pub blocks: u64,
+    pub blocks_free: u64,
+    /// Free blocks for unprivileged users.
+    pub blocks_available: u64,
+    pub files_free: u64,
+}
+
+/// Statistics of the filesystem containing `path`.
+#[allow(unreachable_code)]
+#[allow(clippy::missing_errors_doc)]
+pub fn statvfs(path: &Path) -> io::Result<StatVfs> {
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // field types differ between platforms
+        #[allow(clippy::useless_conversion)]
+        return Ok(StatVfs {
+            block_size: u64::from(stat.f_frsize),
+            blocks: u64::from(stat.f_blocks),
+            blocks_free: u64::from(stat.f_bfree),
+            blocks_available: u64::from(stat.f_bavail),
+            files_free: u64::from(stat.f_ffree),
+        });
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "statvfs is not supported on this platform",
+    ))
+}
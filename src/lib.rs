@@ -86,11 +86,10 @@
 //! #![allow(unused_imports)]
 //! use std::fs;
 //! use shush_rs::SecretString;
-//! use rencfs::encryptedfs::{EncryptedFs, FileType, PasswordProvider, CreateFileAttr};
+//! use rencfs::encryptedfs::{EncryptedFs, FileType, FsOptions, PasswordProvider, CreateFileAttr};
 //! use rencfs::crypto::Cipher;
 //! use anyhow::Result;
 //! use std::path::Path;
-//! use rencfs::encryptedfs::write_all_string_to_fs;
 //!
 //! const ROOT_INODE: u64 = 1;
 //!
@@ -109,12 +108,12 @@
 //!     let data_dir = Path::new("/tmp/rencfs_data_test").to_path_buf();
 //!     let  _ = fs::remove_dir_all(data_dir.to_str().unwrap());
 //!     let cipher = Cipher::ChaCha20Poly1305;
-//!     let mut fs = EncryptedFs::new(data_dir.clone(), Box::new(PasswordProviderImpl{}), cipher, false).await?;
+//!     let mut fs = EncryptedFs::new(data_dir.clone(), Box::new(PasswordProviderImpl{}), cipher, false, FsOptions::default()).await?;
 //!
 //!     let  file1 = SecretString::new(Box::new(String::from("file-1")));
 //!     let (fh, attr) = fs.create(ROOT_INODE, &file1, file_attr(), false, true).await?;
 //!     let data = "Hello, world!";
-//!     write_all_string_to_fs( &fs, attr.ino, 0,data, fh).await?;
+//!     fs.write_all(attr.ino, 0, data.as_bytes(), fh).await?;
 //!     fs.flush(fh).await?;
 //!     fs.release(fh).await?;
 //!     let fh = fs.open(attr.ino, true, false).await?;
@@ -135,6 +134,7 @@
 //!         gid: 0,
 //!         rdev: 0,
 //!         flags: 0,
+//!         compress: None,
 //!     }
 //! }
 //! ```
@@ -297,8 +297,15 @@ pub mod expire_value;
 pub mod fs_util;
 pub mod log;
 pub mod mount;
+pub mod password;
+pub mod segmented_file;
+pub mod server;
+pub mod sharded_map;
+pub mod storage;
 pub mod stream_util;
 pub(crate) mod test_common;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 
 #[allow(unreachable_code)]
 pub static UID: LazyLock<u32> = LazyLock::new(|| {
@@ -1,12 +1,21 @@
 use crate::crypto::Cipher;
-use crate::encryptedfs::{FsResult, PasswordProvider};
+use crate::encryptedfs::{
+    AsyncPasswordProvider, DirectoryEntryPlus, EncryptedFs, FileAttr, FileType, FsError, FsResult,
+    ROOT_INODE,
+};
 use async_trait::async_trait;
 use futures_util::FutureExt;
+use shush_rs::{ExposeSecret, SecretString};
 use std::future::Future;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
 use std::{io, process};
+use tracing::{error, info, warn};
 
 #[cfg(target_os = "linux")]
 mod linux;
@@ -14,13 +23,26 @@ mod linux;
 use linux::MountHandleInnerImpl;
 #[cfg(target_os = "linux")]
 use linux::MountPointImpl;
+#[cfg(target_os = "linux")]
+use linux::{detach, is_mounted};
+
+#[cfg(all(target_os = "windows", feature = "winfsp"))]
+mod windows;
+#[cfg(all(target_os = "windows", feature = "winfsp"))]
+use windows::MountHandleInnerImpl;
+#[cfg(all(target_os = "windows", feature = "winfsp"))]
+use windows::MountPointImpl;
+#[cfg(all(target_os = "windows", feature = "winfsp"))]
+use windows::{detach, is_mounted};
 
-#[cfg(not(target_os = "linux"))]
+#[cfg(not(any(target_os = "linux", all(target_os = "windows", feature = "winfsp"))))]
 mod dummy;
-#[cfg(not(target_os = "linux"))]
+#[cfg(not(any(target_os = "linux", all(target_os = "windows", feature = "winfsp"))))]
 use dummy::MountHandleInnerImpl;
-#[cfg(not(target_os = "linux"))]
+#[cfg(not(any(target_os = "linux", all(target_os = "windows", feature = "winfsp"))))]
 use dummy::MountPointImpl;
+#[cfg(not(any(target_os = "linux", all(target_os = "windows", feature = "winfsp"))))]
+use dummy::{detach, is_mounted};
 
 #[async_trait]
 #[allow(clippy::module_name_repetitions)]
@@ -30,24 +52,571 @@ pub trait MountPoint {
     fn new(
         mountpoint: PathBuf,
         data_dir: PathBuf,
-        password_provider: Box<dyn PasswordProvider>,
+        password_provider: Box<dyn AsyncPasswordProvider>,
         cipher: Cipher,
         allow_root: bool,
         allow_other: bool,
         read_only: bool,
     ) -> Self
+    where
+        Self: Sized;
+    /// Add an extra mount option, see [`MountOption`].
+    ///
+    /// Options that can't be used together or on this platform make [`MountPoint::mount`] fail with
+    /// [`FsError::InvalidInput`](crate::encryptedfs::FsError::InvalidInput).
+    #[must_use]
+    fn with_option(self, option: MountOption) -> Self
+    where
+        Self: Sized;
+    /// Lock or unmount the filesystem when there was no operation for `timeout`, see [`IdleAction`].
+    #[must_use]
+    fn with_idle_timeout(self, timeout: Duration, action: IdleAction) -> Self
+    where
+        Self: Sized;
+    /// Mount only the directory at `root`, a path inside the filesystem like `/projects/foo`, it's resolved when
+    /// mounting. Nothing outside it can be reached from the mountpoint.
+    #[must_use]
+    fn with_root(self, root: PathBuf) -> Self
+    where
+        Self: Sized;
+    /// Add the virtual [`CONTROL_DIR_NAME`] directory in the root of the mount, it's not stored in the data dir.
+    ///
+    /// It has `stats`, the [`EncryptedFs::metrics_snapshot`] as JSON, and `version`, which can be read, and
+    /// `drop-caches` and `lock`, which call [`EncryptedFs::drop_caches`] and [`EncryptedFs::lock`] when written. Its
+    /// entries can't be created, removed or renamed. With `hidden` it's not listed in the root, but it can still be
+    /// opened by its path.
+    #[must_use]
+    fn with_control_dir(self, hidden: bool) -> Self
     where
         Self: Sized;
     async fn mount(mut self) -> FsResult<MountHandle>;
 }
 
+/// What to do when the filesystem is idle, see [`MountPoint::with_idle_timeout`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdleAction {
+    /// Lock it with [`EncryptedFs::lock`], the key is removed from memory and the password is asked again on the
+    /// next operation. Files opened for write are flushed first.
+    Lock,
+    /// Unmount it.
+    Unmount,
+}
+
+/// Keeps the time of the last operation, cheap enough to be updated by each one.
+#[allow(dead_code)]
+pub(in crate::mount) struct Activity {
+    start: Instant,
+    /// Millis since `start`.
+    last: AtomicU64,
+}
+
+#[allow(dead_code)]
+impl Activity {
+    pub(in crate::mount) fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    pub(in crate::mount) fn touch(&self) {
+        self.last.store(self.millis(), Ordering::Relaxed);
+    }
+
+    fn millis(&self) -> u64 {
+        u64::try_from(self.start.elapsed().as_millis()).unwrap_or(u64::MAX)
+    }
+}
+
+/// Run `action` when there was no activity for `timeout`, until the filesystem is dropped.
+///
+/// An operation in progress is not interrupted, [`EncryptedFs::lock`] waits for the writes to the file it flushes, and
+/// a long copy keeps the filesystem active with each write.
+#[allow(dead_code)]
+pub(in crate::mount) fn watch_idle(
+    fs: Weak<EncryptedFs>,
+    activity: Arc<Activity>,
+    timeout: Duration,
+    action: IdleAction,
+    mountpoint: PathBuf,
+) {
+    let timeout_millis = u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX);
+    let interval = (timeout / 10).clamp(Duration::from_millis(10), Duration::from_secs(1));
+    tokio::spawn(async move {
+        // the last activity we acted on, we act once for each idle period
+        let mut handled = None;
+        loop {
+            tokio::time::sleep(interval).await;
+            let Some(fs) = fs.upgrade() else {
+                break;
+            };
+            let last = activity.last.load(Ordering::Relaxed);
+            if activity.millis().saturating_sub(last) < timeout_millis || handled == Some(last) {
+                continue;
+            }
+            handled = Some(last);
+            match action {
+                IdleAction::Lock => {
+                    info!("idle, locking");
+                    if let Err(err) = fs.lock().await {
+                        error!(err = %err, "cannot lock");
+                    }
+                }
+                IdleAction::Unmount => {
+                    info!(mountpoint = %mountpoint.display(), "idle, unmounting");
+                    drop(fs);
+                    if let Err(err) = detach(&mountpoint, false) {
+                        error!(err = %err, "cannot unmount");
+                    }
+                    break;
+                }
+            }
+        }
+    });
+}
+
+/// Extra options for the FUSE mount, besides the ones from [`MountPoint::new`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::module_name_repetitions)]
+pub enum MountOption {
+    /// Let the kernel check access with the mode, owner and group of the files, `default_permissions`.
+    DefaultPermissions,
+    /// Name of the filesystem in the mount table, `fsname`. Default is `rencfs`.
+    FsName(String),
+    /// Type shown in the mount table as `fuse.<subtype>`, `subtype`.
+    Subtype(String),
+    /// Max size in bytes of a read request, `max_read`. Must not be 0.
+    MaxRead(u32),
+    /// Name of the volume in Finder, `volname`. Only on macOS.
+    VolName(String),
+    /// Don't create `._` AppleDouble files, `noappledouble`. Only on macOS.
+    NoAppleDouble,
+    /// Allow creating and using character and block devices, `dev`. Without it `mknod` fails with `EPERM` for them.
+    AllowDevices,
+}
+
+/// The directory presented as the root of the mount, see [`MountPoint::with_root`].
+///
+/// The kernel knows it as [`ROOT_INODE`], so its inode and [`ROOT_INODE`] are swapped when passing inodes between the
+/// kernel and [`EncryptedFs`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub(in crate::mount) struct SubTree {
+    root: u64,
+}
+
+#[allow(dead_code)]
+impl SubTree {
+    /// Resolve `path`, relative to the root of the filesystem, to the directory to present.
+    pub(in crate::mount) async fn resolve(fs: &EncryptedFs, path: &Path) -> FsResult<Self> {
+        let mut ino = ROOT_INODE;
+        for component in path.components() {
+            match component {
+                Component::RootDir | Component::CurDir => {}
+                Component::Normal(name) => {
+                    let name = name
+                        .to_str()
+                        .ok_or_else(|| FsError::invalid_input("root is not valid UTF-8"))?;
+                    ino = fs
+                        .find_by_name(ino, &SecretString::from_str(name).unwrap())
+                        .await?
+                        .ok_or_else(|| FsError::not_found("root not found"))?
+                        .ino;
+                }
+                Component::ParentDir | Component::Prefix(_) => {
+                    return Err(FsError::invalid_input("root must not contain '..'"));
+                }
+            }
+        }
+        if !fs.is_dir(ino).await {
+            return Err(FsError::InvalidInodeType);
+        }
+        Ok(Self { root: ino })
+    }
+
+    pub(in crate::mount) const fn root(self) -> u64 {
+        self.root
+    }
+
+    /// Inode from the kernel to the one in [`EncryptedFs`].
+    pub(in crate::mount) const fn to_fs(self, ino: u64) -> u64 {
+        self.swap(ino)
+    }
+
+    /// Inode from [`EncryptedFs`] to the one for the kernel.
+    pub(in crate::mount) const fn to_kernel(self, ino: u64) -> u64 {
+        self.swap(ino)
+    }
+
+    pub(in crate::mount) const fn attr_to_kernel(self, mut attr: FileAttr) -> FileAttr {
+        attr.ino = self.to_kernel(attr.ino);
+        attr
+    }
+
+    const fn swap(self, ino: u64) -> u64 {
+        if ino == self.root {
+            ROOT_INODE
+        } else if ino == ROOT_INODE {
+            self.root
+        } else {
+            ino
+        }
+    }
+
+    /// Inode in [`EncryptedFs`] of the entry `name` in `parent`, `..` in the root is the root itself, so it doesn't
+    /// escape upward.
+    pub(in crate::mount) fn entry_ino(self, parent: u64, name: &str, ino: u64) -> u64 {
+        if parent == self.root && name == ".." {
+            self.root
+        } else {
+            ino
+        }
+    }
+
+    /// Like [`EncryptedFs::find_by_name`], but `..` in the root is the root itself.
+    pub(in crate::mount) async fn find_by_name(
+        &self,
+        fs: &EncryptedFs,
+        parent: u64,
+        name: &SecretString,
+    ) -> FsResult<Option<FileAttr>> {
+        if parent == self.root && *name.expose_secret() == ".." {
+            return fs.get_attr(parent).await.map(Some);
+        }
+        fs.find_by_name(parent, name).await
+    }
+
+    /// If the directory `ino` is in the sub-tree.
+    pub(in crate::mount) async fn contains(
+        &self,
+        fs: &EncryptedFs,
+        mut ino: u64,
+    ) -> FsResult<bool> {
+        let parent = SecretString::from_str("..").unwrap();
+        loop {
+            if ino == self.root {
+                return Ok(true);
+            }
+            if ino == ROOT_INODE {
+                return Ok(false);
+            }
+            ino = fs
+                .find_by_name(ino, &parent)
+                .await?
+                .ok_or(FsError::InodeNotFound)?
+                .ino;
+        }
+    }
+}
+
+impl Default for SubTree {
+    fn default() -> Self {
+        Self { root: ROOT_INODE }
+    }
+}
+
+/// Name of the [`ControlDir`] in the root of the mount.
+pub const CONTROL_DIR_NAME: &str = ".rencfs";
+
+/// Inode of the [`ControlDir`], the files are after it. Far from the ones given by [`EncryptedFs`].
+const CONTROL_DIR_INO: u64 = u64::MAX - 16;
+
+/// The files in the [`ControlDir`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ControlFile {
+    /// The [`EncryptedFs::metrics_snapshot`] as JSON, read-only.
+    Stats,
+    /// The version of rencfs, read-only.
+    Version,
+    /// A write calls [`EncryptedFs::drop_caches`].
+    DropCaches,
+    /// A write calls [`EncryptedFs::lock`].
+    Lock,
+}
+
+impl ControlFile {
+    const ALL: [Self; 4] = [Self::Stats, Self::Version, Self::DropCaches, Self::Lock];
+
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Stats => "stats",
+            Self::Version => "version",
+            Self::DropCaches => "drop-caches",
+            Self::Lock => "lock",
+        }
+    }
+
+    const fn ino(self) -> u64 {
+        CONTROL_DIR_INO + 1 + self as u64
+    }
+
+    fn from_ino(ino: u64) -> Option<Self> {
+        Self::ALL.into_iter().find(|file| file.ino() == ino)
+    }
+
+    const fn is_write_only(self) -> bool {
+        matches!(self, Self::DropCaches | Self::Lock)
+    }
+}
+
+/// The virtual `/.rencfs` directory, see [`MountPoint::with_control_dir`].
+///
+/// It's made up by the mount and never stored, its inodes are the same for the kernel and here, they are not changed
+/// by [`SubTree`]. It hides an entry with the same name in the root.
+#[allow(dead_code)]
+pub(in crate::mount) struct ControlDir {
+    /// Not listed in the root, it can still be looked up by name.
+    hidden: bool,
+    created: SystemTime,
+}
+
+#[allow(dead_code)]
+impl ControlDir {
+    pub(in crate::mount) fn new(hidden: bool) -> Self {
+        Self {
+            hidden,
+            created: SystemTime::now(),
+        }
+    }
+
+    /// If `ino` is the directory or one of its files.
+    pub(in crate::mount) const fn contains(&self, ino: u64) -> bool {
+        ino >= CONTROL_DIR_INO && ino <= CONTROL_DIR_INO + ControlFile::ALL.len() as u64
+    }
+
+    /// If the entry `name` in `parent` can't be created, removed or renamed, as it's the directory or in it.
+    pub(in crate::mount) fn is_reserved(&self, parent: u64, name: &str) -> bool {
+        self.contains(parent) || (parent == ROOT_INODE && name == CONTROL_DIR_NAME)
+    }
+
+    /// Inode of the entry `name` in `parent` if it's the directory or one of its files, `None` for the others. In the
+    /// directory only its files are found.
+    pub(in crate::mount) fn lookup(&self, parent: u64, name: &str) -> Option<u64> {
+        if parent == ROOT_INODE && name == CONTROL_DIR_NAME {
+            Some(CONTROL_DIR_INO)
+        } else if parent == CONTROL_DIR_INO {
+            ControlFile::ALL
+                .into_iter()
+                .find(|file| file.name() == name)
+                .map(ControlFile::ino)
+        } else {
+            None
+        }
+    }
+
+    pub(in crate::mount) async fn get_attr(
+        &self,
+        fs: &EncryptedFs,
+        ino: u64,
+    ) -> FsResult<FileAttr> {
+        let (kind, perm, size) = if ino == CONTROL_DIR_INO {
+            (FileType::Directory, 0o555, 0)
+        } else {
+            let file = ControlFile::from_ino(ino).ok_or(FsError::InodeNotFound)?;
+            if file.is_write_only() {
+                (FileType::RegularFile, 0o222, 0)
+            } else {
+                let size = self.contents(fs, file).await?.len() as u64;
+                (FileType::RegularFile, 0o444, size)
+            }
+        };
+        Ok(FileAttr {
+            ino,
+            size,
+            blocks: 0,
+            atime: self.created,
+            mtime: self.created,
+            ctime: self.created,
+            crtime: self.created,
+            kind,
+            perm,
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            uid: *crate::UID,
+            gid: *crate::GID,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+            compressed: false,
+            generation: 0,
+            key_id: None,
+            ino_generation: 0,
+            quota: None,
+        })
+    }
+
+    /// Check the access `mask` by the mode only, like `ACCESS_READ | ACCESS_WRITE`.
+    pub(in crate::mount) async fn access(
+        &self,
+        fs: &EncryptedFs,
+        ino: u64,
+        mask: u32,
+    ) -> FsResult<()> {
+        let attr = self.get_attr(fs, ino).await?;
+        let allowed = u32::from((attr.perm >> 6) | (attr.perm >> 3) | attr.perm) & 0o7;
+        if mask & !allowed != 0 {
+            return Err(FsError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    /// The entries of the directory, with the attributes of the root for `..`.
+    pub(in crate::mount) async fn read_dir(
+        &self,
+        fs: &EncryptedFs,
+        root: FileAttr,
+    ) -> FsResult<Vec<DirectoryEntryPlus>> {
+        let dir = self.get_attr(fs, CONTROL_DIR_INO).await?;
+        let mut entries = vec![
+            dir_entry(".", dir),
+            dir_entry(
+                "..",
+                FileAttr {
+                    ino: ROOT_INODE,
+                    ..root
+                },
+            ),
+        ];
+        for file in ControlFile::ALL {
+            entries.push(dir_entry(file.name(), self.get_attr(fs, file.ino()).await?));
+        }
+        Ok(entries)
+    }
+
+    /// The entry to add when listing the root, `None` if it's hidden.
+    pub(in crate::mount) async fn root_entry(
+        &self,
+        fs: &EncryptedFs,
+    ) -> FsResult<Option<DirectoryEntryPlus>> {
+        if self.hidden {
+            return Ok(None);
+        }
+        let attr = self.get_attr(fs, CONTROL_DIR_INO).await?;
+        Ok(Some(dir_entry(CONTROL_DIR_NAME, attr)))
+    }
+
+    /// Check that the file `ino` can be opened for `read` and `write`.
+    pub(in crate::mount) fn open(&self, ino: u64, read: bool, write: bool) -> FsResult<()> {
+        let file = ControlFile::from_ino(ino).ok_or(FsError::InvalidInodeType)?;
+        if (read && file.is_write_only()) || (write && !file.is_write_only()) {
+            return Err(FsError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    /// Read up to `size` bytes from `offset` of the file `ino`, the contents are made on each read.
+    pub(in crate::mount) async fn read(
+        &self,
+        fs: &EncryptedFs,
+        ino: u64,
+        offset: u64,
+        size: usize,
+    ) -> FsResult<Vec<u8>> {
+        let file = ControlFile::from_ino(ino).ok_or(FsError::InvalidInodeType)?;
+        if file.is_write_only() {
+            return Err(FsError::PermissionDenied);
+        }
+        let contents = self.contents(fs, file).await?;
+        let start =
+            usize::try_from(offset).map_or(contents.len(), |offset| offset.min(contents.len()));
+        let end = start.saturating_add(size).min(contents.len());
+        Ok(contents[start..end].to_vec())
+    }
+
+    /// Run the action of the file `ino`, whatever is written.
+    pub(in crate::mount) async fn write(&self, fs: &EncryptedFs, ino: u64) -> FsResult<()> {
+        match ControlFile::from_ino(ino).ok_or(FsError::InvalidInodeType)? {
+            ControlFile::DropCaches => fs.drop_caches().await,
+            ControlFile::Lock => fs.lock().await,
+            ControlFile::Stats | ControlFile::Version => Err(FsError::PermissionDenied),
+        }
+    }
+
+    async fn contents(&self, fs: &EncryptedFs, file: ControlFile) -> FsResult<Vec<u8>> {
+        match file {
+            ControlFile::Stats => {
+                let mut json = serde_json::to_vec_pretty(&fs.metrics_snapshot().await)
+                    .map_err(|err| FsError::other(err.to_string()))?;
+                json.push(b'\n');
+                Ok(json)
+            }
+            ControlFile::Version => Ok(format!("{}\n", env!("CARGO_PKG_VERSION")).into_bytes()),
+            ControlFile::DropCaches | ControlFile::Lock => Ok(vec![]),
+        }
+    }
+}
+
+fn dir_entry(name: &str, attr: FileAttr) -> DirectoryEntryPlus {
+    DirectoryEntryPlus {
+        ino: attr.ino,
+        name: SecretString::from_str(name).unwrap(),
+        kind: attr.kind,
+        attr,
+    }
+}
+
+/// Handle of a mounted filesystem, it completes when the filesystem is unmounted.
+///
+/// When dropped it unmounts the filesystem if it's still mounted, so a panic or a dropped handle doesn't leave the
+/// mountpoint behind.
 #[allow(clippy::module_name_repetitions)]
 pub struct MountHandle {
-    inner: MountHandleInnerImpl,
+    inner: Option<MountHandleInnerImpl>,
+    mountpoint: PathBuf,
 }
 impl MountHandle {
-    pub async fn umount(self) -> io::Result<()> {
-        self.inner.unmount().await
+    #[allow(dead_code)]
+    pub(in crate::mount) const fn new(inner: MountHandleInnerImpl, mountpoint: PathBuf) -> Self {
+        Self {
+            inner: Some(inner),
+            mountpoint,
+        }
+    }
+
+    pub async fn umount(mut self) -> io::Result<()> {
+        self.inner
+            .take()
+            .expect("unmount called twice")
+            .unmount()
+            .await
+    }
+
+    /// Unmount, and if that fails, like when a process still has files open, detach the filesystem lazily.
+    ///
+    /// After it's detached the mountpoint is free, the filesystem is released when the last file is closed.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn umount_force(mut self) -> io::Result<()> {
+        let inner = self.inner.take().expect("unmount called twice");
+        match inner.unmount().await {
+            Ok(()) if !is_mounted(&self.mountpoint) => Ok(()),
+            Ok(()) => detach(&self.mountpoint, true),
+            Err(err) => {
+                warn!(err = %err, "unmount failed, detaching");
+                detach(&self.mountpoint, true)
+            }
+        }
+    }
+
+    /// If the filesystem is still mounted.
+    #[must_use]
+    pub fn is_mounted(&self) -> bool {
+        is_mounted(&self.mountpoint)
+    }
+}
+
+impl Drop for MountHandle {
+    fn drop(&mut self) {
+        let Some(inner) = self.inner.take() else {
+            return;
+        };
+        if is_mounted(&self.mountpoint) {
+            info!(mountpoint = %self.mountpoint.display(), "unmounting dropped handle");
+            if let Err(err) =
+                detach(&self.mountpoint, false).or_else(|_| detach(&self.mountpoint, true))
+            {
+                error!(err = %err, mountpoint = %self.mountpoint.display(), "cannot unmount");
+            }
+        }
+        drop(inner);
     }
 }
 
@@ -55,7 +624,10 @@ impl Future for MountHandle {
     type Output = io::Result<()>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        self.inner.poll_unpin(cx)
+        match self.inner.as_mut() {
+            Some(inner) => inner.poll_unpin(cx),
+            None => Poll::Ready(Ok(())),
+        }
     }
 }
 
@@ -80,7 +652,7 @@ pub(crate) trait MountHandleInner: Future<Output = io::Result<()>> {
 pub fn create_mount_point(
     mountpoint: &Path,
     data_dir: &Path,
-    password_provider: Box<dyn PasswordProvider>,
+    password_provider: Box<dyn AsyncPasswordProvider>,
     cipher: Cipher,
     allow_root: bool,
     allow_other: bool,
@@ -133,3 +705,277 @@ pub fn umount(mountpoint: &str) -> io::Result<()> {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::atomic::AtomicBool;
+
+    use shush_rs::SecretString;
+
+    use super::*;
+    use crate::encryptedfs::{FileType, FsOptions, PasswordProvider};
+    use crate::test_common::create_attr;
+
+    struct ToggledPasswordProvider {
+        available: Arc<AtomicBool>,
+    }
+
+    impl PasswordProvider for ToggledPasswordProvider {
+        fn get_password(&self) -> Option<SecretString> {
+            self.available
+                .load(Ordering::SeqCst)
+                .then(|| SecretString::from_str("password").unwrap())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idle_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let available = Arc::new(AtomicBool::new(true));
+        let fs = EncryptedFs::new(
+            dir.path().to_path_buf(),
+            Box::new(ToggledPasswordProvider {
+                available: available.clone(),
+            }),
+            Cipher::ChaCha20Poly1305,
+            false,
+            FsOptions::default(),
+        )
+        .await
+        .unwrap();
+        available.store(false, Ordering::SeqCst);
+        let activity = Arc::new(Activity::new());
+        watch_idle(
+            Arc::downgrade(&fs),
+            activity.clone(),
+            Duration::from_millis(200),
+            IdleAction::Lock,
+            dir.path().join("mnt"),
+        );
+
+        // kept unlocked while used
+        for _ in 0..20 {
+            activity.touch();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        fs.unlock().await.unwrap();
+
+        // locked when idle
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(matches!(fs.unlock().await, Err(FsError::Locked)));
+        available.store(true, Ordering::SeqCst);
+        fs.unlock().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sub_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let fs = EncryptedFs::new(
+            dir.path().to_path_buf(),
+            Box::new(ToggledPasswordProvider {
+                available: Arc::new(AtomicBool::new(true)),
+            }),
+            Cipher::ChaCha20Poly1305,
+            false,
+            FsOptions::default(),
+        )
+        .await
+        .unwrap();
+        let name = |name: &str| SecretString::from_str(name).unwrap();
+        let mkdir = |parent, dir_name: &'static str| {
+            let fs = fs.clone();
+            async move {
+                fs.create(
+                    parent,
+                    &name(dir_name),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap()
+                .1
+                .ino
+            }
+        };
+        let projects = mkdir(ROOT_INODE, "projects").await;
+        let foo = mkdir(projects, "foo").await;
+        let src = mkdir(foo, "src").await;
+        let bar = mkdir(projects, "bar").await;
+        let (fh, file) = fs
+            .create(
+                foo,
+                &name("file"),
+                create_attr(FileType::RegularFile),
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        fs.release(fh).await.unwrap();
+
+        let sub_tree = SubTree::resolve(&fs, Path::new("/projects/foo"))
+            .await
+            .unwrap();
+        assert_eq!(foo, sub_tree.root());
+
+        // the root is presented as ROOT_INODE, the others keep their inode
+        assert_eq!(foo, sub_tree.to_fs(ROOT_INODE));
+        assert_eq!(ROOT_INODE, sub_tree.to_kernel(foo));
+        assert_eq!(file.ino, sub_tree.to_fs(file.ino));
+        assert_eq!(file.ino, sub_tree.to_kernel(file.ino));
+        let attr = fs.get_attr(sub_tree.to_fs(ROOT_INODE)).await.unwrap();
+        assert_eq!(ROOT_INODE, sub_tree.attr_to_kernel(attr).ino);
+
+        // `..` doesn't escape the root
+        let parent = sub_tree
+            .find_by_name(&fs, foo, &name(".."))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(foo, parent.ino);
+        assert_eq!(foo, sub_tree.entry_ino(foo, "..", projects));
+        let parent = sub_tree
+            .find_by_name(&fs, src, &name(".."))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(foo, parent.ino);
+        assert_eq!(foo, sub_tree.entry_ino(src, "..", foo));
+
+        // renames out of it are rejected with `EXDEV` by the mount
+        assert!(sub_tree.contains(&fs, foo).await.unwrap());
+        assert!(sub_tree.contains(&fs, src).await.unwrap());
+        assert!(!sub_tree.contains(&fs, bar).await.unwrap());
+        assert!(!sub_tree.contains(&fs, projects).await.unwrap());
+        assert!(!sub_tree.contains(&fs, ROOT_INODE).await.unwrap());
+
+        // the whole filesystem
+        let whole = SubTree::resolve(&fs, Path::new("/")).await.unwrap();
+        assert_eq!(SubTree::default(), whole);
+        assert_eq!(foo, whole.to_fs(foo));
+        assert!(whole.contains(&fs, bar).await.unwrap());
+
+        assert!(matches!(
+            SubTree::resolve(&fs, Path::new("/projects/missing")).await,
+            Err(FsError::NotFound { .. })
+        ));
+        assert!(matches!(
+            SubTree::resolve(&fs, Path::new("/projects/foo/file")).await,
+            Err(FsError::InvalidInodeType)
+        ));
+        assert!(matches!(
+            SubTree::resolve(&fs, Path::new("/projects/foo/../bar")).await,
+            Err(FsError::InvalidInput { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_control_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let available = Arc::new(AtomicBool::new(true));
+        let fs = EncryptedFs::new(
+            dir.path().to_path_buf(),
+            Box::new(ToggledPasswordProvider {
+                available: available.clone(),
+            }),
+            Cipher::ChaCha20Poly1305,
+            false,
+            FsOptions::default(),
+        )
+        .await
+        .unwrap();
+        let control = ControlDir::new(false);
+
+        // found in the root and listed there, the files in it
+        let dir_ino = control.lookup(ROOT_INODE, CONTROL_DIR_NAME).unwrap();
+        assert!(control.contains(dir_ino));
+        assert_eq!(None, control.lookup(ROOT_INODE, "other"));
+        assert_eq!(None, control.lookup(dir_ino, "missing"));
+        let entry = control.root_entry(&fs).await.unwrap().unwrap();
+        assert_eq!(dir_ino, entry.ino);
+        assert_eq!(FileType::Directory, entry.kind);
+        assert!(ControlDir::new(true)
+            .root_entry(&fs)
+            .await
+            .unwrap()
+            .is_none());
+        let root = fs.get_attr(ROOT_INODE).await.unwrap();
+        let names: Vec<_> = control
+            .read_dir(&fs, root)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name.expose_secret().to_string())
+            .collect();
+        assert_eq!(
+            vec![".", "..", "stats", "version", "drop-caches", "lock"],
+            names
+        );
+
+        // nothing is stored in the data dir
+        assert!(!fs
+            .exists_by_name(
+                ROOT_INODE,
+                &SecretString::from_str(CONTROL_DIR_NAME).unwrap()
+            )
+            .await
+            .unwrap());
+
+        // stats and version can only be read
+        let stats = control.lookup(dir_ino, "stats").unwrap();
+        let attr = control.get_attr(&fs, stats).await.unwrap();
+        assert_eq!(0o444, attr.perm);
+        let json = control
+            .read(&fs, stats, 0, attr.size as usize)
+            .await
+            .unwrap();
+        let snapshot: crate::encryptedfs::MetricsSnapshot = serde_json::from_slice(&json).unwrap();
+        assert_eq!(0, snapshot.write_handles);
+        let version = control.lookup(dir_ino, "version").unwrap();
+        assert_eq!(
+            format!("{}\n", env!("CARGO_PKG_VERSION")).as_bytes(),
+            control.read(&fs, version, 0, 100).await.unwrap()
+        );
+        assert!(control
+            .read(&fs, version, 100, 100)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(control.open(version, true, false).is_ok());
+        assert!(matches!(
+            control.open(version, false, true),
+            Err(FsError::PermissionDenied)
+        ));
+        assert!(matches!(
+            control.write(&fs, version).await,
+            Err(FsError::PermissionDenied)
+        ));
+
+        // writes to drop-caches and lock run them
+        let drop_caches = control.lookup(dir_ino, "drop-caches").unwrap();
+        assert!(matches!(
+            control.open(drop_caches, true, false),
+            Err(FsError::PermissionDenied)
+        ));
+        assert!(matches!(
+            control
+                .access(&fs, drop_caches, crate::encryptedfs::ACCESS_READ)
+                .await,
+            Err(FsError::PermissionDenied)
+        ));
+        control.open(drop_caches, false, true).unwrap();
+        control.write(&fs, drop_caches).await.unwrap();
+        let lock = control.lookup(dir_ino, "lock").unwrap();
+        available.store(false, Ordering::SeqCst);
+        control.write(&fs, lock).await.unwrap();
+        assert!(matches!(fs.unlock().await, Err(FsError::Locked)));
+
+        // its entries can't be created, removed or renamed
+        assert!(control.is_reserved(ROOT_INODE, CONTROL_DIR_NAME));
+        assert!(control.is_reserved(dir_ino, "stats"));
+        assert!(control.is_reserved(dir_ino, "new"));
+        assert!(!control.is_reserved(ROOT_INODE, "stats"));
+    }
+}
@@ -1,26 +1,31 @@
 use async_trait::async_trait;
 use std::future::Future;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use tracing::error;
 
 use crate::crypto::Cipher;
-use crate::encryptedfs::{FsError, FsResult, PasswordProvider};
+use crate::encryptedfs::{AsyncPasswordProvider, FsError, FsResult};
 use crate::mount;
-use crate::mount::{MountHandleInner, MountPoint};
+use crate::mount::{IdleAction, MountHandleInner, MountOption, MountPoint};
 
 #[allow(clippy::struct_excessive_bools)]
 #[allow(dead_code)]
 pub struct MountPointImpl {
     mountpoint: PathBuf,
     data_dir: PathBuf,
-    password_provider: Option<Box<dyn PasswordProvider>>,
+    password_provider: Option<Box<dyn AsyncPasswordProvider>>,
     cipher: Cipher,
     allow_root: bool,
     allow_other: bool,
     read_only: bool,
+    options: Vec<MountOption>,
+    idle_timeout: Option<(Duration, IdleAction)>,
+    root: Option<PathBuf>,
+    control_dir: Option<bool>,
 }
 
 #[async_trait]
@@ -28,7 +33,7 @@ impl MountPoint for MountPointImpl {
     fn new(
         mountpoint: PathBuf,
         data_dir: PathBuf,
-        password_provider: Box<dyn PasswordProvider>,
+        password_provider: Box<dyn AsyncPasswordProvider>,
         cipher: Cipher,
         allow_root: bool,
         allow_other: bool,
@@ -42,11 +47,35 @@ impl MountPoint for MountPointImpl {
             allow_root,
             allow_other,
             read_only,
+            options: vec![],
+            idle_timeout: None,
+            root: None,
+            control_dir: None,
         }
     }
 
+    fn with_option(mut self, option: MountOption) -> Self {
+        self.options.push(option);
+        self
+    }
+
+    fn with_idle_timeout(mut self, timeout: Duration, action: IdleAction) -> Self {
+        self.idle_timeout = Some((timeout, action));
+        self
+    }
+
+    fn with_root(mut self, root: PathBuf) -> Self {
+        self.root = Some(root);
+        self
+    }
+
+    fn with_control_dir(mut self, hidden: bool) -> Self {
+        self.control_dir = Some(hidden);
+        self
+    }
+
     async fn mount(mut self) -> FsResult<mount::MountHandle> {
-        Err(FsError::Other("Dummy implementation"))
+        Err(FsError::other("Dummy implementation"))
     }
 }
 
@@ -67,3 +96,11 @@ impl MountHandleInner for MountHandleInnerImpl {
         Ok(())
     }
 }
+
+pub(in crate::mount) const fn is_mounted(_mountpoint: &Path) -> bool {
+    false
+}
+
+pub(in crate::mount) const fn detach(_mountpoint: &Path, _lazy: bool) -> io::Result<()> {
+    Ok(())
+}
@@ -1,13 +1,14 @@
-use std::ffi::{OsStr, OsString};
-use std::fs::File;
+use std::collections::VecDeque;
+use std::ffi::{CString, OsStr, OsString};
 use std::future::Future;
 use std::io;
-use std::io::{BufRead, BufReader};
 use std::iter::Skip;
 use std::num::NonZeroU32;
 use std::os::raw::c_int;
-use std::path::PathBuf;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::process;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::task::{Context, Poll};
@@ -18,221 +19,293 @@ use async_trait::async_trait;
 use bytes::Bytes;
 use fuse3::raw::prelude::{
     DirectoryEntry, DirectoryEntryPlus, ReplyAttr, ReplyCopyFileRange, ReplyCreated, ReplyData,
-    ReplyDirectory, ReplyDirectoryPlus, ReplyEntry, ReplyInit, ReplyOpen, ReplyStatFs, ReplyWrite,
+    ReplyDirectory, ReplyDirectoryPlus, ReplyEntry, ReplyInit, ReplyLSeek, ReplyLock, ReplyOpen,
+    ReplyStatFs, ReplyWrite,
 };
 use fuse3::raw::{Filesystem, MountHandle, Request, Session};
 use fuse3::{Errno, Inode, MountOptions, Result, SetAttr, Timestamp};
 use futures_util::stream::Iter;
 use futures_util::{stream, FutureExt};
-use libc::{EACCES, EEXIST, EFBIG, EIO, EISDIR, ENAMETOOLONG, ENOENT, ENOTDIR, ENOTEMPTY, EPERM};
+use libc::{EACCES, ENAMETOOLONG, ENOENT, ENOTDIR, EPERM, EXDEV};
 use shush_rs::{ExposeSecret, SecretString};
 use tracing::{debug, error, instrument, trace, warn};
 use tracing::{info, Level};
 
 use crate::crypto::Cipher;
 use crate::encryptedfs::{
-    CopyFileRangeReq, CreateFileAttr, EncryptedFs, FileAttr, FileType, FsError, FsResult,
-    PasswordProvider, SetFileAttr,
+    AsyncPasswordProvider, CopyFileRangeReq, CreateFileAttr, CreateFlags, DirectoryEntryResult,
+    EncryptedFs, FileAttr, FileType, FsError, FsOptions, FsResult, LockType, OpenFlags,
+    RequestContext, SeekWhence, TimeOrNow, ACCESS_EXEC, ACCESS_READ, ACCESS_WRITE, ROOT_INODE,
 };
 use crate::mount;
-use crate::mount::{MountHandleInner, MountPoint};
+use crate::mount::{
+    Activity, ControlDir, IdleAction, MountHandleInner, MountOption, MountPoint, SubTree,
+};
 
 const TTL: Duration = Duration::from_secs(1);
-const STATFS: ReplyStatFs = ReplyStatFs {
-    blocks: 1,
-    bfree: 0,
-    bavail: 0,
-    files: 1,
-    ffree: 0,
-    bsize: 4096,
-    namelen: u32::MAX,
-    frsize: 0,
-};
+
+const FUSE_CONF: &str = "/etc/fuse.conf";
 
 const FMODE_EXEC: i32 = 0x20;
 
-// const MAX_NAME_LENGTH: u32 = 255 - ENCRYPT_FILENAME_OVERHEAD_CHARS as u32;
+/// Bypass the page cache for the handle, so the [`ControlDir`] files are read each time.
+const FOPEN_DIRECT_IO: u32 = 1;
+
+/// The end the kernel gives to the locks up to the end of the file.
+const OFFSET_MAX: u64 = i64::MAX as u64;
+
+/// How old a time given to `setattr` may be and still be taken as `UTIME_NOW`, see [`time_or_now`].
+const UTIME_NOW_TOLERANCE: Duration = Duration::from_secs(1);
 
-pub struct DirectoryEntryIterator(crate::encryptedfs::DirectoryEntryIterator, u64);
+/// The entries of a directory, then the extra ones, which already have the inodes for the kernel, like the
+/// [`ControlDir`] in the root.
+pub struct DirectoryEntryIterator(
+    Option<crate::encryptedfs::DirectoryEntryResultIterator>,
+    u64,
+    SubTree,
+    u64,
+    VecDeque<crate::encryptedfs::DirectoryEntryPlus>,
+);
 
 impl Iterator for DirectoryEntryIterator {
     type Item = Result<DirectoryEntry>;
 
     #[instrument(name = "DirectoryEntryIterator::next", skip(self))]
     fn next(&mut self) -> Option<Self::Item> {
-        match self.0.next() {
-            Some(Ok(entry)) => {
-                let kind = if entry.kind == FileType::Directory {
-                    fuse3::raw::prelude::FileType::Directory
-                } else {
-                    fuse3::raw::prelude::FileType::RegularFile
-                };
-                self.1 += 1;
-                Some(Ok(DirectoryEntry {
-                    inode: entry.ino,
-                    kind,
-                    name: OsString::from(&*entry.name.expose_secret()),
-                    #[allow(clippy::cast_possible_wrap)]
-                    offset: self.1 as i64,
-                }))
-            }
-            Some(Err(FsError::Io { source, .. })) => {
-                error!(err = %source);
-                Some(Err(source.into()))
+        // skip the corrupted entries, they are logged when listed, so the others can still be listed
+        let entry = self.0.as_mut().and_then(|iter| {
+            iter.find_map(|entry| match entry {
+                DirectoryEntryResult::Entry(entry) => Some(entry),
+                DirectoryEntryResult::Corrupted { .. } => None,
+            })
+        });
+        let (inode, kind, name) = match entry {
+            Some(entry) => {
+                let ino = self
+                    .2
+                    .entry_ino(self.3, &entry.name.expose_secret(), entry.ino);
+                (self.2.to_kernel(ino), entry.kind, entry.name)
             }
-            Some(Err(err)) => {
-                error!(err = %err);
-                Some(Err(EIO.into()))
+            None => {
+                let entry = self.4.pop_front()?;
+                (entry.ino, entry.kind, entry.name)
             }
-            None => None,
-        }
+        };
+        self.1 += 1;
+        Some(Ok(DirectoryEntry {
+            inode,
+            kind: to_fuse_kind(kind),
+            name: OsString::from(&*name.expose_secret()),
+            #[allow(clippy::cast_possible_wrap)]
+            offset: self.1 as i64,
+        }))
     }
 }
 
-pub struct DirectoryEntryPlusIterator(crate::encryptedfs::DirectoryEntryPlusIterator, u64);
+/// Like [`DirectoryEntryIterator`], with the attributes.
+pub struct DirectoryEntryPlusIterator(
+    Option<crate::encryptedfs::DirectoryEntryPlusIterator>,
+    u64,
+    SubTree,
+    u64,
+    VecDeque<crate::encryptedfs::DirectoryEntryPlus>,
+);
 
 impl Iterator for DirectoryEntryPlusIterator {
     type Item = Result<DirectoryEntryPlus>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.0.next() {
+        let (inode, kind, name, attr) = match self.0.as_mut().and_then(Iterator::next) {
             Some(Ok(entry)) => {
-                let kind = if entry.kind == FileType::Directory {
-                    fuse3::raw::prelude::FileType::Directory
-                } else {
-                    fuse3::raw::prelude::FileType::RegularFile
-                };
-                self.1 += 1;
-                Some(Ok(DirectoryEntryPlus {
-                    inode: entry.ino,
-                    generation: 0,
-                    kind,
-                    name: OsString::from(&*entry.name.expose_secret()),
-                    #[allow(clippy::cast_possible_wrap)]
-                    offset: self.1 as i64,
-                    attr: entry.attr.into(),
-                    entry_ttl: TTL,
-                    attr_ttl: TTL,
-                }))
-            }
-            Some(Err(FsError::Io { source, .. })) => {
-                error!(err = %source);
-                Some(Err(source.into()))
+                let ino = self
+                    .2
+                    .entry_ino(self.3, &entry.name.expose_secret(), entry.ino);
+                let mut attr = entry.attr;
+                attr.ino = ino;
+                let attr = self.2.attr_to_kernel(attr);
+                (attr.ino, entry.kind, entry.name, attr)
             }
             Some(Err(err)) => {
                 error!(err = %err);
-                Some(Err(EIO.into()))
+                return Some(Err(err.to_errno().into()));
             }
-            None => None,
-        }
+            None => {
+                let entry = self.4.pop_front()?;
+                (entry.ino, entry.kind, entry.name, entry.attr)
+            }
+        };
+        self.1 += 1;
+        Some(Ok(DirectoryEntryPlus {
+            inode,
+            generation: attr.ino_generation,
+            kind: to_fuse_kind(kind),
+            name: OsString::from(&*name.expose_secret()),
+            #[allow(clippy::cast_possible_wrap)]
+            offset: self.1 as i64,
+            attr: attr.into(),
+            entry_ttl: TTL,
+            attr_ttl: TTL,
+        }))
     }
 }
 
 struct EncryptedFsFuse3 {
     fs: Arc<EncryptedFs>,
+    activity: Arc<Activity>,
+    sub_tree: SubTree,
+    control: Option<ControlDir>,
+    /// If character and block devices can be created, see [`MountOption::AllowDevices`].
+    allow_devices: bool,
 }
 
 impl EncryptedFsFuse3 {
     pub async fn new(
         data_dir: PathBuf,
-        password_provider: Box<dyn PasswordProvider>,
+        password_provider: Box<dyn AsyncPasswordProvider>,
         cipher: Cipher,
         read_only: bool,
+        root: Option<&Path>,
+        control_dir: Option<bool>,
+        allow_devices: bool,
     ) -> FsResult<Self> {
+        let fs = EncryptedFs::new(
+            data_dir,
+            password_provider,
+            cipher,
+            read_only,
+            FsOptions::default(),
+        )
+        .await?;
+        let sub_tree = match root {
+            Some(root) => SubTree::resolve(&fs, root).await?,
+            None => SubTree::default(),
+        };
         Ok(Self {
-            fs: EncryptedFs::new(data_dir, password_provider, cipher, read_only).await?,
+            fs,
+            activity: Arc::new(Activity::new()),
+            sub_tree,
+            control: control_dir.map(ControlDir::new),
+            allow_devices,
         })
     }
 
+    fn reply_attr(&self, attr: FileAttr) -> fuse3::raw::prelude::FileAttr {
+        self.sub_tree.attr_to_kernel(attr).into()
+    }
+
+    /// The [`ControlDir`] if the inode from the kernel is in it.
+    fn control_of(&self, ino: u64) -> Option<&ControlDir> {
+        self.control
+            .as_ref()
+            .filter(|control| control.contains(ino))
+    }
+
+    /// Users can't create, remove or rename the entries of the [`ControlDir`], it fails with `EPERM` for them.
+    fn check_not_reserved(&self, parent: u64, name: &OsStr) -> Result<()> {
+        match &self.control {
+            Some(control) if control.is_reserved(parent, name.to_str().unwrap()) => {
+                Err(EPERM.into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The entries listed after the ones of the directory `ino` from the kernel, the [`ControlDir`] in the root if it's
+    /// not hidden, or all the entries of the [`ControlDir`].
+    async fn extra_entries(
+        &self,
+        ino: u64,
+    ) -> Result<VecDeque<crate::encryptedfs::DirectoryEntryPlus>> {
+        let Some(control) = &self.control else {
+            return Ok(VecDeque::new());
+        };
+        let fs = self.get_fs();
+        let res = if control.contains(ino) {
+            match fs.get_attr(self.sub_tree.root()).await {
+                Ok(root) => control.read_dir(&fs, root).await,
+                Err(err) => Err(err),
+            }
+        } else if ino == ROOT_INODE {
+            control
+                .root_entry(&fs)
+                .await
+                .map(|entry| entry.into_iter().collect())
+        } else {
+            Ok(vec![])
+        };
+        res.map(VecDeque::from).map_err(|err| {
+            error!(err = %err);
+            err.to_errno().into()
+        })
+    }
+
+    async fn control_attr(&self, control: &ControlDir, ino: u64) -> Result<FileAttr> {
+        control.get_attr(&self.get_fs(), ino).await.map_err(|err| {
+            error!(err = %err);
+            err.to_errno().into()
+        })
+    }
+
+    /// Each operation gets the fs with this, so it also marks the activity.
     fn get_fs(&self) -> Arc<EncryptedFs> {
+        self.activity.touch();
         self.fs.clone()
     }
 
+    /// The permissions of a new node, the SUID and SGID bits are kept or cleared by the context of the caller.
     #[allow(clippy::cast_possible_truncation)]
     const fn creation_mode(&self, mode: u32) -> u16 {
-        (mode & !(libc::S_ISUID | libc::S_ISGID)) as u16
+        (mode & 0o7777) as u16
     }
 
     #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn create_nod(
         &self,
         parent: u64,
-        mut mode: u32,
+        mode: u32,
+        rdev: u32,
         req: &Request,
         name: &OsStr,
-        read: bool,
-        write: bool,
+        flags: CreateFlags,
     ) -> std::result::Result<(u64, FileAttr), c_int> {
-        let parent_attr = match self.get_fs().get_attr(parent).await {
-            Err(err) => {
-                error!(err = %err);
-                return Err(ENOENT);
-            }
-            Ok(parent_attr) => parent_attr,
-        };
-
-        if !check_access(
-            parent_attr.uid,
-            parent_attr.gid,
-            parent_attr.perm,
-            req.uid,
-            req.gid,
-            libc::W_OK,
-        ) {
-            return Err(EACCES);
-        }
-
-        if req.uid != 0 {
-            mode &= !(libc::S_ISUID | libc::S_ISGID);
-        }
-
         let kind = as_file_kind(mode);
         let mut attr = if kind == FileType::Directory {
             dir_attr()
         } else {
             file_attr()
         };
+        attr.kind = kind;
+        // the owner and the group are set by the context
         attr.perm = self.creation_mode(mode);
-        attr.uid = req.uid;
-        attr.gid = creation_gid(&parent_attr, req.gid);
+        if matches!(kind, FileType::CharDevice | FileType::BlockDevice) {
+            attr.rdev = rdev;
+        }
 
         let (fh, attr) = self
             .get_fs()
-            .create(
+            .with_context(request_context(req))
+            .create_with_flags(
                 parent,
                 &SecretString::from_str(name.to_str().unwrap()).unwrap(),
                 attr,
-                read,
-                write,
+                flags,
             )
             .await
             .map_err(|err| {
                 error!(err = %err);
-                match err {
-                    FsError::AlreadyExists => EEXIST,
-                    FsError::Io { source, .. } => {
-                        if source.to_string().to_lowercase().contains("too long") {
-                            ENAMETOOLONG
-                        } else {
-                            EIO
-                        }
+                match &err {
+                    FsError::Io { source, .. }
+                        if source.to_string().to_lowercase().contains("too long") =>
+                    {
+                        ENAMETOOLONG
                     }
-                    _ => EIO,
+                    _ => err.to_errno(),
                 }
             })?;
         Ok((fh, attr))
     }
 }
 
-#[allow(clippy::cast_possible_truncation)]
-const fn creation_gid(parent: &FileAttr, gid: u32) -> u32 {
-    if parent.perm & libc::S_ISGID as u16 != 0 {
-        return parent.gid;
-    }
-
-    gid
-}
-
 impl From<FileAttr> for fuse3::raw::prelude::FileAttr {
     fn from(from: FileAttr) -> Self {
         Self {
@@ -242,11 +315,7 @@ impl From<FileAttr> for fuse3::raw::prelude::FileAttr {
             atime: from.atime.into(),
             mtime: from.mtime.into(),
             ctime: from.ctime.into(),
-            kind: if from.kind == FileType::Directory {
-                fuse3::raw::prelude::FileType::Directory
-            } else {
-                fuse3::raw::prelude::FileType::RegularFile
-            },
+            kind: to_fuse_kind(from.kind),
             perm: from.perm,
             nlink: from.nlink,
             uid: from.uid,
@@ -270,39 +339,49 @@ impl Filesystem for EncryptedFsFuse3 {
     #[instrument(skip(self))]
     async fn destroy(&self, req: Request) {
         trace!("");
+
+        if let Err(err) = self.get_fs().close_all().await {
+            error!(err = %err, "closing handles");
+        }
     }
 
     #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::DEBUG), ret(level = Level::DEBUG))]
     async fn lookup(&self, req: Request, parent: u64, name: &OsStr) -> Result<ReplyEntry> {
         trace!("");
+        if let Some(control) = &self.control {
+            if let Some(ino) = control.lookup(parent, name.to_str().unwrap()) {
+                let attr = self.control_attr(control, ino).await?;
+                return Ok(ReplyEntry {
+                    ttl: TTL,
+                    attr: attr.into(),
+                    generation: attr.ino_generation,
+                });
+            }
+            if control.contains(parent) {
+                return Err(ENOENT.into());
+            }
+        }
+        let parent = self.sub_tree.to_fs(parent);
 
         // if name.len() > MAX_NAME_LENGTH as usize {
         //     warn!(name = %name.to_str().unwrap(), "name too long");
         //     return Err(ENAMETOOLONG.into());
         // }
 
-        match self.get_fs().get_attr(parent).await {
-            Err(err) => {
-                error!(parent, err = %err, "not found");
-                return Err(ENOENT.into());
-            }
-            Ok(parent_attr) => {
-                if !check_access(
-                    parent_attr.uid,
-                    parent_attr.gid,
-                    parent_attr.perm,
-                    req.uid,
-                    req.gid,
-                    libc::X_OK,
-                ) {
-                    return Err(EACCES.into());
-                }
-            }
+        if let Err(err) = self
+            .get_fs()
+            .with_context(request_context(&req))
+            .access(parent, ACCESS_EXEC)
+            .await
+        {
+            error!(parent, err = %err);
+            return Err(err.to_errno().into());
         }
 
         let attr = match self
-            .get_fs()
+            .sub_tree
             .find_by_name(
+                &self.get_fs(),
                 parent,
                 &SecretString::from_str(name.to_str().unwrap()).unwrap(),
             )
@@ -320,8 +399,8 @@ impl Filesystem for EncryptedFsFuse3 {
 
         Ok(ReplyEntry {
             ttl: TTL,
-            attr: attr.into(),
-            generation: 0,
+            attr: self.reply_attr(attr),
+            generation: attr.ino_generation,
         })
     }
 
@@ -339,6 +418,13 @@ impl Filesystem for EncryptedFsFuse3 {
         flags: u32,
     ) -> Result<ReplyAttr> {
         trace!("");
+        if let Some(control) = self.control_of(inode) {
+            return Ok(ReplyAttr {
+                ttl: TTL,
+                attr: self.control_attr(control, inode).await?.into(),
+            });
+        }
+        let inode = self.sub_tree.to_fs(inode);
 
         match self.get_fs().get_attr(inode).await {
             Err(err) => {
@@ -347,7 +433,7 @@ impl Filesystem for EncryptedFsFuse3 {
             }
             Ok(attr) => Ok(ReplyAttr {
                 ttl: TTL,
-                attr: attr.into(),
+                attr: self.reply_attr(attr),
             }),
         }
     }
@@ -362,163 +448,100 @@ impl Filesystem for EncryptedFsFuse3 {
         set_attr: SetAttr,
     ) -> Result<ReplyAttr> {
         trace!("");
+        if let Some(control) = self.control_of(inode) {
+            // the size and the times are ignored, so they can be opened with `O_TRUNC`
+            if set_attr.mode.is_some() || set_attr.uid.is_some() || set_attr.gid.is_some() {
+                return Err(EPERM.into());
+            }
+            return Ok(ReplyAttr {
+                ttl: TTL,
+                attr: self.control_attr(control, inode).await?.into(),
+            });
+        }
+        let inode = self.sub_tree.to_fs(inode);
         debug!("{set_attr:#?}");
 
-        let attr = self.get_fs().get_attr(inode).await.map_err(|err| {
+        self.get_fs().get_attr(inode).await.map_err(|err| {
             error!(err = %err);
             Errno::from(ENOENT)
         })?;
 
-        let mut set_attr2 = SetFileAttr::default();
+        let fs = self.get_fs();
+        let ctx_fs = fs.with_context(request_context(&req));
 
         if let Some(mode) = set_attr.mode {
             debug!("chmod mode={mode:o}");
-            let mut set_attr2 = SetFileAttr::default();
-            if req.uid != 0 && req.uid != attr.uid {
-                return Err(EPERM.into());
-            }
-            if req.uid != 0 && req.gid != attr.gid && !get_groups(req.pid).contains(&attr.gid) {
-                // If SGID is set and the file belongs to a group that the caller is not part of
-                // then the SGID bit is supposed to be cleared during chmod
-                set_attr2 = set_attr2.with_perm((mode & !libc::S_ISGID) as u16);
-            } else {
-                set_attr2 = set_attr2.with_perm(mode as u16);
-            }
-            set_attr2 = set_attr2.with_atime(SystemTime::now());
-            self.get_fs()
-                .set_attr(inode, set_attr2)
+            // the SGID bit is cleared if the caller is not in the group of the file
+            ctx_fs
+                .set_permissions(inode, (mode & 0o7777) as u16)
                 .await
                 .map_err(|err| {
                     error!(err = %err);
-                    Errno::from(EIO)
+                    Errno::from(err.to_errno())
                 })?;
             return Ok(ReplyAttr {
                 ttl: TTL,
-                attr: self
-                    .get_fs()
-                    .get_attr(inode)
-                    .await
-                    .map_err(|_err| Errno::from(ENOENT))?
-                    .into(),
+                attr: self.reply_attr(
+                    fs.get_attr(inode)
+                        .await
+                        .map_err(|_err| Errno::from(ENOENT))?,
+                ),
             });
         }
 
         if set_attr.uid.is_some() || set_attr.gid.is_some() {
             debug!(?set_attr.uid, ?set_attr.gid, "chown");
-            let mut set_attr2 = SetFileAttr::default();
-            if let Some(gid) = set_attr2.gid {
-                // Non-root users can only change gid to a group they're in
-                if req.uid != 0 && !get_groups(req.pid).contains(&gid) {
-                    return Err(EPERM.into());
-                }
-            }
-            if let Some(uid) = set_attr2.uid {
-                if req.uid != 0
-                    // but no-op changes by the owner are not an error
-                    && !(uid == attr.uid && req.uid == attr.uid)
-                {
-                    return Err(EPERM.into());
-                }
-            }
-            // Only owner may change the group
-            if set_attr2.gid.is_some() && req.uid != 0 && req.uid != attr.uid {
-                return Err(EPERM.into());
-            }
-
-            set_attr2 = set_attr2.with_perm(attr.perm);
-            if attr.perm & (libc::S_IXUSR | libc::S_IXGRP | libc::S_IXOTH) as u16 != 0 {
-                // SUID & SGID are suppose to be cleared when chown'ing an executable file
-                set_attr2 = set_attr2.with_perm(clear_suid_sgid(attr.perm));
-            }
-
-            if let Some(uid) = set_attr2.uid {
-                set_attr2 = set_attr2.with_uid(uid);
-                // Clear SETUID on owner change
-                let perm = *set_attr2.perm.as_ref().unwrap();
-                set_attr2 = set_attr2.with_perm(perm & !(libc::S_ISUID as u16));
-            }
-            if let Some(gid) = set_attr2.gid {
-                set_attr2 = set_attr2.with_gid(gid);
-                // Clear SETGID unless user is root
-                if req.uid != 0 {
-                    let perm = *set_attr2.perm.as_ref().unwrap();
-                    set_attr2 = set_attr2.with_perm(perm & !(libc::S_ISGID as u16));
-                }
-            }
-            set_attr2 = set_attr2.with_atime(SystemTime::now());
-            self.get_fs()
-                .set_attr(inode, set_attr2)
+            // the SUID and SGID bits are cleared with it
+            ctx_fs
+                .set_owner(inode, set_attr.uid, set_attr.gid)
                 .await
                 .map_err(|err| {
                     error!(err = %err);
-                    Errno::from(EIO)
+                    Errno::from(err.to_errno())
                 })?;
             return Ok(ReplyAttr {
                 ttl: TTL,
-                attr: self
-                    .get_fs()
-                    .get_attr(inode)
-                    .await
-                    .map_err(|_err| Errno::from(ENOENT))?
-                    .into(),
+                attr: self.reply_attr(
+                    fs.get_attr(inode)
+                        .await
+                        .map_err(|_err| Errno::from(ENOENT))?,
+                ),
             });
         }
 
         if let Some(size) = set_attr.size {
             debug!(size, "truncate");
 
-            self.get_fs().set_len(inode, size).await.map_err(|err| {
+            // the SUID and SGID bits are cleared with it
+            ctx_fs.set_len(inode, size).await.map_err(|err| {
                 error!(err = %err);
-                Errno::from(EIO)
+                Errno::from(err.to_errno())
             })?;
-            set_attr2 = set_attr2.with_size(size);
-
-            // Clear SETUID & SETGID on truncate
-            set_attr2 = set_attr2.with_perm(clear_suid_sgid(attr.perm));
         }
 
-        if let Some(atime) = set_attr.atime {
-            debug!(?atime, "utimens");
-
-            if attr.uid != req.uid
-                && !check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, libc::W_OK)
-            {
-                return Err(EACCES.into());
-            }
-
-            set_attr2 = set_attr2.with_atime(system_time_from_timestamp(atime));
-            set_attr2 = set_attr2.with_ctime(SystemTime::now());
-        }
-
-        if let Some(mtime) = set_attr.mtime {
-            debug!(?mtime, "utimens");
-
-            if attr.uid != req.uid
-                && !check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, libc::W_OK)
-            {
-                return Err(EACCES.into());
-            }
-
-            set_attr2 = set_attr2.with_mtime(system_time_from_timestamp(mtime));
-            set_attr2 = set_attr2.with_ctime(SystemTime::now());
+        if set_attr.atime.is_some() || set_attr.mtime.is_some() {
+            debug!(?set_attr.atime, ?set_attr.mtime, "utimens");
+            // explicit times need to be the owner, the current time the owner or write access
+            ctx_fs
+                .set_times(
+                    inode,
+                    set_attr.atime.map(time_or_now),
+                    set_attr.mtime.map(time_or_now),
+                )
+                .await
+                .map_err(|err| {
+                    error!(err = %err);
+                    Errno::from(err.to_errno())
+                })?;
         }
 
-        self.get_fs()
-            .set_attr(inode, set_attr2)
-            .await
-            .map_err(|err| {
-                error!(err = %err);
-                Errno::from(EIO)
-            })?;
-
         Ok(ReplyAttr {
             ttl: TTL,
-            attr: self
-                .get_fs()
-                .get_attr(inode)
-                .await
-                .map_err(|_err| Errno::from(ENOENT))?
-                .into(),
+            attr: self.reply_attr(
+                fs.get_attr(inode)
+                    .await
+                    .map_err(|_err| Errno::from(ENOENT))?,
+            ),
         })
     }
 
@@ -532,20 +555,32 @@ impl Filesystem for EncryptedFsFuse3 {
         rdev: u32,
     ) -> Result<ReplyEntry> {
         trace!("");
+        self.check_not_reserved(parent, name)?;
+        let parent = self.sub_tree.to_fs(parent);
         debug!("mode={mode:o}");
 
         let file_type = mode & libc::S_IFMT;
 
-        if file_type != libc::S_IFREG
-            // && file_type != libc::S_IFLNK as u32
-            && file_type != libc::S_IFDIR
-        {
-            // TODO
-            warn!("implementation is incomplete. Only supports regular files and directories. Got mode={mode:o}");
-            return Err(libc::ENOSYS.into());
+        match file_type {
+            libc::S_IFREG | libc::S_IFDIR | libc::S_IFIFO | libc::S_IFSOCK => {}
+            libc::S_IFCHR | libc::S_IFBLK => {
+                if !self.allow_devices {
+                    warn!("devices are not allowed, mount with MountOption::AllowDevices");
+                    return Err(EPERM.into());
+                }
+            }
+            _ => {
+                // TODO
+                warn!("implementation is incomplete. Doesn't support symlinks. Got mode={mode:o}");
+                return Err(libc::ENOSYS.into());
+            }
         }
 
-        self.create_nod(parent, mode, &req, name, false, false)
+        let flags = CreateFlags {
+            exclusive: true,
+            ..Default::default()
+        };
+        self.create_nod(parent, mode, rdev, &req, name, flags)
             .await
             .map_err(|err| {
                 error!(err = %err);
@@ -554,8 +589,8 @@ impl Filesystem for EncryptedFsFuse3 {
             .map(|(_, attr)| {
                 Ok(ReplyEntry {
                     ttl: TTL,
-                    attr: attr.into(),
-                    generation: 0,
+                    attr: self.reply_attr(attr),
+                    generation: attr.ino_generation,
                 })
             })?
     }
@@ -570,44 +605,17 @@ impl Filesystem for EncryptedFsFuse3 {
         umask: u32,
     ) -> Result<ReplyEntry> {
         trace!("");
+        self.check_not_reserved(parent, name)?;
+        let parent = self.sub_tree.to_fs(parent);
         debug!("mode={mode:o}");
 
-        let parent_attr = match self.get_fs().get_attr(parent).await {
-            Err(err) => {
-                error!(err = %err);
-                return Err(ENOENT.into());
-            }
-            Ok(parent_attr) => parent_attr,
-        };
-
-        if !check_access(
-            parent_attr.uid,
-            parent_attr.gid,
-            parent_attr.perm,
-            req.uid,
-            req.gid,
-            libc::W_OK,
-        ) {
-            return Err(EACCES.into());
-        }
-
         let mut attr = dir_attr();
-
-        let mut mode = mode;
-        if req.uid != 0 {
-            mode &= !(libc::S_ISUID | libc::S_ISGID);
-        }
-        #[allow(clippy::cast_possible_truncation)]
-        if parent_attr.perm & libc::S_ISGID as u16 != 0 {
-            mode |= libc::S_ISGID;
-        }
+        // the owner, the group and the SGID of the parent are set by the context
         attr.perm = self.creation_mode(mode);
 
-        attr.uid = req.uid;
-        attr.gid = creation_gid(&parent_attr, req.gid);
-
         let (_, attr) = self
             .get_fs()
+            .with_context(request_context(&req))
             .create(
                 parent,
                 &SecretString::from_str(name.to_str().unwrap()).unwrap(),
@@ -618,67 +626,25 @@ impl Filesystem for EncryptedFsFuse3 {
             .await
             .map_err(|err| {
                 error!(err = %err);
-                Errno::from(ENOENT)
+                Errno::from(err.to_errno())
             })?;
         Ok(ReplyEntry {
             ttl: TTL,
-            attr: attr.into(),
-            generation: 0,
+            attr: self.reply_attr(attr),
+            generation: attr.ino_generation,
         })
     }
 
     #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn unlink(&self, req: Request, parent: Inode, name: &OsStr) -> Result<()> {
         trace!("");
+        self.check_not_reserved(parent, name)?;
+        let parent = self.sub_tree.to_fs(parent);
 
-        let parent_attr = match self.get_fs().get_attr(parent).await {
-            Err(err) => {
-                error!(err = %err);
-                return Err(ENOENT.into());
-            }
-            Ok(attr) => attr,
-        };
-
-        if !check_access(
-            parent_attr.uid,
-            parent_attr.gid,
-            parent_attr.perm,
-            req.uid,
-            req.gid,
-            libc::W_OK,
-        ) {
-            return Err(EACCES.into());
-        }
-
-        let attr = match self
-            .get_fs()
-            .find_by_name(
-                parent,
-                &SecretString::from_str(name.to_str().unwrap()).unwrap(),
-            )
-            .await
-        {
-            Ok(Some(attr)) => attr,
-            Err(err) => {
-                error!(err = %err);
-                return Err(ENOENT.into());
-            }
-            _ => return Err(ENOENT.into()),
-        };
-
-        let uid = req.uid;
-        // "Sticky bit" handling
-        #[allow(clippy::cast_possible_truncation)]
-        if parent_attr.perm & libc::S_ISVTX as u16 != 0
-            && uid != 0
-            && uid != parent_attr.uid
-            && uid != attr.uid
-        {
-            return Err(EACCES.into());
-        }
-
+        // with the sticky bit handling
         if let Err(err) = self
             .get_fs()
+            .with_context(request_context(&req))
             .remove_file(
                 parent,
                 &SecretString::from_str(name.to_str().unwrap()).unwrap(),
@@ -686,7 +652,7 @@ impl Filesystem for EncryptedFsFuse3 {
             .await
         {
             error!(err = %err);
-            return Err(ENOENT.into());
+            return Err(err.to_errno().into());
         }
 
         Ok(())
@@ -695,52 +661,13 @@ impl Filesystem for EncryptedFsFuse3 {
     #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn rmdir(&self, req: Request, parent: Inode, name: &OsStr) -> Result<()> {
         trace!("");
+        self.check_not_reserved(parent, name)?;
+        let parent = self.sub_tree.to_fs(parent);
 
-        let Ok(parent_attr) = self.get_fs().get_attr(parent).await else {
-            error!(parent, "not found");
-            return Err(ENOENT.into());
-        };
-
-        if !check_access(
-            parent_attr.uid,
-            parent_attr.gid,
-            parent_attr.perm,
-            req.uid,
-            req.gid,
-            libc::W_OK,
-        ) {
-            return Err(EACCES.into());
-        }
-
-        let Ok(Some(attr)) = self
-            .get_fs()
-            .find_by_name(
-                parent,
-                &SecretString::from_str(name.to_str().unwrap()).unwrap(),
-            )
-            .await
-        else {
-            error!(parent, name = name.to_str().unwrap());
-            return Err(ENOENT.into());
-        };
-
-        if attr.kind != FileType::Directory {
-            return Err(ENOTDIR.into());
-        }
-
-        let uid = req.uid;
-        // "Sticky bit" handling
-        #[allow(clippy::cast_possible_truncation)]
-        if parent_attr.perm & libc::S_ISVTX as u16 != 0
-            && uid != 0
-            && uid != parent_attr.uid
-            && uid != attr.uid
-        {
-            return Err(EACCES.into());
-        }
-
+        // with the sticky bit handling
         if let Err(err) = self
             .get_fs()
+            .with_context(request_context(&req))
             .remove_dir(
                 parent,
                 &SecretString::from_str(name.to_str().unwrap()).unwrap(),
@@ -748,10 +675,11 @@ impl Filesystem for EncryptedFsFuse3 {
             .await
         {
             error!(err = %err);
-            return match err {
-                FsError::NotEmpty => Err(EISDIR.into()),
-                _ => Err(EIO.into()),
-            };
+            return Err(match err {
+                FsError::InvalidInodeType => ENOTDIR,
+                _ => err.to_errno(),
+            }
+            .into());
         }
 
         Ok(())
@@ -767,161 +695,154 @@ impl Filesystem for EncryptedFsFuse3 {
         new_name: &OsStr,
     ) -> Result<()> {
         trace!("");
+        self.check_not_reserved(parent, name)?;
+        self.check_not_reserved(new_parent, new_name)?;
+        let parent = self.sub_tree.to_fs(parent);
+        let new_parent = self.sub_tree.to_fs(new_parent);
+
+        // the entry must stay in the mounted sub-tree
+        match self.sub_tree.contains(&self.get_fs(), new_parent).await {
+            Ok(true) => {}
+            Ok(false) => return Err(EXDEV.into()),
+            Err(err) => {
+                error!(err = %err);
+                return Err(ENOENT.into());
+            }
+        }
 
-        let Ok(Some(attr)) = self
+        // with the sticky bit handling in both parents
+        match self
             .get_fs()
-            .find_by_name(
+            .with_context(request_context(&req))
+            .rename(
                 parent,
                 &SecretString::from_str(name.to_str().unwrap()).unwrap(),
+                new_parent,
+                &SecretString::from_str(new_name.to_str().unwrap()).unwrap(),
             )
             .await
-        else {
-            error!(
-                parent,
-                name = name.to_str().unwrap(),
-                new_name = new_name.to_str().unwrap()
-            );
-            return Err(ENOENT.into());
-        };
-
-        let Ok(parent_attr) = self.get_fs().get_attr(parent).await else {
-            error!(parent, "parent not found");
-            return Err(ENOENT.into());
-        };
-
-        if !check_access(
-            parent_attr.uid,
-            parent_attr.gid,
-            parent_attr.perm,
-            req.uid,
-            req.gid,
-            libc::W_OK,
-        ) {
-            return Err(EACCES.into());
-        }
-
-        // "Sticky bit" handling
-        #[allow(clippy::cast_possible_truncation)]
-        if parent_attr.perm & libc::S_ISVTX as u16 != 0
-            && req.uid != 0
-            && req.uid != parent_attr.uid
-            && req.uid != attr.uid
         {
-            return Err(EACCES.into());
+            Ok(()) => Ok(()),
+            Err(err) => {
+                error!(err = %err);
+                Err(err.to_errno().into())
+            }
         }
+    }
 
-        let Ok(new_parent_attr) = self.get_fs().get_attr(new_parent).await else {
-            error!(new_parent, "not found");
-            return Err(ENOENT.into());
-        };
-
-        if !check_access(
-            new_parent_attr.uid,
-            new_parent_attr.gid,
-            new_parent_attr.perm,
-            req.uid,
-            req.gid,
-            libc::W_OK,
-        ) {
-            return Err(EACCES.into());
+    #[instrument(skip(self, new_name), fields(new_name = new_name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn link(
+        &self,
+        req: Request,
+        inode: Inode,
+        new_parent: Inode,
+        new_name: &OsStr,
+    ) -> Result<ReplyEntry> {
+        trace!("");
+        if self.control_of(inode).is_some() {
+            return Err(EPERM.into());
         }
-
-        // "Sticky bit" handling in new_parent
-        #[allow(clippy::cast_possible_truncation)]
-        if new_parent_attr.perm & libc::S_ISVTX as u16 != 0 {
-            if let Ok(Some(new_attrs)) = self
-                .get_fs()
-                .find_by_name(
-                    new_parent,
-                    &SecretString::from_str(new_name.to_str().unwrap()).unwrap(),
-                )
-                .await
-            {
-                if req.uid != 0 && req.uid != new_parent_attr.uid && req.uid != new_attrs.uid {
-                    return Err(EACCES.into());
-                }
+        self.check_not_reserved(new_parent, new_name)?;
+        let inode = self.sub_tree.to_fs(inode);
+        let new_parent = self.sub_tree.to_fs(new_parent);
+
+        // the entry must stay in the mounted sub-tree
+        match self.sub_tree.contains(&self.get_fs(), new_parent).await {
+            Ok(true) => {}
+            Ok(false) => return Err(EXDEV.into()),
+            Err(err) => {
+                error!(err = %err);
+                return Err(ENOENT.into());
             }
         }
 
-        // Only move an existing directory to a new parent, if we have write access to it,
-        // because that will change the ".." link in it
-        if attr.kind == FileType::Directory
-            && parent != new_parent
-            && !check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, libc::W_OK)
-        {
-            return Err(EACCES.into());
-        }
-
-        match self
+        let attr = self
             .get_fs()
-            .rename(
-                parent,
-                &SecretString::from_str(name.to_str().unwrap()).unwrap(),
+            .with_context(request_context(&req))
+            .link(
+                inode,
                 new_parent,
                 &SecretString::from_str(new_name.to_str().unwrap()).unwrap(),
             )
             .await
-        {
-            Ok(()) => Ok(()),
-            Err(FsError::NotEmpty) => Err(ENOTEMPTY.into()),
-            _ => Err(ENOENT.into()),
-        }
+            .map_err(|err| {
+                error!(err = %err);
+                match err {
+                    // can't link directories
+                    FsError::InvalidInodeType => EPERM,
+                    _ => err.to_errno(),
+                }
+            })?;
+
+        Ok(ReplyEntry {
+            ttl: TTL,
+            attr: self.reply_attr(attr),
+            generation: attr.ino_generation,
+        })
     }
 
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn open(&self, req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
         trace!("");
+        let kernel_inode = inode;
+        let inode = self.sub_tree.to_fs(inode);
 
         #[allow(clippy::cast_possible_wrap)]
-        let (access_mask, read, write) = match flags as i32 & libc::O_ACCMODE {
+        let (exec, read, write) = match flags as i32 & libc::O_ACCMODE {
             libc::O_RDONLY => {
                 // Behavior is undefined, but most filesystems return EACCES
                 if flags & libc::O_TRUNC as u32 != 0 {
                     return Err(EACCES.into());
                 }
-                if flags & FMODE_EXEC as u32 != 0 {
-                    // Open is from internal exec syscall
-                    (libc::X_OK, true, false)
-                } else {
-                    (libc::R_OK, true, false)
-                }
+                // Open is from internal exec syscall
+                (flags & FMODE_EXEC as u32 != 0, true, false)
             }
-            libc::O_WRONLY => (libc::W_OK, false, true),
-            libc::O_RDWR => (libc::R_OK | libc::W_OK, true, true),
+            libc::O_WRONLY => (false, false, true),
+            libc::O_RDWR => (false, true, true),
             // Exactly one access mode flag must be specified
             _ => {
                 return Err(libc::EINVAL.into());
             }
         };
 
+        if let Some(control) = self.control_of(kernel_inode) {
+            if exec {
+                return Err(EACCES.into());
+            }
+            control
+                .open(kernel_inode, read, write)
+                .map_err(|err| err.to_errno())?;
+            return Ok(ReplyOpen {
+                fh: 0,
+                flags: FOPEN_DIRECT_IO,
+            });
+        }
+
         // let _create = flags & libc::O_CREAT as u32 != 0;
         let truncate = flags & libc::O_TRUNC as u32 != 0;
         // let _append = flags & libc::O_APPEND as u32 != 0;
 
-        let attr = self.get_fs().get_attr(inode).await.map_err(|err| {
-            error!(err = %err);
-            EIO
-        })?;
-        //
-        if check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, access_mask) {
-            if truncate {
-                self.get_fs().set_len(attr.ino, 0).await.map_err(|err| {
-                    error!(err = %err);
-                    EIO
-                })?;
-            }
-            let fh = self
-                .get_fs()
-                .open(inode, read, write)
-                .await
-                .map_err(|err| {
-                    error!(err = %err);
-                    EIO
-                })?;
-            Ok(ReplyOpen { fh, flags: 0 })
+        let flags = OpenFlags {
+            read,
+            write,
+            truncate,
+        };
+        let fs = self.get_fs();
+        let ctx_fs = fs.with_context(request_context(&req));
+        let res = if exec {
+            // exec only needs the execute permission
+            match ctx_fs.access(inode, ACCESS_EXEC).await {
+                Ok(()) => fs.open_with_flags(inode, flags).await,
+                Err(err) => Err(err),
+            }
         } else {
-            return Err(EACCES.into());
-        }
+            ctx_fs.open_with_flags(inode, flags).await
+        };
+        let fh = res.map_err(|err| {
+            error!(err = %err);
+            err.to_errno()
+        })?;
+        Ok(ReplyOpen { fh, flags: 0 })
     }
 
     #[instrument(skip(self), err(level = Level::WARN))]
@@ -934,12 +855,23 @@ impl Filesystem for EncryptedFsFuse3 {
         size: u32,
     ) -> Result<ReplyData> {
         trace!("");
+        if let Some(control) = self.control_of(inode) {
+            let data = control
+                .read(&self.get_fs(), inode, offset, size as usize)
+                .await
+                .map_err(|err| {
+                    error!(err = %err);
+                    Errno::from(err.to_errno())
+                })?;
+            return Ok(ReplyData { data: data.into() });
+        }
+        let inode = self.sub_tree.to_fs(inode);
 
         let mut buf = vec![0; size as usize];
         match self.get_fs().read(inode, offset, &mut buf, fh).await {
             Err(err) => {
                 error!(err = %err);
-                return Err(EIO.into());
+                return Err(err.to_errno().into());
             }
             Ok(len) => Ok(ReplyData {
                 data: Bytes::copy_from_slice(buf[..len].as_ref()),
@@ -959,18 +891,28 @@ impl Filesystem for EncryptedFsFuse3 {
         flags: u32,
     ) -> Result<ReplyWrite> {
         trace!("");
+        if let Some(control) = self.control_of(inode) {
+            control.write(&self.get_fs(), inode).await.map_err(|err| {
+                error!(err = %err);
+                Errno::from(err.to_errno())
+            })?;
+            return Ok(ReplyWrite {
+                #[allow(clippy::cast_possible_truncation)]
+                written: data.len() as u32,
+            });
+        }
+        let inode = self.sub_tree.to_fs(inode);
         debug!(size = data.len());
 
+        // the SUID and SGID bits are cleared if the caller is not root
         let len = self
             .get_fs()
+            .with_context(request_context(&req))
             .write(inode, offset, data, fh)
             .await
             .map_err(|err| {
                 error!(err = %err);
-                match err {
-                    FsError::MaxFilesizeExceeded(_) => EFBIG,
-                    _ => EIO,
-                }
+                err.to_errno()
             })?;
 
         Ok(ReplyWrite {
@@ -982,8 +924,26 @@ impl Filesystem for EncryptedFsFuse3 {
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn statfs(&self, req: Request, inode: u64) -> Result<ReplyStatFs> {
         trace!("");
-        warn!("implementation is a stub");
-        Ok(STATFS)
+
+        match self.get_fs().statfs().await {
+            Ok(stats) => {
+                let bsize = u64::from(stats.block_size.max(1));
+                Ok(ReplyStatFs {
+                    blocks: stats.total_bytes / bsize,
+                    bfree: stats.free_bytes / bsize,
+                    bavail: stats.available_bytes / bsize,
+                    files: stats.used_inodes + stats.free_inodes,
+                    ffree: stats.free_inodes,
+                    bsize: stats.block_size,
+                    namelen: stats.max_name_len,
+                    frsize: stats.block_size,
+                })
+            }
+            Err(err) => {
+                error!(err = %err);
+                Err(err.to_errno().into())
+            }
+        }
     }
 
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
@@ -997,49 +957,130 @@ impl Filesystem for EncryptedFsFuse3 {
         flush: bool,
     ) -> Result<()> {
         trace!("");
-
+        if self.control_of(inode).is_some() {
+            return Ok(());
+        }
         let fs = self.get_fs();
 
         if flush {
             if let Err(err) = fs.flush(fh).await {
                 error!(err = %err);
-                return Err(EIO.into());
+                return Err(err.to_errno().into());
             }
         }
 
-        let is_write_handle = fs.is_write_handle(fh);
-
         if let Err(err) = fs.release(fh).await {
             error!(err = %err);
-            return Err(EIO.into());
+            return Err(err.to_errno().into());
         }
 
-        if is_write_handle.await {
-            let attr = fs.get_attr(inode).await.map_err(|err| {
-                error!(err = %err);
-                Errno::from(ENOENT)
-            })?;
-            let mut set_attr = SetFileAttr::default();
-
-            // XXX: In theory we should only need to do this when WRITE_KILL_PRIV is set for 7.31+
-            // However, xfstests fail in that case
-            set_attr = set_attr.with_perm(clear_suid_sgid(attr.perm));
-            fs.set_attr(inode, set_attr).await.map_err(|err| {
-                error!(err = %err, "replace attr");
-                Errno::from(EIO)
-            })?;
+        Ok(())
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn flush(&self, req: Request, inode: Inode, fh: u64, lock_owner: u64) -> Result<()> {
+        trace!("");
+        if self.control_of(inode).is_some() {
+            return Ok(());
+        }
+
+        let fs = self.get_fs();
+        // each close flushes, and closing a file drops the locks of the process on it
+        fs.release_locks(self.sub_tree.to_fs(inode), lock_owner);
+        if let Err(err) = fs.flush(fh).await {
+            error!(err = %err, fh);
+            return Err(err.to_errno().into());
         }
 
         Ok(())
     }
 
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
-    async fn flush(&self, req: Request, inode: Inode, fh: u64, lock_owner: u64) -> Result<()> {
+    #[allow(clippy::cast_sign_loss)]
+    async fn getlk(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        r#type: u32,
+        pid: u32,
+    ) -> Result<ReplyLock> {
+        trace!("");
+        let inode = self.sub_tree.to_fs(inode);
+
+        let kind = lock_type(r#type)?.unwrap_or(LockType::Write);
+        let range = lock_range(start, end);
+        match self.get_fs().test_lock(inode, lock_owner, range, kind) {
+            Ok(Some(lock)) => Ok(ReplyLock {
+                start: lock.range.start,
+                end: lock_end(lock.range.end),
+                r#type: match lock.kind {
+                    LockType::Read => libc::F_RDLCK as u32,
+                    LockType::Write => libc::F_WRLCK as u32,
+                },
+                // we don't know the process of the owner
+                pid: 0,
+            }),
+            Ok(None) => Ok(ReplyLock {
+                start,
+                end,
+                r#type: libc::F_UNLCK as u32,
+                pid: 0,
+            }),
+            Err(err) => {
+                error!(err = %err);
+                Err(err.to_errno().into())
+            }
+        }
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn setlk(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        r#type: u32,
+        pid: u32,
+        block: bool,
+    ) -> Result<()> {
+        trace!("");
+        let inode = self.sub_tree.to_fs(inode);
+
+        let fs = self.get_fs();
+        let range = lock_range(start, end);
+        let res = match lock_type(r#type)? {
+            Some(kind) => {
+                fs.lock_range(inode, fh, lock_owner, range, kind, block)
+                    .await
+            }
+            None => fs.unlock_range(inode, lock_owner, &range),
+        };
+        res.map_err(|err| {
+            // a conflict is expected, not worth an error
+            if !matches!(err, FsError::LockConflict) {
+                error!(err = %err);
+            }
+            err.to_errno().into()
+        })
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn fsync(&self, req: Request, inode: Inode, fh: u64, datasync: bool) -> Result<()> {
         trace!("");
+        if self.control_of(inode).is_some() {
+            return Ok(());
+        }
 
-        if let Err(err) = self.get_fs().flush(fh).await {
+        if let Err(err) = self.get_fs().fsync(fh, datasync).await {
             error!(err = %err, fh);
-            return Err(EIO.into());
+            return Err(err.to_errno().into());
         }
 
         Ok(())
@@ -1049,6 +1090,8 @@ impl Filesystem for EncryptedFsFuse3 {
     #[allow(clippy::cast_possible_wrap)]
     async fn opendir(&self, req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
         trace!("");
+        let kernel_inode = inode;
+        let inode = self.sub_tree.to_fs(inode);
 
         let (access_mask, _read, _write) = match flags as i32 & libc::O_ACCMODE {
             libc::O_RDONLY => {
@@ -1056,32 +1099,37 @@ impl Filesystem for EncryptedFsFuse3 {
                 if flags & libc::O_TRUNC as u32 != 0 {
                     return Err(EACCES.into());
                 }
-                (libc::R_OK, true, false)
+                (ACCESS_READ, true, false)
             }
-            libc::O_WRONLY => (libc::W_OK, false, true),
-            libc::O_RDWR => (libc::R_OK | libc::W_OK, true, true),
+            libc::O_WRONLY => (ACCESS_WRITE, false, true),
+            libc::O_RDWR => (ACCESS_READ | ACCESS_WRITE, true, true),
             // Exactly one access mode flag must be specified
             _ => {
                 return Err(libc::EINVAL.into());
             }
         };
 
-        let attr = match self.get_fs().get_attr(inode).await {
-            Err(err) => {
-                error!(err = %err);
-                return Err(ENOENT.into());
+        let res = match self.control_of(kernel_inode) {
+            Some(control) => {
+                control
+                    .access(&self.get_fs(), kernel_inode, access_mask)
+                    .await
+            }
+            None => {
+                self.get_fs()
+                    .with_context(request_context(&req))
+                    .access(inode, access_mask)
+                    .await
             }
-            Ok(attr) => attr,
         };
-
-        if check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, access_mask) {
-            Ok(ReplyOpen {
-                fh: 0, // we don't use handles for directories
-                flags: 0,
-            })
-        } else {
-            return Err(EACCES.into());
+        if let Err(err) = res {
+            error!(err = %err);
+            return Err(err.to_errno().into());
         }
+        Ok(ReplyOpen {
+            fh: 0, // we don't use handles for directories
+            flags: 0,
+        })
     }
 
     type DirEntryStream<'a>
@@ -1098,16 +1146,31 @@ impl Filesystem for EncryptedFsFuse3 {
         offset: i64,
     ) -> Result<ReplyDirectory<Self::DirEntryStream<'_>>> {
         trace!("");
+        let extra = self.extra_entries(inode).await?;
+        if self.control_of(inode).is_some() {
+            let iter = DirectoryEntryIterator(None, 0, self.sub_tree, inode, extra);
+            #[allow(clippy::cast_possible_truncation)]
+            #[allow(clippy::cast_sign_loss)]
+            return Ok(ReplyDirectory {
+                entries: stream::iter(iter.skip(offset as usize)),
+            });
+        }
+        let inode = self.sub_tree.to_fs(inode);
 
         #[allow(clippy::cast_sign_loss)]
-        let iter = match self.get_fs().read_dir(inode).await {
+        let iter = match self
+            .get_fs()
+            .with_context(request_context(&req))
+            .read_dir_tolerant(inode)
+            .await
+        {
             Err(err) => {
                 error!(err = %err);
-                return Err(EIO.into());
+                return Err(err.to_errno().into());
             }
             Ok(iter) => iter,
         };
-        let iter = DirectoryEntryIterator(iter, 0);
+        let iter = DirectoryEntryIterator(Some(iter), 0, self.sub_tree, inode, extra);
 
         Ok(ReplyDirectory {
             #[allow(clippy::cast_possible_truncation)]
@@ -1126,18 +1189,19 @@ impl Filesystem for EncryptedFsFuse3 {
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn access(&self, req: Request, inode: u64, mask: u32) -> Result<()> {
         trace!("");
+        if let Some(control) = self.control_of(inode) {
+            return control
+                .access(&self.get_fs(), inode, mask)
+                .await
+                .map_err(|err| err.to_errno().into());
+        }
+        let inode = self.sub_tree.to_fs(inode);
 
-        self.get_fs().get_attr(inode).await.map_or_else(
-            |_| Err(ENOENT.into()),
-            |attr| {
-                #[allow(clippy::cast_possible_wrap)]
-                if check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, mask as i32) {
-                    Ok(())
-                } else {
-                    Err(EACCES.into())
-                }
-            },
-        )
+        self.get_fs()
+            .with_context(request_context(&req))
+            .access(inode, mask)
+            .await
+            .map_err(|err| err.to_errno().into())
     }
 
     #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
@@ -1150,6 +1214,8 @@ impl Filesystem for EncryptedFsFuse3 {
         flags: u32,
     ) -> Result<ReplyCreated> {
         trace!("");
+        self.check_not_reserved(parent, name)?;
+        let parent = self.sub_tree.to_fs(parent);
 
         #[allow(clippy::cast_possible_wrap)]
         let (read, write) = match flags as i32 & libc::O_ACCMODE {
@@ -1162,17 +1228,26 @@ impl Filesystem for EncryptedFsFuse3 {
             }
         };
 
+        let exclusive = flags & libc::O_EXCL as u32 != 0;
+        let truncate = flags & libc::O_TRUNC as u32 != 0;
+        // if it was created since the kernel looked it up it's opened, with the access checked for that
+        let flags = CreateFlags {
+            read,
+            write,
+            exclusive,
+            truncate,
+        };
         let (handle, attr) = self
-            .create_nod(parent, mode, &req, name, read, write)
+            .create_nod(parent, mode, 0, &req, name, flags)
             .await
             .map_err(|err| {
                 error!(err = %err);
-                Errno::from(ENOENT)
+                Errno::from(err)
             })?;
         Ok(ReplyCreated {
             ttl: TTL,
-            attr: attr.into(),
-            generation: 0,
+            attr: self.reply_attr(attr),
+            generation: attr.ino_generation,
             fh: handle,
             flags: 0,
         })
@@ -1193,16 +1268,30 @@ impl Filesystem for EncryptedFsFuse3 {
         lock_owner: u64,
     ) -> Result<ReplyDirectoryPlus<Self::DirEntryPlusStream<'_>>> {
         trace!("");
+        let extra = self.extra_entries(parent).await?;
+        if self.control_of(parent).is_some() {
+            let iter = DirectoryEntryPlusIterator(None, 0, self.sub_tree, parent, extra);
+            #[allow(clippy::cast_possible_truncation)]
+            return Ok(ReplyDirectoryPlus {
+                entries: stream::iter(iter.skip(offset as usize)),
+            });
+        }
+        let parent = self.sub_tree.to_fs(parent);
 
         #[allow(clippy::cast_sign_loss)]
-        let iter = match self.get_fs().read_dir_plus(parent).await {
+        let iter = match self
+            .get_fs()
+            .with_context(request_context(&req))
+            .read_dir_plus(parent)
+            .await
+        {
             Err(err) => {
                 error!(err = %err);
-                return Err(EIO.into());
+                return Err(err.to_errno().into());
             }
             Ok(iter) => iter,
         };
-        let iter = DirectoryEntryPlusIterator(iter, 0);
+        let iter = DirectoryEntryPlusIterator(Some(iter), 0, self.sub_tree, parent, extra);
 
         Ok(ReplyDirectoryPlus {
             #[allow(clippy::cast_possible_truncation)]
@@ -1210,6 +1299,60 @@ impl Filesystem for EncryptedFsFuse3 {
         })
     }
 
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    #[allow(clippy::cast_possible_wrap)]
+    async fn lseek(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        whence: u32,
+    ) -> Result<ReplyLSeek> {
+        trace!("");
+        let inode = self.sub_tree.to_fs(inode);
+
+        let whence = match whence as i32 {
+            libc::SEEK_DATA => SeekWhence::Data,
+            libc::SEEK_HOLE => SeekWhence::Hole,
+            _ => return Err(libc::EINVAL.into()),
+        };
+        match self.get_fs().lseek(inode, fh, offset, whence).await {
+            Ok(offset) => Ok(ReplyLSeek { offset }),
+            Err(err) => {
+                error!(err = %err);
+                Err(err.to_errno().into())
+            }
+        }
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn fallocate(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        length: u64,
+        mode: u32,
+    ) -> Result<()> {
+        trace!("");
+        let inode = self.sub_tree.to_fs(inode);
+
+        // only allocating, punching holes and the others are not supported
+        if mode != 0 {
+            return Err(libc::EOPNOTSUPP.into());
+        }
+        self.get_fs()
+            .with_context(request_context(&req))
+            .fallocate(inode, offset, length)
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                err.to_errno().into()
+            })
+    }
+
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn copy_file_range(
         &self,
@@ -1224,6 +1367,8 @@ impl Filesystem for EncryptedFsFuse3 {
         flags: u64,
     ) -> Result<ReplyCopyFileRange> {
         trace!("");
+        let inode = self.sub_tree.to_fs(inode);
+        let inode_out = self.sub_tree.to_fs(inode_out);
         let file_range_req = CopyFileRangeReq::builder()
             .src_ino(inode)
             .src_offset(off_in)
@@ -1235,46 +1380,42 @@ impl Filesystem for EncryptedFsFuse3 {
         #[allow(clippy::cast_possible_truncation)]
         match self
             .get_fs()
+            .with_context(request_context(&req))
             .copy_file_range(&file_range_req, length as usize)
             .await
         {
             Err(err) => {
                 error!(err = %err);
-                return Err(EIO.into());
+                return Err(err.to_errno().into());
             }
             Ok(len) => Ok(ReplyCopyFileRange { copied: len as u64 }),
         }
     }
 }
 
+/// The supplementary groups of the process `pid`, from its `status` in `/proc`. None if it's gone, or for the
+/// requests the kernel makes on its own with pid 0.
 fn get_groups(pid: u32) -> Vec<u32> {
-    #[cfg(not(target_os = "macos"))]
-    {
-        let path = format!("/proc/{pid}/task/{pid}/status");
-        let file = File::open(path).unwrap();
-        for line in BufReader::new(file).lines() {
-            let line = line.unwrap();
-            if line.starts_with("Groups:") {
-                return line["Groups: ".len()..]
-                    .split(' ')
-                    .filter(|x| !x.trim().is_empty())
-                    .map(|x| x.parse::<u32>().unwrap())
-                    .collect();
-            }
-        }
+    if pid == 0 {
+        return vec![];
     }
-
-    vec![]
+    std::fs::read_to_string(format!("/proc/{pid}/task/{pid}/status"))
+        .map(|status| parse_groups(&status))
+        .unwrap_or_default()
 }
 
-#[allow(clippy::cast_possible_truncation)]
-const fn clear_suid_sgid(mut perm: u16) -> u16 {
-    perm &= !libc::S_ISUID as u16;
-    // SGID is only suppose to be cleared if XGRP is set
-    if perm & libc::S_IXGRP as u16 != 0 {
-        perm &= !libc::S_ISGID as u16;
-    }
-    perm
+/// The `Groups:` line of a `/proc/<pid>/status`.
+fn parse_groups(status: &str) -> Vec<u32> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Groups:"))
+        .map(|groups| {
+            groups
+                .split_whitespace()
+                .filter_map(|gid| gid.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
 }
 
 fn as_file_kind(mut mode: u32) -> FileType {
@@ -1286,11 +1427,30 @@ fn as_file_kind(mut mode: u32) -> FileType {
         //     return FileType::Symlink;
     } else if mode == libc::S_IFDIR {
         FileType::Directory
+    } else if mode == libc::S_IFIFO {
+        FileType::NamedPipe
+    } else if mode == libc::S_IFCHR {
+        FileType::CharDevice
+    } else if mode == libc::S_IFBLK {
+        FileType::BlockDevice
+    } else if mode == libc::S_IFSOCK {
+        FileType::Socket
     } else {
         unimplemented!("{mode}");
     }
 }
 
+const fn to_fuse_kind(kind: FileType) -> fuse3::raw::prelude::FileType {
+    match kind {
+        FileType::Directory => fuse3::raw::prelude::FileType::Directory,
+        FileType::RegularFile => fuse3::raw::prelude::FileType::RegularFile,
+        FileType::NamedPipe => fuse3::raw::prelude::FileType::NamedPipe,
+        FileType::CharDevice => fuse3::raw::prelude::FileType::CharDevice,
+        FileType::BlockDevice => fuse3::raw::prelude::FileType::BlockDevice,
+        FileType::Socket => fuse3::raw::prelude::FileType::Socket,
+    }
+}
+
 const fn dir_attr() -> CreateFileAttr {
     CreateFileAttr {
         kind: FileType::Directory,
@@ -1299,6 +1459,7 @@ const fn dir_attr() -> CreateFileAttr {
         gid: 0,
         rdev: 0,
         flags: 0,
+        compress: None,
     }
 }
 
@@ -1310,42 +1471,43 @@ const fn file_attr() -> CreateFileAttr {
         gid: 0,
         rdev: 0,
         flags: 0,
+        compress: None,
+    }
+}
+
+/// The caller of a request with its supplementary groups, its permissions are checked by
+/// [`EncryptedFs::with_context`].
+fn request_context(req: &Request) -> RequestContext {
+    RequestContext::new(req.uid, req.gid, req.pid).with_groups(get_groups(req.pid))
+}
+
+/// The [`LockType`] of a `F_RDLCK` or `F_WRLCK`, `None` for `F_UNLCK`.
+#[allow(clippy::cast_possible_wrap)]
+fn lock_type(r#type: u32) -> Result<Option<LockType>> {
+    match r#type as i32 {
+        libc::F_RDLCK => Ok(Some(LockType::Read)),
+        libc::F_WRLCK => Ok(Some(LockType::Write)),
+        libc::F_UNLCK => Ok(None),
+        _ => Err(libc::EINVAL.into()),
     }
 }
 
-fn check_access(
-    #[allow(clippy::similar_names)] file_uid: u32,
-    #[allow(clippy::similar_names)] file_gid: u32,
-    file_mode: u16,
-    uid: u32,
-    gid: u32,
-    mut access_mask: i32,
-) -> bool {
-    // F_OK tests for existence of file
-    if access_mask == libc::F_OK {
-        return true;
-    }
-    let file_mode = i32::from(file_mode);
-
-    // root is allowed to read & write anything
-    if uid == 0 {
-        // root only allowed to exec if one of the X bits is set
-        access_mask &= libc::X_OK;
-        access_mask -= access_mask & (file_mode >> 6);
-        access_mask -= access_mask & (file_mode >> 3);
-        access_mask -= access_mask & file_mode;
-        return access_mask == 0;
-    }
-
-    if uid == file_uid {
-        access_mask -= access_mask & (file_mode >> 6);
-    } else if gid == file_gid {
-        access_mask -= access_mask & (file_mode >> 3);
+/// The range of a lock from the kernel, whose end is inclusive.
+const fn lock_range(start: u64, end: u64) -> std::ops::Range<u64> {
+    if end >= OFFSET_MAX {
+        start..u64::MAX
     } else {
-        access_mask -= access_mask & file_mode;
+        start..end + 1
     }
+}
 
-    access_mask == 0
+/// The end of a lock for the kernel, see [`lock_range`].
+const fn lock_end(end: u64) -> u64 {
+    if end == u64::MAX {
+        OFFSET_MAX
+    } else {
+        end - 1
+    }
 }
 
 #[allow(clippy::cast_sign_loss)]
@@ -1353,15 +1515,33 @@ fn system_time_from_timestamp(t: Timestamp) -> SystemTime {
     UNIX_EPOCH + Duration::new(t.sec as u64, t.nsec)
 }
 
+/// A time given to `setattr`, as [`TimeOrNow::Now`] if it's `UTIME_NOW`. fuse3 gives it as the time it got the
+/// request, so it can't be told apart from an explicit time that close to now, which sets the same times anyway.
+fn time_or_now(t: Timestamp) -> TimeOrNow {
+    let time = system_time_from_timestamp(t);
+    if SystemTime::now()
+        .duration_since(time)
+        .is_ok_and(|elapsed| elapsed < UTIME_NOW_TOLERANCE)
+    {
+        TimeOrNow::Now
+    } else {
+        TimeOrNow::SpecificTime(time)
+    }
+}
+
 #[allow(clippy::struct_excessive_bools)]
 pub struct MountPointImpl {
     mountpoint: PathBuf,
     data_dir: PathBuf,
-    password_provider: Option<Box<dyn PasswordProvider>>,
+    password_provider: Option<Box<dyn AsyncPasswordProvider>>,
     cipher: Cipher,
     allow_root: bool,
     allow_other: bool,
     read_only: bool,
+    options: Vec<MountOption>,
+    idle_timeout: Option<(Duration, IdleAction)>,
+    root: Option<PathBuf>,
+    control_dir: Option<bool>,
 }
 
 #[async_trait]
@@ -1369,7 +1549,7 @@ impl MountPoint for MountPointImpl {
     fn new(
         mountpoint: PathBuf,
         data_dir: PathBuf,
-        password_provider: Box<dyn PasswordProvider>,
+        password_provider: Box<dyn AsyncPasswordProvider>,
         cipher: Cipher,
         allow_root: bool,
         allow_other: bool,
@@ -1383,78 +1563,345 @@ impl MountPoint for MountPointImpl {
             allow_root,
             allow_other,
             read_only,
+            options: vec![],
+            idle_timeout: None,
+            root: None,
+            control_dir: None,
         }
     }
 
+    fn with_option(mut self, option: MountOption) -> Self {
+        self.options.push(option);
+        self
+    }
+
+    fn with_idle_timeout(mut self, timeout: Duration, action: IdleAction) -> Self {
+        self.idle_timeout = Some((timeout, action));
+        self
+    }
+
+    fn with_root(mut self, root: PathBuf) -> Self {
+        self.root = Some(root);
+        self
+    }
+
+    fn with_control_dir(mut self, hidden: bool) -> Self {
+        self.control_dir = Some(hidden);
+        self
+    }
+
     async fn mount(mut self) -> FsResult<mount::MountHandle> {
-        let handle = mount_fuse(
-            self.mountpoint.clone(),
+        let mount_options = fuse_mount_options(
+            self.allow_root,
+            self.allow_other,
+            self.read_only,
+            &self.options,
+        )?;
+        info!("Checking password and mounting FUSE filesystem");
+        let fs = EncryptedFsFuse3::new(
             self.data_dir.clone(),
             self.password_provider.take().unwrap(),
             self.cipher,
-            self.allow_root,
-            self.allow_other,
             self.read_only,
+            self.root.as_deref(),
+            self.control_dir,
+            self.options.contains(&MountOption::AllowDevices),
         )
         .await?;
-        Ok(mount::MountHandle {
-            inner: MountHandleInnerImpl { inner: handle },
-        })
+        if let Some((timeout, action)) = self.idle_timeout {
+            mount::watch_idle(
+                Arc::downgrade(&fs.fs),
+                fs.activity.clone(),
+                timeout,
+                action,
+                self.mountpoint.clone(),
+            );
+        }
+        let handle = mount_fuse(self.mountpoint.clone(), fs, mount_options).await?;
+        Ok(mount::MountHandle::new(
+            MountHandleInnerImpl {
+                inner: Some(handle),
+            },
+            self.mountpoint.clone(),
+        ))
     }
 }
 
 pub(in crate::mount) struct MountHandleInnerImpl {
-    inner: MountHandle,
+    inner: Option<MountHandle>,
 }
 
 impl Future for MountHandleInnerImpl {
     type Output = io::Result<()>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        self.inner.poll_unpin(cx)
+        match self.inner.as_mut() {
+            Some(inner) => inner.poll_unpin(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl Drop for MountHandleInnerImpl {
+    fn drop(&mut self) {
+        // fuse3 spawns a task to unmount when the handle is dropped, which panics outside a runtime
+        if let Some(inner) = self.inner.take() {
+            if tokio::runtime::Handle::try_current().is_err() {
+                std::mem::forget(inner);
+            }
+        }
     }
 }
 
 #[async_trait]
 impl MountHandleInner for MountHandleInnerImpl {
     async fn unmount(mut self) -> io::Result<()> {
-        self.inner.unmount().await
+        self.inner
+            .take()
+            .expect("unmount called twice")
+            .unmount()
+            .await
     }
 }
 
-#[instrument(skip(password_provider))]
-async fn mount_fuse(
-    mountpoint: PathBuf,
-    data_dir: PathBuf,
-    password_provider: Box<dyn PasswordProvider>,
-    cipher: Cipher,
+/// If `mountpoint` is in the mounts of this process.
+pub(in crate::mount) fn is_mounted(mountpoint: &Path) -> bool {
+    let Ok(mountpoint) = std::path::absolute(mountpoint) else {
+        return false;
+    };
+    let Ok(mountinfo) = std::fs::read_to_string("/proc/self/mountinfo") else {
+        return false;
+    };
+    mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .any(|path| Path::new(&unescape_mount_path(path)) == mountpoint)
+}
+
+/// Paths in `/proc/self/mountinfo` have space, tab, new line and backslash as octal escapes.
+fn unescape_mount_path(path: &str) -> String {
+    path.replace("\\040", " ")
+        .replace("\\011", "\t")
+        .replace("\\012", "\n")
+        .replace("\\134", "\\")
+}
+
+/// Unmount `mountpoint` without the FUSE session, with `lazy` it's detached even if files are still open.
+///
+/// It tries `fusermount3`, `fusermount` and then `umount2`, which needs root.
+pub(in crate::mount) fn detach(mountpoint: &Path, lazy: bool) -> io::Result<()> {
+    let flags = if lazy { "-uz" } else { "-u" };
+    for cmd in ["fusermount3", "fusermount"] {
+        if let Ok(output) = process::Command::new(cmd)
+            .arg(flags)
+            .arg(mountpoint)
+            .output()
+        {
+            if output.status.success() {
+                return Ok(());
+            }
+            warn!(
+                cmd,
+                stderr = %String::from_utf8_lossy(&output.stderr).trim(),
+                "cannot unmount"
+            );
+        }
+    }
+    let path = CString::new(mountpoint.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "mountpoint has a nul byte"))?;
+    let flags = if lazy { libc::MNT_DETACH } else { 0 };
+    if unsafe { libc::umount2(path.as_ptr(), flags) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// The options for the FUSE session, it checks they can be used together.
+fn fuse_mount_options(
     allow_root: bool,
     allow_other: bool,
     read_only: bool,
-) -> FsResult<MountHandle> {
-    // create mount point if it doesn't exist
-    if !mountpoint.exists() {
-        fs::create_dir_all(&mountpoint).await?;
+    options: &[MountOption],
+) -> FsResult<MountOptions> {
+    if allow_root && allow_other {
+        return Err(FsError::invalid_input(
+            "allow_root and allow_other can't be used together",
+        ));
     }
-    let mut mount_options = &mut MountOptions::default();
-    {
-        unsafe {
-            mount_options = mount_options.uid(libc::getuid()).gid(libc::getgid());
+    // only root can use them without `user_allow_other`
+    if (allow_root || allow_other) && unsafe { libc::getuid() } != 0 {
+        let fuse_conf = std::fs::read_to_string(FUSE_CONF).unwrap_or_default();
+        if !has_user_allow_other(&fuse_conf) {
+            return Err(FsError::invalid_input(
+                "allow_root and allow_other need user_allow_other in /etc/fuse.conf",
+            ));
         }
     }
-    let mount_options = mount_options
+    let mut mount_options = MountOptions::default();
+    unsafe {
+        mount_options.uid(libc::getuid()).gid(libc::getgid());
+    }
+    mount_options
         .read_only(read_only)
         .allow_root(allow_root)
         .allow_other(allow_other)
-        .fs_name("rencfs")
-        .clone();
+        .fs_name("rencfs");
+    let mut custom_options = vec![];
+    for option in options {
+        match option {
+            MountOption::DefaultPermissions => {
+                mount_options.default_permissions(true);
+            }
+            MountOption::FsName(name) => {
+                mount_options.fs_name(name);
+            }
+            MountOption::Subtype(subtype) => custom_options.push(format!("subtype={subtype}")),
+            MountOption::MaxRead(0) => {
+                return Err(FsError::invalid_input("max_read must be greater than 0"));
+            }
+            MountOption::MaxRead(max_read) => custom_options.push(format!("max_read={max_read}")),
+            MountOption::AllowDevices => custom_options.push("dev".to_string()),
+            MountOption::VolName(_) | MountOption::NoAppleDouble => {
+                return Err(FsError::invalid_input(
+                    "volname and noappledouble are only supported on macOS",
+                ));
+            }
+        }
+    }
+    if !custom_options.is_empty() {
+        mount_options.custom_options(custom_options.join(","));
+    }
+    Ok(mount_options)
+}
+
+/// If `user_allow_other` is set in the content of `/etc/fuse.conf`.
+fn has_user_allow_other(fuse_conf: &str) -> bool {
+    fuse_conf
+        .lines()
+        .any(|line| line.trim() == "user_allow_other")
+}
+
+#[instrument(skip(fs))]
+async fn mount_fuse(
+    mountpoint: PathBuf,
+    fs: EncryptedFsFuse3,
+    mount_options: MountOptions,
+) -> FsResult<MountHandle> {
+    // create mount point if it doesn't exist
+    if !mountpoint.exists() {
+        fs::create_dir_all(&mountpoint).await?;
+    }
     let mount_path = OsStr::new(mountpoint.to_str().unwrap());
 
-    info!("Checking password and mounting FUSE filesystem");
     Ok(Session::new(mount_options)
-        .mount_with_unprivileged(
-            EncryptedFsFuse3::new(data_dir, password_provider, cipher, read_only).await?,
-            mount_path,
-        )
+        .mount_with_unprivileged(fs, mount_path)
         .await?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_common::{create_attr, PasswordProviderImpl};
+
+    #[test]
+    fn test_fuse_mount_options() {
+        let mount_options = fuse_mount_options(
+            false,
+            false,
+            true,
+            &[
+                MountOption::DefaultPermissions,
+                MountOption::FsName("secret".to_string()),
+                MountOption::Subtype("rencfs".to_string()),
+                MountOption::MaxRead(65536),
+                MountOption::AllowDevices,
+            ],
+        )
+        .unwrap();
+        let mut expected = MountOptions::default();
+        unsafe {
+            expected.uid(libc::getuid()).gid(libc::getgid());
+        }
+        expected
+            .read_only(true)
+            .allow_root(false)
+            .allow_other(false)
+            .default_permissions(true)
+            .fs_name("secret")
+            .custom_options("subtype=rencfs,max_read=65536,dev");
+        assert_eq!(expected, mount_options);
+
+        assert!(matches!(
+            fuse_mount_options(true, true, false, &[]),
+            Err(FsError::InvalidInput { .. })
+        ));
+        assert!(matches!(
+            fuse_mount_options(false, false, false, &[MountOption::MaxRead(0)]),
+            Err(FsError::InvalidInput { .. })
+        ));
+        assert!(matches!(
+            fuse_mount_options(false, false, false, &[MountOption::NoAppleDouble]),
+            Err(FsError::InvalidInput { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_groups() {
+        let status = "Name:\tcat\nUid:\t1001\t1001\t1001\t1001\nGid:\t1001\t1001\t1001\t1001\n\
+                      FDSize:\t64\nGroups:\t27 2000 \nNStgid:\t42\n";
+        assert_eq!(vec![27, 2000], parse_groups(status));
+        assert!(parse_groups("Groups:\t\n").is_empty());
+        assert!(parse_groups("").is_empty());
+        assert!(get_groups(0).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_supplementary_group_access() {
+        let fs =
+            EncryptedFs::new_in_memory(Box::new(PasswordProviderImpl {}), Cipher::ChaCha20Poly1305)
+                .await
+                .unwrap();
+        let (fh, attr) = fs
+            .create(
+                ROOT_INODE,
+                &SecretString::from_str("file").unwrap(),
+                CreateFileAttr {
+                    perm: 0o640,
+                    uid: 1000,
+                    gid: 2000,
+                    ..create_attr(FileType::RegularFile)
+                },
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        fs.release(fh).await.unwrap();
+
+        // the group of the file is only a supplementary group of the caller
+        let status = "Uid:\t1001\t1001\t1001\t1001\nGid:\t1001\t1001\t1001\t1001\nGroups:\t2000\n";
+        let member = RequestContext::new(1001, 1001, 42).with_groups(parse_groups(status));
+        let fh = fs
+            .with_context(member)
+            .open(attr.ino, true, false)
+            .await
+            .unwrap();
+        fs.release(fh).await.unwrap();
+        assert!(matches!(
+            fs.with_context(RequestContext::new(1001, 1001, 42))
+                .open(attr.ino, true, false)
+                .await,
+            Err(FsError::PermissionDenied)
+        ));
+    }
+
+    #[test]
+    fn test_has_user_allow_other() {
+        assert!(has_user_allow_other(
+            "# mount_max = 1000\nuser_allow_other\n"
+        ));
+        assert!(!has_user_allow_other("#user_allow_other\n"));
+        assert!(!has_user_allow_other(""));
+    }
+}
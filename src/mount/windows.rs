@@ -0,0 +1,729 @@
+//! Mount with [WinFsp](https://winfsp.dev), enabled by the `winfsp` feature.
+//!
+//! WinFsp calls us from its own threads with a sync API, the [`EncryptedFs`] operations are run with
+//! [`Handle::block_on`] on the runtime that mounted the filesystem.
+
+use std::ffi::c_void;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use futures_util::FutureExt;
+use shush_rs::{ExposeSecret, SecretString};
+use tokio::runtime::Handle;
+use tokio::sync::oneshot;
+use tracing::{error, info, instrument};
+use winfsp::constants::FspCleanupFlags;
+use winfsp::filesystem::{
+    DirBuffer, DirInfo, DirMarker, FileInfo, FileSecurity, FileSystemContext, OpenFileInfo,
+    VolumeInfo, WideNameInfo,
+};
+use winfsp::host::{FileSystemHost, VolumeParams};
+use winfsp::{FspError, U16CStr};
+use winfsp_sys::{FILE_ACCESS_RIGHTS, FILE_FLAGS_AND_ATTRIBUTES};
+
+use crate::crypto::Cipher;
+use crate::encryptedfs::{
+    AsyncPasswordProvider, CreateFileAttr, EncryptedFs, FileAttr, FileType, FsError, FsOptions,
+    FsResult, SetFileAttr, ROOT_INODE,
+};
+use crate::mount;
+use crate::mount::{IdleAction, MountHandleInner, MountOption, MountPoint};
+
+const FILE_ATTRIBUTE_READONLY: u32 = 0x1;
+const FILE_ATTRIBUTE_DIRECTORY: u32 = 0x10;
+const FILE_ATTRIBUTE_NORMAL: u32 = 0x80;
+/// Passed in `create_options` when a directory is created.
+const FILE_DIRECTORY_FILE: u32 = 0x1;
+
+const STATUS_INVALID_HANDLE: i32 = 0xC000_0008_u32 as i32;
+const STATUS_INVALID_PARAMETER: i32 = 0xC000_000D_u32 as i32;
+const STATUS_ACCESS_DENIED: i32 = 0xC000_0022_u32 as i32;
+const STATUS_OBJECT_NAME_INVALID: i32 = 0xC000_0033_u32 as i32;
+const STATUS_OBJECT_NAME_NOT_FOUND: i32 = 0xC000_0034_u32 as i32;
+const STATUS_OBJECT_NAME_COLLISION: i32 = 0xC000_0035_u32 as i32;
+const STATUS_OBJECT_PATH_NOT_FOUND: i32 = 0xC000_003A_u32 as i32;
+const STATUS_DATA_ERROR: i32 = 0xC000_003E_u32 as i32;
+const STATUS_MEDIA_WRITE_PROTECTED: i32 = 0xC000_00A2_u32 as i32;
+const STATUS_UNEXPECTED_IO_ERROR: i32 = 0xC000_00E9_u32 as i32;
+const STATUS_DIRECTORY_NOT_EMPTY: i32 = 0xC000_0101_u32 as i32;
+const STATUS_DISK_FULL: i32 = 0xC000_007F_u32 as i32;
+const STATUS_FILE_TOO_LARGE: i32 = 0xC000_0904_u32 as i32;
+const STATUS_CANCELLED: i32 = 0xC000_0120_u32 as i32;
+const STATUS_LOCK_NOT_GRANTED: i32 = 0xC000_0055_u32 as i32;
+const STATUS_POSSIBLE_DEADLOCK: i32 = 0xC000_0194_u32 as i32;
+const STATUS_FILE_INVALID: i32 = 0xC000_0098_u32 as i32;
+
+/// Seconds between 1601-01-01, the start of Windows file times, and the Unix epoch.
+const FILETIME_UNIX_EPOCH_SECS: u64 = 11_644_473_600;
+
+/// What WinFsp keeps for each opened file or directory.
+struct FileContext {
+    ino: u64,
+    kind: FileType,
+    /// The handle from [`EncryptedFs::open`], 0 for directories.
+    fh: AtomicU64,
+    dir_buffer: DirBuffer,
+}
+
+struct EncryptedFsWinFsp {
+    fs: Arc<EncryptedFs>,
+    rt: Handle,
+    read_only: bool,
+    stopped: Mutex<Option<oneshot::Sender<()>>>,
+}
+
+impl EncryptedFsWinFsp {
+    fn block_on<F: Future>(&self, f: F) -> F::Output {
+        self.rt.block_on(f)
+    }
+
+    fn open_context(&self, attr: &FileAttr) -> winfsp::Result<FileContext> {
+        let fh = if attr.kind == FileType::RegularFile {
+            self.block_on(self.fs.open(attr.ino, true, !self.read_only))
+                .map_err(to_fsp_error)?
+        } else {
+            0
+        };
+        Ok(FileContext {
+            ino: attr.ino,
+            kind: attr.kind,
+            fh: AtomicU64::new(fh),
+            dir_buffer: DirBuffer::new(),
+        })
+    }
+
+    fn fill_file_info(&self, ino: u64, file_info: &mut FileInfo) -> winfsp::Result<()> {
+        let attr = self.block_on(self.fs.get_attr(ino)).map_err(to_fsp_error)?;
+        to_file_info(&attr, file_info);
+        Ok(())
+    }
+
+    /// Resolve the parent of `path` and the name of the last component.
+    fn resolve_parent(&self, path: &Path) -> winfsp::Result<(u64, SecretString)> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or(FspError::NTSTATUS(STATUS_INVALID_PARAMETER))?;
+        let parent = match path.parent() {
+            Some(parent) => self.block_on(self.fs.resolve(parent)),
+            None => self.block_on(self.fs.get_attr(ROOT_INODE)),
+        }
+        .map_err(to_fsp_error)?;
+        if parent.kind != FileType::Directory {
+            return Err(FspError::NTSTATUS(STATUS_OBJECT_PATH_NOT_FOUND));
+        }
+        Ok((parent.ino, SecretString::from_str(name).unwrap()))
+    }
+}
+
+impl FileSystemContext for EncryptedFsWinFsp {
+    type FileContext = FileContext;
+
+    #[instrument(skip_all)]
+    fn get_security_by_name(
+        &self,
+        file_name: &U16CStr,
+        _security_descriptor: Option<&mut [c_void]>,
+        _reparse_point_resolver: impl FnOnce(&U16CStr) -> Option<FileSecurity>,
+    ) -> winfsp::Result<FileSecurity> {
+        let attr = self
+            .block_on(self.fs.resolve(&to_path(file_name)))
+            .map_err(to_fsp_error)?;
+        // we don't keep security descriptors, access is checked only by the read-only mode
+        Ok(FileSecurity {
+            reparse: false,
+            sz_security_descriptor: 0,
+            attributes: to_file_attributes(&attr),
+        })
+    }
+
+    #[instrument(skip_all)]
+    fn open(
+        &self,
+        file_name: &U16CStr,
+        _create_options: u32,
+        _granted_access: FILE_ACCESS_RIGHTS,
+        file_info: &mut OpenFileInfo,
+    ) -> winfsp::Result<Self::FileContext> {
+        let attr = self
+            .block_on(self.fs.resolve(&to_path(file_name)))
+            .map_err(to_fsp_error)?;
+        let context = self.open_context(&attr)?;
+        self.fill_file_info(attr.ino, file_info.as_mut())?;
+        Ok(context)
+    }
+
+    #[instrument(skip_all)]
+    fn close(&self, context: Self::FileContext) {
+        let fh = context.fh.swap(0, Ordering::SeqCst);
+        if fh != 0 {
+            if let Err(err) = self.block_on(self.fs.release(fh)) {
+                error!(err = %err, "release");
+            }
+        }
+    }
+
+    #[instrument(skip_all)]
+    fn create(
+        &self,
+        file_name: &U16CStr,
+        create_options: u32,
+        _granted_access: FILE_ACCESS_RIGHTS,
+        _file_attributes: FILE_FLAGS_AND_ATTRIBUTES,
+        _security_descriptor: Option<&[c_void]>,
+        _allocation_size: u64,
+        _extra_buffer: Option<&[u8]>,
+        _extra_buffer_is_reparse_point: bool,
+        file_info: &mut OpenFileInfo,
+    ) -> winfsp::Result<Self::FileContext> {
+        let (parent, name) = self.resolve_parent(&to_path(file_name))?;
+        let kind = if create_options & FILE_DIRECTORY_FILE != 0 {
+            FileType::Directory
+        } else {
+            FileType::RegularFile
+        };
+        let create_attr = CreateFileAttr {
+            kind,
+            perm: if kind == FileType::Directory {
+                0o755
+            } else {
+                0o644
+            },
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            flags: 0,
+            compress: None,
+        };
+        let is_file = kind == FileType::RegularFile;
+        let (fh, attr) = self
+            .block_on(self.fs.create(parent, &name, create_attr, is_file, is_file))
+            .map_err(to_fsp_error)?;
+        to_file_info(&attr, file_info.as_mut());
+        Ok(FileContext {
+            ino: attr.ino,
+            kind,
+            fh: AtomicU64::new(fh),
+            dir_buffer: DirBuffer::new(),
+        })
+    }
+
+    #[instrument(skip_all)]
+    fn cleanup(&self, context: &Self::FileContext, file_name: Option<&U16CStr>, flags: u32) {
+        if !FspCleanupFlags::FspCleanupDelete.is_flagged(flags) {
+            return;
+        }
+        let Some(file_name) = file_name else {
+            return;
+        };
+        // the file is removed now, close will find no handle
+        let fh = context.fh.swap(0, Ordering::SeqCst);
+        let res = self.block_on(async {
+            if fh != 0 {
+                self.fs.release(fh).await?;
+            }
+            self.fs.remove_by_path(&to_path(file_name)).await
+        });
+        if let Err(err) = res {
+            error!(err = %err, "remove on cleanup");
+        }
+    }
+
+    #[instrument(skip_all)]
+    fn flush(
+        &self,
+        context: Option<&Self::FileContext>,
+        file_info: &mut FileInfo,
+    ) -> winfsp::Result<()> {
+        // flush of the whole volume
+        let Some(context) = context else {
+            return Ok(());
+        };
+        let fh = context.fh.load(Ordering::SeqCst);
+        if fh != 0 && !self.read_only {
+            self.block_on(self.fs.flush(fh)).map_err(to_fsp_error)?;
+        }
+        self.fill_file_info(context.ino, file_info)
+    }
+
+    #[instrument(skip_all)]
+    fn get_file_info(
+        &self,
+        context: &Self::FileContext,
+        file_info: &mut FileInfo,
+    ) -> winfsp::Result<()> {
+        self.fill_file_info(context.ino, file_info)
+    }
+
+    #[instrument(skip_all)]
+    fn overwrite(
+        &self,
+        context: &Self::FileContext,
+        _file_attributes: FILE_FLAGS_AND_ATTRIBUTES,
+        _replace_file_attributes: bool,
+        _allocation_size: u64,
+        _extra_buffer: Option<&[u8]>,
+        file_info: &mut FileInfo,
+    ) -> winfsp::Result<()> {
+        self.block_on(self.fs.set_len(context.ino, 0))
+            .map_err(to_fsp_error)?;
+        self.fill_file_info(context.ino, file_info)
+    }
+
+    #[instrument(skip_all)]
+    fn read_directory(
+        &self,
+        context: &Self::FileContext,
+        _pattern: Option<&U16CStr>,
+        marker: DirMarker,
+        buffer: &mut [u8],
+    ) -> winfsp::Result<u32> {
+        if context.kind != FileType::Directory {
+            return Err(FspError::NTSTATUS(STATUS_INVALID_PARAMETER));
+        }
+        // fill the buffer when the listing starts, the next calls continue from the marker
+        if marker.is_none() {
+            let lock = context.dir_buffer.acquire(true, None)?;
+            let entries = self
+                .block_on(self.fs.read_dir_plus(context.ino))
+                .map_err(to_fsp_error)?;
+            let mut dir_info: DirInfo = DirInfo::new();
+            for entry in entries {
+                let entry = entry.map_err(to_fsp_error)?;
+                dir_info.reset();
+                to_file_info(&entry.attr, dir_info.file_info_mut());
+                dir_info.set_name(&*entry.name.expose_secret())?;
+                lock.write(&mut dir_info)?;
+            }
+        }
+        Ok(context.dir_buffer.read(marker, buffer))
+    }
+
+    #[instrument(skip_all)]
+    fn rename(
+        &self,
+        _context: &Self::FileContext,
+        file_name: &U16CStr,
+        new_file_name: &U16CStr,
+        replace_if_exists: bool,
+    ) -> winfsp::Result<()> {
+        let (parent, name) = self.resolve_parent(&to_path(file_name))?;
+        let (new_parent, new_name) = self.resolve_parent(&to_path(new_file_name))?;
+        self.block_on(async {
+            if !replace_if_exists && self.fs.exists_by_name(new_parent, &new_name).await? {
+                return Err(FsError::AlreadyExists);
+            }
+            self.fs.rename(parent, &name, new_parent, &new_name).await
+        })
+        .map_err(to_fsp_error)
+    }
+
+    #[instrument(skip_all)]
+    fn set_basic_info(
+        &self,
+        context: &Self::FileContext,
+        file_attributes: u32,
+        creation_time: u64,
+        last_access_time: u64,
+        last_write_time: u64,
+        last_change_time: u64,
+        file_info: &mut FileInfo,
+    ) -> winfsp::Result<()> {
+        let attr = self
+            .block_on(self.fs.get_attr(context.ino))
+            .map_err(to_fsp_error)?;
+        let mut set_attr = SetFileAttr::default();
+        // u32::MAX means the attributes are not changed
+        if file_attributes != u32::MAX {
+            let perm = if file_attributes & FILE_ATTRIBUTE_READONLY != 0 {
+                attr.perm & !0o222
+            } else {
+                attr.perm | 0o200
+            };
+            set_attr = set_attr.with_perm(perm);
+        }
+        // 0 means the time is not changed
+        if creation_time != 0 {
+            set_attr = set_attr.with_crtime(from_filetime(creation_time));
+        }
+        if last_access_time != 0 {
+            set_attr = set_attr.with_atime(from_filetime(last_access_time));
+        }
+        if last_write_time != 0 {
+            set_attr = set_attr.with_mtime(from_filetime(last_write_time));
+        }
+        if last_change_time != 0 {
+            set_attr = set_attr.with_ctime(from_filetime(last_change_time));
+        }
+        self.block_on(self.fs.set_attr(context.ino, set_attr))
+            .map_err(to_fsp_error)?;
+        self.fill_file_info(context.ino, file_info)
+    }
+
+    #[instrument(skip_all)]
+    fn set_delete(
+        &self,
+        context: &Self::FileContext,
+        _file_name: &U16CStr,
+        delete_file: bool,
+    ) -> winfsp::Result<()> {
+        if self.read_only {
+            return Err(FspError::NTSTATUS(STATUS_MEDIA_WRITE_PROTECTED));
+        }
+        // it's removed in cleanup, here we only check it can be
+        if delete_file && context.kind == FileType::Directory {
+            let mut entries = self
+                .block_on(self.fs.read_dir(context.ino))
+                .map_err(to_fsp_error)?;
+            let not_empty = entries.any(|entry| {
+                entry.is_ok_and(|entry| {
+                    let name = entry.name.expose_secret();
+                    *name != "." && *name != ".."
+                })
+            });
+            if not_empty {
+                return Err(FspError::NTSTATUS(STATUS_DIRECTORY_NOT_EMPTY));
+            }
+        }
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn set_file_size(
+        &self,
+        context: &Self::FileContext,
+        new_size: u64,
+        set_allocation_size: bool,
+        file_info: &mut FileInfo,
+    ) -> winfsp::Result<()> {
+        let size = self
+            .block_on(self.fs.get_attr(context.ino))
+            .map_err(to_fsp_error)?
+            .size;
+        // we don't preallocate, a smaller allocation size truncates the file
+        if !set_allocation_size || new_size < size {
+            self.block_on(self.fs.set_len(context.ino, new_size))
+                .map_err(to_fsp_error)?;
+        }
+        self.fill_file_info(context.ino, file_info)
+    }
+
+    #[instrument(skip_all)]
+    fn read(
+        &self,
+        context: &Self::FileContext,
+        buffer: &mut [u8],
+        offset: u64,
+    ) -> winfsp::Result<u32> {
+        let fh = context.fh.load(Ordering::SeqCst);
+        let len = self
+            .block_on(self.fs.read(context.ino, offset, buffer, fh))
+            .map_err(to_fsp_error)?;
+        Ok(u32::try_from(len).unwrap_or(u32::MAX))
+    }
+
+    #[instrument(skip_all)]
+    fn write(
+        &self,
+        context: &Self::FileContext,
+        buffer: &[u8],
+        offset: u64,
+        write_to_eof: bool,
+        constrained_io: bool,
+        file_info: &mut FileInfo,
+    ) -> winfsp::Result<u32> {
+        let fh = context.fh.load(Ordering::SeqCst);
+        let len = self
+            .block_on(async {
+                let size = self.fs.get_attr(context.ino).await?.size;
+                let offset = if write_to_eof { size } else { offset };
+                let mut buffer = buffer;
+                // paging IO can't extend the file
+                if constrained_io {
+                    if offset >= size {
+                        return Ok(0);
+                    }
+                    let max = usize::try_from(size - offset).unwrap_or(usize::MAX);
+                    buffer = &buffer[..buffer.len().min(max)];
+                }
+                self.fs.write(context.ino, offset, buffer, fh).await
+            })
+            .map_err(to_fsp_error)?;
+        self.fill_file_info(context.ino, file_info)?;
+        Ok(u32::try_from(len).unwrap_or(u32::MAX))
+    }
+
+    #[instrument(skip_all)]
+    fn get_volume_info(&self, out_volume_info: &mut VolumeInfo) -> winfsp::Result<()> {
+        let stats = self.block_on(self.fs.statfs()).map_err(to_fsp_error)?;
+        out_volume_info.total_size = stats.total_bytes;
+        out_volume_info.free_size = stats.available_bytes;
+        out_volume_info.set_volume_label("rencfs");
+        Ok(())
+    }
+
+    fn dispatcher_stopped(&self, _normally: bool) {
+        if let Some(stopped) = self.stopped.lock().unwrap().take() {
+            let _ = stopped.send(());
+        }
+    }
+}
+
+fn to_path(file_name: &U16CStr) -> PathBuf {
+    PathBuf::from(file_name.to_os_string())
+}
+
+fn to_file_attributes(attr: &FileAttr) -> u32 {
+    let mut attributes = if attr.kind == FileType::Directory {
+        FILE_ATTRIBUTE_DIRECTORY
+    } else {
+        0
+    };
+    if attr.perm & 0o222 == 0 {
+        attributes |= FILE_ATTRIBUTE_READONLY;
+    }
+    if attributes == 0 {
+        FILE_ATTRIBUTE_NORMAL
+    } else {
+        attributes
+    }
+}
+
+fn to_file_info(attr: &FileAttr, file_info: &mut FileInfo) {
+    file_info.file_attributes = to_file_attributes(attr);
+    file_info.reparse_tag = 0;
+    file_info.file_size = attr.size;
+    file_info.allocation_size = attr.blocks * 512;
+    file_info.creation_time = to_filetime(attr.crtime);
+    file_info.last_access_time = to_filetime(attr.atime);
+    file_info.last_write_time = to_filetime(attr.mtime);
+    file_info.change_time = to_filetime(attr.ctime);
+    file_info.index_number = attr.ino;
+    file_info.hard_links = 0;
+    file_info.ea_size = 0;
+}
+
+/// 100ns intervals since 1601-01-01.
+fn to_filetime(time: SystemTime) -> u64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let nanos = (since_epoch + Duration::from_secs(FILETIME_UNIX_EPOCH_SECS)).as_nanos() / 100;
+    u64::try_from(nanos).unwrap_or(u64::MAX)
+}
+
+fn from_filetime(filetime: u64) -> SystemTime {
+    let since_1601 = Duration::from_nanos(filetime.saturating_mul(100));
+    UNIX_EPOCH + since_1601.saturating_sub(Duration::from_secs(FILETIME_UNIX_EPOCH_SECS))
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn to_fsp_error(err: FsError) -> FspError {
+    let status = match err {
+        FsError::NotFound { .. } | FsError::InodeNotFound => STATUS_OBJECT_NAME_NOT_FOUND,
+        FsError::AlreadyExists => STATUS_OBJECT_NAME_COLLISION,
+        FsError::NotEmpty => STATUS_DIRECTORY_NOT_EMPTY,
+        FsError::InvalidInodeType | FsError::InvalidInput { .. } => STATUS_INVALID_PARAMETER,
+        FsError::InvalidFileHandle => STATUS_INVALID_HANDLE,
+        FsError::ReadOnly => STATUS_MEDIA_WRITE_PROTECTED,
+        FsError::QuotaExceeded => STATUS_DISK_FULL,
+        FsError::MaxFilesizeExceeded => STATUS_FILE_TOO_LARGE,
+        FsError::Cancelled => STATUS_CANCELLED,
+        FsError::LockConflict => STATUS_LOCK_NOT_GRANTED,
+        FsError::Deadlock => STATUS_POSSIBLE_DEADLOCK,
+        FsError::StaleFileId => STATUS_FILE_INVALID,
+        FsError::PermissionDenied | FsError::NotPermitted => STATUS_ACCESS_DENIED,
+        FsError::NameTooLong => STATUS_OBJECT_NAME_INVALID,
+        FsError::IntegrityViolation | FsError::Corrupted { .. } => STATUS_DATA_ERROR,
+        FsError::Io { source, .. } => return source.into(),
+        err => {
+            error!(err = %err);
+            STATUS_UNEXPECTED_IO_ERROR
+        }
+    };
+    FspError::NTSTATUS(status)
+}
+
+#[allow(clippy::struct_excessive_bools)]
+#[allow(dead_code)]
+pub struct MountPointImpl {
+    mountpoint: PathBuf,
+    data_dir: PathBuf,
+    password_provider: Option<Box<dyn AsyncPasswordProvider>>,
+    cipher: Cipher,
+    allow_root: bool,
+    allow_other: bool,
+    read_only: bool,
+    // FUSE options, WinFsp doesn't use them
+    options: Vec<MountOption>,
+    idle_timeout: Option<(Duration, IdleAction)>,
+    root: Option<PathBuf>,
+    control_dir: Option<bool>,
+}
+
+#[async_trait]
+impl MountPoint for MountPointImpl {
+    fn new(
+        mountpoint: PathBuf,
+        data_dir: PathBuf,
+        password_provider: Box<dyn AsyncPasswordProvider>,
+        cipher: Cipher,
+        allow_root: bool,
+        allow_other: bool,
+        read_only: bool,
+    ) -> Self {
+        Self {
+            mountpoint,
+            data_dir,
+            password_provider: Some(password_provider),
+            cipher,
+            allow_root,
+            allow_other,
+            read_only,
+            options: vec![],
+            idle_timeout: None,
+            root: None,
+            control_dir: None,
+        }
+    }
+
+    fn with_option(mut self, option: MountOption) -> Self {
+        self.options.push(option);
+        self
+    }
+
+    fn with_idle_timeout(mut self, timeout: Duration, action: IdleAction) -> Self {
+        self.idle_timeout = Some((timeout, action));
+        self
+    }
+
+    fn with_root(mut self, root: PathBuf) -> Self {
+        self.root = Some(root);
+        self
+    }
+
+    fn with_control_dir(mut self, hidden: bool) -> Self {
+        self.control_dir = Some(hidden);
+        self
+    }
+
+    async fn mount(mut self) -> FsResult<mount::MountHandle> {
+        if self.idle_timeout.is_some() {
+            return Err(FsError::invalid_input(
+                "idle timeout is not supported with WinFsp yet",
+            ));
+        }
+        if self.root.is_some() {
+            return Err(FsError::invalid_input(
+                "mounting a sub-tree is not supported with WinFsp yet",
+            ));
+        }
+        if self.control_dir.is_some() {
+            return Err(FsError::invalid_input(
+                "the control dir is not supported with WinFsp yet",
+            ));
+        }
+        let handle = mount_winfsp(
+            self.mountpoint.clone(),
+            self.data_dir.clone(),
+            self.password_provider.take().unwrap(),
+            self.cipher,
+            self.read_only,
+        )
+        .await?;
+        Ok(mount::MountHandle::new(handle, self.mountpoint.clone()))
+    }
+}
+
+pub(in crate::mount) struct MountHandleInnerImpl {
+    host: FileSystemHost<EncryptedFsWinFsp>,
+    fs: Arc<EncryptedFs>,
+    stopped: oneshot::Receiver<()>,
+}
+
+impl Future for MountHandleInnerImpl {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // completes when the dispatcher stops
+        self.stopped.poll_unpin(cx).map(|_| Ok(()))
+    }
+}
+
+#[async_trait]
+impl MountHandleInner for MountHandleInnerImpl {
+    async fn unmount(mut self) -> io::Result<()> {
+        self.host.unmount();
+        self.host.stop();
+        self.fs.close_all().await.map_err(io::Error::other)
+    }
+}
+
+/// A drive letter or a directory is there only while mounted.
+pub(in crate::mount) fn is_mounted(mountpoint: &Path) -> bool {
+    mountpoint.exists()
+}
+
+/// Dropping the [`FileSystemHost`] stops and unmounts it, even with opened files.
+pub(in crate::mount) const fn detach(_mountpoint: &Path, _lazy: bool) -> io::Result<()> {
+    Ok(())
+}
+
+#[instrument(skip(password_provider))]
+async fn mount_winfsp(
+    mountpoint: PathBuf,
+    data_dir: PathBuf,
+    password_provider: Box<dyn AsyncPasswordProvider>,
+    cipher: Cipher,
+    read_only: bool,
+) -> FsResult<MountHandleInnerImpl> {
+    winfsp::winfsp_init().map_err(io::Error::other)?;
+    // a drive letter like `X:` or a directory, which WinFsp creates
+    if mountpoint.is_dir() {
+        if mountpoint.read_dir()?.next().is_some() {
+            return Err(FsError::invalid_input("mountpoint directory is not empty"));
+        }
+        std::fs::remove_dir(&mountpoint)?;
+    }
+
+    info!("Checking password and mounting WinFsp filesystem");
+    let fs = EncryptedFs::new(
+        data_dir,
+        password_provider,
+        cipher,
+        read_only,
+        FsOptions::default(),
+    )
+    .await?;
+
+    let mut volume_params = VolumeParams::new();
+    volume_params
+        .filesystem_name("rencfs")
+        .sector_size(512)
+        .sectors_per_allocation_unit(1)
+        .max_component_length(255)
+        .case_sensitive_search(true)
+        .case_preserved_names(true)
+        .unicode_on_disk(true)
+        .post_cleanup_when_modified_only(true)
+        .read_only_volume(read_only)
+        .file_info_timeout(1000);
+    let (stopped_tx, stopped) = oneshot::channel();
+    let context = EncryptedFsWinFsp {
+        fs: fs.clone(),
+        rt: Handle::current(),
+        read_only,
+        stopped: Mutex::new(Some(stopped_tx)),
+    };
+    let mut host = FileSystemHost::new(volume_params, context).map_err(io::Error::other)?;
+    host.mount(mountpoint).map_err(io::Error::other)?;
+    host.start().map_err(io::Error::other)?;
+
+    Ok(MountHandleInnerImpl { host, fs, stopped })
+}
@@ -0,0 +1,276 @@
+//! Ready to use [`PasswordProvider`]s, taking the password from an env variable, a file, the system keyring, the
+//! terminal or a fixed value.
+//!
+//! The buffers holding the password while it's read are zeroized, the password itself is kept in a [`SecretString`].
+
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use shush_rs::zeroize::Zeroize;
+use shush_rs::{ExposeSecret, SecretString};
+use tracing::error;
+
+use crate::encryptedfs::{FsResult, PasswordProvider};
+
+/// Takes the password from the env variable with this name.
+pub struct EnvPasswordProvider(pub String);
+
+impl PasswordProvider for EnvPasswordProvider {
+    fn get_password(&self) -> Option<SecretString> {
+        let mut value = std::env::var(&self.0).ok()?;
+        let password = SecretString::from_str(&value).unwrap();
+        value.zeroize();
+        Some(password)
+    }
+}
+
+/// Takes the password from the file at this path, without the trailing new line.
+///
+/// On Unix the file must not be readable by others.
+pub struct FilePasswordProvider(pub PathBuf);
+
+impl PasswordProvider for FilePasswordProvider {
+    fn get_password(&self) -> Option<SecretString> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = fs::metadata(&self.0)
+                .inspect_err(|err| error!(err = %err, "reading password file"))
+                .ok()?
+                .permissions()
+                .mode();
+            if mode & 0o004 != 0 {
+                error!(path = %self.0.display(), "password file is readable by others");
+                return None;
+            }
+        }
+        let mut content = fs::read_to_string(&self.0)
+            .inspect_err(|err| error!(err = %err, "reading password file"))
+            .ok()?;
+        let password = SecretString::from_str(content.trim_end_matches(['\n', '\r'])).unwrap();
+        content.zeroize();
+        Some(password)
+    }
+}
+
+/// Where [`KeyringPasswordProvider`] keeps the passwords, the system keyring with [`SystemKeyring`].
+pub trait KeyringBackend: Send + Sync + 'static {
+    /// `None` if there is no password for `service` and `user`.
+    #[allow(clippy::missing_errors_doc)]
+    fn get(&self, service: &str, user: &str) -> FsResult<Option<SecretString>>;
+    #[allow(clippy::missing_errors_doc)]
+    fn set(&self, service: &str, user: &str, password: &SecretString) -> FsResult<()>;
+    #[allow(clippy::missing_errors_doc)]
+    fn delete(&self, service: &str, user: &str) -> FsResult<()>;
+}
+
+/// The keyring of the system, see the `keyring` crate.
+pub struct SystemKeyring;
+
+impl KeyringBackend for SystemKeyring {
+    fn get(&self, service: &str, user: &str) -> FsResult<Option<SecretString>> {
+        match keyring::Entry::new(service, user)?.get_password() {
+            Ok(mut value) => {
+                let password = SecretString::from_str(&value).unwrap();
+                value.zeroize();
+                Ok(Some(password))
+            }
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn set(&self, service: &str, user: &str, password: &SecretString) -> FsResult<()> {
+        Ok(keyring::Entry::new(service, user)?.set_password(&password.expose_secret())?)
+    }
+
+    fn delete(&self, service: &str, user: &str) -> FsResult<()> {
+        match keyring::Entry::new(service, user)?.delete_password() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Takes the password from the keyring, for `service` and `user`.
+pub struct KeyringPasswordProvider<B: KeyringBackend = SystemKeyring> {
+    service: String,
+    user: String,
+    backend: B,
+}
+
+impl KeyringPasswordProvider {
+    /// With the system keyring.
+    #[must_use]
+    pub fn new(service: &str, user: &str) -> Self {
+        Self::with_backend(service, user, SystemKeyring)
+    }
+}
+
+impl<B: KeyringBackend> KeyringPasswordProvider<B> {
+    #[must_use]
+    pub fn with_backend(service: &str, user: &str, backend: B) -> Self {
+        Self {
+            service: service.to_string(),
+            user: user.to_string(),
+            backend,
+        }
+    }
+
+    /// Save `password` in the keyring, replacing the previous one.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn save(&self, password: &SecretString) -> FsResult<()> {
+        self.backend.set(&self.service, &self.user, password)
+    }
+
+    /// Delete the password from the keyring, if there is one.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn delete(&self) -> FsResult<()> {
+        self.backend.delete(&self.service, &self.user)
+    }
+}
+
+impl<B: KeyringBackend> PasswordProvider for KeyringPasswordProvider<B> {
+    fn get_password(&self) -> Option<SecretString> {
+        self.backend
+            .get(&self.service, &self.user)
+            .inspect_err(|err| error!(err = %err, "getting password from keyring"))
+            .ok()?
+    }
+}
+
+/// Asks for the password in the terminal, with this prompt.
+pub struct InteractivePasswordProvider(pub String);
+
+impl PasswordProvider for InteractivePasswordProvider {
+    fn get_password(&self) -> Option<SecretString> {
+        let mut value = rpassword::prompt_password(&self.0)
+            .inspect_err(|err| error!(err = %err, "reading password"))
+            .ok()?;
+        let password = SecretString::from_str(&value).unwrap();
+        value.zeroize();
+        Some(password)
+    }
+}
+
+/// Always provides the same password, useful for tests.
+pub struct StaticPasswordProvider(pub SecretString);
+
+impl PasswordProvider for StaticPasswordProvider {
+    fn get_password(&self) -> Option<SecretString> {
+        Some(self.0.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_env_password_provider() {
+        std::env::set_var("RENCFS_TEST_ENV_PASSWORD", "password");
+        let provider = EnvPasswordProvider("RENCFS_TEST_ENV_PASSWORD".to_string());
+        assert_eq!(
+            "password",
+            provider.get_password().unwrap().expose_secret().as_str()
+        );
+        let provider = EnvPasswordProvider("RENCFS_TEST_ENV_PASSWORD_MISSING".to_string());
+        assert!(provider.get_password().is_none());
+    }
+
+    #[test]
+    fn test_file_password_provider() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("password");
+        fs::write(&path, "password\r\n").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        }
+        let provider = FilePasswordProvider(path.clone());
+        assert_eq!(
+            "password",
+            provider.get_password().unwrap().expose_secret().as_str()
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+            assert!(provider.get_password().is_none());
+        }
+
+        let provider = FilePasswordProvider(dir.path().join("missing"));
+        assert!(provider.get_password().is_none());
+    }
+
+    #[derive(Default)]
+    struct MockKeyring {
+        entries: Mutex<HashMap<(String, String), String>>,
+    }
+
+    impl KeyringBackend for MockKeyring {
+        fn get(&self, service: &str, user: &str) -> FsResult<Option<SecretString>> {
+            Ok(self
+                .entries
+                .lock()
+                .unwrap()
+                .get(&(service.to_string(), user.to_string()))
+                .map(|password| SecretString::from_str(password).unwrap()))
+        }
+
+        fn set(&self, service: &str, user: &str, password: &SecretString) -> FsResult<()> {
+            self.entries.lock().unwrap().insert(
+                (service.to_string(), user.to_string()),
+                password.expose_secret().clone(),
+            );
+            Ok(())
+        }
+
+        fn delete(&self, service: &str, user: &str) -> FsResult<()> {
+            self.entries
+                .lock()
+                .unwrap()
+                .remove(&(service.to_string(), user.to_string()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_keyring_password_provider() {
+        let provider =
+            KeyringPasswordProvider::with_backend("rencfs", "test", MockKeyring::default());
+        assert!(provider.get_password().is_none());
+
+        provider
+            .save(&SecretString::from_str("password").unwrap())
+            .unwrap();
+        assert_eq!(
+            "password",
+            provider.get_password().unwrap().expose_secret().as_str()
+        );
+
+        provider.delete().unwrap();
+        assert!(provider.get_password().is_none());
+        // nothing to delete
+        provider.delete().unwrap();
+    }
+
+    #[test]
+    fn test_static_password_provider() {
+        let provider = StaticPasswordProvider(SecretString::from_str("password").unwrap());
+        assert_eq!(
+            "password",
+            provider.get_password().unwrap().expose_secret().as_str()
+        );
+        assert_eq!(
+            "password",
+            provider.get_password().unwrap().expose_secret().as_str()
+        );
+    }
+}
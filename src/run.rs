@@ -249,6 +249,9 @@ async fn run_change_password(cipher: Cipher, matches: &ArgMatches) -> Result<()>
                 FsError::InvalidDataDirStructure => {
                     println!("Invalid structure of data directory");
                 }
+                FsError::UnsupportedVersion(version) => {
+                    println!("Data directory version {version} is not supported, it was created by a newer version");
+                }
                 _ => {
                     error!(err = %err);
                 }
@@ -0,0 +1,496 @@
+//! A file stored as several segment files in a [`Storage`], seen as a single file.
+//!
+//! The first segment is at `path`, the next ones at `path.1`, `path.2`, ... Each segment except the last one holds
+//! `segment_size` bytes, when it's shorter the rest of it reads as zeros, so the holes left by writing past the end
+//! take no space. This keeps each segment under a size limit while the whole file can grow up to `u64::MAX`.
+
+use std::ffi::OsString;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::async_util::block_on;
+use crate::storage::{Storage, StorageFile};
+
+/// Only the segment we are positioned in is kept open, so large files don't use many file descriptors.
+///
+/// The segments are in a [`Storage`], the ones opened or created as we move around are from sync code, see
+/// [`block_on`].
+pub struct SegmentedFile {
+    storage: Arc<dyn Storage>,
+    path: PathBuf,
+    segment_size: u64,
+    write: bool,
+    /// Segments in the storage the last time we looked, at least the first one.
+    count: usize,
+    current: Option<(usize, Box<dyn StorageFile>)>,
+    pos: u64,
+}
+
+impl SegmentedFile {
+    /// Open the segments of `path` for reading, the first segment must exist.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn open(
+        storage: &Arc<dyn Storage>,
+        path: &Path,
+        segment_size: u64,
+    ) -> io::Result<Self> {
+        Self::open_with(storage, path, segment_size, false).await
+    }
+
+    /// Open the segments of `path` for reading and writing, the first segment must exist.
+    ///
+    /// New segments are created as the file grows.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn open_rw(
+        storage: &Arc<dyn Storage>,
+        path: &Path,
+        segment_size: u64,
+    ) -> io::Result<Self> {
+        Self::open_with(storage, path, segment_size, true).await
+    }
+
+    /// Create an empty file at `path` for reading and writing, removing any existing segments.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn create(
+        storage: &Arc<dyn Storage>,
+        path: &Path,
+        segment_size: u64,
+    ) -> io::Result<Self> {
+        storage.create(path).await?;
+        let mut file = Self::open_rw(storage, path, segment_size).await?;
+        file.set_len(0)?;
+        Ok(file)
+    }
+
+    async fn open_with(
+        storage: &Arc<dyn Storage>,
+        path: &Path,
+        segment_size: u64,
+        write: bool,
+    ) -> io::Result<Self> {
+        assert!(segment_size > 0, "segment size must be positive");
+        let first = storage.open(path, write).await?;
+        let mut file = Self {
+            storage: storage.clone(),
+            path: path.to_path_buf(),
+            segment_size,
+            write,
+            count: 1,
+            current: Some((0, first)),
+            pos: 0,
+        };
+        file.count = Self::segments_count(storage, path).await?.max(1);
+        Ok(file)
+    }
+
+    /// Path of the segment with `index`, the first one is `path` itself.
+    #[must_use]
+    pub fn segment_path(path: &Path, index: usize) -> PathBuf {
+        if index == 0 {
+            return path.to_path_buf();
+        }
+        let mut segment: OsString = path.as_os_str().to_owned();
+        segment.push(format!(".{index}"));
+        PathBuf::from(segment)
+    }
+
+    /// Number of segments in the storage for `path`.
+    pub(crate) async fn segments_count(
+        storage: &Arc<dyn Storage>,
+        path: &Path,
+    ) -> io::Result<usize> {
+        let mut count = 0;
+        while storage
+            .kind(&Self::segment_path(path, count))
+            .await?
+            .is_some()
+        {
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Remove all segments of `path`.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn remove(storage: &Arc<dyn Storage>, path: &Path) -> io::Result<()> {
+        // from the last one, so the segments stay contiguous if we fail in between
+        for index in (1..Self::segments_count(storage, path).await?).rev() {
+            storage.remove(&Self::segment_path(path, index)).await?;
+        }
+        storage.remove(path).await
+    }
+
+    /// Move all segments of `from` over `to`, the extra segments of `to` are removed.
+    ///
+    /// Each segment is renamed atomically, but not the file as a whole.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn rename(storage: &Arc<dyn Storage>, from: &Path, to: &Path) -> io::Result<()> {
+        let count = Self::segments_count(storage, from).await?;
+        for index in (count.max(1)..Self::segments_count(storage, to).await?).rev() {
+            storage.remove(&Self::segment_path(to, index)).await?;
+        }
+        for index in 0..count {
+            storage
+                .rename(
+                    &Self::segment_path(from, index),
+                    &Self::segment_path(to, index),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    fn segment_exists(&self, index: usize) -> io::Result<bool> {
+        Ok(block_on(self.storage.kind(&Self::segment_path(&self.path, index)))?.is_some())
+    }
+
+    /// Update the count of segments with the ones added or removed since we last looked, by us or by someone else.
+    fn refresh_count(&mut self) -> io::Result<()> {
+        while self.count > 1 && !self.segment_exists(self.count - 1)? {
+            self.count -= 1;
+        }
+        while self.segment_exists(self.count)? {
+            self.count += 1;
+        }
+        Ok(())
+    }
+
+    /// Get the segment with `index`, if `create` it creates it and the missing ones before it, which are left empty.
+    fn segment(
+        &mut self,
+        index: usize,
+        create: bool,
+    ) -> io::Result<Option<&mut Box<dyn StorageFile>>> {
+        if !matches!(self.current, Some((current, _)) if current == index) {
+            if index >= self.count {
+                self.refresh_count()?;
+            }
+            if index >= self.count {
+                if !create {
+                    return Ok(None);
+                }
+                self.check_write()?;
+                self.current = None;
+                // the last segment and the new ones before `index` read as zeros after what they have
+                while self.count <= index {
+                    block_on(
+                        self.storage
+                            .create_new(&Self::segment_path(&self.path, self.count)),
+                    )?;
+                    self.count += 1;
+                }
+            }
+            let file = block_on(
+                self.storage
+                    .open(&Self::segment_path(&self.path, index), self.write),
+            )?;
+            self.current = Some((index, file));
+        }
+        Ok(self.current.as_mut().map(|(_, file)| file))
+    }
+
+    /// If the segment with `index` is followed by others, so past its end it reads as zeros.
+    fn is_followed(&mut self, index: usize) -> io::Result<bool> {
+        if index + 1 >= self.count {
+            self.refresh_count()?;
+        }
+        Ok(index + 1 < self.count)
+    }
+
+    fn check_write(&self) -> io::Result<()> {
+        if self.write {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file not opened for write",
+            ))
+        }
+    }
+
+    /// Total length of all segments.
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&mut self) -> io::Result<u64> {
+        self.refresh_count()?;
+        let last = self.count - 1;
+        let last_len = match &self.current {
+            Some((current, file)) if *current == last => file.len()?,
+            _ => block_on(
+                self.storage
+                    .open(&Self::segment_path(&self.path, last), false),
+            )?
+            .len()?,
+        };
+        Ok(last as u64 * self.segment_size + last_len)
+    }
+
+    /// Bytes the segments take in the storage, see [`StorageFile::allocated`]. The holes are not counted.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn allocated(&mut self) -> io::Result<u64> {
+        self.refresh_count()?;
+        let mut allocated = 0;
+        for index in 0..self.count {
+            allocated += match &self.current {
+                Some((current, file)) if *current == index => file.allocated()?,
+                _ => block_on(
+                    self.storage
+                        .open(&Self::segment_path(&self.path, index), false),
+                )?
+                .allocated()?,
+            };
+        }
+        Ok(allocated)
+    }
+
+    /// Truncate or extend the file with zeros, removing or creating segments as needed.
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn set_len(&mut self, size: u64) -> io::Result<()> {
+        self.check_write()?;
+        self.refresh_count()?;
+        let last = if size == 0 {
+            0
+        } else {
+            ((size - 1) / self.segment_size) as usize
+        };
+        if matches!(self.current, Some((current, _)) if current > last) {
+            self.current = None;
+        }
+        while self.count > last + 1 {
+            self.count -= 1;
+            block_on(
+                self.storage
+                    .remove(&Self::segment_path(&self.path, self.count)),
+            )?;
+        }
+        let segment_size = self.segment_size;
+        self.segment(last, true)?
+            .expect("segment is missing")
+            .set_len(size - last as u64 * segment_size)
+    }
+
+    /// Sync all segments and the directory containing them.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn sync_all(&mut self) -> io::Result<()> {
+        self.sync(|file| file.sync_all())
+    }
+
+    /// Like [`SegmentedFile::sync_all`] but only the data is synced, see [`StorageFile::sync_data`].
+    ///
+    /// The directory is synced too, it's needed to find the segments created since.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn sync_data(&mut self) -> io::Result<()> {
+        self.sync(|file| file.sync_data())
+    }
+
+    fn sync(&mut self, sync: fn(&dyn StorageFile) -> io::Result<()>) -> io::Result<()> {
+        self.refresh_count()?;
+        for index in 0..self.count {
+            match &self.current {
+                Some((current, file)) if *current == index => sync(file.as_ref())?,
+                _ => sync(
+                    block_on(
+                        self.storage
+                            .open(&Self::segment_path(&self.path, index), false),
+                    )?
+                    .as_ref(),
+                )?,
+            }
+        }
+        if let Some(parent) = self.path.parent() {
+            block_on(self.storage.sync(parent))?;
+        }
+        Ok(())
+    }
+
+    /// Segment index, the offset in it and the bytes remaining in it for the current position.
+    #[allow(clippy::cast_possible_truncation)]
+    const fn position(&self) -> (usize, u64, usize) {
+        let offset = self.pos % self.segment_size;
+        let remaining = self.segment_size - offset;
+        (
+            (self.pos / self.segment_size) as usize,
+            offset,
+            if remaining > usize::MAX as u64 {
+                usize::MAX
+            } else {
+                remaining as usize
+            },
+        )
+    }
+}
+
+impl Read for SegmentedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let (index, offset, remaining) = self.position();
+        let Some(segment) = self.segment(index, false)? else {
+            return Ok(0);
+        };
+        segment.seek(SeekFrom::Start(offset))?;
+        let max = buf.len().min(remaining);
+        let mut len = segment.read(&mut buf[..max])?;
+        if len == 0 && self.is_followed(index)? {
+            // a hole
+            buf[..max].fill(0);
+            len = max;
+        }
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for SegmentedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_write()?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let (index, offset, remaining) = self.position();
+        let segment = self.segment(index, true)?.expect("segment is missing");
+        segment.seek(SeekFrom::Start(offset))?;
+        let len = segment.write(&buf[..buf.len().min(remaining)])?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some((_, file)) = self.current.as_mut() {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Seek for SegmentedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.len()?.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = new_pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{InMemoryStorage, LocalStorage};
+
+    async fn segment_len(storage: &Arc<dyn Storage>, path: &Path, index: usize) -> u64 {
+        storage
+            .open(&SegmentedFile::segment_path(path, index), false)
+            .await
+            .unwrap()
+            .len()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_segmented_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(dir.path()));
+        let path = Path::new("file");
+        let mut file = SegmentedFile::create(&storage, path, 10).await.unwrap();
+
+        // write across segments
+        let data: Vec<u8> = (0..25).collect();
+        file.write_all(&data).unwrap();
+        assert_eq!(file.len().unwrap(), 25);
+        assert_eq!(
+            SegmentedFile::segments_count(&storage, path).await.unwrap(),
+            3
+        );
+        assert_eq!(segment_len(&storage, path, 0).await, 10);
+        assert_eq!(segment_len(&storage, path, 2).await, 5);
+
+        // read from another instance
+        let mut reader = SegmentedFile::open(&storage, path, 10).await.unwrap();
+        reader.seek(SeekFrom::Start(8)).unwrap();
+        let mut buf = vec![];
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, data[8..]);
+        assert!(reader.write(&[1]).is_err());
+
+        // write after the end leaves a hole which reads as zeros
+        file.seek(SeekFrom::Start(42)).unwrap();
+        file.write_all(&[42]).unwrap();
+        assert_eq!(file.len().unwrap(), 43);
+        assert_eq!(segment_len(&storage, path, 2).await, 5);
+        assert_eq!(segment_len(&storage, path, 3).await, 0);
+        let mut buf = vec![];
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf[..25], data);
+        assert!(buf[25..42].iter().all(|b| *b == 0));
+        assert_eq!(buf[42], 42);
+
+        // truncate into an earlier segment
+        file.set_len(12).unwrap();
+        assert_eq!(file.len().unwrap(), 12);
+        assert_eq!(
+            SegmentedFile::segments_count(&storage, path).await.unwrap(),
+            2
+        );
+        // extend
+        file.set_len(30).unwrap();
+        assert_eq!(file.len().unwrap(), 30);
+        file.set_len(0).unwrap();
+        assert_eq!(
+            SegmentedFile::segments_count(&storage, path).await.unwrap(),
+            1
+        );
+        assert_eq!(file.len().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_rename_and_remove() {
+        let storage: Arc<dyn Storage> = Arc::new(InMemoryStorage::new());
+        let from = Path::new("from");
+        let to = Path::new("to");
+        SegmentedFile::create(&storage, from, 10)
+            .await
+            .unwrap()
+            .write_all(&[1; 15])
+            .unwrap();
+        SegmentedFile::create(&storage, to, 10)
+            .await
+            .unwrap()
+            .write_all(&[2; 35])
+            .unwrap();
+
+        SegmentedFile::rename(&storage, from, to).await.unwrap();
+        assert_eq!(
+            SegmentedFile::segments_count(&storage, from).await.unwrap(),
+            0
+        );
+        assert_eq!(
+            SegmentedFile::segments_count(&storage, to).await.unwrap(),
+            2
+        );
+        let mut buf = vec![];
+        SegmentedFile::open(&storage, to, 10)
+            .await
+            .unwrap()
+            .read_to_end(&mut buf)
+            .unwrap();
+        assert_eq!(buf, [1; 15]);
+
+        SegmentedFile::remove(&storage, to).await.unwrap();
+        assert_eq!(
+            SegmentedFile::segments_count(&storage, to).await.unwrap(),
+            0
+        );
+    }
+}
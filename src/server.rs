@@ -0,0 +1,10 @@
+//! Frontends serving an [`EncryptedFs`] without mounting it, for where FUSE is not available.
+//!
+//! [`EncryptedFs`]: crate::encryptedfs::EncryptedFs
+
+#[cfg(feature = "nfs")]
+pub mod nfs;
+#[cfg(feature = "sftp")]
+pub mod sftp;
+#[cfg(feature = "webdav")]
+pub mod webdav;
@@ -0,0 +1,505 @@
+//! NFSv3 server over an [`EncryptedFs`], with the `nfs` feature, see [`serve`].
+//!
+//! [`EncryptedNfs`] implements the [`NFSFileSystem`] of `nfsserve`, which does the RPC, the mount protocol and the
+//! weak cache consistency data, taken from [`NFSFileSystem::getattr`] before and after the changes. NFS has no
+//! open and close, so each read and write opens the file for its own.
+//!
+//! The file handles have the inode and its [`FileAttr::ino_generation`], a handle of a removed file whose number
+//! was given to another one is stale. The directories are listed sorted by name, so the cookies, the inode of the
+//! last entry sent, find the same place in the next request.
+//!
+//! There is no authentication, every client which reaches it can read and change everything, so only export it to
+//! trusted networks.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use nfsserve::nfs::{
+    fattr3, fileid3, filename3, ftype3, nfs_fh3, nfspath3, nfsstat3, nfstime3, sattr3, set_atime,
+    set_gid3, set_mode3, set_mtime, set_size3, set_uid3, specdata3,
+};
+use nfsserve::tcp::{NFSTcp, NFSTcpListener};
+use nfsserve::vfs::{DirEntry, NFSFileSystem, ReadDirResult, VFSCapabilities};
+use shush_rs::{ExposeSecret, SecretString};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::encryptedfs::{
+    CreateFileAttr, EncryptedFs, FileAttr, FileType, FsError, FsResult, SetFileAttr, ROOT_INODE,
+};
+use crate::{GID, UID};
+
+/// Length of the file handles, the generation of the server, the inode and its generation.
+const FH_LEN: usize = 24;
+/// `du` counts in these.
+const STAT_BLOCK_SIZE: u64 = 512;
+
+/// A running [`serve`], it stops when [`NfsServer::shutdown`] is called or it's dropped.
+pub struct NfsServer {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl NfsServer {
+    /// The address it listens on, with the port picked if it was `0`.
+    pub const fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stop serving, the connections are closed.
+    pub async fn shutdown(mut self) {
+        self.task.abort();
+        let _ = (&mut self.task).await;
+    }
+}
+
+impl Drop for NfsServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Export `fs` over NFSv3 on `addr`, it's mounted with the path `/`, like
+/// `mount -t nfs -o nolock,vers=3,tcp,port=PORT,mountport=PORT HOST:/ MOUNTPOINT`.
+#[allow(clippy::missing_errors_doc)]
+pub async fn serve(addr: SocketAddr, fs: Arc<EncryptedFs>) -> FsResult<NfsServer> {
+    let listener = NFSTcpListener::bind(&addr.to_string(), EncryptedNfs::new(fs)).await?;
+    let addr = SocketAddr::new(addr.ip(), listener.get_listen_port());
+    info!(%addr, "serving over nfs");
+    let task = tokio::spawn(async move {
+        if let Err(err) = listener.handle_forever().await {
+            warn!(err = %err, "nfs server");
+        }
+    });
+    Ok(NfsServer { addr, task })
+}
+
+/// [`EncryptedFs`] as an [`NFSFileSystem`].
+pub struct EncryptedNfs {
+    fs: Arc<EncryptedFs>,
+    /// Changes each time the server starts, the handles given before are stale.
+    server_generation: u64,
+    /// The [`FileAttr::ino_generation`] of the inodes the clients got handles for.
+    generations: Mutex<HashMap<u64, u64>>,
+}
+
+impl EncryptedNfs {
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn new(fs: Arc<EncryptedFs>) -> Self {
+        let server_generation = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos() as u64);
+        Self {
+            fs,
+            server_generation,
+            generations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Keep the generation of `attr`, for its handles.
+    fn seen(&self, attr: &FileAttr) -> fattr3 {
+        self.generations
+            .lock()
+            .unwrap()
+            .insert(attr.ino, attr.ino_generation);
+        to_fattr(attr)
+    }
+
+    fn forget(&self, ino: u64) {
+        self.generations.lock().unwrap().remove(&ino);
+    }
+
+    async fn get_attr(&self, ino: u64) -> Result<fattr3, nfsstat3> {
+        let attr = self.fs.get_attr(ino).await.map_err(nfs_error)?;
+        Ok(self.seen(&attr))
+    }
+
+    async fn find(&self, dir: u64, name: &SecretString) -> Result<FileAttr, nfsstat3> {
+        self.fs
+            .find_by_name(dir, name)
+            .await
+            .map_err(nfs_error)?
+            .ok_or(nfsstat3::NFS3ERR_NOENT)
+    }
+
+    async fn set_attrs(&self, ino: u64, setattr: &sattr3) -> FsResult<()> {
+        if let set_size3::size(size) = setattr.size {
+            self.fs.set_len(ino, size).await?;
+        }
+        let mut set_attr = SetFileAttr::default();
+        #[allow(clippy::cast_possible_truncation)]
+        if let set_mode3::mode(mode) = setattr.mode {
+            set_attr = set_attr.with_perm((mode & 0o7777) as u16);
+        }
+        if let set_uid3::uid(uid) = setattr.uid {
+            set_attr = set_attr.with_uid(uid);
+        }
+        if let set_gid3::gid(gid) = setattr.gid {
+            set_attr = set_attr.with_gid(gid);
+        }
+        match setattr.atime {
+            set_atime::SET_TO_SERVER_TIME => set_attr = set_attr.with_atime(SystemTime::now()),
+            set_atime::SET_TO_CLIENT_TIME(time) => {
+                set_attr = set_attr.with_atime(from_nfstime(time))
+            }
+            set_atime::DONT_CHANGE => {}
+        }
+        match setattr.mtime {
+            set_mtime::SET_TO_SERVER_TIME => set_attr = set_attr.with_mtime(SystemTime::now()),
+            set_mtime::SET_TO_CLIENT_TIME(time) => {
+                set_attr = set_attr.with_mtime(from_nfstime(time))
+            }
+            set_mtime::DONT_CHANGE => {}
+        }
+        self.fs.set_attr(ino, set_attr).await
+    }
+
+    async fn create_node(
+        &self,
+        dir: u64,
+        name: &filename3,
+        kind: FileType,
+        setattr: Option<&sattr3>,
+    ) -> Result<FileAttr, nfsstat3> {
+        let name = to_name(name)?;
+        let mut create_attr = create_attr(kind);
+        if let Some(set_mode3::mode(mode)) = setattr.map(|setattr| &setattr.mode) {
+            #[allow(clippy::cast_possible_truncation)]
+            {
+                create_attr.perm = (*mode & 0o7777) as u16;
+            }
+        }
+        let (_, attr) = self
+            .fs
+            .create(dir, &name, create_attr, false, false)
+            .await
+            .map_err(nfs_error)?;
+        if let Some(setattr) = setattr {
+            self.set_attrs(attr.ino, setattr).await.map_err(nfs_error)?;
+            return self.fs.get_attr(attr.ino).await.map_err(nfs_error);
+        }
+        Ok(attr)
+    }
+
+    /// The entries of `dir` without `.` and `..`, sorted by name, so they are in the same order each time.
+    async fn sorted_entries(&self, dir: u64) -> FsResult<Vec<(String, FileAttr)>> {
+        let mut entries = vec![];
+        for entry in self.fs.read_dir_plus(dir).await? {
+            let entry = entry?;
+            let name = entry.name.expose_secret().to_string();
+            if name != "." && name != ".." {
+                entries.push((name, entry.attr));
+            }
+        }
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(entries)
+    }
+}
+
+#[async_trait]
+impl NFSFileSystem for EncryptedNfs {
+    fn capabilities(&self) -> VFSCapabilities {
+        // the changes of a read-only fs fail with `NFS3ERR_ROFS`
+        VFSCapabilities::ReadWrite
+    }
+
+    fn root_dir(&self) -> fileid3 {
+        ROOT_INODE
+    }
+
+    async fn lookup(&self, dirid: fileid3, filename: &filename3) -> Result<fileid3, nfsstat3> {
+        let name = to_name(filename)?;
+        if *name.expose_secret() == "." {
+            return Ok(dirid);
+        }
+        let attr = self.find(dirid, &name).await?;
+        self.seen(&attr);
+        Ok(attr.ino)
+    }
+
+    async fn getattr(&self, id: fileid3) -> Result<fattr3, nfsstat3> {
+        self.get_attr(id).await
+    }
+
+    async fn setattr(&self, id: fileid3, setattr: sattr3) -> Result<fattr3, nfsstat3> {
+        self.set_attrs(id, &setattr).await.map_err(nfs_error)?;
+        self.get_attr(id).await
+    }
+
+    async fn read(
+        &self,
+        id: fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfsstat3> {
+        let fh = self.fs.open(id, true, false).await.map_err(nfs_error)?;
+        let mut buf = vec![0; count as usize];
+        let mut read = 0;
+        let res = loop {
+            if read == buf.len() {
+                break Ok(());
+            }
+            match self
+                .fs
+                .read(id, offset + read as u64, &mut buf[read..], fh)
+                .await
+            {
+                Ok(0) => break Ok(()),
+                Ok(len) => read += len,
+                Err(err) => break Err(err),
+            }
+        };
+        self.fs.release(fh).await.map_err(nfs_error)?;
+        res.map_err(nfs_error)?;
+        buf.truncate(read);
+        let size = self.fs.get_attr(id).await.map_err(nfs_error)?.size;
+        Ok((buf, offset + read as u64 >= size))
+    }
+
+    async fn write(&self, id: fileid3, offset: u64, data: &[u8]) -> Result<fattr3, nfsstat3> {
+        let fh = self.fs.open(id, false, true).await.map_err(nfs_error)?;
+        let res = self.fs.write_all(id, offset, data, fh).await;
+        self.fs.release(fh).await.map_err(nfs_error)?;
+        res.map_err(nfs_error)?;
+        self.get_attr(id).await
+    }
+
+    async fn create(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+        attr: sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        let attr = self
+            .create_node(dirid, filename, FileType::RegularFile, Some(&attr))
+            .await?;
+        Ok((attr.ino, self.seen(&attr)))
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+    ) -> Result<fileid3, nfsstat3> {
+        let attr = self
+            .create_node(dirid, filename, FileType::RegularFile, None)
+            .await?;
+        self.seen(&attr);
+        Ok(attr.ino)
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: fileid3,
+        dirname: &filename3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        let attr = self
+            .create_node(dirid, dirname, FileType::Directory, None)
+            .await?;
+        Ok((attr.ino, self.seen(&attr)))
+    }
+
+    async fn remove(&self, dirid: fileid3, filename: &filename3) -> Result<(), nfsstat3> {
+        let name = to_name(filename)?;
+        let attr = self.find(dirid, &name).await?;
+        let res = if attr.kind == FileType::Directory {
+            self.fs.remove_dir(dirid, &name).await
+        } else {
+            self.fs.remove_file(dirid, &name).await
+        };
+        res.map_err(nfs_error)?;
+        if attr.nlink <= 1 {
+            self.forget(attr.ino);
+        }
+        Ok(())
+    }
+
+    async fn rename(
+        &self,
+        from_dirid: fileid3,
+        from_filename: &filename3,
+        to_dirid: fileid3,
+        to_filename: &filename3,
+    ) -> Result<(), nfsstat3> {
+        let from = to_name(from_filename)?;
+        let to = to_name(to_filename)?;
+        let replaced = self
+            .fs
+            .find_by_name(to_dirid, &to)
+            .await
+            .map_err(nfs_error)?;
+        self.fs
+            .rename(from_dirid, &from, to_dirid, &to)
+            .await
+            .map_err(nfs_error)?;
+        if let Some(replaced) = replaced.filter(|attr| attr.nlink <= 1) {
+            self.forget(replaced.ino);
+        }
+        Ok(())
+    }
+
+    async fn readdir(
+        &self,
+        dirid: fileid3,
+        start_after: fileid3,
+        max_entries: usize,
+    ) -> Result<ReadDirResult, nfsstat3> {
+        let entries = self.sorted_entries(dirid).await.map_err(nfs_error)?;
+        let total = entries.len();
+        let start = if start_after == 0 {
+            0
+        } else {
+            entries
+                .iter()
+                .position(|(_, attr)| attr.ino == start_after)
+                .ok_or(nfsstat3::NFS3ERR_BAD_COOKIE)?
+                + 1
+        };
+        let end = total.min(start.saturating_add(max_entries));
+        let entries = entries[start..end]
+            .iter()
+            .map(|(name, attr)| DirEntry {
+                fileid: attr.ino,
+                name: name.as_bytes().to_vec().into(),
+                attr: self.seen(attr),
+            })
+            .collect();
+        Ok(ReadDirResult {
+            entries,
+            end: end == total,
+        })
+    }
+
+    async fn symlink(
+        &self,
+        _dirid: fileid3,
+        _linkname: &filename3,
+        _symlink: &nfspath3,
+        _attr: &sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        Err(nfsstat3::NFS3ERR_NOTSUPP)
+    }
+
+    async fn readlink(&self, _id: fileid3) -> Result<nfspath3, nfsstat3> {
+        Err(nfsstat3::NFS3ERR_NOTSUPP)
+    }
+
+    fn id_to_fh(&self, id: fileid3) -> nfs_fh3 {
+        let generation = self
+            .generations
+            .lock()
+            .unwrap()
+            .get(&id)
+            .copied()
+            .unwrap_or_default();
+        let mut data = Vec::with_capacity(FH_LEN);
+        data.extend_from_slice(&self.server_generation.to_le_bytes());
+        data.extend_from_slice(&id.to_le_bytes());
+        data.extend_from_slice(&generation.to_le_bytes());
+        nfs_fh3 { data }
+    }
+
+    fn fh_to_id(&self, fh: &nfs_fh3) -> Result<fileid3, nfsstat3> {
+        if fh.data.len() != FH_LEN {
+            return Err(nfsstat3::NFS3ERR_BADHANDLE);
+        }
+        let part = |i: usize| u64::from_le_bytes(fh.data[i * 8..(i + 1) * 8].try_into().unwrap());
+        let (server_generation, ino, generation) = (part(0), part(1), part(2));
+        if server_generation != self.server_generation {
+            return Err(nfsstat3::NFS3ERR_STALE);
+        }
+        // another file has the number now
+        match self.generations.lock().unwrap().get(&ino) {
+            Some(current) if *current != generation => Err(nfsstat3::NFS3ERR_STALE),
+            _ => Ok(ino),
+        }
+    }
+}
+
+fn create_attr(kind: FileType) -> CreateFileAttr {
+    CreateFileAttr {
+        kind,
+        perm: if matches!(kind, FileType::Directory) {
+            0o755
+        } else {
+            0o644
+        },
+        uid: *UID,
+        gid: *GID,
+        rdev: 0,
+        flags: 0,
+        compress: None,
+    }
+}
+
+fn to_name(name: &filename3) -> Result<SecretString, nfsstat3> {
+    let name = std::str::from_utf8(name).map_err(|_| nfsstat3::NFS3ERR_INVAL)?;
+    SecretString::from_str(name).map_err(|_| nfsstat3::NFS3ERR_INVAL)
+}
+
+fn to_fattr(attr: &FileAttr) -> fattr3 {
+    let ftype = match attr.kind {
+        FileType::Directory => ftype3::NF3DIR,
+        FileType::RegularFile => ftype3::NF3REG,
+        FileType::NamedPipe => ftype3::NF3FIFO,
+        FileType::CharDevice => ftype3::NF3CHR,
+        FileType::BlockDevice => ftype3::NF3BLK,
+        FileType::Socket => ftype3::NF3SOCK,
+    };
+    fattr3 {
+        ftype,
+        mode: u32::from(attr.perm),
+        nlink: attr.nlink,
+        uid: attr.uid,
+        gid: attr.gid,
+        size: attr.size,
+        used: attr.blocks * STAT_BLOCK_SIZE,
+        // the major and minor numbers, as Linux encodes them in 32 bits
+        rdev: specdata3 {
+            specdata1: (attr.rdev >> 8) & 0xfff,
+            specdata2: (attr.rdev & 0xff) | ((attr.rdev >> 12) & 0xf_ff00),
+        },
+        fsid: 0,
+        fileid: attr.ino,
+        atime: to_nfstime(attr.atime),
+        mtime: to_nfstime(attr.mtime),
+        ctime: to_nfstime(attr.ctime),
+    }
+}
+
+fn to_nfstime(time: SystemTime) -> nfstime3 {
+    let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    nfstime3 {
+        seconds: u32::try_from(time.as_secs()).unwrap_or(u32::MAX),
+        nseconds: time.subsec_nanos(),
+    }
+}
+
+fn from_nfstime(time: nfstime3) -> SystemTime {
+    UNIX_EPOCH + Duration::new(u64::from(time.seconds), time.nseconds)
+}
+
+/// The status the client gets for `err`.
+fn nfs_error(err: FsError) -> nfsstat3 {
+    match err {
+        FsError::NotFound { .. } | FsError::InodeNotFound => nfsstat3::NFS3ERR_NOENT,
+        FsError::AlreadyExists => nfsstat3::NFS3ERR_EXIST,
+        FsError::NotEmpty => nfsstat3::NFS3ERR_NOTEMPTY,
+        FsError::InvalidInodeType | FsError::InvalidInput { .. } => nfsstat3::NFS3ERR_INVAL,
+        FsError::ReadOnly => nfsstat3::NFS3ERR_ROFS,
+        FsError::PermissionDenied | FsError::Locked => nfsstat3::NFS3ERR_ACCES,
+        FsError::NotPermitted => nfsstat3::NFS3ERR_PERM,
+        FsError::NameTooLong => nfsstat3::NFS3ERR_NAMETOOLONG,
+        FsError::QuotaExceeded => nfsstat3::NFS3ERR_DQUOT,
+        FsError::MaxFilesizeExceeded => nfsstat3::NFS3ERR_FBIG,
+        FsError::StaleFileId => nfsstat3::NFS3ERR_STALE,
+        err => {
+            warn!(err = %err, "nfs request failed");
+            nfsstat3::NFS3ERR_IO
+        }
+    }
+}
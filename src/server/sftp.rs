@@ -0,0 +1,636 @@
+//! SFTP server over an [`EncryptedFs`], with the `sftp` feature, see [`serve`].
+//!
+//! It runs the `sftp` subsystem of SSH on an fs which is already unlocked, the users are checked by the callback of
+//! [`SftpConfig`]. All of them see the whole fs, the paths are resolved from its root like
+//! [`EncryptedFs::resolve`], and the files they create are owned by the user running the server.
+
+use std::collections::{HashMap, VecDeque};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use russh::server::{Auth, Msg, Session};
+use russh::{Channel, ChannelId};
+use russh_keys::key::{KeyPair, PublicKey};
+use russh_sftp::protocol::{
+    Attrs, Data, File, FileAttributes, Handle, Name, OpenFlags, Status, StatusCode, Version,
+};
+use shush_rs::ExposeSecret;
+use tokio::net::TcpListener;
+use tracing::{debug, info, warn};
+
+use crate::encryptedfs::{
+    split_parent, CreateFileAttr, EncryptedFs, FileAttr, FileType, FsError, FsResult, SetFileAttr,
+};
+use crate::{GID, UID};
+
+/// Entries sent in each reply to `readdir`.
+const READDIR_BATCH: usize = 128;
+/// `S_IFDIR` and `S_IFREG` of the permissions, the same on all the platforms.
+const MODE_DIR: u32 = 0o040_000;
+const MODE_FILE: u32 = 0o100_000;
+const MODE_FIFO: u32 = 0o010_000;
+const MODE_CHAR: u32 = 0o020_000;
+const MODE_BLOCK: u32 = 0o060_000;
+const MODE_SOCKET: u32 = 0o140_000;
+
+/// What the client authenticates with, see [`SftpConfig::new`].
+pub enum SftpCredentials<'a> {
+    Password(&'a str),
+    PublicKey(&'a PublicKey),
+}
+
+/// Called with the user and what it authenticates with, `true` lets it in.
+pub type SftpAuthFn = Arc<dyn Fn(&str, SftpCredentials<'_>) -> bool + Send + Sync>;
+
+/// How [`serve`] runs.
+pub struct SftpConfig {
+    auth: SftpAuthFn,
+    host_keys: Vec<KeyPair>,
+}
+
+impl SftpConfig {
+    /// The users are let in if `auth` returns `true` for them.
+    pub fn new(auth: impl Fn(&str, SftpCredentials<'_>) -> bool + Send + Sync + 'static) -> Self {
+        Self {
+            auth: Arc::new(auth),
+            host_keys: vec![],
+        }
+    }
+
+    /// Identify the server with `key`, without any a new ed25519 key is made each time [`serve`] starts, so the
+    /// clients can't check it.
+    #[must_use]
+    pub fn with_host_key(mut self, key: KeyPair) -> Self {
+        self.host_keys.push(key);
+        self
+    }
+}
+
+/// Accept SSH connections on `listener` and serve `fs` over SFTP to the users let in by `config`, until accepting
+/// fails.
+///
+/// Each connection runs on its own task, its handles are released when it closes.
+#[allow(clippy::missing_errors_doc)]
+pub async fn serve(
+    fs: Arc<EncryptedFs>,
+    listener: TcpListener,
+    config: SftpConfig,
+) -> FsResult<()> {
+    let mut keys = config.host_keys;
+    if keys.is_empty() {
+        keys.push(
+            KeyPair::generate_ed25519()
+                .ok_or_else(|| FsError::other("cannot generate host key"))?,
+        );
+    }
+    let ssh_config = Arc::new(russh::server::Config {
+        keys,
+        auth_rejection_time: Duration::from_secs(1),
+        auth_rejection_time_initial: Some(Duration::ZERO),
+        ..Default::default()
+    });
+    info!(addr = ?listener.local_addr()?, "serving over sftp");
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        debug!(%addr, "sftp connection");
+        let handler = SshSession {
+            fs: fs.clone(),
+            auth: config.auth.clone(),
+            channels: HashMap::new(),
+        };
+        let ssh_config = ssh_config.clone();
+        tokio::spawn(async move {
+            let res = match russh::server::run_stream(ssh_config, stream, handler).await {
+                Ok(session) => session.await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = res {
+                warn!(%addr, err = %err, "sftp connection");
+            }
+        });
+    }
+}
+
+/// An SSH connection, it only runs the `sftp` subsystem.
+struct SshSession {
+    fs: Arc<EncryptedFs>,
+    auth: SftpAuthFn,
+    // the sessions opened and not yet given to a subsystem
+    channels: HashMap<ChannelId, Channel<Msg>>,
+}
+
+impl SshSession {
+    fn auth(&self, user: &str, credentials: SftpCredentials<'_>) -> Auth {
+        if (self.auth)(user, credentials) {
+            Auth::Accept
+        } else {
+            warn!(user, "sftp authentication failed");
+            Auth::Reject {
+                proceed_with_methods: None,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl russh::server::Handler for SshSession {
+    type Error = russh::Error;
+
+    async fn auth_password(&mut self, user: &str, password: &str) -> Result<Auth, Self::Error> {
+        Ok(self.auth(user, SftpCredentials::Password(password)))
+    }
+
+    async fn auth_publickey(
+        &mut self,
+        user: &str,
+        public_key: &PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        Ok(self.auth(user, SftpCredentials::PublicKey(public_key)))
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        _session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        self.channels.insert(channel.id(), channel);
+        Ok(true)
+    }
+
+    async fn subsystem_request(
+        &mut self,
+        channel_id: ChannelId,
+        name: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        match (name, self.channels.remove(&channel_id)) {
+            ("sftp", Some(channel)) => {
+                session.channel_success(channel_id);
+                russh_sftp::server::run(channel.into_stream(), SftpSession::new(self.fs.clone()))
+                    .await;
+            }
+            _ => session.channel_failure(channel_id),
+        }
+        Ok(())
+    }
+}
+
+enum OpenHandle {
+    File {
+        ino: u64,
+        fh: u64,
+        append: bool,
+    },
+    /// The entries not sent yet.
+    Dir(VecDeque<File>),
+}
+
+/// The `sftp` subsystem of a connection, with the handles it opened.
+struct SftpSession {
+    fs: Arc<EncryptedFs>,
+    handles: HashMap<String, OpenHandle>,
+    next_handle: u64,
+}
+
+impl SftpSession {
+    fn new(fs: Arc<EncryptedFs>) -> Self {
+        Self {
+            fs,
+            handles: HashMap::new(),
+            next_handle: 1,
+        }
+    }
+
+    fn add_handle(&mut self, handle: OpenHandle) -> String {
+        let name = self.next_handle.to_string();
+        self.next_handle += 1;
+        self.handles.insert(name.clone(), handle);
+        name
+    }
+
+    fn file_handle(&self, handle: &str) -> Result<(u64, u64, bool), StatusCode> {
+        match self.handles.get(handle) {
+            Some(OpenHandle::File { ino, fh, append }) => Ok((*ino, *fh, *append)),
+            _ => Err(StatusCode::Failure),
+        }
+    }
+
+    async fn open_file(
+        &self,
+        path: &Path,
+        pflags: OpenFlags,
+        attrs: &FileAttributes,
+    ) -> FsResult<(u64, u64)> {
+        let read = pflags.contains(OpenFlags::READ);
+        let write = pflags.intersects(OpenFlags::WRITE | OpenFlags::APPEND);
+        let (fh, attr) = match self.fs.resolve(path).await {
+            Ok(_) if pflags.contains(OpenFlags::CREATE | OpenFlags::EXCLUDE) => {
+                return Err(FsError::AlreadyExists)
+            }
+            Ok(attr) if attr.kind != FileType::RegularFile => {
+                return Err(FsError::InvalidInodeType)
+            }
+            Ok(_) => self.fs.open_by_path(path, read, write).await?,
+            Err(FsError::NotFound { .. }) if pflags.contains(OpenFlags::CREATE) => {
+                let attr = create_attr(FileType::RegularFile, attrs, 0o644);
+                self.fs.create_file_by_path(path, attr, read, write).await?
+            }
+            Err(err) => return Err(err),
+        };
+        if write && pflags.contains(OpenFlags::TRUNCATE) {
+            if let Err(err) = self.fs.set_len(attr.ino, 0).await {
+                self.fs.release(fh).await?;
+                return Err(err);
+            }
+        }
+        Ok((attr.ino, fh))
+    }
+
+    async fn stat_at(&self, id: u32, path: &str) -> Result<Attrs, StatusCode> {
+        let attr = self.fs.resolve(&normalize(path)).await.map_err(status)?;
+        Ok(Attrs {
+            id,
+            attrs: to_attrs(&attr),
+        })
+    }
+
+    async fn set_attrs(&self, ino: u64, attrs: &FileAttributes) -> FsResult<()> {
+        if let Some(size) = attrs.size {
+            self.fs.set_len(ino, size).await?;
+        }
+        let mut set_attr = SetFileAttr::default();
+        #[allow(clippy::cast_possible_truncation)]
+        if let Some(permissions) = attrs.permissions {
+            set_attr = set_attr.with_perm((permissions & 0o7777) as u16);
+        }
+        if let Some(uid) = attrs.uid {
+            set_attr = set_attr.with_uid(uid);
+        }
+        if let Some(gid) = attrs.gid {
+            set_attr = set_attr.with_gid(gid);
+        }
+        if let Some(atime) = attrs.atime {
+            set_attr = set_attr.with_atime(from_secs(atime));
+        }
+        if let Some(mtime) = attrs.mtime {
+            set_attr = set_attr.with_mtime(from_secs(mtime));
+        }
+        self.fs.set_attr(ino, set_attr).await
+    }
+
+    async fn list_dir(&self, path: &Path) -> FsResult<VecDeque<File>> {
+        let attr = self.fs.resolve(path).await?;
+        if attr.kind != FileType::Directory {
+            return Err(FsError::InvalidInodeType);
+        }
+        let mut files = VecDeque::new();
+        for entry in self.fs.read_dir_plus(attr.ino).await? {
+            let entry = entry?;
+            let name = entry.name.expose_secret().to_string();
+            if name == "." || name == ".." {
+                continue;
+            }
+            files.push_back(File::new(name, to_attrs(&entry.attr)));
+        }
+        Ok(files)
+    }
+
+    async fn mkdir_at(&self, path: &Path, attrs: &FileAttributes) -> FsResult<()> {
+        let (parent, name) = split_parent(path)?;
+        let parent = self.fs.resolve(parent).await?;
+        let attr = create_attr(FileType::Directory, attrs, 0o755);
+        self.fs
+            .create(parent.ino, &name, attr, false, false)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_at(&self, path: &Path, kind: FileType) -> FsResult<()> {
+        if self.fs.resolve(path).await?.kind != kind {
+            return Err(FsError::InvalidInodeType);
+        }
+        self.fs.remove_by_path(path).await
+    }
+
+    /// Unlike [`EncryptedFs::rename`] it fails if `to` exists, like SFTP asks.
+    async fn rename_at(&self, from: &Path, to: &Path) -> FsResult<()> {
+        let (parent, name) = split_parent(from)?;
+        let (new_parent, new_name) = split_parent(to)?;
+        let parent = self.fs.resolve(parent).await?;
+        let new_parent = self.fs.resolve(new_parent).await?;
+        if self.fs.exists_by_name(new_parent.ino, &new_name).await? {
+            return Err(FsError::AlreadyExists);
+        }
+        self.fs
+            .rename(parent.ino, &name, new_parent.ino, &new_name)
+            .await
+    }
+}
+
+impl Drop for SftpSession {
+    fn drop(&mut self) {
+        // the client went away without closing them
+        let fs = self.fs.clone();
+        let handles: Vec<_> = self
+            .handles
+            .drain()
+            .filter_map(|(_, handle)| match handle {
+                OpenHandle::File { fh, .. } => Some(fh),
+                OpenHandle::Dir(_) => None,
+            })
+            .collect();
+        if handles.is_empty() {
+            return;
+        }
+        tokio::spawn(async move {
+            for fh in handles {
+                if let Err(err) = fs.release(fh).await {
+                    warn!(fh, err = %err, "releasing sftp handle");
+                }
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl russh_sftp::server::Handler for SftpSession {
+    type Error = StatusCode;
+
+    fn unimplemented(&self) -> Self::Error {
+        StatusCode::OpUnsupported
+    }
+
+    async fn init(
+        &mut self,
+        version: u32,
+        _extensions: HashMap<String, String>,
+    ) -> Result<Version, Self::Error> {
+        debug!(version, "sftp init");
+        Ok(Version::new())
+    }
+
+    async fn open(
+        &mut self,
+        id: u32,
+        filename: String,
+        pflags: OpenFlags,
+        attrs: FileAttributes,
+    ) -> Result<Handle, Self::Error> {
+        let (ino, fh) = self
+            .open_file(&normalize(&filename), pflags, &attrs)
+            .await
+            .map_err(status)?;
+        let handle = self.add_handle(OpenHandle::File {
+            ino,
+            fh,
+            append: pflags.contains(OpenFlags::APPEND),
+        });
+        Ok(Handle { id, handle })
+    }
+
+    async fn close(&mut self, id: u32, handle: String) -> Result<Status, Self::Error> {
+        match self.handles.remove(&handle) {
+            Some(OpenHandle::File { fh, .. }) => self.fs.release(fh).await.map_err(status)?,
+            Some(OpenHandle::Dir(_)) => {}
+            None => return Err(StatusCode::Failure),
+        }
+        Ok(ok(id))
+    }
+
+    async fn read(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        len: u32,
+    ) -> Result<Data, Self::Error> {
+        let (ino, fh, _) = self.file_handle(&handle)?;
+        let mut data = vec![0; len as usize];
+        let mut read = 0;
+        while read < data.len() {
+            let n = self
+                .fs
+                .read(ino, offset + read as u64, &mut data[read..], fh)
+                .await
+                .map_err(status)?;
+            if n == 0 {
+                break;
+            }
+            read += n;
+        }
+        if read == 0 && len > 0 {
+            return Err(StatusCode::Eof);
+        }
+        data.truncate(read);
+        Ok(Data { id, data })
+    }
+
+    async fn write(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<Status, Self::Error> {
+        let (ino, fh, append) = self.file_handle(&handle)?;
+        let offset = if append {
+            self.fs.get_attr(ino).await.map_err(status)?.size
+        } else {
+            offset
+        };
+        self.fs
+            .write_all(ino, offset, &data, fh)
+            .await
+            .map_err(status)?;
+        Ok(ok(id))
+    }
+
+    async fn lstat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        // there are no links
+        self.stat_at(id, &path).await
+    }
+
+    async fn fstat(&mut self, id: u32, handle: String) -> Result<Attrs, Self::Error> {
+        let (ino, _, _) = self.file_handle(&handle)?;
+        let attr = self.fs.get_attr(ino).await.map_err(status)?;
+        Ok(Attrs {
+            id,
+            attrs: to_attrs(&attr),
+        })
+    }
+
+    async fn setstat(
+        &mut self,
+        id: u32,
+        path: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        let attr = self.fs.resolve(&normalize(&path)).await.map_err(status)?;
+        self.set_attrs(attr.ino, &attrs).await.map_err(status)?;
+        Ok(ok(id))
+    }
+
+    async fn fsetstat(
+        &mut self,
+        id: u32,
+        handle: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        let (ino, _, _) = self.file_handle(&handle)?;
+        self.set_attrs(ino, &attrs).await.map_err(status)?;
+        Ok(ok(id))
+    }
+
+    async fn opendir(&mut self, id: u32, path: String) -> Result<Handle, Self::Error> {
+        let files = self.list_dir(&normalize(&path)).await.map_err(status)?;
+        let handle = self.add_handle(OpenHandle::Dir(files));
+        Ok(Handle { id, handle })
+    }
+
+    async fn readdir(&mut self, id: u32, handle: String) -> Result<Name, Self::Error> {
+        let Some(OpenHandle::Dir(files)) = self.handles.get_mut(&handle) else {
+            return Err(StatusCode::Failure);
+        };
+        if files.is_empty() {
+            return Err(StatusCode::Eof);
+        }
+        let files = files.drain(..files.len().min(READDIR_BATCH)).collect();
+        Ok(Name { id, files })
+    }
+
+    async fn remove(&mut self, id: u32, filename: String) -> Result<Status, Self::Error> {
+        self.remove_at(&normalize(&filename), FileType::RegularFile)
+            .await
+            .map_err(status)?;
+        Ok(ok(id))
+    }
+
+    async fn mkdir(
+        &mut self,
+        id: u32,
+        path: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        self.mkdir_at(&normalize(&path), &attrs)
+            .await
+            .map_err(status)?;
+        Ok(ok(id))
+    }
+
+    async fn rmdir(&mut self, id: u32, path: String) -> Result<Status, Self::Error> {
+        self.remove_at(&normalize(&path), FileType::Directory)
+            .await
+            .map_err(status)?;
+        Ok(ok(id))
+    }
+
+    async fn realpath(&mut self, id: u32, path: String) -> Result<Name, Self::Error> {
+        let path = normalize(&path).to_string_lossy().to_string();
+        Ok(Name {
+            id,
+            files: vec![File::dummy(path)],
+        })
+    }
+
+    async fn stat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        self.stat_at(id, &path).await
+    }
+
+    async fn rename(
+        &mut self,
+        id: u32,
+        oldpath: String,
+        newpath: String,
+    ) -> Result<Status, Self::Error> {
+        self.rename_at(&normalize(&oldpath), &normalize(&newpath))
+            .await
+            .map_err(status)?;
+        Ok(ok(id))
+    }
+}
+
+/// `path` from the root, with `.` and `..` resolved without looking them up. The clients use relative paths from
+/// the root, which is their home.
+fn normalize(path: &str) -> PathBuf {
+    let mut normalized = PathBuf::from("/");
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    normalized
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn create_attr(kind: FileType, attrs: &FileAttributes, perm: u16) -> CreateFileAttr {
+    CreateFileAttr {
+        kind,
+        perm: attrs
+            .permissions
+            .map_or(perm, |permissions| (permissions & 0o7777) as u16),
+        uid: *UID,
+        gid: *GID,
+        rdev: 0,
+        flags: 0,
+        compress: None,
+    }
+}
+
+fn to_attrs(attr: &FileAttr) -> FileAttributes {
+    let kind = match attr.kind {
+        FileType::Directory => MODE_DIR,
+        FileType::RegularFile => MODE_FILE,
+        FileType::NamedPipe => MODE_FIFO,
+        FileType::CharDevice => MODE_CHAR,
+        FileType::BlockDevice => MODE_BLOCK,
+        FileType::Socket => MODE_SOCKET,
+    };
+    FileAttributes {
+        size: Some(attr.size),
+        uid: Some(attr.uid),
+        gid: Some(attr.gid),
+        permissions: Some(kind | u32::from(attr.perm)),
+        atime: Some(to_secs(attr.atime)),
+        mtime: Some(to_secs(attr.mtime)),
+        ..FileAttributes::empty()
+    }
+}
+
+/// The times of SFTP are seconds since the epoch in 32 bits.
+fn to_secs(time: SystemTime) -> u32 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |time| u32::try_from(time.as_secs()).unwrap_or(u32::MAX))
+}
+
+fn from_secs(secs: u32) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(u64::from(secs))
+}
+
+fn ok(id: u32) -> Status {
+    Status {
+        id,
+        status_code: StatusCode::Ok,
+        error_message: "Ok".to_string(),
+        language_tag: "en-US".to_string(),
+    }
+}
+
+/// The status the client gets for `err`.
+fn status(err: FsError) -> StatusCode {
+    match err {
+        FsError::NotFound { .. } | FsError::InodeNotFound => StatusCode::NoSuchFile,
+        FsError::PermissionDenied | FsError::NotPermitted | FsError::ReadOnly | FsError::Locked => {
+            StatusCode::PermissionDenied
+        }
+        err => {
+            warn!(err = %err, "sftp request failed");
+            StatusCode::Failure
+        }
+    }
+}
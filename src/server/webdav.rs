@@ -0,0 +1,569 @@
+//! WebDAV server over an [`EncryptedFs`], with the `webdav` feature, see [`serve`].
+//!
+//! [`EncryptedDavFs`] adapts the fs to the [`DavFileSystem`] of `dav-server`, so it can also be put behind another
+//! [`DavHandler`]. The paths are resolved from the root like [`EncryptedFs::resolve`]. The files are read at the
+//! offsets asked, so range requests don't read what comes before, and written with [`EncryptedFileWriter`]. The
+//! ETags are made of the inode and its [`FileAttr::generation`], which changes with every change of the file.
+//!
+//! There is no authentication and the locks are faked, like Windows needs to map it as a drive, so put a proxy in
+//! front of it if it's reachable by others.
+
+use std::convert::Infallible;
+use std::fmt;
+use std::io::SeekFrom;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use bytes::{Buf, Bytes};
+use dav_server::davpath::DavPath;
+use dav_server::fakels::FakeLs;
+use dav_server::fs::{
+    DavDirEntry, DavFile, DavFileSystem, DavMetaData, FsError as DavError, FsFuture, FsStream,
+    OpenOptions, ReadDirMeta,
+};
+use dav_server::DavHandler;
+use futures_util::{stream, FutureExt};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use shush_rs::ExposeSecret;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::encryptedfs::{
+    split_parent, CopyFileRangeReq, CreateFileAttr, EncryptedFileWriter, EncryptedFs, FileAttr,
+    FileType, FsError, FsResult,
+};
+use crate::{GID, UID};
+
+/// Most copied with one [`EncryptedFs::copy_file_range`].
+const COPY_CHUNK: usize = 1024 * 1024;
+
+/// A running [`serve`], it stops when [`WebDavServer::shutdown`] is called or it's dropped.
+pub struct WebDavServer {
+    addr: SocketAddr,
+    shutdown: Option<oneshot::Sender<()>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl WebDavServer {
+    /// The address it listens on, with the port picked if it was `0`.
+    pub const fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stop accepting connections and wait for it, the requests in progress are completed.
+    pub async fn shutdown(mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(task) = self.task.take() {
+            if let Err(err) = task.await {
+                warn!(err = %err, "webdav server");
+            }
+        }
+    }
+}
+
+impl Drop for WebDavServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+/// Serve `fs` over WebDAV on `addr`, until the returned [`WebDavServer`] is shut down.
+#[allow(clippy::missing_errors_doc)]
+pub async fn serve(addr: SocketAddr, fs: Arc<EncryptedFs>) -> FsResult<WebDavServer> {
+    let listener = TcpListener::bind(addr).await?;
+    let addr = listener.local_addr()?;
+    let handler = DavHandler::builder()
+        .filesystem(Box::new(EncryptedDavFs::new(fs)))
+        .locksystem(FakeLs::new())
+        .build_handler();
+    let (shutdown, mut shutdown_rx) = oneshot::channel();
+    let task = tokio::spawn(async move {
+        loop {
+            let stream = tokio::select! {
+                res = listener.accept() => match res {
+                    Ok((stream, _)) => stream,
+                    Err(err) => {
+                        warn!(err = %err, "webdav accept");
+                        continue;
+                    }
+                },
+                _ = &mut shutdown_rx => break,
+            };
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |req| {
+                    let handler = handler.clone();
+                    async move { Ok::<_, Infallible>(handler.handle(req).await) }
+                });
+                if let Err(err) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    debug!(err = %err, "webdav connection");
+                }
+            });
+        }
+        info!(%addr, "webdav server stopped");
+    });
+    info!(%addr, "serving over webdav");
+    Ok(WebDavServer {
+        addr,
+        shutdown: Some(shutdown),
+        task: Some(task),
+    })
+}
+
+/// [`EncryptedFs`] as a [`DavFileSystem`].
+#[derive(Clone)]
+pub struct EncryptedDavFs {
+    fs: Arc<EncryptedFs>,
+}
+
+impl EncryptedDavFs {
+    pub const fn new(fs: Arc<EncryptedFs>) -> Self {
+        Self { fs }
+    }
+
+    async fn open_file(&self, path: &DavPath, options: OpenOptions) -> FsResult<DavEncryptedFile> {
+        let path = path.as_rel_ospath();
+        let attr = match self.fs.resolve(path).await {
+            Ok(_) if options.create_new => return Err(FsError::AlreadyExists),
+            Ok(attr) => attr,
+            Err(FsError::NotFound { .. }) if options.create || options.create_new => {
+                self.fs
+                    .create_file_by_path(path, create_attr(FileType::RegularFile), false, false)
+                    .await?
+                    .1
+            }
+            Err(err) => return Err(err),
+        };
+        if attr.kind != FileType::RegularFile {
+            return Err(FsError::InvalidInodeType);
+        }
+        if options.write && options.truncate {
+            self.fs.set_len(attr.ino, 0).await?;
+        }
+        let read_fh = if options.read {
+            Some(self.fs.open(attr.ino, true, false).await?)
+        } else {
+            None
+        };
+        let writer = if options.write || options.append {
+            match self.fs.open_async_writer(attr.ino).await {
+                Ok(writer) => Some(writer),
+                Err(err) => {
+                    if let Some(fh) = read_fh {
+                        self.fs.release(fh).await?;
+                    }
+                    return Err(err);
+                }
+            }
+        } else {
+            None
+        };
+        let pos = if options.append {
+            self.fs.get_attr(attr.ino).await?.size
+        } else {
+            0
+        };
+        Ok(DavEncryptedFile {
+            fs: self.fs.clone(),
+            ino: attr.ino,
+            pos,
+            read_fh,
+            writer,
+        })
+    }
+
+    async fn list_dir(&self, path: &DavPath) -> FsResult<Vec<DavEntry>> {
+        let attr = self.fs.resolve(path.as_rel_ospath()).await?;
+        if attr.kind != FileType::Directory {
+            return Err(FsError::InvalidInodeType);
+        }
+        let mut entries = vec![];
+        for entry in self.fs.read_dir_plus(attr.ino).await? {
+            let entry = entry?;
+            let name = entry.name.expose_secret().to_string();
+            if name == "." || name == ".." {
+                continue;
+            }
+            entries.push(DavEntry {
+                name,
+                attr: entry.attr,
+            });
+        }
+        Ok(entries)
+    }
+
+    async fn create_dir_at(&self, path: &Path) -> FsResult<()> {
+        let (parent, name) = split_parent(path)?;
+        let parent = self.fs.resolve(parent).await?;
+        self.fs
+            .create(
+                parent.ino,
+                &name,
+                create_attr(FileType::Directory),
+                false,
+                false,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_at(&self, path: &Path, kind: FileType) -> FsResult<()> {
+        if self.fs.resolve(path).await?.kind != kind {
+            return Err(FsError::InvalidInodeType);
+        }
+        self.fs.remove_by_path(path).await
+    }
+
+    async fn rename_at(&self, from: &Path, to: &Path) -> FsResult<()> {
+        let (parent, name) = split_parent(from)?;
+        let (new_parent, new_name) = split_parent(to)?;
+        let parent = self.fs.resolve(parent).await?;
+        let new_parent = self.fs.resolve(new_parent).await?;
+        self.fs
+            .rename(parent.ino, &name, new_parent.ino, &new_name)
+            .await
+    }
+
+    /// Copy the file `from` to `to` with [`EncryptedFs::copy_file_range`], `to` is replaced if it exists.
+    async fn copy_at(&self, from: &Path, to: &Path) -> FsResult<()> {
+        let (src_fh, src) = self.fs.open_by_path(from, true, false).await?;
+        let res = self.copy_from(src_fh, &src, to).await;
+        self.fs.release(src_fh).await?;
+        res
+    }
+
+    async fn copy_from(&self, src_fh: u64, src: &FileAttr, to: &Path) -> FsResult<()> {
+        if src.kind != FileType::RegularFile {
+            return Err(FsError::InvalidInodeType);
+        }
+        let (dest_fh, dest) = match self.fs.resolve(to).await {
+            Ok(_) => {
+                let (fh, attr) = self.fs.open_by_path(to, false, true).await?;
+                self.fs.set_len(attr.ino, 0).await?;
+                (fh, attr)
+            }
+            Err(FsError::NotFound { .. }) => {
+                self.fs
+                    .create_file_by_path(to, create_attr(FileType::RegularFile), false, true)
+                    .await?
+            }
+            Err(err) => return Err(err),
+        };
+        let mut offset = 0;
+        let res = loop {
+            let req = CopyFileRangeReq::builder()
+                .src_ino(src.ino)
+                .src_offset(offset)
+                .dest_ino(dest.ino)
+                .dest_offset(offset)
+                .src_fh(src_fh)
+                .dest_fh(dest_fh)
+                .build();
+            match self.fs.copy_file_range(&req, COPY_CHUNK).await {
+                Ok(0) => break Ok(()),
+                Ok(len) => offset += len as u64,
+                Err(err) => break Err(err),
+            }
+        };
+        self.fs.release(dest_fh).await?;
+        res
+    }
+}
+
+impl DavFileSystem for EncryptedDavFs {
+    fn open<'a>(
+        &'a self,
+        path: &'a DavPath,
+        options: OpenOptions,
+    ) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            let file = self.open_file(path, options).await.map_err(dav_error)?;
+            Ok(Box::new(file) as Box<dyn DavFile>)
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        _meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>> {
+        async move {
+            let entries = self.list_dir(path).await.map_err(dav_error)?;
+            let entries = entries
+                .into_iter()
+                .map(|entry| Ok(Box::new(entry) as Box<dyn DavDirEntry>));
+            Ok(Box::pin(stream::iter(entries)) as FsStream<Box<dyn DavDirEntry>>)
+        }
+        .boxed()
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            let attr = self
+                .fs
+                .resolve(path.as_rel_ospath())
+                .await
+                .map_err(dav_error)?;
+            Ok(Box::new(DavAttr(attr)) as Box<dyn DavMetaData>)
+        }
+        .boxed()
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.create_dir_at(path.as_rel_ospath())
+                .await
+                .map_err(dav_error)
+        }
+        .boxed()
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.remove_at(path.as_rel_ospath(), FileType::Directory)
+                .await
+                .map_err(dav_error)
+        }
+        .boxed()
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.remove_at(path.as_rel_ospath(), FileType::RegularFile)
+                .await
+                .map_err(dav_error)
+        }
+        .boxed()
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.rename_at(from.as_rel_ospath(), to.as_rel_ospath())
+                .await
+                .map_err(dav_error)
+        }
+        .boxed()
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.copy_at(from.as_rel_ospath(), to.as_rel_ospath())
+                .await
+                .map_err(dav_error)
+        }
+        .boxed()
+    }
+}
+
+/// An open file, read at its position with [`EncryptedFs::read`] and written with [`EncryptedFileWriter`].
+struct DavEncryptedFile {
+    fs: Arc<EncryptedFs>,
+    ino: u64,
+    pos: u64,
+    read_fh: Option<u64>,
+    writer: Option<EncryptedFileWriter>,
+}
+
+impl DavEncryptedFile {
+    async fn read_at_pos(&mut self, count: usize) -> FsResult<Bytes> {
+        let fh = self.read_fh.ok_or(FsError::InvalidFileHandle)?;
+        let mut buf = vec![0; count];
+        let mut read = 0;
+        while read < count {
+            let len = self
+                .fs
+                .read(self.ino, self.pos + read as u64, &mut buf[read..], fh)
+                .await?;
+            if len == 0 {
+                break;
+            }
+            read += len;
+        }
+        buf.truncate(read);
+        self.pos += read as u64;
+        Ok(Bytes::from(buf))
+    }
+
+    async fn write_at_pos(&mut self, buf: &[u8]) -> FsResult<()> {
+        let writer = self.writer.as_mut().ok_or(FsError::InvalidFileHandle)?;
+        writer.seek(SeekFrom::Start(self.pos)).await?;
+        writer.write_all(buf).await?;
+        self.pos += buf.len() as u64;
+        Ok(())
+    }
+
+    async fn seek_to(&mut self, pos: SeekFrom) -> FsResult<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(pos) => (pos, 0),
+            SeekFrom::Current(offset) => (self.pos, offset),
+            SeekFrom::End(offset) => (self.fs.get_attr(self.ino).await?.size, offset),
+        };
+        self.pos = base
+            .checked_add_signed(offset)
+            .ok_or_else(|| FsError::invalid_input("seek before the start"))?;
+        Ok(self.pos)
+    }
+}
+
+impl fmt::Debug for DavEncryptedFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DavEncryptedFile")
+            .field("ino", &self.ino)
+            .field("pos", &self.pos)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for DavEncryptedFile {
+    fn drop(&mut self) {
+        // the writer releases its own handle
+        if let Some(fh) = self.read_fh.take() {
+            let fs = self.fs.clone();
+            tokio::spawn(async move {
+                if let Err(err) = fs.release(fh).await {
+                    warn!(fh, err = %err, "releasing webdav handle");
+                }
+            });
+        }
+    }
+}
+
+impl DavFile for DavEncryptedFile {
+    fn metadata(&mut self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        async move {
+            let attr = self.fs.get_attr(self.ino).await.map_err(dav_error)?;
+            Ok(Box::new(DavAttr(attr)) as Box<dyn DavMetaData>)
+        }
+        .boxed()
+    }
+
+    fn write_buf(&mut self, mut buf: Box<dyn Buf + Send>) -> FsFuture<'_, ()> {
+        async move {
+            let buf = buf.copy_to_bytes(buf.remaining());
+            self.write_at_pos(&buf).await.map_err(dav_error)
+        }
+        .boxed()
+    }
+
+    fn write_bytes(&mut self, buf: Bytes) -> FsFuture<'_, ()> {
+        async move { self.write_at_pos(&buf).await.map_err(dav_error) }.boxed()
+    }
+
+    fn read_bytes(&mut self, count: usize) -> FsFuture<'_, Bytes> {
+        async move { self.read_at_pos(count).await.map_err(dav_error) }.boxed()
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> FsFuture<'_, u64> {
+        async move { self.seek_to(pos).await.map_err(dav_error) }.boxed()
+    }
+
+    fn flush(&mut self) -> FsFuture<'_, ()> {
+        async move {
+            if let Some(writer) = self.writer.as_mut() {
+                writer.flush().await.map_err(|err| dav_error(err.into()))?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+struct DavEntry {
+    name: String,
+    attr: FileAttr,
+}
+
+impl DavDirEntry for DavEntry {
+    fn name(&self) -> Vec<u8> {
+        self.name.as_bytes().to_vec()
+    }
+
+    fn metadata(&self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        let attr = self.attr;
+        async move { Ok(Box::new(DavAttr(attr)) as Box<dyn DavMetaData>) }.boxed()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DavAttr(FileAttr);
+
+impl DavMetaData for DavAttr {
+    fn len(&self) -> u64 {
+        self.0.size
+    }
+
+    fn modified(&self) -> Result<SystemTime, DavError> {
+        Ok(self.0.mtime)
+    }
+
+    fn is_dir(&self) -> bool {
+        self.0.kind == FileType::Directory
+    }
+
+    fn etag(&self) -> Option<String> {
+        Some(format!("{:x}-{:x}", self.0.ino, self.0.generation))
+    }
+
+    fn accessed(&self) -> Result<SystemTime, DavError> {
+        Ok(self.0.atime)
+    }
+
+    fn created(&self) -> Result<SystemTime, DavError> {
+        Ok(self.0.crtime)
+    }
+
+    fn status_changed(&self) -> Result<SystemTime, DavError> {
+        Ok(self.0.ctime)
+    }
+}
+
+fn create_attr(kind: FileType) -> CreateFileAttr {
+    CreateFileAttr {
+        kind,
+        perm: if matches!(kind, FileType::Directory) {
+            0o755
+        } else {
+            0o644
+        },
+        uid: *UID,
+        gid: *GID,
+        rdev: 0,
+        flags: 0,
+        compress: None,
+    }
+}
+
+/// The error the client gets for `err`.
+fn dav_error(err: FsError) -> DavError {
+    match err {
+        FsError::NotFound { .. } | FsError::InodeNotFound => DavError::NotFound,
+        FsError::AlreadyExists => DavError::Exists,
+        FsError::PermissionDenied
+        | FsError::NotPermitted
+        | FsError::ReadOnly
+        | FsError::Locked
+        | FsError::InvalidInodeType => DavError::Forbidden,
+        FsError::QuotaExceeded => DavError::InsufficientStorage,
+        FsError::NameTooLong => DavError::PathTooLong,
+        FsError::MaxFilesizeExceeded => DavError::TooLarge,
+        err => {
+            warn!(err = %err, "webdav request failed");
+            DavError::GeneralFailure
+        }
+    }
+}
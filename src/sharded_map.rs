@@ -0,0 +1,124 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+const SHARDS: usize = 64;
+
+/// A [`HashMap`] split in shards, each behind its own [`RwLock`], so operations on different keys mostly don't wait
+/// for each other.
+///
+/// The guards lock the whole shard of the key, so a key is read and changed with the usual [`HashMap`] methods.
+pub struct ShardedMap<K, V> {
+    shards: Box<[RwLock<HashMap<K, V>>]>,
+    hasher: RandomState,
+}
+
+impl<K: Eq + Hash, V> Default for ShardedMap<K, V> {
+    fn default() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash, V> ShardedMap<K, V> {
+    fn shard(&self, key: &K) -> &RwLock<HashMap<K, V>> {
+        #[allow(clippy::cast_possible_truncation)]
+        let index = self.hasher.hash_one(key) as usize % self.shards.len();
+        &self.shards[index]
+    }
+
+    /// Lock the shard of `key` for reading.
+    pub async fn read(&self, key: &K) -> RwLockReadGuard<'_, HashMap<K, V>> {
+        self.shard(key).read().await
+    }
+
+    /// Lock the shard of `key` for writing.
+    pub async fn write(&self, key: &K) -> RwLockWriteGuard<'_, HashMap<K, V>> {
+        self.shard(key).write().await
+    }
+
+    pub async fn contains_key(&self, key: &K) -> bool {
+        self.read(key).await.contains_key(key)
+    }
+
+    pub async fn insert(&self, key: K, value: V) -> Option<V> {
+        self.write(&key).await.insert(key, value)
+    }
+
+    pub async fn remove(&self, key: &K) -> Option<V> {
+        self.write(key).await.remove(key)
+    }
+
+    /// The shards, to go over all the entries one shard at a time.
+    pub fn shards(&self) -> impl Iterator<Item = &RwLock<HashMap<K, V>>> {
+        self.shards.iter()
+    }
+
+    /// Remove all the entries and return them, one shard at a time.
+    pub async fn drain(&self) -> Vec<(K, V)> {
+        let mut entries = vec![];
+        for shard in self.shards() {
+            entries.extend(shard.write().await.drain());
+        }
+        entries
+    }
+
+    pub async fn clear(&self) {
+        for shard in self.shards() {
+            shard.write().await.clear();
+        }
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        for shard in self.shards() {
+            if !shard.read().await.is_empty() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Number of entries in all shards, each one is locked in turn so it's not a consistent count.
+    pub async fn len(&self) -> usize {
+        let mut len = 0;
+        for shard in self.shards() {
+            len += shard.read().await.len();
+        }
+        len
+    }
+
+    /// Like [`ShardedMap::is_empty`] without locking, as we have the only reference.
+    pub fn is_empty_mut(&mut self) -> bool {
+        self.shards.iter_mut().all(|shard| shard.get_mut().is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sharded_map() {
+        let mut m = ShardedMap::default();
+        assert!(m.is_empty().await);
+        for i in 0..1000 {
+            assert_eq!(m.insert(i, i * 2).await, None);
+        }
+        assert!(!m.is_empty().await);
+        assert!(m.contains_key(&42).await);
+        assert_eq!(m.read(&42).await.get(&42), Some(&84));
+        *m.write(&42).await.get_mut(&42).unwrap() = 1;
+        assert_eq!(m.remove(&42).await, Some(1));
+        assert!(!m.contains_key(&42).await);
+
+        let mut entries = m.drain().await;
+        entries.sort_unstable();
+        assert_eq!(999, entries.len());
+        assert_eq!((0, 0), entries[0]);
+        assert!(m.is_empty_mut());
+    }
+}
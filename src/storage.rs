@@ -0,0 +1,171 @@
+//! Where the data dir of [`EncryptedFs`](crate::encryptedfs::EncryptedFs) is kept, see [`Storage`].
+//!
+//! [`LocalStorage`] keeps it in a directory on disk and [`InMemoryStorage`] in memory, mostly for tests.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+
+use async_trait::async_trait;
+
+use crate::fs_util::StatVfs;
+
+mod local;
+mod memory;
+
+pub use local::LocalStorage;
+pub use memory::InMemoryStorage;
+
+/// A file opened from a [`Storage`], like [`File`].
+#[allow(clippy::len_without_is_empty)]
+pub trait StorageFile: Read + Write + Seek + Send + Sync {
+    #[allow(clippy::missing_errors_doc)]
+    fn len(&self) -> io::Result<u64>;
+
+    /// Truncate or extend the file with zeros, see [`File::set_len`].
+    #[allow(clippy::missing_errors_doc)]
+    fn set_len(&self, size: u64) -> io::Result<()>;
+
+    /// See [`File::sync_all`].
+    #[allow(clippy::missing_errors_doc)]
+    fn sync_all(&self) -> io::Result<()>;
+
+    /// See [`File::sync_data`].
+    #[allow(clippy::missing_errors_doc)]
+    fn sync_data(&self) -> io::Result<()>;
+
+    /// Bytes the file takes in the storage, less than its length if it has holes the storage doesn't keep.
+    #[allow(clippy::missing_errors_doc)]
+    fn allocated(&self) -> io::Result<u64> {
+        self.len()
+    }
+}
+
+impl StorageFile for File {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        Self::set_len(self, size)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        Self::sync_all(self)
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        Self::sync_data(self)
+    }
+
+    #[cfg(unix)]
+    fn allocated(&self) -> io::Result<u64> {
+        use std::os::unix::fs::MetadataExt;
+        // in 512-byte units whatever the block size of the filesystem
+        Ok(self.metadata()?.blocks() * 512)
+    }
+}
+
+/// What is at a key of a [`Storage`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Dir,
+}
+
+/// An entry of a directory, see [`Storage::list`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageEntry {
+    pub name: String,
+    pub kind: EntryKind,
+}
+
+/// Files and directories of a data dir, addressed by keys.
+///
+/// Keys are relative paths like `inodes/00/01/1`, the empty key is the root directory. Like on a local filesystem a
+/// file or directory can only be created in an existing directory.
+///
+/// The files returned by [`Storage::open`] are used from sync code, which drives the futures of this trait with
+/// [`async_util::block_on`](crate::async_util::block_on). They must not wait on tasks of the tokio runtime, the
+/// blocking threads are fine.
+#[async_trait]
+pub trait Storage: Send + Sync + 'static {
+    /// Open the file at `key` for reading, and for writing if `write`.
+    async fn open(&self, key: &Path, write: bool) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Create the file at `key` or truncate it, and open it for reading and writing.
+    async fn create(&self, key: &Path) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Like [`Storage::create`] but it fails with [`io::ErrorKind::AlreadyExists`] if the file exists.
+    async fn create_new(&self, key: &Path) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Read the whole file at `key`.
+    async fn read(&self, key: &Path) -> io::Result<Vec<u8>> {
+        let mut buf = vec![];
+        self.open(key, false).await?.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Replace the file at `key` with `data` atomically, it's durable when this returns.
+    async fn write(&self, key: &Path, data: &[u8]) -> io::Result<()>;
+
+    /// Like [`Storage::write`] but nothing is synced, it's durable only after a [`Storage::sync`] of the file and its
+    /// parent.
+    async fn write_unsynced(&self, key: &Path, data: &[u8]) -> io::Result<()> {
+        self.write(key, data).await
+    }
+
+    /// Move the file or directory at `from` to `to`, replacing the file at `to` if any.
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Remove the file at `key`.
+    async fn remove(&self, key: &Path) -> io::Result<()>;
+
+    /// Create the directory at `key` with the missing parents, it's fine if it exists.
+    async fn create_dir(&self, key: &Path) -> io::Result<()>;
+
+    /// Remove the directory at `key` with all its content.
+    async fn remove_dir(&self, key: &Path) -> io::Result<()>;
+
+    /// Entries of the directory at `key`, in no particular order.
+    async fn list(&self, key: &Path) -> io::Result<Vec<StorageEntry>>;
+
+    /// What is at `key`, `None` if nothing.
+    async fn kind(&self, key: &Path) -> io::Result<Option<EntryKind>>;
+
+    /// Make the file or directory at `key` durable, for a directory its entries.
+    async fn sync(&self, key: &Path) -> io::Result<()>;
+
+    /// Make `to` another name of the file at `from`, like a hard link, so they share the content. It fails with
+    /// [`io::ErrorKind::AlreadyExists`] if `to` exists.
+    ///
+    /// Writing to the file through one of them changes it for all, replacing one with [`Storage::write`] or
+    /// [`Storage::rename`] doesn't.
+    async fn hard_link(&self, _from: &Path, _to: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "hard links are not supported by this storage",
+        ))
+    }
+
+    /// Number of names of the file at `key`, more than `1` if it has others made by [`Storage::hard_link`].
+    async fn link_count(&self, _key: &Path) -> io::Result<u64> {
+        Ok(1)
+    }
+
+    /// Space and inodes statistics of where the files are kept.
+    async fn statvfs(&self) -> io::Result<StatVfs> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "statvfs is not supported by this storage",
+        ))
+    }
+
+    /// The directory holding the files, if they are on the local filesystem.
+    ///
+    /// Only needed by what works on the data dir directly, like the instance lock and the migrations of older
+    /// layouts.
+    fn local_path(&self) -> Option<&Path> {
+        None
+    }
+}
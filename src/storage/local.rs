@@ -0,0 +1,183 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use tokio::runtime::Handle;
+
+use crate::fs_util::{self, StatVfs};
+use crate::storage::{EntryKind, Storage, StorageEntry, StorageFile};
+
+/// Keeps the files in a directory on the local filesystem, the keys are paths relative to it.
+///
+/// The blocking calls run with [`tokio::task::spawn_blocking`] when on a tokio runtime, so they don't hold its workers.
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, key: &Path) -> PathBuf {
+        self.dir.join(key)
+    }
+}
+
+/// Run `f` on the blocking threads of the current runtime, or right away if there is none.
+async fn blocking<T, F>(f: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    match Handle::try_current() {
+        Ok(handle) => handle.spawn_blocking(f).await.map_err(io::Error::other)?,
+        Err(_) => f(),
+    }
+}
+
+async fn open_file(path: PathBuf, options: OpenOptions) -> io::Result<Box<dyn StorageFile>> {
+    blocking(move || Ok(Box::new(options.open(path)?) as Box<dyn StorageFile>)).await
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn open(&self, key: &Path, write: bool) -> io::Result<Box<dyn StorageFile>> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(write);
+        open_file(self.path(key), options).await
+    }
+
+    async fn create(&self, key: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true).truncate(true);
+        open_file(self.path(key), options).await
+    }
+
+    async fn create_new(&self, key: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create_new(true);
+        open_file(self.path(key), options).await
+    }
+
+    async fn read(&self, key: &Path) -> io::Result<Vec<u8>> {
+        let path = self.path(key);
+        blocking(move || fs::read(path)).await
+    }
+
+    async fn write(&self, key: &Path, data: &[u8]) -> io::Result<()> {
+        let path = self.path(key);
+        let data = data.to_vec();
+        blocking(move || {
+            let mut file = fs_util::open_atomic_write(&path)?;
+            file.write_all(&data)?;
+            file.commit()?;
+            File::open(path.parent().expect("oops, we don't have a parent"))?.sync_all()
+        })
+        .await
+    }
+
+    async fn write_unsynced(&self, key: &Path, data: &[u8]) -> io::Result<()> {
+        let path = self.path(key);
+        let data = data.to_vec();
+        blocking(move || {
+            let mut file = tempfile::Builder::new()
+                .prefix(".")
+                .suffix(".tmp")
+                .tempfile_in(path.parent().expect("oops, we don't have a parent"))?;
+            file.write_all(&data)?;
+            file.persist(path)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from, to) = (self.path(from), self.path(to));
+        blocking(move || fs::rename(from, to)).await
+    }
+
+    async fn remove(&self, key: &Path) -> io::Result<()> {
+        let path = self.path(key);
+        blocking(move || fs::remove_file(path)).await
+    }
+
+    async fn create_dir(&self, key: &Path) -> io::Result<()> {
+        let path = self.path(key);
+        blocking(move || fs::create_dir_all(path)).await
+    }
+
+    async fn remove_dir(&self, key: &Path) -> io::Result<()> {
+        let path = self.path(key);
+        blocking(move || fs::remove_dir_all(path)).await
+    }
+
+    async fn list(&self, key: &Path) -> io::Result<Vec<StorageEntry>> {
+        let path = self.path(key);
+        blocking(move || {
+            fs::read_dir(path)?
+                .map(|entry| {
+                    let entry = entry?;
+                    Ok(StorageEntry {
+                        name: entry.file_name().to_string_lossy().to_string(),
+                        kind: if entry.file_type()?.is_dir() {
+                            EntryKind::Dir
+                        } else {
+                            EntryKind::File
+                        },
+                    })
+                })
+                .collect()
+        })
+        .await
+    }
+
+    async fn kind(&self, key: &Path) -> io::Result<Option<EntryKind>> {
+        let path = self.path(key);
+        blocking(move || match fs::metadata(path) {
+            Ok(metadata) if metadata.is_dir() => Ok(Some(EntryKind::Dir)),
+            Ok(_) => Ok(Some(EntryKind::File)),
+            // a parent being a file means there's nothing there either
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::NotADirectory
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        })
+        .await
+    }
+
+    async fn sync(&self, key: &Path) -> io::Result<()> {
+        let path = self.path(key);
+        blocking(move || File::open(path)?.sync_all()).await
+    }
+
+    #[cfg(unix)]
+    async fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from, to) = (self.path(from), self.path(to));
+        blocking(move || fs::hard_link(from, to)).await
+    }
+
+    #[cfg(unix)]
+    async fn link_count(&self, key: &Path) -> io::Result<u64> {
+        use std::os::unix::fs::MetadataExt;
+
+        let path = self.path(key);
+        blocking(move || Ok(fs::metadata(path)?.nlink())).await
+    }
+
+    async fn statvfs(&self) -> io::Result<StatVfs> {
+        let dir = self.dir.clone();
+        blocking(move || fs_util::statvfs(&dir)).await
+    }
+
+    fn local_path(&self) -> Option<&Path> {
+        Some(&self.dir)
+    }
+}
@@ -0,0 +1,497 @@
+use std::collections::BTreeMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use async_trait::async_trait;
+
+use crate::fs_util::StatVfs;
+use crate::storage::{EntryKind, Storage, StorageEntry, StorageFile};
+
+/// Block size reported by [`InMemoryStorage::statvfs`].
+const BLOCK_SIZE: u64 = 4096;
+
+/// Free blocks reported by [`InMemoryStorage::statvfs`], there is no limit besides the memory.
+const FREE_BLOCKS: u64 = u32::MAX as u64;
+
+type Data = Arc<Mutex<Vec<u8>>>;
+
+enum Node {
+    Dir,
+    /// Shared with the opened files, like an inode, so they still work after it's renamed or removed.
+    File(Data),
+}
+
+/// Keeps the files in memory, they are lost when it's dropped.
+///
+/// Mostly for tests, so they don't touch the disk.
+#[derive(Default)]
+pub struct InMemoryStorage {
+    nodes: Mutex<BTreeMap<PathBuf, Node>>,
+}
+
+impl InMemoryStorage {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn nodes(&self) -> MutexGuard<BTreeMap<PathBuf, Node>> {
+        self.nodes.lock().expect("cannot obtain lock")
+    }
+}
+
+/// Key without `.` components, the root is the empty path.
+fn normalize(key: &Path) -> PathBuf {
+    key.components()
+        .filter(|c| !matches!(c, Component::CurDir))
+        .collect()
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "no such file or directory")
+}
+
+fn kind(nodes: &BTreeMap<PathBuf, Node>, key: &Path) -> Option<EntryKind> {
+    if key.as_os_str().is_empty() {
+        return Some(EntryKind::Dir);
+    }
+    nodes.get(key).map(|node| match node {
+        Node::Dir => EntryKind::Dir,
+        Node::File(_) => EntryKind::File,
+    })
+}
+
+/// Check the parent of `key` is a directory, so we can create `key` in it.
+fn check_parent(nodes: &BTreeMap<PathBuf, Node>, key: &Path) -> io::Result<()> {
+    let Some(parent) = key.parent() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the root can't be a file",
+        ));
+    };
+    match kind(nodes, parent) {
+        Some(EntryKind::Dir) => Ok(()),
+        Some(EntryKind::File) => Err(io::ErrorKind::NotADirectory.into()),
+        None => Err(not_found()),
+    }
+}
+
+/// `key` and all the keys under it.
+fn subtree<'a>(
+    nodes: &'a BTreeMap<PathBuf, Node>,
+    key: &'a Path,
+) -> impl Iterator<Item = (&'a PathBuf, &'a Node)> + 'a {
+    // paths are ordered by components, so the ones under `key` come right after it
+    nodes
+        .range(key.to_path_buf()..)
+        .take_while(move |(k, _)| k.starts_with(key))
+}
+
+#[async_trait]
+impl Storage for InMemoryStorage {
+    async fn open(&self, key: &Path, write: bool) -> io::Result<Box<dyn StorageFile>> {
+        match self.nodes().get(&normalize(key)) {
+            Some(Node::File(data)) => Ok(Box::new(MemoryFile {
+                data: data.clone(),
+                pos: 0,
+                write,
+            })),
+            Some(Node::Dir) => Err(io::ErrorKind::IsADirectory.into()),
+            None => Err(not_found()),
+        }
+    }
+
+    async fn create(&self, key: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let key = normalize(key);
+        let mut nodes = self.nodes();
+        check_parent(&nodes, &key)?;
+        let data = match nodes.get(&key) {
+            Some(Node::File(data)) => {
+                data.lock().expect("cannot obtain lock").clear();
+                data.clone()
+            }
+            Some(Node::Dir) => return Err(io::ErrorKind::IsADirectory.into()),
+            None => {
+                let data = Data::default();
+                nodes.insert(key, Node::File(data.clone()));
+                data
+            }
+        };
+        Ok(Box::new(MemoryFile {
+            data,
+            pos: 0,
+            write: true,
+        }))
+    }
+
+    async fn create_new(&self, key: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let key = normalize(key);
+        let mut nodes = self.nodes();
+        check_parent(&nodes, &key)?;
+        if nodes.contains_key(&key) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        let data = Data::default();
+        nodes.insert(key, Node::File(data.clone()));
+        Ok(Box::new(MemoryFile {
+            data,
+            pos: 0,
+            write: true,
+        }))
+    }
+
+    async fn read(&self, key: &Path) -> io::Result<Vec<u8>> {
+        match self.nodes().get(&normalize(key)) {
+            Some(Node::File(data)) => Ok(data.lock().expect("cannot obtain lock").clone()),
+            Some(Node::Dir) => Err(io::ErrorKind::IsADirectory.into()),
+            None => Err(not_found()),
+        }
+    }
+
+    async fn write(&self, key: &Path, data: &[u8]) -> io::Result<()> {
+        let key = normalize(key);
+        let mut nodes = self.nodes();
+        check_parent(&nodes, &key)?;
+        if matches!(nodes.get(&key), Some(Node::Dir)) {
+            return Err(io::ErrorKind::IsADirectory.into());
+        }
+        // a new file, the opened ones keep the old content like with a rename over it
+        nodes.insert(key, Node::File(Arc::new(Mutex::new(data.to_vec()))));
+        Ok(())
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let from = normalize(from);
+        let to = normalize(to);
+        let mut nodes = self.nodes();
+        check_parent(&nodes, &to)?;
+        match (kind(&nodes, &from), kind(&nodes, &to)) {
+            (None, _) => return Err(not_found()),
+            _ if from == to => return Ok(()),
+            (Some(EntryKind::File), Some(EntryKind::Dir)) => {
+                return Err(io::ErrorKind::IsADirectory.into())
+            }
+            (Some(EntryKind::Dir), Some(EntryKind::File)) => {
+                return Err(io::ErrorKind::NotADirectory.into())
+            }
+            (Some(EntryKind::Dir), Some(EntryKind::Dir)) => {
+                if subtree(&nodes, &to).count() > 1 {
+                    return Err(io::ErrorKind::DirectoryNotEmpty.into());
+                }
+            }
+            _ => {}
+        }
+        if to.starts_with(&from) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot move a directory into itself",
+            ));
+        }
+        let keys: Vec<_> = subtree(&nodes, &from).map(|(k, _)| k.clone()).collect();
+        for key in keys {
+            let node = nodes.remove(&key).expect("node is missing");
+            let rest = key.strip_prefix(&from).expect("key is not under from");
+            nodes.insert(to.join(rest), node);
+        }
+        Ok(())
+    }
+
+    async fn remove(&self, key: &Path) -> io::Result<()> {
+        let key = normalize(key);
+        let mut nodes = self.nodes();
+        match nodes.get(&key) {
+            Some(Node::File(_)) => {
+                nodes.remove(&key);
+                Ok(())
+            }
+            Some(Node::Dir) => Err(io::ErrorKind::IsADirectory.into()),
+            None => Err(not_found()),
+        }
+    }
+
+    async fn create_dir(&self, key: &Path) -> io::Result<()> {
+        let key = normalize(key);
+        let mut nodes = self.nodes();
+        for dir in key.ancestors().collect::<Vec<_>>().into_iter().rev() {
+            match kind(&nodes, dir) {
+                Some(EntryKind::Dir) => {}
+                Some(EntryKind::File) => return Err(io::ErrorKind::AlreadyExists.into()),
+                None => {
+                    nodes.insert(dir.to_path_buf(), Node::Dir);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn remove_dir(&self, key: &Path) -> io::Result<()> {
+        let key = normalize(key);
+        let mut nodes = self.nodes();
+        match kind(&nodes, &key) {
+            Some(EntryKind::Dir) => {}
+            Some(EntryKind::File) => return Err(io::ErrorKind::NotADirectory.into()),
+            None => return Err(not_found()),
+        }
+        let keys: Vec<_> = subtree(&nodes, &key).map(|(k, _)| k.clone()).collect();
+        for key in keys {
+            nodes.remove(&key);
+        }
+        Ok(())
+    }
+
+    async fn list(&self, key: &Path) -> io::Result<Vec<StorageEntry>> {
+        let key = normalize(key);
+        let nodes = self.nodes();
+        match kind(&nodes, &key) {
+            Some(EntryKind::Dir) => {}
+            Some(EntryKind::File) => return Err(io::ErrorKind::NotADirectory.into()),
+            None => return Err(not_found()),
+        }
+        Ok(subtree(&nodes, &key)
+            .filter(|(k, _)| k.parent() == Some(key.as_path()))
+            .map(|(k, node)| StorageEntry {
+                name: k
+                    .file_name()
+                    .expect("entry without a name")
+                    .to_string_lossy()
+                    .to_string(),
+                kind: match node {
+                    Node::Dir => EntryKind::Dir,
+                    Node::File(_) => EntryKind::File,
+                },
+            })
+            .collect())
+    }
+
+    async fn kind(&self, key: &Path) -> io::Result<Option<EntryKind>> {
+        Ok(kind(&self.nodes(), &normalize(key)))
+    }
+
+    async fn sync(&self, key: &Path) -> io::Result<()> {
+        if kind(&self.nodes(), &normalize(key)).is_none() {
+            return Err(not_found());
+        }
+        Ok(())
+    }
+
+    async fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let to = normalize(to);
+        let mut nodes = self.nodes();
+        check_parent(&nodes, &to)?;
+        let data = match nodes.get(&normalize(from)) {
+            Some(Node::File(data)) => data.clone(),
+            Some(Node::Dir) => return Err(io::ErrorKind::IsADirectory.into()),
+            None => return Err(not_found()),
+        };
+        if nodes.contains_key(&to) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        nodes.insert(to, Node::File(data));
+        Ok(())
+    }
+
+    async fn link_count(&self, key: &Path) -> io::Result<u64> {
+        let nodes = self.nodes();
+        let Some(Node::File(data)) = nodes.get(&normalize(key)) else {
+            return Err(not_found());
+        };
+        // the opened files share it too, so the names are counted
+        Ok(nodes
+            .values()
+            .filter(|node| matches!(node, Node::File(other) if Arc::ptr_eq(data, other)))
+            .count() as u64)
+    }
+
+    async fn statvfs(&self) -> io::Result<StatVfs> {
+        let nodes = self.nodes();
+        let used: u64 = nodes
+            .values()
+            .map(|node| match node {
+                Node::Dir => 1,
+                Node::File(data) => {
+                    (data.lock().expect("cannot obtain lock").len() as u64).div_ceil(BLOCK_SIZE)
+                }
+            })
+            .sum();
+        Ok(StatVfs {
+            block_size: BLOCK_SIZE,
+            blocks: used + FREE_BLOCKS,
+            blocks_free: FREE_BLOCKS,
+            blocks_available: FREE_BLOCKS,
+            files_free: FREE_BLOCKS,
+        })
+    }
+}
+
+/// A file of [`InMemoryStorage`].
+struct MemoryFile {
+    data: Data,
+    pos: u64,
+    write: bool,
+}
+
+impl MemoryFile {
+    fn data(&self) -> MutexGuard<Vec<u8>> {
+        self.data.lock().expect("cannot obtain lock")
+    }
+
+    fn check_write(&self) -> io::Result<()> {
+        if self.write {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file not opened for write",
+            ))
+        }
+    }
+}
+
+impl Read for MemoryFile {
+    #[allow(clippy::cast_possible_truncation)]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = {
+            let data = self.data();
+            let start = (self.pos as usize).min(data.len());
+            let len = buf.len().min(data.len() - start);
+            buf[..len].copy_from_slice(&data[start..start + len]);
+            len
+        };
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for MemoryFile {
+    #[allow(clippy::cast_possible_truncation)]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_write()?;
+        {
+            let mut data = self.data();
+            let start = self.pos as usize;
+            let end = start + buf.len();
+            if data.len() < end {
+                data.resize(end, 0);
+            }
+            data[start..end].copy_from_slice(buf);
+        }
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.len()?.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = new_pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+impl StorageFile for MemoryFile {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.data().len() as u64)
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        self.check_write()?;
+        self.data().resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_in_memory_storage() {
+        let storage = InMemoryStorage::new();
+        let dir = Path::new("a/b");
+        storage.create_dir(dir).await.unwrap();
+        assert_eq!(
+            Some(EntryKind::Dir),
+            storage.kind(Path::new("a")).await.unwrap()
+        );
+        assert!(storage.create(Path::new("c/file")).await.is_err());
+
+        let mut file = storage.create(&dir.join("file")).await.unwrap();
+        file.write_all(b"hello").unwrap();
+        let mut reader = storage.open(&dir.join("file"), false).await.unwrap();
+        assert!(reader.write(b"x").is_err());
+        let mut buf = String::new();
+        reader.read_to_string(&mut buf).unwrap();
+        assert_eq!("hello", buf);
+
+        // replaced files keep the old content for the opened ones
+        storage.write(&dir.join("file"), b"world").await.unwrap();
+        assert_eq!(
+            b"world",
+            &storage.read(&dir.join("file")).await.unwrap()[..]
+        );
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        buf.clear();
+        reader.read_to_string(&mut buf).unwrap();
+        assert_eq!("hello", buf);
+
+        // moving a dir moves what's in it
+        storage
+            .rename(Path::new("a"), Path::new("d"))
+            .await
+            .unwrap();
+        assert_eq!(None, storage.kind(Path::new("a/b")).await.unwrap());
+        assert_eq!(
+            vec![StorageEntry {
+                name: "file".to_string(),
+                kind: EntryKind::File
+            }],
+            storage.list(Path::new("d/b")).await.unwrap()
+        );
+        assert_eq!(1, storage.list(Path::new("")).await.unwrap().len());
+
+        // hard links share the content until one is replaced
+        let (file, link) = (Path::new("d/b/file"), Path::new("d/link"));
+        storage.hard_link(file, link).await.unwrap();
+        assert!(storage.hard_link(file, link).await.is_err());
+        assert_eq!(2, storage.link_count(file).await.unwrap());
+        storage
+            .open(link, true)
+            .await
+            .unwrap()
+            .write_all(b"W")
+            .unwrap();
+        assert_eq!(b"World", &storage.read(file).await.unwrap()[..]);
+        storage.write(link, b"other").await.unwrap();
+        assert_eq!(b"World", &storage.read(file).await.unwrap()[..]);
+        assert_eq!(1, storage.link_count(file).await.unwrap());
+        storage.remove(link).await.unwrap();
+
+        storage.remove(Path::new("d/b/file")).await.unwrap();
+        assert!(storage.remove(Path::new("d/b/file")).await.is_err());
+        storage.remove_dir(Path::new("d")).await.unwrap();
+        assert!(storage.list(Path::new("")).await.unwrap().is_empty());
+    }
+}
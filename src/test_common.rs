@@ -1,5 +1,4 @@
 use std::future::Future;
-use std::io::{Cursor, Write};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::{Arc, LazyLock};
@@ -12,8 +11,9 @@ use tokio::sync::Mutex;
 
 use crate::crypto::Cipher;
 use crate::encryptedfs::{
-    CopyFileRangeReq, CreateFileAttr, EncryptedFs, FileType, PasswordProvider,
+    CopyFileRangeReq, CreateFileAttr, EncryptedFs, FileType, FsOptions, PasswordProvider,
 };
+use crate::storage::InMemoryStorage;
 
 #[allow(dead_code)]
 pub static TESTS_DATA_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
@@ -36,6 +36,11 @@ pub static TESTS_DATA_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
         .join("rencfs-test-data")
 });
 
+/// Run the tests on an [`InMemoryStorage`] instead of a data dir, with `RENCFS_TEST_STORAGE=memory`.
+#[allow(dead_code)]
+pub static IN_MEMORY: LazyLock<bool> =
+    LazyLock::new(|| env::var("RENCFS_TEST_STORAGE").is_ok_and(|v| v == "memory"));
+
 #[allow(dead_code)]
 pub static SETUP_RESULT: ThreadLocal<Mutex<Option<SetupResult>>> = ThreadLocal::new();
 
@@ -48,6 +53,7 @@ pub const fn create_attr(kind: FileType) -> CreateFileAttr {
         gid: 0,
         rdev: 0,
         flags: 0,
+        compress: None,
     }
 }
 
@@ -78,16 +84,29 @@ async fn setup(setup: TestSetup) -> SetupResult {
     let read_only = setup.read_only;
     let data_dir_str = path.to_str().unwrap();
     let _ = fs::remove_dir_all(data_dir_str);
-    let _ = fs::create_dir_all(data_dir_str);
 
-    let fs = EncryptedFs::new(
-        Path::new(data_dir_str).to_path_buf(),
-        Box::new(PasswordProviderImpl {}),
-        Cipher::ChaCha20Poly1305,
-        read_only,
-    )
-    .await
-    .unwrap();
+    let fs = if *IN_MEMORY {
+        EncryptedFs::with_storage(
+            Arc::new(InMemoryStorage::new()),
+            Box::new(PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            read_only,
+            FsOptions::default(),
+        )
+        .await
+        .unwrap()
+    } else {
+        let _ = fs::create_dir_all(data_dir_str);
+        EncryptedFs::new(
+            Path::new(data_dir_str).to_path_buf(),
+            Box::new(PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            read_only,
+            FsOptions::default(),
+        )
+        .await
+        .unwrap()
+    };
 
     SetupResult {
         fs: Some(fs),
@@ -101,6 +120,9 @@ async fn teardown() -> Result<(), io::Error> {
     let s = s.lock().await;
     let path = TESTS_DATA_DIR.join(s.as_ref().unwrap().setup.key);
     let data_dir_str = path.to_str().unwrap();
+    if *IN_MEMORY {
+        return Ok(());
+    }
     fs::remove_dir_all(data_dir_str)?;
 
     Ok(())
@@ -124,24 +146,9 @@ where
 #[allow(dead_code)]
 pub async fn read_to_string(ino: u64, fs: &EncryptedFs) -> String {
     let fh = fs.open(ino, true, false).await.unwrap();
-    let buf = &mut [0; 4096];
-    let buf2 = vec![];
-    let mut cur = Cursor::new(buf2);
-    let mut read = 0;
-    let mut offset = 0;
-    loop {
-        if read == buf.len() {
-            read = 0;
-        }
-        let len = fs.read(ino, offset, &mut buf[read..], fh).await.unwrap();
-        if len == 0 {
-            fs.release(fh).await.unwrap();
-            return String::from_utf8(cur.into_inner()).unwrap();
-        }
-        cur.write_all(&buf[read..len]).unwrap();
-        read += len;
-        offset += len as u64;
-    }
+    let data = fs.read_all(ino, fh, u64::MAX).await.unwrap();
+    fs.release(fh).await.unwrap();
+    String::from_utf8(data).unwrap()
 }
 
 #[allow(dead_code)]
@@ -188,12 +195,7 @@ pub async fn read_exact(fs: &EncryptedFs, ino: u64, offset: u64, buf: &mut [u8],
 }
 
 #[allow(dead_code)]
-pub fn bench<F: Future + Send + Sync>(
-    key: &'static str,
-    worker_threads: usize,
-    read_only: bool,
-    f: F,
-) {
+pub fn bench<F: Future + Send>(key: &'static str, worker_threads: usize, read_only: bool, f: F) {
     block_on(
         async {
             run_test(TestSetup { key, read_only }, f).await;
@@ -219,3 +221,11 @@ pub async fn get_fs() -> Arc<EncryptedFs> {
     let mut fs = fs.lock().await;
     fs.as_mut().unwrap().fs.as_ref().unwrap().clone()
 }
+
+/// Take the fs out of the test setup, so it's closed when the returned one is dropped.
+#[allow(dead_code)]
+pub async fn take_fs() -> Arc<EncryptedFs> {
+    let fs = SETUP_RESULT.get_or(|| Mutex::new(None));
+    let mut fs = fs.lock().await;
+    fs.as_mut().unwrap().fs.take().unwrap()
+}
@@ -0,0 +1,237 @@
+//! Helpers for the tests of apps using [`EncryptedFs`], with the `test-util` feature.
+//!
+//! [`TestFs`] is a fs on a temporary data dir, the functions work with any [`EncryptedFs`] and take the paths from
+//! the root like [`EncryptedFs::resolve`].
+
+use std::collections::BTreeMap;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use shush_rs::{ExposeSecret, SecretString};
+use tempfile::TempDir;
+
+use crate::crypto::Cipher;
+use crate::encryptedfs::{
+    CreateFileAttr, EncryptedFs, FileType, FsOptions, FsResult, PasswordProvider, ROOT_INODE,
+};
+
+/// Password of the data dir of [`TestFs`].
+pub const TEST_PASSWORD: &str = "password";
+
+/// Always provides the same password.
+pub struct FixedPasswordProvider {
+    password: SecretString,
+}
+
+impl FixedPasswordProvider {
+    pub const fn new(password: SecretString) -> Self {
+        Self { password }
+    }
+}
+
+impl PasswordProvider for FixedPasswordProvider {
+    fn get_password(&self) -> Option<SecretString> {
+        Some(self.password.clone())
+    }
+}
+
+/// A new [`EncryptedFs`] in memory, see [`EncryptedFs::new_in_memory`].
+#[allow(clippy::missing_errors_doc)]
+pub async fn in_memory_fs(password: SecretString, cipher: Cipher) -> FsResult<Arc<EncryptedFs>> {
+    EncryptedFs::new_in_memory(Box::new(FixedPasswordProvider::new(password)), cipher).await
+}
+
+/// An [`EncryptedFs`] on a data dir in a new temporary directory, with [`TEST_PASSWORD`]. It's not mounted.
+///
+/// It derefs to the fs. When it's dropped the fs is closed, if there are no other references to it, and the data
+/// dir is removed.
+pub struct TestFs {
+    // dropped before the data dir
+    fs: Arc<EncryptedFs>,
+    dir: TempDir,
+}
+
+impl TestFs {
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn new(cipher: Cipher) -> FsResult<Self> {
+        let dir = tempfile::tempdir()?;
+        let fs = EncryptedFs::new(
+            dir.path().to_path_buf(),
+            Box::new(FixedPasswordProvider::new(test_password())),
+            cipher,
+            false,
+            FsOptions::default(),
+        )
+        .await?;
+        Ok(Self { fs, dir })
+    }
+
+    pub const fn fs(&self) -> &Arc<EncryptedFs> {
+        &self.fs
+    }
+
+    pub fn data_dir(&self) -> &Path {
+        self.dir.path()
+    }
+}
+
+impl Deref for TestFs {
+    type Target = EncryptedFs;
+
+    fn deref(&self) -> &Self::Target {
+        &self.fs
+    }
+}
+
+fn test_password() -> SecretString {
+    SecretString::from_str(TEST_PASSWORD).unwrap()
+}
+
+/// Create a file at `path` with `contents`, and its missing parent directories. Returns its inode.
+#[allow(clippy::missing_errors_doc)]
+pub async fn create_file(
+    fs: &EncryptedFs,
+    path: impl AsRef<Path>,
+    contents: &[u8],
+) -> FsResult<u64> {
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        fs.create_dir_all_by_path(parent, test_attr(FileType::Directory))
+            .await?;
+    }
+    let (fh, attr) = fs
+        .create_file_by_path(path, test_attr(FileType::RegularFile), false, true)
+        .await?;
+    let res = fs.write_all(attr.ino, 0, contents, fh).await;
+    fs.release(fh).await?;
+    res?;
+    Ok(attr.ino)
+}
+
+/// The contents of the file at `path`.
+#[allow(clippy::missing_errors_doc)]
+pub async fn read_to_vec(fs: &EncryptedFs, path: impl AsRef<Path>) -> FsResult<Vec<u8>> {
+    let ino = fs.resolve(path.as_ref()).await?.ino;
+    let fh = fs.open(ino, true, false).await?;
+    let res = fs.read_all(ino, fh, u64::MAX).await;
+    fs.release(fh).await?;
+    res
+}
+
+/// All the nodes under the root by path, with the contents of the files and `None` for the directories.
+#[allow(clippy::missing_errors_doc)]
+pub async fn tree(fs: &EncryptedFs) -> FsResult<BTreeMap<PathBuf, Option<Vec<u8>>>> {
+    let mut tree = BTreeMap::new();
+    let mut dirs = vec![(ROOT_INODE, PathBuf::new())];
+    while let Some((dir, dir_path)) = dirs.pop() {
+        for entry in fs.read_dir(dir).await? {
+            let entry = entry?;
+            let name = entry.name.expose_secret().to_string();
+            if name == "." || name == ".." {
+                continue;
+            }
+            let path = dir_path.join(name);
+            if entry.kind == FileType::Directory {
+                dirs.push((entry.ino, path.clone()));
+                tree.insert(path, None);
+            } else {
+                let contents = read_to_vec(fs, &path).await?;
+                tree.insert(path, Some(contents));
+            }
+        }
+    }
+    Ok(tree)
+}
+
+/// Assert the fs has exactly the nodes in `expected`, see [`tree`].
+///
+/// # Panics
+///
+/// If it has other nodes or other contents, or it can't be read.
+pub async fn assert_tree(fs: &EncryptedFs, expected: &[(&str, Option<&[u8]>)]) {
+    let expected: BTreeMap<PathBuf, Option<Vec<u8>>> = expected
+        .iter()
+        .map(|(path, contents)| (PathBuf::from(path), contents.map(<[u8]>::to_vec)))
+        .collect();
+    assert_eq!(expected, tree(fs).await.unwrap());
+}
+
+const fn test_attr(kind: FileType) -> CreateFileAttr {
+    CreateFileAttr {
+        kind,
+        perm: if matches!(kind, FileType::Directory) {
+            0o755
+        } else {
+            0o644
+        },
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: 0,
+        compress: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use shush_rs::SecretString;
+
+    use super::{assert_tree, create_file, in_memory_fs, read_to_vec, TestFs};
+    use crate::crypto::Cipher;
+    use crate::encryptedfs::{FileType, ROOT_INODE};
+    use crate::test_common::{create_attr, read_to_string};
+
+    #[tokio::test]
+    async fn test_in_memory_fs() {
+        let fs = in_memory_fs(
+            SecretString::from_str("password").unwrap(),
+            Cipher::ChaCha20Poly1305,
+        )
+        .await
+        .unwrap();
+        let (fh, attr) = fs
+            .create(
+                ROOT_INODE,
+                &SecretString::from_str("test-file").unwrap(),
+                create_attr(FileType::RegularFile),
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        fs.write_all(attr.ino, 0, b"test-42", fh).await.unwrap();
+        fs.release(fh).await.unwrap();
+        assert_eq!("test-42", read_to_string(attr.ino, &fs).await);
+        assert!(fs.storage.local_path().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_test_fs() {
+        let fs = TestFs::new(Cipher::ChaCha20Poly1305).await.unwrap();
+        let data_dir = fs.data_dir().to_path_buf();
+        assert_eq!(Some(data_dir.as_path()), fs.storage.local_path());
+        create_file(&fs, "a/b/file", b"test-42").await.unwrap();
+        create_file(&fs, "empty", b"").await.unwrap();
+        assert_eq!(
+            b"test-42".to_vec(),
+            read_to_vec(&fs, "a/b/file").await.unwrap()
+        );
+        assert_tree(
+            &fs,
+            &[
+                ("a", None),
+                ("a/b", None),
+                ("a/b/file", Some(b"test-42")),
+                ("empty", Some(b"")),
+            ],
+        )
+        .await;
+
+        drop(fs);
+        assert!(!data_dir.exists());
+    }
+}
@@ -26,7 +26,8 @@ impl TestResource {
         let mount_point = create_mount_point(
             Path::new(&MOUNT_PATH),
             Path::new(&DATA_PATH),
-            get_password_provider(),
+            // a boxed sync provider is also an async one
+            Box::new(get_password_provider()),
             Cipher::ChaCha20Poly1305,
             false,
             false,
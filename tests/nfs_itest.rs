@@ -0,0 +1,158 @@
+use std::str::FromStr;
+
+use nfsserve::nfs::{filename3, nfsstat3};
+use nfsserve::vfs::NFSFileSystem;
+use rencfs::crypto::Cipher;
+use rencfs::encryptedfs::ROOT_INODE;
+use rencfs::server::nfs::{serve, EncryptedNfs};
+use rencfs::test_util::{create_file, in_memory_fs, TEST_PASSWORD};
+use shush_rs::SecretString;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const NFS_PROGRAM: u32 = 100_003;
+const MOUNT_PROGRAM: u32 = 100_005;
+const NF3DIR: u32 = 2;
+
+fn name(name: &str) -> filename3 {
+    name.as_bytes().to_vec().into()
+}
+
+/// Call `procedure` of `program` version 3 with `args` in XDR, returns the results after the accepted reply.
+async fn call(
+    stream: &mut TcpStream,
+    xid: u32,
+    program: u32,
+    procedure: u32,
+    args: &[u8],
+) -> Vec<u8> {
+    let mut msg = vec![];
+    // CALL, RPC version 2, then AUTH_NONE credentials and verifier
+    for word in [xid, 0, 2, program, 3, procedure, 0, 0, 0, 0] {
+        msg.extend_from_slice(&word.to_be_bytes());
+    }
+    msg.extend_from_slice(args);
+    let marker = 0x8000_0000 | u32::try_from(msg.len()).unwrap();
+    stream.write_all(&marker.to_be_bytes()).await.unwrap();
+    stream.write_all(&msg).await.unwrap();
+
+    let mut marker = [0; 4];
+    stream.read_exact(&mut marker).await.unwrap();
+    let len = u32::from_be_bytes(marker) & 0x7fff_ffff;
+    let mut reply = vec![0; len as usize];
+    stream.read_exact(&mut reply).await.unwrap();
+    let word = |i: usize| u32::from_be_bytes(reply[i * 4..(i + 1) * 4].try_into().unwrap());
+    // xid, REPLY, MSG_ACCEPTED, the verifier and SUCCESS
+    assert_eq!([xid, 1, 0, 0, 0, 0], [0, 1, 2, 3, 4, 5].map(word));
+    reply[24..].to_vec()
+}
+
+#[tokio::test]
+async fn it_nfs_rpc() {
+    let fs = in_memory_fs(
+        SecretString::from_str(TEST_PASSWORD).unwrap(),
+        Cipher::ChaCha20Poly1305,
+    )
+    .await
+    .unwrap();
+    let server = serve("127.0.0.1:0".parse().unwrap(), fs.clone())
+        .await
+        .unwrap();
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+
+    // NULL
+    assert!(call(&mut stream, 1, NFS_PROGRAM, 0, &[]).await.is_empty());
+
+    // MNT of `/`, the handle of the root
+    let reply = call(
+        &mut stream,
+        2,
+        MOUNT_PROGRAM,
+        1,
+        &[0, 0, 0, 1, b'/', 0, 0, 0],
+    )
+    .await;
+    assert_eq!([0; 4], reply[..4]);
+    let fh_len = u32::from_be_bytes(reply[4..8].try_into().unwrap()) as usize;
+    let fh = &reply[4..8 + fh_len];
+
+    // GETATTR of the root
+    let reply = call(&mut stream, 3, NFS_PROGRAM, 1, fh).await;
+    assert_eq!([0; 4], reply[..4]);
+    assert_eq!(NF3DIR.to_be_bytes(), reply[4..8]);
+    assert_eq!(ROOT_INODE.to_be_bytes(), reply[56..64]);
+
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn it_nfs_fs() {
+    let fs = in_memory_fs(
+        SecretString::from_str(TEST_PASSWORD).unwrap(),
+        Cipher::ChaCha20Poly1305,
+    )
+    .await
+    .unwrap();
+    let nfs = EncryptedNfs::new(fs.clone());
+
+    // create, write and read
+    let ino = nfs.create_exclusive(ROOT_INODE, &name("a")).await.unwrap();
+    assert!(matches!(
+        nfs.create_exclusive(ROOT_INODE, &name("a")).await,
+        Err(nfsstat3::NFS3ERR_EXIST)
+    ));
+    assert_eq!(5, nfs.write(ino, 0, b"hello").await.unwrap().size);
+    assert_eq!(
+        (b"hello".to_vec(), true),
+        nfs.read(ino, 0, 100).await.unwrap()
+    );
+    assert_eq!((b"el".to_vec(), false), nfs.read(ino, 1, 2).await.unwrap());
+    assert_eq!(ino, nfs.lookup(ROOT_INODE, &name("a")).await.unwrap());
+    let (dir, attr) = nfs.mkdir(ROOT_INODE, &name("dir")).await.unwrap();
+    assert_eq!(dir, attr.fileid);
+
+    // the listing continues after the cookie, in the same order
+    for i in 0..5 {
+        create_file(&fs, format!("dir/file-{i}"), b"")
+            .await
+            .unwrap();
+    }
+    let mut names = vec![];
+    let mut cookie = 0;
+    loop {
+        let res = nfs.readdir(dir, cookie, 2).await.unwrap();
+        assert!(res.entries.len() <= 2);
+        names.extend(
+            res.entries
+                .iter()
+                .map(|entry| String::from_utf8(entry.name.to_vec()).unwrap()),
+        );
+        if res.end {
+            break;
+        }
+        cookie = res.entries.last().unwrap().fileid;
+    }
+    assert_eq!(
+        (0..5).map(|i| format!("file-{i}")).collect::<Vec<_>>(),
+        names
+    );
+
+    // rename and remove
+    nfs.rename(ROOT_INODE, &name("a"), dir, &name("b"))
+        .await
+        .unwrap();
+    assert_eq!(ino, nfs.lookup(dir, &name("b")).await.unwrap());
+    nfs.remove(dir, &name("b")).await.unwrap();
+    assert!(matches!(
+        nfs.lookup(dir, &name("b")).await,
+        Err(nfsstat3::NFS3ERR_NOENT)
+    ));
+
+    // the handles have the inode, the ones of another server are stale
+    let fh = nfs.id_to_fh(dir);
+    assert_eq!(dir, nfs.fh_to_id(&fh).unwrap());
+    assert!(matches!(
+        EncryptedNfs::new(fs.clone()).fh_to_id(&fh),
+        Err(nfsstat3::NFS3ERR_STALE)
+    ));
+}
@@ -1,6 +1,8 @@
 #![cfg(target_os = "linux")]
 mod linux_mount_setup;
-use linux_mount_setup::{count_files, TestGuard, DATA_PATH, MOUNT_PATH};
+use linux_mount_setup::{count_files, get_password_provider, TestGuard, DATA_PATH, MOUNT_PATH};
+use rencfs::crypto::Cipher;
+use rencfs::mount::{create_mount_point, MountHandle, MountPoint};
 use std::{
     fs::{self, File},
     io::{Read, Write},
@@ -147,3 +149,50 @@ fn it_create_empty_dir_check_attr() {
     let res = fs::remove_dir_all(Path::new(&test_folder));
     assert!(res.is_ok(), "failed to delete [{}]", &test_folder);
 }
+
+fn mount_in(runtime: &tokio::runtime::Runtime, mount_dir: &Path, data_dir: &Path) -> MountHandle {
+    let mount_point = create_mount_point(
+        mount_dir,
+        data_dir,
+        Box::new(get_password_provider()),
+        Cipher::ChaCha20Poly1305,
+        false,
+        false,
+        false,
+    );
+    runtime.block_on(mount_point.mount()).expect("cannot mount")
+}
+
+fn in_mounts(path: &Path) -> bool {
+    let path = path.to_str().unwrap();
+    fs::read_to_string("/proc/self/mountinfo")
+        .unwrap()
+        .lines()
+        .any(|line| line.split(' ').nth(4) == Some(path))
+}
+
+#[test]
+fn it_unmount_on_drop() {
+    let mount_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let handle = mount_in(&runtime, mount_dir.path(), data_dir.path());
+    assert!(handle.is_mounted());
+    assert!(in_mounts(mount_dir.path()));
+
+    drop(handle);
+    assert!(!in_mounts(mount_dir.path()));
+}
+
+#[test]
+fn it_umount_force_with_open_file() {
+    let mount_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let handle = mount_in(&runtime, mount_dir.path(), data_dir.path());
+    let _file = File::create(mount_dir.path().join("busy.txt")).unwrap();
+    runtime.block_on(handle.umount_force()).unwrap();
+    assert!(!in_mounts(mount_dir.path()));
+}
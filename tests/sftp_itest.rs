@@ -0,0 +1,109 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use rencfs::crypto::Cipher;
+use rencfs::server::sftp::{serve, SftpConfig, SftpCredentials};
+use rencfs::test_util::{assert_tree, create_file, in_memory_fs, read_to_vec, TEST_PASSWORD};
+use russh::client::{self, Handle};
+use russh_keys::key::PublicKey;
+use russh_sftp::client::SftpSession;
+use shush_rs::SecretString;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const USER: &str = "user";
+const PASSWORD: &str = "secret";
+
+struct Client;
+
+#[async_trait]
+impl client::Handler for Client {
+    type Error = russh::Error;
+
+    async fn check_server_key(&mut self, _key: &PublicKey) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+}
+
+async fn connect(addr: SocketAddr) -> Handle<Client> {
+    client::connect(Arc::new(client::Config::default()), addr, Client)
+        .await
+        .unwrap()
+}
+
+async fn open_sftp(addr: SocketAddr) -> SftpSession {
+    let mut session = connect(addr).await;
+    assert!(session.authenticate_password(USER, PASSWORD).await.unwrap());
+    let channel = session.channel_open_session().await.unwrap();
+    channel.request_subsystem(true, "sftp").await.unwrap();
+    // the connection is kept by the channel
+    SftpSession::new(channel.into_stream()).await.unwrap()
+}
+
+#[tokio::test]
+async fn it_sftp() {
+    let fs = in_memory_fs(
+        SecretString::from_str(TEST_PASSWORD).unwrap(),
+        Cipher::ChaCha20Poly1305,
+    )
+    .await
+    .unwrap();
+    create_file(&fs, "dir/old.txt", b"old").await.unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = SftpConfig::new(|user, credentials| {
+        user == USER
+            && matches!(credentials, SftpCredentials::Password(password) if password == PASSWORD)
+    });
+    tokio::spawn(serve(fs.clone(), listener, config));
+
+    let mut session = connect(addr).await;
+    assert!(!session.authenticate_password(USER, "wrong").await.unwrap());
+
+    let sftp = open_sftp(addr).await;
+    assert_eq!("/", sftp.canonicalize(".").await.unwrap());
+
+    // write and read
+    let mut file = sftp.create("dir/new.txt").await.unwrap();
+    file.write_all(b"hello").await.unwrap();
+    file.shutdown().await.unwrap();
+    assert_eq!(
+        b"hello".to_vec(),
+        read_to_vec(&fs, "dir/new.txt").await.unwrap()
+    );
+    let mut file = sftp.open("dir/old.txt").await.unwrap();
+    let mut data = vec![];
+    file.read_to_end(&mut data).await.unwrap();
+    file.shutdown().await.unwrap();
+    assert_eq!(b"old".to_vec(), data);
+
+    // list and stat
+    let mut names: Vec<_> = sftp
+        .read_dir("dir")
+        .await
+        .unwrap()
+        .map(|entry| entry.file_name())
+        .collect();
+    names.sort();
+    assert_eq!(vec!["new.txt", "old.txt"], names);
+    let metadata = sftp.metadata("dir/new.txt").await.unwrap();
+    assert_eq!(Some(5), metadata.size);
+    assert!(!metadata.is_dir());
+    assert!(sftp.metadata("dir").await.unwrap().is_dir());
+    assert!(sftp.metadata("missing").await.is_err());
+
+    // rename, it doesn't replace
+    assert!(sftp.rename("dir/new.txt", "dir/old.txt").await.is_err());
+    sftp.rename("dir/new.txt", "renamed.txt").await.unwrap();
+
+    // the directories and the removal
+    sftp.create_dir("empty").await.unwrap();
+    assert!(sftp.remove_file("empty").await.is_err());
+    sftp.remove_dir("empty").await.unwrap();
+    assert!(sftp.remove_dir("dir/old.txt").await.is_err());
+    sftp.remove_file("dir/old.txt").await.unwrap();
+
+    assert_tree(&fs, &[("dir", None), ("renamed.txt", Some(b"hello"))]).await;
+}
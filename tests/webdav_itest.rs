@@ -0,0 +1,89 @@
+use std::str::FromStr;
+
+use rencfs::crypto::Cipher;
+use rencfs::server::webdav::serve;
+use rencfs::test_util::{assert_tree, create_file, in_memory_fs, read_to_vec, TEST_PASSWORD};
+use reqwest::header::{ETAG, RANGE};
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode};
+use shush_rs::SecretString;
+
+/// A request with a WebDAV `method`, like `MKCOL`, to `path` on `host`.
+fn dav(client: &Client, method: &str, host: &str, path: &str) -> RequestBuilder {
+    client.request(
+        Method::from_bytes(method.as_bytes()).unwrap(),
+        format!("{host}/{path}"),
+    )
+}
+
+async fn check(req: RequestBuilder) -> Response {
+    let res = req.send().await.unwrap();
+    assert!(res.status().is_success(), "{}", res.status());
+    res
+}
+
+#[tokio::test]
+async fn it_webdav() {
+    let fs = in_memory_fs(
+        SecretString::from_str(TEST_PASSWORD).unwrap(),
+        Cipher::ChaCha20Poly1305,
+    )
+    .await
+    .unwrap();
+    create_file(&fs, "dir/old.txt", b"old").await.unwrap();
+    let server = serve("127.0.0.1:0".parse().unwrap(), fs.clone())
+        .await
+        .unwrap();
+    let host = format!("http://{}", server.local_addr());
+    let client = Client::new();
+
+    // PUT and GET
+    check(dav(&client, "PUT", &host, "hello.txt").body("hello world")).await;
+    assert_eq!(
+        b"hello world".to_vec(),
+        read_to_vec(&fs, "hello.txt").await.unwrap()
+    );
+    let res = check(dav(&client, "GET", &host, "hello.txt")).await;
+    let etag = res.headers()[ETAG].clone();
+    assert_eq!("hello world", res.text().await.unwrap());
+
+    // a range
+    let res = check(dav(&client, "GET", &host, "hello.txt").header(RANGE, "bytes=6-10")).await;
+    assert_eq!(StatusCode::PARTIAL_CONTENT, res.status());
+    assert_eq!("world", res.text().await.unwrap());
+
+    // the ETag changes with the contents
+    check(dav(&client, "PUT", &host, "hello.txt").body("hello again")).await;
+    let res = check(dav(&client, "GET", &host, "hello.txt")).await;
+    assert_ne!(etag, res.headers()[ETAG]);
+    assert_eq!("hello again", res.text().await.unwrap());
+
+    // MKCOL, MOVE, COPY, DELETE and PROPFIND
+    check(dav(&client, "MKCOL", &host, "new")).await;
+    check(
+        dav(&client, "MOVE", &host, "hello.txt")
+            .header("Destination", format!("{host}/new/moved.txt")),
+    )
+    .await;
+    check(
+        dav(&client, "COPY", &host, "new/moved.txt")
+            .header("Destination", format!("{host}/copied.txt")),
+    )
+    .await;
+    check(dav(&client, "DELETE", &host, "dir/old.txt")).await;
+    let res = check(dav(&client, "PROPFIND", &host, "new").header("Depth", "1")).await;
+    assert_eq!(StatusCode::MULTI_STATUS, res.status());
+    // the directory and the file
+    assert_eq!(2, res.text().await.unwrap().matches("<D:response>").count());
+
+    assert_tree(
+        &fs,
+        &[
+            ("copied.txt", Some(b"hello again")),
+            ("dir", None),
+            ("new", None),
+            ("new/moved.txt", Some(b"hello again")),
+        ],
+    )
+    .await;
+    server.shutdown().await;
+}